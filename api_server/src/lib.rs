@@ -31,12 +31,20 @@ use mmds::data_store::Mmds;
 use parsed_request::ParsedRequest;
 use sys_util::EventFd;
 use vmm::default_syscalls;
+use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStatistics, BalloonUpdateConfig};
 use vmm::vmm_config::boot_source::BootSourceConfig;
-use vmm::vmm_config::drive::BlockDeviceConfig;
+use vmm::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, OverlayMergeConfig};
 use vmm::vmm_config::instance_info::InstanceInfo;
 use vmm::vmm_config::logger::LoggerConfig;
 use vmm::vmm_config::machine_config::VmConfig;
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::migration::MigrationSendConfig;
+use vmm::vmm_config::net::{
+    NetworkInterfaceCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig,
+};
+use vmm::vmm_config::shutdown::{ExitStatus, ShutdownConfig};
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::snapshot::{SnapshotCreateConfig, SnapshotLoadConfig, SnapshotMergeConfig};
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 use vmm::VmmActionError;
 
@@ -65,6 +73,16 @@ pub enum VmmAction {
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted.
     SetVsockDevice(VsockDeviceConfig),
+    /// Set the balloon device or update the one that already exists using the
+    /// `BalloonDeviceConfig` as input. This action can only be called before the microVM has
+    /// booted.
+    SetBalloonDevice(BalloonDeviceConfig),
+    /// Update the target size of the balloon using the `BalloonUpdateConfig` as input.
+    UpdateBalloon(BalloonUpdateConfig),
+    /// Get the configuration of the balloon device.
+    GetBalloonConfig,
+    /// Get the size of the balloon and the latest memory statistics reported by the guest.
+    GetBalloonStats,
     /// Update the size of an existing block device specified by an ID. The ID is the first data
     /// associated with this enum variant. This action can only be called after the microVM is
     /// started.
@@ -78,12 +96,46 @@ pub enum VmmAction {
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     #[cfg(target_arch = "x86_64")]
     SendCtrlAltDel,
+    /// Ask the guest to shut down and stop the microVM if it is still running at the end of the
+    /// grace period from `ShutdownConfig`.
+    Shutdown(ShutdownConfig),
+    /// Get the exit status of a microVM that stopped running.
+    GetExitStatus,
+    /// Write the contents of a drive with an overlay to a new standalone image using as input
+    /// the `OverlayMergeConfig`.
+    MergeOverlay(OverlayMergeConfig),
     /// Update the path of an existing block device. The data associated with this variant
     /// represents the `drive_id` and the `path_on_host`.
     UpdateBlockDevicePath(String, String),
+    /// Update the path and the rate limiter of an existing block device using the
+    /// `BlockDeviceUpdateConfig` as input. The rate limiter of a running microVM is updated live.
+    UpdateBlockDevice(BlockDeviceUpdateConfig),
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
+    /// Start or stop capturing the frames of a network interface to a pcap file, after microVM
+    /// start, using the `NetworkInterfaceCaptureConfig` as input.
+    CaptureNetworkInterface(NetworkInterfaceCaptureConfig),
+    /// Park the vCPUs and stop the devices of the running microVM.
+    PauseVm,
+    /// Restart the devices and the vCPUs of the paused microVM.
+    ResumeVm,
+    /// Save the state and memory of the running or paused microVM using as input the
+    /// `SnapshotCreateConfig`. A running microVM is resumed once the snapshot is created.
+    #[cfg(target_arch = "x86_64")]
+    CreateSnapshot(SnapshotCreateConfig),
+    /// Rebuild and resume a microVM from a snapshot using as input the `SnapshotLoadConfig`.
+    /// This action can only be called before the microVM is configured and started.
+    #[cfg(target_arch = "x86_64")]
+    LoadSnapshot(SnapshotLoadConfig),
+    /// Copy the pages of a diff snapshot to the memory file of a previous snapshot using as
+    /// input the `SnapshotMergeConfig`.
+    #[cfg(target_arch = "x86_64")]
+    MergeSnapshot(SnapshotMergeConfig),
+    /// Migrate the running or paused microVM to another Firecracker process using as input the
+    /// `MigrationSendConfig`. The microVM stays paused once the destination resumed it.
+    #[cfg(target_arch = "x86_64")]
+    SendMigration(MigrationSendConfig),
 }
 
 /// The enum represents the response sent by the VMM in case of success. The response is either
@@ -94,6 +146,12 @@ pub enum VmmData {
     Empty,
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
+    /// The balloon device configuration represented by `BalloonDeviceConfig`.
+    BalloonConfig(BalloonDeviceConfig),
+    /// The balloon size and memory statistics represented by `BalloonStatistics`.
+    BalloonStatistics(BalloonStatistics),
+    /// The outcome of a microVM that stopped running represented by `ExitStatus`.
+    ExitStatus(ExitStatus),
 }
 
 pub enum Error {
@@ -5,6 +5,7 @@ use serde_json::Value;
 
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use request::actions::parse_put_actions;
+use request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use request::boot_source::parse_put_boot_source;
 use request::drive::{parse_patch_drive, parse_put_drive};
 use request::instance_info::parse_get_instance_info;
@@ -12,8 +13,11 @@ use request::logger::parse_put_logger;
 use request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
+use request::migration::parse_put_migration;
 use request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use request::net::{parse_patch_net, parse_put_net};
+use request::net::{parse_patch_net, parse_put_net, parse_put_net_capture};
+use request::snapshot::parse_put_snapshot;
+use request::vm::{parse_get_vm, parse_patch_vm};
 use request::vsock::parse_put_vsock;
 use {ApiServer, VmmAction, VmmData};
 
@@ -43,26 +47,34 @@ impl ParsedRequest {
 
         match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "", None) => parse_get_instance_info(),
+            (Method::Get, "balloon", None) => parse_get_balloon(path_tokens.get(1)),
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "vm", None) => parse_get_vm(path_tokens.get(1)),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
+            (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
+            (Method::Put, "migration", Some(body)) => parse_put_migration(body, path_tokens.get(1)),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body),
-            (Method::Put, "network-interfaces", Some(body)) => {
-                parse_put_net(body, path_tokens.get(1))
-            }
+            (Method::Put, "network-interfaces", Some(body)) => match path_tokens.get(2) {
+                Some(&"capture") => parse_put_net_capture(body, path_tokens.get(1)),
+                _ => parse_put_net(body, path_tokens.get(1)),
+            },
+            (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.get(1)),
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
+            (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body),
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
             (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.get(1))
             }
+            (Method::Patch, "vm", Some(body)) => parse_patch_vm(body),
             (Method::Patch, _, None) => method_to_error(Method::Patch),
             (method, unknown_uri, _) => {
                 Err(Error::InvalidPathMethod(unknown_uri.to_string(), method))
@@ -85,6 +97,30 @@ impl ParsedRequest {
                     response.set_body(Body::new(vm_config.to_string()));
                     response
                 }
+                VmmData::BalloonConfig(balloon_config) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    ApiServer::json_response(
+                        StatusCode::OK,
+                        serde_json::to_string(&balloon_config)
+                            .expect("Cannot serialize the balloon configuration"),
+                    )
+                }
+                VmmData::BalloonStatistics(balloon_stats) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    ApiServer::json_response(
+                        StatusCode::OK,
+                        serde_json::to_string(&balloon_stats)
+                            .expect("Cannot serialize the balloon statistics"),
+                    )
+                }
+                VmmData::ExitStatus(exit_status) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    ApiServer::json_response(
+                        StatusCode::OK,
+                        serde_json::to_string(&exit_status)
+                            .expect("Cannot serialize the exit status"),
+                    )
+                }
             },
             Err(vmm_action_error) => {
                 error!(
@@ -218,6 +254,7 @@ mod tests {
     use std::str::FromStr;
 
     use micro_http::HttpConnection;
+    use vmm::vmm_config::balloon::BalloonDeviceConfig;
     use vmm::vmm_config::machine_config::VmConfig;
     use vmm::{StartMicrovmError, VmmActionError};
 
@@ -443,7 +480,7 @@ mod tests {
         assert_eq!(&buf[..], expected_response.as_bytes());
 
         // With Vmm data.
-        let mut buf: [u8; 214] = [0; 214];
+        let mut buf: [u8; 318] = [0; 318];
         let response = ParsedRequest::convert_to_response(Ok(VmmData::MachineConfiguration(
             VmConfig::default(),
         )));
@@ -453,11 +490,30 @@ mod tests {
              Server: Firecracker API\r\n\
              Connection: keep-alive\r\n\
              Content-Type: application/json\r\n\
-             Content-Length: 96\r\n\r\n{}",
+             Content-Length: 199\r\n\r\n{}",
             VmConfig::default().to_string()
         );
         assert_eq!(&buf[..], expected_response.as_bytes());
 
+        // With a Vmm data serialized as json.
+        let mut buf = Vec::new();
+        let response =
+            ParsedRequest::convert_to_response(Ok(VmmData::BalloonConfig(BalloonDeviceConfig {
+                amount_mib: 16,
+                deflate_on_oom: true,
+                free_page_reporting: false,
+                stats_polling_interval_s: 0,
+            })));
+        assert!(response.write_all(&mut buf).is_ok());
+        let expected_response = "HTTP/1.1 200 \r\n\
+                                 Server: Firecracker API\r\n\
+                                 Connection: keep-alive\r\n\
+                                 Content-Type: application/json\r\n\
+                                 Content-Length: 96\r\n\r\n\
+                                 {\"amount_mib\":16,\"deflate_on_oom\":true,\
+                                 \"free_page_reporting\":false,\"stats_polling_interval_s\":0}";
+        assert_eq!(&buf[..], expected_response.as_bytes());
+
         // Error.
         let mut buf: [u8; 160] = [0; 160];
         let response = ParsedRequest::convert_to_response(Err(VmmActionError::from(
@@ -497,6 +553,36 @@ mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_balloon() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(b"GET /balloon/statistics HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Ok(ParsedRequest::Sync(VmmAction::GetBalloonStats)) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_try_from_get_vm() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(b"GET /vm/exit-status HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Ok(ParsedRequest::Sync(VmmAction::GetExitStatus)) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_try_from_get_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -697,6 +783,65 @@ mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_netif_capture() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /network-interfaces/string/capture HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 60\r\n\r\n{ \
+                \"iface_id\": \"string\", \
+                \"capture_path\": \"/tmp/string.pcap\" \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Ok(ParsedRequest::Sync(VmmAction::CaptureNetworkInterface(_))) => (),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_try_from_put_balloon() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /balloon HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 44\r\n\r\n{ \
+                \"amount_mib\": 64, \
+                \"deflate_on_oom\": true \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_patch_balloon() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PATCH /balloon HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 20\r\n\r\n{ \
+                \"amount_mib\": 32 \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_vsock() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -717,6 +862,45 @@ mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_try_from_put_snapshot() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /snapshot/create HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 50\r\n\r\n{ \
+                \"snapshot_path\": \"foo\", \
+                \"mem_file_path\": \"bar\" \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_try_from_put_migration() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /migration/send HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 24\r\n\r\n{ \
+                \"socket_path\": \"foo\" \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_drives() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -790,4 +974,25 @@ mod tests {
         let req = connection.pop_parsed_request().unwrap();
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
+
+    #[test]
+    fn test_try_from_patch_vm() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PATCH /vm HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 21\r\n\r\n{ \
+                \"state\": \"Paused\" \
+            }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Ok(ParsedRequest::Sync(VmmAction::PauseVm)) => {}
+            _ => panic!("Test failed."),
+        }
+    }
 }
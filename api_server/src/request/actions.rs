@@ -6,6 +6,8 @@ use serde_json::Value;
 use super::super::VmmAction;
 use logger::{Metric, METRICS};
 use request::{Body, Error, ParsedRequest, StatusCode};
+use vmm::vmm_config::drive::OverlayMergeConfig;
+use vmm::vmm_config::shutdown::ShutdownConfig;
 
 // The names of the members from this enum must precisely correspond (as a string) to the possible
 // values of "action_type" from the json request body. This is useful to get a strongly typed
@@ -15,7 +17,9 @@ enum ActionType {
     BlockDeviceRescan,
     FlushMetrics,
     InstanceStart,
+    MergeOverlay,
     SendCtrlAltDel,
+    Shutdown,
 }
 
 // The model of the json body from a sync request. We use Serde to transform each associated
@@ -59,6 +63,32 @@ fn validate_payload(action_body: &ActionBody) -> Result<(), Error> {
             }
             Ok(())
         }
+        ActionType::MergeOverlay => match action_body.payload {
+            Some(ref payload) if payload.is_object() => Ok(()),
+            Some(_) => Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Invalid payload type. Expected an object holding the drive_id and the \
+                 destination_path"
+                    .to_string(),
+            )),
+            None => Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Payload is required for merging an overlay.".to_string(),
+            )),
+        },
+        ActionType::Shutdown => {
+            // The grace period is optional.
+            if let Some(ref payload) = action_body.payload {
+                if !payload.is_object() {
+                    return Err(Error::Generic(
+                        StatusCode::BadRequest,
+                        "Invalid payload type. Expected an object holding the grace_period_ms"
+                            .to_string(),
+                    ));
+                }
+            }
+            Ok(())
+        }
     }
 }
 
@@ -80,6 +110,15 @@ pub fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
         }
         ActionType::FlushMetrics => Ok(ParsedRequest::Sync(VmmAction::FlushMetrics)),
         ActionType::InstanceStart => Ok(ParsedRequest::Sync(VmmAction::StartMicroVm)),
+        ActionType::MergeOverlay => {
+            // Safe to unwrap because we validated the payload in the validate_payload func.
+            let config = serde_json::from_value::<OverlayMergeConfig>(action_body.payload.unwrap())
+                .map_err(|e| {
+                    METRICS.put_api_requests.actions_fails.inc();
+                    Error::SerdeJson(e)
+                })?;
+            Ok(ParsedRequest::Sync(VmmAction::MergeOverlay(config)))
+        }
         ActionType::SendCtrlAltDel => {
             // SendCtrlAltDel not supported on aarch64.
             #[cfg(target_arch = "aarch64")]
@@ -91,6 +130,18 @@ pub fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
             #[cfg(target_arch = "x86_64")]
             Ok(ParsedRequest::Sync(VmmAction::SendCtrlAltDel))
         }
+        ActionType::Shutdown => {
+            let config = match action_body.payload {
+                Some(payload) => {
+                    serde_json::from_value::<ShutdownConfig>(payload).map_err(|e| {
+                        METRICS.put_api_requests.actions_fails.inc();
+                        Error::SerdeJson(e)
+                    })?
+                }
+                None => ShutdownConfig::default(),
+            };
+            Ok(ParsedRequest::Sync(VmmAction::Shutdown(config)))
+        }
     }
 }
 
@@ -98,6 +149,8 @@ pub fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
 mod tests {
     use super::*;
 
+    use std::path::PathBuf;
+
     #[test]
     fn test_validate_payload() {
         // Test InstanceStart.
@@ -159,6 +212,46 @@ mod tests {
             payload: Some(Value::String("dummy-payload".to_string())),
         };
         assert!(validate_payload(&action_body).is_err());
+
+        // Test Shutdown.
+        let action_body = ActionBody {
+            action_type: ActionType::Shutdown,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        let action_body = ActionBody {
+            action_type: ActionType::Shutdown,
+            payload: Some(serde_json::from_str(r#"{"grace_period_ms": 100}"#).unwrap()),
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: the payload is not an object.
+        let action_body = ActionBody {
+            action_type: ActionType::Shutdown,
+            payload: Some(Value::from(100)),
+        };
+        assert!(validate_payload(&action_body).is_err());
+
+        // Test MergeOverlay.
+        let action_body = ActionBody {
+            action_type: ActionType::MergeOverlay,
+            payload: Some(
+                serde_json::from_str(r#"{"drive_id": "root", "destination_path": "/foo"}"#)
+                    .unwrap(),
+            ),
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: no payload.
+        let action_body = ActionBody {
+            action_type: ActionType::MergeOverlay,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_err());
+        // Error case: the payload is not an object.
+        let action_body = ActionBody {
+            action_type: ActionType::MergeOverlay,
+            payload: Some(Value::String("root".to_string())),
+        };
+        assert!(validate_payload(&action_body).is_err());
     }
 
     #[test]
@@ -226,5 +319,59 @@ mod tests {
             let result = parse_put_actions(&Body::new(json));
             assert!(result.is_err());
         }
+
+        {
+            let json = r#"{
+                "action_type": "Shutdown"
+            }"#;
+
+            let req: ParsedRequest =
+                ParsedRequest::Sync(VmmAction::Shutdown(ShutdownConfig::default()));
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_ok());
+            assert!(result.unwrap().eq(&req));
+
+            let json = r#"{
+                "action_type": "Shutdown",
+                "payload": {"grace_period_ms": 100}
+            }"#;
+
+            let req: ParsedRequest = ParsedRequest::Sync(VmmAction::Shutdown(ShutdownConfig {
+                grace_period_ms: 100,
+            }));
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_ok());
+            assert!(result.unwrap().eq(&req));
+
+            let json = r#"{
+                "action_type": "Shutdown",
+                "payload": {"grace_period": 100}
+            }"#;
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_err());
+        }
+
+        {
+            let json = r#"{
+                "action_type": "MergeOverlay",
+                "payload": {"drive_id": "root", "destination_path": "/foo"}
+            }"#;
+
+            let req: ParsedRequest =
+                ParsedRequest::Sync(VmmAction::MergeOverlay(OverlayMergeConfig {
+                    drive_id: String::from("root"),
+                    destination_path: PathBuf::from("/foo"),
+                }));
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_ok());
+            assert!(result.unwrap().eq(&req));
+
+            let json = r#"{
+                "action_type": "MergeOverlay",
+                "payload": {"drive_id": "root"}
+            }"#;
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_err());
+        }
     }
 }
@@ -0,0 +1,105 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use logger::{Metric, METRICS};
+use request::{Body, Error, ParsedRequest, StatusCode};
+use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonUpdateConfig};
+
+pub fn parse_get_balloon(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
+    METRICS.get_api_requests.balloon_count.inc();
+    match path_second_token {
+        None => Ok(ParsedRequest::Sync(VmmAction::GetBalloonConfig)),
+        Some(&"statistics") => Ok(ParsedRequest::Sync(VmmAction::GetBalloonStats)),
+        Some(&unrecognized) => {
+            METRICS.get_api_requests.balloon_fails.inc();
+            Err(Error::Generic(
+                StatusCode::BadRequest,
+                format!("Unrecognized GET request path `{}`.", unrecognized),
+            ))
+        }
+    }
+}
+
+pub fn parse_put_balloon(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.balloon_count.inc();
+    Ok(ParsedRequest::Sync(VmmAction::SetBalloonDevice(
+        serde_json::from_slice::<BalloonDeviceConfig>(body.raw()).map_err(|e| {
+            METRICS.put_api_requests.balloon_fails.inc();
+            Error::SerdeJson(e)
+        })?,
+    )))
+}
+
+pub fn parse_patch_balloon(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.balloon_count.inc();
+    Ok(ParsedRequest::Sync(VmmAction::UpdateBalloon(
+        serde_json::from_slice::<BalloonUpdateConfig>(body.raw()).map_err(|e| {
+            METRICS.patch_api_requests.balloon_fails.inc();
+            Error::SerdeJson(e)
+        })?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get_balloon_request() {
+        match parse_get_balloon(None) {
+            Ok(ParsedRequest::Sync(VmmAction::GetBalloonConfig)) => {}
+            _ => panic!("Test failed."),
+        }
+        match parse_get_balloon(Some(&"statistics")) {
+            Ok(ParsedRequest::Sync(VmmAction::GetBalloonStats)) => {}
+            _ => panic!("Test failed."),
+        }
+        assert!(parse_get_balloon(Some(&"foo")).is_err());
+    }
+
+    #[test]
+    fn test_parse_put_balloon_request() {
+        let body = r#"{
+                "amount_mib": 64,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 5
+              }"#;
+        match parse_put_balloon(&Body::new(body)) {
+            Ok(ParsedRequest::Sync(VmmAction::SetBalloonDevice(config))) => {
+                assert_eq!(
+                    config,
+                    BalloonDeviceConfig {
+                        amount_mib: 64,
+                        deflate_on_oom: true,
+                        free_page_reporting: false,
+                        stats_polling_interval_s: 5,
+                    }
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_balloon(&Body::new(r#"{"amount_mib": 64}"#)).is_err());
+        assert!(parse_put_balloon(&Body::new(
+            r#"{"amount_mib": 64, "deflate_on_oom": false, "foo": 1}"#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_patch_balloon_request() {
+        match parse_patch_balloon(&Body::new(r#"{"amount_mib": 32}"#)) {
+            Ok(ParsedRequest::Sync(VmmAction::UpdateBalloon(update))) => {
+                assert_eq!(update, BalloonUpdateConfig { amount_mib: 32 });
+            }
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_patch_balloon(&Body::new(r#"{}"#)).is_err());
+        assert!(
+            parse_patch_balloon(&Body::new(r#"{"amount_mib": 32, "deflate_on_oom": true}"#))
+                .is_err()
+        );
+    }
+}
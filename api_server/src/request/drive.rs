@@ -6,7 +6,7 @@ use serde_json::{Map, Value};
 use super::super::VmmAction;
 use logger::{Metric, METRICS};
 use request::{checked_id, Body, Error, ParsedRequest, StatusCode};
-use vmm::vmm_config::drive::BlockDeviceConfig;
+use vmm::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig};
 
 #[derive(Clone)]
 pub struct PatchDrivePayload {
@@ -36,22 +36,32 @@ impl PatchDrivePayload {
         Ok(())
     }
 
-    /// Validates that only path_on_host and drive_id are present in the payload.
+    /// Validates that only drive_id, path_on_host and rate_limiter are present in the payload,
+    /// and that at least one of path_on_host and rate_limiter is.
     fn validate(&self) -> Result<(), Error> {
         match self.fields.as_object() {
             Some(fields_map) => {
                 // Check that field `drive_id` exists and its type is String.
                 PatchDrivePayload::check_field_is_string(fields_map, "drive_id")
                     .map_err(|e| Error::Generic(StatusCode::BadRequest, e))?;
-                // Check that field `drive_id` exists and its type is String.
-                PatchDrivePayload::check_field_is_string(fields_map, "path_on_host")
-                    .map_err(|e| Error::Generic(StatusCode::BadRequest, e))?;
+                // Check that field `path_on_host` exists and its type is String, unless only
+                // the rate limiter is updated.
+                if !fields_map.contains_key("rate_limiter")
+                    || fields_map.contains_key("path_on_host")
+                {
+                    PatchDrivePayload::check_field_is_string(fields_map, "path_on_host")
+                        .map_err(|e| Error::Generic(StatusCode::BadRequest, e))?;
+                }
 
                 // Check that there are no other fields in the object.
-                if fields_map.len() > 2 {
+                if fields_map
+                    .keys()
+                    .any(|key| !["drive_id", "path_on_host", "rate_limiter"].contains(&&key[..]))
+                {
                     return Err(Error::Generic(
                         StatusCode::BadRequest,
-                        "Invalid PATCH payload. Only updates on path_on_host are allowed."
+                        "Invalid PATCH payload. Only updates on path_on_host and rate_limiter \
+                         are allowed."
                             .to_string(),
                     ));
                 }
@@ -122,7 +132,6 @@ pub fn parse_patch_drive(body: &Body, id_from_path: Option<&&str>) -> Result<Par
 
     patch_drive_payload.validate()?;
     let drive_id: String = patch_drive_payload.get_string_field_unchecked("drive_id");
-    let path_on_host: String = patch_drive_payload.get_string_field_unchecked("path_on_host");
 
     if id != drive_id.as_str() {
         METRICS.patch_api_requests.drive_fails.inc();
@@ -132,6 +141,20 @@ pub fn parse_patch_drive(body: &Body, id_from_path: Option<&&str>) -> Result<Par
         ));
     }
 
+    if patch_drive_payload.fields.get("rate_limiter").is_some() {
+        let drive_update = serde_json::from_value::<BlockDeviceUpdateConfig>(
+            patch_drive_payload.fields,
+        )
+        .map_err(|e| {
+            METRICS.patch_api_requests.drive_fails.inc();
+            Error::SerdeJson(e)
+        })?;
+        return Ok(ParsedRequest::Sync(VmmAction::UpdateBlockDevice(
+            drive_update,
+        )));
+    }
+
+    let path_on_host: String = patch_drive_payload.get_string_field_unchecked("path_on_host");
     Ok(ParsedRequest::Sync(VmmAction::UpdateBlockDevicePath(
         drive_id,
         path_on_host,
@@ -218,6 +241,67 @@ mod tests {
         assert!(parse_patch_drive(&Body::new(body), Some(&"bar")).is_err());
     }
 
+    #[test]
+    fn test_parse_patch_rate_limiter() {
+        // PATCH with an invalid rate limiter.
+        let body = r#"{
+                "drive_id": "foo",
+                "rate_limiter": {
+                    "bandwidth": "dummy"
+                }
+              }"#;
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        // PATCH with an invalid type on path_on_host.
+        let body = r#"{
+                "drive_id": "foo",
+                "path_on_host": 1000,
+                "rate_limiter": {}
+              }"#;
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        let body = r#"{
+                "drive_id": "foo",
+                "rate_limiter": {
+                    "ops": {
+                        "size": 100,
+                        "refill_time": 1000
+                    }
+                }
+              }"#;
+        match parse_patch_drive(&Body::new(body), Some(&"foo")) {
+            Ok(ParsedRequest::Sync(VmmAction::UpdateBlockDevice(cfg))) => {
+                assert_eq!(cfg.drive_id, "foo");
+                assert!(cfg.path_on_host.is_none());
+                let rate_limiter = cfg.rate_limiter.unwrap();
+                assert!(rate_limiter.bandwidth.is_none());
+                assert_eq!(rate_limiter.ops.unwrap().size, 100);
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+        assert!(parse_patch_drive(&Body::new(body), Some(&"bar")).is_err());
+
+        // The path and the rate limiter can be updated together.
+        let body = r#"{
+                "drive_id": "foo",
+                "path_on_host": "dummy",
+                "rate_limiter": {
+                    "write_bandwidth": {
+                        "size": 1000,
+                        "refill_time": 1000
+                    }
+                }
+              }"#;
+        match parse_patch_drive(&Body::new(body), Some(&"foo")) {
+            Ok(ParsedRequest::Sync(VmmAction::UpdateBlockDevice(cfg))) => {
+                assert_eq!(cfg.path_on_host, Some("dummy".to_string()));
+                let rate_limiter = cfg.rate_limiter.unwrap();
+                assert_eq!(rate_limiter.write_bandwidth.unwrap().size, 1000);
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
     #[test]
     fn test_parse_put_request() {
         assert!(parse_put_drive(&Body::new("invalid_payload"), Some(&"id")).is_err());
@@ -41,6 +41,9 @@ pub fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, Error> {
         && vm_config.mem_size_mib.is_none()
         && vm_config.cpu_template.is_none()
         && vm_config.ht_enabled.is_none()
+        && vm_config.track_dirty_pages.is_none()
+        && vm_config.mem_backend.is_none()
+        && vm_config.backing_page_size.is_none()
     {
         return method_to_error(Method::Patch);
     }
@@ -69,6 +72,9 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         let body = r#"{
                 "vcpu_count": 8,
@@ -108,5 +114,17 @@ mod tests {
                 "ht_enabled": false
               }"#;
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+        let body = r#"{
+                "track_dirty_pages": true
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+        let body = r#"{
+                "mem_backend": {"backend_type": "Memfd"}
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+        let body = r#"{
+                "backing_page_size": "2M"
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
     }
 }
@@ -0,0 +1,63 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use logger::{Metric, METRICS};
+use request::{Body, Error, ParsedRequest, StatusCode};
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::migration::MigrationSendConfig;
+
+pub fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.migration_count.inc();
+    match request_type_from_path {
+        #[cfg(target_arch = "x86_64")]
+        Some(&"send") => Ok(ParsedRequest::Sync(VmmAction::SendMigration(
+            serde_json::from_slice::<MigrationSendConfig>(body.raw()).map_err(|e| {
+                METRICS.put_api_requests.migration_fails.inc();
+                Error::SerdeJson(e)
+            })?,
+        ))),
+        _ => {
+            METRICS.put_api_requests.migration_fails.inc();
+            Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Invalid migration request type. Supported types: send.".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_put_migration_request() {
+        let body = r#"{
+                "socket_path": "foo"
+              }"#;
+        #[cfg(target_arch = "x86_64")]
+        match parse_put_migration(&Body::new(body), Some(&"send")) {
+            Ok(ParsedRequest::Sync(VmmAction::SendMigration(config))) => {
+                assert_eq!(
+                    config,
+                    MigrationSendConfig {
+                        socket_path: "foo".into(),
+                    }
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+        assert!(parse_put_migration(&Body::new(body), Some(&"receive")).is_err());
+        assert!(parse_put_migration(&Body::new(body), None).is_err());
+
+        let body = r#"{
+                "socket_path": "foo",
+                "invalid_field": false
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"send")).is_err());
+    }
+}
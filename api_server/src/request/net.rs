@@ -4,7 +4,9 @@
 use super::super::VmmAction;
 use logger::{Metric, METRICS};
 use request::{checked_id, Body, Error, ParsedRequest, StatusCode};
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::net::{
+    NetworkInterfaceCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig,
+};
 
 pub fn parse_put_net(body: &Body, id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
     METRICS.patch_api_requests.network_count.inc();
@@ -53,6 +55,34 @@ pub fn parse_patch_net(body: &Body, id_from_path: Option<&&str>) -> Result<Parse
     )))
 }
 
+pub fn parse_put_net_capture(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.network_count.inc();
+    let id = match id_from_path {
+        Some(&id) => checked_id(id)?,
+        None => {
+            return Err(Error::EmptyID);
+        }
+    };
+
+    let capture =
+        serde_json::from_slice::<NetworkInterfaceCaptureConfig>(body.raw()).map_err(|e| {
+            METRICS.put_api_requests.network_fails.inc();
+            Error::SerdeJson(e)
+        })?;
+    if id != capture.iface_id {
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The id from the path does not match the id from the body!".to_string(),
+        ));
+    }
+    Ok(ParsedRequest::Sync(VmmAction::CaptureNetworkInterface(
+        capture,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     extern crate dumbo;
@@ -77,6 +107,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         }
     }
 
@@ -113,6 +146,9 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: true,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
 
         // This is the json encoding of the netif variable.
@@ -145,4 +181,42 @@ mod tests {
         assert!(parse_put_net(&Body::new(body), Some(&"bar")).is_err());
         assert!(parse_patch_net(&Body::new(body), Some(&"bar")).is_err());
     }
+
+    #[test]
+    fn test_parse_netif_capture_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "capture_path": "/tmp/foo.pcap",
+                "snap_len": 128,
+                "max_size_bytes": 1048576
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"bar")).is_err());
+        assert!(parse_put_net_capture(&Body::new(body), None).is_err());
+        match parse_put_net_capture(&Body::new(body), Some(&"foo")) {
+            Ok(ParsedRequest::Sync(VmmAction::CaptureNetworkInterface(capture))) => assert_eq!(
+                capture,
+                NetworkInterfaceCaptureConfig {
+                    iface_id: String::from("foo"),
+                    capture_path: Some("/tmp/foo.pcap".into()),
+                    snap_len: 128,
+                    max_size_bytes: 1_048_576,
+                }
+            ),
+            _ => panic!("Test failed."),
+        }
+
+        // The capture stops without a path.
+        match parse_put_net_capture(&Body::new(r#"{"iface_id": "foo"}"#), Some(&"foo")) {
+            Ok(ParsedRequest::Sync(VmmAction::CaptureNetworkInterface(capture))) => {
+                assert!(capture.capture_path.is_none())
+            }
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_net_capture(
+            &Body::new(r#"{"iface_id": "foo", "path": "/tmp/foo.pcap"}"#),
+            Some(&"foo")
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,153 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use logger::{Metric, METRICS};
+use request::{Body, Error, ParsedRequest, StatusCode};
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::snapshot::{SnapshotCreateConfig, SnapshotLoadConfig, SnapshotMergeConfig};
+
+pub fn parse_put_snapshot(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.snapshot_count.inc();
+    match request_type_from_path {
+        #[cfg(target_arch = "x86_64")]
+        Some(&"create") => Ok(ParsedRequest::Sync(VmmAction::CreateSnapshot(
+            serde_json::from_slice::<SnapshotCreateConfig>(body.raw()).map_err(|e| {
+                METRICS.put_api_requests.snapshot_fails.inc();
+                Error::SerdeJson(e)
+            })?,
+        ))),
+        #[cfg(target_arch = "x86_64")]
+        Some(&"load") => Ok(ParsedRequest::Sync(VmmAction::LoadSnapshot(
+            serde_json::from_slice::<SnapshotLoadConfig>(body.raw()).map_err(|e| {
+                METRICS.put_api_requests.snapshot_fails.inc();
+                Error::SerdeJson(e)
+            })?,
+        ))),
+        #[cfg(target_arch = "x86_64")]
+        Some(&"merge") => Ok(ParsedRequest::Sync(VmmAction::MergeSnapshot(
+            serde_json::from_slice::<SnapshotMergeConfig>(body.raw()).map_err(|e| {
+                METRICS.put_api_requests.snapshot_fails.inc();
+                Error::SerdeJson(e)
+            })?,
+        ))),
+        _ => {
+            METRICS.put_api_requests.snapshot_fails.inc();
+            Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Invalid snapshot request type. Supported types: create, load, merge.".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "x86_64")]
+    use vmm::vmm_config::snapshot::{SnapshotMemoryBackend, SnapshotType};
+
+    #[test]
+    fn test_parse_put_snapshot_request() {
+        let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar"
+              }"#;
+        #[cfg(target_arch = "x86_64")]
+        {
+            match parse_put_snapshot(&Body::new(body), Some(&"create")) {
+                Ok(ParsedRequest::Sync(VmmAction::CreateSnapshot(config))) => {
+                    assert_eq!(
+                        config,
+                        SnapshotCreateConfig {
+                            snapshot_path: "foo".into(),
+                            mem_file_path: "bar".into(),
+                            snapshot_type: SnapshotType::Full,
+                        }
+                    );
+                }
+                _ => panic!("Test failed."),
+            }
+            match parse_put_snapshot(&Body::new(body), Some(&"load")) {
+                Ok(ParsedRequest::Sync(VmmAction::LoadSnapshot(config))) => {
+                    assert_eq!(
+                        config,
+                        SnapshotLoadConfig {
+                            snapshot_path: "foo".into(),
+                            mem_file_path: "bar".into(),
+                            mem_backend: SnapshotMemoryBackend::File,
+                        }
+                    );
+                }
+                _ => panic!("Test failed."),
+            }
+        }
+        assert!(parse_put_snapshot(&Body::new(body), Some(&"merge")).is_err());
+        assert!(parse_put_snapshot(&Body::new(body), Some(&"invalid")).is_err());
+        assert!(parse_put_snapshot(&Body::new(body), None).is_err());
+
+        let body = r#"{
+                "snapshot_path": "foo",
+                "invalid_field": false
+              }"#;
+        assert!(parse_put_snapshot(&Body::new(body), Some(&"create")).is_err());
+        assert!(parse_put_snapshot(&Body::new(body), Some(&"load")).is_err());
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "snapshot_type": "Diff"
+              }"#;
+            match parse_put_snapshot(&Body::new(body), Some(&"create")) {
+                Ok(ParsedRequest::Sync(VmmAction::CreateSnapshot(config))) => {
+                    assert_eq!(config.snapshot_type, SnapshotType::Diff);
+                }
+                _ => panic!("Test failed."),
+            }
+
+            let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_backend": {
+                    "backend_type": "UffdSocket",
+                    "socket_path": "baz"
+                }
+              }"#;
+            match parse_put_snapshot(&Body::new(body), Some(&"load")) {
+                Ok(ParsedRequest::Sync(VmmAction::LoadSnapshot(config))) => {
+                    assert_eq!(
+                        config.mem_backend,
+                        SnapshotMemoryBackend::UffdSocket {
+                            socket_path: "baz".into()
+                        }
+                    );
+                }
+                _ => panic!("Test failed."),
+            }
+
+            let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "base_mem_file_path": "baz"
+              }"#;
+            match parse_put_snapshot(&Body::new(body), Some(&"merge")) {
+                Ok(ParsedRequest::Sync(VmmAction::MergeSnapshot(config))) => {
+                    assert_eq!(
+                        config,
+                        SnapshotMergeConfig {
+                            snapshot_path: "foo".into(),
+                            mem_file_path: "bar".into(),
+                            base_mem_file_path: "baz".into(),
+                        }
+                    );
+                }
+                _ => panic!("Test failed."),
+            }
+        }
+    }
+}
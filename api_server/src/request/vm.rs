@@ -0,0 +1,71 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use logger::{Metric, METRICS};
+use request::{Body, Error, ParsedRequest, StatusCode};
+use vmm::vmm_config::instance_info::{VmRunState, VmRunStateConfig};
+
+pub fn parse_get_vm(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
+    METRICS.get_api_requests.vm_count.inc();
+    match path_second_token {
+        Some(&"exit-status") => Ok(ParsedRequest::Sync(VmmAction::GetExitStatus)),
+        Some(&unrecognized) => {
+            METRICS.get_api_requests.vm_fails.inc();
+            Err(Error::Generic(
+                StatusCode::BadRequest,
+                format!("Unrecognized GET request path `{}`.", unrecognized),
+            ))
+        }
+        None => {
+            METRICS.get_api_requests.vm_fails.inc();
+            Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Missing the exit-status path segment.".to_string(),
+            ))
+        }
+    }
+}
+
+pub fn parse_patch_vm(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.vm_count.inc();
+    let config = serde_json::from_slice::<VmRunStateConfig>(body.raw()).map_err(|e| {
+        METRICS.patch_api_requests.vm_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+    match config.state {
+        VmRunState::Paused => Ok(ParsedRequest::Sync(VmmAction::PauseVm)),
+        VmRunState::Resumed => Ok(ParsedRequest::Sync(VmmAction::ResumeVm)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get_vm_request() {
+        match parse_get_vm(Some(&"exit-status")) {
+            Ok(ParsedRequest::Sync(VmmAction::GetExitStatus)) => {}
+            _ => panic!("Test failed."),
+        }
+        assert!(parse_get_vm(Some(&"foo")).is_err());
+        assert!(parse_get_vm(None).is_err());
+    }
+
+    #[test]
+    fn test_parse_patch_vm_request() {
+        match parse_patch_vm(&Body::new(r#"{"state": "Paused"}"#)) {
+            Ok(ParsedRequest::Sync(VmmAction::PauseVm)) => {}
+            _ => panic!("Test failed."),
+        }
+        match parse_patch_vm(&Body::new(r#"{"state": "Resumed"}"#)) {
+            Ok(ParsedRequest::Sync(VmmAction::ResumeVm)) => {}
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_patch_vm(&Body::new(r#"{"state": "Running"}"#)).is_err());
+        assert!(parse_patch_vm(&Body::new(r#"{"state": "Paused", "foo": 1}"#)).is_err());
+        assert!(parse_patch_vm(&Body::new(r#"{}"#)).is_err());
+    }
+}
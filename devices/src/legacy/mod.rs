@@ -14,4 +14,4 @@ pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
-pub use self::serial::Serial;
+pub use self::serial::{Serial, SerialState};
@@ -8,6 +8,7 @@
 use std::collections::VecDeque;
 use std::io;
 
+use fc_util::versioned::Versioned;
 use logger::{Metric, METRICS};
 use sys_util::EventFd;
 
@@ -51,6 +52,34 @@ const DEFAULT_MODEM_CONTROL: u8 = 0x8; // Auxiliary output 2
 const DEFAULT_MODEM_STATUS: u8 = 0x20 | 0x10 | 0x80; // data ready, clear to send, carrier detect
 const DEFAULT_BAUD_DIVISOR: u16 = 12; // 9600 bps
 
+/// The serializable state of a serial port.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SerialState {
+    /// The interrupt enable register.
+    pub interrupt_enable: u8,
+    /// The interrupt identification register.
+    pub interrupt_identification: u8,
+    /// The line control register.
+    pub line_control: u8,
+    /// The line status register.
+    pub line_status: u8,
+    /// The modem control register.
+    pub modem_control: u8,
+    /// The modem status register.
+    pub modem_status: u8,
+    /// The scratch register.
+    pub scratch: u8,
+    /// The baud rate divisor.
+    pub baud_divisor: u16,
+    /// The input not yet read by the guest.
+    pub in_buffer: Vec<u8>,
+}
+
+impl Versioned for SerialState {
+    const NAME: &'static str = "Serial";
+    const VERSION: u16 = 1;
+}
+
 /// Emulates serial COM ports commonly seen on x86 I/O ports 0x3f8/0x2f8/0x3e8/0x2e8.
 ///
 /// This can optionally write the guest's output to a Write trait object. To send input to the
@@ -96,6 +125,34 @@ impl Serial {
         Self::new(interrupt_evt, None)
     }
 
+    /// Returns the current state of the serial port.
+    pub fn save_state(&self) -> SerialState {
+        SerialState {
+            interrupt_enable: self.interrupt_enable,
+            interrupt_identification: self.interrupt_identification,
+            line_control: self.line_control,
+            line_status: self.line_status,
+            modem_control: self.modem_control,
+            modem_status: self.modem_status,
+            scratch: self.scratch,
+            baud_divisor: self.baud_divisor,
+            in_buffer: self.in_buffer.iter().cloned().collect(),
+        }
+    }
+
+    /// Restores the serial port to a previously saved state.
+    pub fn restore_state(&mut self, state: &SerialState) {
+        self.interrupt_enable = state.interrupt_enable;
+        self.interrupt_identification = state.interrupt_identification;
+        self.line_control = state.line_control;
+        self.line_status = state.line_status;
+        self.modem_control = state.modem_control;
+        self.modem_status = state.modem_status;
+        self.scratch = state.scratch;
+        self.baud_divisor = state.baud_divisor;
+        self.in_buffer = state.in_buffer.iter().cloned().collect();
+    }
+
     fn is_dlab_set(&self) -> bool {
         (self.line_control & LCR_DLAB_BIT) != 0
     }
@@ -391,6 +448,34 @@ mod tests {
         assert_eq!(data[0], 0x12 as u8);
     }
 
+    #[test]
+    fn test_save_restore_state() {
+        let mut serial = Serial::new_sink(EventFd::new().unwrap());
+        serial.write(u64::from(IER), &[IER_RECV_BIT]);
+        serial.write(u64::from(LCR), &[LCR_DLAB_BIT]);
+        serial.write(u64::from(DLAB_LOW), &[0x34]);
+        serial.write(u64::from(LCR), &[DEFAULT_LINE_CONTROL]);
+        serial.write(u64::from(SCR), &[0x12]);
+        serial.raw_input(&[b'a', b'b']).unwrap();
+        let state = serial.save_state();
+        assert_eq!(state.baud_divisor, 0x34);
+        assert_eq!(state.in_buffer, vec![b'a', b'b']);
+
+        let mut restored = Serial::new_sink(EventFd::new().unwrap());
+        restored.restore_state(&state);
+        assert_eq!(restored.save_state(), state);
+
+        let mut data = [0u8];
+        restored.read(u64::from(IER), &mut data[..]);
+        assert_eq!(data[0], IER_RECV_BIT);
+        restored.read(u64::from(SCR), &mut data[..]);
+        assert_eq!(data[0], 0x12);
+        restored.read(u64::from(DATA), &mut data[..]);
+        assert_eq!(data[0], b'a');
+        restored.read(u64::from(DATA), &mut data[..]);
+        assert_eq!(data[0], b'b');
+    }
+
     #[test]
     fn test_serial_data_len() {
         const LEN: usize = 1;
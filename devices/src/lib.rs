@@ -17,7 +17,13 @@ extern crate memory_model;
 extern crate net_gen;
 extern crate net_util;
 extern crate rate_limiter;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
 extern crate sys_util;
+extern crate timerfd;
 extern crate virtio_gen;
 
 use rate_limiter::Error as RateLimiterError;
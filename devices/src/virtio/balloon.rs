@@ -0,0 +1,1044 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use epoll;
+use fc_util::versioned::Versioned;
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+
+use logger::{Metric, METRICS};
+use memory_model::{GuestAddress, GuestMemory};
+use sys_util::EventFd;
+use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
+
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollConfigConstructor, Queue, VirtioDevice,
+    TYPE_BALLOON, VIRTIO_MMIO_INT_VRING,
+};
+use crate::{DeviceEventT, EpollHandler, Error as DeviceError};
+
+// See include/uapi/linux/virtio_balloon.h in the kernel code.
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+/// The size of the pages the balloon is inflated and deflated with.
+pub const BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+// The config space holds the number of pages the host asks for (`num_pages`), followed by the
+// number of pages the guest handed over (`actual`), both little endian.
+const CONFIG_SPACE_SIZE: usize = 8;
+const NUM_PAGES_OFFSET: usize = 0;
+const ACTUAL_OFFSET: usize = 4;
+const QUEUE_SIZE: u16 = 256;
+// The size of a `struct virtio_balloon_stat`: a 16 bit tag followed by a 64 bit value.
+const STAT_SIZE: usize = 10;
+
+// The inflate and deflate queues are always present, followed by the statistics and the free page
+// reporting queues, when enabled.
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+
+// New descriptors are pending on the inflate queue.
+const INFLATE_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the deflate queue.
+const DEFLATE_QUEUE_EVENT: DeviceEventT = 1;
+// The guest refreshed its memory statistics.
+const STATS_QUEUE_EVENT: DeviceEventT = 2;
+// The guest reported free pages.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 3;
+// The memory statistics are due for an update.
+const STATS_TIMER_EVENT: DeviceEventT = 4;
+// Number of DeviceEventT events supported by this implementation.
+pub const BALLOON_EVENTS_COUNT: usize = 5;
+
+#[derive(Debug)]
+enum Error {
+    /// Guest gave us a write only descriptor that protocol says to read from.
+    UnexpectedWriteOnlyDescriptor,
+    /// Guest gave us a read only descriptor that protocol says to write to.
+    UnexpectedReadOnlyDescriptor,
+    /// Guest gave us a descriptor outside of its memory.
+    GuestMemory,
+}
+
+/// The memory statistics reported by the guest. The statistics the guest did not report yet are
+/// `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BalloonStats {
+    /// The amount of memory swapped in, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_in: Option<u64>,
+    /// The amount of memory swapped out, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_out: Option<u64>,
+    /// The number of major page faults.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major_faults: Option<u64>,
+    /// The number of minor page faults.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_faults: Option<u64>,
+    /// The amount of memory not used for any purpose, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory: Option<u64>,
+    /// The total amount of memory available to the guest, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_memory: Option<u64>,
+    /// An estimate of the memory available for starting new applications, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_memory: Option<u64>,
+    /// The amount of memory used by the page cache, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_caches: Option<u64>,
+    /// The number of successful hugetlb page allocations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_allocations: Option<u64>,
+    /// The number of failed hugetlb page allocations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStats {
+    fn update(&mut self, tag: u16, val: u64) {
+        let stat = match tag {
+            VIRTIO_BALLOON_S_SWAP_IN => &mut self.swap_in,
+            VIRTIO_BALLOON_S_SWAP_OUT => &mut self.swap_out,
+            VIRTIO_BALLOON_S_MAJFLT => &mut self.major_faults,
+            VIRTIO_BALLOON_S_MINFLT => &mut self.minor_faults,
+            VIRTIO_BALLOON_S_MEMFREE => &mut self.free_memory,
+            VIRTIO_BALLOON_S_MEMTOT => &mut self.total_memory,
+            VIRTIO_BALLOON_S_AVAIL => &mut self.available_memory,
+            VIRTIO_BALLOON_S_CACHES => &mut self.disk_caches,
+            VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut self.hugetlb_allocations,
+            VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut self.hugetlb_failures,
+            // Newer drivers may report statistics we do not know about.
+            _ => return,
+        };
+        *stat = Some(val);
+    }
+}
+
+// Reads the page frame numbers held by the descriptor chain starting at `head`.
+fn read_pfns(head: DescriptorChain, mem: &GuestMemory) -> result::Result<Vec<u32>, Error> {
+    let mut pfns = Vec::new();
+    let mut desc = Some(head);
+    while let Some(d) = desc {
+        if d.is_write_only() {
+            return Err(Error::UnexpectedWriteOnlyDescriptor);
+        }
+        let mut buf = [0u8; 4];
+        for i in 0..(d.len as usize / buf.len()) {
+            let addr = d
+                .addr
+                .checked_add(i * buf.len())
+                .ok_or(Error::GuestMemory)?;
+            mem.read_slice_at_addr(&mut buf, addr)
+                .map_err(|_| Error::GuestMemory)?;
+            pfns.push(LittleEndian::read_u32(&buf));
+        }
+        desc = d.next_descriptor();
+    }
+    Ok(pfns)
+}
+
+// Coalesces the pages described by `pfns` into `(guest address, length)` ranges.
+fn page_ranges(mut pfns: Vec<u32>) -> Vec<(GuestAddress, usize)> {
+    pfns.sort_unstable();
+    pfns.dedup();
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for pfn in pfns {
+        let pfn = u64::from(pfn);
+        match ranges.last_mut() {
+            Some(&mut (first, ref mut count)) if first + *count == pfn => *count += 1,
+            _ => ranges.push((pfn, 1)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(first, count)| {
+            (
+                GuestAddress((first << VIRTIO_BALLOON_PFN_SHIFT) as usize),
+                (count << VIRTIO_BALLOON_PFN_SHIFT) as usize,
+            )
+        })
+        .collect()
+}
+
+// Releases the guest memory range `[addr, addr + len)` to the host.
+fn discard_range(mem: &GuestMemory, addr: GuestAddress, len: usize) {
+    if let Err(e) = mem.discard_range(addr, len) {
+        error!(
+            "Failed to discard balloon range 0x{:x}:0x{:x}: {:?}",
+            addr.offset(),
+            len,
+            e
+        );
+        METRICS.balloon.discard_fails.inc();
+    }
+}
+
+pub struct BalloonEpollHandler {
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+    mem: GuestMemory,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    stats_queue: Option<usize>,
+    reporting_queue: Option<usize>,
+    stats_timer: Option<TimerFd>,
+    // The driver refreshes its statistics once we hand it back the descriptor holding them.
+    stats_desc_index: Option<u16>,
+    latest_stats: BalloonStats,
+}
+
+impl BalloonEpollHandler {
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            METRICS.balloon.event_fails.inc();
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    fn read_queue_event(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.queue_evts[queue_index]
+            .read()
+            .map(|_| ())
+            .map_err(|e| {
+                error!("Failed to get balloon queue event: {:?}", e);
+                METRICS.balloon.event_fails.inc();
+                DeviceError::FailedReadingQueue {
+                    event_type: "balloon queue event",
+                    underlying: e,
+                }
+            })
+    }
+
+    fn process_inflate_queue(&mut self) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[INFLATE_QUEUE];
+        let mut pfns = Vec::new();
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+            match read_pfns(head, mem) {
+                Ok(mut desc_pfns) => pfns.append(&mut desc_pfns),
+                Err(e) => error!("Failed to read the inflated balloon pages: {:?}", e),
+            }
+            queue.add_used(mem, index, 0);
+            used_any = true;
+        }
+
+        METRICS.balloon.inflated_pages.add(pfns.len());
+        for (addr, len) in page_ranges(pfns) {
+            discard_range(mem, addr, len);
+        }
+        used_any
+    }
+
+    fn process_deflate_queue(&mut self) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[DEFLATE_QUEUE];
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            // The pages are given back to the guest the next time it touches them, there is
+            // nothing to do but keep count.
+            let index = head.index;
+            match read_pfns(head, mem) {
+                Ok(pfns) => METRICS.balloon.deflated_pages.add(pfns.len()),
+                Err(e) => error!("Failed to read the deflated balloon pages: {:?}", e),
+            }
+            queue.add_used(mem, index, 0);
+            used_any = true;
+        }
+        used_any
+    }
+
+    fn process_reporting_queue(&mut self, queue_index: usize) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[queue_index];
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+            let mut desc = Some(head);
+            while let Some(d) = desc {
+                if d.is_write_only() {
+                    discard_range(mem, d.addr, d.len as usize);
+                    METRICS.balloon.reported_ranges.inc();
+                } else {
+                    error!(
+                        "Failed to discard the reported free pages: {:?}",
+                        Error::UnexpectedReadOnlyDescriptor
+                    );
+                }
+                desc = d.next_descriptor();
+            }
+            queue.add_used(mem, index, 0);
+            used_any = true;
+        }
+        used_any
+    }
+
+    fn process_stats_queue(&mut self, queue_index: usize) -> bool {
+        let mem = &self.mem;
+        let queue = &mut self.queues[queue_index];
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            // The driver only keeps one buffer in flight, but do not leak the previous one if it
+            // misbehaves.
+            if let Some(index) = self.stats_desc_index.take() {
+                queue.add_used(mem, index, 0);
+                used_any = true;
+            }
+            if head.is_write_only() {
+                error!(
+                    "Failed to read the balloon statistics: {:?}",
+                    Error::UnexpectedWriteOnlyDescriptor
+                );
+                METRICS.balloon.stats_update_fails.inc();
+                queue.add_used(mem, head.index, 0);
+                used_any = true;
+                continue;
+            }
+
+            let mut buf = [0u8; STAT_SIZE];
+            for i in 0..(head.len as usize / STAT_SIZE) {
+                let read = head
+                    .addr
+                    .checked_add(i * STAT_SIZE)
+                    .and_then(|addr| mem.read_slice_at_addr(&mut buf, addr).ok());
+                if read.is_none() {
+                    error!(
+                        "Failed to read the balloon statistics: {:?}",
+                        Error::GuestMemory
+                    );
+                    METRICS.balloon.stats_update_fails.inc();
+                    break;
+                }
+                self.latest_stats.update(
+                    LittleEndian::read_u16(&buf[0..2]),
+                    LittleEndian::read_u64(&buf[2..]),
+                );
+            }
+            METRICS.balloon.stats_updates_count.inc();
+            self.stats_desc_index = Some(head.index);
+        }
+        used_any
+    }
+
+    fn request_stats_update(&mut self) -> bool {
+        match (self.stats_queue, self.stats_desc_index.take()) {
+            (Some(queue_index), Some(index)) => {
+                self.queues[queue_index].add_used(&self.mem, index, 0);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the latest memory statistics reported by the guest.
+    pub fn latest_stats(&self) -> &BalloonStats {
+        &self.latest_stats
+    }
+}
+
+impl EpollHandler for BalloonEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _evset: epoll::Events,
+    ) -> result::Result<(), DeviceError> {
+        let used_any = match device_event {
+            INFLATE_QUEUE_EVENT => {
+                self.read_queue_event(INFLATE_QUEUE)?;
+                self.process_inflate_queue()
+            }
+            DEFLATE_QUEUE_EVENT => {
+                self.read_queue_event(DEFLATE_QUEUE)?;
+                self.process_deflate_queue()
+            }
+            STATS_QUEUE_EVENT if self.stats_queue.is_some() => {
+                let queue_index = self.stats_queue.unwrap();
+                self.read_queue_event(queue_index)?;
+                self.process_stats_queue(queue_index)
+            }
+            REPORTING_QUEUE_EVENT if self.reporting_queue.is_some() => {
+                let queue_index = self.reporting_queue.unwrap();
+                self.read_queue_event(queue_index)?;
+                self.process_reporting_queue(queue_index)
+            }
+            STATS_TIMER_EVENT if self.stats_timer.is_some() => {
+                // The number of expirations is irrelevant, the statistics are refreshed once.
+                self.stats_timer.as_mut().unwrap().read();
+                self.request_stats_update()
+            }
+            unknown => {
+                return Err(DeviceError::UnknownEvent {
+                    device: "balloon",
+                    event: unknown,
+                });
+            }
+        };
+
+        if used_any {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct EpollConfig {
+    inflate_token: u64,
+    deflate_token: u64,
+    stats_token: u64,
+    reporting_token: u64,
+    stats_timer_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<dyn EpollHandler>>,
+}
+
+impl EpollConfigConstructor for EpollConfig {
+    fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<dyn EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            inflate_token: first_token + u64::from(INFLATE_QUEUE_EVENT),
+            deflate_token: first_token + u64::from(DEFLATE_QUEUE_EVENT),
+            stats_token: first_token + u64::from(STATS_QUEUE_EVENT),
+            reporting_token: first_token + u64::from(REPORTING_QUEUE_EVENT),
+            stats_timer_token: first_token + u64::from(STATS_TIMER_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Virtio device letting the host reclaim the memory of the guest.
+pub struct Balloon {
+    avail_features: u64,
+    acked_features: u64,
+    config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
+    stats_polling_interval_s: u16,
+    stats_timer: Option<TimerFd>,
+    free_page_reporting: bool,
+    epoll_config: EpollConfig,
+}
+
+/// The serializable state of a virtio balloon device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BalloonState {
+    /// The features offered by the device.
+    pub avail_features: u64,
+    /// The features acknowledged by the driver.
+    pub acked_features: u64,
+    /// The configuration space of the device.
+    pub config_space: Vec<u8>,
+}
+
+impl Versioned for BalloonState {
+    const NAME: &'static str = "Balloon";
+    const VERSION: u16 = 1;
+}
+
+impl Balloon {
+    /// Create a new virtio balloon device asking the guest for `num_pages` pages.
+    ///
+    /// With `deflate_on_oom`, the guest may take pages back from the balloon when it runs out
+    /// of memory. With `free_page_reporting`, the guest hands its free pages over to the host.
+    /// A non zero `stats_polling_interval_s` enables the statistics queue, which the guest
+    /// refreshes every `stats_polling_interval_s` seconds.
+    pub fn new(
+        num_pages: u32,
+        deflate_on_oom: bool,
+        free_page_reporting: bool,
+        stats_polling_interval_s: u16,
+        epoll_config: EpollConfig,
+    ) -> io::Result<Balloon> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut num_queues = 2;
+
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        let stats_timer = if stats_polling_interval_s > 0 {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+            num_queues += 1;
+            Some(TimerFd::new_custom(ClockId::Monotonic, true, true)?)
+        } else {
+            None
+        };
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+            num_queues += 1;
+        }
+
+        let mut config_space = vec![0u8; CONFIG_SPACE_SIZE];
+        LittleEndian::write_u32(&mut config_space[NUM_PAGES_OFFSET..], num_pages);
+
+        Ok(Balloon {
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            queue_sizes: vec![QUEUE_SIZE; num_queues],
+            stats_polling_interval_s,
+            stats_timer,
+            free_page_reporting,
+            epoll_config,
+        })
+    }
+
+    /// Returns the number of pages the device asks the guest for.
+    pub fn num_pages(&self) -> u32 {
+        LittleEndian::read_u32(&self.config_space[NUM_PAGES_OFFSET..])
+    }
+
+    /// Returns the number of pages the guest handed over to the device.
+    pub fn actual_pages(&self) -> u32 {
+        LittleEndian::read_u32(&self.config_space[ACTUAL_OFFSET..])
+    }
+
+    /// Returns the current state of the device.
+    pub fn save_state(&self) -> BalloonState {
+        BalloonState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config_space: self.config_space.clone(),
+        }
+    }
+
+    /// Restores the device to a previously saved state. Acknowledged features that the device
+    /// does not offer anymore are dropped.
+    pub fn restore_state(&mut self, state: &BalloonState) {
+        self.acked_features = state.acked_features & self.avail_features;
+        self.config_space = state.config_space.clone();
+    }
+
+    fn register_fd(&self, fd: RawFd, token: u64) -> ActivateResult {
+        epoll::ctl(
+            self.epoll_config.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )
+        .map_err(|e| {
+            METRICS.balloon.activate_fails.inc();
+            ActivateError::EpollCtl(e)
+        })
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        TYPE_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.balloon.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        let config_len = self.config_space.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            METRICS.balloon.cfg_fails.inc();
+            return;
+        }
+        let (_, right) = self.config_space.split_at_mut(offset as usize);
+        right[..data.len()].copy_from_slice(data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                num_queues,
+                queues.len()
+            );
+            METRICS.balloon.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        let stats_queue = if self.stats_polling_interval_s > 0 {
+            Some(DEFLATE_QUEUE + 1)
+        } else {
+            None
+        };
+        let reporting_queue = if self.free_page_reporting {
+            Some(stats_queue.unwrap_or(DEFLATE_QUEUE) + 1)
+        } else {
+            None
+        };
+
+        let mut tokens = vec![
+            self.epoll_config.inflate_token,
+            self.epoll_config.deflate_token,
+        ];
+        if stats_queue.is_some() {
+            tokens.push(self.epoll_config.stats_token);
+        }
+        if reporting_queue.is_some() {
+            tokens.push(self.epoll_config.reporting_token);
+        }
+        let queue_evt_raw_fds: Vec<RawFd> = queue_evts.iter().map(AsRawFd::as_raw_fd).collect();
+
+        let mut stats_timer = self.stats_timer.take();
+        if let Some(ref mut timer) = stats_timer {
+            let interval = Duration::from_secs(u64::from(self.stats_polling_interval_s));
+            timer.set_state(
+                TimerState::Periodic {
+                    current: interval,
+                    interval,
+                },
+                SetTimeFlags::Default,
+            );
+        }
+        let stats_timer_raw_fd = stats_timer.as_ref().map(AsRawFd::as_raw_fd);
+
+        let handler = BalloonEpollHandler {
+            queues,
+            queue_evts,
+            mem,
+            interrupt_status: status,
+            interrupt_evt,
+            stats_queue,
+            reporting_queue,
+            stats_timer,
+            stats_desc_index: None,
+            latest_stats: BalloonStats::default(),
+        };
+
+        // The channel should be open at this point.
+        self.epoll_config
+            .sender
+            .send(Box::new(handler))
+            .expect("Failed to send through the channel");
+
+        for (fd, token) in queue_evt_raw_fds.into_iter().zip(tokens) {
+            self.register_fd(fd, token)?;
+        }
+        if let Some(fd) = stats_timer_raw_fd {
+            self.register_fd(fd, self.epoll_config.stats_timer_token)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::Receiver;
+
+    use crate::virtio::queue::tests::*;
+
+    const EPOLLIN: epoll::Events = epoll::Events::EPOLLIN;
+
+    struct DummyBalloon {
+        balloon: Balloon,
+        epoll_raw_fd: i32,
+        receiver: Receiver<Box<dyn EpollHandler>>,
+    }
+
+    impl DummyBalloon {
+        fn new(deflate_on_oom: bool, free_page_reporting: bool, stats_polling: u16) -> Self {
+            let epoll_raw_fd = epoll::create(true).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+            let balloon = Balloon::new(
+                0x100,
+                deflate_on_oom,
+                free_page_reporting,
+                stats_polling,
+                epoll_config,
+            )
+            .unwrap();
+
+            DummyBalloon {
+                balloon,
+                epoll_raw_fd,
+                receiver,
+            }
+        }
+
+        fn activate(&mut self, mem: &GuestMemory, vqs: &[VirtQueue]) -> ActivateResult {
+            let queues = vqs.iter().map(VirtQueue::create_queue).collect();
+            let queue_evts = vqs.iter().map(|_| EventFd::new().unwrap()).collect();
+            self.balloon.activate(
+                mem.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                queues,
+                queue_evts,
+            )
+        }
+
+        fn handler(&mut self) -> Box<dyn EpollHandler> {
+            self.receiver.try_recv().unwrap()
+        }
+    }
+
+    fn as_balloon(handler: &mut Box<dyn EpollHandler>) -> &mut BalloonEpollHandler {
+        (**handler)
+            .as_mut_any()
+            .downcast_mut::<BalloonEpollHandler>()
+            .unwrap()
+    }
+
+    impl Drop for DummyBalloon {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.epoll_raw_fd) };
+        }
+    }
+
+    fn trigger(h: &mut BalloonEpollHandler, queue_index: usize, event: DeviceEventT) {
+        h.queue_evts[queue_index].write(1).unwrap();
+        h.handle_event(event, EPOLLIN).unwrap();
+    }
+
+    #[test]
+    fn test_virtio_device() {
+        let mut dummy = DummyBalloon::new(false, false, 0);
+        let b = &mut dummy.balloon;
+
+        assert_eq!(b.device_type(), TYPE_BALLOON);
+        assert_eq!(b.queue_max_sizes(), &[QUEUE_SIZE, QUEUE_SIZE]);
+        assert_eq!(b.avail_features(), 1u64 << VIRTIO_F_VERSION_1);
+        b.set_acked_features(b.avail_features());
+        assert_eq!(b.acked_features(), b.avail_features());
+
+        let features = DummyBalloon::new(true, true, 1).balloon.avail_features();
+        assert_eq!(
+            features,
+            (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM)
+                | (1u64 << VIRTIO_BALLOON_F_STATS_VQ)
+                | (1u64 << VIRTIO_BALLOON_F_REPORTING)
+        );
+        assert_eq!(
+            DummyBalloon::new(false, true, 0).balloon.queue_max_sizes(),
+            &[QUEUE_SIZE; 3]
+        );
+
+        // The config space holds the target and actual number of pages.
+        assert_eq!(b.num_pages(), 0x100);
+        assert_eq!(b.actual_pages(), 0);
+        let mut data = [0u8; 4];
+        b.read_config(NUM_PAGES_OFFSET as u64, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), 0x100);
+        b.write_config(ACTUAL_OFFSET as u64, &[0x10, 0, 0, 0]);
+        assert_eq!(b.actual_pages(), 0x10);
+        b.read_config(ACTUAL_OFFSET as u64, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), 0x10);
+
+        let cfg_fails = METRICS.balloon.cfg_fails.count();
+        b.read_config(CONFIG_SPACE_SIZE as u64, &mut data);
+        b.write_config(ACTUAL_OFFSET as u64 + 1, &data);
+        assert_eq!(METRICS.balloon.cfg_fails.count(), cfg_fails + 2);
+        assert_eq!(b.actual_pages(), 0x10);
+
+        let state = b.save_state();
+        let mut other = DummyBalloon::new(false, false, 0);
+        other.balloon.restore_state(&state);
+        assert_eq!(other.balloon.save_state(), state);
+    }
+
+    #[test]
+    fn test_page_ranges() {
+        assert!(page_ranges(vec![]).is_empty());
+        assert_eq!(
+            page_ranges(vec![3, 1, 2, 2, 7, 8, 5]),
+            vec![
+                (GuestAddress(0x1000), 0x3000),
+                (GuestAddress(0x5000), 0x1000),
+                (GuestAddress(0x7000), 0x2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_activate() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+        ];
+
+        let mut dummy = DummyBalloon::new(false, false, 0);
+        // Wrong number of queues.
+        assert!(dummy.activate(&mem, &vqs[..1]).is_err());
+        assert!(dummy.activate(&mem, &vqs).is_ok());
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+        assert_eq!(h.stats_queue, None);
+        assert_eq!(h.reporting_queue, None);
+        // Events of the queues that are not enabled are unknown.
+        assert!(h.handle_event(STATS_QUEUE_EVENT, EPOLLIN).is_err());
+        assert!(h.handle_event(REPORTING_QUEUE_EVENT, EPOLLIN).is_err());
+        assert!(h.handle_event(STATS_TIMER_EVENT, EPOLLIN).is_err());
+        assert!(h
+            .handle_event(BALLOON_EVENTS_COUNT as u16, EPOLLIN)
+            .is_err());
+
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+            VirtQueue::new(GuestAddress(0x2000), &mem, 16),
+        ];
+        let mut dummy = DummyBalloon::new(false, true, 0);
+        assert!(dummy.activate(&mem, &vqs).is_ok());
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+        assert_eq!(h.stats_queue, None);
+        assert_eq!(h.reporting_queue, Some(2));
+        assert!(h.stats_timer.is_none());
+
+        let mut dummy = DummyBalloon::new(false, false, 1);
+        assert!(dummy.activate(&mem, &vqs).is_ok());
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+        assert_eq!(h.stats_queue, Some(2));
+        assert_eq!(h.reporting_queue, None);
+        assert!(h.stats_timer.is_some());
+    }
+
+    #[test]
+    fn test_inflate_deflate() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+        ];
+        let mut dummy = DummyBalloon::new(true, false, 0);
+        dummy.activate(&mem, &vqs).unwrap();
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+
+        // The guest inflates the balloon with pages 0x10 to 0x12.
+        for pfn in 0x10..0x13 {
+            mem.write_obj_at_addr(1u64, GuestAddress(pfn << VIRTIO_BALLOON_PFN_SHIFT))
+                .unwrap();
+        }
+        for (i, pfn) in [0x12u32, 0x10, 0x11].iter().enumerate() {
+            mem.write_obj_at_addr(*pfn, GuestAddress(0x3000 + i * 4))
+                .unwrap();
+        }
+        vqs[INFLATE_QUEUE].dtable[0].set(0x3000, 12, 0, 0);
+        vqs[INFLATE_QUEUE].avail.ring[0].set(0);
+        vqs[INFLATE_QUEUE].avail.idx.set(1);
+
+        let inflated_pages = METRICS.balloon.inflated_pages.count();
+        trigger(h, INFLATE_QUEUE, INFLATE_QUEUE_EVENT);
+        assert_eq!(METRICS.balloon.inflated_pages.count(), inflated_pages + 3);
+        assert_eq!(vqs[INFLATE_QUEUE].used.idx.get(), 1);
+        assert_eq!(vqs[INFLATE_QUEUE].used.ring[0].get().id, 0);
+        for pfn in 0x10..0x13 {
+            assert_eq!(
+                mem.read_obj_from_addr::<u64>(GuestAddress(pfn << VIRTIO_BALLOON_PFN_SHIFT))
+                    .unwrap(),
+                0
+            );
+        }
+
+        // Pages outside of the guest memory are not discarded.
+        mem.write_obj_at_addr(0x100u32, GuestAddress(0x3000))
+            .unwrap();
+        vqs[INFLATE_QUEUE].dtable[0].set(0x3000, 4, 0, 0);
+        vqs[INFLATE_QUEUE].avail.ring[1].set(0);
+        vqs[INFLATE_QUEUE].avail.idx.set(2);
+        let discard_fails = METRICS.balloon.discard_fails.count();
+        trigger(h, INFLATE_QUEUE, INFLATE_QUEUE_EVENT);
+        assert_eq!(METRICS.balloon.discard_fails.count(), discard_fails + 1);
+        assert_eq!(vqs[INFLATE_QUEUE].used.idx.get(), 2);
+
+        // Write only descriptors are rejected, but still returned to the guest.
+        vqs[INFLATE_QUEUE].dtable[0].set(0x3000, 4, VIRTQ_DESC_F_WRITE, 0);
+        vqs[INFLATE_QUEUE].avail.ring[2].set(0);
+        vqs[INFLATE_QUEUE].avail.idx.set(3);
+        let inflated_pages = METRICS.balloon.inflated_pages.count();
+        trigger(h, INFLATE_QUEUE, INFLATE_QUEUE_EVENT);
+        assert_eq!(METRICS.balloon.inflated_pages.count(), inflated_pages);
+        assert_eq!(vqs[INFLATE_QUEUE].used.idx.get(), 3);
+
+        // The guest deflates the balloon.
+        vqs[DEFLATE_QUEUE].dtable[0].set(0x3000, 8, 0, 0);
+        vqs[DEFLATE_QUEUE].avail.ring[0].set(0);
+        vqs[DEFLATE_QUEUE].avail.idx.set(1);
+        let deflated_pages = METRICS.balloon.deflated_pages.count();
+        trigger(h, DEFLATE_QUEUE, DEFLATE_QUEUE_EVENT);
+        assert_eq!(METRICS.balloon.deflated_pages.count(), deflated_pages + 2);
+        assert_eq!(vqs[DEFLATE_QUEUE].used.idx.get(), 1);
+
+        // Nothing is pending.
+        trigger(h, DEFLATE_QUEUE, DEFLATE_QUEUE_EVENT);
+        assert_eq!(vqs[DEFLATE_QUEUE].used.idx.get(), 1);
+    }
+
+    #[test]
+    fn test_free_page_reporting() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+            VirtQueue::new(GuestAddress(0x2000), &mem, 16),
+        ];
+        let mut dummy = DummyBalloon::new(false, true, 0);
+        dummy.activate(&mem, &vqs).unwrap();
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+
+        mem.write_obj_at_addr(1u64, GuestAddress(0x10000)).unwrap();
+        mem.write_obj_at_addr(1u64, GuestAddress(0x13000)).unwrap();
+        vqs[2].dtable[0].set(0x10000, 0x2000, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        vqs[2].dtable[1].set(0x13000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vqs[2].avail.ring[0].set(0);
+        vqs[2].avail.idx.set(1);
+
+        let reported_ranges = METRICS.balloon.reported_ranges.count();
+        trigger(h, 2, REPORTING_QUEUE_EVENT);
+        assert_eq!(METRICS.balloon.reported_ranges.count(), reported_ranges + 2);
+        assert_eq!(vqs[2].used.idx.get(), 1);
+        assert_eq!(vqs[2].used.ring[0].get().len, 0);
+        assert_eq!(
+            mem.read_obj_from_addr::<u64>(GuestAddress(0x10000))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u64>(GuestAddress(0x13000))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let vqs = [
+            VirtQueue::new(GuestAddress(0), &mem, 16),
+            VirtQueue::new(GuestAddress(0x1000), &mem, 16),
+            VirtQueue::new(GuestAddress(0x2000), &mem, 16),
+            VirtQueue::new(GuestAddress(0x3000), &mem, 16),
+        ];
+        let mut dummy = DummyBalloon::new(false, true, 1);
+        dummy.activate(&mem, &vqs).unwrap();
+        let mut handler = dummy.handler();
+        let h = as_balloon(&mut handler);
+        assert_eq!(h.stats_queue, Some(2));
+        assert_eq!(h.reporting_queue, Some(3));
+
+        // No update is requested before the guest hands over its statistics.
+        assert!(!h.request_stats_update());
+
+        let stats: [(u16, u64); 3] = [
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x8000),
+            // Unknown statistics are ignored.
+            (0x100, 1),
+        ];
+        let mut buf = [0u8; STAT_SIZE];
+        for (i, &(tag, val)) in stats.iter().enumerate() {
+            LittleEndian::write_u16(&mut buf[0..2], tag);
+            LittleEndian::write_u64(&mut buf[2..], val);
+            mem.write_slice_at_addr(&buf, GuestAddress(0x5000 + i * STAT_SIZE))
+                .unwrap();
+        }
+        vqs[2].dtable[0].set(0x5000, (stats.len() * STAT_SIZE) as u32, 0, 0);
+        vqs[2].avail.ring[0].set(0);
+        vqs[2].avail.idx.set(1);
+
+        let stats_updates = METRICS.balloon.stats_updates_count.count();
+        trigger(h, 2, STATS_QUEUE_EVENT);
+        assert_eq!(
+            METRICS.balloon.stats_updates_count.count(),
+            stats_updates + 1
+        );
+        // The descriptor is held until the next update is due.
+        assert_eq!(vqs[2].used.idx.get(), 0);
+        assert_eq!(
+            h.latest_stats(),
+            &BalloonStats {
+                free_memory: Some(0x1000),
+                total_memory: Some(0x8000),
+                ..Default::default()
+            }
+        );
+
+        assert!(h.request_stats_update());
+        assert_eq!(vqs[2].used.idx.get(), 1);
+        assert_eq!(vqs[2].used.ring[0].get().id, 0);
+        assert!(!h.request_stats_update());
+
+        // The statistics are only replaced once the guest reports them again.
+        LittleEndian::write_u16(&mut buf[0..2], VIRTIO_BALLOON_S_MEMFREE);
+        LittleEndian::write_u64(&mut buf[2..], 0x2000);
+        mem.write_slice_at_addr(&buf, GuestAddress(0x5000)).unwrap();
+        vqs[2].dtable[0].set(0x5000, STAT_SIZE as u32, 0, 0);
+        vqs[2].avail.ring[1].set(0);
+        vqs[2].avail.idx.set(2);
+        trigger(h, 2, STATS_QUEUE_EVENT);
+        assert_eq!(h.latest_stats().free_memory, Some(0x2000));
+        assert_eq!(h.latest_stats().total_memory, Some(0x8000));
+    }
+}
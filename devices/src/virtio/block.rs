@@ -6,41 +6,332 @@
 // found in the THIRD-PARTY file.
 
 use epoll;
+use fc_util::versioned::Versioned;
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
 
-use logger::{Metric, METRICS};
+use logger::metrics::{BlockDeviceMetrics, DeviceMetrics};
+use logger::METRICS;
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
-use rate_limiter::{RateLimiter, TokenType};
-use sys_util::EventFd;
+use rate_limiter::{RateLimiter, TokenBucket, TokenType};
+use sys_util::{EventFd, IoUring};
 use virtio_gen::virtio_blk::*;
 
 use super::{
-    ActivateError, ActivateResult, DescriptorChain, EpollConfigConstructor, Queue, VirtioDevice,
-    TYPE_BLOCK, VIRTIO_MMIO_INT_VRING,
+    is_nbd_uri, ActivateError, ActivateResult, BlockTrace, DescriptorChain, DirectFile,
+    EpollConfigConstructor, NbdAddress, NbdDisk, OverlayFile, QcowFile, Queue, TraceRecord,
+    VirtioDevice, TYPE_BLOCK, VIRTIO_MMIO_INT_VRING,
 };
 use crate::{DeviceEventT, EpollHandler, Error as DeviceError};
 
-const CONFIG_SPACE_SIZE: usize = 8;
+const CONFIG_SPACE_SIZE: usize = 60;
+// Offset of the number of request queues in the configuration space.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+// Offset of the discard and write zeroes limits in the configuration space.
+const CONFIG_DISCARD_OFFSET: usize = 36;
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 1;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+/// Maximum number of request queues of a block device.
+pub const MAX_NUM_QUEUES: u16 = 16;
+// Size of the segments describing the ranges of discard and write zeroes requests.
+const DISCARD_SEGMENT_SIZE: u32 = 16;
+// Number of segments accepted in a single discard or write zeroes request.
+const MAX_DISCARD_SEGMENTS: u32 = 16;
+// Sectors of a single discard or write zeroes request, so that its size in bytes fits a u32.
+const MAX_DISCARD_SECTORS: u32 = 0xffff_ffff >> SECTOR_SHIFT;
+// Host file systems deallocate whole blocks, which are usually 4 KiB large.
+const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
 
-// New descriptors are pending on the virtio queue.
-const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 // Rate limiter budget is now available.
-const RATE_LIMITER_EVENT: DeviceEventT = 1;
-// Number of DeviceEventT events supported by this implementation.
-pub const BLOCK_EVENTS_COUNT: usize = 2;
+const RATE_LIMITER_EVENT: DeviceEventT = 0;
+// Requests submitted to the asynchronous I/O engine or to the NBD worker completed.
+const IO_COMPLETION_EVENT: DeviceEventT = 1;
+// New descriptors are pending on the first virtio queue. The events of the other queues follow.
+const QUEUE_AVAIL_EVENT: DeviceEventT = 2;
+
+/// Number of DeviceEventT events supported by a block device with `num_queues` request queues.
+pub fn block_events_count(num_queues: u16) -> usize {
+    QUEUE_AVAIL_EVENT as usize + num_queues as usize
+}
+
+/// The engine used by a block device for accessing its backing file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum IoEngine {
+    /// Requests are executed one at a time, with blocking system calls.
+    Sync,
+    /// Requests are submitted to an io_uring and completed when the kernel signals them.
+    Async,
+}
+
+impl Default for IoEngine {
+    fn default() -> Self {
+        IoEngine::Sync
+    }
+}
+
+/// The format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ImageFormat {
+    /// The file holds the disk contents as is.
+    Raw,
+    /// The file is a qcow2 image, possibly backed by other images.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Raw
+    }
+}
+
+/// How a block device uses the host page cache.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum CacheType {
+    /// The data goes through the page cache, and the flushes requested by the guest are ignored.
+    Unsafe,
+    /// The data goes through the page cache, which is written to the storage on flushes.
+    Writeback,
+    /// The page cache is bypassed, and flushes are written to the storage.
+    Direct,
+}
+
+impl Default for CacheType {
+    fn default() -> Self {
+        CacheType::Writeback
+    }
+}
+
+/// Operations on the backing file of a block device, besides reading and writing it.
+pub trait DiskFile: Read + Write + Seek {
+    /// Deallocates `len` bytes at `offset`, which then read as zeroes.
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()>;
+    /// Hints that `len` bytes at `offset` are no longer used, leaving their contents unspecified.
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.punch_hole(offset, len)
+    }
+    /// Makes `len` bytes at `offset` read as zeroes, keeping them allocated.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()>;
+    /// Writes the data cached by the host to the storage device.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl DiskFile for File {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        fallocate(
+            self.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fallocate(
+            self.as_raw_fd(),
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        ) {
+            // Zeroes are then written the slow way.
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zero_bytes(self, offset, len)
+            }
+            result => result,
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.zero_range(offset, len, true)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.zero_range(offset, len, false)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        // The metadata is written through, along with the data.
+        self.file().sync_data()
+    }
+}
+
+// Writes `len` zeroes at `offset`, for the files which cannot zero a range otherwise.
+pub(crate) fn write_zero_bytes<T: Write + Seek>(
+    disk: &mut T,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    let zeroes = [0u8; 4096];
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = cmp::min(remaining, zeroes.len() as u64) as usize;
+        disk.write_all(&zeroes[..count])?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+/// The disk image backing a block device, in one of the supported formats.
+pub enum DiskImage {
+    /// A raw disk image.
+    Raw(File),
+    /// A qcow2 disk image.
+    Qcow2(Box<QcowFile>),
+    /// A read only disk image, with the written sectors redirected to an overlay file.
+    Overlay(Box<OverlayFile>),
+    /// A raw disk image accessed with direct I/O.
+    Direct(DirectFile),
+    /// A raw disk image served by an NBD server.
+    Nbd(NbdDisk),
+}
+
+impl DiskImage {
+    /// Opens the disk image held by `file`, found at `path`, in the given format.
+    pub fn new(file: File, format: ImageFormat, path: &Path) -> io::Result<DiskImage> {
+        match format {
+            ImageFormat::Raw => Ok(DiskImage::Raw(file)),
+            ImageFormat::Qcow2 => Ok(DiskImage::Qcow2(Box::new(QcowFile::from(file, path)?))),
+        }
+    }
+
+    /// Redirects the writes to `base` to the `overlay` file, which is initialized if empty.
+    pub fn with_overlay(base: DiskImage, overlay: File) -> io::Result<DiskImage> {
+        Ok(DiskImage::Overlay(Box::new(OverlayFile::open(
+            base, overlay,
+        )?)))
+    }
+
+    /// Returns the file holding the disk image, or its overlay. NBD exports have none.
+    pub fn file(&self) -> Option<&File> {
+        match *self {
+            DiskImage::Raw(ref file) => Some(file),
+            DiskImage::Qcow2(ref qcow) => Some(qcow.file()),
+            DiskImage::Overlay(ref overlay) => Some(overlay.file()),
+            DiskImage::Direct(ref direct) => Some(direct.file()),
+            DiskImage::Nbd(_) => None,
+        }
+    }
+
+    /// Returns true if the disk image is a raw file, accessed through the host page cache.
+    pub fn is_raw(&self) -> bool {
+        match *self {
+            DiskImage::Raw(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Read for DiskImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.read(buf),
+            DiskImage::Qcow2(ref mut qcow) => qcow.read(buf),
+            DiskImage::Overlay(ref mut overlay) => overlay.read(buf),
+            DiskImage::Direct(ref mut direct) => direct.read(buf),
+            DiskImage::Nbd(ref mut nbd) => nbd.read(buf),
+        }
+    }
+}
+
+impl Write for DiskImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.write(buf),
+            DiskImage::Qcow2(ref mut qcow) => qcow.write(buf),
+            DiskImage::Overlay(ref mut overlay) => overlay.write(buf),
+            DiskImage::Direct(ref mut direct) => direct.write(buf),
+            DiskImage::Nbd(ref mut nbd) => nbd.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.flush(),
+            DiskImage::Qcow2(ref mut qcow) => qcow.flush(),
+            DiskImage::Overlay(ref mut overlay) => overlay.flush(),
+            DiskImage::Direct(ref mut direct) => direct.flush(),
+            DiskImage::Nbd(ref mut nbd) => nbd.flush(),
+        }
+    }
+}
+
+impl Seek for DiskImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.seek(pos),
+            DiskImage::Qcow2(ref mut qcow) => qcow.seek(pos),
+            DiskImage::Overlay(ref mut overlay) => overlay.seek(pos),
+            DiskImage::Direct(ref mut direct) => direct.seek(pos),
+            DiskImage::Nbd(ref mut nbd) => nbd.seek(pos),
+        }
+    }
+}
+
+impl DiskFile for DiskImage {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.punch_hole(offset, len),
+            DiskImage::Qcow2(ref mut qcow) => qcow.punch_hole(offset, len),
+            DiskImage::Overlay(ref mut overlay) => overlay.punch_hole(offset, len),
+            DiskImage::Direct(ref mut direct) => direct.punch_hole(offset, len),
+            DiskImage::Nbd(ref mut nbd) => nbd.punch_hole(offset, len),
+        }
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match *self {
+            DiskImage::Nbd(ref mut nbd) => nbd.discard(offset, len),
+            _ => self.punch_hole(offset, len),
+        }
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.write_zeroes(offset, len),
+            DiskImage::Qcow2(ref mut qcow) => qcow.write_zeroes(offset, len),
+            DiskImage::Overlay(ref mut overlay) => overlay.write_zeroes(offset, len),
+            DiskImage::Direct(ref mut direct) => direct.write_zeroes(offset, len),
+            DiskImage::Nbd(ref mut nbd) => nbd.write_zeroes(offset, len),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match *self {
+            DiskImage::Raw(ref mut file) => file.sync(),
+            DiskImage::Qcow2(ref mut qcow) => qcow.sync(),
+            DiskImage::Overlay(ref mut overlay) => overlay.sync(),
+            DiskImage::Direct(ref mut direct) => direct.sync(),
+            DiskImage::Nbd(ref mut nbd) => nbd.sync(),
+        }
+    }
+}
+
+/// Returns the size of the virtual disk held by the image at `path`.
+pub fn disk_image_size(path: &Path, format: ImageFormat) -> io::Result<u64> {
+    if is_nbd_uri(path) {
+        return NbdDisk::connect(&NbdAddress::parse(path)?, false)?.seek(SeekFrom::End(0));
+    }
+    let file = File::open(path)?;
+    DiskImage::new(file, format, path)?.seek(SeekFrom::End(0))
+}
 
 #[derive(Debug)]
 enum Error {
@@ -60,27 +351,35 @@ enum Error {
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// Guest gave us a discard or write zeroes request with an invalid number of segments.
+    InvalidSegmentCount(u32),
 }
 
 #[derive(Debug)]
 enum ExecuteError {
     BadRequest(Error),
+    Fallocate(io::Error),
     Flush(io::Error),
     Read(GuestMemoryError),
     Seek(io::Error),
+    Submit,
     Write(GuestMemoryError),
     Unsupported(u32),
+    UnsupportedFlags(u32),
 }
 
 impl ExecuteError {
     fn status(&self) -> u32 {
         match *self {
             ExecuteError::BadRequest(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Fallocate(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Submit => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::UnsupportedFlags(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
 }
@@ -91,9 +390,26 @@ enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
+impl RequestType {
+    // Returns the `VIRTIO_BLK_T_*` value of the request type.
+    fn raw(self) -> u32 {
+        match self {
+            RequestType::In => VIRTIO_BLK_T_IN,
+            RequestType::Out => VIRTIO_BLK_T_OUT,
+            RequestType::Flush => VIRTIO_BLK_T_FLUSH,
+            RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+            RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+            RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
+            RequestType::Unsupported(t) => t,
+        }
+    }
+}
+
 fn request_type(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<RequestType, Error> {
     let type_ = mem
         .read_obj_from_addr(desc_addr)
@@ -103,6 +419,8 @@ fn request_type(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<Re
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -117,6 +435,43 @@ fn sector(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<u64, Err
     mem.read_obj_from_addr(addr).map_err(Error::GuestMemory)
 }
 
+// A range of sectors to discard or to fill with zeroes.
+#[derive(Debug, PartialEq)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl DiscardSegment {
+    fn read(mem: &GuestMemory, addr: GuestAddress) -> result::Result<DiscardSegment, Error> {
+        let read_field = |offset: usize| {
+            mem.checked_offset(addr, offset)
+                .ok_or(Error::CheckedOffset(addr, offset))
+        };
+        Ok(DiscardSegment {
+            sector: mem.read_obj_from_addr(addr).map_err(Error::GuestMemory)?,
+            num_sectors: mem
+                .read_obj_from_addr(read_field(8)?)
+                .map_err(Error::GuestMemory)?,
+            flags: mem
+                .read_obj_from_addr(read_field(12)?)
+                .map_err(Error::GuestMemory)?,
+        })
+    }
+}
+
+// Runs fallocate(2) on `fd` for the given range.
+pub(crate) fn fallocate(fd: RawFd, mode: i32, offset: u64, len: u64) -> io::Result<()> {
+    // This is safe because fallocate only changes the allocation of the file and we check the
+    // return value.
+    let ret = unsafe { libc::fallocate(fd, mode, offset as libc::off_t, len as libc::off_t) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 fn build_device_id(disk_image: &File) -> result::Result<String, Error> {
     let blk_metadata = match disk_image.metadata() {
         Err(_) => return Err(Error::GetFileMetadata),
@@ -133,9 +488,16 @@ fn build_device_id(disk_image: &File) -> result::Result<String, Error> {
     Ok(device_id)
 }
 
-fn build_disk_image_id(disk_image: &File) -> Vec<u8> {
+fn build_disk_image_id(disk_image: &DiskImage) -> Vec<u8> {
     let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
-    match build_device_id(disk_image) {
+    let device_id = match *disk_image {
+        // NBD exports are identified by the address of their server.
+        DiskImage::Nbd(ref nbd) => Ok(nbd.address().to_string()),
+        _ => disk_image
+            .file()
+            .map_or(Err(Error::GetFileMetadata), build_device_id),
+    };
+    match device_id {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
         }
@@ -150,6 +512,7 @@ fn build_disk_image_id(disk_image: &File) -> Vec<u8> {
     default_disk_image_id
 }
 
+#[derive(Clone, Copy)]
 struct Request {
     request_type: RequestType,
     sector: u64,
@@ -159,6 +522,17 @@ struct Request {
 }
 
 impl Request {
+    // Returns the token types the request is charged to by the rate limiter: the ops one and, for
+    // data transfers, the bytes one.
+    fn token_types(&self) -> (TokenType, Option<TokenType>) {
+        match self.request_type {
+            RequestType::In => (TokenType::ReadOps, Some(TokenType::ReadBytes)),
+            RequestType::Out => (TokenType::WriteOps, Some(TokenType::WriteBytes)),
+            RequestType::Discard | RequestType::WriteZeroes => (TokenType::WriteOps, None),
+            _ => (TokenType::Ops, None),
+        }
+    }
+
     fn parse(avail_desc: &DescriptorChain, mem: &GuestMemory) -> result::Result<Request, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -191,7 +565,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -219,13 +597,7 @@ impl Request {
         Ok(req)
     }
 
-    fn execute<T: Seek + Read + Write>(
-        &self,
-        disk: &mut T,
-        disk_nsectors: u64,
-        mem: &GuestMemory,
-        disk_id: &[u8],
-    ) -> result::Result<u32, ExecuteError> {
+    fn check_bounds(&self, disk_nsectors: u64) -> result::Result<(), ExecuteError> {
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -236,6 +608,131 @@ impl Request {
         if top > disk_nsectors {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
+        Ok(())
+    }
+
+    // Returns the ranges accessed by the request, as their first sector, their length in bytes
+    // and their flags. Discard and write zeroes requests access the ranges of their segments.
+    fn traced_ranges(&self, mem: &GuestMemory) -> Vec<(u64, u32, u16)> {
+        if self.request_type == RequestType::Discard
+            || self.request_type == RequestType::WriteZeroes
+        {
+            let segments: Option<Vec<(u64, u32, u16)>> = (0..self.data_len / DISCARD_SEGMENT_SIZE)
+                .take(MAX_DISCARD_SEGMENTS as usize)
+                .map(|i| {
+                    let addr = self
+                        .data_addr
+                        .checked_add((i * DISCARD_SEGMENT_SIZE) as usize)?;
+                    let segment = DiscardSegment::read(mem, addr).ok()?;
+                    let len = segment.num_sectors.checked_mul(SECTOR_SIZE as u32)?;
+                    Some((segment.sector, len, segment.flags as u16))
+                })
+                .collect();
+            // Requests with invalid segments are traced as they were received.
+            if let Some(segments) = segments.filter(|segments| !segments.is_empty()) {
+                return segments;
+            }
+        }
+        vec![(self.sector, self.data_len, 0)]
+    }
+
+    // Only the requests accessing the backing file go through the asynchronous I/O engine.
+    fn is_async(&self, cache_type: CacheType) -> bool {
+        match self.request_type {
+            RequestType::In | RequestType::Out => true,
+            RequestType::Flush => cache_type != CacheType::Unsafe,
+            _ => false,
+        }
+    }
+
+    // Discards or fills with zeroes the ranges described by the segments of the request.
+    fn execute_discard<T: DiskFile>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+        metrics: &DeviceMetrics<BlockDeviceMetrics>,
+    ) -> result::Result<u32, ExecuteError> {
+        let num_segments = self.data_len / DISCARD_SEGMENT_SIZE;
+        if self.data_len % DISCARD_SEGMENT_SIZE != 0
+            || num_segments == 0
+            || num_segments > MAX_DISCARD_SEGMENTS
+        {
+            return Err(ExecuteError::BadRequest(Error::InvalidSegmentCount(
+                num_segments,
+            )));
+        }
+
+        for i in 0..num_segments {
+            let addr = self
+                .data_addr
+                .checked_add((i * DISCARD_SEGMENT_SIZE) as usize)
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            let segment = DiscardSegment::read(mem, addr).map_err(ExecuteError::BadRequest)?;
+            let top = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors || segment.num_sectors > MAX_DISCARD_SECTORS {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+
+            let offset = segment.sector << SECTOR_SHIFT;
+            let len = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+            let unmap = match self.request_type {
+                RequestType::Discard if segment.flags == 0 => true,
+                RequestType::WriteZeroes
+                    if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP == 0 =>
+                {
+                    segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                }
+                _ => return Err(ExecuteError::UnsupportedFlags(segment.flags)),
+            };
+            let result = if self.request_type == RequestType::Discard {
+                disk.discard(offset, len)
+            } else if unmap {
+                disk.punch_hole(offset, len)
+            } else {
+                disk.write_zeroes(offset, len)
+            };
+
+            match result {
+                Ok(()) => (),
+                // Discarding is only a hint, which the backing file may not support.
+                Err(ref e)
+                    if e.raw_os_error() == Some(libc::EOPNOTSUPP)
+                        && self.request_type == RequestType::Discard => {}
+                // The range is then zeroed without being deallocated.
+                Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => disk
+                    .write_zeroes(offset, len)
+                    .map_err(ExecuteError::Fallocate)?,
+                Err(e) => return Err(ExecuteError::Fallocate(e)),
+            }
+        }
+
+        if self.request_type == RequestType::Discard {
+            metrics.inc(|m| &m.discard_count);
+        } else {
+            metrics.inc(|m| &m.write_zeroes_count);
+        }
+        Ok(0)
+    }
+
+    fn execute<T: DiskFile>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+        disk_id: &[u8],
+        cache_type: CacheType,
+        metrics: &DeviceMetrics<BlockDeviceMetrics>,
+    ) -> result::Result<u32, ExecuteError> {
+        match self.request_type {
+            RequestType::Discard | RequestType::WriteZeroes => {
+                return self.execute_discard(disk, disk_nsectors, mem, metrics);
+            }
+            _ => self.check_bounds(disk_nsectors)?,
+        }
 
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
@@ -244,23 +741,25 @@ impl Request {
             RequestType::In => {
                 mem.read_to_memory(self.data_addr, disk, self.data_len as usize)
                     .map_err(ExecuteError::Read)?;
-                METRICS.block.read_bytes.add(self.data_len as usize);
-                METRICS.block.read_count.inc();
+                metrics.add(|m| &m.read_bytes, self.data_len as usize);
+                metrics.inc(|m| &m.read_count);
                 return Ok(self.data_len);
             }
             RequestType::Out => {
                 mem.write_from_memory(self.data_addr, disk, self.data_len as usize)
                     .map_err(ExecuteError::Write)?;
-                METRICS.block.write_bytes.add(self.data_len as usize);
-                METRICS.block.write_count.inc();
+                metrics.add(|m| &m.write_bytes, self.data_len as usize);
+                metrics.inc(|m| &m.write_count);
             }
-            RequestType::Flush => match disk.flush() {
-                Ok(_) => {
-                    METRICS.block.flush_count.inc();
-                    return Ok(0);
+            RequestType::Flush => {
+                disk.flush().map_err(ExecuteError::Flush)?;
+                // The data of unsafe devices is left in the host page cache.
+                if cache_type != CacheType::Unsafe {
+                    disk.sync().map_err(ExecuteError::Flush)?;
                 }
-                Err(e) => return Err(ExecuteError::Flush(e)),
-            },
+                metrics.inc(|m| &m.flush_count);
+                return Ok(0);
+            }
             RequestType::GetDeviceID => {
                 if (self.data_len as usize) < disk_id.len() {
                     return Err(ExecuteError::BadRequest(Error::InvalidOffset));
@@ -268,150 +767,734 @@ impl Request {
                 mem.write_slice_at_addr(disk_id, self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard | RequestType::WriteZeroes => unreachable!(),
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
 }
 
-/// Handler that drives the execution of the Block devices
-pub struct BlockEpollHandler {
-    queues: Vec<Queue>,
-    mem: GuestMemory,
-    disk_image: File,
-    disk_nsectors: u64,
-    interrupt_status: Arc<AtomicUsize>,
-    interrupt_evt: EventFd,
-    queue_evt: EventFd,
-    rate_limiter: RateLimiter,
-    disk_image_id: Vec<u8>,
+// A request handed over to the asynchronous I/O engine, waiting for its completion.
+struct PendingRequest {
+    request_type: RequestType,
+    sector: u64,
+    data_addr: GuestAddress,
+    data_len: u32,
+    status_addr: GuestAddress,
+    // When the device started handling the request, for traces.
+    start: Instant,
 }
 
-impl BlockEpollHandler {
-    fn process_queue(&mut self, queue_index: usize) -> bool {
-        let queue = &mut self.queues[queue_index];
-        let mut used_any = false;
-
-        while let Some(head) = queue.pop(&self.mem) {
-            let len;
-            match Request::parse(&head, &self.mem) {
-                Ok(request) => {
-                    // If limiter.consume() fails it means there is no more TokenType::Ops
-                    // budget and rate limiting is in effect.
-                    if !self.rate_limiter.consume(1, TokenType::Ops) {
-                        // Stop processing the queue and return this descriptor chain to the
-                        // avail ring, for later processing.
-                        queue.undo_pop();
-                        break;
-                    }
-                    // Exercise the rate limiter only if this request is of data transfer type.
-                    if request.request_type == RequestType::In
-                        || request.request_type == RequestType::Out
-                    {
-                        // If limiter.consume() fails it means there is no more TokenType::Bytes
-                        // budget and rate limiting is in effect.
-                        if !self
-                            .rate_limiter
-                            .consume(u64::from(request.data_len), TokenType::Bytes)
-                        {
-                            // Revert the OPS consume().
-                            self.rate_limiter.manual_replenish(1, TokenType::Ops);
-                            // Stop processing the queue and return this descriptor chain to the
-                            // avail ring, for later processing.
-                            queue.undo_pop();
-                            break;
-                        }
-                    }
-                    let status = match request.execute(
-                        &mut self.disk_image,
-                        self.disk_nsectors,
-                        &self.mem,
-                        &self.disk_image_id,
-                    ) {
-                        Ok(l) => {
-                            len = l;
-                            VIRTIO_BLK_S_OK
-                        }
-                        Err(e) => {
-                            error!("Failed to execute request: {:?}", e);
-                            METRICS.block.invalid_reqs_count.inc();
-                            len = 1; // We need at least 1 byte for the status.
-                            e.status()
-                        }
-                    };
-                    // We use unwrap because the request parsing process already checked that the
-                    // status_addr was valid.
-                    self.mem
-                        .write_obj_at_addr(status, request.status_addr)
-                        .unwrap();
-                }
-                Err(e) => {
-                    error!("Failed to parse available descriptor chain: {:?}", e);
-                    METRICS.block.execute_fails.inc();
-                    len = 0;
-                }
+impl PendingRequest {
+    // Returns the status of the request and the number of bytes written to guest memory.
+    fn complete(
+        &self,
+        result: i32,
+        mem: &GuestMemory,
+        metrics: &DeviceMetrics<BlockDeviceMetrics>,
+    ) -> (u32, u32) {
+        let expected = match self.request_type {
+            RequestType::Flush => 0,
+            _ => self.data_len as i32,
+        };
+        if result != expected {
+            if result < 0 {
+                error!(
+                    "Failed to execute request: {:?}",
+                    io::Error::from_raw_os_error(-result)
+                );
+            } else {
+                error!(
+                    "Failed to execute request: transferred {} bytes out of {}",
+                    result, expected
+                );
             }
-            queue.add_used(&self.mem, head.index, len);
-            used_any = true;
+            metrics.inc(|m| &m.invalid_reqs_count);
+            // We need at least 1 byte for the status.
+            return (VIRTIO_BLK_S_IOERR, 1);
         }
 
-        used_any
+        match self.request_type {
+            RequestType::In => {
+                // We use unwrap because the range was checked when the request was pushed.
+                mem.mark_dirty(self.data_addr, self.data_len as usize)
+                    .unwrap();
+                metrics.add(|m| &m.read_bytes, self.data_len as usize);
+                metrics.inc(|m| &m.read_count);
+                (VIRTIO_BLK_S_OK, self.data_len)
+            }
+            RequestType::Out => {
+                metrics.add(|m| &m.write_bytes, self.data_len as usize);
+                metrics.inc(|m| &m.write_count);
+                (VIRTIO_BLK_S_OK, 0)
+            }
+            _ => {
+                metrics.inc(|m| &m.flush_count);
+                (VIRTIO_BLK_S_OK, 0)
+            }
+        }
     }
+}
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).map_err(|e| {
-            error!("Failed to signal used queue: {:?}", e);
-            METRICS.block.event_fails.inc();
-            DeviceError::FailedSignalingUsedQueue(e)
-        })
-    }
+// The io_uring based I/O engine of a block device.
+struct AsyncIo {
+    ring: IoUring,
+    completion_evt: EventFd,
+    // The requests in flight, indexed by their queue and the head of their descriptor chain.
+    pending: HashMap<u64, PendingRequest>,
+}
 
-    /// Update the backing file for the Block device
-    pub fn update_disk_image(&mut self, disk_image: File) -> result::Result<(), DeviceError> {
-        self.disk_image = disk_image;
-        self.disk_nsectors = self
-            .disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
-        self.disk_image_id = build_disk_image_id(&self.disk_image);
-        METRICS.block.update_count.inc();
-        Ok(())
-    }
+// Identifies a request in flight, as the head of its descriptor chain is only unique within its
+// queue.
+fn request_key(queue_index: usize, head_index: u16) -> u64 {
+    ((queue_index as u64) << 16) | u64::from(head_index)
 }
 
-impl EpollHandler for BlockEpollHandler {
-    fn handle_event(
+impl AsyncIo {
+    fn new(num_queues: u16) -> io::Result<AsyncIo> {
+        let ring = IoUring::new(u32::from(QUEUE_SIZE) * u32::from(num_queues))?;
+        let completion_evt = EventFd::new()?;
+        ring.register_eventfd(&completion_evt)?;
+        Ok(AsyncIo {
+            ring,
+            completion_evt,
+            pending: HashMap::new(),
+        })
+    }
+
+    fn push(
         &mut self,
-        device_event: DeviceEventT,
+        request: &Request,
+        queue_index: usize,
+        head_index: u16,
+        disk: &File,
+        disk_nsectors: u64,
+        mem: &GuestMemory,
+    ) -> result::Result<(), ExecuteError> {
+        request.check_bounds(disk_nsectors)?;
+
+        let fd = disk.as_raw_fd();
+        let offset = request.sector << SECTOR_SHIFT;
+        let user_data = request_key(queue_index, head_index);
+        match request.request_type {
+            RequestType::In => {
+                let buf = mem
+                    .get_host_address_range(request.data_addr, request.data_len as usize)
+                    .map_err(ExecuteError::Read)?;
+                // This is safe because guest memory outlives the ring, which waits for the
+                // requests in flight when dropped.
+                unsafe {
+                    self.ring
+                        .push_read(fd, buf, request.data_len, offset, user_data)
+                }
+            }
+            RequestType::Out => {
+                let buf = mem
+                    .get_host_address_range(request.data_addr, request.data_len as usize)
+                    .map_err(ExecuteError::Write)?;
+                // This is safe for the same reason as above.
+                unsafe {
+                    self.ring
+                        .push_write(fd, buf, request.data_len, offset, user_data)
+                }
+            }
+            _ => self.ring.push_fdatasync(fd, user_data),
+        }
+        .map_err(|e| {
+            error!("Failed to push block request: {:?}", e);
+            ExecuteError::Submit
+        })?;
+
+        self.pending.insert(
+            user_data,
+            PendingRequest {
+                request_type: request.request_type,
+                sector: request.sector,
+                data_addr: request.data_addr,
+                data_len: request.data_len,
+                status_addr: request.status_addr,
+                start: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    // Waits for the submitted requests to complete. Their completions are still processed on the
+    // next completion event.
+    fn wait_in_flight(&mut self) -> io::Result<()> {
+        self.ring.submit()?;
+        match self.ring.pending() {
+            0 => Ok(()),
+            count => self.ring.wait(count),
+        }
+    }
+}
+
+impl Drop for AsyncIo {
+    fn drop(&mut self) {
+        // The kernel must not access guest memory or the backing file after they are released.
+        if let Err(e) = self.wait_in_flight() {
+            error!("Failed to wait for the block requests in flight: {:?}", e);
+        }
+    }
+}
+
+// A request handed over to the NBD worker.
+struct NbdJob {
+    // Identifies the request, as in `request_key`.
+    key: u64,
+    request: Request,
+    // When the device started handling the request, for traces.
+    start: Instant,
+}
+
+enum NbdMessage {
+    // The guest memory the requests transfer their data to and from, sent on activation.
+    Activate(GuestMemory),
+    Execute(NbdJob),
+}
+
+// A request executed by the NBD worker, with its outcome.
+struct NbdCompletion {
+    job: NbdJob,
+    result: result::Result<u32, ExecuteError>,
+}
+
+// Executes the requests to a disk image served over NBD on a worker thread, so that the VMM thread
+// never waits for the server, nor for the reconnections to it.
+struct NbdIo {
+    messages: Sender<NbdMessage>,
+    completions: Receiver<NbdCompletion>,
+    completion_evt: EventFd,
+    // The number of requests handed over to the worker and not completed yet.
+    in_flight: usize,
+    // The completions received while waiting for the requests in flight.
+    completed: VecDeque<NbdCompletion>,
+}
+
+impl NbdIo {
+    // Starts the worker owning `disk_image`, which runs `init` first. The worker stops once the
+    // device drops its end of the channel.
+    fn start<F>(
+        mut disk_image: DiskImage,
+        disk_nsectors: u64,
+        cache_type: CacheType,
+        metrics: DeviceMetrics<BlockDeviceMetrics>,
+        init: F,
+    ) -> io::Result<NbdIo>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (messages, message_receiver) = mpsc::channel();
+        let (completion_sender, completions) = mpsc::channel();
+        let completion_evt = EventFd::new()?;
+        let worker_evt = completion_evt.try_clone()?;
+        let disk_image_id = build_disk_image_id(&disk_image);
+        thread::Builder::new()
+            .name("fc_nbd".to_string())
+            .spawn(move || {
+                init();
+                let mut mem = None;
+                while let Ok(message) = message_receiver.recv() {
+                    let job = match message {
+                        NbdMessage::Activate(guest_memory) => {
+                            mem = Some(guest_memory);
+                            continue;
+                        }
+                        NbdMessage::Execute(job) => job,
+                    };
+                    // Requests are only sent once the device is activated.
+                    let result = match mem {
+                        Some(ref mem) => job.request.execute(
+                            &mut disk_image,
+                            disk_nsectors,
+                            mem,
+                            &disk_image_id,
+                            cache_type,
+                            &metrics,
+                        ),
+                        None => continue,
+                    };
+                    if completion_sender
+                        .send(NbdCompletion { job, result })
+                        .is_err()
+                    {
+                        break;
+                    }
+                    if let Err(e) = worker_evt.write(1) {
+                        error!("Failed to signal the completion of an NBD request: {:?}", e);
+                        metrics.inc(|m| &m.event_fails);
+                    }
+                }
+            })?;
+        Ok(NbdIo {
+            messages,
+            completions,
+            completion_evt,
+            in_flight: 0,
+            completed: VecDeque::new(),
+        })
+    }
+
+    fn activate(&self, mem: GuestMemory) -> io::Result<()> {
+        self.messages
+            .send(NbdMessage::Activate(mem))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn push(&mut self, request: &Request, key: u64) -> result::Result<(), ExecuteError> {
+        let job = NbdJob {
+            key,
+            request: *request,
+            start: Instant::now(),
+        };
+        if self.messages.send(NbdMessage::Execute(job)).is_err() {
+            error!("Failed to push block request: the NBD worker stopped");
+            return Err(ExecuteError::Submit);
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    fn pop_completion(&mut self) -> Option<NbdCompletion> {
+        let completion = match self.completed.pop_front() {
+            Some(completion) => completion,
+            None => self.completions.try_recv().ok()?,
+        };
+        self.in_flight -= 1;
+        Some(completion)
+    }
+
+    // Waits for the requests handed over to the worker to complete. Their completions are still
+    // processed on the next completion event.
+    fn wait_in_flight(&mut self) -> io::Result<()> {
+        while self.completed.len() < self.in_flight {
+            let completion = self
+                .completions
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            self.completed.push_back(completion);
+        }
+        Ok(())
+    }
+}
+
+// Returns the status of the request executed with `result` and the number of bytes written to
+// guest memory.
+fn execution_status(
+    result: result::Result<u32, ExecuteError>,
+    metrics: &DeviceMetrics<BlockDeviceMetrics>,
+) -> (u32, u32) {
+    match result {
+        Ok(len) => (VIRTIO_BLK_S_OK, len),
+        Err(e) => {
+            error!("Failed to execute request: {:?}", e);
+            metrics.inc(|m| &m.invalid_reqs_count);
+            // We need at least 1 byte for the status.
+            (e.status(), 1)
+        }
+    }
+}
+
+// Appends the request, started at `start` and completed with `status`, to `trace`. Tracing stops
+// when the trace cannot be written.
+fn trace_request(
+    trace: &mut Option<BlockTrace>,
+    request_type: RequestType,
+    ranges: &[(u64, u32, u16)],
+    queue_index: usize,
+    start: Instant,
+    status: u32,
+) {
+    let result = match *trace {
+        Some(ref mut trace) => {
+            let timestamp_us = trace.timestamp_us(start);
+            let latency_us =
+                cmp::min(start.elapsed().as_micros(), u128::from(std::u32::MAX)) as u32;
+            ranges.iter().try_for_each(|&(sector, len, flags)| {
+                trace.record(&TraceRecord {
+                    timestamp_us,
+                    sector,
+                    len,
+                    latency_us,
+                    request_type: request_type.raw(),
+                    flags,
+                    queue_index: queue_index as u8,
+                    status: status as u8,
+                })
+            })
+        }
+        None => return,
+    };
+    if let Err(e) = result {
+        error!("Failed to write the block trace; stopping it: {:?}", e);
+        *trace = None;
+    }
+}
+
+/// Handler that drives the execution of the Block devices
+pub struct BlockEpollHandler {
+    // Declared first, so that it is dropped before the guest memory and the backing file.
+    async_io: Option<AsyncIo>,
+    nbd_io: Option<NbdIo>,
+    queues: Vec<Queue>,
+    mem: GuestMemory,
+    // The NBD worker owns the disk image of the devices it serves.
+    disk_image: Option<DiskImage>,
+    disk_nsectors: u64,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    queue_evts: Vec<EventFd>,
+    rate_limiter: RateLimiter,
+    disk_image_id: Vec<u8>,
+    cache_type: CacheType,
+    metrics: DeviceMetrics<BlockDeviceMetrics>,
+    trace: Option<BlockTrace>,
+}
+
+impl BlockEpollHandler {
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+        let mut used_any = false;
+
+        loop {
+            // Requests stay in the avail ring while the asynchronous engine is full.
+            if let Some(ref async_io) = self.async_io {
+                if async_io.ring.free_slots() == 0 {
+                    break;
+                }
+            }
+            let head = match queue.pop(&self.mem) {
+                Some(head) => head,
+                None => break,
+            };
+            let len;
+            match Request::parse(&head, &self.mem) {
+                Ok(request) => {
+                    let start = self.trace.as_ref().map(|_| Instant::now());
+                    // Reads and writes are charged to their own token types, which are also
+                    // charged to the aggregate TokenType::Ops and TokenType::Bytes buckets.
+                    let (ops_token_type, bytes_token_type) = request.token_types();
+                    // If limiter.consume() fails it means there is no more ops budget and rate
+                    // limiting is in effect.
+                    if !self.rate_limiter.consume(1, ops_token_type) {
+                        // Stop processing the queue and return this descriptor chain to the
+                        // avail ring, for later processing.
+                        queue.undo_pop();
+                        break;
+                    }
+                    // Exercise the rate limiter only if this request is of data transfer type.
+                    if let Some(bytes_token_type) = bytes_token_type {
+                        // If limiter.consume() fails it means there is no more bytes budget and
+                        // rate limiting is in effect.
+                        if !self
+                            .rate_limiter
+                            .consume(u64::from(request.data_len), bytes_token_type)
+                        {
+                            // Revert the OPS consume().
+                            self.rate_limiter.manual_replenish(1, ops_token_type);
+                            // Stop processing the queue and return this descriptor chain to the
+                            // avail ring, for later processing.
+                            queue.undo_pop();
+                            break;
+                        }
+                    }
+                    // Only raw images are accessed asynchronously, and NBD exports by their worker.
+                    let result = match (self.nbd_io.as_mut(), self.disk_image.as_mut()) {
+                        (Some(nbd_io), _) => nbd_io
+                            .push(&request, request_key(queue_index, head.index))
+                            .map(|_| None),
+                        (None, Some(disk_image)) => {
+                            match (self.async_io.as_mut(), disk_image.file()) {
+                                (Some(async_io), Some(file))
+                                    if request.is_async(self.cache_type) =>
+                                {
+                                    async_io
+                                        .push(
+                                            &request,
+                                            queue_index,
+                                            head.index,
+                                            file,
+                                            self.disk_nsectors,
+                                            &self.mem,
+                                        )
+                                        .map(|_| None)
+                                }
+                                _ => request
+                                    .execute(
+                                        disk_image,
+                                        self.disk_nsectors,
+                                        &self.mem,
+                                        &self.disk_image_id,
+                                        self.cache_type,
+                                        &self.metrics,
+                                    )
+                                    .map(Some),
+                            }
+                        }
+                        // Devices without an NBD worker own their disk image.
+                        (None, None) => unreachable!(),
+                    };
+                    let status = match result.transpose() {
+                        // The request is completed on the next completion event.
+                        None => continue,
+                        Some(result) => {
+                            let (status, l) = execution_status(result, &self.metrics);
+                            len = l;
+                            status
+                        }
+                    };
+                    // We use unwrap because the request parsing process already checked that the
+                    // status_addr was valid.
+                    self.mem
+                        .write_obj_at_addr(status, request.status_addr)
+                        .unwrap();
+                    if let Some(start) = start {
+                        trace_request(
+                            &mut self.trace,
+                            request.request_type,
+                            &request.traced_ranges(&self.mem),
+                            queue_index,
+                            start,
+                            status,
+                        );
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to parse available descriptor chain: {:?}", e);
+                    self.metrics.inc(|m| &m.execute_fails);
+                    len = 0;
+                }
+            }
+            queue.add_used(&self.mem, head.index, len);
+            used_any = true;
+        }
+
+        if let Some(ref mut async_io) = self.async_io {
+            if let Err(e) = async_io.ring.submit() {
+                // The requests are submitted again on the next queue or completion event.
+                error!("Failed to submit block requests: {:?}", e);
+                self.metrics.inc(|m| &m.execute_fails);
+            }
+        }
+
+        self.flush_trace();
+        used_any
+    }
+
+    fn process_queues(&mut self) -> bool {
+        let mut used_any = false;
+        for queue_index in 0..self.queues.len() {
+            used_any |= self.process_queue(queue_index);
+        }
+        used_any
+    }
+
+    fn process_completions(&mut self) -> bool {
+        let mut used_any = false;
+
+        if let Some(ref mut async_io) = self.async_io {
+            while let Some(completion) = async_io.ring.pop_completion() {
+                let request = match async_io.pending.remove(&completion.user_data) {
+                    Some(request) => request,
+                    None => {
+                        error!(
+                            "Unexpected block request completion: {}",
+                            completion.user_data
+                        );
+                        self.metrics.inc(|m| &m.execute_fails);
+                        continue;
+                    }
+                };
+                let (status, len) = request.complete(completion.result, &self.mem, &self.metrics);
+                // We use unwrap because the request parsing process already checked that the
+                // status_addr was valid.
+                self.mem
+                    .write_obj_at_addr(status, request.status_addr)
+                    .unwrap();
+                let queue_index = (completion.user_data >> 16) as usize;
+                let head_index = completion.user_data as u16;
+                self.queues[queue_index].add_used(&self.mem, head_index, len);
+                trace_request(
+                    &mut self.trace,
+                    request.request_type,
+                    &[(request.sector, request.data_len, 0)],
+                    queue_index,
+                    request.start,
+                    status,
+                );
+                used_any = true;
+            }
+        }
+
+        while let Some(NbdCompletion { job, result }) =
+            self.nbd_io.as_mut().and_then(NbdIo::pop_completion)
+        {
+            let (status, len) = execution_status(result, &self.metrics);
+            // We use unwrap because the request parsing process already checked that the
+            // status_addr was valid.
+            self.mem
+                .write_obj_at_addr(status, job.request.status_addr)
+                .unwrap();
+            let queue_index = (job.key >> 16) as usize;
+            self.queues[queue_index].add_used(&self.mem, job.key as u16, len);
+            if self.trace.is_some() {
+                trace_request(
+                    &mut self.trace,
+                    job.request.request_type,
+                    &job.request.traced_ranges(&self.mem),
+                    queue_index,
+                    job.start,
+                    status,
+                );
+            }
+            used_any = true;
+        }
+
+        self.flush_trace();
+        used_any
+    }
+
+    // Writes the buffered trace records, so that the trace is complete whenever the device is
+    // idle.
+    fn flush_trace(&mut self) {
+        if let Some(Err(e)) = self.trace.as_mut().map(BlockTrace::flush) {
+            error!("Failed to write the block trace; stopping it: {:?}", e);
+            self.trace = None;
+        }
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    /// Waits for the requests in flight on the asynchronous I/O engine or on the NBD worker and
+    /// completes them, so that the guest memory is no longer written to while the state of the
+    /// microVM is saved.
+    pub fn drain_in_flight(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(ref mut async_io) = self.async_io {
+            async_io.wait_in_flight().map_err(DeviceError::IoError)?;
+        }
+        if let Some(ref mut nbd_io) = self.nbd_io {
+            nbd_io.wait_in_flight().map_err(DeviceError::IoError)?;
+        }
+        if self.process_completions() {
+            self.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    /// Update the backing file for the Block device. The disk images served over NBD can only be
+    /// set before the device is activated, as their worker is started beforehand.
+    pub fn update_disk_image(&mut self, disk_image: DiskImage) -> result::Result<(), DeviceError> {
+        let is_nbd = match disk_image {
+            DiskImage::Nbd(_) => true,
+            _ => false,
+        };
+        if is_nbd || self.nbd_io.is_some() {
+            return Err(DeviceError::IoError(io::Error::from_raw_os_error(
+                libc::EOPNOTSUPP,
+            )));
+        }
+        // The requests in flight must not outlive the previous backing file.
+        if let Some(ref mut async_io) = self.async_io {
+            async_io.wait_in_flight().map_err(DeviceError::IoError)?;
+        }
+        if self.async_io.is_some() && !disk_image.is_raw() {
+            warn!(
+                "The new disk image is not a raw file; falling back to the synchronous I/O engine."
+            );
+            self.async_io = None;
+        }
+        let mut disk_image = disk_image;
+        self.disk_nsectors = disk_image
+            .seek(SeekFrom::End(0))
+            .map_err(DeviceError::IoError)?
+            / SECTOR_SIZE;
+        self.disk_image_id = build_disk_image_id(&disk_image);
+        self.disk_image = Some(disk_image);
+        self.metrics.inc(|m| &m.update_count);
+        Ok(())
+    }
+
+    /// Updates the parameters of the rate limiter. The buckets passed as `None` are left
+    /// unchanged.
+    pub fn patch_rate_limiter(
+        &mut self,
+        bytes: Option<TokenBucket>,
+        ops: Option<TokenBucket>,
+        read_bytes: Option<TokenBucket>,
+        read_ops: Option<TokenBucket>,
+        write_bytes: Option<TokenBucket>,
+        write_ops: Option<TokenBucket>,
+    ) {
+        self.rate_limiter.update_buckets(bytes, ops);
+        self.rate_limiter.update_read_buckets(read_bytes, read_ops);
+        self.rate_limiter
+            .update_write_buckets(write_bytes, write_ops);
+    }
+}
+
+impl EpollHandler for BlockEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
         _evset: epoll::Events,
     ) -> result::Result<(), DeviceError> {
         match device_event {
-            QUEUE_AVAIL_EVENT => {
-                METRICS.block.queue_event_count.inc();
-                if let Err(e) = self.queue_evt.read() {
-                    error!("Failed to get queue event: {:?}", e);
-                    METRICS.block.event_fails.inc();
-                    Err(DeviceError::FailedReadingQueue {
-                        event_type: "queue event",
+            RATE_LIMITER_EVENT => {
+                self.metrics.inc(|m| &m.rate_limiter_event_count);
+                // Upon rate limiter event, call the rate limiter handler
+                // and restart processing the queues.
+                if self.rate_limiter.event_handler().is_ok() && self.process_queues() {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            IO_COMPLETION_EVENT => {
+                let read_result = match (&self.async_io, &self.nbd_io) {
+                    (Some(ref async_io), _) => async_io.completion_evt.read(),
+                    (None, Some(ref nbd_io)) => nbd_io.completion_evt.read(),
+                    (None, None) => Ok(0),
+                };
+                if let Err(e) = read_result {
+                    error!("Failed to get I/O completion event: {:?}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                    return Err(DeviceError::FailedReadingQueue {
+                        event_type: "I/O completion event",
                         underlying: e,
-                    })
-                } else if !self.rate_limiter.is_blocked() && self.process_queue(0) {
+                    });
+                }
+                let completed_any = self.process_completions();
+                // Completions free slots for the requests left in the avail ring.
+                let processed_any = !self.rate_limiter.is_blocked() && self.process_queues();
+                if completed_any || processed_any {
                     self.signal_used_queue()
                 } else {
-                    // While limiter is blocked, don't process any more requests.
                     Ok(())
                 }
             }
-            RATE_LIMITER_EVENT => {
-                METRICS.block.rate_limiter_event_count.inc();
-                // Upon rate limiter event, call the rate limiter handler
-                // and restart processing the queue.
-                if self.rate_limiter.event_handler().is_ok() && self.process_queue(0) {
+            event if usize::from(event - QUEUE_AVAIL_EVENT) < self.queue_evts.len() => {
+                let queue_index = usize::from(event - QUEUE_AVAIL_EVENT);
+                self.metrics.inc(|m| &m.queue_event_count);
+                if let Err(e) = self.queue_evts[queue_index].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "queue event",
+                        underlying: e,
+                    })
+                } else if !self.rate_limiter.is_blocked() && self.process_queue(queue_index) {
                     self.signal_used_queue()
                 } else {
+                    // While limiter is blocked, don't process any more requests.
                     Ok(())
                 }
             }
@@ -426,6 +1509,7 @@ impl EpollHandler for BlockEpollHandler {
 pub struct EpollConfig {
     q_avail_token: u64,
     rate_limiter_token: u64,
+    io_completion_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<dyn EpollHandler>>,
 }
@@ -439,6 +1523,7 @@ impl EpollConfigConstructor for EpollConfig {
         EpollConfig {
             q_avail_token: first_token + u64::from(QUEUE_AVAIL_EVENT),
             rate_limiter_token: first_token + u64::from(RATE_LIMITER_EVENT),
+            io_completion_token: first_token + u64::from(IO_COMPLETION_EVENT),
             epoll_raw_fd,
             sender,
         }
@@ -447,17 +1532,41 @@ impl EpollConfigConstructor for EpollConfig {
 
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
-    disk_image: Option<File>,
+    disk_image: Option<DiskImage>,
     disk_nsectors: u64,
+    queue_sizes: Vec<u16>,
     avail_features: u64,
     acked_features: u64,
     config_space: Vec<u8>,
     epoll_config: EpollConfig,
     rate_limiter: Option<RateLimiter>,
+    async_io: Option<AsyncIo>,
+    nbd_io: Option<NbdIo>,
+    cache_type: CacheType,
+    metrics: DeviceMetrics<BlockDeviceMetrics>,
+    trace: Option<BlockTrace>,
+}
+
+/// The serializable state of a virtio block device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BlockState {
+    /// The features offered by the device.
+    pub avail_features: u64,
+    /// The features acknowledged by the driver.
+    pub acked_features: u64,
+    /// The configuration space of the device.
+    pub config_space: Vec<u8>,
 }
 
-pub fn build_config_space(disk_size: u64) -> Vec<u8> {
-    // We only support disk size, which uses the first two words of the configuration space.
+impl Versioned for BlockState {
+    const NAME: &'static str = "Block";
+    const VERSION: u16 = 1;
+}
+
+pub fn build_config_space(disk_size: u64, num_queues: u16) -> Vec<u8> {
+    // We only support disk size, which uses the first two words of the configuration space,
+    // the number of request queues and the limits of discard and write zeroes requests. The
+    // fields in between are left to 0.
     // If the image is not a multiple of the sector size, the tail bits are not exposed.
     // The config space is little endian.
     let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
@@ -465,19 +1574,50 @@ pub fn build_config_space(disk_size: u64) -> Vec<u8> {
     for i in 0..8 {
         config.push((num_sectors >> (8 * i)) as u8);
     }
+    config.resize(CONFIG_NUM_QUEUES_OFFSET, 0);
+    config.push(num_queues as u8);
+    config.push((num_queues >> 8) as u8);
+    config.resize(CONFIG_DISCARD_OFFSET, 0);
+    for field in &[
+        MAX_DISCARD_SECTORS,
+        MAX_DISCARD_SEGMENTS,
+        DISCARD_SECTOR_ALIGNMENT,
+        MAX_DISCARD_SECTORS,
+        MAX_DISCARD_SEGMENTS,
+    ] {
+        for i in 0..4 {
+            config.push((field >> (8 * i)) as u8);
+        }
+    }
+    // write_zeroes_may_unmap, followed by padding.
+    config.extend_from_slice(&[1, 0, 0, 0]);
     config
 }
 
 impl Block {
-    /// Create a new virtio block device that operates on the given file.
+    /// Create a new virtio block device that operates on the given disk image.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given image must be seekable and sizable. If the asynchronous I/O engine cannot be set
+    /// up, or the image is not a raw file, the device falls back to the synchronous one. The
+    /// device exposes `num_queues` request queues, between 1 and `MAX_NUM_QUEUES`. The flush
+    /// command is only offered when `cache_type` honours it, and a `Direct` cache type expects a
+    /// `DiskImage::Direct` image. Its metrics are accounted in `metrics`, as well as in the
+    /// aggregate block device metrics.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        mut disk_image: File,
+        mut disk_image: DiskImage,
         is_disk_read_only: bool,
         epoll_config: EpollConfig,
         rate_limiter: Option<RateLimiter>,
+        io_engine: IoEngine,
+        num_queues: u16,
+        cache_type: CacheType,
+        metrics: Arc<BlockDeviceMetrics>,
     ) -> io::Result<Block> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
         if disk_size % SECTOR_SIZE != 0 {
             warn!(
@@ -487,22 +1627,112 @@ impl Block {
             );
         }
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if cache_type != CacheType::Unsafe {
+            avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
+        }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        };
+
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let async_io = match io_engine {
+            IoEngine::Sync => None,
+            IoEngine::Async if !disk_image.is_raw() => {
+                warn!(
+                    "Only raw disk images without overlays or direct I/O support the asynchronous \
+                     I/O engine."
+                );
+                None
+            }
+            IoEngine::Async => match AsyncIo::new(num_queues) {
+                Ok(async_io) => Some(async_io),
+                Err(e) => {
+                    warn!(
+                        "Cannot set up the asynchronous I/O engine; \
+                         falling back to the synchronous one: {:?}",
+                        e
+                    );
+                    None
+                }
+            },
         };
 
         Ok(Block {
             disk_image: Some(disk_image),
             disk_nsectors: disk_size / SECTOR_SIZE,
+            queue_sizes: vec![QUEUE_SIZE; usize::from(num_queues)],
             avail_features,
             acked_features: 0u64,
-            config_space: build_config_space(disk_size),
+            config_space: build_config_space(disk_size, num_queues),
             epoll_config,
             rate_limiter,
+            async_io,
+            nbd_io: None,
+            cache_type,
+            metrics: DeviceMetrics::new(&METRICS.block, metrics),
+            trace: None,
         })
     }
+
+    /// Executes the requests to the disk image served over NBD on a worker thread, so that the
+    /// VMM thread never waits for the server. The worker runs `init` first, for instance to load
+    /// its seccomp filters. Must be called before the device is activated.
+    pub fn start_nbd_worker<F>(&mut self, init: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.disk_image {
+            Some(DiskImage::Nbd(_)) => (),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+        // We use unwrap because we checked that the disk image is there.
+        let disk_image = self.disk_image.take().unwrap();
+        self.nbd_io = Some(NbdIo::start(
+            disk_image,
+            self.disk_nsectors,
+            self.cache_type,
+            self.metrics.clone(),
+            init,
+        )?);
+        Ok(())
+    }
+
+    /// Records the requests handled by the device in `trace`.
+    pub fn set_trace(&mut self, trace: BlockTrace) {
+        self.trace = Some(trace);
+    }
+
+    /// Returns the engine actually used for accessing the backing file.
+    pub fn io_engine(&self) -> IoEngine {
+        match self.async_io {
+            Some(_) => IoEngine::Async,
+            None => IoEngine::Sync,
+        }
+    }
+
+    /// Returns the current state of the device.
+    pub fn save_state(&self) -> BlockState {
+        BlockState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config_space: self.config_space.clone(),
+        }
+    }
+
+    /// Restores the device to a previously saved state. Acknowledged features that the device
+    /// does not offer anymore are dropped.
+    pub fn restore_state(&mut self, state: &BlockState) {
+        self.acked_features = state.acked_features & self.avail_features;
+        self.config_space = state.config_space.clone();
+    }
 }
 
 impl VirtioDevice for Block {
@@ -511,7 +1741,7 @@ impl VirtioDevice for Block {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn avail_features(&self) -> u64 {
@@ -530,7 +1760,7 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
@@ -545,11 +1775,11 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         let (_, right) = self.config_space.split_at_mut(offset as usize);
-        right.copy_from_slice(&data[..]);
+        right[..data.len()].copy_from_slice(data);
     }
 
     fn activate(
@@ -558,33 +1788,56 @@ impl VirtioDevice for Block {
         interrupt_evt: EventFd,
         status: Arc<AtomicUsize>,
         queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
+        queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
-            METRICS.block.activate_fails.inc();
+            self.metrics.inc(|m| &m.activate_fails);
             return Err(ActivateError::BadActivate);
         }
 
-        if let Some(disk_image) = self.disk_image.take() {
-            let queue_evt = queue_evts.remove(0);
-            let queue_evt_raw_fd = queue_evt.as_raw_fd();
-
-            let disk_image_id = build_disk_image_id(&disk_image);
+        // The disk image of the devices served over NBD is owned by their worker.
+        let disk_image = self.disk_image.take();
+        let nbd_io = self.nbd_io.take();
+        if disk_image.is_some() || nbd_io.is_some() {
+            let queue_evt_raw_fds: Vec<RawFd> = queue_evts.iter().map(EventFd::as_raw_fd).collect();
+
+            let disk_image_id = disk_image
+                .as_ref()
+                .map_or_else(Vec::new, build_disk_image_id);
+            let async_io = self.async_io.take();
+            let io_completion_rawfd = match (&async_io, &nbd_io) {
+                (Some(ref async_io), _) => async_io.completion_evt.as_raw_fd(),
+                (None, Some(ref nbd_io)) => nbd_io.completion_evt.as_raw_fd(),
+                (None, None) => -1,
+            };
+            if let Some(ref nbd_io) = nbd_io {
+                nbd_io.activate(mem.clone()).map_err(|e| {
+                    error!("Cannot activate the NBD worker: {:?}", e);
+                    self.metrics.inc(|m| &m.activate_fails);
+                    ActivateError::BadActivate
+                })?;
+            }
             let handler = BlockEpollHandler {
+                async_io,
+                nbd_io,
                 queues,
                 mem,
                 disk_image,
                 disk_nsectors: self.disk_nsectors,
                 interrupt_status: status,
                 interrupt_evt,
-                queue_evt,
+                queue_evts,
                 rate_limiter: self.rate_limiter.take().unwrap_or_default(),
                 disk_image_id,
+                cache_type: self.cache_type,
+                metrics: self.metrics.clone(),
+                trace: self.trace.take(),
             };
             let rate_limiter_rawfd = handler.rate_limiter.as_raw_fd();
 
@@ -595,16 +1848,21 @@ impl VirtioDevice for Block {
                 .expect("Failed to send through the channel");
 
             //TODO: barrier needed here by any chance?
-            epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
-                epoll::ControlOptions::EPOLL_CTL_ADD,
-                queue_evt_raw_fd,
-                epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.q_avail_token),
-            )
-            .map_err(|e| {
-                METRICS.block.activate_fails.inc();
-                ActivateError::EpollCtl(e)
-            })?;
+            for (queue_index, queue_evt_raw_fd) in queue_evt_raw_fds.into_iter().enumerate() {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    queue_evt_raw_fd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.q_avail_token + queue_index as u64,
+                    ),
+                )
+                .map_err(|e| {
+                    self.metrics.inc(|m| &m.activate_fails);
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
 
             if rate_limiter_rawfd != -1 {
                 epoll::ctl(
@@ -614,14 +1872,30 @@ impl VirtioDevice for Block {
                     epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.rate_limiter_token),
                 )
                 .map_err(|e| {
-                    METRICS.block.activate_fails.inc();
+                    self.metrics.inc(|m| &m.activate_fails);
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
+            if io_completion_rawfd != -1 {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    io_completion_rawfd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.io_completion_token,
+                    ),
+                )
+                .map_err(|e| {
+                    self.metrics.inc(|m| &m.activate_fails);
                     ActivateError::EpollCtl(e)
                 })?;
             }
 
             return Ok(());
         }
-        METRICS.block.activate_fails.inc();
+        self.metrics.inc(|m| &m.activate_fails);
         Err(ActivateError::BadActivate)
     }
 }
@@ -630,17 +1904,21 @@ impl VirtioDevice for Block {
 mod tests {
     extern crate tempfile;
 
-    use self::tempfile::{tempfile, NamedTempFile};
+    use self::tempfile::{tempfile, NamedTempFile, TempDir};
     use super::*;
 
     use libc;
+    use logger::Metric;
     use std::fs::{metadata, OpenOptions};
+    use std::os::unix::fs::FileExt;
     use std::sync::mpsc::Receiver;
     use std::thread;
     use std::time::Duration;
     use std::u32;
 
+    use crate::virtio::nbd::tests::{start_server, EXPORT_SIZE};
     use crate::virtio::queue::tests::*;
+    use crate::virtio::trace::{replay, TraceReader};
 
     const EPOLLIN: epoll::Events = epoll::Events::EPOLLIN;
 
@@ -658,6 +1936,10 @@ mod tests {
             self.queues[idx] = q;
         }
 
+        fn disk_image(&mut self) -> &mut DiskImage {
+            self.disk_image.as_mut().unwrap()
+        }
+
         fn get_rate_limiter(&self) -> &RateLimiter {
             &self.rate_limiter
         }
@@ -686,7 +1968,17 @@ mod tests {
             // Rate limiting is enabled but with a high operation rate (10 million ops/s).
             let rate_limiter = RateLimiter::new(0, None, 0, 100_000, None, 10).unwrap();
             DummyBlock {
-                block: Block::new(f, is_disk_read_only, epoll_config, Some(rate_limiter)).unwrap(),
+                block: Block::new(
+                    DiskImage::Raw(f),
+                    is_disk_read_only,
+                    epoll_config,
+                    Some(rate_limiter),
+                    IoEngine::Sync,
+                    1,
+                    CacheType::Writeback,
+                    Arc::default(),
+                )
+                .unwrap(),
                 epoll_raw_fd,
                 _receiver,
             }
@@ -715,24 +2007,29 @@ mod tests {
         let disk_nsectors = disk_image.seek(SeekFrom::End(0)).unwrap() / SECTOR_SIZE;
         let status = Arc::new(AtomicUsize::new(0));
         let interrupt_evt = EventFd::new().unwrap();
-        let queue_evt = EventFd::new().unwrap();
+        let queue_evts = vec![EventFd::new().unwrap()];
 
-        let disk_image_id_str = build_device_id(&disk_image).unwrap();
+        let disk_image_id_str = build_device_id(disk_image.file().unwrap()).unwrap();
         let mut disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
         let disk_image_id_bytes = disk_image_id_str.as_bytes();
         let bytes_to_copy = cmp::min(disk_image_id_bytes.len(), VIRTIO_BLK_ID_BYTES as usize);
         disk_image_id[..bytes_to_copy].clone_from_slice(&disk_image_id_bytes[..bytes_to_copy]);
         (
             BlockEpollHandler {
+                async_io: None,
+                nbd_io: None,
                 queues,
                 mem: mem.clone(),
-                disk_image,
+                disk_image: Some(disk_image),
                 disk_nsectors,
                 interrupt_status: status,
                 interrupt_evt,
-                queue_evt,
+                queue_evts,
                 rate_limiter: RateLimiter::default(),
                 disk_image_id,
+                cache_type: CacheType::Writeback,
+                metrics: DeviceMetrics::new(&METRICS.block, Arc::default()),
+                trace: None,
             },
             vq,
         )
@@ -769,7 +2066,7 @@ mod tests {
         // leave at least one event here so that reading it later won't block
         h.interrupt_evt.write(1).unwrap();
         // trigger the queue event
-        h.queue_evt.write(1).unwrap();
+        h.queue_evts[0].write(1).unwrap();
         // handle event
         h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
         // validate the queue operation finished successfully
@@ -949,7 +2246,7 @@ mod tests {
         // Test `queue_max_sizes()`.
         {
             let x = b.queue_max_sizes();
-            assert_eq!(x, QUEUE_SIZES);
+            assert_eq!(x, &[QUEUE_SIZE]);
 
             // power of 2?
             for &y in x {
@@ -1046,7 +2343,11 @@ mod tests {
             b.read_config(0, &mut new_config_read);
             assert_eq!(new_config, new_config_read);
             // Invalid write.
-            check_metric_after_block!(&METRICS.block.cfg_fails, 1, b.write_config(5, &new_config));
+            check_metric_after_block!(
+                &METRICS.block.cfg_fails,
+                1,
+                b.write_config(CONFIG_SPACE_SIZE as u64 - 4, &new_config)
+            );
             // Make sure nothing got written.
             new_config_read = [0u8; 8];
             b.read_config(0, &mut new_config_read);
@@ -1054,14 +2355,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_save_restore_state() {
+        let mut dummy = DummyBlock::new(false);
+        let b = dummy.block();
+        b.ack_features_by_page(0, 1u32 << VIRTIO_BLK_F_FLUSH);
+        b.write_config(0, &[0x10, 0, 0, 0, 0, 0, 0, 0]);
+        let mut state = b.save_state();
+        assert_eq!(state.acked_features, 1u64 << VIRTIO_BLK_F_FLUSH);
+
+        let mut other_dummy = DummyBlock::new(false);
+        let other = other_dummy.block();
+        other.restore_state(&state);
+        assert_eq!(other.save_state(), state);
+
+        // Features that the device does not offer are not restored.
+        state.acked_features |= 1u64 << VIRTIO_BLK_F_RO;
+        other.restore_state(&state);
+        assert_eq!(other.acked_features(), 1u64 << VIRTIO_BLK_F_FLUSH);
+    }
+
     #[test]
     fn test_invalid_event_handler() {
         let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let (mut h, _vq) = default_test_blockepollhandler(&m);
-        let r = h.handle_event(BLOCK_EVENTS_COUNT as DeviceEventT, EPOLLIN);
+        let r = h.handle_event(block_events_count(1) as DeviceEventT, EPOLLIN);
         match r {
             Err(DeviceError::UnknownEvent { event, device }) => {
-                assert_eq!(event, BLOCK_EVENTS_COUNT as DeviceEventT);
+                assert_eq!(event, block_events_count(1) as DeviceEventT);
                 assert_eq!(device, "block");
             }
             _ => panic!("invalid"),
@@ -1078,7 +2399,7 @@ mod tests {
         let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let (mut h, vq) = default_test_blockepollhandler(&m);
 
-        let blk_metadata = h.disk_image.metadata();
+        let blk_metadata = h.disk_image().file().unwrap().metadata();
 
         for i in 0..3 {
             vq.avail.ring[i].set(i as u16);
@@ -1378,7 +2699,7 @@ mod tests {
                 // leave at least one event here so that reading it later won't block
                 h.interrupt_evt.write(1).unwrap();
                 // trigger the attempt to write
-                h.queue_evt.write(1).unwrap();
+                h.queue_evts[0].write(1).unwrap();
                 h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
 
                 // assert that limiter is blocked
@@ -1437,7 +2758,7 @@ mod tests {
                 // leave at least one event here so that reading it later won't block
                 h.interrupt_evt.write(1).unwrap();
                 // trigger the attempt to write
-                h.queue_evt.write(1).unwrap();
+                h.queue_evts[0].write(1).unwrap();
                 h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
 
                 // assert that limiter is blocked
@@ -1453,7 +2774,7 @@ mod tests {
                 // leave at least one event here so that reading it later won't block
                 h.interrupt_evt.write(1).unwrap();
                 // trigger the attempt to write
-                h.queue_evt.write(1).unwrap();
+                h.queue_evts[0].write(1).unwrap();
                 h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
 
                 // assert that limiter is blocked
@@ -1489,6 +2810,61 @@ mod tests {
             }
         }
 
+        // test the separate read and write rate limiters
+        {
+            // only limit the writes, to 80 bytes/s with bucket size of 8 bytes
+            let mut rl = RateLimiter::new(0, None, 0, 0, None, 0).unwrap();
+            rl.update_write_buckets(Some(TokenBucket::new(8, None, 100)), None);
+            // use up the write budget
+            assert!(rl.consume(8, TokenType::WriteBytes));
+
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            h.set_rate_limiter(rl);
+
+            // following read procedure should succeed since reads are not limited
+            {
+                m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                    .unwrap();
+                vq.dtable[1]
+                    .flags
+                    .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+                vq.dtable[1].len.set(8);
+
+                invoke_handler_for_queue_event(&mut h);
+
+                assert!(!h.get_rate_limiter().is_blocked());
+                assert_eq!(vq.used.idx.get(), 1);
+                assert_eq!(vq.used.ring[0].get().id, 0);
+                assert_eq!(
+                    m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                    VIRTIO_BLK_S_OK
+                );
+            }
+
+            // following write procedure should fail because of write bandwidth rate limiting
+            {
+                vq.used.idx.set(0);
+                h.set_queue(0, vq.create_queue());
+                m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                    .unwrap();
+                vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+
+                // leave at least one event here so that reading it later won't block
+                h.interrupt_evt.write(1).unwrap();
+                // trigger the attempt to write
+                h.queue_evts[0].write(1).unwrap();
+                h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+
+                // assert that limiter is blocked
+                assert!(h.get_rate_limiter().is_blocked());
+                // assert that no operation actually completed (limiter blocked it)
+                assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+                // make sure the data is still queued for processing
+                assert_eq!(vq.used.idx.get(), 0);
+            }
+        }
+
         // test block device update handler
         {
             let f = NamedTempFile::new().unwrap();
@@ -1506,10 +2882,935 @@ mod tests {
                 .write(true)
                 .open(path)
                 .unwrap();
-            h.update_disk_image(file).unwrap();
+            h.update_disk_image(DiskImage::Raw(file)).unwrap();
 
-            assert_eq!(h.disk_image.metadata().unwrap().st_ino(), mdata.st_ino());
+            assert_eq!(
+                h.disk_image().file().unwrap().metadata().unwrap().st_ino(),
+                mdata.st_ino()
+            );
             assert_eq!(h.disk_image_id, id);
         }
     }
+
+    fn invoke_handler_for_io_completion_event(h: &mut BlockEpollHandler) {
+        h.async_io.as_mut().unwrap().wait_in_flight().unwrap();
+        // leave at least one event here so that reading it later won't block
+        h.interrupt_evt.write(1).unwrap();
+        h.handle_event(IO_COMPLETION_EVENT, EPOLLIN).unwrap();
+        assert_eq!(h.interrupt_evt.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_async_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.async_io = Some(AsyncIo::new(1).unwrap());
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.dtable[1].len.set(8);
+        vq.avail.idx.set(1);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        let status_addr = GuestAddress(vq.dtable[2].addr.get() as usize);
+
+        {
+            // write
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(123_456_789, data_addr).unwrap();
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            // The request is only completed on the I/O completion event.
+            assert_eq!(vq.used.idx.get(), 0);
+            assert_eq!(h.async_io.as_ref().unwrap().pending.len(), 1);
+
+            check_metric_after_block!(
+                &METRICS.block.write_count,
+                1,
+                invoke_handler_for_io_completion_event(&mut h)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert!(h.async_io.as_ref().unwrap().pending.is_empty());
+
+            let mut data = [0u8; 8];
+            h.disk_image().seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+            h.disk_image().read_exact(&mut data).unwrap();
+            assert_eq!(u64::from_le_bytes(data), 123_456_789);
+        }
+
+        {
+            // read
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0, data_addr).unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            check_metric_after_block!(
+                &METRICS.block.read_count,
+                1,
+                invoke_handler_for_io_completion_event(&mut h)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 8);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(m.read_obj_from_addr::<u64>(data_addr).unwrap(), 123_456_789);
+        }
+
+        {
+            // The requests in flight are completed when draining the engine.
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            m.write_obj_at_addr::<u64>(0, data_addr).unwrap();
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            assert_eq!(vq.used.idx.get(), 0);
+            h.drain_in_flight().unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            assert!(h.async_io.as_ref().unwrap().pending.is_empty());
+            assert_eq!(m.read_obj_from_addr::<u64>(data_addr).unwrap(), 123_456_789);
+            assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        }
+
+        {
+            // flush
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+                .unwrap();
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            check_metric_after_block!(
+                &METRICS.block.flush_count,
+                1,
+                invoke_handler_for_io_completion_event(&mut h)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        {
+            // Requests failing the bounds check complete right away.
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0x000f_ffff_ffff, GuestAddress(0x1000 + 8))
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
+    #[test]
+    fn test_nbd_worker() {
+        let dir = TempDir::new().unwrap();
+        let (address, server) = start_server(&dir, 0, std::usize::MAX);
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        let disk_image = DiskImage::Nbd(NbdDisk::connect(&address, true).unwrap());
+        h.disk_image = None;
+        h.disk_nsectors = EXPORT_SIZE as u64 / SECTOR_SIZE;
+        let nbd_io = NbdIo::start(
+            disk_image,
+            h.disk_nsectors,
+            CacheType::Writeback,
+            h.metrics.clone(),
+            || (),
+        )
+        .unwrap();
+        nbd_io.activate(m.clone()).unwrap();
+        h.nbd_io = Some(nbd_io);
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.dtable[1].len.set(8);
+        vq.avail.idx.set(1);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        let status_addr = GuestAddress(vq.dtable[2].addr.get() as usize);
+
+        {
+            // write
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(1, GuestAddress(0x1000 + 8))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(123_456_789, data_addr).unwrap();
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            // The request is completed once the worker is done with it.
+            assert_eq!(h.nbd_io.as_ref().unwrap().in_flight, 1);
+            h.drain_in_flight().unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(h.nbd_io.as_ref().unwrap().in_flight, 0);
+            assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+            let offset = SECTOR_SIZE as usize;
+            assert_eq!(
+                &server.disk.lock().unwrap()[offset..offset + 8],
+                &123_456_789u64.to_le_bytes()
+            );
+        }
+
+        {
+            // read, completed on the I/O completion event.
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0, data_addr).unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            // The worker signals each completion right after sending it, so the event of the
+            // write request may still be pending.
+            let completion_evt = h
+                .nbd_io
+                .as_ref()
+                .unwrap()
+                .completion_evt
+                .try_clone()
+                .unwrap();
+            while vq.used.idx.get() == 0 {
+                while completion_evt.read().is_err() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                completion_evt.write(1).unwrap();
+                h.handle_event(IO_COMPLETION_EVENT, EPOLLIN).unwrap();
+            }
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 8);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(m.read_obj_from_addr::<u64>(data_addr).unwrap(), 123_456_789);
+            assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        }
+
+        {
+            // Requests failing the bounds check fail on the worker as well.
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u64>(0x000f_ffff_ffff, GuestAddress(0x1000 + 8))
+                .unwrap();
+            h.queue_evts[0].write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, EPOLLIN).unwrap();
+            h.drain_in_flight().unwrap();
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // The disk image of a device served over NBD cannot be replaced.
+        assert!(h
+            .update_disk_image(DiskImage::Raw(tempfile().unwrap()))
+            .is_err());
+
+        // Only the devices served over NBD start a worker, before they are activated.
+        let dir = TempDir::new().unwrap();
+        let (address, _) = start_server(&dir, 0, std::usize::MAX);
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let mut block = Block::new(
+            DiskImage::Nbd(NbdDisk::connect(&address, true).unwrap()),
+            false,
+            EpollConfig::new(0, epoll_raw_fd, sender),
+            None,
+            IoEngine::Sync,
+            1,
+            CacheType::Writeback,
+            Arc::default(),
+        )
+        .unwrap();
+        block.start_nbd_worker(|| ()).unwrap();
+        assert!(block.start_nbd_worker(|| ()).is_err());
+        activate_block_with_modifiers(&mut block, false, false).unwrap();
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
+    #[test]
+    fn test_io_engine() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let f: File = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        let block = Block::new(
+            DiskImage::Raw(f),
+            false,
+            EpollConfig::new(0, epoll_raw_fd, sender),
+            None,
+            IoEngine::Async,
+            1,
+            CacheType::Writeback,
+            Arc::default(),
+        )
+        .unwrap();
+        assert_eq!(block.io_engine(), IoEngine::Async);
+        unsafe { libc::close(epoll_raw_fd) };
+
+        assert_eq!(IoEngine::default(), IoEngine::Sync);
+    }
+
+    #[test]
+    fn test_disk_image() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        QcowFile::create(file, &path, 0x10000, None).unwrap();
+        assert_eq!(disk_image_size(&path, ImageFormat::Qcow2).unwrap(), 0x10000);
+        assert_ne!(disk_image_size(&path, ImageFormat::Raw).unwrap(), 0x10000);
+
+        // A raw file is not a qcow2 image.
+        let raw = NamedTempFile::new().unwrap();
+        raw.as_file().set_len(0x1000).unwrap();
+        let err = disk_image_size(raw.path(), ImageFormat::Qcow2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut disk =
+            DiskImage::new(File::open(&path).unwrap(), ImageFormat::Qcow2, &path).unwrap();
+        assert!(!disk.is_raw());
+        m.write_slice_at_addr(&[0xab; 512], GuestAddress(0x100))
+            .unwrap();
+        let mut request = Request {
+            request_type: RequestType::Out,
+            sector: 3,
+            data_addr: GuestAddress(0x100),
+            data_len: 512,
+            status_addr: GuestAddress(0x800),
+        };
+        let metrics = DeviceMetrics::new(&METRICS.block, Arc::default());
+        // The image was opened read only.
+        match request.execute(&mut disk, 0x80, &m, &[], CacheType::Writeback, &metrics) {
+            Err(ExecuteError::Write(_)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut disk = DiskImage::new(file, ImageFormat::Qcow2, &path).unwrap();
+        request
+            .execute(&mut disk, 0x80, &m, &[], CacheType::Writeback, &metrics)
+            .unwrap();
+        request.request_type = RequestType::In;
+        request.data_addr = GuestAddress(0x400);
+        assert_eq!(
+            request
+                .execute(&mut disk, 0x80, &m, &[], CacheType::Writeback, &metrics)
+                .unwrap(),
+            512
+        );
+        let mut data = [0u8; 512];
+        m.read_slice_at_addr(&mut data, GuestAddress(0x400))
+            .unwrap();
+        assert_eq!(&data[..], &[0xab; 512][..]);
+
+        // The asynchronous engine only supports raw images.
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let block = Block::new(
+            disk,
+            false,
+            EpollConfig::new(0, epoll_raw_fd, sender),
+            None,
+            IoEngine::Async,
+            1,
+            CacheType::Writeback,
+            Arc::default(),
+        )
+        .unwrap();
+        assert_eq!(block.io_engine(), IoEngine::Sync);
+        assert_eq!(block.disk_nsectors, 0x80);
+        unsafe { libc::close(epoll_raw_fd) };
+
+        assert_eq!(ImageFormat::default(), ImageFormat::Raw);
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.disk_image().seek(SeekFrom::Start(0)).unwrap();
+        h.disk_image().write_all(&[0xff; 0x1000]).unwrap();
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.dtable[1].len.set(DISCARD_SEGMENT_SIZE);
+        vq.avail.idx.set(1);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        let status_addr = GuestAddress(vq.dtable[2].addr.get() as usize);
+        let write_segment = |sector: u64, num_sectors: u32, flags: u32| {
+            m.write_obj_at_addr::<u64>(sector, data_addr).unwrap();
+            m.write_obj_at_addr::<u32>(num_sectors, GuestAddress(data_addr.0 + 8))
+                .unwrap();
+            m.write_obj_at_addr::<u32>(flags, GuestAddress(data_addr.0 + 12))
+                .unwrap();
+        };
+        let read_disk = |h: &mut BlockEpollHandler| {
+            let mut data = vec![0u8; 0x1000];
+            h.disk_image().seek(SeekFrom::Start(0)).unwrap();
+            h.disk_image().read_exact(&mut data).unwrap();
+            data
+        };
+
+        {
+            // discard
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_DISCARD, GuestAddress(0x1000))
+                .unwrap();
+            write_segment(1, 2, 0);
+
+            check_metric_after_block!(
+                &METRICS.block.discard_count,
+                1,
+                invoke_handler_for_queue_event(&mut h)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            let data = read_disk(&mut h);
+            assert!(data[..512].iter().all(|&b| b == 0xff));
+            assert!(data[512..1536].iter().all(|&b| b == 0));
+            assert!(data[1536..].iter().all(|&b| b == 0xff));
+        }
+
+        {
+            // write zeroes
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, GuestAddress(0x1000))
+                .unwrap();
+            write_segment(6, 2, 0);
+
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_count,
+                1,
+                invoke_handler_for_queue_event(&mut h)
+            );
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            let data = read_disk(&mut h);
+            assert!(data[1536..3072].iter().all(|&b| b == 0xff));
+            assert!(data[3072..].iter().all(|&b| b == 0));
+        }
+
+        {
+            // discard requests must not set the unmap flag
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_DISCARD, GuestAddress(0x1000))
+                .unwrap();
+            write_segment(1, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP);
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+        }
+
+        {
+            // the range ends beyond the end of the disk
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, GuestAddress(0x1000))
+                .unwrap();
+            write_segment(7, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP);
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        {
+            // the data descriptor does not hold whole segments
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+
+            vq.dtable[1].len.set(DISCARD_SEGMENT_SIZE / 2);
+            write_segment(1, 1, 0);
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        let trace_file = NamedTempFile::new().unwrap();
+        h.trace = Some(BlockTrace::new(trace_file.reopen().unwrap()).unwrap());
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq.dtable[1].len.set(0x400);
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.avail.idx.set(1);
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        let mut run_request = |request_type: u32, sector: u64| {
+            vq.used.idx.set(0);
+            h.set_queue(0, vq.create_queue());
+            m.write_obj_at_addr::<u32>(request_type, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(sector, GuestAddress(0x1000 + 8))
+                .unwrap();
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+        };
+
+        run_request(VIRTIO_BLK_T_OUT, 2);
+        // The request ends beyond the end of the disk.
+        run_request(VIRTIO_BLK_T_OUT, 7);
+        run_request(VIRTIO_BLK_T_FLUSH, 0);
+        run_request(100, 0);
+        // Discard requests are traced per segment.
+        vq.dtable[1].len.set(2 * DISCARD_SEGMENT_SIZE);
+        for (i, &(sector, num_sectors)) in [(1u64, 2u32), (6, 1)].iter().enumerate() {
+            let addr = GuestAddress(data_addr.0 + i * DISCARD_SEGMENT_SIZE as usize);
+            m.write_obj_at_addr(sector, addr).unwrap();
+            m.write_obj_at_addr(num_sectors, GuestAddress(addr.0 + 8))
+                .unwrap();
+            m.write_obj_at_addr(0u32, GuestAddress(addr.0 + 12))
+                .unwrap();
+        }
+        run_request(VIRTIO_BLK_T_DISCARD, 0);
+        // Requests which cannot be parsed are not traced.
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        run_request(VIRTIO_BLK_T_OUT, 0);
+
+        let records: Vec<TraceRecord> = TraceReader::new(File::open(trace_file.path()).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected = [
+            (VIRTIO_BLK_T_OUT, 2, 0x400, VIRTIO_BLK_S_OK),
+            (VIRTIO_BLK_T_OUT, 7, 0x400, VIRTIO_BLK_S_IOERR),
+            (VIRTIO_BLK_T_FLUSH, 0, 0x400, VIRTIO_BLK_S_OK),
+            (100, 0, 0x400, VIRTIO_BLK_S_UNSUPP),
+            (VIRTIO_BLK_T_DISCARD, 1, 0x400, VIRTIO_BLK_S_OK),
+            (VIRTIO_BLK_T_DISCARD, 6, 0x200, VIRTIO_BLK_S_OK),
+        ];
+        assert_eq!(records.len(), expected.len());
+        for (record, &(request_type, sector, len, status)) in records.iter().zip(expected.iter()) {
+            assert_eq!(record.request_type, request_type);
+            assert_eq!(record.sector, sector);
+            assert_eq!(record.len, len);
+            assert_eq!(u32::from(record.status), status);
+            assert_eq!(record.queue_index, 0);
+        }
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us));
+
+        // Replaying the trace against a disk of the same size yields the same statuses.
+        let mut disk = tempfile().unwrap();
+        disk.set_len(0x1000).unwrap();
+        let trace = TraceReader::new(File::open(trace_file.path()).unwrap()).unwrap();
+        let replayed = replay(trace, &mut disk).unwrap();
+        assert_eq!(replayed.len(), records.len());
+        assert!(replayed
+            .iter()
+            .all(|replayed| replayed.status == replayed.record.status));
+    }
+
+    #[test]
+    fn test_execute_discard() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut request = Request {
+            request_type: RequestType::Discard,
+            sector: 0,
+            data_addr: GuestAddress(0x100),
+            data_len: 0,
+            status_addr: GuestAddress(0x200),
+        };
+        let mut f = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        let metrics = DeviceMetrics::new(&METRICS.block, Arc::default());
+
+        match request.execute(&mut f, 8, &m, &[], CacheType::Writeback, &metrics) {
+            Err(ExecuteError::BadRequest(Error::InvalidSegmentCount(0))) => (),
+            _ => panic!("Test failed."),
+        }
+
+        request.data_len = DISCARD_SEGMENT_SIZE;
+        m.write_obj_at_addr::<u32>(2, GuestAddress(0x100 + 12))
+            .unwrap();
+        match request.execute(&mut f, 8, &m, &[], CacheType::Writeback, &metrics) {
+            Err(ExecuteError::UnsupportedFlags(2)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        // Read only backing files cannot be discarded.
+        m.write_obj_at_addr::<u32>(1, GuestAddress(0x100 + 8))
+            .unwrap();
+        m.write_obj_at_addr::<u32>(0, GuestAddress(0x100 + 12))
+            .unwrap();
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        let mut f = OpenOptions::new().read(true).open(&path).unwrap();
+        match request.execute(&mut f, 8, &m, &[], CacheType::Writeback, &metrics) {
+            Err(ExecuteError::Fallocate(ref e)) => {
+                assert_eq!(e.raw_os_error(), Some(libc::EBADF))
+            }
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_discard_features() {
+        let mut dummy = DummyBlock::new(false);
+        let b = dummy.block();
+        assert_ne!(b.avail_features() & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(b.avail_features() & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+
+        let mut max_discard_seg = [0u8; 4];
+        b.read_config(CONFIG_DISCARD_OFFSET as u64 + 4, &mut max_discard_seg);
+        assert_eq!(u32::from_le_bytes(max_discard_seg), MAX_DISCARD_SEGMENTS);
+        let mut write_zeroes_may_unmap = [0u8; 1];
+        b.read_config(
+            CONFIG_DISCARD_OFFSET as u64 + 20,
+            &mut write_zeroes_may_unmap,
+        );
+        assert_eq!(write_zeroes_may_unmap[0], 1);
+        assert_eq!(build_config_space(0x1000, 1).len(), CONFIG_SPACE_SIZE);
+
+        // Read only drives do not offer discarding.
+        let mut dummy = DummyBlock::new(true);
+        let b = dummy.block();
+        assert_eq!(b.avail_features() & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_eq!(b.avail_features() & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let new_block = |num_queues| {
+            let f: File = tempfile().unwrap();
+            f.set_len(0x1000).unwrap();
+            Block::new(
+                DiskImage::Raw(f),
+                false,
+                EpollConfig::new(0, epoll_raw_fd, sender.clone()),
+                None,
+                IoEngine::Sync,
+                num_queues,
+                CacheType::Writeback,
+                Arc::default(),
+            )
+        };
+        assert!(new_block(0).is_err());
+        assert!(new_block(MAX_NUM_QUEUES + 1).is_err());
+
+        let b = new_block(1).unwrap();
+        assert_eq!(b.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+
+        let b = new_block(4).unwrap();
+        assert_eq!(b.queue_max_sizes(), &[QUEUE_SIZE; 4]);
+        assert_ne!(b.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        let mut num_queues = [0u8; 2];
+        b.read_config(CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 4);
+        assert_eq!(block_events_count(4), 6);
+        unsafe { libc::close(epoll_raw_fd) };
+
+        // Requests are processed on the queue whose event fired.
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq0) = default_test_blockepollhandler(&m);
+        let vq1 = VirtQueue::new(GuestAddress(0x8000), &m, 16);
+        h.queues.push(vq1.create_queue());
+        h.queue_evts.push(EventFd::new().unwrap());
+
+        for i in 0..3 {
+            vq1.avail.ring[i].set(i as u16);
+            vq1.dtable[i].set(
+                (0x9000 + 0x1000 * i) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq1.dtable[1].len.set(8);
+        vq1.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq1.avail.idx.set(1);
+        let status_addr = GuestAddress(0xb000);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x9000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(1, GuestAddress(0x9000 + 8))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(123_456_789, GuestAddress(0xa000))
+            .unwrap();
+
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT + 1, EPOLLIN).unwrap();
+        assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(vq0.used.idx.get(), 0);
+        assert_eq!(vq1.used.idx.get(), 1);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        // Asynchronous completions are returned to the queue of their request.
+        h.async_io = Some(AsyncIo::new(2).unwrap());
+        vq1.used.idx.set(0);
+        h.set_queue(1, vq1.create_queue());
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_S_IOERR, status_addr)
+            .unwrap();
+
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT + 1, EPOLLIN).unwrap();
+        assert_eq!(vq1.used.idx.get(), 0);
+        invoke_handler_for_io_completion_event(&mut h);
+        assert_eq!(vq0.used.idx.get(), 0);
+        assert_eq!(vq1.used.idx.get(), 1);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        // Both requests are accounted in the metrics of the device.
+        assert_eq!(h.metrics.device().write_count.count(), 2);
+        assert_eq!(h.metrics.device().write_bytes.count(), 16);
+
+        // There is no event past the last queue.
+        match h.handle_event(QUEUE_AVAIL_EVENT + 2, EPOLLIN) {
+            Err(DeviceError::UnknownEvent { event, .. }) => {
+                assert_eq!(event, QUEUE_AVAIL_EVENT + 2)
+            }
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_patch_rate_limiter() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+
+        h.set_rate_limiter(RateLimiter::new(10, None, 10, 2, None, 2).unwrap());
+
+        let bytes = TokenBucket::new(1000, Some(1001), 1002);
+        let read_ops = TokenBucket::new(1003, Some(1004), 1005);
+        let write_bytes = TokenBucket::new(1006, Some(1007), 1008);
+
+        h.patch_rate_limiter(
+            Some(bytes.clone()),
+            None,
+            None,
+            Some(read_ops.clone()),
+            Some(write_bytes.clone()),
+            None,
+        );
+
+        let compare_buckets = |a: &TokenBucket, b: &TokenBucket| {
+            assert_eq!(a.capacity(), b.capacity());
+            assert_eq!(a.one_time_burst(), b.one_time_burst());
+            assert_eq!(a.refill_time_ms(), b.refill_time_ms());
+        };
+
+        let rl = h.get_rate_limiter();
+        compare_buckets(rl.bandwidth().unwrap(), &bytes);
+        // The buckets that were not patched are left unchanged.
+        assert_eq!(rl.ops().unwrap().capacity(), 2);
+        assert!(rl.read_bandwidth().is_none());
+        compare_buckets(rl.read_ops().unwrap(), &read_ops);
+        compare_buckets(rl.write_bandwidth().unwrap(), &write_bytes);
+        assert!(rl.write_ops().is_none());
+    }
+
+    #[test]
+    fn test_cache_type() {
+        assert_eq!(CacheType::default(), CacheType::Writeback);
+
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let new_block = |cache_type| {
+            let f: File = tempfile().unwrap();
+            f.set_len(0x1000).unwrap();
+            Block::new(
+                DiskImage::Raw(f),
+                false,
+                EpollConfig::new(0, epoll_raw_fd, sender.clone()),
+                None,
+                IoEngine::Sync,
+                1,
+                cache_type,
+                Arc::default(),
+            )
+            .unwrap()
+        };
+        // Only the devices honouring flushes offer them.
+        let b = new_block(CacheType::Unsafe);
+        assert_eq!(b.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+        let b = new_block(CacheType::Writeback);
+        assert_ne!(b.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+        let b = new_block(CacheType::Direct);
+        assert_ne!(b.avail_features() & (1u64 << VIRTIO_BLK_F_FLUSH), 0);
+        unsafe { libc::close(epoll_raw_fd) };
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let metrics = DeviceMetrics::new(&METRICS.block, Arc::default());
+        let mut request = Request {
+            request_type: RequestType::Flush,
+            sector: 0,
+            data_addr: GuestAddress(0),
+            data_len: 0,
+            status_addr: GuestAddress(0x200),
+        };
+        // Unsafe flushes complete without going through the asynchronous engine.
+        assert!(!request.is_async(CacheType::Unsafe));
+        assert!(request.is_async(CacheType::Writeback));
+        let mut f = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        for cache_type in &[CacheType::Unsafe, CacheType::Writeback] {
+            assert_eq!(
+                request
+                    .execute(&mut f, 8, &m, &[], *cache_type, &metrics)
+                    .unwrap(),
+                0
+            );
+        }
+        assert_eq!(metrics.device().flush_count.count(), 2);
+
+        // Direct disk images transfer unaligned guest buffers.
+        let mut disk = DiskImage::Direct(DirectFile::new(f).unwrap());
+        assert!(!disk.is_raw());
+        m.write_slice_at_addr(&[0xab; 0x400], GuestAddress(0x123))
+            .unwrap();
+        request.request_type = RequestType::Out;
+        request.sector = 2;
+        request.data_addr = GuestAddress(0x123);
+        request.data_len = 0x400;
+        request
+            .execute(&mut disk, 8, &m, &[], CacheType::Direct, &metrics)
+            .unwrap();
+        request.request_type = RequestType::Flush;
+        request
+            .execute(&mut disk, 8, &m, &[], CacheType::Direct, &metrics)
+            .unwrap();
+        let mut data = [0u8; 0x400];
+        disk.file()
+            .unwrap()
+            .read_exact_at(&mut data, 0x400)
+            .unwrap();
+        assert!(data.iter().all(|&b| b == 0xab));
+
+        request.request_type = RequestType::In;
+        request.sector = 1;
+        request.data_addr = GuestAddress(0x601);
+        assert_eq!(
+            request
+                .execute(&mut disk, 8, &m, &[], CacheType::Direct, &metrics)
+                .unwrap(),
+            0x400
+        );
+        let mut data = [0u8; 0x400];
+        m.read_slice_at_addr(&mut data, GuestAddress(0x601))
+            .unwrap();
+        assert!(data[..0x200].iter().all(|&b| b == 0));
+        assert!(data[0x200..].iter().all(|&b| b == 0xab));
+    }
 }
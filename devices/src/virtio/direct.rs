@@ -0,0 +1,370 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the access to raw disk images opened with `O_DIRECT`, which bypass the host page
+//! cache.
+//!
+//! Direct I/O requires the file offsets, the lengths and the memory buffers of the transfers to be
+//! aligned on the logical block size of the storage. Guest buffers have no such guarantee, so the
+//! data goes through an aligned bounce buffer, and the partial blocks at both ends of a write are
+//! completed with their current contents.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_int;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
+
+use sys_util::{ioctl_with_mut_ref, SyscallReturnCode};
+
+use super::block::{fallocate, write_zero_bytes, DiskFile, SECTOR_SIZE};
+use super::qcow::add_signed;
+
+// Alignment of the bounce buffer in memory, which bounds the supported block sizes.
+const BUFFER_ALIGNMENT: usize = 4096;
+// Size of the bounce buffer, which bounds the size of a single transfer.
+const BUFFER_SIZE: usize = 1 << 20;
+
+// See include/uapi/linux/fs.h in the kernel code.
+ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+
+// The number of statx, which the libc crate only defines for some targets. See
+// arch/x86/entry/syscalls/syscall_64.tbl and include/uapi/asm-generic/unistd.h in the kernel code.
+#[cfg(target_arch = "x86_64")]
+const SYS_STATX: libc::c_long = 332;
+#[cfg(target_arch = "aarch64")]
+const SYS_STATX: libc::c_long = 291;
+
+// See include/uapi/linux/stat.h in the kernel code. The direct I/O alignment is reported since
+// Linux 6.1, at these offsets of `struct statx`.
+const STATX_DIOALIGN: u32 = 0x2000;
+const STATX_SIZE: usize = 256;
+const STATX_DIO_OFFSET_ALIGN: usize = 156;
+
+// Returns the direct I/O alignment of the file reported by `statx`, if the kernel knows it.
+fn statx_dio_align(file: &File) -> io::Result<Option<u64>> {
+    let mut statx = [0u8; STATX_SIZE];
+    // This is safe because the kernel writes at most `STATX_SIZE` bytes to `statx`.
+    SyscallReturnCode(unsafe {
+        libc::syscall(
+            SYS_STATX,
+            file.as_raw_fd(),
+            b"\0".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            STATX_DIOALIGN,
+            statx.as_mut_ptr(),
+        )
+    } as c_int)
+    .into_empty_result()?;
+    let read_u32 = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&statx[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+    match read_u32(STATX_DIO_OFFSET_ALIGN) {
+        align if read_u32(0) & STATX_DIOALIGN != 0 && align != 0 => Ok(Some(u64::from(align))),
+        _ => Ok(None),
+    }
+}
+
+// Returns the logical block size of the storage holding `file`, on which the direct transfers
+// must be aligned. Without a way to query it, the file is assumed to use 512 bytes sectors.
+fn logical_block_size(file: &File) -> io::Result<u64> {
+    let block_size = if file.metadata()?.file_type().is_block_device() {
+        let mut block_size: c_int = 0;
+        // This is safe because BLKSSZGET only writes an int to `block_size`.
+        SyscallReturnCode(unsafe { ioctl_with_mut_ref(file, BLKSSZGET(), &mut block_size) })
+            .into_empty_result()?;
+        block_size as u64
+    } else {
+        match statx_dio_align(file) {
+            Ok(align) => align.unwrap_or(SECTOR_SIZE),
+            // Kernels older than 4.11 do not have statx.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => SECTOR_SIZE,
+            Err(e) => return Err(e),
+        }
+    };
+    if !block_size.is_power_of_two() || block_size > BUFFER_ALIGNMENT as u64 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(block_size)
+}
+
+/// A raw disk image opened with `O_DIRECT`.
+pub struct DirectFile {
+    file: File,
+    // The alignment of the transfers, a power of two.
+    block_size: u64,
+    // Holds the aligned bounce buffer, which starts at `buffer_start`.
+    buffer: Vec<u8>,
+    buffer_start: usize,
+    current_offset: u64,
+}
+
+impl DirectFile {
+    /// Accesses the disk image held by `file`, which should be opened with `O_DIRECT`. The
+    /// transfers are aligned on the logical block size of the storage holding the file.
+    pub fn new(file: File) -> io::Result<DirectFile> {
+        let block_size = logical_block_size(&file)?;
+        // The vector is never resized, so its contents never move.
+        let buffer = vec![0; BUFFER_SIZE + BUFFER_ALIGNMENT];
+        let misalignment = buffer.as_ptr() as usize % BUFFER_ALIGNMENT;
+        Ok(DirectFile {
+            file,
+            block_size,
+            buffer,
+            buffer_start: (BUFFER_ALIGNMENT - misalignment) % BUFFER_ALIGNMENT,
+            current_offset: 0,
+        })
+    }
+
+    fn align_down(&self, offset: u64) -> u64 {
+        offset & !(self.block_size - 1)
+    }
+
+    fn align_up(&self, offset: u64) -> u64 {
+        self.align_down(offset + self.block_size - 1)
+    }
+
+    /// Returns the file holding the disk image.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn bounce_buffer(&mut self, len: usize) -> &mut [u8] {
+        &mut self.buffer[self.buffer_start..self.buffer_start + len]
+    }
+
+    // Reads the block at `offset` into the bounce buffer, at `buffer_offset`. The part of the
+    // block past the end of the file reads as zeroes.
+    fn read_block(&mut self, offset: u64, buffer_offset: usize) -> io::Result<()> {
+        let end = buffer_offset + self.block_size as usize;
+        let file = &self.file;
+        let block = &mut self.buffer[self.buffer_start + buffer_offset..self.buffer_start + end];
+        let count = file.read_at(block, offset)?;
+        for byte in &mut block[count..] {
+            *byte = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let start = self.align_down(self.current_offset);
+        let skip = (self.current_offset - start) as usize;
+        let len = self.align_up((skip + cmp::min(buf.len(), BUFFER_SIZE - skip)) as u64) as usize;
+        let file = &self.file;
+        let bounce = &mut self.buffer[self.buffer_start..self.buffer_start + len];
+        let count = file.read_at(bounce, start)?;
+        if count <= skip {
+            // The end of the file was reached.
+            return Ok(0);
+        }
+        let count = cmp::min(count - skip, buf.len());
+        buf[..count].copy_from_slice(&bounce[skip..skip + count]);
+        self.current_offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let start = self.align_down(self.current_offset);
+        let skip = (self.current_offset - start) as usize;
+        let count = cmp::min(buf.len(), BUFFER_SIZE - skip);
+        let end = self.current_offset + count as u64;
+        let len = (self.align_up(end) - start) as usize;
+
+        if skip != 0 {
+            self.read_block(start, 0)?;
+        }
+        if end != self.align_up(end) {
+            self.read_block(self.align_down(end), len - self.block_size as usize)?;
+        }
+        self.bounce_buffer(len)[skip..skip + count].copy_from_slice(&buf[..count]);
+
+        let file = &self.file;
+        file.write_all_at(
+            &self.buffer[self.buffer_start..self.buffer_start + len],
+            start,
+        )?;
+        self.current_offset = end;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Nothing is cached by the host.
+        Ok(())
+    }
+}
+
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(self.file.metadata()?.len(), offset),
+            SeekFrom::Current(offset) => add_signed(self.current_offset, offset),
+        };
+        match offset {
+            Some(offset) => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl DiskFile for DirectFile {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.file.punch_hole(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fallocate(
+            self.file.as_raw_fd(),
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        ) {
+            // The zeroes then go through the bounce buffer as well.
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zero_bytes(self, offset, len)
+            }
+            result => result,
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        // The data may still be cached by the storage device.
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use self::tempfile::{tempfile, NamedTempFile};
+    use super::*;
+
+    #[test]
+    fn test_block_size() {
+        let direct = DirectFile::new(tempfile().unwrap()).unwrap();
+        assert!(direct.block_size.is_power_of_two());
+        assert!(direct.block_size >= SECTOR_SIZE);
+        assert!(direct.block_size <= BUFFER_ALIGNMENT as u64);
+
+        let mut direct = direct;
+        direct.block_size = 0x1000;
+        assert_eq!(direct.align_down(0x1fff), 0x1000);
+        assert_eq!(direct.align_up(0x1001), 0x2000);
+        assert_eq!(direct.align_up(0x2000), 0x2000);
+    }
+
+    #[test]
+    fn test_bounce_buffer() {
+        let mut direct = DirectFile::new(tempfile().unwrap()).unwrap();
+        let bounce = direct.bounce_buffer(BUFFER_SIZE);
+        assert_eq!(bounce.as_ptr() as usize % BUFFER_ALIGNMENT, 0);
+        assert_eq!(bounce.len(), BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let file = tempfile().unwrap();
+        file.write_all_at(&[0xaa; 0x1000], 0).unwrap();
+        let mut direct = DirectFile::new(file).unwrap();
+        assert_eq!(direct.seek(SeekFrom::End(0)).unwrap(), 0x1000);
+
+        // Writes covering partial sectors keep the rest of the sectors.
+        direct.seek(SeekFrom::Start(0x1f0)).unwrap();
+        direct.write_all(&[0x55; 0x20]).unwrap();
+        let mut data = [0u8; 0x400];
+        direct.file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..0x1f0].iter().all(|&b| b == 0xaa));
+        assert!(data[0x1f0..0x210].iter().all(|&b| b == 0x55));
+        assert!(data[0x210..].iter().all(|&b| b == 0xaa));
+
+        // Unaligned reads return the requested bytes only.
+        direct.seek(SeekFrom::Start(0x1e0)).unwrap();
+        let mut data = [0u8; 0x40];
+        direct.read_exact(&mut data).unwrap();
+        assert!(data[..0x10].iter().all(|&b| b == 0xaa));
+        assert!(data[0x10..0x30].iter().all(|&b| b == 0x55));
+        assert!(data[0x30..].iter().all(|&b| b == 0xaa));
+        assert_eq!(direct.current_offset, 0x220);
+
+        // Transfers larger than the bounce buffer are split.
+        let data = vec![0x11; BUFFER_SIZE + 0x200];
+        direct.seek(SeekFrom::Start(0x200)).unwrap();
+        direct.write_all(&data).unwrap();
+        assert_eq!(
+            direct.seek(SeekFrom::End(0)).unwrap(),
+            BUFFER_SIZE as u64 + 0x400
+        );
+        let mut read = vec![0; data.len()];
+        direct.seek(SeekFrom::Start(0x200)).unwrap();
+        direct.read_exact(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // Reads stop at the end of the file.
+        let mut data = [0u8; 0x400];
+        direct.seek(SeekFrom::End(-0x100)).unwrap();
+        assert_eq!(direct.read(&mut data).unwrap(), 0x100);
+        assert_eq!(direct.read(&mut data).unwrap(), 0);
+
+        direct.write_zeroes(0, 0x400).unwrap();
+        direct.file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+        direct.sync().unwrap();
+
+        // Writes covering partial blocks keep the rest of the blocks, whatever the block size.
+        direct.block_size = 0x1000;
+        direct.seek(SeekFrom::Start(0xff0)).unwrap();
+        direct.write_all(&[0x77; 0x20]).unwrap();
+        let mut data = [0u8; 0x2000];
+        direct.file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..0x400].iter().all(|&b| b == 0));
+        assert!(data[0x400..0xff0].iter().all(|&b| b == 0x11));
+        assert!(data[0xff0..0x1010].iter().all(|&b| b == 0x77));
+        assert!(data[0x1010..].iter().all(|&b| b == 0x11));
+    }
+
+    #[test]
+    fn test_o_direct() {
+        let temp_file = NamedTempFile::new().unwrap();
+        temp_file.as_file().set_len(0x2000).unwrap();
+        // Some file systems, such as older versions of tmpfs, do not support direct I/O.
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(temp_file.path())
+        {
+            Ok(file) => file,
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("Cannot open the file: {:?}", e),
+        };
+        let mut direct = DirectFile::new(file).unwrap();
+        direct.seek(SeekFrom::Start(0x201)).unwrap();
+        direct.write_all(&[0x42; 0x300]).unwrap();
+        let mut data = [0u8; 0x302];
+        direct.seek(SeekFrom::Start(0x200)).unwrap();
+        direct.read_exact(&mut data).unwrap();
+        assert_eq!(data[0], 0);
+        assert!(data[1..0x301].iter().all(|&b| b == 0x42));
+        assert_eq!(data[0x301], 0);
+    }
+}
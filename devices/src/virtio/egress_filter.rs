@@ -0,0 +1,211 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filters the frames sent by a guest through its network interface, so that it cannot
+//! impersonate other hosts on the network.
+
+use std::net::Ipv4Addr;
+
+use dumbo::{
+    EthIPv4ArpFrame, EthernetFrame, IPv4Packet, MacAddr, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+    ETH_IPV4_FRAME_LEN,
+};
+
+// The length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV4_VERSION: u8 = 4;
+// The ethertypes of the 802.1Q VLAN tag and of the 802.1ad service tag.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// Drops the frames whose source MAC address is not the one of the guest, along with the ARP
+/// frames whose sender hardware address is not the one of the guest. When a list of allowed IPv4
+/// addresses is set, the IPv4 packets and the ARP frames whose sender address is not in the list
+/// are dropped too. VLAN tagged frames are dropped, since the tags would hide the ARP and IPv4
+/// headers from the checks. The frames of other protocols are only subject to the MAC address
+/// check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EgressFilter {
+    guest_mac: MacAddr,
+    allowed_ipv4_addresses: Option<Vec<Ipv4Addr>>,
+}
+
+impl EgressFilter {
+    /// Creates a filter for a guest using `guest_mac`. The IPv4 sender addresses are not checked
+    /// when `allowed_ipv4_addresses` is None.
+    pub fn new(guest_mac: MacAddr, allowed_ipv4_addresses: Option<Vec<Ipv4Addr>>) -> Self {
+        EgressFilter {
+            guest_mac,
+            allowed_ipv4_addresses,
+        }
+    }
+
+    /// Returns whether the Ethernet frame `frame` may leave the guest. The frames that cannot be
+    /// parsed far enough to be checked are dropped.
+    pub fn allows(&self, frame: &[u8]) -> bool {
+        let eth_frame = match EthernetFrame::from_bytes(frame) {
+            Ok(eth_frame) => eth_frame,
+            Err(_) => return false,
+        };
+        if eth_frame.src_mac() != self.guest_mac {
+            return false;
+        }
+
+        match eth_frame.ethertype() {
+            ETHERTYPE_ARP => {
+                // The ARP frame may be followed by the padding of the Ethernet frame.
+                let arp_frame = eth_frame
+                    .payload()
+                    .get(..ETH_IPV4_FRAME_LEN)
+                    .and_then(|bytes| EthIPv4ArpFrame::from_bytes(bytes).ok());
+                match arp_frame {
+                    Some(arp_frame) => {
+                        arp_frame.sha() == self.guest_mac && self.allows_ipv4(arp_frame.spa())
+                    }
+                    None => false,
+                }
+            }
+            ETHERTYPE_IPV4 if self.allowed_ipv4_addresses.is_some() => {
+                // Only the source address is checked, the host drops invalid packets anyway.
+                let payload = eth_frame.payload();
+                if payload.len() < IPV4_MIN_HEADER_LEN {
+                    return false;
+                }
+                let packet = IPv4Packet::from_bytes_unchecked(payload);
+                packet.version_and_header_len().0 == IPV4_VERSION
+                    && self.allows_ipv4(packet.source_address())
+            }
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ => false,
+            _ => true,
+        }
+    }
+
+    fn allows_ipv4(&self, addr: Ipv4Addr) -> bool {
+        match self.allowed_ipv4_addresses {
+            Some(ref allowed_ipv4_addresses) => allowed_ipv4_addresses.contains(&addr),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETH_HEADER_LEN: usize = 14;
+
+    // Writes an Ethernet header to `buf`.
+    fn write_eth_header(buf: &mut [u8], src_mac: MacAddr, ethertype: u16) {
+        buf[..6].copy_from_slice(&[0xff; 6]);
+        buf[6..12].copy_from_slice(src_mac.get_bytes());
+        buf[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    }
+
+    fn ipv4_frame(src_mac: MacAddr, src_addr: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_HEADER_LEN + IPV4_MIN_HEADER_LEN];
+        write_eth_header(&mut frame, src_mac, ETHERTYPE_IPV4);
+        let header = &mut frame[ETH_HEADER_LEN..];
+        header[0] = IPV4_VERSION << 4 | 5;
+        header[2..4].copy_from_slice(&(IPV4_MIN_HEADER_LEN as u16).to_be_bytes());
+        header[12..16].copy_from_slice(&src_addr.octets());
+        frame
+    }
+
+    fn arp_frame(src_mac: MacAddr, sha: MacAddr, spa: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_HEADER_LEN + ETH_IPV4_FRAME_LEN];
+        write_eth_header(&mut frame, src_mac, ETHERTYPE_ARP);
+        EthIPv4ArpFrame::write_reply(
+            &mut frame[ETH_HEADER_LEN..],
+            sha,
+            spa,
+            MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap();
+        frame
+    }
+
+    #[test]
+    fn test_mac_filter() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let other_mac = MacAddr::parse_str("12:34:56:78:9a:bd").unwrap();
+        let addr = Ipv4Addr::new(10, 0, 0, 2);
+        let filter = EgressFilter::new(guest_mac, None);
+
+        assert!(filter.allows(&ipv4_frame(guest_mac, addr)));
+        assert!(!filter.allows(&ipv4_frame(other_mac, addr)));
+        // Any IPv4 address is allowed.
+        assert!(filter.allows(&ipv4_frame(guest_mac, Ipv4Addr::new(10, 0, 0, 3))));
+
+        // ARP frames are checked for their sender hardware address too.
+        assert!(filter.allows(&arp_frame(guest_mac, guest_mac, addr)));
+        assert!(!filter.allows(&arp_frame(guest_mac, other_mac, addr)));
+        assert!(!filter.allows(&arp_frame(other_mac, guest_mac, addr)));
+
+        // Frames that are too short are dropped.
+        assert!(!filter.allows(&[0u8; ETH_HEADER_LEN - 1]));
+        let frame = arp_frame(guest_mac, guest_mac, addr);
+        assert!(!filter.allows(&frame[..frame.len() - 1]));
+
+        // The frames of other protocols only go through the MAC address check.
+        let mut frame = [0u8; ETH_HEADER_LEN];
+        write_eth_header(&mut frame, guest_mac, 0x86dd);
+        assert!(filter.allows(&frame));
+        write_eth_header(&mut frame, other_mac, 0x86dd);
+        assert!(!filter.allows(&frame));
+
+        // VLAN tagged frames are dropped, whatever their inner ethertype.
+        let mut frame = [0u8; ETH_HEADER_LEN + 4];
+        write_eth_header(&mut frame, guest_mac, ETHERTYPE_VLAN);
+        frame[ETH_HEADER_LEN + 2..].copy_from_slice(&0x86ddu16.to_be_bytes());
+        assert!(!filter.allows(&frame));
+        write_eth_header(&mut frame, guest_mac, ETHERTYPE_QINQ);
+        assert!(!filter.allows(&frame));
+    }
+
+    #[test]
+    fn test_ipv4_filter() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let allowed = Ipv4Addr::new(10, 0, 0, 2);
+        let other = Ipv4Addr::new(10, 0, 0, 3);
+        let filter = EgressFilter::new(guest_mac, Some(vec![Ipv4Addr::new(0, 0, 0, 0), allowed]));
+
+        assert!(filter.allows(&ipv4_frame(guest_mac, allowed)));
+        assert!(filter.allows(&ipv4_frame(guest_mac, Ipv4Addr::new(0, 0, 0, 0))));
+        assert!(!filter.allows(&ipv4_frame(guest_mac, other)));
+
+        assert!(filter.allows(&arp_frame(guest_mac, guest_mac, allowed)));
+        assert!(!filter.allows(&arp_frame(guest_mac, guest_mac, other)));
+
+        // Padded ARP frames are fine.
+        let mut frame = arp_frame(guest_mac, guest_mac, allowed);
+        frame.resize(60, 0);
+        assert!(filter.allows(&frame));
+
+        // IPv4 packets that are too short or of the wrong version are dropped.
+        let frame = ipv4_frame(guest_mac, allowed);
+        assert!(!filter.allows(&frame[..frame.len() - 1]));
+        let mut frame = ipv4_frame(guest_mac, allowed);
+        frame[ETH_HEADER_LEN] = 6 << 4 | 5;
+        assert!(!filter.allows(&frame));
+
+        // A spoofed IPv4 packet cannot hide behind a VLAN tag.
+        let mut frame = ipv4_frame(guest_mac, other);
+        let tag = [0x81, 0x00, 0x00, 0x0a];
+        frame.splice(12..12, tag.iter().cloned());
+        assert_eq!(u16::from_be_bytes([frame[16], frame[17]]), ETHERTYPE_IPV4);
+        assert!(!filter.allows(&frame));
+        // Nor can a spoofed ARP frame.
+        let mut frame = arp_frame(guest_mac, guest_mac, other);
+        frame.splice(12..12, tag.iter().cloned());
+        assert!(!filter.allows(&frame));
+
+        // Nothing is allowed with an empty list, except for the frames of other protocols.
+        let filter = EgressFilter::new(guest_mac, Some(vec![]));
+        assert!(!filter.allows(&ipv4_frame(guest_mac, allowed)));
+        assert!(!filter.allows(&arp_frame(guest_mac, guest_mac, allowed)));
+        let mut frame = [0u8; ETH_HEADER_LEN];
+        write_eth_header(&mut frame, guest_mac, 0x86dd);
+        assert!(filter.allows(&frame));
+    }
+}
@@ -10,6 +10,7 @@ use std::sync::Arc;
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use fc_util::versioned::Versioned;
 use memory_model::{GuestAddress, GuestMemory};
 use sys_util::EventFd;
 
@@ -32,7 +33,7 @@ const MMIO_VERSION: u32 = 2;
 /// and all the events, memory, and queues for device operation will be moved into the device.
 /// Optionally, a virtio device can implement device reset in which it returns said resources and
 /// resets its internal.
-pub trait VirtioDevice: Send {
+pub trait VirtioDevice: AsAny + Send {
     /// Get the available features offered by device.
     fn avail_features(&self) -> u64;
 
@@ -111,6 +112,52 @@ pub trait VirtioDevice: Send {
     }
 }
 
+/// The serializable state of the MMIO transport of a virtio device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MmioDeviceState {
+    /// The virtio device type.
+    pub device_type: u32,
+    /// Whether the device was activated by the guest driver.
+    pub device_activated: bool,
+    /// The features page selected by the driver.
+    pub features_select: u32,
+    /// The acknowledged features page selected by the driver.
+    pub acked_features_select: u32,
+    /// The queue selected by the driver.
+    pub queue_select: u32,
+    /// The pending interrupt status bits.
+    pub interrupt_status: usize,
+    /// The status written by the driver.
+    pub driver_status: u32,
+    /// The configuration generation counter.
+    pub config_generation: u32,
+    /// The features acknowledged by the driver.
+    pub acked_features: u64,
+    /// The state of each virtio queue.
+    #[serde(with = "fc_util::versioned::vec")]
+    pub queues: Vec<QueueState>,
+}
+
+impl Versioned for MmioDeviceState {
+    const NAME: &'static str = "MmioDevice";
+    const VERSION: u16 = 1;
+}
+
+/// Errors triggered when restoring the MMIO transport state.
+#[derive(Debug)]
+pub enum RestoreError {
+    /// The saved state belongs to a different type of virtio device.
+    DeviceType(u32),
+    /// The number of saved queues does not match the device.
+    QueueCount(usize),
+    /// The device has already been activated.
+    AlreadyActivated,
+    /// The restored queues are not valid for the guest memory.
+    InvalidQueues,
+    /// The device failed to activate.
+    Activate(ActivateError),
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -170,6 +217,11 @@ impl MmioDevice {
         })
     }
 
+    /// Gets the encapsulated VirtioDevice.
+    pub fn device(&self) -> &dyn VirtioDevice {
+        &*self.device
+    }
+
     // Gets the encapsulated VirtioDevice
     pub fn device_mut(&mut self) -> &mut dyn VirtioDevice {
         &mut *self.device
@@ -187,6 +239,96 @@ impl MmioDevice {
         self.interrupt_evt.as_ref()
     }
 
+    /// Returns the current state of the transport.
+    ///
+    /// Once a device is activated, the queues are owned by its backend. The backends complete
+    /// every descriptor chain they pop before going back to the event loop, so the ring positions
+    /// are re-synced from the used ring index found in `mem`. The device backends must not be
+    /// processing events while the state is saved.
+    pub fn save_state(&self, mem: &GuestMemory) -> MmioDeviceState {
+        let queues = self
+            .queues
+            .iter()
+            .map(|q| {
+                let mut queue = q.clone();
+                if self.device_activated {
+                    queue.sync_with_used_ring(mem);
+                }
+                queue.save_state()
+            })
+            .collect();
+
+        MmioDeviceState {
+            device_type: self.device.device_type(),
+            device_activated: self.device_activated,
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation,
+            acked_features: self.device.acked_features(),
+            queues,
+        }
+    }
+
+    /// Restores the transport to a previously saved state, activating the device if it was
+    /// active when the state was saved.
+    pub fn restore_state(
+        &mut self,
+        state: &MmioDeviceState,
+    ) -> std::result::Result<(), RestoreError> {
+        if state.device_type != self.device.device_type() {
+            return Err(RestoreError::DeviceType(state.device_type));
+        }
+        if state.queues.len() != self.queues.len() {
+            return Err(RestoreError::QueueCount(state.queues.len()));
+        }
+        if self.device_activated {
+            return Err(RestoreError::AlreadyActivated);
+        }
+
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.driver_status = state.driver_status;
+        self.config_generation = state.config_generation;
+        self.device.set_acked_features(state.acked_features);
+        self.queues = state.queues.iter().map(Queue::from_state).collect();
+
+        if state.device_activated {
+            if !self.are_queues_valid() {
+                return Err(RestoreError::InvalidQueues);
+            }
+            if let (Some(interrupt_evt), Some(mem)) = (self.interrupt_evt.as_ref(), self.mem.take())
+            {
+                let interrupt_evt = interrupt_evt
+                    .try_clone()
+                    .map_err(|e| RestoreError::Activate(ActivateError::EpollCtl(e)))?;
+                // Kick every queue once, so that the backend picks up any descriptor chains
+                // the driver made available while the device was not being serviced.
+                for queue_evt in self.queue_evts.iter() {
+                    queue_evt
+                        .write(1)
+                        .map_err(|e| RestoreError::Activate(ActivateError::EpollCtl(e)))?;
+                }
+                self.device
+                    .activate(
+                        mem,
+                        interrupt_evt,
+                        self.interrupt_status.clone(),
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .map_err(RestoreError::Activate)?;
+                self.device_activated = true;
+            }
+        }
+        Ok(())
+    }
+
     fn check_driver_status(&self, set: u32, clr: u32) -> bool {
         self.driver_status & (set | clr) == set
     }
@@ -911,6 +1053,52 @@ mod tests {
         assert!(d.device_activated);
     }
 
+    #[test]
+    fn test_save_restore_state() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        activate_device(&mut d);
+        d.device.set_acked_features(0x24);
+        d.interrupt_status.store(1, Ordering::SeqCst);
+
+        let state = d.save_state(&m);
+        assert!(state.device_activated);
+        assert_eq!(state.device_type, d.device.device_type());
+        assert_eq!(state.acked_features, 0x24);
+        assert_eq!(state.interrupt_status, 1);
+        assert_eq!(state.queues.len(), 2);
+
+        // Restoring onto an already activated device is not allowed.
+        match d.restore_state(&state) {
+            Err(RestoreError::AlreadyActivated) => (),
+            _ => panic!("Unexpected restore result."),
+        }
+
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        restored.restore_state(&state).unwrap();
+        assert!(restored.device_activated);
+        assert_eq!(restored.driver_status, d.driver_status);
+        assert_eq!(restored.device.acked_features(), 0x24);
+        assert_eq!(restored.save_state(&m), state);
+
+        // A state with a different number of queues is rejected.
+        let mut bad_state = state.clone();
+        bad_state.queues.pop();
+        let mut other = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        match other.restore_state(&bad_state) {
+            Err(RestoreError::QueueCount(1)) => (),
+            _ => panic!("Unexpected restore result."),
+        }
+
+        // A state belonging to another device type is rejected.
+        bad_state = state.clone();
+        bad_state.device_type += 1;
+        match other.restore_state(&bad_state) {
+            Err(RestoreError::DeviceType(_)) => (),
+            _ => panic!("Unexpected restore result."),
+        }
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
@@ -12,16 +12,33 @@ use std::io::Error as IOError;
 use std::os::unix::io::RawFd;
 use std::sync::mpsc;
 
+pub mod balloon;
 pub mod block;
+pub mod direct;
+pub mod egress_filter;
 mod mmio;
+pub mod nbd;
 pub mod net;
+pub mod overlay;
+pub mod pcap;
+pub mod qcow;
 mod queue;
+pub mod trace;
+pub mod vhost;
 pub mod vsock;
 
+pub use self::balloon::*;
 pub use self::block::*;
+pub use self::direct::*;
+pub use self::egress_filter::*;
 pub use self::mmio::*;
+pub use self::nbd::*;
 pub use self::net::*;
+pub use self::overlay::*;
+pub use self::pcap::*;
+pub use self::qcow::*;
 pub use self::queue::*;
+pub use self::trace::*;
 pub use self::vsock::*;
 
 use super::EpollHandler;
@@ -37,6 +54,7 @@ const DEVICE_FAILED: u32 = 0x80;
 /// Type 0 is not used by virtio. Use it as wildcard for non-virtio devices
 pub const TYPE_NET: u32 = 1;
 pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_BALLOON: u32 = 5;
 
 /// Interrupt flags (re: interrupt status & acknowledge registers).
 /// See linux/virtio_mmio.h.
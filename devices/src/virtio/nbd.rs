@@ -0,0 +1,826 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a client of the Network Block Device protocol, as described in doc/proto.md in the
+//! NBD code, for serving the disk of a block device from a user space block server.
+//!
+//! The client goes through the fixed newstyle negotiation and selects its export with
+//! `NBD_OPT_EXPORT_NAME`. It then sends one request at a time and waits for its simple reply.
+//! When the connection to the server fails, the client connects again and retries the request.
+
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::block::{write_zero_bytes, DiskFile};
+use super::qcow::add_signed;
+
+const NBD_URI_SCHEME: &str = "nbd://";
+const NBD_UNIX_URI_SCHEME: &str = "nbd+unix://";
+const NBD_UNIX_URI_EXPORT: &str = "?export=";
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags of the server, and the matching flags of the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const MAX_EXPORT_NAME_SIZE: usize = 4096;
+// The reply to `NBD_OPT_EXPORT_NAME` ends with zeroes, unless `NBD_FLAG_NO_ZEROES` is agreed on.
+const EXPORT_NAME_REPLY_PADDING: usize = 124;
+
+// Transmission flags of the export.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+// The zeroed range must stay allocated.
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const REQUEST_HEADER_SIZE: usize = 28;
+const REPLY_HEADER_SIZE: usize = 16;
+// Bounds the data of a single read or write request, which servers may reject when too large.
+const MAX_TRANSFER_SIZE: u64 = 1 << 20;
+// Bounds the length of a single trim or write zeroes request.
+const MAX_RANGE_SIZE: u64 = 1 << 30;
+
+// Number of times a request is retried on a new connection, after the previous one failed.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+// Delay before connecting again, which grows with each attempt.
+const RECONNECT_DELAY_MS: u64 = 100;
+// A server which does not answer in time is considered unreachable.
+const IO_TIMEOUT_S: u64 = 30;
+
+/// Errors associated with NBD exports.
+#[derive(Debug)]
+pub enum NbdError {
+    /// The NBD URI is invalid.
+    InvalidUri(String),
+    /// Communicating with the server failed.
+    Io(io::Error),
+    /// The export is read only, while the drive is not.
+    ReadOnlyExport,
+    /// The server failed the request, with the given error value.
+    Request(u32),
+    /// The server sent an invalid message.
+    Protocol(&'static str),
+    /// The server does not support the fixed newstyle negotiation.
+    UnsupportedServer,
+}
+
+impl Display for NbdError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::NbdError::*;
+        match *self {
+            InvalidUri(ref uri) => write!(f, "Invalid NBD URI: {}", uri),
+            Io(ref e) => write!(f, "{}", e),
+            ReadOnlyExport => write!(f, "The NBD export is read only."),
+            Request(error) => write!(f, "The NBD server failed the request: {}", error),
+            Protocol(reason) => write!(f, "Invalid message from the NBD server: {}", reason),
+            UnsupportedServer => write!(
+                f,
+                "The NBD server does not support the fixed newstyle negotiation."
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for NbdError {
+    fn from(e: io::Error) -> Self {
+        NbdError::Io(e)
+    }
+}
+
+impl From<NbdError> for io::Error {
+    fn from(e: NbdError) -> Self {
+        match e {
+            NbdError::Io(e) => e,
+            // The error values of the protocol are the ones of Linux.
+            NbdError::Request(error) => io::Error::from_raw_os_error(error as i32),
+            e @ NbdError::InvalidUri(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+            }
+            e @ NbdError::ReadOnlyExport => {
+                io::Error::new(io::ErrorKind::PermissionDenied, e.to_string())
+            }
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, NbdError>;
+
+/// Returns true if `path` is an NBD URI rather than the path of a file.
+pub fn is_nbd_uri(path: &Path) -> bool {
+    path.to_str().map_or(false, |path| {
+        path.starts_with(NBD_URI_SCHEME) || path.starts_with(NBD_UNIX_URI_SCHEME)
+    })
+}
+
+/// The address of an NBD server, and the name of the export.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbdAddress {
+    /// An export served over TCP, written `nbd://<ip>:<port>[/<export name>]`.
+    Tcp(SocketAddr, String),
+    /// An export served over a Unix domain socket, written
+    /// `nbd+unix://<socket path>[?export=<export name>]`.
+    Unix(PathBuf, String),
+}
+
+impl NbdAddress {
+    /// Parses the NBD URI held by `path`.
+    pub fn parse(path: &Path) -> Result<NbdAddress> {
+        let uri = path
+            .to_str()
+            .ok_or_else(|| NbdError::InvalidUri(path.to_string_lossy().into_owned()))?;
+        let invalid_uri = || NbdError::InvalidUri(uri.to_string());
+        let address = if uri.starts_with(NBD_URI_SCHEME) {
+            let rest = &uri[NBD_URI_SCHEME.len()..];
+            let (socket_addr, export_name) = match rest.find('/') {
+                Some(index) => (&rest[..index], &rest[index + 1..]),
+                None => (rest, ""),
+            };
+            // Host names are not resolved.
+            let socket_addr = socket_addr.parse().map_err(|_| invalid_uri())?;
+            NbdAddress::Tcp(socket_addr, export_name.to_string())
+        } else if uri.starts_with(NBD_UNIX_URI_SCHEME) {
+            let rest = &uri[NBD_UNIX_URI_SCHEME.len()..];
+            let (socket_path, export_name) = match rest.find(NBD_UNIX_URI_EXPORT) {
+                Some(index) => (&rest[..index], &rest[index + NBD_UNIX_URI_EXPORT.len()..]),
+                None => (rest, ""),
+            };
+            if !socket_path.starts_with('/') {
+                return Err(invalid_uri());
+            }
+            NbdAddress::Unix(PathBuf::from(socket_path), export_name.to_string())
+        } else {
+            return Err(invalid_uri());
+        };
+        if address.export_name().len() > MAX_EXPORT_NAME_SIZE {
+            return Err(invalid_uri());
+        }
+        Ok(address)
+    }
+
+    /// Returns the name of the export.
+    pub fn export_name(&self) -> &str {
+        match *self {
+            NbdAddress::Tcp(_, ref export_name) | NbdAddress::Unix(_, ref export_name) => {
+                export_name
+            }
+        }
+    }
+}
+
+impl Display for NbdAddress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            NbdAddress::Tcp(ref socket_addr, ref export_name) if export_name.is_empty() => {
+                write!(f, "{}{}", NBD_URI_SCHEME, socket_addr)
+            }
+            NbdAddress::Tcp(ref socket_addr, ref export_name) => {
+                write!(f, "{}{}/{}", NBD_URI_SCHEME, socket_addr, export_name)
+            }
+            NbdAddress::Unix(ref socket_path, ref export_name) if export_name.is_empty() => {
+                write!(f, "{}{}", NBD_UNIX_URI_SCHEME, socket_path.display())
+            }
+            NbdAddress::Unix(ref socket_path, ref export_name) => write!(
+                f,
+                "{}{}{}{}",
+                NBD_UNIX_URI_SCHEME,
+                socket_path.display(),
+                NBD_UNIX_URI_EXPORT,
+                export_name
+            ),
+        }
+    }
+}
+
+// A connection to an NBD server.
+enum NbdStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl NbdStream {
+    fn connect(address: &NbdAddress) -> io::Result<NbdStream> {
+        let timeout = Some(Duration::from_secs(IO_TIMEOUT_S));
+        match *address {
+            NbdAddress::Tcp(ref socket_addr, _) => {
+                let stream = TcpStream::connect(socket_addr)?;
+                // Requests are written at once, and waited for.
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                Ok(NbdStream::Tcp(stream))
+            }
+            NbdAddress::Unix(ref socket_path, _) => {
+                let stream = UnixStream::connect(socket_path)?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                Ok(NbdStream::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            NbdStream::Tcp(ref mut stream) => stream.read(buf),
+            NbdStream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            NbdStream::Tcp(ref mut stream) => stream.write(buf),
+            NbdStream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            NbdStream::Tcp(ref mut stream) => stream.flush(),
+            NbdStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+// Connects to the server at `address` and negotiates the export. Returns the connection, the
+// size of the export and its transmission flags.
+fn connect(address: &NbdAddress) -> Result<(NbdStream, u64, u16)> {
+    let mut stream = NbdStream::connect(address)?;
+
+    let mut greeting = [0u8; 18];
+    stream.read_exact(&mut greeting)?;
+    if BigEndian::read_u64(&greeting[0..8]) != NBD_MAGIC {
+        return Err(NbdError::Protocol("invalid magic"));
+    }
+    if BigEndian::read_u64(&greeting[8..16]) != NBD_OPTS_MAGIC {
+        // Only the oldstyle negotiation is supported.
+        return Err(NbdError::UnsupportedServer);
+    }
+    let handshake_flags = BigEndian::read_u16(&greeting[16..18]);
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(NbdError::UnsupportedServer);
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+
+    let export_name = address.export_name().as_bytes();
+    let mut option = vec![0u8; 20];
+    BigEndian::write_u32(&mut option[0..4], client_flags);
+    BigEndian::write_u64(&mut option[4..12], NBD_OPTS_MAGIC);
+    BigEndian::write_u32(&mut option[12..16], NBD_OPT_EXPORT_NAME);
+    BigEndian::write_u32(&mut option[16..20], export_name.len() as u32);
+    option.extend_from_slice(export_name);
+    stream.write_all(&option)?;
+
+    // The server closes the connection if the export does not exist.
+    let mut reply = vec![0u8; 10];
+    if !no_zeroes {
+        reply.resize(10 + EXPORT_NAME_REPLY_PADDING, 0);
+    }
+    stream.read_exact(&mut reply)?;
+    let size = BigEndian::read_u64(&reply[0..8]);
+    let transmission_flags = BigEndian::read_u16(&reply[8..10]);
+    Ok((stream, size, transmission_flags))
+}
+
+/// A disk served by an NBD server.
+pub struct NbdDisk {
+    address: NbdAddress,
+    // The current connection, if any.
+    stream: Option<NbdStream>,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+    current_offset: u64,
+}
+
+impl NbdDisk {
+    /// Connects to the NBD server at `address`. Writable drives cannot use read only exports.
+    pub fn connect(address: &NbdAddress, writable: bool) -> Result<NbdDisk> {
+        let (stream, size, flags) = connect(address)?;
+        if writable && flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(NbdError::ReadOnlyExport);
+        }
+        Ok(NbdDisk {
+            address: address.clone(),
+            stream: Some(stream),
+            size,
+            flags,
+            next_handle: 0,
+            current_offset: 0,
+        })
+    }
+
+    /// Returns the address of the server.
+    pub fn address(&self) -> &NbdAddress {
+        &self.address
+    }
+
+    // Sends the request and waits for its reply, whose data is read into `data_in`.
+    fn transmit(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data_out: &[u8],
+        data_in: &mut [u8],
+    ) -> Result<()> {
+        if self.stream.is_none() {
+            let (stream, size, _) = connect(&self.address)?;
+            if size != self.size {
+                return Err(NbdError::Protocol("the size of the export changed"));
+            }
+            self.stream = Some(stream);
+        }
+        let stream = self.stream.as_mut().unwrap();
+
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let mut request = vec![0u8; REQUEST_HEADER_SIZE];
+        BigEndian::write_u32(&mut request[0..4], NBD_REQUEST_MAGIC);
+        BigEndian::write_u16(&mut request[4..6], flags);
+        BigEndian::write_u16(&mut request[6..8], command);
+        BigEndian::write_u64(&mut request[8..16], handle);
+        BigEndian::write_u64(&mut request[16..24], offset);
+        BigEndian::write_u32(&mut request[24..28], len);
+        request.extend_from_slice(data_out);
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; REPLY_HEADER_SIZE];
+        stream.read_exact(&mut reply)?;
+        if BigEndian::read_u32(&reply[0..4]) != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(NbdError::Protocol("invalid reply magic"));
+        }
+        if BigEndian::read_u64(&reply[8..16]) != handle {
+            return Err(NbdError::Protocol("unexpected reply handle"));
+        }
+        // Failed requests are not followed by data.
+        match BigEndian::read_u32(&reply[4..8]) {
+            0 => stream.read_exact(data_in).map_err(NbdError::Io),
+            error => Err(NbdError::Request(error)),
+        }
+    }
+
+    // Executes the request, on a new connection when the current one fails.
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data_out: &[u8],
+        data_in: &mut [u8],
+    ) -> io::Result<()> {
+        let mut attempts = 0;
+        loop {
+            match self.transmit(command, flags, offset, len, data_out, data_in) {
+                // The server only fails the request it was sent, and is still usable.
+                Err(e @ NbdError::Request(_)) => return Err(e.into()),
+                Err(e) if attempts < MAX_RECONNECT_ATTEMPTS => {
+                    attempts += 1;
+                    warn!(
+                        "Lost the connection to the NBD server {}: {}. Reconnecting.",
+                        self.address, e
+                    );
+                    // The connection may be out of sync with the server.
+                    self.stream = None;
+                    thread::sleep(Duration::from_millis(
+                        RECONNECT_DELAY_MS * u64::from(attempts),
+                    ));
+                }
+                result => return result.map_err(io::Error::from),
+            }
+        }
+    }
+
+    // Sends a request without data over ranges of any length, split when too long.
+    fn request_range(&mut self, command: u16, flags: u16, offset: u64, len: u64) -> io::Result<()> {
+        let end = offset
+            .checked_add(len)
+            .filter(|&end| end <= self.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let mut offset = offset;
+        while offset < end {
+            let count = cmp::min(end - offset, MAX_RANGE_SIZE);
+            self.request(command, flags, offset, count as u32, &[], &mut [])?;
+            offset += count;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NbdDisk {
+    fn drop(&mut self) {
+        // The server does not reply to disconnection requests.
+        if let Some(ref mut stream) = self.stream {
+            let mut request = [0u8; REQUEST_HEADER_SIZE];
+            BigEndian::write_u32(&mut request[0..4], NBD_REQUEST_MAGIC);
+            BigEndian::write_u16(&mut request[6..8], NBD_CMD_DISC);
+            let _ = stream.write_all(&request);
+        }
+    }
+}
+
+impl Read for NbdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.current_offset >= self.size {
+            return Ok(0);
+        }
+        let count = cmp::min(
+            cmp::min(buf.len() as u64, self.size - self.current_offset),
+            MAX_TRANSFER_SIZE,
+        ) as usize;
+        if count == 0 {
+            return Ok(0);
+        }
+        let offset = self.current_offset;
+        self.request(
+            NBD_CMD_READ,
+            0,
+            offset,
+            count as u32,
+            &[],
+            &mut buf[..count],
+        )?;
+        self.current_offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for NbdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current_offset >= self.size {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        let count = cmp::min(
+            cmp::min(buf.len() as u64, self.size - self.current_offset),
+            MAX_TRANSFER_SIZE,
+        ) as usize;
+        let offset = self.current_offset;
+        self.request(
+            NBD_CMD_WRITE,
+            0,
+            offset,
+            count as u32,
+            &buf[..count],
+            &mut [],
+        )?;
+        self.current_offset += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Nothing is buffered by the client.
+        Ok(())
+    }
+}
+
+impl Seek for NbdDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(self.size, offset),
+            SeekFrom::Current(offset) => add_signed(self.current_offset, offset),
+        };
+        match offset {
+            Some(offset) => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl DiskFile for NbdDisk {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        // Trimmed ranges do not necessarily read as zeroes, unlike zeroed ones, which the server
+        // is free to deallocate.
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        self.request_range(NBD_CMD_WRITE_ZEROES, 0, offset, len)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_TRIM == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        self.request_range(NBD_CMD_TRIM, 0, offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            return write_zero_bytes(self, offset, len);
+        }
+        self.request_range(NBD_CMD_WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE, offset, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            // The server writes the data to its storage right away.
+            return Ok(());
+        }
+        self.request(NBD_CMD_FLUSH, 0, 0, 0, &[], &mut [])
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    extern crate tempfile;
+
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+    pub const EXPORT_SIZE: usize = 0x20_0000;
+    // Reads at this offset fail on the server.
+    const BAD_OFFSET: u64 = 0x1000;
+
+    pub struct TestServer {
+        pub disk: Mutex<Vec<u8>>,
+        flags: u16,
+        connections: AtomicUsize,
+        // The server closes each connection after this many requests.
+        max_requests: usize,
+    }
+
+    impl TestServer {
+        fn handle(&self, mut stream: UnixStream) -> io::Result<()> {
+            let mut greeting = [0u8; 18];
+            BigEndian::write_u64(&mut greeting[0..8], NBD_MAGIC);
+            BigEndian::write_u64(&mut greeting[8..16], NBD_OPTS_MAGIC);
+            BigEndian::write_u16(
+                &mut greeting[16..18],
+                NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES,
+            );
+            stream.write_all(&greeting)?;
+
+            let mut option = [0u8; 20];
+            stream.read_exact(&mut option)?;
+            assert_eq!(BigEndian::read_u64(&option[4..12]), NBD_OPTS_MAGIC);
+            assert_eq!(BigEndian::read_u32(&option[12..16]), NBD_OPT_EXPORT_NAME);
+            let mut export_name = vec![0u8; BigEndian::read_u32(&option[16..20]) as usize];
+            stream.read_exact(&mut export_name)?;
+            assert_eq!(export_name, b"disk");
+
+            let mut reply = vec![0u8; 10];
+            BigEndian::write_u64(&mut reply[0..8], EXPORT_SIZE as u64);
+            BigEndian::write_u16(&mut reply[8..10], self.flags);
+            if BigEndian::read_u32(&option[0..4]) & NBD_FLAG_C_NO_ZEROES == 0 {
+                reply.resize(10 + EXPORT_NAME_REPLY_PADDING, 0);
+            }
+            stream.write_all(&reply)?;
+
+            for _ in 0..self.max_requests {
+                let mut request = [0u8; REQUEST_HEADER_SIZE];
+                stream.read_exact(&mut request)?;
+                assert_eq!(BigEndian::read_u32(&request[0..4]), NBD_REQUEST_MAGIC);
+                let command = BigEndian::read_u16(&request[6..8]);
+                let offset = BigEndian::read_u64(&request[16..24]) as usize;
+                let end = offset + BigEndian::read_u32(&request[24..28]) as usize;
+                assert!(end <= EXPORT_SIZE);
+
+                let mut error = 0;
+                let mut data = Vec::new();
+                let mut disk = self.disk.lock().unwrap();
+                match command {
+                    NBD_CMD_READ if offset as u64 == BAD_OFFSET => error = libc::EIO as u32,
+                    NBD_CMD_READ => data.extend_from_slice(&disk[offset..end]),
+                    NBD_CMD_WRITE => stream.read_exact(&mut disk[offset..end])?,
+                    NBD_CMD_TRIM => {
+                        assert_ne!(self.flags & NBD_FLAG_SEND_TRIM, 0);
+                        // Trimmed data is left as is.
+                    }
+                    NBD_CMD_WRITE_ZEROES => {
+                        assert_ne!(self.flags & NBD_FLAG_SEND_WRITE_ZEROES, 0);
+                        disk[offset..end].iter_mut().for_each(|b| *b = 0);
+                    }
+                    NBD_CMD_FLUSH => assert_ne!(self.flags & NBD_FLAG_SEND_FLUSH, 0),
+                    NBD_CMD_DISC => return Ok(()),
+                    _ => panic!("Unexpected command: {}", command),
+                }
+
+                let mut reply = vec![0u8; REPLY_HEADER_SIZE];
+                BigEndian::write_u32(&mut reply[0..4], NBD_SIMPLE_REPLY_MAGIC);
+                BigEndian::write_u32(&mut reply[4..8], error);
+                reply[8..16].copy_from_slice(&request[8..16]);
+                reply.extend_from_slice(&data);
+                stream.write_all(&reply)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Serves an export over a Unix domain socket in `dir`, and returns the address of the export.
+    pub fn start_server(
+        dir: &TempDir,
+        flags: u16,
+        max_requests: usize,
+    ) -> (NbdAddress, Arc<TestServer>) {
+        let socket_path = dir.path().join("nbd.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = Arc::new(TestServer {
+            disk: Mutex::new(vec![0u8; EXPORT_SIZE]),
+            flags,
+            connections: AtomicUsize::new(0),
+            max_requests,
+        });
+        let thread_server = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                thread_server.connections.fetch_add(1, Ordering::SeqCst);
+                let _ = thread_server.handle(stream.unwrap());
+            }
+        });
+        let address = NbdAddress::parse(Path::new(&format!(
+            "nbd+unix://{}?export=disk",
+            socket_path.display()
+        )))
+        .unwrap();
+        (address, server)
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let parse = |uri: &str| NbdAddress::parse(Path::new(uri));
+
+        assert!(is_nbd_uri(Path::new("nbd://127.0.0.1:10809")));
+        assert!(is_nbd_uri(Path::new("nbd+unix:///tmp/nbd.sock")));
+        assert!(!is_nbd_uri(Path::new("/tmp/nbd.sock")));
+
+        let address = parse("nbd://127.0.0.1:10809").unwrap();
+        assert_eq!(
+            address,
+            NbdAddress::Tcp("127.0.0.1:10809".parse().unwrap(), String::new())
+        );
+        assert_eq!(address.to_string(), "nbd://127.0.0.1:10809");
+        let address = parse("nbd://[::1]:10809/disk").unwrap();
+        assert_eq!(address.export_name(), "disk");
+        assert_eq!(address.to_string(), "nbd://[::1]:10809/disk");
+
+        let address = parse("nbd+unix:///tmp/nbd.sock").unwrap();
+        assert_eq!(
+            address,
+            NbdAddress::Unix(PathBuf::from("/tmp/nbd.sock"), String::new())
+        );
+        assert_eq!(address.to_string(), "nbd+unix:///tmp/nbd.sock");
+        let address = parse("nbd+unix:///tmp/nbd.sock?export=disk").unwrap();
+        assert_eq!(address.export_name(), "disk");
+        assert_eq!(address.to_string(), "nbd+unix:///tmp/nbd.sock?export=disk");
+
+        // Host names are not resolved, and Unix socket paths must be absolute.
+        assert!(parse("nbd://localhost:10809").is_err());
+        assert!(parse("nbd://127.0.0.1").is_err());
+        assert!(parse("nbd+unix://nbd.sock").is_err());
+        assert!(parse("/tmp/disk.img").is_err());
+        let long_name = format!("nbd://127.0.0.1:10809/{}", "a".repeat(5000));
+        assert!(parse(&long_name).is_err());
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = TempDir::new().unwrap();
+        let flags = NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+        let (address, server) = start_server(&dir, flags, std::usize::MAX);
+        let mut disk = NbdDisk::connect(&address, true).unwrap();
+        assert_eq!(disk.address(), &address);
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), EXPORT_SIZE as u64);
+
+        // Transfers larger than the maximum size of a request are split.
+        let data: Vec<u8> = (0..MAX_TRANSFER_SIZE as usize + 0x200)
+            .map(|i| i as u8)
+            .collect();
+        disk.seek(SeekFrom::Start(0x200)).unwrap();
+        disk.write_all(&data).unwrap();
+        assert_eq!(
+            &server.disk.lock().unwrap()[0x200..0x200 + data.len()],
+            &data[..]
+        );
+        let mut read = vec![0u8; data.len()];
+        disk.seek(SeekFrom::Start(0x200)).unwrap();
+        disk.read_exact(&mut read).unwrap();
+        assert_eq!(read, data);
+        disk.sync().unwrap();
+
+        // Transfers stop at the end of the export.
+        let mut data = [0u8; 0x400];
+        disk.seek(SeekFrom::End(-0x100)).unwrap();
+        assert_eq!(disk.read(&mut data).unwrap(), 0x100);
+        assert_eq!(disk.read(&mut data).unwrap(), 0);
+        assert_eq!(
+            disk.write(&data).unwrap_err().raw_os_error(),
+            Some(libc::ENOSPC)
+        );
+
+        disk.write_zeroes(0x200, 0x200).unwrap();
+        disk.punch_hole(0x400, 0x200).unwrap();
+        disk.discard(0x600, 0x200).unwrap();
+        let contents = server.disk.lock().unwrap();
+        assert!(contents[0x200..0x600].iter().all(|&b| b == 0));
+        assert!(contents[0x600..0x800].iter().any(|&b| b != 0));
+        drop(contents);
+        assert_eq!(
+            disk.write_zeroes(EXPORT_SIZE as u64, 1)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_unsupported_commands() {
+        let dir = TempDir::new().unwrap();
+        let (address, _) = start_server(&dir, NBD_FLAG_READ_ONLY, std::usize::MAX);
+        match NbdDisk::connect(&address, true) {
+            Err(NbdError::ReadOnlyExport) => (),
+            _ => panic!("Read only exports should not be writable."),
+        }
+
+        // The server does not need to be asked to write its data to its storage.
+        let dir = TempDir::new().unwrap();
+        let (address, server) = start_server(&dir, 0, std::usize::MAX);
+        let mut disk = NbdDisk::connect(&address, true).unwrap();
+        disk.sync().unwrap();
+        // Zeroes are then written as data.
+        server.disk.lock().unwrap()[0x200..0x400]
+            .iter_mut()
+            .for_each(|b| *b = 0xaa);
+        disk.write_zeroes(0x200, 0x200).unwrap();
+        assert!(server.disk.lock().unwrap()[0x200..0x400]
+            .iter()
+            .all(|&b| b == 0));
+        for result in [disk.punch_hole(0, 0x200), disk.discard(0, 0x200)].iter() {
+            assert_eq!(
+                result.as_ref().unwrap_err().raw_os_error(),
+                Some(libc::EOPNOTSUPP)
+            );
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let dir = TempDir::new().unwrap();
+        let (address, server) = start_server(&dir, 0, 2);
+        let mut disk = NbdDisk::connect(&address, true).unwrap();
+        let data = [0x55u8; 0x200];
+        for i in 0..5 {
+            disk.seek(SeekFrom::Start(i * 0x200)).unwrap();
+            disk.write_all(&data).unwrap();
+        }
+        assert!(server.disk.lock().unwrap()[..0xa00]
+            .iter()
+            .all(|&b| b == 0x55));
+        assert!(server.connections.load(Ordering::SeqCst) > 1);
+
+        // Failed requests are not retried.
+        let dir = TempDir::new().unwrap();
+        let (address, server) = start_server(&dir, 0, std::usize::MAX);
+        let mut disk = NbdDisk::connect(&address, true).unwrap();
+        disk.seek(SeekFrom::Start(BAD_OFFSET)).unwrap();
+        let mut read = [0u8; 0x200];
+        assert_eq!(
+            disk.read(&mut read).unwrap_err().raw_os_error(),
+            Some(libc::EIO)
+        );
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -20,17 +20,21 @@ use std::sync::Arc;
 use std::vec::Vec;
 
 use dumbo::{ns::MmdsNetworkStack, EthernetFrame, MacAddr, MAC_ADDR_LEN};
+use fc_util::versioned::{Error as VersionError, Result as VersionResult, Versioned};
+use logger::metrics::{DeviceMetrics, NetDeviceMetrics};
 use logger::{Metric, METRICS};
 use memory_model::{GuestAddress, GuestMemory};
 use net_gen;
 use net_util::{Tap, TapError};
 use rate_limiter::{RateLimiter, TokenBucket, TokenType};
+use serde_json::Value;
 use sys_util::EventFd;
 use virtio_gen::virtio_net::*;
 
+use super::vhost::{self, VhostNet};
 use super::{
-    ActivateError, ActivateResult, EpollConfigConstructor, Queue, VirtioDevice, TYPE_NET,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DescriptorChain, EgressFilter, EpollConfigConstructor,
+    PacketCapture, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
 };
 use crate::{DeviceEventT, EpollHandler, Error as DeviceError};
 
@@ -39,21 +43,44 @@ use crate::{DeviceEventT, EpollHandler, Error as DeviceError};
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The control queue only carries a few small commands.
+const CTRL_QUEUE_SIZE: u16 = 64;
+/// Maximum number of RX/TX queue pairs of a network device.
+pub const MAX_QUEUE_PAIRS: u16 = 16;
+// Offset of the maximum number of queue pairs in the configuration space.
+const CONFIG_MAX_QUEUE_PAIRS_OFFSET: usize = 8;
+// The largest control command read from the control queue, header included.
+const MAX_CTRL_COMMAND_SIZE: usize = 64;
 
+// rx rate limiter budget is now available.
+const RX_RATE_LIMITER_EVENT: DeviceEventT = 0;
+// tx rate limiter budget is now available.
+const TX_RATE_LIMITER_EVENT: DeviceEventT = 1;
+// The guest has placed a command on the control queue.
+const CTRL_QUEUE_EVENT: DeviceEventT = 2;
+// The vhost-net driver has placed buffers on the used rings.
+const VHOST_CALL_EVENT: DeviceEventT = 3;
+// The following events belong to the first queue pair. The events of the other queue pairs
+// follow, in the same order.
 // A frame is available for reading from the tap device to receive in the guest.
-const RX_TAP_EVENT: DeviceEventT = 0;
+const RX_TAP_EVENT: DeviceEventT = 4;
 // The guest has made a buffer available to receive a frame into.
-const RX_QUEUE_EVENT: DeviceEventT = 1;
+const RX_QUEUE_EVENT: DeviceEventT = 5;
 // The transmit queue has a frame that is ready to send from the guest.
-const TX_QUEUE_EVENT: DeviceEventT = 2;
-// rx rate limiter budget is now available.
-const RX_RATE_LIMITER_EVENT: DeviceEventT = 3;
-// tx rate limiter budget is now available.
-const TX_RATE_LIMITER_EVENT: DeviceEventT = 4;
-// Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 5;
+const TX_QUEUE_EVENT: DeviceEventT = 6;
+// Number of events of each queue pair.
+const QUEUE_PAIR_EVENTS_COUNT: DeviceEventT = 3;
+
+/// Number of DeviceEventT events supported by a network device with `num_queue_pairs` RX/TX
+/// queue pairs.
+pub fn net_events_count(num_queue_pairs: u16) -> usize {
+    usize::from(RX_TAP_EVENT) + usize::from(num_queue_pairs) * usize::from(QUEUE_PAIR_EVENTS_COUNT)
+}
+
+// Returns the offset of the events of a queue pair from the events of the first one.
+fn queue_pair_event_offset(queue_pair: usize) -> u64 {
+    queue_pair as u64 * u64::from(QUEUE_PAIR_EVENTS_COUNT)
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -65,24 +92,30 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Detaching a queue of the tap interface failed.
+    TapDetachQueue(TapError),
+    /// The number of tap queues is out of range.
+    InvalidQueuePairs,
+    /// Setting up the vhost-net driver failed.
+    Vhost(vhost::Error),
+    /// The vhost-net driver does not support virtio 1.0.
+    VhostNoVersion1,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 struct TxVirtio {
     queue_evt: EventFd,
-    rate_limiter: RateLimiter,
     queue: Queue,
     iovec: Vec<(GuestAddress, usize)>,
     frame_buf: [u8; MAX_BUFFER_SIZE],
 }
 
 impl TxVirtio {
-    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: RateLimiter) -> Self {
+    fn new(queue: Queue, queue_evt: EventFd) -> Self {
         let tx_queue_max_size = queue.get_max_size() as usize;
         TxVirtio {
             queue_evt,
-            rate_limiter,
             queue,
             iovec: Vec::with_capacity(tx_queue_max_size),
             frame_buf: [0u8; MAX_BUFFER_SIZE],
@@ -92,7 +125,6 @@ impl TxVirtio {
 
 struct RxVirtio {
     queue_evt: EventFd,
-    rate_limiter: RateLimiter,
     deferred_frame: bool,
     deferred_irqs: bool,
     queue: Queue,
@@ -101,10 +133,9 @@ struct RxVirtio {
 }
 
 impl RxVirtio {
-    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: RateLimiter) -> Self {
+    fn new(queue: Queue, queue_evt: EventFd) -> Self {
         RxVirtio {
             queue_evt,
-            rate_limiter,
             deferred_frame: false,
             deferred_irqs: false,
             queue,
@@ -114,6 +145,24 @@ impl RxVirtio {
     }
 }
 
+// A receive and a transmit queue, along with the tap queue their frames go through.
+struct QueuePair {
+    rx: RxVirtio,
+    tx: TxVirtio,
+    tap: Tap,
+    // Whether the driver uses this queue pair. The tap queues of the other ones are detached.
+    enabled: bool,
+    rx_tap_listening: bool,
+    rx_tap_epoll_token: u64,
+    // The vhost-net driver moving the frames of this queue pair, if any.
+    vhost: Option<VhostNet>,
+}
+
+struct CtrlVirtio {
+    queue_evt: EventFd,
+    queue: Queue,
+}
+
 fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -137,12 +186,75 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     }
 }
 
+// Appends the frame held by `frame_buf`, VNET header included, to `capture`. Capturing stops when
+// the capture is full or cannot be written.
+fn capture_frame(capture: &mut Option<PacketCapture>, frame_buf: &[u8]) {
+    let result = match (capture.as_mut(), frame_buf.get(vnet_hdr_len()..)) {
+        (Some(capture), Some(frame)) => capture.record(frame),
+        _ => return,
+    };
+    match result {
+        Ok(true) => (),
+        Ok(false) => {
+            info!("The packet capture reached its size limit; stopping it.");
+            stop_capture(capture);
+        }
+        Err(e) => {
+            error!("Failed to write the packet capture; stopping it: {:?}", e);
+            *capture = None;
+        }
+    }
+}
+
+// Writes the buffered frames of `capture`, so that the capture is complete whenever the device
+// is idle. Capturing stops when the capture cannot be written.
+fn flush_capture(capture: &mut Option<PacketCapture>) {
+    if let Some(Err(e)) = capture.as_mut().map(PacketCapture::flush) {
+        error!("Failed to write the packet capture; stopping it: {:?}", e);
+        *capture = None;
+    }
+}
+
+fn stop_capture(capture: &mut Option<PacketCapture>) {
+    flush_capture(capture);
+    *capture = None;
+}
+
+// Reads the device-readable part of a control command, made of the class, the command and its
+// data. Also returns the address of the byte the device writes the status of the command to.
+fn read_ctrl_command(mem: &GuestMemory, head: DescriptorChain) -> (Vec<u8>, Option<GuestAddress>) {
+    let mut command = Vec::new();
+    let mut next_desc = Some(head);
+    while let Some(desc) = next_desc {
+        if desc.is_write_only() {
+            return (command, Some(desc.addr));
+        }
+        let start = command.len();
+        command.resize(
+            cmp::min(start + desc.len as usize, MAX_CTRL_COMMAND_SIZE),
+            0,
+        );
+        match mem.read_slice_at_addr(&mut command[start..], desc.addr) {
+            Ok(len) => command.truncate(start + len),
+            Err(e) => {
+                error!("Failed to read control command: {:?}", e);
+                command.truncate(start);
+            }
+        }
+        next_desc = desc.next_descriptor();
+    }
+    (command, None)
+}
+
 /// Handler that drives the execution of the Net devices
 pub struct NetEpollHandler {
-    rx: RxVirtio,
-    tap: Tap,
+    queue_pairs: Vec<QueuePair>,
+    ctrl: Option<CtrlVirtio>,
+    // Signaled by the vhost-net drivers of the queue pairs, if they use one.
+    vhost_call_evt: Option<EventFd>,
+    rx_rate_limiter: RateLimiter,
+    tx_rate_limiter: RateLimiter,
     mem: GuestMemory,
-    tx: TxVirtio,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
     // TODO(smbarber): http://crbug.com/753630
@@ -151,9 +263,12 @@ pub struct NetEpollHandler {
     acked_features: u64,
     mmds_ns: Option<MmdsNetworkStack>,
     guest_mac: Option<MacAddr>,
+    egress_filter: Option<EgressFilter>,
+    capture: Option<PacketCapture>,
     epoll_fd: RawFd,
-    rx_tap_listening: bool,
-    rx_tap_epoll_token: u64,
+    // The number of queue pairs enabled by the driver, shared with the device for saving it.
+    active_queue_pairs: Arc<AtomicUsize>,
+    metrics: DeviceMetrics<NetDeviceMetrics>,
 
     #[cfg(test)]
     test_mutators: tests::TestMutators,
@@ -165,7 +280,7 @@ impl NetEpollHandler {
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
         self.interrupt_evt.write(1).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
-            METRICS.net.event_fails.inc();
+            self.metrics.inc(|m| &m.event_fails);
             DeviceError::FailedSignalingUsedQueue(e)
         })
     }
@@ -173,44 +288,41 @@ impl NetEpollHandler {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, queue_pair: usize) -> bool {
+        let bytes_read = self.queue_pairs[queue_pair].rx.bytes_read as u64;
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx.rate_limiter.consume(1, TokenType::Ops) {
+        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
-            .rx
-            .rate_limiter
-            .consume(self.rx.bytes_read as u64, TokenType::Bytes)
-        {
+        if !self.rx_rate_limiter.consume(bytes_read, TokenType::Bytes) {
             // revert the OPS consume()
-            self.rx.rate_limiter.manual_replenish(1, TokenType::Ops);
+            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.rx_single_frame();
+        let success = self.rx_single_frame(queue_pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
             // revert the OPS consume()
-            self.rx.rate_limiter.manual_replenish(1, TokenType::Ops);
+            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx
-                .rate_limiter
-                .manual_replenish(self.rx.bytes_read as u64, TokenType::Bytes);
+            self.rx_rate_limiter
+                .manual_replenish(bytes_read, TokenType::Bytes);
         }
         success
     }
 
-    // Copies a single frame from `self.rx.frame_buf` into the guest. Returns true
-    // if a buffer was used, and false if the frame must be deferred until a buffer
-    // is made available by the driver.
-    fn rx_single_frame(&mut self) -> bool {
-        let mut next_desc = self.rx.queue.pop(&self.mem);
+    // Copies a single frame from the `frame_buf` of a receive queue into the guest. Returns true
+    // if a buffer was used, and false if the frame must be deferred until a buffer is made
+    // available by the driver.
+    fn rx_single_frame(&mut self, queue_pair: usize) -> bool {
+        let rx = &mut self.queue_pairs[queue_pair].rx;
+        let mut next_desc = rx.queue.pop(&self.mem);
 
         if next_desc.is_none() {
             return false;
@@ -227,45 +339,43 @@ impl NetEpollHandler {
                     if !desc.is_write_only() {
                         break;
                     }
-                    let limit = cmp::min(write_count + desc.len as usize, self.rx.bytes_read);
-                    let source_slice = &self.rx.frame_buf[write_count..limit];
+                    let limit = cmp::min(write_count + desc.len as usize, rx.bytes_read);
+                    let source_slice = &rx.frame_buf[write_count..limit];
                     let write_result = self.mem.write_slice_at_addr(source_slice, desc.addr);
 
                     match write_result {
                         Ok(sz) => {
-                            METRICS.net.rx_count.inc();
+                            self.metrics.inc(|m| &m.rx_count);
                             write_count += sz;
                         }
                         Err(e) => {
                             error!("Failed to write slice: {:?}", e);
-                            METRICS.net.rx_fails.inc();
+                            self.metrics.inc(|m| &m.rx_fails);
                             break;
                         }
                     };
 
-                    if write_count >= self.rx.bytes_read {
+                    if write_count >= rx.bytes_read {
                         break;
                     }
                     next_desc = desc.next_descriptor();
                 }
                 None => {
                     warn!("Receiving buffer is too small to hold frame of current size");
-                    METRICS.net.rx_fails.inc();
+                    self.metrics.inc(|m| &m.rx_fails);
                     break;
                 }
             }
         }
 
-        self.rx
-            .queue
-            .add_used(&self.mem, head_index, write_count as u32);
+        rx.queue.add_used(&self.mem, head_index, write_count as u32);
 
         // Mark that we have at least one pending packet and we need to interrupt the guest.
-        self.rx.deferred_irqs = true;
+        rx.deferred_irqs = true;
 
-        if write_count >= self.rx.bytes_read {
-            METRICS.net.rx_bytes_count.add(write_count);
-            METRICS.net.rx_packets_count.inc();
+        if write_count >= rx.bytes_read {
+            self.metrics.add(|m| &m.rx_bytes_count, write_count);
+            self.metrics.inc(|m| &m.rx_packets_count);
             true
         } else {
             false
@@ -282,6 +392,8 @@ impl NetEpollHandler {
         frame_buf: &[u8],
         tap: &mut Tap,
         guest_mac: Option<MacAddr>,
+        egress_filter: Option<&EgressFilter>,
+        metrics: &DeviceMetrics<NetDeviceMetrics>,
     ) -> bool {
         if let Some(ns) = mmds_ns {
             if ns.detour_frame(frame_bytes_from_buf(frame_buf)) {
@@ -302,51 +414,62 @@ impl NetEpollHandler {
         if let Some(mac) = guest_mac {
             let _ = EthernetFrame::from_bytes(&frame_buf[vnet_hdr_len()..]).and_then(|eth_frame| {
                 if mac != eth_frame.src_mac() {
-                    METRICS.net.tx_spoofed_mac_count.inc();
+                    metrics.inc(|m| &m.tx_spoofed_mac_count);
                 }
                 Ok(())
             });
         }
 
+        if let Some(filter) = egress_filter {
+            if !filter.allows(frame_bytes_from_buf(frame_buf)) {
+                metrics.inc(|m| &m.tx_filtered_count);
+                return false;
+            }
+        }
+
         let write_result = tap.write(frame_buf);
         match write_result {
             Ok(_) => {
-                METRICS.net.tx_bytes_count.add(frame_buf.len());
-                METRICS.net.tx_packets_count.inc();
-                METRICS.net.tx_count.inc();
+                metrics.add(|m| &m.tx_bytes_count, frame_buf.len());
+                metrics.inc(|m| &m.tx_packets_count);
+                metrics.inc(|m| &m.tx_count);
             }
             Err(e) => {
                 error!("Failed to write to tap: {:?}", e);
-                METRICS.net.tx_fails.inc();
+                metrics.inc(|m| &m.tx_fails);
             }
         };
         false
     }
 
     // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> io::Result<usize> {
+    fn read_from_mmds_or_tap(&mut self, queue_pair: usize) -> io::Result<usize> {
         if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx.frame_buf))
-            {
+            let rx = &mut self.queue_pairs[queue_pair].rx;
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(&mut rx.frame_buf)) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx.frame_buf);
+                init_vnet_hdr(&mut rx.frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
-        self.read_tap()
+        self.read_tap(queue_pair)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(queue_pair) {
                 Ok(count) => {
-                    self.rx.bytes_read = count;
-                    METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx.deferred_frame = true;
+                    self.queue_pairs[queue_pair].rx.bytes_read = count;
+                    capture_frame(
+                        &mut self.capture,
+                        &self.queue_pairs[queue_pair].rx.frame_buf[..count],
+                    );
+                    self.metrics.inc(|m| &m.rx_count);
+                    if !self.rate_limited_rx_single_frame(queue_pair) {
+                        self.queue_pairs[queue_pair].rx.deferred_frame = true;
                         break;
                     }
                 }
@@ -357,7 +480,7 @@ impl NetEpollHandler {
                         Some(err) if err == EAGAIN => (),
                         _ => {
                             error!("Failed to read tap: {:?}", e);
-                            METRICS.net.rx_fails.inc();
+                            self.metrics.inc(|m| &m.rx_fails);
                             return Err(DeviceError::FailedReadTap);
                         }
                     };
@@ -365,23 +488,24 @@ impl NetEpollHandler {
                 }
             }
         }
-        if self.rx.deferred_irqs {
-            self.rx.deferred_irqs = false;
+        flush_capture(&mut self.capture);
+        if self.queue_pairs[queue_pair].rx.deferred_irqs {
+            self.queue_pairs[queue_pair].rx.deferred_irqs = false;
             self.signal_used_queue()
         } else {
             Ok(())
         }
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx.deferred_frame {
-            if self.rate_limited_rx_single_frame() {
-                self.rx.deferred_frame = false;
+    fn resume_rx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[queue_pair].rx.deferred_frame {
+            if self.rate_limited_rx_single_frame(queue_pair) {
+                self.queue_pairs[queue_pair].rx.deferred_frame = false;
                 // process_rx() was interrupted possibly before consuming all
                 // packets in the tap; try continuing now.
-                self.process_rx()
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
+                self.process_rx(queue_pair)
+            } else if self.queue_pairs[queue_pair].rx.deferred_irqs {
+                self.queue_pairs[queue_pair].rx.deferred_irqs = false;
                 self.signal_used_queue()
             } else {
                 Ok(())
@@ -391,20 +515,21 @@ impl NetEpollHandler {
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
         // The MMDS network stack works like a state machine, based on synchronous calls, and
         // without being added to any event loop. If any frame is accepted by the MMDS, we also
         // trigger a process_rx() which checks if there are any new frames to be sent, starting
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
+        let pair = &mut self.queue_pairs[queue_pair];
 
-        while let Some(head) = self.tx.queue.pop(&self.mem) {
+        while let Some(head) = pair.tx.queue.pop(&self.mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx.rate_limiter.consume(1, TokenType::Ops) {
+            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
-                self.tx.queue.undo_pop();
+                pair.tx.queue.undo_pop();
                 break;
             }
 
@@ -412,12 +537,12 @@ impl NetEpollHandler {
             let mut read_count = 0;
             let mut next_desc = Some(head);
 
-            self.tx.iovec.clear();
+            pair.tx.iovec.clear();
             while let Some(desc) = next_desc {
                 if desc.is_write_only() {
                     break;
                 }
-                self.tx.iovec.push((desc.addr, desc.len as usize));
+                pair.tx.iovec.push((desc.addr, desc.len as usize));
                 read_count += desc.len as usize;
                 next_desc = desc.next_descriptor();
             }
@@ -425,15 +550,14 @@ impl NetEpollHandler {
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
             if !self
-                .tx
-                .rate_limiter
+                .tx_rate_limiter
                 .consume(read_count as u64, TokenType::Bytes)
             {
                 // revert the OPS consume()
-                self.tx.rate_limiter.manual_replenish(1, TokenType::Ops);
+                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
-                self.tx.queue.undo_pop();
+                pair.tx.queue.undo_pop();
                 break;
             }
 
@@ -441,49 +565,189 @@ impl NetEpollHandler {
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
             // and get rid of the intermediate buffer.
-            for (desc_addr, desc_len) in self.tx.iovec.drain(..) {
-                let limit = cmp::min((read_count + desc_len) as usize, self.tx.frame_buf.len());
+            for (desc_addr, desc_len) in pair.tx.iovec.drain(..) {
+                let limit = cmp::min((read_count + desc_len) as usize, pair.tx.frame_buf.len());
 
                 let read_result = self.mem.read_slice_at_addr(
-                    &mut self.tx.frame_buf[read_count..limit as usize],
+                    &mut pair.tx.frame_buf[read_count..limit as usize],
                     desc_addr,
                 );
                 match read_result {
                     Ok(sz) => {
                         read_count += sz;
-                        METRICS.net.tx_count.inc();
+                        self.metrics.inc(|m| &m.tx_count);
                     }
                     Err(e) => {
                         error!("Failed to read slice: {:?}", e);
-                        METRICS.net.tx_fails.inc();
+                        self.metrics.inc(|m| &m.tx_fails);
                         break;
                     }
                 }
             }
 
+            // Frames are captured before the MMDS and the egress filter get them.
+            capture_frame(&mut self.capture, &pair.tx.frame_buf[..read_count]);
             if Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
-                &mut self.tx.rate_limiter,
-                &self.tx.frame_buf[..read_count],
-                &mut self.tap,
+                &mut self.tx_rate_limiter,
+                &pair.tx.frame_buf[..read_count],
+                &mut pair.tap,
                 self.guest_mac,
-            ) && !self.rx.deferred_frame
+                self.egress_filter.as_ref(),
+                &self.metrics,
+            ) && !pair.rx.deferred_frame
             {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
             }
 
-            self.tx.queue.add_used(&self.mem, head_index, 0);
+            pair.tx.queue.add_used(&self.mem, head_index, 0);
         }
+        flush_capture(&mut self.capture);
 
         // An incoming frame for the MMDS may trigger the transmission of a new message.
         if process_rx_for_mmds {
-            self.process_rx()
+            self.process_rx(queue_pair)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn process_ctrl_queue(&mut self) -> result::Result<(), DeviceError> {
+        let mut used_any = false;
+
+        loop {
+            let (head_index, command, status_addr) = {
+                let ctrl = match self.ctrl.as_mut() {
+                    Some(ctrl) => ctrl,
+                    None => break,
+                };
+                let head = match ctrl.queue.pop(&self.mem) {
+                    Some(head) => head,
+                    None => break,
+                };
+                let head_index = head.index;
+                let (command, status_addr) = read_ctrl_command(&self.mem, head);
+                (head_index, command, status_addr)
+            };
+
+            let len = match status_addr {
+                Some(status_addr) => {
+                    let status = self.execute_ctrl_command(&command);
+                    match self.mem.write_obj_at_addr(status, status_addr) {
+                        Ok(()) => 1,
+                        Err(e) => {
+                            error!("Failed to write control command status: {:?}", e);
+                            self.metrics.inc(|m| &m.event_fails);
+                            0
+                        }
+                    }
+                }
+                None => {
+                    error!("Control command without a status descriptor");
+                    self.metrics.inc(|m| &m.event_fails);
+                    0
+                }
+            };
+
+            if let Some(ctrl) = self.ctrl.as_mut() {
+                ctrl.queue.add_used(&self.mem, head_index, len);
+            }
+            used_any = true;
+        }
+
+        if used_any {
+            self.signal_used_queue()
         } else {
             Ok(())
         }
     }
 
+    // Executes a control command and returns its status. Only the command setting the number of
+    // queue pairs is supported.
+    fn execute_ctrl_command(&mut self, command: &[u8]) -> u8 {
+        if command.len() < mem::size_of::<virtio_net_ctrl_hdr>() {
+            error!("Control command too short: {} bytes", command.len());
+            return VIRTIO_NET_ERR as u8;
+        }
+        let (class, cmd) = (u32::from(command[0]), u32::from(command[1]));
+        let data = &command[mem::size_of::<virtio_net_ctrl_hdr>()..];
+
+        match (class, cmd) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) if data.len() >= 2 => {
+                let count = usize::from(u16::from_le_bytes([data[0], data[1]]));
+                if count == 0 || count > self.queue_pairs.len() {
+                    error!("Invalid number of queue pairs: {}", count);
+                    VIRTIO_NET_ERR as u8
+                } else if self.set_active_queue_pairs(count) {
+                    VIRTIO_NET_OK as u8
+                } else {
+                    VIRTIO_NET_ERR as u8
+                }
+            }
+            _ => {
+                warn!(
+                    "Unsupported control command: class {}, command {}",
+                    class, cmd
+                );
+                VIRTIO_NET_ERR as u8
+            }
+        }
+    }
+
+    // Enables the first `count` queue pairs and disables the other ones. The tap queues of the
+    // disabled queue pairs are detached, so that the host only places frames on the receive
+    // queues that the driver uses. Returns whether all queue pairs were updated.
+    fn set_active_queue_pairs(&mut self, count: usize) -> bool {
+        let mut success = true;
+
+        for queue_pair in 0..self.queue_pairs.len() {
+            let enable = queue_pair < count;
+            if self.queue_pairs[queue_pair].enabled == enable {
+                continue;
+            }
+            if let Err(e) = self.queue_pairs[queue_pair].tap.set_queue_enabled(enable) {
+                error!("Failed to update tap queue {}: {:?}", queue_pair, e);
+                success = false;
+                continue;
+            }
+            self.queue_pairs[queue_pair].enabled = enable;
+
+            let pair = &self.queue_pairs[queue_pair];
+            if let Some(ref vhost) = pair.vhost {
+                // The vhost-net driver only moves the frames of the enabled queue pairs.
+                let tap = if enable { Some(&pair.tap) } else { None };
+                if let Err(e) = vhost.set_backend(tap) {
+                    error!("Failed to update vhost backend {}: {:?}", queue_pair, e);
+                    success = false;
+                }
+                continue;
+            }
+
+            // Buffers made available while the queue pair was disabled are filled as soon as
+            // frames arrive. Otherwise, the next receive queue event starts the listening.
+            let result = if enable && !self.queue_pairs[queue_pair].rx.queue.is_empty(&self.mem) {
+                self.register_tap_rx_listener(queue_pair)
+            } else if !enable && self.queue_pairs[queue_pair].rx_tap_listening {
+                self.unregister_tap_rx_listener(queue_pair)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                error!(
+                    "Failed to update tap queue {} listener: {:?}",
+                    queue_pair, e
+                );
+                success = false;
+            }
+        }
+
+        let active_queue_pairs = self.queue_pairs.iter().take_while(|p| p.enabled).count();
+        self.active_queue_pairs
+            .store(active_queue_pairs, Ordering::SeqCst);
+        success
+    }
+
     /// Updates the parameters for the rate limiters
     pub fn patch_rate_limiters(
         &mut self,
@@ -492,36 +756,127 @@ impl NetEpollHandler {
         tx_bytes: Option<TokenBucket>,
         tx_ops: Option<TokenBucket>,
     ) {
-        self.rx.rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx.rate_limiter.update_buckets(tx_bytes, tx_ops);
+        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
+        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+    }
+
+    /// Writes the frames received and sent by the device to `capture`, replacing the current
+    /// capture, or stops capturing when `capture` is None.
+    pub fn set_capture(&mut self, capture: Option<PacketCapture>) {
+        stop_capture(&mut self.capture);
+        self.capture = capture;
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx.frame_buf)
+    fn read_tap(&mut self, queue_pair: usize) -> io::Result<usize> {
+        let pair = &mut self.queue_pairs[queue_pair];
+        pair.tap.read(&mut pair.rx.frame_buf)
     }
 
-    fn register_tap_rx_listener(&mut self) -> std::result::Result<(), std::io::Error> {
+    fn register_tap_rx_listener(
+        &mut self,
+        queue_pair: usize,
+    ) -> std::result::Result<(), std::io::Error> {
+        let pair = &mut self.queue_pairs[queue_pair];
         epoll::ctl(
             self.epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.tap.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, self.rx_tap_epoll_token),
+            pair.tap.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, pair.rx_tap_epoll_token),
         )?;
-        self.rx_tap_listening = true;
+        pair.rx_tap_listening = true;
         Ok(())
     }
 
-    fn unregister_tap_rx_listener(&mut self) -> std::result::Result<(), std::io::Error> {
+    fn unregister_tap_rx_listener(
+        &mut self,
+        queue_pair: usize,
+    ) -> std::result::Result<(), std::io::Error> {
+        let pair = &mut self.queue_pairs[queue_pair];
         epoll::ctl(
             self.epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_DEL,
-            self.tap.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, self.rx_tap_epoll_token),
+            pair.tap.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, pair.rx_tap_epoll_token),
         )?;
-        self.rx_tap_listening = false;
+        pair.rx_tap_listening = false;
         Ok(())
     }
+
+    fn handle_rx_queue_event(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        self.metrics.inc(|m| &m.rx_queue_event_count);
+        if let Err(e) = self.queue_pairs[queue_pair].rx.queue_evt.read() {
+            error!("Failed to get rx queue event: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+            Err(DeviceError::FailedReadingQueue {
+                event_type: "rx queue event",
+                underlying: e,
+            })
+        } else if !self.queue_pairs[queue_pair].enabled {
+            // No frames arrive on the tap queue of a disabled queue pair.
+            Ok(())
+        } else {
+            if !self.queue_pairs[queue_pair].rx_tap_listening {
+                self.register_tap_rx_listener(queue_pair)
+                    .map_err(DeviceError::IoError)?;
+            }
+            // If the limiter is not blocked, resume the receiving of bytes.
+            if !self.rx_rate_limiter.is_blocked() {
+                // There should be a buffer available now to receive the frame into.
+                self.resume_rx(queue_pair)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_rx_tap_event(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        self.metrics.inc(|m| &m.rx_tap_event_count);
+
+        if self.queue_pairs[queue_pair].rx.queue.is_empty(&self.mem) {
+            self.unregister_tap_rx_listener(queue_pair)
+                .map_err(DeviceError::IoError)?;
+            return Err(DeviceError::NoAvailBuffers);
+        }
+
+        // While limiter is blocked, don't process any more incoming.
+        if self.rx_rate_limiter.is_blocked() {
+            Ok(())
+        } else if self.queue_pairs[queue_pair].rx.deferred_frame
+        // Process a deferred frame first if available. Don't read from tap again
+        // until we manage to receive this deferred frame.
+        {
+            if self.rate_limited_rx_single_frame(queue_pair) {
+                self.queue_pairs[queue_pair].rx.deferred_frame = false;
+                self.process_rx(queue_pair)
+            } else if self.queue_pairs[queue_pair].rx.deferred_irqs {
+                self.queue_pairs[queue_pair].rx.deferred_irqs = false;
+                self.signal_used_queue()
+            } else {
+                Ok(())
+            }
+        } else {
+            self.process_rx(queue_pair)
+        }
+    }
+
+    fn handle_tx_queue_event(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        self.metrics.inc(|m| &m.tx_queue_event_count);
+        if let Err(e) = self.queue_pairs[queue_pair].tx.queue_evt.read() {
+            error!("Failed to get tx queue event: {:?}", e);
+            self.metrics.inc(|m| &m.event_fails);
+            Err(DeviceError::FailedReadingQueue {
+                event_type: "tx queue event",
+                underlying: e,
+            })
+        } else if !self.tx_rate_limiter.is_blocked()
+        // If the limiter is not blocked, continue transmitting bytes.
+        {
+            self.process_tx(queue_pair)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl EpollHandler for NetEpollHandler {
@@ -530,108 +885,78 @@ impl EpollHandler for NetEpollHandler {
         device_event: DeviceEventT,
         _evset: epoll::Events,
     ) -> result::Result<(), DeviceError> {
+        let num_queue_pairs = self.queue_pairs.len();
         match device_event {
-            RX_QUEUE_EVENT => {
-                METRICS.net.rx_queue_event_count.inc();
-                if let Err(e) = self.rx.queue_evt.read() {
-                    error!("Failed to get rx queue event: {:?}", e);
-                    METRICS.net.event_fails.inc();
-                    Err(DeviceError::FailedReadingQueue {
-                        event_type: "rx queue event",
-                        underlying: e,
-                    })
-                } else {
-                    if !self.rx_tap_listening {
-                        self.register_tap_rx_listener()
-                            .map_err(DeviceError::IoError)?;
-                    }
-                    // If the limiter is not blocked, resume the receiving of bytes.
-                    if !self.rx.rate_limiter.is_blocked() {
-                        // There should be a buffer available now to receive the frame into.
-                        self.resume_rx()
-                    } else {
-                        Ok(())
-                    }
-                }
-            }
-            RX_TAP_EVENT => {
-                METRICS.net.rx_tap_event_count.inc();
-
-                if self.rx.queue.is_empty(&self.mem) {
-                    self.unregister_tap_rx_listener()
-                        .map_err(DeviceError::IoError)?;
-                    return Err(DeviceError::NoAvailBuffers);
-                }
-
-                // While limiter is blocked, don't process any more incoming.
-                if self.rx.rate_limiter.is_blocked() {
-                    Ok(())
-                } else if self.rx.deferred_frame
-                // Process a deferred frame first if available. Don't read from tap again
-                // until we manage to receive this deferred frame.
-                {
-                    if self.rate_limited_rx_single_frame() {
-                        self.rx.deferred_frame = false;
-                        self.process_rx()
-                    } else if self.rx.deferred_irqs {
-                        self.rx.deferred_irqs = false;
-                        self.signal_used_queue()
-                    } else {
-                        Ok(())
-                    }
-                } else {
-                    self.process_rx()
-                }
-            }
-            TX_QUEUE_EVENT => {
-                METRICS.net.tx_queue_event_count.inc();
-                if let Err(e) = self.tx.queue_evt.read() {
-                    error!("Failed to get tx queue event: {:?}", e);
-                    METRICS.net.event_fails.inc();
-                    Err(DeviceError::FailedReadingQueue {
-                        event_type: "tx queue event",
-                        underlying: e,
-                    })
-                } else if !self.tx.rate_limiter.is_blocked()
-                // If the limiter is not blocked, continue transmitting bytes.
-                {
-                    self.process_tx()
-                } else {
-                    Ok(())
-                }
-            }
             RX_RATE_LIMITER_EVENT => {
-                METRICS.net.rx_event_rate_limiter_count.inc();
+                self.metrics.inc(|m| &m.rx_event_rate_limiter_count);
                 // Upon rate limiter event, call the rate limiter handler
-                // and restart processing the queue.
-                match self.rx.rate_limiter.event_handler() {
+                // and restart processing the queues.
+                match self.rx_rate_limiter.event_handler() {
                     Ok(_) => {
-                        // There might be enough budget now to receive the frame.
-                        self.resume_rx()
+                        // There might be enough budget now to receive the frames.
+                        (0..num_queue_pairs).try_for_each(|queue_pair| self.resume_rx(queue_pair))
                     }
                     Err(e) => {
-                        METRICS.net.event_fails.inc();
+                        self.metrics.inc(|m| &m.event_fails);
                         error!("Failed to get rx rate-limiter event: {:?}", e);
                         Err(DeviceError::RateLimited(e))
                     }
                 }
             }
             TX_RATE_LIMITER_EVENT => {
-                METRICS.net.tx_rate_limiter_event_count.inc();
+                self.metrics.inc(|m| &m.tx_rate_limiter_event_count);
                 // Upon rate limiter event, call the rate limiter handler
-                // and restart processing the queue.
-                match self.tx.rate_limiter.event_handler() {
+                // and restart processing the queues.
+                match self.tx_rate_limiter.event_handler() {
                     Ok(_) => {
-                        // There might be enough budget now to send the frame.
-                        self.process_tx()
+                        // There might be enough budget now to send the frames.
+                        (0..num_queue_pairs).try_for_each(|queue_pair| self.process_tx(queue_pair))
                     }
                     Err(e) => {
-                        METRICS.net.event_fails.inc();
+                        self.metrics.inc(|m| &m.event_fails);
                         error!("Failed to get tx rate-limiter event: {:?}", e);
                         Err(DeviceError::RateLimited(e))
                     }
                 }
             }
+            CTRL_QUEUE_EVENT if self.ctrl.is_some() => {
+                // We just checked that the control queue exists.
+                if let Err(e) = self.ctrl.as_ref().unwrap().queue_evt.read() {
+                    error!("Failed to get control queue event: {:?}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "control queue event",
+                        underlying: e,
+                    })
+                } else {
+                    self.process_ctrl_queue()
+                }
+            }
+            VHOST_CALL_EVENT if self.vhost_call_evt.is_some() => {
+                // We just checked that the vhost call event exists.
+                if let Err(e) = self.vhost_call_evt.as_ref().unwrap().read() {
+                    error!("Failed to get vhost call event: {:?}", e);
+                    self.metrics.inc(|m| &m.event_fails);
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "vhost call event",
+                        underlying: e,
+                    })
+                } else {
+                    self.signal_used_queue()
+                }
+            }
+            event
+                if event >= RX_TAP_EVENT
+                    && usize::from((event - RX_TAP_EVENT) / QUEUE_PAIR_EVENTS_COUNT)
+                        < num_queue_pairs =>
+            {
+                let queue_pair = usize::from((event - RX_TAP_EVENT) / QUEUE_PAIR_EVENTS_COUNT);
+                match RX_TAP_EVENT + (event - RX_TAP_EVENT) % QUEUE_PAIR_EVENTS_COUNT {
+                    RX_TAP_EVENT => self.handle_rx_tap_event(queue_pair),
+                    RX_QUEUE_EVENT => self.handle_rx_queue_event(queue_pair),
+                    _ => self.handle_tx_queue_event(queue_pair),
+                }
+            }
             other => Err(DeviceError::UnknownEvent {
                 device: "net",
                 event: other,
@@ -646,6 +971,8 @@ pub struct EpollConfig {
     tx_queue_token: u64,
     rx_rate_limiter_token: u64,
     tx_rate_limiter_token: u64,
+    ctrl_queue_token: u64,
+    vhost_call_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<dyn EpollHandler>>,
 }
@@ -662,6 +989,8 @@ impl EpollConfigConstructor for EpollConfig {
             tx_queue_token: first_token + u64::from(TX_QUEUE_EVENT),
             rx_rate_limiter_token: first_token + u64::from(RX_RATE_LIMITER_EVENT),
             tx_rate_limiter_token: first_token + u64::from(TX_RATE_LIMITER_EVENT),
+            ctrl_queue_token: first_token + u64::from(CTRL_QUEUE_EVENT),
+            vhost_call_token: first_token + u64::from(VHOST_CALL_EVENT),
             epoll_raw_fd,
             sender,
         }
@@ -669,20 +998,81 @@ impl EpollConfigConstructor for EpollConfig {
 }
 
 pub struct Net {
-    tap: Option<Tap>,
+    taps: Vec<Tap>,
+    // One vhost-net driver per tap queue when the data path is handed over to the kernel.
+    vhost: Vec<VhostNet>,
+    // The features supported by all the vhost-net drivers.
+    vhost_features: u64,
+    queue_sizes: Vec<u16>,
     avail_features: u64,
     acked_features: u64,
     // The config space will only consist of the MAC address specified by the user,
-    // or nothing, if no such address if provided.
+    // or nothing, if no such address if provided. Devices with several queue pairs
+    // also expose the maximum number of queue pairs.
     config_space: Vec<u8>,
     epoll_config: EpollConfig,
     rx_rate_limiter: Option<RateLimiter>,
     tx_rate_limiter: Option<RateLimiter>,
     allow_mmds_requests: bool,
+    egress_filter: Option<EgressFilter>,
+    // The number of queue pairs enabled by the driver, updated by the epoll handler.
+    active_queue_pairs: Arc<AtomicUsize>,
+    metrics: DeviceMetrics<NetDeviceMetrics>,
+}
+
+/// The serializable state of a virtio network device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NetState {
+    /// The features offered by the device.
+    pub avail_features: u64,
+    /// The features acknowledged by the driver.
+    pub acked_features: u64,
+    /// The configuration space of the device.
+    pub config_space: Vec<u8>,
+    /// The number of RX/TX queue pairs enabled by the driver.
+    pub active_queue_pairs: u16,
+}
+
+// Version 1 did not have the `active_queue_pairs` field, and only the first queue pair was
+// enabled after a restore.
+impl Versioned for NetState {
+    const NAME: &'static str = "Net";
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, mut state: Value) -> VersionResult<Value> {
+        match version {
+            1 => {
+                if let Some(fields) = state.as_object_mut() {
+                    fields.insert("active_queue_pairs".to_string(), Value::from(1));
+                }
+                Ok(state)
+            }
+            _ => Err(VersionError::UnsupportedVersion {
+                name: Self::NAME,
+                version,
+            }),
+        }
+    }
+
+    fn downgrade(version: u16, mut state: Value) -> VersionResult<Value> {
+        match version {
+            2 => {
+                state
+                    .as_object_mut()
+                    .map(|fields| fields.remove("active_queue_pairs"));
+                Ok(state)
+            }
+            _ => Err(VersionError::UnsupportedVersion {
+                name: Self::NAME,
+                version: version - 1,
+            }),
+        }
+    }
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
+    /// Create a new virtio network device with the given TAP interface. Its metrics are accounted
+    /// in `metrics`, as well as in the aggregate network device metrics.
     pub fn new_with_tap(
         tap: Tap,
         guest_mac: Option<&MacAddr>,
@@ -690,16 +1080,57 @@ impl Net {
         rx_rate_limiter: Option<RateLimiter>,
         tx_rate_limiter: Option<RateLimiter>,
         allow_mmds_requests: bool,
+        metrics: Arc<NetDeviceMetrics>,
     ) -> Result<Self> {
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
+        Net::new_with_taps(
+            vec![tap],
+            guest_mac,
+            epoll_config,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            allow_mmds_requests,
+            metrics,
         )
-        .map_err(Error::TapSetOffload)?;
+    }
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+    /// Create a new virtio network device with one RX/TX queue pair per queue of the given TAP
+    /// interface, up to `MAX_QUEUE_PAIRS`. Devices with several queue pairs offer
+    /// `VIRTIO_NET_F_MQ`, and only use the first one until the driver enables the other ones.
+    /// The rate limiters apply to the traffic of all the queue pairs.
+    pub fn new_with_taps(
+        taps: Vec<Tap>,
+        guest_mac: Option<&MacAddr>,
+        epoll_config: EpollConfig,
+        rx_rate_limiter: Option<RateLimiter>,
+        tx_rate_limiter: Option<RateLimiter>,
+        allow_mmds_requests: bool,
+        metrics: Arc<NetDeviceMetrics>,
+    ) -> Result<Self> {
+        let num_queue_pairs = taps.len();
+        if num_queue_pairs == 0 || num_queue_pairs > usize::from(MAX_QUEUE_PAIRS) {
+            return Err(Error::InvalidQueuePairs);
+        }
+
+        for tap in &taps {
+            // Set offload flags to match the virtio features below.
+            tap.set_offload(
+                net_gen::TUN_F_CSUM
+                    | net_gen::TUN_F_UFO
+                    | net_gen::TUN_F_TSO4
+                    | net_gen::TUN_F_TSO6,
+            )
+            .map_err(Error::TapSetOffload)?;
+
+            let vnet_hdr_size = vnet_hdr_len() as i32;
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
+
+        // The host must not place frames on the queues the driver does not use yet.
+        for tap in &taps[1..] {
+            tap.set_queue_enabled(false)
+                .map_err(Error::TapDetachQueue)?;
+        }
 
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
@@ -722,8 +1153,23 @@ impl Net {
             config_space = Vec::new();
         }
 
+        // The receive and transmit queues of each queue pair, followed by the control queue
+        // when there are several queue pairs.
+        let mut queue_sizes = vec![QUEUE_SIZE; 2 * num_queue_pairs];
+        if num_queue_pairs > 1 {
+            // The maximum number of queue pairs follows the MAC address and the status in the
+            // config space. Both are ignored by the driver without their feature bits.
+            config_space.resize(CONFIG_MAX_QUEUE_PAIRS_OFFSET, 0);
+            config_space.extend_from_slice(&(num_queue_pairs as u16).to_le_bytes());
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            queue_sizes.push(CTRL_QUEUE_SIZE);
+        }
+
         Ok(Net {
-            tap: Some(tap),
+            taps,
+            vhost: Vec::new(),
+            vhost_features: 0,
+            queue_sizes,
             avail_features,
             acked_features: 0u64,
             config_space,
@@ -731,11 +1177,68 @@ impl Net {
             rx_rate_limiter,
             tx_rate_limiter,
             allow_mmds_requests,
+            egress_filter: None,
+            active_queue_pairs: Arc::new(AtomicUsize::new(1)),
+            metrics: DeviceMetrics::new(&METRICS.net, metrics),
         })
     }
 
+    /// Hands the data path of the device over to the in-kernel vhost-net driver, with one
+    /// instance of the driver per queue pair. The device model then only handles the control
+    /// queue and the interrupts, so frames neither go through the rate limiters nor reach the
+    /// MMDS.
+    pub fn enable_vhost(&mut self) -> Result<()> {
+        let mut vhost = Vec::with_capacity(self.taps.len());
+        let mut vhost_features = !0u64;
+        for _ in &self.taps {
+            let vhost_net = VhostNet::new().map_err(Error::Vhost)?;
+            vhost_features &= vhost_net.get_features().map_err(Error::Vhost)?;
+            vhost.push(vhost_net);
+        }
+        // The driver must use the same vnet header as the tap devices, which is the one of
+        // virtio 1.0.
+        if vhost_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::VhostNoVersion1);
+        }
+
+        self.vhost = vhost;
+        self.vhost_features = vhost_features;
+        Ok(())
+    }
+
+    /// Drops the frames sent by the guest that `egress_filter` does not allow, instead of
+    /// writing them to the TAP device. The frames of devices using vhost-net are not filtered.
+    pub fn set_egress_filter(&mut self, egress_filter: EgressFilter) {
+        self.egress_filter = Some(egress_filter);
+    }
+
+    /// Returns the current state of the device.
+    pub fn save_state(&self) -> NetState {
+        NetState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config_space: self.config_space.clone(),
+            active_queue_pairs: self.active_queue_pairs.load(Ordering::SeqCst) as u16,
+        }
+    }
+
+    /// Restores the device to a previously saved state. Acknowledged features that the device
+    /// does not offer anymore are dropped, and the number of enabled queue pairs is capped to
+    /// the queue pairs of the device. The queue pairs are enabled when the device is activated.
+    pub fn restore_state(&mut self, state: &NetState) {
+        self.acked_features = state.acked_features & self.avail_features;
+        self.config_space = state.config_space.clone();
+        let active_queue_pairs = usize::from(state.active_queue_pairs)
+            .max(1)
+            .min(self.queue_sizes.len() / 2);
+        self.active_queue_pairs
+            .store(active_queue_pairs, Ordering::SeqCst);
+    }
+
     fn guest_mac(&self) -> Option<MacAddr> {
-        if self.config_space.len() < MAC_ADDR_LEN {
+        if self.avail_features & (1 << VIRTIO_NET_F_MAC) == 0
+            || self.config_space.len() < MAC_ADDR_LEN
+        {
             None
         } else {
             Some(MacAddr::from_bytes_unchecked(
@@ -745,13 +1248,35 @@ impl Net {
     }
 }
 
+// Hands the queue pairs over to their vhost-net drivers, which signal used buffers through
+// `call_evt`. Only the enabled queue pairs get a backend.
+fn activate_vhost(
+    queue_pairs: &[QueuePair],
+    mem: &GuestMemory,
+    features: u64,
+    call_evt: &EventFd,
+) -> vhost::Result<()> {
+    for pair in queue_pairs {
+        if let Some(ref vhost) = pair.vhost {
+            vhost.set_features(features)?;
+            vhost.set_mem_table(mem)?;
+            vhost.set_vring(0, &pair.rx.queue, mem, &pair.rx.queue_evt, call_evt)?;
+            vhost.set_vring(1, &pair.tx.queue, mem, &pair.tx.queue_evt, call_evt)?;
+            if pair.enabled {
+                vhost.set_backend(Some(&pair.tap))?;
+            }
+        }
+    }
+    Ok(())
+}
+
 impl VirtioDevice for Net {
     fn device_type(&self) -> u32 {
         TYPE_NET
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn avail_features(&self) -> u64 {
@@ -770,7 +1295,7 @@ impl VirtioDevice for Net {
         let config_len = self.config_space.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
-            METRICS.net.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
@@ -785,7 +1310,7 @@ impl VirtioDevice for Net {
         let config_len = self.config_space.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
-            METRICS.net.cfg_fails.inc();
+            self.metrics.inc(|m| &m.cfg_fails);
             return;
         }
         let (_, right) = self.config_space.split_at_mut(offset as usize);
@@ -797,61 +1322,127 @@ impl VirtioDevice for Net {
         mem: GuestMemory,
         interrupt_evt: EventFd,
         status: Arc<AtomicUsize>,
-        mut queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
-            METRICS.net.activate_fails.inc();
+            self.metrics.inc(|m| &m.activate_fails);
 
             return Err(ActivateError::BadActivate);
         }
 
-        if let Some(tap) = self.tap.take() {
-            let rx_queue = queues.remove(0);
-            let tx_queue = queues.remove(0);
-            let rx_queue_evt = queue_evts.remove(0);
-            let tx_queue_evt = queue_evts.remove(0);
+        if !self.taps.is_empty() {
+            let mut queues = queues.into_iter();
+            let mut queue_evts = queue_evts.into_iter();
+            let mut queue_pairs = Vec::with_capacity(self.taps.len());
+            let mut vhost = mem::replace(&mut self.vhost, Vec::new()).into_iter();
+            // Only the first queue pair is enabled, unless a restored state enabled more.
+            let active_queue_pairs = self.active_queue_pairs.load(Ordering::SeqCst);
+            for (index, tap) in self.taps.drain(..).enumerate() {
+                let enabled = index < active_queue_pairs;
+                if index > 0 && enabled {
+                    if let Err(e) = tap.set_queue_enabled(true) {
+                        error!("Failed to attach tap queue {}: {:?}", index, e);
+                        self.metrics.inc(|m| &m.activate_fails);
+                        return Err(ActivateError::BadActivate);
+                    }
+                }
+                // The number of queues was checked above.
+                queue_pairs.push(QueuePair {
+                    rx: RxVirtio::new(queues.next().unwrap(), queue_evts.next().unwrap()),
+                    tx: TxVirtio::new(queues.next().unwrap(), queue_evts.next().unwrap()),
+                    tap,
+                    enabled,
+                    rx_tap_listening: false,
+                    rx_tap_epoll_token: self.epoll_config.rx_tap_token
+                        + queue_pair_event_offset(index),
+                    vhost: vhost.next(),
+                });
+            }
+            // The control queue, if any, is the last one.
+            let ctrl = queues.next().map(|queue| CtrlVirtio {
+                queue_evt: queue_evts.next().unwrap(),
+                queue,
+            });
+
+            let vhost_call_evt = if queue_pairs[0].vhost.is_some() {
+                let features = self.acked_features & self.vhost_features;
+                let call_evt = match EventFd::new() {
+                    Ok(call_evt) => call_evt,
+                    Err(e) => {
+                        error!("Failed to create vhost call event: {:?}", e);
+                        self.metrics.inc(|m| &m.activate_fails);
+                        return Err(ActivateError::BadActivate);
+                    }
+                };
+                if let Err(e) = activate_vhost(&queue_pairs, &mem, features, &call_evt) {
+                    error!("Failed to set up vhost-net: {:?}", e);
+                    self.metrics.inc(|m| &m.activate_fails);
+                    return Err(ActivateError::BadActivate);
+                }
+                Some(call_evt)
+            } else {
+                None
+            };
+
             let mmds_ns = if self.allow_mmds_requests {
                 Some(MmdsNetworkStack::new_with_defaults())
             } else {
                 None
             };
             let handler = NetEpollHandler {
-                rx: RxVirtio::new(
-                    rx_queue,
-                    rx_queue_evt,
-                    self.rx_rate_limiter.take().unwrap_or_default(),
-                ),
-                tap,
+                queue_pairs,
+                ctrl,
+                vhost_call_evt,
+                rx_rate_limiter: self.rx_rate_limiter.take().unwrap_or_default(),
+                tx_rate_limiter: self.tx_rate_limiter.take().unwrap_or_default(),
                 mem,
-                tx: TxVirtio::new(
-                    tx_queue,
-                    tx_queue_evt,
-                    self.tx_rate_limiter.take().unwrap_or_default(),
-                ),
                 interrupt_status: status,
                 interrupt_evt,
                 acked_features: self.acked_features,
                 mmds_ns,
                 guest_mac: self.guest_mac(),
+                egress_filter: self.egress_filter.take(),
+                capture: None,
                 epoll_fd: self.epoll_config.epoll_raw_fd,
-                rx_tap_listening: false,
-                rx_tap_epoll_token: self.epoll_config.rx_tap_token,
+                active_queue_pairs: self.active_queue_pairs.clone(),
+                metrics: self.metrics.clone(),
 
                 #[cfg(test)]
                 test_mutators: tests::TestMutators::default(),
             };
 
-            let rx_queue_raw_fd = handler.rx.queue_evt.as_raw_fd();
-            let tx_queue_raw_fd = handler.tx.queue_evt.as_raw_fd();
+            let mut queue_evt_tokens = Vec::with_capacity(num_queues);
+            if let Some(ref call_evt) = handler.vhost_call_evt {
+                // The queue events of the queue pairs go to the vhost-net drivers.
+                queue_evt_tokens.push((call_evt.as_raw_fd(), self.epoll_config.vhost_call_token));
+            } else {
+                for (index, pair) in handler.queue_pairs.iter().enumerate() {
+                    queue_evt_tokens.push((
+                        pair.rx.queue_evt.as_raw_fd(),
+                        self.epoll_config.rx_queue_token + queue_pair_event_offset(index),
+                    ));
+                    queue_evt_tokens.push((
+                        pair.tx.queue_evt.as_raw_fd(),
+                        self.epoll_config.tx_queue_token + queue_pair_event_offset(index),
+                    ));
+                }
+            }
+            if let Some(ref ctrl) = handler.ctrl {
+                queue_evt_tokens.push((
+                    ctrl.queue_evt.as_raw_fd(),
+                    self.epoll_config.ctrl_queue_token,
+                ));
+            }
 
-            let rx_rate_limiter_rawfd = handler.rx.rate_limiter.as_raw_fd();
-            let tx_rate_limiter_rawfd = handler.tx.rate_limiter.as_raw_fd();
+            let rx_rate_limiter_rawfd = handler.rx_rate_limiter.as_raw_fd();
+            let tx_rate_limiter_rawfd = handler.tx_rate_limiter.as_raw_fd();
 
             //channel should be open and working
             self.epoll_config
@@ -861,27 +1452,18 @@ impl VirtioDevice for Net {
 
             //TODO: barrier needed here maybe?
 
-            epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
-                epoll::ControlOptions::EPOLL_CTL_ADD,
-                rx_queue_raw_fd,
-                epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.rx_queue_token),
-            )
-            .map_err(|e| {
-                METRICS.net.activate_fails.inc();
-                ActivateError::EpollCtl(e)
-            })?;
-
-            epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
-                epoll::ControlOptions::EPOLL_CTL_ADD,
-                tx_queue_raw_fd,
-                epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.tx_queue_token),
-            )
-            .map_err(|e| {
-                METRICS.net.activate_fails.inc();
-                ActivateError::EpollCtl(e)
-            })?;
+            for (queue_evt_raw_fd, token) in queue_evt_tokens {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    queue_evt_raw_fd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, token),
+                )
+                .map_err(|e| {
+                    self.metrics.inc(|m| &m.activate_fails);
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
 
             if rx_rate_limiter_rawfd != -1 {
                 epoll::ctl(
@@ -911,7 +1493,7 @@ impl VirtioDevice for Net {
 
             return Ok(());
         }
-        METRICS.net.activate_fails.inc();
+        self.metrics.inc(|m| &m.activate_fails);
         Err(ActivateError::BadActivate)
     }
 }
@@ -962,6 +1544,7 @@ mod tests {
             rx_rate_limiter,
             tx_rate_limiter,
             allow_mmds_requests,
+            Arc::default(),
         )
     }
 
@@ -1037,21 +1620,22 @@ mod tests {
 
     impl NetEpollHandler {
         fn get_rx_rate_limiter(&self) -> &RateLimiter {
-            &self.rx.rate_limiter
+            &self.rx_rate_limiter
         }
 
         fn get_tx_rate_limiter(&self) -> &RateLimiter {
-            &self.tx.rate_limiter
+            &self.tx_rate_limiter
         }
 
         // This needs to be public to be accessible from the non-cfg-test `impl NetEpollHandler`.
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, queue_pair: usize) -> io::Result<usize> {
             use std::cmp::min;
 
-            let count = min(1234, self.rx.frame_buf.len());
+            let rx = &mut self.queue_pairs[queue_pair].rx;
+            let count = min(1234, rx.frame_buf.len());
 
             for i in 0..count {
-                self.rx.frame_buf[i] = 5;
+                rx.frame_buf[i] = 5;
             }
 
             if self.test_mutators.tap_read_fail {
@@ -1062,20 +1646,20 @@ mod tests {
         }
 
         fn rx_single_frame_no_irq_coalescing(&mut self) -> bool {
-            let ret = self.rx_single_frame();
-            if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
+            let ret = self.rx_single_frame(0);
+            if self.queue_pairs[0].rx.deferred_irqs {
+                self.queue_pairs[0].rx.deferred_irqs = false;
                 let _ = self.signal_used_queue();
             }
             ret
         }
 
         fn set_rx_rate_limiter(&mut self, rx_rate_limiter: RateLimiter) {
-            self.rx.rate_limiter = rx_rate_limiter;
+            self.rx_rate_limiter = rx_rate_limiter;
         }
 
         fn set_tx_rate_limiter(&mut self, tx_rate_limiter: RateLimiter) {
-            self.tx.rate_limiter = tx_rate_limiter;
+            self.tx_rate_limiter = tx_rate_limiter;
         }
     }
 
@@ -1125,19 +1709,31 @@ mod tests {
 
         (
             NetEpollHandler {
-                rx: RxVirtio::new(rx_queue, rx_queue_evt, RateLimiter::default()),
-                tap: n.tap.take().unwrap(),
+                queue_pairs: vec![QueuePair {
+                    rx: RxVirtio::new(rx_queue, rx_queue_evt),
+                    tx: TxVirtio::new(tx_queue, tx_queue_evt),
+                    tap: n.taps.remove(0),
+                    enabled: true,
+                    rx_tap_listening: false,
+                    rx_tap_epoll_token: 0,
+                    vhost: None,
+                }],
+                ctrl: None,
+                vhost_call_evt: None,
+                rx_rate_limiter: RateLimiter::default(),
+                tx_rate_limiter: RateLimiter::default(),
                 mem: mem.clone(),
-                tx: TxVirtio::new(tx_queue, tx_queue_evt, RateLimiter::default()),
                 interrupt_status,
                 interrupt_evt,
                 acked_features: n.acked_features,
                 mmds_ns: Some(MmdsNetworkStack::new_with_defaults()),
                 test_mutators,
                 guest_mac: None,
+                egress_filter: None,
+                capture: None,
                 epoll_fd,
-                rx_tap_epoll_token: 0,
-                rx_tap_listening: false,
+                active_queue_pairs: n.active_queue_pairs.clone(),
+                metrics: n.metrics.clone(),
             },
             txq,
             rxq,
@@ -1182,7 +1778,7 @@ mod tests {
         // Test `queue_max_sizes()`.
         {
             let x = n.queue_max_sizes();
-            assert_eq!(x, QUEUE_SIZES);
+            assert_eq!(x, &[QUEUE_SIZE; 2]);
 
             // power of 2?
             for &y in x {
@@ -1295,6 +1891,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_save_restore_state() {
+        let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
+        let mut dummy = DummyNet::new(Some(&mac));
+        let n = dummy.net();
+        n.ack_features_by_page(0, 1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_CSUM);
+        let mut state = n.save_state();
+        assert_eq!(
+            state.acked_features,
+            1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_CSUM
+        );
+        assert_eq!(state.config_space, mac.get_bytes());
+        assert_eq!(state.active_queue_pairs, 1);
+
+        let mut other_dummy = DummyNet::new(Some(&mac));
+        let other = other_dummy.net();
+        other.restore_state(&state);
+        assert_eq!(other.save_state(), state);
+
+        // The device cannot enable more queue pairs than it has.
+        state.active_queue_pairs = 2;
+        other.restore_state(&state);
+        assert_eq!(other.save_state().active_queue_pairs, 1);
+        state.active_queue_pairs = 1;
+
+        // States saved before the number of enabled queue pairs was recorded enable the first
+        // one only, and the number is dropped when saving them in that layout.
+        let value = fc_util::versioned::to_value(&state, 1).unwrap();
+        assert!(value["state"].get("active_queue_pairs").is_none());
+        assert_eq!(
+            fc_util::versioned::from_value::<NetState>(value).unwrap(),
+            state
+        );
+
+        // Features that the device does not offer are not restored.
+        let mut no_mac_dummy = DummyNet::new(None);
+        let no_mac = no_mac_dummy.net();
+        state.config_space = vec![];
+        no_mac.restore_state(&state);
+        assert_eq!(no_mac.acked_features(), 1 << VIRTIO_NET_F_CSUM);
+        assert!(no_mac.guest_mac().is_none());
+    }
+
     #[test]
     fn test_mmds_detour_and_injection() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1309,7 +1948,7 @@ mod tests {
         {
             // Create an ethernet frame.
             let eth_frame_i = EthernetFrame::write_incomplete(
-                frame_bytes_from_buf_mut(&mut h.tx.frame_buf),
+                frame_bytes_from_buf_mut(&mut h.queue_pairs[0].tx.frame_buf),
                 tha,
                 sha,
                 ETHERTYPE_ARP,
@@ -1335,16 +1974,19 @@ mod tests {
         }
 
         // Call the code which sends the packet to the host or MMDS.
+        let pair = &mut h.queue_pairs[0];
         // Validate the frame was consumed by MMDS and that the metrics reflect that.
         check_metric_after_block!(
             &METRICS.mmds.rx_accepted,
             1,
             assert!(NetEpollHandler::write_to_mmds_or_tap(
                 h.mmds_ns.as_mut(),
-                &mut h.tx.rate_limiter,
-                &h.tx.frame_buf[..packet_len],
-                &mut h.tap,
+                &mut h.tx_rate_limiter,
+                &pair.tx.frame_buf[..packet_len],
+                &mut pair.tap,
                 Some(sha),
+                None,
+                &h.metrics,
             ))
         );
 
@@ -1352,10 +1994,84 @@ mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            h.read_from_mmds_or_tap().unwrap()
+            h.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
+    #[test]
+    fn test_packet_capture() {
+        extern crate tempfile;
+
+        use std::fs;
+
+        use byteorder::{ByteOrder, LittleEndian};
+
+        use crate::virtio::PCAP_HEADER_SIZE;
+
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        let capture_file = tempfile::NamedTempFile::new().unwrap();
+        h.set_capture(Some(
+            PacketCapture::new(capture_file.reopen().unwrap(), 0xffff, 1 << 20).unwrap(),
+        ));
+
+        // The guest sends an ARP request for the MMDS address.
+        let sha = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let mut frame_buf = vec![0u8; vnet_hdr_len() + 14 + ETH_IPV4_FRAME_LEN];
+        {
+            let mut eth_frame = EthernetFrame::write_incomplete(
+                frame_bytes_from_buf_mut(&mut frame_buf),
+                MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+                sha,
+                ETHERTYPE_ARP,
+            )
+            .unwrap()
+            .with_payload_len_unchecked(ETH_IPV4_FRAME_LEN);
+            EthIPv4ArpFrame::write_request(
+                eth_frame.payload_mut(),
+                sha,
+                Ipv4Addr::new(10, 1, 2, 3),
+                MacAddr::parse_str("00:00:00:00:00:00").unwrap(),
+                Ipv4Addr::new(169, 254, 169, 254),
+            )
+            .unwrap();
+        }
+        let daddr = 0x2000u64;
+        mem.write_slice_at_addr(&frame_buf, GuestAddress(daddr as usize))
+            .unwrap();
+        txq.avail.idx.set(1);
+        txq.avail.ring[0].set(0);
+        txq.dtable[0].set(daddr, frame_buf.len() as u32, 0, 0);
+        h.queue_pairs[0].tx.queue_evt.write(1).unwrap();
+        h.handle_event(TX_QUEUE_EVENT, EPOLLIN).unwrap();
+        assert_eq!(txq.used.idx.get(), 1);
+
+        // Both the request and the reply of the MMDS are captured, without their VNET header.
+        let data = fs::read(capture_file.path()).unwrap();
+        let record = &data[PCAP_HEADER_SIZE as usize..];
+        let request_len = frame_buf.len() - vnet_hdr_len();
+        assert_eq!(LittleEndian::read_u32(&record[8..12]) as usize, request_len);
+        assert_eq!(
+            &record[16..16 + request_len],
+            frame_bytes_from_buf(&frame_buf)
+        );
+        let record = &record[16 + request_len..];
+        let reply_len = LittleEndian::read_u32(&record[8..12]) as usize;
+        assert_eq!(record.len(), 16 + reply_len);
+        let reply = EthernetFrame::from_bytes(&record[16..]).unwrap();
+        assert_eq!(reply.dst_mac(), sha);
+        assert_eq!(reply.ethertype(), ETHERTYPE_ARP);
+
+        // Nothing is captured once the capture is stopped.
+        h.set_capture(None);
+        txq.avail.idx.set(2);
+        txq.avail.ring[1].set(0);
+        h.queue_pairs[0].tx.queue_evt.write(1).unwrap();
+        h.handle_event(TX_QUEUE_EVENT, EPOLLIN).unwrap();
+        assert_eq!(txq.used.idx.get(), 2);
+        assert_eq!(fs::read(capture_file.path()).unwrap(), data);
+    }
+
     #[test]
     fn test_mac_spoofing_detection() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1371,7 +2087,7 @@ mod tests {
         {
             // Create an ethernet frame.
             let eth_frame_i = EthernetFrame::write_incomplete(
-                frame_bytes_from_buf_mut(&mut h.tx.frame_buf),
+                frame_bytes_from_buf_mut(&mut h.queue_pairs[0].tx.frame_buf),
                 dst_mac,
                 guest_mac,
                 ETHERTYPE_ARP,
@@ -1396,29 +2112,86 @@ mod tests {
             assert!(arp_req.is_ok());
         }
 
+        let pair = &mut h.queue_pairs[0];
         // Check that a legit MAC doesn't affect the spoofed MAC metric.
         check_metric_after_block!(
             &METRICS.net.tx_spoofed_mac_count,
             0,
             NetEpollHandler::write_to_mmds_or_tap(
                 h.mmds_ns.as_mut(),
-                &mut h.tx.rate_limiter,
-                &h.tx.frame_buf[..packet_len],
-                &mut h.tap,
+                &mut h.tx_rate_limiter,
+                &pair.tx.frame_buf[..packet_len],
+                &mut pair.tap,
                 Some(guest_mac),
+                None,
+                &h.metrics,
             )
         );
 
-        // Check that a spoofed MAC increases our spoofed MAC metric.
+        // Check that a spoofed MAC increases our spoofed MAC metric, including the one of the
+        // device.
+        check_metric_after_block!(
+            &h.metrics.device().tx_spoofed_mac_count,
+            1,
+            NetEpollHandler::write_to_mmds_or_tap(
+                h.mmds_ns.as_mut(),
+                &mut h.tx_rate_limiter,
+                &pair.tx.frame_buf[..packet_len],
+                &mut pair.tap,
+                Some(not_guest_mac),
+                None,
+                &h.metrics,
+            )
+        );
         check_metric_after_block!(
             &METRICS.net.tx_spoofed_mac_count,
             1,
             NetEpollHandler::write_to_mmds_or_tap(
                 h.mmds_ns.as_mut(),
-                &mut h.tx.rate_limiter,
-                &h.tx.frame_buf[..packet_len],
-                &mut h.tap,
+                &mut h.tx_rate_limiter,
+                &pair.tx.frame_buf[..packet_len],
+                &mut pair.tap,
                 Some(not_guest_mac),
+                None,
+                &h.metrics,
+            )
+        );
+
+        // Check that the egress filter drops the frames it does not allow, and only those.
+        let filter = EgressFilter::new(guest_mac, Some(vec![guest_ip]));
+        check_metric_after_block!(
+            &h.metrics.device().tx_filtered_count,
+            0,
+            check_metric_after_block!(
+                &h.metrics.device().tx_packets_count,
+                1,
+                NetEpollHandler::write_to_mmds_or_tap(
+                    h.mmds_ns.as_mut(),
+                    &mut h.tx_rate_limiter,
+                    &pair.tx.frame_buf[..packet_len],
+                    &mut pair.tap,
+                    Some(guest_mac),
+                    Some(&filter),
+                    &h.metrics,
+                )
+            )
+        );
+        let filter = EgressFilter::new(not_guest_mac, None);
+        check_metric_after_block!(
+            &METRICS.net.tx_filtered_count,
+            1,
+            check_metric_after_block!(
+                &h.metrics.device().tx_packets_count,
+                0,
+                NetEpollHandler::write_to_mmds_or_tap(
+                    h.mmds_ns.as_mut(),
+                    &mut h.tx_rate_limiter,
+                    &pair.tx.frame_buf[..packet_len],
+                    &mut pair.tap,
+                    Some(not_guest_mac),
+                    Some(&filter),
+                    &h.metrics,
+                )
             )
         );
     }
@@ -1462,6 +2235,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_vhost_call_event_handler() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+
+        // Without vhost-net, there is no vhost call event.
+        match h.handle_event(VHOST_CALL_EVENT, EPOLLIN) {
+            Err(DeviceError::UnknownEvent { event, .. }) => assert_eq!(event, VHOST_CALL_EVENT),
+            _ => panic!("invalid"),
+        }
+
+        // The used buffers of the vhost-net driver are signaled to the guest.
+        h.vhost_call_evt = Some(EventFd::new().unwrap());
+        h.vhost_call_evt.as_ref().unwrap().write(1).unwrap();
+        h.interrupt_status.store(0, Ordering::SeqCst);
+        assert!(h.handle_event(VHOST_CALL_EVENT, EPOLLIN).is_ok());
+        assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(
+            h.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING as usize
+        );
+        // The call event was consumed.
+        assert!(h.vhost_call_evt.as_ref().unwrap().read().is_err());
+    }
+
     // Cannot easily test failures for:
     //  * queue_evt.read (rx and tx)
     //  * interrupt_evt.write
@@ -1473,7 +2271,7 @@ mod tests {
         };
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let (mut h, _txq, rxq) = default_test_netepollhandler(&mem, test_mutators);
-        h.register_tap_rx_listener().unwrap();
+        h.register_tap_rx_listener(0).unwrap();
 
         // The RX queue is empty.
         match h.handle_event(RX_TAP_EVENT, epoll::Events::EPOLLIN) {
@@ -1481,7 +2279,7 @@ mod tests {
             _ => panic!("invalid"),
         }
         // Since the RX was empty, we shouldn't be listening for tap RX events.
-        assert!(!h.rx_tap_listening);
+        assert!(!h.queue_pairs[0].rx_tap_listening);
 
         // Fake an avail buffer; this time, tap reading should error out.
         rxq.avail.idx.set(1);
@@ -1527,10 +2325,10 @@ mod tests {
 
         // Some corner cases for rx_single_frame().
         {
-            assert_eq!(h.rx.bytes_read, 0);
+            assert_eq!(h.queue_pairs[0].rx.bytes_read, 0);
 
             // Let's imagine we received some data.
-            h.rx.bytes_read = MAX_BUFFER_SIZE;
+            h.queue_pairs[0].rx.bytes_read = MAX_BUFFER_SIZE;
 
             {
                 // a read only descriptor
@@ -1542,7 +2340,7 @@ mod tests {
 
                 // resetting values
                 rxq.used.idx.set(0);
-                h.rx.queue = rxq.create_queue();
+                h.queue_pairs[0].rx.queue = rxq.create_queue();
                 h.interrupt_evt.write(1).unwrap();
                 // The prev rx_single_frame_no_irq_coalescing() call should have written one more.
                 assert_eq!(h.interrupt_evt.read().unwrap(), 2);
@@ -1560,13 +2358,13 @@ mod tests {
                 assert_eq!(rxq.used.idx.get(), 1);
 
                 rxq.used.idx.set(0);
-                h.rx.queue = rxq.create_queue();
+                h.queue_pairs[0].rx.queue = rxq.create_queue();
                 h.interrupt_evt.write(1).unwrap();
                 assert_eq!(h.interrupt_evt.read().unwrap(), 2);
             }
 
             // set rx_count back to 0
-            h.rx.bytes_read = 0;
+            h.queue_pairs[0].rx.bytes_read = 0;
         }
 
         // Now let's move on to the actual device events.
@@ -1577,7 +2375,7 @@ mod tests {
             txq.avail.ring[0].set(0);
             txq.dtable[0].set(daddr, 0x1000, 0, 0);
 
-            h.tx.queue_evt.write(1).unwrap();
+            h.queue_pairs[0].tx.queue_evt.write(1).unwrap();
             h.handle_event(TX_QUEUE_EVENT, EPOLLIN).unwrap();
             // Make sure the data queue advanced.
             assert_eq!(txq.used.idx.get(), 1);
@@ -1586,7 +2384,7 @@ mod tests {
         {
             // testing RX_TAP_EVENT
 
-            assert!(!h.rx.deferred_frame);
+            assert!(!h.queue_pairs[0].rx.deferred_frame);
 
             // this should work just fine
             rxq.avail.idx.set(1);
@@ -1595,7 +2393,7 @@ mod tests {
 
             h.interrupt_evt.write(1).unwrap();
             h.handle_event(RX_TAP_EVENT, EPOLLIN).unwrap();
-            assert!(h.rx.deferred_frame);
+            assert!(h.queue_pairs[0].rx.deferred_frame);
             assert_eq!(h.interrupt_evt.read().unwrap(), 2);
             // The #cfg(test) enabled version of read_tap always returns 1234 bytes (or the len of
             // the buffer, whichever is smaller).
@@ -1605,20 +2403,20 @@ mod tests {
             // a different execution path.
 
             // reset some parts of the queue first
-            h.rx.queue = rxq.create_queue();
+            h.queue_pairs[0].rx.queue = rxq.create_queue();
             rxq.used.idx.set(0);
 
             // this should also be successful
             h.interrupt_evt.write(1).unwrap();
             h.handle_event(RX_TAP_EVENT, EPOLLIN).unwrap();
-            assert!(h.rx.deferred_frame);
+            assert!(h.queue_pairs[0].rx.deferred_frame);
             assert_eq!(h.interrupt_evt.read().unwrap(), 2);
 
             // ... but the following shouldn't, because we emulate receiving much more data than
             // we can fit inside a single descriptor
 
-            h.rx.bytes_read = MAX_BUFFER_SIZE;
-            h.rx.queue = rxq.create_queue();
+            h.queue_pairs[0].rx.bytes_read = MAX_BUFFER_SIZE;
+            h.queue_pairs[0].rx.queue = rxq.create_queue();
             rxq.used.idx.set(0);
 
             h.interrupt_evt.write(1).unwrap();
@@ -1627,14 +2425,17 @@ mod tests {
                 1,
                 h.handle_event(RX_TAP_EVENT, EPOLLIN)
             );
-            assert!(h.rx.deferred_frame);
+            assert!(h.queue_pairs[0].rx.deferred_frame);
             assert_eq!(h.interrupt_evt.read().unwrap(), 2);
 
             // A mismatch shows the reception was unsuccessful.
-            assert_ne!(rxq.used.ring[0].get().len as usize, h.rx.bytes_read);
+            assert_ne!(
+                rxq.used.ring[0].get().len as usize,
+                h.queue_pairs[0].rx.bytes_read
+            );
 
             // We set this back to a manageable size, for the following test.
-            h.rx.bytes_read = 1234;
+            h.queue_pairs[0].rx.bytes_read = 1234;
         }
 
         {
@@ -1643,7 +2444,7 @@ mod tests {
             rxq.avail.ring[1].set(1);
             rxq.dtable[1].set(daddr + 0x1000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
 
-            h.rx.queue_evt.write(1).unwrap();
+            h.queue_pairs[0].rx.queue_evt.write(1).unwrap();
             h.interrupt_evt.write(1).unwrap();
 
             // rx_count increments 1 from rx_single_frame() and 1 from process_rx()
@@ -1662,7 +2463,7 @@ mod tests {
             let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
             let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
 
-            check_metric_after_block!(&METRICS.net.rx_fails, 1, h.process_rx());
+            check_metric_after_block!(&METRICS.net.rx_fails, 1, h.process_rx(0));
         }
     }
 
@@ -1692,7 +2493,7 @@ mod tests {
             // following TX procedure should fail because of bandwidth rate limiting
             {
                 // trigger the TX handler
-                h.tx.queue_evt.write(1).unwrap();
+                h.queue_pairs[0].tx.queue_evt.write(1).unwrap();
                 h.handle_event(TX_QUEUE_EVENT, EPOLLIN).unwrap();
 
                 // assert that limiter is blocked
@@ -1731,7 +2532,7 @@ mod tests {
             h.set_rx_rate_limiter(rl);
 
             // set up RX
-            assert!(!h.rx.deferred_frame);
+            assert!(!h.queue_pairs[0].rx.deferred_frame);
             rxq.avail.idx.set(1);
             rxq.avail.ring[0].set(0);
             rxq.dtable[0].set(daddr, 0x1000, VIRTQ_DESC_F_WRITE, 0);
@@ -1745,7 +2546,7 @@ mod tests {
 
                 // assert that limiter is blocked
                 assert!(h.get_rx_rate_limiter().is_blocked());
-                assert!(h.rx.deferred_frame);
+                assert!(h.queue_pairs[0].rx.deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert_eq!(h.interrupt_evt.read().unwrap(), 1);
                 // make sure the data is still queued for processing
@@ -1800,7 +2601,7 @@ mod tests {
             // following TX procedure should fail because of ops rate limiting
             {
                 // trigger the TX handler
-                h.tx.queue_evt.write(1).unwrap();
+                h.queue_pairs[0].tx.queue_evt.write(1).unwrap();
                 h.handle_event(TX_QUEUE_EVENT, EPOLLIN).unwrap();
 
                 // assert that limiter is blocked
@@ -1834,7 +2635,7 @@ mod tests {
             h.set_rx_rate_limiter(rl);
 
             // set up RX
-            assert!(!h.rx.deferred_frame);
+            assert!(!h.queue_pairs[0].rx.deferred_frame);
             rxq.avail.idx.set(1);
             rxq.avail.ring[0].set(0);
             rxq.dtable[0].set(daddr, 0x1000, VIRTQ_DESC_F_WRITE, 0);
@@ -1848,7 +2649,7 @@ mod tests {
 
                 // assert that limiter is blocked
                 assert!(h.get_rx_rate_limiter().is_blocked());
-                assert!(h.rx.deferred_frame);
+                assert!(h.queue_pairs[0].rx.deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert_eq!(h.interrupt_evt.read().unwrap(), 1);
                 // make sure the data is still queued for processing
@@ -1915,4 +2716,219 @@ mod tests {
         compare_buckets(h.get_tx_rate_limiter().bandwidth().unwrap(), &tx_bytes);
         compare_buckets(h.get_tx_rate_limiter().ops().unwrap(), &tx_ops);
     }
+
+    #[test]
+    fn test_multi_queue() {
+        assert_eq!(net_events_count(1), 7);
+        assert_eq!(net_events_count(MAX_QUEUE_PAIRS), 52);
+
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender.clone());
+        match Net::new_with_taps(
+            vec![],
+            None,
+            epoll_config,
+            None,
+            None,
+            false,
+            Arc::default(),
+        ) {
+            Err(Error::InvalidQueuePairs) => (),
+            _ => panic!("Expected Error::InvalidQueuePairs"),
+        }
+
+        let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+        let taps = Tap::open_multi_queue(&format!("mqnet{}", next_tap), 2).unwrap();
+        taps[0].enable().unwrap();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+        let mut n = Net::new_with_taps(taps, None, epoll_config, None, None, false, Arc::default())
+            .unwrap();
+
+        // Two queue pairs and the control queue.
+        assert_eq!(
+            n.queue_max_sizes(),
+            &[
+                QUEUE_SIZE,
+                QUEUE_SIZE,
+                QUEUE_SIZE,
+                QUEUE_SIZE,
+                CTRL_QUEUE_SIZE
+            ]
+        );
+        let features = n.avail_features();
+        assert_ne!(features & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(features & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+        assert_eq!(features & (1 << VIRTIO_NET_F_MAC), 0);
+        assert!(n.guest_mac().is_none());
+        let mut max_queue_pairs = [0u8; 2];
+        n.read_config(
+            CONFIG_MAX_QUEUE_PAIRS_OFFSET as u64,
+            &mut max_queue_pairs[..],
+        );
+        assert_eq!(u16::from_le_bytes(max_queue_pairs), 2);
+
+        // Activate the device, and retrieve its handler.
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vqs: Vec<VirtQueue> = (0..5)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let queues = vqs.iter().map(VirtQueue::create_queue).collect();
+        let queue_evts = (0..5).map(|_| EventFd::new().unwrap()).collect();
+        let status = Arc::new(AtomicUsize::new(0));
+        n.activate(
+            mem.clone(),
+            EventFd::new().unwrap(),
+            status,
+            queues,
+            queue_evts,
+        )
+        .unwrap();
+        let mut handler = receiver.recv().unwrap();
+        let h = handler
+            .as_mut_any()
+            .downcast_mut::<NetEpollHandler>()
+            .unwrap();
+        assert_eq!(h.queue_pairs.len(), 2);
+        assert!(h.queue_pairs[0].enabled);
+        assert!(!h.queue_pairs[1].enabled);
+
+        // Places a control command with the given class, command and data, and returns the
+        // status written by the device.
+        let ctrl_vq = &vqs[4];
+        let ctrl_command = |h: &mut NetEpollHandler, class: u32, cmd: u32, data: &[u8]| -> u8 {
+            let idx = ctrl_vq.avail.idx.get();
+            mem.write_slice_at_addr(&[class as u8, cmd as u8], GuestAddress(0x8000))
+                .unwrap();
+            mem.write_slice_at_addr(data, GuestAddress(0x8100)).unwrap();
+            ctrl_vq.dtable[0].set(0x8000, 2, VIRTQ_DESC_F_NEXT, 1);
+            ctrl_vq.dtable[1].set(0x8100, data.len() as u32, VIRTQ_DESC_F_NEXT, 2);
+            ctrl_vq.dtable[2].set(0x8200, 1, VIRTQ_DESC_F_WRITE, 0);
+            ctrl_vq.avail.ring[usize::from(idx % 16)].set(0);
+            ctrl_vq.avail.idx.set(idx.wrapping_add(1));
+
+            h.ctrl.as_ref().unwrap().queue_evt.write(1).unwrap();
+            h.handle_event(CTRL_QUEUE_EVENT, EPOLLIN).unwrap();
+            assert_eq!(ctrl_vq.used.idx.get(), idx.wrapping_add(1));
+            assert_eq!(ctrl_vq.used.ring[usize::from(idx % 16)].get().len, 1);
+            mem.read_obj_from_addr(GuestAddress(0x8200)).unwrap()
+        };
+
+        // The driver enables the second queue pair.
+        assert_eq!(
+            ctrl_command(
+                h,
+                VIRTIO_NET_CTRL_MQ,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+                &2u16.to_le_bytes()
+            ),
+            VIRTIO_NET_OK as u8
+        );
+        assert_eq!(n.save_state().active_queue_pairs, 2);
+        // The device has only two queue pairs.
+        assert_eq!(
+            ctrl_command(
+                h,
+                VIRTIO_NET_CTRL_MQ,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+                &3u16.to_le_bytes()
+            ),
+            VIRTIO_NET_ERR as u8
+        );
+        assert_eq!(
+            ctrl_command(
+                h,
+                VIRTIO_NET_CTRL_MQ,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+                &0u16.to_le_bytes()
+            ),
+            VIRTIO_NET_ERR as u8
+        );
+        // Other commands are not supported.
+        assert_eq!(
+            ctrl_command(h, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC, &[1]),
+            VIRTIO_NET_ERR as u8
+        );
+        assert!(h.queue_pairs[1].enabled);
+
+        // Frames sent through the second queue pair go through its own queues.
+        let txq = &vqs[3];
+        txq.avail.idx.set(1);
+        txq.avail.ring[0].set(0);
+        txq.dtable[0].set(0x9000, 0x100, 0, 0);
+        h.queue_pairs[1].tx.queue_evt.write(1).unwrap();
+        h.handle_event(TX_QUEUE_EVENT + QUEUE_PAIR_EVENTS_COUNT, EPOLLIN)
+            .unwrap();
+        assert_eq!(txq.used.idx.get(), 1);
+        assert_eq!(vqs[1].used.idx.get(), 0);
+
+        // Buffers made available on the second receive queue are filled.
+        let rxq = &vqs[2];
+        rxq.avail.idx.set(1);
+        rxq.avail.ring[0].set(0);
+        rxq.dtable[0].set(0xa000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        h.queue_pairs[1].rx.queue_evt.write(1).unwrap();
+        h.handle_event(RX_QUEUE_EVENT + QUEUE_PAIR_EVENTS_COUNT, EPOLLIN)
+            .unwrap();
+        assert!(h.queue_pairs[1].rx_tap_listening);
+        h.handle_event(RX_TAP_EVENT + QUEUE_PAIR_EVENTS_COUNT, EPOLLIN)
+            .unwrap();
+        // The #cfg(test) enabled version of read_tap always returns 1234 bytes.
+        assert_eq!(rxq.used.ring[0].get().len, 1234);
+        assert_eq!(vqs[0].used.idx.get(), 0);
+
+        // The driver goes back to a single queue pair.
+        assert_eq!(
+            ctrl_command(
+                h,
+                VIRTIO_NET_CTRL_MQ,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+                &1u16.to_le_bytes()
+            ),
+            VIRTIO_NET_OK as u8
+        );
+        assert!(!h.queue_pairs[1].enabled);
+        assert!(!h.queue_pairs[1].rx_tap_listening);
+        assert_eq!(n.save_state().active_queue_pairs, 1);
+
+        // There are no events past the ones of the second queue pair.
+        match h.handle_event(RX_TAP_EVENT + 2 * QUEUE_PAIR_EVENTS_COUNT, EPOLLIN) {
+            Err(DeviceError::UnknownEvent { .. }) => (),
+            _ => panic!("invalid"),
+        }
+
+        // A restored device enables the queue pairs that the driver had enabled.
+        let mut state = n.save_state();
+        state.active_queue_pairs = 2;
+        let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+        let taps = Tap::open_multi_queue(&format!("mqnet{}", next_tap), 2).unwrap();
+        taps[0].enable().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+        let mut restored =
+            Net::new_with_taps(taps, None, epoll_config, None, None, false, Arc::default())
+                .unwrap();
+        restored.restore_state(&state);
+        let queues = vqs.iter().map(VirtQueue::create_queue).collect();
+        let queue_evts = (0..5).map(|_| EventFd::new().unwrap()).collect();
+        restored
+            .activate(
+                mem.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                queues,
+                queue_evts,
+            )
+            .unwrap();
+        let mut handler = receiver.recv().unwrap();
+        let h = handler
+            .as_mut_any()
+            .downcast_mut::<NetEpollHandler>()
+            .unwrap();
+        assert!(h.queue_pairs[0].enabled);
+        assert!(h.queue_pairs[1].enabled);
+        assert_eq!(restored.save_state().active_queue_pairs, 2);
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
 }
@@ -0,0 +1,549 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements copy on write overlays, which keep the sectors written to a disk in a separate
+//! file so that its base image can be shared, read only, between many block devices.
+//!
+//! An overlay file starts with a header, followed by a bitmap holding one bit per sector of the
+//! disk, which is set once the sector was written. The written sectors are stored in a sparse
+//! data region, at the same offset as in the disk.
+
+use std::cmp;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::result;
+
+use super::block::{DiskFile, DiskImage, SECTOR_SIZE};
+use super::qcow::add_signed;
+
+const OVERLAY_MAGIC: &[u8; 8] = b"FCOVRLAY";
+const OVERLAY_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 40;
+// The bitmap and the data region start on a page boundary.
+const OVERLAY_ALIGNMENT: u64 = 4096;
+// Size of the chunks copied when merging the overlay into a new image.
+const MERGE_CHUNK_SIZE: usize = 1 << 20;
+
+/// Errors associated with overlay files.
+#[derive(Debug)]
+pub enum OverlayError {
+    /// The header of the overlay is invalid.
+    InvalidHeader(&'static str),
+    /// Reading or writing the overlay failed.
+    Io(io::Error),
+    /// The overlay was created for a disk of a different size.
+    SizeMismatch(u64, u64),
+}
+
+impl Display for OverlayError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::OverlayError::*;
+        match *self {
+            InvalidHeader(reason) => write!(f, "Invalid overlay header: {}", reason),
+            Io(ref e) => write!(f, "{}", e),
+            SizeMismatch(overlay_size, base_size) => write!(
+                f,
+                "The overlay covers {} bytes, but the base image holds {} bytes.",
+                overlay_size, base_size
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for OverlayError {
+    fn from(e: io::Error) -> Self {
+        OverlayError::Io(e)
+    }
+}
+
+impl From<OverlayError> for io::Error {
+    fn from(e: OverlayError) -> Self {
+        match e {
+            OverlayError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, OverlayError>;
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A disk made of a read only base image and of an overlay file holding the sectors written
+/// since the overlay was created.
+///
+/// The bitmap is kept in memory and only persisted when the disk is synced, after the data, so
+/// that a host crash never leaves a sector marked as written without its contents.
+pub struct OverlayFile {
+    base: DiskImage,
+    overlay: File,
+    size: u64,
+    bitmap: Vec<u8>,
+    bitmap_offset: u64,
+    // Range of the bitmap bytes changed since it was last persisted.
+    dirty_bitmap: Option<(usize, usize)>,
+    data_offset: u64,
+    current_offset: u64,
+}
+
+impl OverlayFile {
+    /// Opens the overlay held by `overlay`, on top of `base`. An empty overlay file is
+    /// initialized, in which case it must be writable.
+    pub fn open(mut base: DiskImage, overlay: File) -> Result<OverlayFile> {
+        let size = base.seek(SeekFrom::End(0))?;
+        let bitmap_len = align_up(size, SECTOR_SIZE) / SECTOR_SIZE;
+        let bitmap_len = align_up(bitmap_len, 8) / 8;
+
+        let (bitmap_offset, data_offset) = if overlay.metadata()?.len() == 0 {
+            let bitmap_offset = OVERLAY_ALIGNMENT;
+            let data_offset = align_up(bitmap_offset + bitmap_len, OVERLAY_ALIGNMENT);
+            let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+            header.extend_from_slice(OVERLAY_MAGIC);
+            header.extend_from_slice(&OVERLAY_VERSION.to_le_bytes());
+            header.extend_from_slice(&[0u8; 4]);
+            header.extend_from_slice(&size.to_le_bytes());
+            header.extend_from_slice(&bitmap_offset.to_le_bytes());
+            header.extend_from_slice(&data_offset.to_le_bytes());
+            overlay.write_all_at(&header, 0)?;
+            overlay.set_len(data_offset + size)?;
+            (bitmap_offset, data_offset)
+        } else {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            overlay.read_exact_at(&mut header, 0)?;
+            if &header[..8] != OVERLAY_MAGIC {
+                return Err(OverlayError::InvalidHeader("not an overlay file"));
+            }
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&header[8..12]);
+            if u32::from_le_bytes(version) != OVERLAY_VERSION {
+                return Err(OverlayError::InvalidHeader("unsupported version"));
+            }
+            let overlay_size = read_u64(&header, 16);
+            if overlay_size != size {
+                return Err(OverlayError::SizeMismatch(overlay_size, size));
+            }
+            let bitmap_offset = read_u64(&header, 24);
+            let data_offset = read_u64(&header, 32);
+            if bitmap_offset < HEADER_SIZE
+                || bitmap_offset
+                    .checked_add(bitmap_len)
+                    .map_or(true, |end| end > data_offset)
+            {
+                return Err(OverlayError::InvalidHeader("invalid bitmap offset"));
+            }
+            (bitmap_offset, data_offset)
+        };
+
+        let mut bitmap = vec![0u8; bitmap_len as usize];
+        overlay.read_exact_at(&mut bitmap, bitmap_offset)?;
+        Ok(OverlayFile {
+            base,
+            overlay,
+            size,
+            bitmap,
+            bitmap_offset,
+            dirty_bitmap: None,
+            data_offset,
+            current_offset: 0,
+        })
+    }
+
+    /// Returns the overlay file.
+    pub fn file(&self) -> &File {
+        &self.overlay
+    }
+
+    fn is_written(&self, sector: u64) -> bool {
+        self.bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0
+    }
+
+    // Marks `count` sectors, starting at `sector`, as written. The bitmap is persisted on the
+    // next sync.
+    fn mark_written(&mut self, sector: u64, count: u64) {
+        if (sector..sector + count).all(|s| self.is_written(s)) {
+            return;
+        }
+        for s in sector..sector + count {
+            self.bitmap[(s / 8) as usize] |= 1 << (s % 8);
+        }
+        let first = (sector / 8) as usize;
+        let last = ((sector + count - 1) / 8) as usize;
+        self.dirty_bitmap = Some(match self.dirty_bitmap {
+            Some((start, end)) => (cmp::min(start, first), cmp::max(end, last)),
+            None => (first, last),
+        });
+    }
+
+    // Writes the changed part of the bitmap to the overlay, once the data is synced.
+    fn persist_bitmap(&mut self) -> io::Result<()> {
+        if let Some((first, last)) = self.dirty_bitmap {
+            self.overlay.sync_data()?;
+            self.overlay.write_all_at(
+                &self.bitmap[first..=last],
+                self.bitmap_offset + first as u64,
+            )?;
+            self.dirty_bitmap = None;
+        }
+        Ok(())
+    }
+
+    // Reads `buf` at `offset`, from the overlay for the written sectors and from the base image
+    // for the others.
+    fn read_at(&mut self, mut offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset + buf.len() as u64;
+        while offset < end {
+            let written = self.is_written(offset / SECTOR_SIZE);
+            // Extend the range while the following sectors are in the same state.
+            let mut run_end = align_up(offset + 1, SECTOR_SIZE);
+            while run_end < end && self.is_written(run_end / SECTOR_SIZE) == written {
+                run_end += SECTOR_SIZE;
+            }
+            let run_end = cmp::min(run_end, end);
+            let start = (offset + buf.len() as u64 - end) as usize;
+            let chunk = &mut buf[start..start + (run_end - offset) as usize];
+            if written {
+                self.overlay
+                    .read_exact_at(chunk, self.data_offset + offset)?;
+            } else {
+                self.base.seek(SeekFrom::Start(offset))?;
+                self.base.read_exact(chunk)?;
+            }
+            offset = run_end;
+        }
+        Ok(())
+    }
+
+    // Writes `buf` at `offset` to the overlay. Partially written sectors are completed with their
+    // current contents.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let end = offset + buf.len() as u64;
+        let start = offset - offset % SECTOR_SIZE;
+        let stop = cmp::min(align_up(end, SECTOR_SIZE), self.size);
+        if start == offset && stop == end {
+            self.overlay.write_all_at(buf, self.data_offset + offset)?;
+        } else {
+            let mut data = vec![0u8; (stop - start) as usize];
+            if offset > start {
+                let head = cmp::min(SECTOR_SIZE, stop - start) as usize;
+                self.read_at(start, &mut data[..head])?;
+            }
+            if end < stop {
+                let last = (end - 1) / SECTOR_SIZE * SECTOR_SIZE;
+                self.read_at(last, &mut data[(last - start) as usize..])?;
+            }
+            let from = (offset - start) as usize;
+            data[from..from + buf.len()].copy_from_slice(buf);
+            self.overlay.write_all_at(&data, self.data_offset + start)?;
+        }
+        self.mark_written(
+            start / SECTOR_SIZE,
+            align_up(stop, SECTOR_SIZE) / SECTOR_SIZE - start / SECTOR_SIZE,
+        );
+        Ok(())
+    }
+
+    // Makes `len` bytes at `offset` read as zeroes, hiding the base image. The whole sectors of
+    // the range are deallocated from the overlay if `unmap` is set.
+    fn zero_range(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= self.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let start = cmp::min(align_up(offset, SECTOR_SIZE), end);
+        let stop = cmp::max(end - end % SECTOR_SIZE, start);
+        if start > offset {
+            self.write_at(offset, &vec![0u8; (start - offset) as usize])?;
+        }
+        if stop > start {
+            let data_start = self.data_offset + start;
+            let result = if unmap {
+                self.overlay.punch_hole(data_start, stop - start)
+            } else {
+                self.overlay.write_zeroes(data_start, stop - start)
+            };
+            match result {
+                // The range is then zeroed without being deallocated.
+                Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    self.overlay.write_zeroes(data_start, stop - start)?
+                }
+                result => result?,
+            }
+            self.mark_written(start / SECTOR_SIZE, (stop - start) / SECTOR_SIZE);
+        }
+        if end > stop {
+            self.write_at(stop, &vec![0u8; (end - stop) as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of the disk to `dest`, which then holds a standalone raw image.
+    /// The ranges of zeroes are left as holes.
+    pub fn merge_into(&mut self, dest: &mut File) -> io::Result<()> {
+        let mut buf = vec![0u8; MERGE_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < self.size {
+            let count = cmp::min(self.size - offset, MERGE_CHUNK_SIZE as u64) as usize;
+            self.read_at(offset, &mut buf[..count])?;
+            if buf[..count].iter().any(|&b| b != 0) {
+                dest.write_all_at(&buf[..count], offset)?;
+            }
+            offset += count as u64;
+        }
+        dest.set_len(self.size)?;
+        dest.sync_all()
+    }
+}
+
+impl Read for OverlayFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.size.saturating_sub(self.current_offset),
+        ) as usize;
+        let offset = self.current_offset;
+        self.read_at(offset, &mut buf[..len])?;
+        self.current_offset += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for OverlayFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.size.saturating_sub(self.current_offset),
+        ) as usize;
+        if len > 0 {
+            let offset = self.current_offset;
+            self.write_at(offset, &buf[..len])?;
+        }
+        self.current_offset += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.flush()
+    }
+}
+
+impl Seek for OverlayFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(self.size, offset),
+            SeekFrom::Current(offset) => add_signed(self.current_offset, offset),
+        };
+        match offset {
+            Some(offset) => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl DiskFile for OverlayFile {
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.zero_range(offset, len, true)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.zero_range(offset, len, false)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        // The base image is only read.
+        self.persist_bitmap()?;
+        self.overlay.sync_data()
+    }
+}
+
+impl Drop for OverlayFile {
+    fn drop(&mut self) {
+        // The sectors written since the last sync are kept if the overlay is closed cleanly.
+        if let Err(e) = self.persist_bitmap() {
+            error!("Failed to persist the overlay bitmap: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    fn base_image(len: usize) -> File {
+        let mut base = tempfile().unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i / 512 + 1) as u8).collect();
+        base.write_all(&data).unwrap();
+        base
+    }
+
+    fn read_disk(disk: &mut OverlayFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_disk(disk: &mut OverlayFile, offset: u64, buf: &[u8]) {
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.write_all(buf).unwrap();
+    }
+
+    #[test]
+    fn test_read_write() {
+        let base = base_image(0x4000);
+        let overlay = tempfile().unwrap();
+        let mut disk = OverlayFile::open(
+            DiskImage::Raw(base.try_clone().unwrap()),
+            overlay.try_clone().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), 0x4000);
+        assert_eq!(read_disk(&mut disk, 0x200, 0x200), vec![2u8; 0x200]);
+
+        // A write of whole sectors, and an unaligned one spanning two sectors.
+        write_disk(&mut disk, 0x400, &[0xaa; 0x400]);
+        write_disk(&mut disk, 0x1100, &[0xbb; 0x300]);
+        let data = read_disk(&mut disk, 0, 0x1600);
+        assert!(data[..0x200].iter().all(|&b| b == 1));
+        assert!(data[0x400..0x800].iter().all(|&b| b == 0xaa));
+        assert!(data[0x800..0xa00].iter().all(|&b| b == 5));
+        assert!(data[0x1000..0x1100].iter().all(|&b| b == 9));
+        assert!(data[0x1100..0x1400].iter().all(|&b| b == 0xbb));
+        assert!(data[0x1400..0x1600].iter().all(|&b| b == 11));
+
+        // A write within a single sector.
+        write_disk(&mut disk, 0x2080, &[0xee; 0x100]);
+        let data = read_disk(&mut disk, 0x2000, 0x200);
+        assert!(data[..0x80].iter().all(|&b| b == 17));
+        assert!(data[0x80..0x180].iter().all(|&b| b == 0xee));
+        assert!(data[0x180..].iter().all(|&b| b == 17));
+        for sector in 0..32 {
+            assert_eq!(
+                disk.is_written(sector),
+                sector == 2 || sector == 3 || sector == 8 || sector == 9 || sector == 16
+            );
+        }
+
+        // The base image is left untouched, and the bitmap persists.
+        let mut buf = [0u8; 0x200];
+        base.read_exact_at(&mut buf, 0x400).unwrap();
+        assert_eq!(&buf[..], &[3u8; 0x200][..]);
+        drop(disk);
+        let mut disk = OverlayFile::open(DiskImage::Raw(base), overlay).unwrap();
+        assert!(disk.is_written(2));
+        assert!(!disk.is_written(4));
+        assert_eq!(read_disk(&mut disk, 0x1100, 0x300), vec![0xbb; 0x300]);
+
+        // Accesses stop at the end of the disk.
+        disk.seek(SeekFrom::Start(0x3f00)).unwrap();
+        assert_eq!(disk.write(&[1u8; 0x200]).unwrap(), 0x100);
+        assert_eq!(disk.read(&mut [0u8; 0x200]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_open() {
+        let overlay = tempfile().unwrap();
+        OverlayFile::open(
+            DiskImage::Raw(base_image(0x4000)),
+            overlay.try_clone().unwrap(),
+        )
+        .unwrap();
+
+        // The base image must keep its size.
+        match OverlayFile::open(
+            DiskImage::Raw(base_image(0x2000)),
+            overlay.try_clone().unwrap(),
+        ) {
+            Err(OverlayError::SizeMismatch(0x4000, 0x2000)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        overlay.write_all_at(b"NOTVRLAY", 0).unwrap();
+        match OverlayFile::open(DiskImage::Raw(base_image(0x4000)), overlay) {
+            Err(OverlayError::InvalidHeader(_)) => (),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_zero_range() {
+        let mut disk =
+            OverlayFile::open(DiskImage::Raw(base_image(0x4000)), tempfile().unwrap()).unwrap();
+        write_disk(&mut disk, 0x800, &[0xcc; 0x200]);
+
+        disk.punch_hole(0x700, 0x400).unwrap();
+        disk.write_zeroes(0x2000, 0x200).unwrap();
+        let data = read_disk(&mut disk, 0, 0x4000);
+        assert!(data[..0x600].iter().all(|&b| b != 0));
+        assert!(data[0x600..0x700].iter().all(|&b| b == 4));
+        assert!(data[0x700..0xb00].iter().all(|&b| b == 0));
+        assert!(data[0xb00..0xc00].iter().all(|&b| b == 6));
+        assert!(data[0x2000..0x2200].iter().all(|&b| b == 0));
+        assert!(data[0x2200..0x2400].iter().all(|&b| b == 18));
+
+        assert!(disk.punch_hole(0x3e00, 0x400).is_err());
+    }
+
+    #[test]
+    fn test_sync() {
+        let overlay = tempfile().unwrap();
+        let mut disk = OverlayFile::open(
+            DiskImage::Raw(base_image(0x4000)),
+            overlay.try_clone().unwrap(),
+        )
+        .unwrap();
+        let read_bitmap = || {
+            let mut bitmap = [0u8; 4];
+            overlay
+                .read_exact_at(&mut bitmap, OVERLAY_ALIGNMENT)
+                .unwrap();
+            bitmap
+        };
+
+        // The bitmap is only persisted when the disk is synced.
+        write_disk(&mut disk, 0x400, &[0xaa; 0x400]);
+        disk.write_zeroes(0x3000, 0x200).unwrap();
+        assert_eq!(read_bitmap(), [0, 0, 0, 0]);
+        disk.sync().unwrap();
+        assert_eq!(read_bitmap(), [0x0c, 0, 0, 0x01]);
+
+        // And when the overlay is closed.
+        write_disk(&mut disk, 0x1000, &[0xbb; 0x200]);
+        assert_eq!(read_bitmap(), [0x0c, 0, 0, 0x01]);
+        drop(disk);
+        assert_eq!(read_bitmap(), [0x0c, 0x01, 0, 0x01]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut disk =
+            OverlayFile::open(DiskImage::Raw(base_image(0x4000)), tempfile().unwrap()).unwrap();
+        write_disk(&mut disk, 0x200, &[0xdd; 0x200]);
+        disk.write_zeroes(0x3000, 0x1000).unwrap();
+
+        let mut dest = tempfile().unwrap();
+        disk.merge_into(&mut dest).unwrap();
+        let mut merged = Vec::new();
+        dest.seek(SeekFrom::Start(0)).unwrap();
+        dest.read_to_end(&mut merged).unwrap();
+        assert_eq!(merged, read_disk(&mut disk, 0, 0x4000));
+        assert_eq!(&merged[0x200..0x400], &[0xdd; 0x200][..]);
+        assert_eq!(&merged[0x400..0x600], &[3u8; 0x200][..]);
+    }
+}
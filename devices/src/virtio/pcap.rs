@@ -0,0 +1,151 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the captures of the frames seen by network devices, in the pcap format read by
+//! tcpdump and wireshark.
+//!
+//! A capture starts with the 24 bytes pcap header, written in little endian, and is followed by
+//! one record per frame: a 16 bytes header holding the time at which the frame was seen and its
+//! length, then the first bytes of the frame, up to the snap length.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// The size of the header starting a capture.
+pub const PCAP_HEADER_SIZE: u64 = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Writes the frames seen by a network device to a pcap file.
+pub struct PacketCapture {
+    writer: BufWriter<File>,
+    snap_len: u32,
+    max_size: u64,
+    size: u64,
+}
+
+impl PacketCapture {
+    /// Starts a capture in `file`, which should be empty. At most `snap_len` bytes of each frame
+    /// are recorded, and the capture stops growing before exceeding `max_size` bytes, header
+    /// included.
+    pub fn new(file: File, snap_len: u32, max_size: u64) -> io::Result<PacketCapture> {
+        let mut writer = BufWriter::new(file);
+        let mut header = [0u8; PCAP_HEADER_SIZE as usize];
+        LittleEndian::write_u32(&mut header[0..4], PCAP_MAGIC);
+        LittleEndian::write_u16(&mut header[4..6], PCAP_VERSION_MAJOR);
+        LittleEndian::write_u16(&mut header[6..8], PCAP_VERSION_MINOR);
+        // The timestamps are in UTC, and their accuracy is unknown.
+        LittleEndian::write_i32(&mut header[8..12], 0);
+        LittleEndian::write_u32(&mut header[12..16], 0);
+        LittleEndian::write_u32(&mut header[16..20], snap_len);
+        LittleEndian::write_u32(&mut header[20..24], LINKTYPE_ETHERNET);
+        writer.write_all(&header)?;
+        writer.flush()?;
+        Ok(PacketCapture {
+            writer,
+            snap_len,
+            max_size,
+            size: PCAP_HEADER_SIZE,
+        })
+    }
+
+    /// Appends the Ethernet frame `frame` to the capture. Returns false, without writing
+    /// anything, when the record would not fit within the size cap. Records are buffered until
+    /// the capture is flushed.
+    pub fn record(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let captured_len = frame.len().min(self.snap_len as usize);
+        let record_size = (RECORD_HEADER_SIZE + captured_len) as u64;
+        if self.size + record_size > self.max_size {
+            return Ok(false);
+        }
+
+        // A clock set before the epoch is not worth failing the capture for.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], timestamp.as_secs() as u32);
+        LittleEndian::write_u32(&mut header[4..8], timestamp.subsec_micros());
+        LittleEndian::write_u32(&mut header[8..12], captured_len as u32);
+        LittleEndian::write_u32(&mut header[12..16], frame.len() as u32);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&frame[..captured_len])?;
+        self.size += record_size;
+        Ok(true)
+    }
+
+    /// Writes the buffered records to the capture file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let capture_file = NamedTempFile::new().unwrap();
+        let header_size = PCAP_HEADER_SIZE as usize;
+        // Room for the header, a full 16 bytes frame and a 8 bytes snap of a larger frame.
+        let max_size = PCAP_HEADER_SIZE + 2 * RECORD_HEADER_SIZE as u64 + 24;
+        let mut capture = PacketCapture::new(capture_file.reopen().unwrap(), 16, max_size).unwrap();
+
+        let data = fs::read(capture_file.path()).unwrap();
+        assert_eq!(data.len(), header_size);
+        assert_eq!(LittleEndian::read_u32(&data[0..4]), PCAP_MAGIC);
+        assert_eq!(LittleEndian::read_u16(&data[4..6]), 2);
+        assert_eq!(LittleEndian::read_u16(&data[6..8]), 4);
+        assert_eq!(LittleEndian::read_u32(&data[16..20]), 16);
+        assert_eq!(LittleEndian::read_u32(&data[20..24]), LINKTYPE_ETHERNET);
+
+        let frame: Vec<u8> = (0..32).collect();
+        assert!(capture.record(&frame[..16]).unwrap());
+        // Only the snap length of the frame fits, and the frame is still too large for the cap.
+        assert!(!capture.record(&frame).unwrap());
+        assert!(capture.record(&frame[..8]).unwrap());
+        // The capture is full.
+        assert!(!capture.record(&[]).unwrap());
+        capture.flush().unwrap();
+
+        let data = fs::read(capture_file.path()).unwrap();
+        assert_eq!(data.len() as u64, max_size);
+        let record = &data[header_size..];
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), 16);
+        assert_eq!(LittleEndian::read_u32(&record[12..16]), 16);
+        assert_eq!(&record[16..32], &frame[..16]);
+        let record = &record[32..];
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), 8);
+        assert_eq!(LittleEndian::read_u32(&record[12..16]), 8);
+        assert_eq!(&record[16..], &frame[..8]);
+    }
+
+    #[test]
+    fn test_snap_len() {
+        let capture_file = NamedTempFile::new().unwrap();
+        let mut capture = PacketCapture::new(capture_file.reopen().unwrap(), 16, 1 << 20).unwrap();
+        let frame: Vec<u8> = (0..32).collect();
+        assert!(capture.record(&frame).unwrap());
+        capture.flush().unwrap();
+
+        let data = fs::read(capture_file.path()).unwrap();
+        let record = &data[PCAP_HEADER_SIZE as usize..];
+        assert_eq!(record.len(), RECORD_HEADER_SIZE + 16);
+        // The record holds the original length of the frame.
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), 16);
+        assert_eq!(LittleEndian::read_u32(&record[12..16]), 32);
+        assert_eq!(&record[16..], &frame[..16]);
+    }
+}
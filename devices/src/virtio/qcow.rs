@@ -0,0 +1,1431 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the qcow2 disk image format, as described in docs/interop/qcow2.txt in the QEMU
+//! code. Compressed clusters, encryption, external data files and writing to images with internal
+//! snapshots are not supported.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use super::block::{DiskImage, ImageFormat};
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_SIZE: u32 = 72;
+const V3_HEADER_SIZE: u32 = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+// Refcounts narrower than a byte are not supported.
+const MIN_REFCOUNT_ORDER: u32 = 3;
+const MAX_REFCOUNT_ORDER: u32 = 6;
+const MAX_BACKING_FILE_NAME_SIZE: u32 = 1023;
+// Backing files nested deeper than this are rejected, which also stops loops.
+const MAX_BACKING_DEPTH: u32 = 16;
+// The L1 and refcount tables are kept in memory, so their size is bounded.
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+
+// Types of the header extensions.
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Incompatible feature bits, which make the image unusable unless they are understood.
+const INCOMPATIBLE_DIRTY: u64 = 1;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+
+// The cluster is only referenced once, so it can be written in place.
+const ENTRY_COPIED: u64 = 1 << 63;
+const L2_ENTRY_COMPRESSED: u64 = 1 << 62;
+// The cluster reads as zeroes, whether it is allocated or not. Only valid in version 3.
+const L2_ENTRY_ZERO: u64 = 1;
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = !0x1ff;
+
+// Number of L2 tables cached in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+/// Errors associated with qcow2 disk images.
+#[derive(Debug)]
+pub enum QcowError {
+    /// The backing file of the image cannot be opened.
+    BackingFile(PathBuf, io::Error),
+    /// The backing files of the image are nested too deep.
+    BackingChainTooDeep,
+    /// The backing file of the image has an unknown format.
+    BackingFormat(String),
+    /// The cluster is compressed.
+    CompressedCluster,
+    /// The image is encrypted.
+    Encrypted,
+    /// The image has internal snapshots, so it cannot be written.
+    HasSnapshots,
+    /// The header of the image is invalid.
+    InvalidHeader(&'static str),
+    /// The tables of the image are invalid.
+    InvalidMetadata(&'static str),
+    /// Reading or writing the image failed.
+    Io(io::Error),
+    /// The file is not a qcow2 image.
+    NotQcow,
+    /// The refcount width of the image is not supported.
+    UnsupportedRefcountOrder(u32),
+    /// The image uses incompatible features that are not supported.
+    UnsupportedFeatures(u64),
+    /// The version of the image is not supported.
+    UnsupportedVersion(u32),
+    /// The virtual size of the image is too large.
+    VirtualSizeTooLarge(u64),
+}
+
+impl Display for QcowError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::QcowError::*;
+        match *self {
+            BackingFile(ref path, ref e) => {
+                write!(f, "Cannot open the backing file {:?}: {}", path, e)
+            }
+            BackingChainTooDeep => write!(f, "The backing files are nested too deep."),
+            BackingFormat(ref format) => write!(f, "Unknown backing file format: {}", format),
+            CompressedCluster => write!(f, "Compressed clusters are not supported."),
+            Encrypted => write!(f, "Encrypted images are not supported."),
+            HasSnapshots => write!(f, "Images with internal snapshots cannot be written."),
+            InvalidHeader(reason) => write!(f, "Invalid qcow2 header: {}", reason),
+            InvalidMetadata(reason) => write!(f, "Invalid qcow2 metadata: {}", reason),
+            Io(ref e) => write!(f, "{}", e),
+            NotQcow => write!(f, "The file is not a qcow2 image."),
+            UnsupportedRefcountOrder(order) => {
+                write!(f, "Unsupported refcount order: {}", order)
+            }
+            UnsupportedFeatures(features) => {
+                write!(f, "Unsupported incompatible features: {:#x}", features)
+            }
+            UnsupportedVersion(version) => write!(f, "Unsupported qcow2 version: {}", version),
+            VirtualSizeTooLarge(size) => write!(f, "The virtual size {} is too large.", size),
+        }
+    }
+}
+
+impl From<io::Error> for QcowError {
+    fn from(e: io::Error) -> Self {
+        QcowError::Io(e)
+    }
+}
+
+impl From<QcowError> for io::Error {
+    fn from(e: QcowError) -> Self {
+        match e {
+            QcowError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, QcowError>;
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf.chunks(8).map(|entry| read_u64(entry, 0)).collect())
+}
+
+fn write_table(file: &File, offset: u64, table: &[u64]) -> io::Result<()> {
+    let buf: Vec<u8> = table
+        .iter()
+        .flat_map(|entry| entry.to_be_bytes().to_vec())
+        .collect();
+    file.write_all_at(&buf, offset)
+}
+
+fn write_u64_at(file: &File, offset: u64, value: u64) -> io::Result<()> {
+    file.write_all_at(&value.to_be_bytes(), offset)
+}
+
+pub(crate) fn add_signed(value: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        value.checked_add(offset as u64)
+    } else {
+        value.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+fn div_round_up(value: u64, divisor: u64) -> u64 {
+    (value + divisor - 1) / divisor
+}
+
+// The header of a qcow2 image, which sits at the beginning of its first cluster.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct QcowHeader {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    snapshots_offset: u64,
+    incompatible_features: u64,
+    compatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+    // Not part of the header itself: the name of the backing file and the format found in the
+    // header extensions.
+    backing_file: Option<String>,
+    backing_format: Option<String>,
+}
+
+impl QcowHeader {
+    fn read(file: &File) -> Result<QcowHeader> {
+        let mut buf = [0u8; V3_HEADER_SIZE as usize];
+        let len = file.metadata()?.len();
+        if len < u64::from(V2_HEADER_SIZE) {
+            return Err(QcowError::NotQcow);
+        }
+        let header_size = cmp::min(len, u64::from(V3_HEADER_SIZE)) as usize;
+        file.read_exact_at(&mut buf[..header_size], 0)?;
+        if read_u32(&buf, 0) != QCOW_MAGIC {
+            return Err(QcowError::NotQcow);
+        }
+
+        let mut header = QcowHeader {
+            version: read_u32(&buf, 4),
+            backing_file_offset: read_u64(&buf, 8),
+            backing_file_size: read_u32(&buf, 16),
+            cluster_bits: read_u32(&buf, 20),
+            size: read_u64(&buf, 24),
+            crypt_method: read_u32(&buf, 32),
+            l1_size: read_u32(&buf, 36),
+            l1_table_offset: read_u64(&buf, 40),
+            refcount_table_offset: read_u64(&buf, 48),
+            refcount_table_clusters: read_u32(&buf, 56),
+            nb_snapshots: read_u32(&buf, 60),
+            snapshots_offset: read_u64(&buf, 64),
+            ..Default::default()
+        };
+        match header.version {
+            2 => {
+                header.refcount_order = DEFAULT_REFCOUNT_ORDER;
+                header.header_length = V2_HEADER_SIZE;
+            }
+            3 => {
+                if header_size < V3_HEADER_SIZE as usize {
+                    return Err(QcowError::InvalidHeader("the header is truncated"));
+                }
+                header.incompatible_features = read_u64(&buf, 72);
+                header.compatible_features = read_u64(&buf, 80);
+                header.autoclear_features = read_u64(&buf, 88);
+                header.refcount_order = read_u32(&buf, 96);
+                header.header_length = read_u32(&buf, 100);
+            }
+            version => return Err(QcowError::UnsupportedVersion(version)),
+        }
+
+        if header.cluster_bits < MIN_CLUSTER_BITS || header.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(QcowError::InvalidHeader("invalid cluster size"));
+        }
+        let cluster_size = 1u64 << header.cluster_bits;
+        if header.header_length < V2_HEADER_SIZE
+            || u64::from(header.header_length) > cluster_size
+            || header.header_length % 8 != 0
+        {
+            return Err(QcowError::InvalidHeader("invalid header length"));
+        }
+        if header.crypt_method != 0 {
+            return Err(QcowError::Encrypted);
+        }
+        // Dirty images need their refcounts rebuilt, which is left to qemu-img check.
+        let unsupported = header.incompatible_features;
+        if unsupported != 0 {
+            if unsupported & INCOMPATIBLE_CORRUPT != 0 {
+                return Err(QcowError::InvalidHeader("the image is marked corrupt"));
+            }
+            if unsupported & INCOMPATIBLE_DIRTY != 0 {
+                return Err(QcowError::InvalidHeader("the image is marked dirty"));
+            }
+            return Err(QcowError::UnsupportedFeatures(unsupported));
+        }
+        if header.refcount_order < MIN_REFCOUNT_ORDER || header.refcount_order > MAX_REFCOUNT_ORDER
+        {
+            return Err(QcowError::UnsupportedRefcountOrder(header.refcount_order));
+        }
+
+        header.read_extensions(file, cluster_size)?;
+        header.read_backing_file(file, cluster_size)?;
+        Ok(header)
+    }
+
+    fn read_extensions(&mut self, file: &File, cluster_size: u64) -> Result<()> {
+        // The extensions end before the backing file name, or at the end of the first cluster.
+        let end = if self.backing_file_offset != 0 {
+            cmp::min(self.backing_file_offset, cluster_size)
+        } else {
+            cluster_size
+        };
+        let mut offset = u64::from(self.header_length);
+        while offset + 8 <= end {
+            let mut ext = [0u8; 8];
+            file.read_exact_at(&mut ext, offset)?;
+            let ext_type = read_u32(&ext, 0);
+            let ext_len = u64::from(read_u32(&ext, 4));
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+            offset += 8;
+            if offset + ext_len > end {
+                return Err(QcowError::InvalidHeader("invalid header extension"));
+            }
+            if ext_type == HEADER_EXT_BACKING_FORMAT {
+                let mut format = vec![0u8; ext_len as usize];
+                file.read_exact_at(&mut format, offset)?;
+                self.backing_format = Some(String::from_utf8_lossy(&format).into_owned());
+            }
+            offset += div_round_up(ext_len, 8) * 8;
+        }
+        Ok(())
+    }
+
+    fn read_backing_file(&mut self, file: &File, cluster_size: u64) -> Result<()> {
+        if self.backing_file_offset == 0 {
+            return Ok(());
+        }
+        if self.backing_file_size > MAX_BACKING_FILE_NAME_SIZE
+            || self.backing_file_offset + u64::from(self.backing_file_size) > cluster_size
+        {
+            return Err(QcowError::InvalidHeader("invalid backing file name"));
+        }
+        let mut name = vec![0u8; self.backing_file_size as usize];
+        file.read_exact_at(&mut name, self.backing_file_offset)?;
+        self.backing_file = Some(String::from_utf8_lossy(&name).into_owned());
+        Ok(())
+    }
+
+    // Returns the fields of the header, as laid out at the beginning of the image.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(V3_HEADER_SIZE as usize);
+        buf.extend_from_slice(&QCOW_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        buf.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.crypt_method.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buf.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        buf.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+        if self.version >= 3 {
+            buf.extend_from_slice(&self.incompatible_features.to_be_bytes());
+            buf.extend_from_slice(&self.compatible_features.to_be_bytes());
+            buf.extend_from_slice(&self.autoclear_features.to_be_bytes());
+            buf.extend_from_slice(&self.refcount_order.to_be_bytes());
+            buf.extend_from_slice(&self.header_length.to_be_bytes());
+        }
+        buf
+    }
+}
+
+/// A disk image in the qcow2 format, which reads and writes the virtual disk it holds.
+///
+/// The metadata is written through as soon as it changes, so the image is consistent whenever
+/// no operation is in progress. New clusters are allocated at the end of the file, unless some
+/// were freed since the image was opened.
+pub struct QcowFile {
+    file: File,
+    header: QcowHeader,
+    backing: Option<Box<DiskImage>>,
+    // The virtual size of the backing file.
+    backing_size: u64,
+    cluster_size: u64,
+    // Number of entries of an L2 table.
+    l2_entries: u64,
+    // Width of a refcount, in bytes.
+    refcount_bytes: u64,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    // The L2 tables read last, indexed by their offset in the file.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    // The end of the clusters in use.
+    end_offset: u64,
+    // Clusters freed since the image was opened, allocated before growing the file.
+    free_clusters: Vec<u64>,
+    current_offset: u64,
+}
+
+impl QcowFile {
+    /// Opens the qcow2 image held by `file`, found at `path`. Relative paths of backing files
+    /// are resolved from the directory of the image.
+    pub fn from(file: File, path: &Path) -> Result<QcowFile> {
+        QcowFile::open(file, path, 0)
+    }
+
+    /// Creates a qcow2 image of `virtual_size` bytes in the empty `file`, found at `path`.
+    /// Clusters that were never written are read from `backing_file`, if any, whose path is
+    /// written in the image as is.
+    pub fn create(
+        file: File,
+        path: &Path,
+        virtual_size: u64,
+        backing_file: Option<(&Path, ImageFormat)>,
+    ) -> Result<QcowFile> {
+        QcowFile::create_with_cluster_bits(
+            file,
+            path,
+            virtual_size,
+            backing_file,
+            DEFAULT_CLUSTER_BITS,
+        )
+    }
+
+    fn create_with_cluster_bits(
+        file: File,
+        path: &Path,
+        virtual_size: u64,
+        backing_file: Option<(&Path, ImageFormat)>,
+        cluster_bits: u32,
+    ) -> Result<QcowFile> {
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_size = div_round_up(virtual_size, cluster_size * l2_entries);
+        if l1_size * 8 > MAX_TABLE_SIZE {
+            return Err(QcowError::VirtualSizeTooLarge(virtual_size));
+        }
+        let l1_clusters = cmp::max(div_round_up(l1_size * 8, cluster_size), 1);
+        // The header, the L1 table, the refcount table and a refcount block.
+        let clusters = 1 + l1_clusters + 2;
+        let refcount_bytes = 1u64 << (DEFAULT_REFCOUNT_ORDER - 3);
+        if clusters > cluster_size / refcount_bytes {
+            return Err(QcowError::VirtualSizeTooLarge(virtual_size));
+        }
+
+        let mut header = QcowHeader {
+            version: 3,
+            cluster_bits,
+            size: virtual_size,
+            l1_size: l1_size as u32,
+            l1_table_offset: cluster_size,
+            refcount_table_offset: (1 + l1_clusters) * cluster_size,
+            refcount_table_clusters: 1,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: V3_HEADER_SIZE,
+            ..Default::default()
+        };
+        let mut first_cluster = Vec::new();
+        if let Some((backing_path, backing_format)) = backing_file {
+            let name = backing_path.to_string_lossy().into_owned();
+            let format: &[u8] = match backing_format {
+                ImageFormat::Raw => b"raw",
+                ImageFormat::Qcow2 => b"qcow2",
+            };
+            // The backing format extension, padded to 8 bytes, then the end of the extensions.
+            let mut extensions = Vec::new();
+            extensions.extend_from_slice(&HEADER_EXT_BACKING_FORMAT.to_be_bytes());
+            extensions.extend_from_slice(&(format.len() as u32).to_be_bytes());
+            extensions.extend_from_slice(format);
+            extensions.resize(div_round_up(extensions.len() as u64, 8) as usize * 8, 0);
+            extensions.extend_from_slice(&[0u8; 8]);
+
+            header.backing_file_offset = u64::from(V3_HEADER_SIZE) + extensions.len() as u64;
+            header.backing_file_size = name.len() as u32;
+            if name.len() as u32 > MAX_BACKING_FILE_NAME_SIZE
+                || header.backing_file_offset + name.len() as u64 > cluster_size
+            {
+                return Err(QcowError::InvalidHeader("invalid backing file name"));
+            }
+            first_cluster = header.to_bytes();
+            first_cluster.extend_from_slice(&extensions);
+            first_cluster.extend_from_slice(name.as_bytes());
+        } else {
+            first_cluster.extend_from_slice(&header.to_bytes());
+            first_cluster.extend_from_slice(&[0u8; 8]);
+        }
+
+        file.set_len(clusters * cluster_size)?;
+        file.write_all_at(&first_cluster, 0)?;
+        let refcount_block_offset = header.refcount_table_offset + cluster_size;
+        write_u64_at(&file, header.refcount_table_offset, refcount_block_offset)?;
+        let refcounts: Vec<u8> = (0..clusters)
+            .flat_map(|_| 1u16.to_be_bytes().to_vec())
+            .collect();
+        file.write_all_at(&refcounts, refcount_block_offset)?;
+
+        QcowFile::from(file, path)
+    }
+
+    fn open(file: File, path: &Path, depth: u32) -> Result<QcowFile> {
+        let header = QcowHeader::read(&file)?;
+        let cluster_size = 1u64 << header.cluster_bits;
+        let l2_entries = cluster_size / 8;
+
+        let l1_entries_needed = div_round_up(header.size, cluster_size * l2_entries);
+        if u64::from(header.l1_size) < l1_entries_needed {
+            return Err(QcowError::InvalidMetadata("the L1 table is too small"));
+        }
+        if u64::from(header.l1_size) * 8 > MAX_TABLE_SIZE
+            || u64::from(header.refcount_table_clusters) * cluster_size > MAX_TABLE_SIZE
+        {
+            return Err(QcowError::InvalidMetadata("the tables are too large"));
+        }
+        if header.l1_table_offset % cluster_size != 0
+            || header.refcount_table_offset % cluster_size != 0
+        {
+            return Err(QcowError::InvalidMetadata("the tables are not aligned"));
+        }
+        let l1_table = read_table(&file, header.l1_table_offset, u64::from(header.l1_size))?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            u64::from(header.refcount_table_clusters) * cluster_size / 8,
+        )?;
+
+        let mut backing = match header.backing_file {
+            Some(ref name) => Some(Box::new(QcowFile::open_backing_file(
+                path,
+                name,
+                header.backing_format.as_ref(),
+                depth,
+            )?)),
+            None => None,
+        };
+        let backing_size = match backing {
+            Some(ref mut backing) => backing.seek(SeekFrom::End(0))?,
+            None => 0,
+        };
+
+        let end_offset = div_round_up(file.metadata()?.len(), cluster_size) * cluster_size;
+        let qcow = QcowFile {
+            file,
+            backing,
+            backing_size,
+            cluster_size,
+            l2_entries,
+            refcount_bytes: 1u64 << (header.refcount_order - 3),
+            l1_table,
+            refcount_table,
+            l2_cache: HashMap::new(),
+            end_offset,
+            free_clusters: Vec::new(),
+            current_offset: 0,
+            header,
+        };
+        qcow.check_tables()?;
+        Ok(qcow)
+    }
+
+    // Checks that the tables do not overlap the header or each other, and that the L2 tables
+    // and refcount blocks they point to are valid metadata clusters.
+    fn check_tables(&self) -> Result<()> {
+        let l1_offset = self.header.l1_table_offset;
+        let l1_end = l1_offset
+            + div_round_up(u64::from(self.header.l1_size) * 8, self.cluster_size)
+                * self.cluster_size;
+        let refcount_offset = self.header.refcount_table_offset;
+        let refcount_end =
+            refcount_offset + u64::from(self.header.refcount_table_clusters) * self.cluster_size;
+        if l1_offset < self.cluster_size
+            || refcount_offset < self.cluster_size
+            || (l1_offset < refcount_end && refcount_offset < l1_end)
+        {
+            return Err(QcowError::InvalidMetadata("the tables overlap"));
+        }
+        for entry in self.l1_table.iter() {
+            let l2_offset = entry & ENTRY_OFFSET_MASK;
+            if l2_offset != 0 {
+                self.check_metadata_cluster(l2_offset, "an L2 table is misplaced")?;
+            }
+        }
+        for entry in self.refcount_table.iter() {
+            let block = entry & REFCOUNT_TABLE_OFFSET_MASK;
+            if block != 0 {
+                self.check_metadata_cluster(block, "a refcount block is misplaced")?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that the cluster at `offset` is within the file and does not overlap the header,
+    // the L1 table or the refcount table, so that writing to it cannot corrupt them.
+    fn check_metadata_cluster(&self, offset: u64, reason: &'static str) -> Result<()> {
+        let overlaps =
+            |start: u64, len: u64| offset < start + len && start < offset + self.cluster_size;
+        if offset % self.cluster_size != 0
+            || offset < self.cluster_size
+            || offset >= self.end_offset
+            || overlaps(
+                self.header.l1_table_offset,
+                u64::from(self.header.l1_size) * 8,
+            )
+            || overlaps(
+                self.header.refcount_table_offset,
+                u64::from(self.header.refcount_table_clusters) * self.cluster_size,
+            )
+        {
+            return Err(QcowError::InvalidMetadata(reason));
+        }
+        Ok(())
+    }
+
+    fn open_backing_file(
+        path: &Path,
+        name: &str,
+        format: Option<&String>,
+        depth: u32,
+    ) -> Result<DiskImage> {
+        if depth >= MAX_BACKING_DEPTH {
+            return Err(QcowError::BackingChainTooDeep);
+        }
+        let backing_path = match path.parent() {
+            Some(dir) if !Path::new(name).is_absolute() => dir.join(name),
+            _ => PathBuf::from(name),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&backing_path)
+            .map_err(|e| QcowError::BackingFile(backing_path.clone(), e))?;
+
+        let is_qcow = match format.map(|format| format.as_str()) {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => return Err(QcowError::BackingFormat(format.to_string())),
+            // Without a format in the header, the backing file is probed.
+            None => {
+                let mut magic = [0u8; 4];
+                file.read_exact_at(&mut magic, 0).is_ok() && u32::from_be_bytes(magic) == QCOW_MAGIC
+            }
+        };
+        if is_qcow {
+            Ok(DiskImage::Qcow2(Box::new(QcowFile::open(
+                file,
+                &backing_path,
+                depth + 1,
+            )?)))
+        } else {
+            Ok(DiskImage::Raw(file))
+        }
+    }
+
+    /// Returns the size of the virtual disk.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Returns the file holding the image.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn write_header(&self) -> io::Result<()> {
+        self.file.write_all_at(&self.header.to_bytes(), 0)
+    }
+
+    // Checks that the image can be written, which clears the autoclear features the first time.
+    fn prepare_write(&mut self) -> Result<()> {
+        if self.header.nb_snapshots != 0 {
+            return Err(QcowError::HasSnapshots);
+        }
+        if self.header.autoclear_features != 0 {
+            self.header.autoclear_features = 0;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            self.check_metadata_cluster(l2_offset, "an L2 table is misplaced")?;
+            if self.l2_cache.len() >= L2_CACHE_SIZE {
+                self.l2_cache.clear();
+            }
+            let table = read_table(&self.file, l2_offset, self.l2_entries)?;
+            self.l2_cache.insert(l2_offset, table);
+        }
+        // The table was inserted above if it was missing.
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    // Returns the indices, in the L1 and L2 tables, of the entry describing the guest cluster
+    // found at `offset`.
+    fn table_indices(&self, offset: u64) -> (usize, usize) {
+        let cluster = offset / self.cluster_size;
+        (
+            (cluster / self.l2_entries) as usize,
+            (cluster % self.l2_entries) as usize,
+        )
+    }
+
+    fn l2_entry(&mut self, offset: u64) -> Result<u64> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+        let l2_offset = self.l1_table[l1_index] & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(l2_offset)?[l2_index])
+    }
+
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> Result<()> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+        let mut l2_offset = self.l1_table[l1_index] & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size as usize], l2_offset)?;
+            self.l1_table[l1_index] = l2_offset | ENTRY_COPIED;
+            write_u64_at(
+                &self.file,
+                self.header.l1_table_offset + l1_index as u64 * 8,
+                l2_offset | ENTRY_COPIED,
+            )?;
+        }
+        self.l2_table(l2_offset)?[l2_index] = entry;
+        // The tables may have moved since the L2 table was cached.
+        self.check_metadata_cluster(l2_offset, "an L2 table is misplaced")?;
+        write_u64_at(&self.file, l2_offset + l2_index as u64 * 8, entry)?;
+        Ok(())
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, refcount: u64) -> Result<()> {
+        let entries_per_block = self.cluster_size / self.refcount_bytes;
+        let cluster = host_offset / self.cluster_size;
+        let table_index = (cluster / entries_per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index)?;
+        }
+
+        let mut block = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block == 0 {
+            block = self.end_offset;
+            self.end_offset += self.cluster_size;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size as usize], block)?;
+            self.refcount_table[table_index] = block;
+            write_u64_at(
+                &self.file,
+                self.header.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            // The new block is counted as well, possibly by itself.
+            self.set_refcount(block, 1)?;
+        }
+
+        self.check_metadata_cluster(block, "a refcount block is misplaced")?;
+        let width = self.refcount_bytes as usize;
+        self.file.write_all_at(
+            &refcount.to_be_bytes()[8 - width..],
+            block + (cluster % entries_per_block) * self.refcount_bytes,
+        )?;
+        Ok(())
+    }
+
+    // Moves the refcount table to a larger one at the end of the file, so that it holds the
+    // entry at `min_index`.
+    fn grow_refcount_table(&mut self, min_index: usize) -> Result<()> {
+        let entries_per_cluster = (self.cluster_size / 8) as usize;
+        let entries = cmp::max(min_index + 1, self.refcount_table.len() * 2);
+        let clusters = div_round_up(entries as u64, entries_per_cluster as u64);
+        if clusters * self.cluster_size > MAX_TABLE_SIZE {
+            return Err(QcowError::InvalidMetadata(
+                "the refcount table is too large",
+            ));
+        }
+
+        let mut table = self.refcount_table.clone();
+        table.resize(clusters as usize * entries_per_cluster, 0);
+        let table_offset = self.end_offset;
+        self.end_offset += clusters * self.cluster_size;
+        write_table(&self.file, table_offset, &table)?;
+
+        let old_offset = self.header.refcount_table_offset;
+        let old_clusters = u64::from(self.header.refcount_table_clusters);
+        self.header.refcount_table_offset = table_offset;
+        self.header.refcount_table_clusters = clusters as u32;
+        self.write_header()?;
+        self.refcount_table = table;
+
+        for i in 0..clusters {
+            self.set_refcount(table_offset + i * self.cluster_size, 1)?;
+        }
+        for i in 0..old_clusters {
+            self.free_cluster(old_offset + i * self.cluster_size)?;
+        }
+        Ok(())
+    }
+
+    fn allocate_cluster(&mut self) -> Result<u64> {
+        let offset = match self.free_clusters.pop() {
+            Some(offset) => offset,
+            None => {
+                let offset = self.end_offset;
+                self.end_offset += self.cluster_size;
+                offset
+            }
+        };
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn free_cluster(&mut self, offset: u64) -> Result<()> {
+        self.set_refcount(offset, 0)?;
+        self.free_clusters.push(offset);
+        // The host space is released if the file system supports it.
+        // This is safe because fallocate only changes the allocation of the file.
+        unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                self.cluster_size as libc::off_t,
+            )
+        };
+        Ok(())
+    }
+
+    // Reads the backing file at `offset`, or zeroes past its end.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let count = cmp::min(self.backing_size.saturating_sub(offset), buf.len() as u64) as usize;
+        if count > 0 {
+            // The count is only positive if there is a backing file.
+            let backing = self.backing.as_mut().unwrap();
+            backing.seek(SeekFrom::Start(offset))?;
+            backing.read_exact(&mut buf[..count])?;
+        }
+        for byte in buf[count..].iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    // Reads `buf` at `offset`, within a single cluster.
+    fn read_cluster(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let entry = self.l2_entry(offset)?;
+        let host_offset = entry & ENTRY_OFFSET_MASK;
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            Err(QcowError::CompressedCluster)
+        } else if entry & L2_ENTRY_ZERO != 0 && self.header.version >= 3 {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            Ok(())
+        } else if host_offset == 0 {
+            self.read_backing(offset, buf)
+        } else {
+            self.file
+                .read_exact_at(buf, host_offset + offset % self.cluster_size)?;
+            Ok(())
+        }
+    }
+
+    // Writes `buf` at `offset`, within a single cluster.
+    fn write_cluster(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.prepare_write()?;
+        let entry = self.l2_entry(offset)?;
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            return Err(QcowError::CompressedCluster);
+        }
+        let is_zero = entry & L2_ENTRY_ZERO != 0 && self.header.version >= 3;
+        let mut host_offset = entry & ENTRY_OFFSET_MASK;
+        if host_offset != 0 {
+            self.check_metadata_cluster(host_offset, "a data cluster is misplaced")?;
+        }
+        if host_offset != 0 && !is_zero {
+            self.file
+                .write_all_at(buf, host_offset + offset % self.cluster_size)?;
+            return Ok(());
+        }
+
+        // The cluster is allocated, unless it only needs its zero flag cleared. The rest of it
+        // keeps reading as before: from the backing file, or as zeroes.
+        if host_offset == 0 {
+            host_offset = self.allocate_cluster()?;
+        }
+        let cluster_offset = offset - offset % self.cluster_size;
+        if buf.len() as u64 == self.cluster_size {
+            self.file.write_all_at(buf, host_offset)?;
+        } else {
+            let mut data = vec![0u8; self.cluster_size as usize];
+            if !is_zero {
+                self.read_backing(cluster_offset, &mut data)?;
+            }
+            let start = (offset % self.cluster_size) as usize;
+            data[start..start + buf.len()].copy_from_slice(buf);
+            self.file.write_all_at(&data, host_offset)?;
+        }
+        self.set_l2_entry(cluster_offset, host_offset | ENTRY_COPIED)
+    }
+
+    // Makes the whole cluster at `offset` read as zeroes. Its host cluster is freed if `unmap`
+    // is set.
+    fn zero_cluster(&mut self, offset: u64, unmap: bool) -> Result<()> {
+        let entry = self.l2_entry(offset)?;
+        let host_offset = entry & ENTRY_OFFSET_MASK;
+        let compressed = entry & L2_ENTRY_COMPRESSED != 0;
+
+        if self.header.version < 3 {
+            // Without the zero flag, only unallocated clusters of images without backing files
+            // read as zeroes.
+            if host_offset == 0 && self.backing.is_none() {
+                return Ok(());
+            }
+            return self.write_cluster(offset, &vec![0u8; self.cluster_size as usize]);
+        }
+
+        let new_entry = if !unmap && host_offset != 0 && !compressed {
+            host_offset | ENTRY_COPIED | L2_ENTRY_ZERO
+        } else if self.backing.is_none() {
+            0
+        } else {
+            L2_ENTRY_ZERO
+        };
+        if entry == new_entry {
+            return Ok(());
+        }
+        if host_offset != 0 && !compressed {
+            self.check_metadata_cluster(host_offset, "a data cluster is misplaced")?;
+        }
+        self.set_l2_entry(offset, new_entry)?;
+        // The host clusters of compressed clusters are not tracked, so they are leaked.
+        if host_offset != 0 && !compressed && new_entry & ENTRY_OFFSET_MASK == 0 {
+            self.free_cluster(host_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `len` bytes at `offset` read as zeroes. The clusters fully in the range are
+    /// deallocated if `unmap` is set.
+    pub fn zero_range(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.prepare_write()?;
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= self.header.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let mut offset = offset;
+        while offset < end {
+            let count = cmp::min(end - offset, self.cluster_size - offset % self.cluster_size);
+            if count == self.cluster_size {
+                self.zero_cluster(offset, unmap)?;
+            } else {
+                self.write_cluster(offset, &vec![0u8; count as usize])?;
+            }
+            offset += count;
+        }
+        Ok(())
+    }
+}
+
+impl Read for QcowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.header.size.saturating_sub(self.current_offset),
+        ) as usize;
+        let mut done = 0;
+        while done < len {
+            let offset = self.current_offset + done as u64;
+            let count = cmp::min(
+                len - done,
+                (self.cluster_size - offset % self.cluster_size) as usize,
+            );
+            self.read_cluster(offset, &mut buf[done..done + count])?;
+            done += count;
+        }
+        self.current_offset += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for QcowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(
+            buf.len() as u64,
+            self.header.size.saturating_sub(self.current_offset),
+        ) as usize;
+        let mut done = 0;
+        while done < len {
+            let offset = self.current_offset + done as u64;
+            let count = cmp::min(
+                len - done,
+                (self.cluster_size - offset % self.cluster_size) as usize,
+            );
+            self.write_cluster(offset, &buf[done..done + count])?;
+            done += count;
+        }
+        self.current_offset += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The metadata is written through, so there is nothing cached to write out.
+        self.file.flush()
+    }
+}
+
+impl Seek for QcowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(self.header.size, offset),
+            SeekFrom::Current(offset) => add_signed(self.current_offset, offset),
+        };
+        match offset {
+            Some(offset) => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    fn create_file(dir: &TempDir, name: &str) -> (File, PathBuf) {
+        let path = dir.path().join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (file, path)
+    }
+
+    fn reopen(qcow: QcowFile, path: &Path) -> QcowFile {
+        drop(qcow);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        QcowFile::from(file, path).unwrap()
+    }
+
+    fn read_at(qcow: &mut QcowFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        qcow.seek(SeekFrom::Start(offset)).unwrap();
+        qcow.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(qcow: &mut QcowFile, offset: u64, buf: &[u8]) {
+        qcow.seek(SeekFrom::Start(offset)).unwrap();
+        qcow.write_all(buf).unwrap();
+    }
+
+    fn refcount(qcow: &QcowFile, host_offset: u64) -> u64 {
+        let entries_per_block = qcow.cluster_size / qcow.refcount_bytes;
+        let cluster = host_offset / qcow.cluster_size;
+        let block = match qcow
+            .refcount_table
+            .get((cluster / entries_per_block) as usize)
+        {
+            Some(entry) => entry & REFCOUNT_TABLE_OFFSET_MASK,
+            None => return 0,
+        };
+        if block == 0 {
+            return 0;
+        }
+        let mut bytes = [0u8; 8];
+        let width = qcow.refcount_bytes as usize;
+        qcow.file
+            .read_exact_at(
+                &mut bytes[8 - width..],
+                block + (cluster % entries_per_block) * qcow.refcount_bytes,
+            )
+            .unwrap();
+        u64::from_be_bytes(bytes)
+    }
+
+    // Checks that the refcount of every cluster matches the number of references to it.
+    fn check_refcounts(qcow: &mut QcowFile) {
+        let cluster_size = qcow.cluster_size;
+        let mut references: HashMap<u64, u64> = HashMap::new();
+        let mut add = |offset: u64| *references.entry(offset).or_insert(0) += 1;
+
+        add(0);
+        for i in 0..div_round_up(u64::from(qcow.header.l1_size) * 8, cluster_size) {
+            add(qcow.header.l1_table_offset + i * cluster_size);
+        }
+        for i in 0..u64::from(qcow.header.refcount_table_clusters) {
+            add(qcow.header.refcount_table_offset + i * cluster_size);
+        }
+        for block in qcow.refcount_table.clone() {
+            if block != 0 {
+                add(block);
+            }
+        }
+        for l1_entry in qcow.l1_table.clone() {
+            let l2_offset = l1_entry & ENTRY_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            add(l2_offset);
+            for l2_entry in read_table(&qcow.file, l2_offset, qcow.l2_entries).unwrap() {
+                if l2_entry & ENTRY_OFFSET_MASK != 0 {
+                    add(l2_entry & ENTRY_OFFSET_MASK);
+                }
+            }
+        }
+
+        let mut offset = 0;
+        while offset < qcow.file.metadata().unwrap().len() {
+            assert_eq!(
+                refcount(qcow, offset),
+                *references.get(&offset).unwrap_or(&0),
+                "unexpected refcount of the cluster at {:#x}",
+                offset
+            );
+            offset += cluster_size;
+        }
+    }
+
+    #[test]
+    fn test_create() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        let qcow = QcowFile::create(file, &path, 100 * MIB, None).unwrap();
+        assert_eq!(qcow.virtual_size(), 100 * MIB);
+        assert_eq!(qcow.cluster_size, 1 << DEFAULT_CLUSTER_BITS);
+        assert_eq!(qcow.header.version, 3);
+        assert_eq!(qcow.header.l1_size, 1);
+        assert!(qcow.backing.is_none());
+
+        let mut qcow = reopen(qcow, &path);
+        assert_eq!(qcow.seek(SeekFrom::End(0)).unwrap(), 100 * MIB);
+        assert_eq!(read_at(&mut qcow, 0, 4096), vec![0u8; 4096]);
+        check_refcounts(&mut qcow);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        file.set_len(4096).unwrap();
+        match QcowFile::from(file, &path) {
+            Err(QcowError::NotQcow) => (),
+            _ => panic!("Test failed."),
+        }
+
+        let (file, path) = create_file(&dir, "disk2.qcow2");
+        let qcow = QcowFile::create(file, &path, MIB, None).unwrap();
+        let file = qcow.file.try_clone().unwrap();
+        drop(qcow);
+
+        // Unsupported version.
+        file.write_all_at(&4u32.to_be_bytes(), 4).unwrap();
+        match QcowFile::from(file.try_clone().unwrap(), &path) {
+            Err(QcowError::UnsupportedVersion(4)) => (),
+            _ => panic!("Test failed."),
+        }
+        file.write_all_at(&3u32.to_be_bytes(), 4).unwrap();
+
+        // Unknown incompatible feature.
+        file.write_all_at(&(1u64 << 10).to_be_bytes(), 72).unwrap();
+        match QcowFile::from(file.try_clone().unwrap(), &path) {
+            Err(QcowError::UnsupportedFeatures(features)) => assert_eq!(features, 1 << 10),
+            _ => panic!("Test failed."),
+        }
+        file.write_all_at(&0u64.to_be_bytes(), 72).unwrap();
+
+        // Encryption.
+        file.write_all_at(&1u32.to_be_bytes(), 32).unwrap();
+        match QcowFile::from(file.try_clone().unwrap(), &path) {
+            Err(QcowError::Encrypted) => (),
+            _ => panic!("Test failed."),
+        }
+        file.write_all_at(&0u32.to_be_bytes(), 32).unwrap();
+
+        assert!(QcowFile::from(file, &path).is_ok());
+    }
+
+    #[test]
+    fn test_misplaced_metadata() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        let mut qcow = QcowFile::create(file, &path, MIB, None).unwrap();
+        write_at(&mut qcow, 0, &[1u8; 512]);
+        let cluster_size = qcow.cluster_size;
+        let header = qcow.header.clone();
+        let l1_entry = qcow.l1_table[0];
+        let l2_offset = l1_entry & ENTRY_OFFSET_MASK;
+        let refcount_entry = qcow.refcount_table[0];
+        let file = qcow.file.try_clone().unwrap();
+        drop(qcow);
+        let file_size = file.metadata().unwrap().len();
+
+        let check_open = |reason: &str| match QcowFile::from(file.try_clone().unwrap(), &path) {
+            Err(QcowError::InvalidMetadata(r)) => assert_eq!(r, reason),
+            _ => panic!("Test failed."),
+        };
+
+        // The tables overlap the header or each other.
+        file.write_all_at(&0u64.to_be_bytes(), 40).unwrap();
+        check_open("the tables overlap");
+        file.write_all_at(&header.refcount_table_offset.to_be_bytes(), 40)
+            .unwrap();
+        check_open("the tables overlap");
+        file.write_all_at(&header.l1_table_offset.to_be_bytes(), 40)
+            .unwrap();
+
+        // An L2 table is past the end of the file, overlaps the header or the tables.
+        for offset in &[
+            file_size + cluster_size,
+            0x200,
+            header.l1_table_offset,
+            header.refcount_table_offset,
+        ] {
+            file.write_all_at(
+                &(offset | ENTRY_COPIED).to_be_bytes(),
+                header.l1_table_offset,
+            )
+            .unwrap();
+            check_open("an L2 table is misplaced");
+        }
+        file.write_all_at(&l1_entry.to_be_bytes(), header.l1_table_offset)
+            .unwrap();
+
+        // A refcount block overlaps the L1 table.
+        file.write_all_at(
+            &header.l1_table_offset.to_be_bytes(),
+            header.refcount_table_offset,
+        )
+        .unwrap();
+        check_open("a refcount block is misplaced");
+        file.write_all_at(&refcount_entry.to_be_bytes(), header.refcount_table_offset)
+            .unwrap();
+
+        // A data cluster pointing to the refcount table is not written.
+        let mut qcow = QcowFile::from(file.try_clone().unwrap(), &path).unwrap();
+        qcow.file
+            .write_all_at(
+                &(header.refcount_table_offset | ENTRY_COPIED).to_be_bytes(),
+                l2_offset,
+            )
+            .unwrap();
+        qcow.l2_cache.clear();
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        assert!(qcow.write(&[2u8; 512]).is_err());
+        assert!(qcow.zero_range(0, cluster_size, true).is_err());
+        assert_eq!(qcow.refcount_table[0], refcount_entry);
+        assert_eq!(
+            read_table(&qcow.file, header.refcount_table_offset, 1).unwrap(),
+            vec![refcount_entry]
+        );
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        let mut qcow = QcowFile::create(file, &path, 10 * MIB, None).unwrap();
+        let cluster_size = qcow.cluster_size;
+
+        // A write spanning two clusters, and a full cluster write.
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        write_at(&mut qcow, cluster_size - 4096, &data);
+        write_at(
+            &mut qcow,
+            4 * cluster_size,
+            &vec![0xab; cluster_size as usize],
+        );
+        assert_eq!(read_at(&mut qcow, cluster_size - 4096, 8192), data);
+        assert_eq!(read_at(&mut qcow, 0, 512), vec![0u8; 512]);
+        assert_eq!(
+            read_at(&mut qcow, 2 * cluster_size - 512, 512),
+            vec![0u8; 512]
+        );
+        check_refcounts(&mut qcow);
+
+        // Overwriting allocated clusters does not allocate more of them.
+        let len = qcow.file.metadata().unwrap().len();
+        write_at(&mut qcow, cluster_size, &data[..4096]);
+        assert_eq!(qcow.file.metadata().unwrap().len(), len);
+
+        let mut qcow = reopen(qcow, &path);
+        assert_eq!(read_at(&mut qcow, cluster_size - 4096, 4096), &data[..4096]);
+        assert_eq!(read_at(&mut qcow, cluster_size, 4096), &data[..4096]);
+        assert_eq!(
+            read_at(&mut qcow, 4 * cluster_size, cluster_size as usize),
+            vec![0xab; cluster_size as usize]
+        );
+        check_refcounts(&mut qcow);
+
+        // Accesses stop at the end of the virtual disk.
+        qcow.seek(SeekFrom::Start(10 * MIB - 512)).unwrap();
+        assert_eq!(qcow.write(&[1u8; 1024]).unwrap(), 512);
+        assert_eq!(qcow.read(&mut [0u8; 1024]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let (mut base, base_path) = create_file(&dir, "base.raw");
+        base.write_all(&vec![0x11; 3 * MIB as usize]).unwrap();
+
+        // A qcow2 image backed by the raw image, itself backing another qcow2 image.
+        let (file, middle_path) = create_file(&dir, "middle.qcow2");
+        let mut middle = QcowFile::create(
+            file,
+            &middle_path,
+            4 * MIB,
+            Some((Path::new("base.raw"), ImageFormat::Raw)),
+        )
+        .unwrap();
+        write_at(&mut middle, MIB, &[0x22; 512]);
+        drop(middle);
+        let (file, top_path) = create_file(&dir, "top.qcow2");
+        let mut top = QcowFile::create(
+            file,
+            &top_path,
+            4 * MIB,
+            Some((&middle_path, ImageFormat::Qcow2)),
+        )
+        .unwrap();
+        let cluster_size = top.cluster_size as usize;
+
+        assert_eq!(read_at(&mut top, 0, 512), vec![0x11; 512]);
+        assert_eq!(read_at(&mut top, MIB, 512), vec![0x22; 512]);
+        assert_eq!(read_at(&mut top, MIB + 512, 512), vec![0x11; 512]);
+        // Past the end of the raw image.
+        assert_eq!(read_at(&mut top, 3 * MIB, 512), vec![0u8; 512]);
+
+        // Partial writes keep the rest of the cluster from the backing files.
+        write_at(&mut top, MIB + 1024, &[0x33; 512]);
+        let cluster = read_at(&mut top, MIB, cluster_size);
+        assert_eq!(&cluster[..512], &[0x22; 512][..]);
+        assert_eq!(&cluster[512..1024], &[0x11; 512][..]);
+        assert_eq!(&cluster[1024..1536], &[0x33; 512][..]);
+        assert!(cluster[1536..].iter().all(|&b| b == 0x11));
+        check_refcounts(&mut top);
+
+        // The backing files are left untouched.
+        let mut data = vec![0u8; 1536];
+        base.read_exact_at(&mut data, MIB).unwrap();
+        assert_eq!(data, vec![0x11; 1536]);
+
+        // Zeroed clusters do not show the backing file anymore.
+        top.zero_range(0, cluster_size as u64, true).unwrap();
+        assert_eq!(read_at(&mut top, 0, 512), vec![0u8; 512]);
+        drop(top);
+
+        std::fs::remove_file(&base_path).unwrap();
+        let file = File::open(&top_path).unwrap();
+        match QcowFile::from(file, &top_path) {
+            Err(QcowError::BackingFile(path, _)) => assert_eq!(path, base_path),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_zero_range() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        let mut qcow = QcowFile::create(file, &path, 10 * MIB, None).unwrap();
+        let cluster_size = qcow.cluster_size;
+
+        write_at(&mut qcow, 0, &vec![0xff; 4 * cluster_size as usize]);
+        let len = qcow.file.metadata().unwrap().len();
+
+        // Deallocate the second cluster and half of the third one.
+        qcow.zero_range(cluster_size, cluster_size + cluster_size / 2, true)
+            .unwrap();
+        assert_eq!(qcow.l2_entry(cluster_size).unwrap(), 0);
+        assert_ne!(qcow.l2_entry(2 * cluster_size).unwrap(), 0);
+        let data = read_at(&mut qcow, 0, 4 * cluster_size as usize);
+        let (first, rest) = data.split_at(cluster_size as usize);
+        assert!(first.iter().all(|&b| b == 0xff));
+        let (zeroes, rest) = rest.split_at(cluster_size as usize * 3 / 2);
+        assert!(zeroes.iter().all(|&b| b == 0));
+        assert!(rest.iter().all(|&b| b == 0xff));
+        check_refcounts(&mut qcow);
+
+        // The freed cluster is allocated again.
+        write_at(&mut qcow, 8 * cluster_size, &[1u8; 512]);
+        assert_eq!(qcow.file.metadata().unwrap().len(), len);
+        check_refcounts(&mut qcow);
+
+        // Zeroing without unmapping keeps the cluster allocated.
+        qcow.zero_range(0, cluster_size, false).unwrap();
+        let entry = qcow.l2_entry(0).unwrap();
+        assert_ne!(entry & ENTRY_OFFSET_MASK, 0);
+        assert_ne!(entry & L2_ENTRY_ZERO, 0);
+        assert_eq!(read_at(&mut qcow, 0, 512), vec![0u8; 512]);
+        write_at(&mut qcow, 512, &[2u8; 512]);
+        assert_eq!(qcow.l2_entry(0).unwrap(), entry & !L2_ENTRY_ZERO);
+        assert_eq!(read_at(&mut qcow, 0, 512), vec![0u8; 512]);
+        assert_eq!(read_at(&mut qcow, 512, 512), vec![2u8; 512]);
+        check_refcounts(&mut qcow);
+
+        assert!(qcow.zero_range(10 * MIB - 512, 1024, true).is_err());
+    }
+
+    #[test]
+    fn test_refcount_table_growth() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        // With 512 byte clusters, a refcount block covers 256 clusters and the refcount table
+        // holds 64 blocks, so it has to grow past 8 MiB.
+        let mut qcow =
+            QcowFile::create_with_cluster_bits(file, &path, 16 * MIB, None, MIN_CLUSTER_BITS)
+                .unwrap();
+        let old_table_offset = qcow.header.refcount_table_offset;
+
+        let chunk: Vec<u8> = (0..64 * 1024).map(|i| (i % 253) as u8).collect();
+        let mut offset = 0;
+        while offset < 10 * MIB {
+            write_at(&mut qcow, offset, &chunk);
+            offset += chunk.len() as u64;
+        }
+        assert_ne!(qcow.header.refcount_table_offset, old_table_offset);
+        check_refcounts(&mut qcow);
+
+        let mut qcow = reopen(qcow, &path);
+        assert_eq!(read_at(&mut qcow, 9 * MIB, chunk.len()), chunk);
+        check_refcounts(&mut qcow);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let (file, path) = create_file(&dir, "disk.qcow2");
+        let mut qcow = QcowFile::create(file, &path, MIB, None).unwrap();
+        write_at(&mut qcow, 0, &[1u8; 512]);
+        qcow.file.write_all_at(&1u32.to_be_bytes(), 60).unwrap();
+
+        let mut qcow = reopen(qcow, &path);
+        assert_eq!(read_at(&mut qcow, 0, 512), vec![1u8; 512]);
+        qcow.seek(SeekFrom::Start(0)).unwrap();
+        assert!(qcow.write(&[2u8; 512]).is_err());
+    }
+}
@@ -9,6 +9,7 @@ use std::cmp::min;
 use std::num::Wrapping;
 use std::sync::atomic::{fence, Ordering};
 
+use fc_util::versioned::Versioned;
 use memory_model::{DataInit, GuestAddress, GuestMemory};
 
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
@@ -144,6 +145,32 @@ impl<'a> DescriptorChain<'a> {
     }
 }
 
+/// The serializable state of a virtio queue.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QueueState {
+    /// The maximal size in elements offered by the device.
+    pub max_size: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// Indicates if the queue is finished with configuration.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc_table: u64,
+    /// Guest physical address of the available ring.
+    pub avail_ring: u64,
+    /// Guest physical address of the used ring.
+    pub used_ring: u64,
+    /// Index of the next descriptor chain to be popped from the avail ring.
+    pub next_avail: u16,
+    /// Index of the next slot to be filled in the used ring.
+    pub next_used: u16,
+}
+
+impl Versioned for QueueState {
+    const NAME: &'static str = "Queue";
+    const VERSION: u16 = 1;
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -184,6 +211,50 @@ impl Queue {
         }
     }
 
+    /// Constructs a virtio queue from a previously saved state.
+    pub fn from_state(state: &QueueState) -> Queue {
+        Queue {
+            max_size: state.max_size,
+            size: state.size,
+            ready: state.ready,
+            desc_table: GuestAddress(state.desc_table as usize),
+            avail_ring: GuestAddress(state.avail_ring as usize),
+            used_ring: GuestAddress(state.used_ring as usize),
+            next_avail: Wrapping(state.next_avail),
+            next_used: Wrapping(state.next_used),
+        }
+    }
+
+    /// Returns the current state of the queue.
+    pub fn save_state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.offset() as u64,
+            avail_ring: self.avail_ring.offset() as u64,
+            used_ring: self.used_ring.offset() as u64,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+        }
+    }
+
+    /// Re-syncs the ring positions with the used ring index the device last published in guest
+    /// memory.
+    ///
+    /// The device backends complete every popped descriptor chain before returning to the event
+    /// loop, so once they are quiesced the next available and next used positions both equal the
+    /// used ring index.
+    pub fn sync_with_used_ring(&mut self, mem: &GuestMemory) {
+        if !self.ready || self.actual_size() == 0 {
+            return;
+        }
+        if let Ok(used_idx) = mem.read_obj_from_addr::<u16>(self.used_ring.unchecked_add(2)) {
+            self.next_avail = Wrapping(used_idx);
+            self.next_used = Wrapping(used_idx);
+        }
+    }
+
     pub fn get_max_size(&self) -> u16 {
         self.max_size
     }
@@ -801,4 +872,30 @@ pub mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_queue_state() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.add_used(m, 1, 0x1000);
+        q.add_used(m, 2, 0x1000);
+
+        let state = q.save_state();
+        assert_eq!(state.size, 16);
+        assert!(state.ready);
+        assert_eq!(state.next_used, 2);
+        assert_eq!(state.used_ring, vq.used_start().offset() as u64);
+
+        let restored = Queue::from_state(&state);
+        assert_eq!(restored.save_state(), state);
+
+        // A fresh copy of the queue catches up with the used ring published in guest memory.
+        let mut stale = vq.create_queue();
+        assert_eq!(stale.save_state().next_used, 0);
+        stale.sync_with_used_ring(m);
+        assert_eq!(stale.save_state().next_used, 2);
+        assert_eq!(stale.save_state().next_avail, 2);
+    }
 }
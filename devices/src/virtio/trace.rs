@@ -0,0 +1,412 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the traces of the requests handled by block devices, and their replay against a
+//! disk image.
+//!
+//! A trace starts with a 16 bytes header: the `FCBLKTRC` magic, the version of the format and
+//! the size of a record, as little endian 32 bit integers. It is followed by one 32 bytes record
+//! per completed request, in the order of completion. Discard and write zeroes requests get one
+//! record per segment. The data of the requests is not recorded, so replayed writes store a
+//! pattern identifying each sector instead.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, SeekFrom, Write};
+use std::time::Instant;
+
+use byteorder::{ByteOrder, LittleEndian};
+use virtio_gen::virtio_blk::*;
+
+use super::block::{DiskFile, SECTOR_SIZE};
+
+const TRACE_MAGIC: &[u8; 8] = b"FCBLKTRC";
+const TRACE_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 32;
+
+// The only flag of the segments of write zeroes requests.
+const WRITE_ZEROES_FLAG_UNMAP: u16 = 1;
+
+/// A request completed by a block device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceRecord {
+    /// The time at which the device started handling the request, in microseconds since the
+    /// start of the trace.
+    pub timestamp_us: u64,
+    /// The first sector accessed by the request.
+    pub sector: u64,
+    /// The number of bytes accessed by the request.
+    pub len: u32,
+    /// The time the device took to complete the request, in microseconds.
+    pub latency_us: u32,
+    /// The type of the request, as a `VIRTIO_BLK_T_*` value.
+    pub request_type: u32,
+    /// The flags of the discard or write zeroes segment.
+    pub flags: u16,
+    /// The queue holding the request.
+    pub queue_index: u8,
+    /// The status of the request, as a `VIRTIO_BLK_S_*` value.
+    pub status: u8,
+}
+
+impl TraceRecord {
+    fn write_to(&self, buf: &mut [u8]) {
+        LittleEndian::write_u64(&mut buf[0..8], self.timestamp_us);
+        LittleEndian::write_u64(&mut buf[8..16], self.sector);
+        LittleEndian::write_u32(&mut buf[16..20], self.len);
+        LittleEndian::write_u32(&mut buf[20..24], self.latency_us);
+        LittleEndian::write_u32(&mut buf[24..28], self.request_type);
+        LittleEndian::write_u16(&mut buf[28..30], self.flags);
+        buf[30] = self.queue_index;
+        buf[31] = self.status;
+    }
+
+    fn read_from(buf: &[u8]) -> TraceRecord {
+        TraceRecord {
+            timestamp_us: LittleEndian::read_u64(&buf[0..8]),
+            sector: LittleEndian::read_u64(&buf[8..16]),
+            len: LittleEndian::read_u32(&buf[16..20]),
+            latency_us: LittleEndian::read_u32(&buf[20..24]),
+            request_type: LittleEndian::read_u32(&buf[24..28]),
+            flags: LittleEndian::read_u16(&buf[28..30]),
+            queue_index: buf[30],
+            status: buf[31],
+        }
+    }
+}
+
+/// Writes the requests completed by a block device to a trace file.
+pub struct BlockTrace {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl BlockTrace {
+    /// Starts a trace in `file`, which should be empty.
+    pub fn new(file: File) -> io::Result<BlockTrace> {
+        let mut writer = BufWriter::new(file);
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(TRACE_MAGIC);
+        LittleEndian::write_u32(&mut header[8..12], TRACE_VERSION);
+        LittleEndian::write_u32(&mut header[12..16], RECORD_SIZE as u32);
+        writer.write_all(&header)?;
+        writer.flush()?;
+        Ok(BlockTrace {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Returns the time elapsed between the start of the trace and `instant`, in microseconds.
+    pub fn timestamp_us(&self, instant: Instant) -> u64 {
+        // Requests submitted before the trace was started get a null timestamp.
+        if instant > self.start {
+            instant.duration_since(self.start).as_micros() as u64
+        } else {
+            0
+        }
+    }
+
+    /// Appends `record` to the trace. Records are buffered until the trace is flushed.
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut buf = [0u8; RECORD_SIZE];
+        record.write_to(&mut buf);
+        self.writer.write_all(&buf)
+    }
+
+    /// Writes the buffered records to the trace file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a trace.
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Reads the trace held by `reader`, after checking its header.
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[0..8] != TRACE_MAGIC
+            || LittleEndian::read_u32(&header[8..12]) != TRACE_VERSION
+            || LittleEndian::read_u32(&header[12..16]) != RECORD_SIZE as u32
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a block trace, or an unsupported version of it.",
+            ));
+        }
+        Ok(TraceReader { reader })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = [0u8; RECORD_SIZE];
+        let mut count = 0;
+        while count < RECORD_SIZE {
+            match self.reader.read(&mut buf[count..]) {
+                // The trace may end with a partial record, if the device was stopped while
+                // writing it.
+                Ok(0) => return None,
+                Ok(n) => count += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(TraceRecord::read_from(&buf)))
+    }
+}
+
+/// The outcome of a replayed request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayedRecord {
+    /// The request, as traced.
+    pub record: TraceRecord,
+    /// The status of the replayed request, as a `VIRTIO_BLK_S_*` value.
+    pub status: u8,
+}
+
+// Fills `buf`, holding the data written at `sector`, with the number of each sector it covers.
+fn fill_pattern(buf: &mut [u8], sector: u64) {
+    for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+        let mut pattern = [0u8; 8];
+        LittleEndian::write_u64(&mut pattern, sector + i as u64);
+        for (j, byte) in chunk.iter_mut().enumerate() {
+            *byte = pattern[j % pattern.len()];
+        }
+    }
+}
+
+// Executes the traced request against `disk`, holding `disk_size` bytes.
+fn replay_record<T: DiskFile>(
+    record: &TraceRecord,
+    disk: &mut T,
+    disk_size: u64,
+) -> io::Result<()> {
+    let offset = record
+        .sector
+        .checked_mul(SECTOR_SIZE)
+        .filter(|&offset| offset.saturating_add(u64::from(record.len)) <= disk_size)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL));
+    let len = u64::from(record.len);
+    match record.request_type {
+        VIRTIO_BLK_T_IN => {
+            disk.seek(SeekFrom::Start(offset?))?;
+            let mut data = vec![0u8; record.len as usize];
+            disk.read_exact(&mut data)
+        }
+        VIRTIO_BLK_T_OUT => {
+            disk.seek(SeekFrom::Start(offset?))?;
+            let mut data = vec![0u8; record.len as usize];
+            fill_pattern(&mut data, record.sector);
+            disk.write_all(&data)
+        }
+        VIRTIO_BLK_T_FLUSH => {
+            disk.flush()?;
+            disk.sync()
+        }
+        VIRTIO_BLK_T_GET_ID => Ok(()),
+        VIRTIO_BLK_T_DISCARD if record.flags == 0 => match disk.discard(offset?, len) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        },
+        VIRTIO_BLK_T_WRITE_ZEROES if record.flags & !WRITE_ZEROES_FLAG_UNMAP == 0 => {
+            let offset = offset?;
+            let result = if record.flags & WRITE_ZEROES_FLAG_UNMAP != 0 {
+                disk.punch_hole(offset, len)
+            } else {
+                disk.write_zeroes(offset, len)
+            };
+            match result {
+                Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    disk.write_zeroes(offset, len)
+                }
+                result => result,
+            }
+        }
+        _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+    }
+}
+
+/// Replays the requests of `trace` against `disk`, in the order in which the device started
+/// handling them, and returns their outcomes.
+pub fn replay<R: Read, T: DiskFile>(
+    trace: TraceReader<R>,
+    disk: &mut T,
+) -> io::Result<Vec<ReplayedRecord>> {
+    let mut records = trace.collect::<io::Result<Vec<TraceRecord>>>()?;
+    records.sort_by_key(|record| record.timestamp_us);
+    let disk_size = disk.seek(SeekFrom::End(0))?;
+    // Partial sectors at the end of the image are not visible to the guest.
+    let disk_size = disk_size - disk_size % SECTOR_SIZE;
+
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            let status = match replay_record(&record, disk, disk_size) {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => VIRTIO_BLK_S_UNSUPP,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            };
+            ReplayedRecord {
+                record,
+                status: status as u8,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::os::unix::fs::FileExt;
+
+    use self::tempfile::{tempfile, NamedTempFile};
+    use super::*;
+
+    fn record(request_type: u32, sector: u64, len: u32, flags: u16) -> TraceRecord {
+        TraceRecord {
+            sector,
+            len,
+            request_type,
+            flags,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_write_trace() {
+        let trace_file = NamedTempFile::new().unwrap();
+        let mut trace = BlockTrace::new(trace_file.reopen().unwrap()).unwrap();
+        let first = TraceRecord {
+            timestamp_us: 0x0102_0304_0506_0708,
+            sector: 0x1112_1314_1516_1718,
+            len: 0x2122_2324,
+            latency_us: 0x3132_3334,
+            request_type: VIRTIO_BLK_T_OUT,
+            flags: 0x4142,
+            queue_index: 3,
+            status: VIRTIO_BLK_S_IOERR as u8,
+        };
+        let second = record(VIRTIO_BLK_T_IN, 8, 0x200, 0);
+        trace.record(&first).unwrap();
+        trace.record(&second).unwrap();
+        // Records are only written on flushes.
+        assert_eq!(
+            trace_file.as_file().metadata().unwrap().len(),
+            HEADER_SIZE as u64
+        );
+        trace.flush().unwrap();
+        assert_eq!(
+            trace_file.as_file().metadata().unwrap().len(),
+            (HEADER_SIZE + 2 * RECORD_SIZE) as u64
+        );
+        assert!(trace.timestamp_us(Instant::now()) < 60_000_000);
+
+        let mut data = [0u8; HEADER_SIZE + RECORD_SIZE];
+        trace_file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(&data[..8], b"FCBLKTRC");
+        assert_eq!(
+            &data[HEADER_SIZE..HEADER_SIZE + 8],
+            &[8, 7, 6, 5, 4, 3, 2, 1]
+        );
+
+        let records: Vec<TraceRecord> = TraceReader::new(trace_file.reopen().unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records, vec![first, second]);
+
+        // A partial record at the end of the trace is ignored.
+        trace_file
+            .as_file()
+            .set_len((HEADER_SIZE + RECORD_SIZE + 10) as u64)
+            .unwrap();
+        let records: Vec<TraceRecord> = TraceReader::new(trace_file.reopen().unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records, vec![first]);
+
+        // Other files are not traces.
+        trace_file.as_file().write_all_at(b"FCBLKTRX", 0).unwrap();
+        assert!(TraceReader::new(trace_file.reopen().unwrap()).is_err());
+        assert!(TraceReader::new(&[0u8; 4][..]).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut trace = Vec::new();
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(TRACE_MAGIC);
+        LittleEndian::write_u32(&mut header[8..12], TRACE_VERSION);
+        LittleEndian::write_u32(&mut header[12..16], RECORD_SIZE as u32);
+        trace.extend_from_slice(&header);
+        let records = [
+            record(VIRTIO_BLK_T_OUT, 1, 0x400, 0),
+            record(VIRTIO_BLK_T_IN, 0, 0x1000, 0),
+            record(VIRTIO_BLK_T_WRITE_ZEROES, 2, 0x200, WRITE_ZEROES_FLAG_UNMAP),
+            record(VIRTIO_BLK_T_DISCARD, 3, 0x200, 0),
+            record(VIRTIO_BLK_T_FLUSH, 0, 0, 0),
+            record(VIRTIO_BLK_T_GET_ID, 0, 20, 0),
+            // The request ends beyond the end of the disk.
+            record(VIRTIO_BLK_T_IN, 7, 0x400, 0),
+            // Discard requests cannot set the unmap flag.
+            record(VIRTIO_BLK_T_DISCARD, 0, 0x200, WRITE_ZEROES_FLAG_UNMAP),
+            record(100, 0, 0, 0),
+        ];
+        // Records are written in the order of completion, and replayed in the order of start.
+        for (i, record) in records.iter().enumerate().rev() {
+            let mut buf = [0u8; RECORD_SIZE];
+            TraceRecord {
+                timestamp_us: i as u64,
+                ..*record
+            }
+            .write_to(&mut buf);
+            trace.extend_from_slice(&buf);
+        }
+
+        let mut disk = tempfile().unwrap();
+        disk.set_len(0x1000).unwrap();
+        let replayed = replay(TraceReader::new(&trace[..]).unwrap(), &mut disk).unwrap();
+        let statuses: Vec<u32> = replayed
+            .iter()
+            .map(|replayed| u32::from(replayed.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_OK,
+                VIRTIO_BLK_S_IOERR,
+                VIRTIO_BLK_S_UNSUPP,
+                VIRTIO_BLK_S_UNSUPP,
+            ]
+        );
+        assert!(replayed
+            .iter()
+            .enumerate()
+            .all(|(i, replayed)| replayed.record.timestamp_us == i as u64));
+
+        // Writes store the number of each sector, and the zeroed sector reads as zeroes.
+        let mut data = [0u8; 0x600];
+        disk.read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..0x200].iter().all(|&b| b == 0));
+        assert_eq!(
+            &data[0x200..0x210],
+            &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(data[0x400..0x600].iter().all(|&b| b == 0));
+    }
+}
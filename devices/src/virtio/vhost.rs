@@ -0,0 +1,269 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for handing the data path of a virtio network device over to the in-kernel vhost-net
+//! driver. The device model only sets up the driver: the guest memory table, the addresses of
+//! the vrings, the eventfds used for notifications and the TAP backend. The kernel then moves
+//! the frames between the vrings and the TAP device on its own.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+
+use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
+use net_util::Tap;
+use sys_util::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref, EventFd};
+
+use super::Queue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_VIRTIO: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, vhost_memory);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, vhost_vring_state);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, vhost_vring_addr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, vhost_vring_state);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, vhost_vring_file);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, vhost_vring_file);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, vhost_vring_file);
+
+// The vhost-net driver serves one receive and one transmit vring, in this order.
+const VHOST_NET_VRINGS: u32 = 2;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct vhost_vring_state {
+    index: c_uint,
+    num: c_uint,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct vhost_vring_file {
+    index: c_uint,
+    fd: c_int,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct vhost_vring_addr {
+    index: c_uint,
+    flags: c_uint,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct vhost_memory_region {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+// The header of the memory table, which is directly followed by `nregions` regions.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct vhost_memory {
+    nregions: u32,
+    padding: u32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Opening the vhost-net device failed.
+    Open(io::Error),
+    /// A vhost ioctl failed.
+    Ioctl(io::Error),
+    /// A vring is not placed in guest memory.
+    VringAddress(GuestMemoryError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Turns the return value of a vhost ioctl into a result.
+fn ioctl_result(ret: c_int) -> Result<()> {
+    if ret < 0 {
+        Err(Error::Ioctl(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Handle for an instance of the vhost-net driver, which serves one RX/TX queue pair. The
+/// driver stops when the handle is dropped.
+#[derive(Debug)]
+pub struct VhostNet {
+    file: File,
+}
+
+impl VhostNet {
+    /// Opens an instance of the vhost-net driver and makes the current process its owner.
+    pub fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::Open)?;
+        let vhost_net = VhostNet { file };
+
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl(&vhost_net.file, VHOST_SET_OWNER()) })?;
+
+        Ok(vhost_net)
+    }
+
+    /// Returns the virtio features that the driver supports.
+    pub fn get_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_GET_FEATURES(), &mut features)
+        })?;
+        Ok(features)
+    }
+
+    /// Sets the virtio features acknowledged by the guest driver. Only the features that the
+    /// vhost-net driver supports may be set.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })
+    }
+
+    /// Describes the guest memory to the driver, so that it can translate the guest addresses
+    /// found in the vrings.
+    pub fn set_mem_table(&self, mem: &GuestMemory) -> Result<()> {
+        let header_words = mem::size_of::<vhost_memory>() / mem::size_of::<u64>();
+        let region_words = mem::size_of::<vhost_memory_region>() / mem::size_of::<u64>();
+        // The table is built in a buffer of u64 words, which is suitably aligned for both the
+        // header and the regions following it.
+        let mut table = vec![0u64; header_words + mem.num_regions() * region_words];
+        let header = table.as_mut_ptr() as *mut vhost_memory;
+        let regions = table[header_words..].as_mut_ptr() as *mut vhost_memory_region;
+
+        mem.with_regions(|index, guest_addr, size, host_addr| -> Result<()> {
+            // This is safe, the buffer holds one region per guest memory region.
+            unsafe {
+                *regions.add(index) = vhost_memory_region {
+                    guest_phys_addr: guest_addr.offset() as u64,
+                    memory_size: size as u64,
+                    userspace_addr: host_addr as u64,
+                    flags_padding: 0,
+                };
+            }
+            Ok(())
+        })?;
+        // This is safe, the buffer starts with the header.
+        unsafe {
+            (*header).nregions = mem.num_regions() as u32;
+        }
+
+        // ioctl is safe. Called with a valid vhost fd and a table that describes the regions
+        // it holds, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ptr(&self.file, VHOST_SET_MEM_TABLE(), header) })
+    }
+
+    /// Hands the vring `index` over to the driver. The guest notifies the driver of new buffers
+    /// through `kick`, and the driver signals used buffers through `call`. The queue must be
+    /// valid, see `Queue::is_valid`.
+    pub fn set_vring(
+        &self,
+        index: u32,
+        queue: &Queue,
+        mem: &GuestMemory,
+        kick: &EventFd,
+        call: &EventFd,
+    ) -> Result<()> {
+        let host_address = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(Error::VringAddress)
+        };
+
+        let num = vhost_vring_state {
+            index,
+            num: c_uint::from(queue.actual_size()),
+        };
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_NUM(), &num) })?;
+
+        let addr = vhost_vring_addr {
+            index,
+            flags: 0,
+            desc_user_addr: host_address(queue.desc_table)?,
+            used_user_addr: host_address(queue.used_ring)?,
+            avail_user_addr: host_address(queue.avail_ring)?,
+            log_guest_addr: 0,
+        };
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) })?;
+
+        // The driver picks up the ring where the device model left it.
+        let base = vhost_vring_state {
+            index,
+            num: c_uint::from(queue.save_state().next_avail),
+        };
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_BASE(), &base) })?;
+
+        self.set_vring_file(VHOST_SET_VRING_KICK(), index, kick.as_raw_fd())?;
+        self.set_vring_file(VHOST_SET_VRING_CALL(), index, call.as_raw_fd())
+    }
+
+    /// Starts moving frames between the vrings and `tap`, or stops it when `tap` is None.
+    pub fn set_backend(&self, tap: Option<&Tap>) -> Result<()> {
+        let fd = tap.map_or(-1, Tap::as_raw_fd);
+        for index in 0..VHOST_NET_VRINGS {
+            self.set_vring_file(VHOST_NET_SET_BACKEND(), index, fd)?;
+        }
+        Ok(())
+    }
+
+    fn set_vring_file(&self, request: libc::c_ulong, index: u32, fd: RawFd) -> Result<()> {
+        let file = vhost_vring_file { index, fd };
+        // ioctl is safe. Called with a valid vhost fd, and we check the return.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, request, &file) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_abi() {
+        assert_eq!(mem::size_of::<vhost_vring_state>(), 8);
+        assert_eq!(mem::size_of::<vhost_vring_file>(), 8);
+        assert_eq!(mem::size_of::<vhost_vring_addr>(), 40);
+        assert_eq!(mem::size_of::<vhost_memory_region>(), 32);
+        assert_eq!(mem::size_of::<vhost_memory>(), 8);
+
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_af10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_af20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_af21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+}
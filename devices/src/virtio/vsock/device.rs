@@ -30,6 +30,7 @@ use std::sync::Arc;
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use fc_util::versioned::Versioned;
 use memory_model::GuestMemory;
 use sys_util::EventFd;
 
@@ -53,6 +54,22 @@ pub struct Vsock<B: VsockBackend> {
     epoll_config: EpollConfig,
 }
 
+/// The serializable state of a virtio-vsock device.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct VsockState {
+    /// The context identifier of the guest.
+    pub cid: u64,
+    /// The features offered by the device.
+    pub avail_features: u64,
+    /// The features acknowledged by the driver.
+    pub acked_features: u64,
+}
+
+impl Versioned for VsockState {
+    const NAME: &'static str = "Vsock";
+    const VERSION: u16 = 1;
+}
+
 impl<B> Vsock<B>
 where
     B: VsockBackend,
@@ -67,6 +84,22 @@ where
             backend: Some(backend),
         })
     }
+
+    /// Returns the current state of the device.
+    pub fn save_state(&self) -> VsockState {
+        VsockState {
+            cid: self.cid,
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+        }
+    }
+
+    /// Restores the device to a previously saved state. The guest CID is part of the device
+    /// configuration and is left unchanged. Acknowledged features that the device does not offer
+    /// anymore are dropped.
+    pub fn restore_state(&mut self, state: &VsockState) {
+        self.acked_features = state.acked_features & self.avail_features;
+    }
 }
 
 impl<B> VirtioDevice for Vsock<B>
@@ -300,4 +333,26 @@ mod tests {
             )
             .unwrap();
     }
+
+    #[test]
+    fn test_save_restore_state() {
+        let mut ctx = TestContext::new();
+        ctx.device.ack_features_by_page(0, AVAIL_FEATURES as u32);
+        ctx.device
+            .ack_features_by_page(1, (AVAIL_FEATURES >> 32) as u32);
+        let mut state = ctx.device.save_state();
+        assert_eq!(state.cid, ctx.cid);
+        assert_eq!(state.acked_features, AVAIL_FEATURES);
+
+        let mut other = TestContext::new();
+        other.device.restore_state(&state);
+        assert_eq!(other.device.save_state(), state);
+
+        // The CID is not restored, and neither are the features the device does not offer.
+        state.cid += 1;
+        state.acked_features |= 1;
+        other.device.restore_state(&state);
+        assert_eq!(other.device.cid, ctx.cid);
+        assert_eq!(other.device.acked_features, AVAIL_FEATURES);
+    }
 }
@@ -13,7 +13,7 @@ mod unix;
 
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::defs::EVENT_COUNT as VSOCK_EVENTS_COUNT;
-pub use self::device::Vsock;
+pub use self::device::{Vsock, VsockState};
 pub use self::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
 
 use std::os::unix::io::RawFd;
@@ -183,6 +183,16 @@ impl VsockPacket {
         }
         let buf_desc = head.next_descriptor().ok_or(VsockError::BufDescMissing)?;
 
+        // The device writes to the RX buffers through raw pointers, so the writes have to be
+        // recorded in the dirty page tracking of the guest memory up front.
+        head.mem
+            .mark_dirty(head.addr, VSOCK_PKT_HDR_SIZE)
+            .map_err(VsockError::GuestMemory)?;
+        buf_desc
+            .mem
+            .mark_dirty(buf_desc.addr, buf_desc.len as usize)
+            .map_err(VsockError::GuestMemory)?;
+
         Ok(Self {
             hdr: head
                 .mem
@@ -2,7 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 pub mod rand;
 pub mod time;
 pub mod validators;
+pub mod versioned;
@@ -0,0 +1,303 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioned serialization of the state structures saved in snapshots.
+//!
+//! A structure implementing `Versioned` is serialized together with the version of its layout:
+//!
+//! ```json
+//! { "version": 1, "state": { ... } }
+//! ```
+//!
+//! Whenever the layout of a structure changes, its `VERSION` is bumped and the `upgrade` and
+//! `downgrade` hooks translate the serialized state between consecutive versions. This way a
+//! state saved by a previous release can be loaded by the current one, and a state can be saved
+//! in the layout expected by a previous release.
+//!
+//! The `serialize` and `deserialize` functions of this module (and of the `vec` submodule) are
+//! meant to be used with `#[serde(with = "fc_util::versioned")]`.
+
+use std::fmt;
+use std::result;
+
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::ser::Error as SerError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// Errors associated with translating and loading versioned states.
+#[derive(Debug)]
+pub enum Error {
+    /// The state was saved with a version that cannot be translated to the requested one.
+    UnsupportedVersion {
+        /// The name of the state structure.
+        name: &'static str,
+        /// The unsupported version.
+        version: u16,
+    },
+    /// The serialized state does not match the layout of its version.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnsupportedVersion { name, version } => {
+                write!(f, "Unsupported version {} of the {} state.", version, name)
+            }
+            Error::Json(ref e) => write!(f, "Invalid state: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// A state structure whose serialized layout is versioned.
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The name of the structure, used in error messages.
+    const NAME: &'static str;
+    /// The version of the layout of the structure in this build.
+    const VERSION: u16;
+
+    /// Translates a state serialized in the layout of `version` into the layout of
+    /// `version + 1`.
+    fn upgrade(version: u16, _state: Value) -> Result<Value> {
+        Err(Error::UnsupportedVersion {
+            name: Self::NAME,
+            version,
+        })
+    }
+
+    /// Translates a state serialized in the layout of `version` into the layout of
+    /// `version - 1`.
+    fn downgrade(version: u16, _state: Value) -> Result<Value> {
+        Err(Error::UnsupportedVersion {
+            name: Self::NAME,
+            version: version - 1,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct VersionedState {
+    version: u16,
+    state: Value,
+}
+
+fn check_version<T: Versioned>(version: u16) -> Result<()> {
+    if version == 0 || version > T::VERSION {
+        return Err(Error::UnsupportedVersion {
+            name: T::NAME,
+            version,
+        });
+    }
+    Ok(())
+}
+
+/// Serializes `state` in the layout of `version`, which must not be newer than `T::VERSION`.
+pub fn to_value<T: Versioned>(state: &T, version: u16) -> Result<Value> {
+    check_version::<T>(version)?;
+    let mut value = serde_json::to_value(state).map_err(Error::Json)?;
+    let mut current = T::VERSION;
+    while current > version {
+        value = T::downgrade(current, value)?;
+        current -= 1;
+    }
+    serde_json::to_value(VersionedState {
+        version,
+        state: value,
+    })
+    .map_err(Error::Json)
+}
+
+/// Loads a state serialized by `to_value`, translating it from the layout it was saved in.
+pub fn from_value<T: Versioned>(value: Value) -> Result<T> {
+    let VersionedState { version, state } = serde_json::from_value(value).map_err(Error::Json)?;
+    check_version::<T>(version)?;
+    let mut value = state;
+    let mut current = version;
+    while current < T::VERSION {
+        value = T::upgrade(current, value)?;
+        current += 1;
+    }
+    serde_json::from_value(value).map_err(Error::Json)
+}
+
+/// Serializes `state` in the layout of the current version.
+pub fn serialize<T: Versioned, S: Serializer>(
+    state: &T,
+    serializer: S,
+) -> result::Result<S::Ok, S::Error> {
+    to_value(state, T::VERSION)
+        .map_err(S::Error::custom)?
+        .serialize(serializer)
+}
+
+/// Deserializes a state saved in any supported version.
+pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(
+    deserializer: D,
+) -> result::Result<T, D::Error> {
+    from_value(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// Serializes and deserializes vectors of versioned states.
+pub mod vec {
+    use super::*;
+
+    /// Serializes every state of `states` in the layout of the current version.
+    pub fn serialize<T: Versioned, S: Serializer>(
+        states: &[T],
+        serializer: S,
+    ) -> result::Result<S::Ok, S::Error> {
+        states
+            .iter()
+            .map(|state| to_value(state, T::VERSION))
+            .collect::<Result<Vec<Value>>>()
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+
+    /// Deserializes a vector of states saved in any supported version.
+    pub fn deserialize<'de, T: Versioned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> result::Result<Vec<T>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(from_value)
+            .collect::<Result<Vec<T>>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Version 1 of this structure did not have the `z` coordinate.
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Point {
+        x: u32,
+        y: u32,
+        z: u32,
+    }
+
+    impl Versioned for Point {
+        const NAME: &'static str = "Point";
+        const VERSION: u16 = 2;
+
+        fn upgrade(version: u16, mut state: Value) -> Result<Value> {
+            match version {
+                1 => {
+                    state["z"] = Value::from(0);
+                    Ok(state)
+                }
+                _ => Err(Error::UnsupportedVersion {
+                    name: Self::NAME,
+                    version,
+                }),
+            }
+        }
+
+        fn downgrade(version: u16, mut state: Value) -> Result<Value> {
+            match version {
+                2 => {
+                    state.as_object_mut().map(|fields| fields.remove("z"));
+                    Ok(state)
+                }
+                _ => Err(Error::UnsupportedVersion {
+                    name: Self::NAME,
+                    version: version - 1,
+                }),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Wrapper {
+        #[serde(with = "::versioned")]
+        single: Point,
+        #[serde(with = "::versioned::vec")]
+        many: Vec<Point>,
+    }
+
+    fn point(x: u32, y: u32, z: u32) -> Point {
+        Point { x, y, z }
+    }
+
+    #[test]
+    fn test_translate() {
+        // Current version.
+        let value = to_value(&point(1, 2, 3), 2).unwrap();
+        assert_eq!(
+            value.to_string(),
+            r#"{"state":{"x":1,"y":2,"z":3},"version":2}"#
+        );
+        assert_eq!(from_value::<Point>(value).unwrap(), point(1, 2, 3));
+
+        // Older version.
+        let value = to_value(&point(1, 2, 3), 1).unwrap();
+        assert_eq!(value.to_string(), r#"{"state":{"x":1,"y":2},"version":1}"#);
+        assert_eq!(from_value::<Point>(value).unwrap(), point(1, 2, 0));
+
+        // Unsupported versions.
+        for version in [0, 3].iter() {
+            match to_value(&point(1, 2, 3), *version) {
+                Err(Error::UnsupportedVersion { name, version: v }) => {
+                    assert_eq!(name, "Point");
+                    assert_eq!(v, *version);
+                }
+                _ => unreachable!(),
+            }
+            let value = serde_json::from_str(&format!(r#"{{"version":{},"state":{{}}}}"#, version))
+                .unwrap();
+            match from_value::<Point>(value) {
+                Err(Error::UnsupportedVersion { version: v, .. }) => assert_eq!(v, *version),
+                _ => unreachable!(),
+            }
+        }
+
+        // Malformed states.
+        let value = serde_json::from_str(r#"{"version":2,"state":{"x":1}}"#).unwrap();
+        match from_value::<Point>(value) {
+            Err(Error::Json(_)) => (),
+            _ => unreachable!(),
+        }
+        let value = serde_json::from_str(r#"{"x":1,"y":2,"z":3}"#).unwrap();
+        match from_value::<Point>(value) {
+            Err(Error::Json(_)) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let wrapper = Wrapper {
+            single: point(1, 2, 3),
+            many: vec![point(4, 5, 6), point(7, 8, 9)],
+        };
+        let json = serde_json::to_string(&wrapper).unwrap();
+        assert_eq!(serde_json::from_str::<Wrapper>(&json).unwrap(), wrapper);
+
+        let json = r#"{
+            "single": {"version": 1, "state": {"x": 1, "y": 2}},
+            "many": [{"version": 2, "state": {"x": 4, "y": 5, "z": 6}},
+                     {"version": 1, "state": {"x": 7, "y": 8}}]
+        }"#;
+        assert_eq!(
+            serde_json::from_str::<Wrapper>(json).unwrap(),
+            Wrapper {
+                single: point(1, 2, 0),
+                many: vec![point(4, 5, 6), point(7, 8, 0)],
+            }
+        );
+
+        let json = r#"{
+            "single": {"version": 3, "state": {"x": 1, "y": 2}},
+            "many": []
+        }"#;
+        let err = serde_json::from_str::<Wrapper>(json).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Unsupported version 3 of the Point state."));
+    }
+}
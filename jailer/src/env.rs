@@ -23,6 +23,7 @@ const STDERR_FILENO: libc::c_int = 2;
 
 const DEV_KVM_WITH_NUL: &[u8] = b"/dev/kvm\0";
 const DEV_NET_TUN_WITH_NUL: &[u8] = b"/dev/net/tun\0";
+const DEV_VHOST_NET_WITH_NUL: &[u8] = b"/dev/vhost-net\0";
 const DEV_NULL_WITH_NUL: &[u8] = b"/dev/null\0";
 const ROOT_PATH_WITH_NUL: &[u8] = b"/\0";
 
@@ -270,6 +271,9 @@ impl Env {
         self.mknod_and_own_dev(DEV_NET_TUN_WITH_NUL, 10, 200)?;
         // Do the same for /dev/kvm with (major, minor) = (10, 232).
         self.mknod_and_own_dev(DEV_KVM_WITH_NUL, 10, 232)?;
+        // Do the same for /dev/vhost-net with (major, minor) = (10, 238), used by the network
+        // interfaces that hand their data path over to the kernel.
+        self.mknod_and_own_dev(DEV_VHOST_NET_WITH_NUL, 10, 238)?;
 
         // Change ownership of the jail root to Firecracker's UID and GID. This is necessary
         // so Firecracker can create the unix domain socket in its own jail.
@@ -24,8 +24,11 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 
 /// Used for defining new types of metrics that can be either incremented with an unit
@@ -86,6 +89,85 @@ impl Serialize for SharedMetric {
     }
 }
 
+/// A set of metrics that is also kept for every single device of its type, next to the
+/// aggregate of all of them.
+pub trait DeviceMetricSet: Default + Serialize {
+    /// Prefix of the serialized name of the set of a device, which is followed by its id.
+    const PREFIX: &'static str;
+}
+
+/// The metric sets of the devices of a type, indexed by the ids of the devices. Each set is
+/// serialized under the `<prefix>_<id>` name.
+#[derive(Default)]
+pub struct DeviceMetricsMap<T>(Mutex<BTreeMap<String, Arc<T>>>);
+
+impl<T: DeviceMetricSet> DeviceMetricsMap<T> {
+    /// Returns the metric set of the device with the given id, which is created on first use.
+    pub fn register(&self, id: &str) -> Arc<T> {
+        self.0
+            .lock()
+            .expect("Poisoned lock")
+            .entry(id.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+impl<T: DeviceMetricSet> Serialize for DeviceMetricsMap<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let sets = self.0.lock().expect("Poisoned lock");
+        let mut map = serializer.serialize_map(Some(sets.len()))?;
+        for (id, set) in sets.iter() {
+            map.serialize_entry(&format!("{}_{}", T::PREFIX, id), set.as_ref())?;
+        }
+        map.end()
+    }
+}
+
+/// Updates the metrics of a device along with the aggregate metrics of its type.
+pub struct DeviceMetrics<T: 'static> {
+    aggregate: &'static T,
+    device: Arc<T>,
+}
+
+impl<T> DeviceMetrics<T> {
+    /// Creates the metrics of a device, from the aggregate set of its type and its own set.
+    pub fn new(aggregate: &'static T, device: Arc<T>) -> Self {
+        DeviceMetrics { aggregate, device }
+    }
+
+    /// Adds `value` to the metric picked by `metric` in both sets.
+    pub fn add<F>(&self, metric: F, value: usize)
+    where
+        F: Fn(&T) -> &SharedMetric,
+    {
+        metric(self.aggregate).add(value);
+        metric(&self.device).add(value);
+    }
+
+    /// Increments by 1 unit the metric picked by `metric` in both sets.
+    pub fn inc<F>(&self, metric: F)
+    where
+        F: Fn(&T) -> &SharedMetric,
+    {
+        self.add(metric, 1);
+    }
+
+    /// Returns the set of the device.
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+}
+
+impl<T> Clone for DeviceMetrics<T> {
+    fn clone(&self) -> Self {
+        DeviceMetrics {
+            aggregate: self.aggregate,
+            device: self.device.clone(),
+        }
+    }
+}
+
 // The following structs are used to define a certain organization for the set of metrics we
 // are interested in. Whenever the name of a field differs from its ideal textual representation
 // in the serialized form, we can use the #[serde(rename = "name")] attribute to, well, rename it.
@@ -114,6 +196,15 @@ pub struct GetRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures during GETs for getting information on the instance.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of GETs for getting the balloon device configuration or statistics.
+    pub balloon_count: SharedMetric,
+    /// Number of failures during GETs for getting the balloon device configuration or
+    /// statistics.
+    pub balloon_fails: SharedMetric,
+    /// Number of GETs for getting the exit status of the microVM.
+    pub vm_count: SharedMetric,
+    /// Number of failures during GETs for getting the exit status of the microVM.
+    pub vm_fails: SharedMetric,
 }
 
 /// Metrics specific to PUT API Requests for counting user triggered actions and/or failures.
@@ -123,6 +214,10 @@ pub struct PutRequestsMetrics {
     pub actions_count: SharedMetric,
     /// Number of failures in triggering an action on the VM.
     pub actions_fails: SharedMetric,
+    /// Number of PUTs for configuring the balloon device.
+    pub balloon_count: SharedMetric,
+    /// Number of failures in configuring the balloon device.
+    pub balloon_fails: SharedMetric,
     /// Number of PUTs for attaching source of boot.
     pub boot_source_count: SharedMetric,
     /// Number of failures during attaching source of boot.
@@ -139,15 +234,27 @@ pub struct PutRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures in configuring the machine.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of PUTs for migrating the microVM.
+    pub migration_count: SharedMetric,
+    /// Number of failures in migrating the microVM.
+    pub migration_fails: SharedMetric,
     /// Number of PUTs for creating a new network interface.
     pub network_count: SharedMetric,
     /// Number of failures in creating a new network interface.
     pub network_fails: SharedMetric,
+    /// Number of PUTs for creating or loading a snapshot.
+    pub snapshot_count: SharedMetric,
+    /// Number of failures in creating or loading a snapshot.
+    pub snapshot_fails: SharedMetric,
 }
 
 /// Metrics specific to PATCH API Requests for counting user triggered actions and/or failures.
 #[derive(Default, Serialize)]
 pub struct PatchRequestsMetrics {
+    /// Number of tries to PATCH the balloon device.
+    pub balloon_count: SharedMetric,
+    /// Number of failures in PATCHing the balloon device.
+    pub balloon_fails: SharedMetric,
     /// Number of tries to PATCH a block device.
     pub drive_count: SharedMetric,
     /// Number of failures in PATCHing a block device.
@@ -160,6 +267,33 @@ pub struct PatchRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures in configuring the machine.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of tries to PATCH the state of the microVM.
+    pub vm_count: SharedMetric,
+    /// Number of failures in PATCHing the state of the microVM.
+    pub vm_fails: SharedMetric,
+}
+
+/// Balloon Device associated metrics.
+#[derive(Default, Serialize)]
+pub struct BalloonDeviceMetrics {
+    /// Number of times when activate failed on the balloon device.
+    pub activate_fails: SharedMetric,
+    /// Number of times when interacting with the space config of the balloon device failed.
+    pub cfg_fails: SharedMetric,
+    /// Number of times when handling events on the balloon device failed.
+    pub event_fails: SharedMetric,
+    /// Number of guest pages inflated into the balloon.
+    pub inflated_pages: SharedMetric,
+    /// Number of guest pages deflated out of the balloon.
+    pub deflated_pages: SharedMetric,
+    /// Number of guest memory ranges reported free by the guest.
+    pub reported_ranges: SharedMetric,
+    /// Number of failures in releasing guest memory to the host.
+    pub discard_fails: SharedMetric,
+    /// Number of statistics updates received from the guest.
+    pub stats_updates_count: SharedMetric,
+    /// Number of invalid statistics updates received from the guest.
+    pub stats_update_fails: SharedMetric,
 }
 
 /// Block Device associated metrics.
@@ -177,6 +311,10 @@ pub struct BlockDeviceMetrics {
     pub invalid_reqs_count: SharedMetric,
     /// Number of flushes operation triggered on this block device.
     pub flush_count: SharedMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedMetric,
     /// Number of events triggerd on the queue of this block device.
     pub queue_event_count: SharedMetric,
     /// Number of events ratelimiter-related.
@@ -195,6 +333,10 @@ pub struct BlockDeviceMetrics {
     pub write_count: SharedMetric,
 }
 
+impl DeviceMetricSet for BlockDeviceMetrics {
+    const PREFIX: &'static str = "block";
+}
+
 /// Metrics specific to the i8042 device.
 #[derive(Default, Serialize)]
 pub struct I8042DeviceMetrics {
@@ -289,6 +431,12 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_event_count: SharedMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedMetric,
+    /// Number of frames sent by the guest and dropped by the egress filter.
+    pub tx_filtered_count: SharedMetric,
+}
+
+impl DeviceMetricSet for NetDeviceMetrics {
+    const PREFIX: &'static str = "net";
 }
 
 /// Metrics specific to the i8042 device.
@@ -357,6 +505,10 @@ pub struct VmmMetrics {
 pub struct MemoryMetrics {
     /// Number of pages dirtied since the last call to `KVM_GET_DIRTY_LOG`.
     pub dirty_pages: SharedMetric,
+    /// Number of guest memory pages loaded on demand by the page fault handler.
+    pub page_faults: SharedMetric,
+    /// Number of page faults the page fault handler failed to serve.
+    pub page_fault_fails: SharedMetric,
 }
 
 /// Metrics related to signals.
@@ -386,8 +538,13 @@ pub struct FirecrackerMetrics {
     utc_timestamp_ms: SerializeToUtcTimestampMs,
     /// API Server related metrics.
     pub api_server: ApiServerMetrics,
+    /// The balloon device's related metrics.
+    pub balloon: BalloonDeviceMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// The metrics of every block device, keyed by drive id.
+    #[serde(flatten)]
+    pub block_devices: DeviceMetricsMap<BlockDeviceMetrics>,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics relaetd to the i8042 device.
@@ -398,6 +555,9 @@ pub struct FirecrackerMetrics {
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
     pub net: NetDeviceMetrics,
+    /// The metrics of every network device, keyed by interface id.
+    #[serde(flatten)]
+    pub net_devices: DeviceMetricsMap<NetDeviceMetrics>,
     /// Metrics related to API PATCH requests.
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
@@ -473,4 +633,33 @@ mod tests {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
         assert!(s.is_ok());
     }
+
+    #[test]
+    fn test_device_metrics() {
+        let metrics = FirecrackerMetrics::default();
+        let rootfs = DeviceMetrics::new(&METRICS.block, metrics.block_devices.register("rootfs"));
+        let eth0 = DeviceMetrics::new(&METRICS.net, metrics.net_devices.register("eth0"));
+        // Registering a device again returns its existing set.
+        assert!(Arc::ptr_eq(
+            &rootfs.device,
+            &metrics.block_devices.register("rootfs")
+        ));
+
+        let read_bytes = METRICS.block.read_bytes.count();
+        rootfs.add(|m| &m.read_bytes, 512);
+        rootfs.inc(|m| &m.read_count);
+        eth0.inc(|m| &m.rx_fails);
+        assert_eq!(METRICS.block.read_bytes.count(), read_bytes + 512);
+        assert_eq!(rootfs.device().read_bytes.count(), 512);
+        assert_eq!(rootfs.clone().device().read_count.count(), 1);
+
+        let value: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&metrics).unwrap()).unwrap();
+        assert_eq!(value["block_rootfs"]["read_bytes"], 512);
+        assert_eq!(value["net_eth0"]["rx_fails"], 1);
+        // The sets of the devices are flushed as the aggregates.
+        let value: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&metrics).unwrap()).unwrap();
+        assert_eq!(value["block_rootfs"]["read_bytes"], 0);
+    }
 }
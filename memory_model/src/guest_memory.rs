@@ -7,12 +7,14 @@
 
 //! Track memory regions that are mapped to the guest microVM.
 
+use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{mem, result};
 
 use guest_address::GuestAddress;
-use mmap::{self, MemoryMapping};
+use mmap::{self, HugePageConfig, MemoryMapping};
 use DataInit;
 
 /// Errors associated with handling guest memory regions.
@@ -22,9 +24,11 @@ pub enum Error {
     InvalidGuestAddress(GuestAddress),
     /// Failure in finding a guest address range in any memory regions mapped by this guest.
     InvalidGuestAddressRange(GuestAddress, usize),
+    /// There is no memory region with the given index.
+    InvalidRegionIndex(usize),
     /// Failure in accessing the memory located at some address.
     MemoryAccess(GuestAddress, mmap::Error),
-    /// Failure in creating an anonymous shared mapping.
+    /// Failure in creating an anonymous or file backed mapping.
     MemoryMappingFailed(mmap::Error),
     /// Failure in initializing guest memory.
     MemoryNotInitialized,
@@ -35,11 +39,58 @@ pub enum Error {
 }
 type Result<T> = result::Result<T, Error>;
 
-/// Tracks a mapping of anonymous memory in the current process and the corresponding base address
-/// in the guest's memory space.
+/// The granularity, in bytes, at which writes to the guest memory are tracked.
+pub const DIRTY_PAGE_SIZE: usize = 4096;
+
+/// Describes the file backing a guest memory region.
+#[derive(Clone, Debug)]
+pub struct FileBacking {
+    file: Arc<File>,
+    offset: u64,
+    shared: bool,
+}
+
+impl FileBacking {
+    /// Creates the description of a region backed by `file`, starting at `offset`. With `shared`
+    /// set, the writes to the region reach the file. Otherwise, the region is a copy on write
+    /// view of the file.
+    pub fn new(file: File, offset: u64, shared: bool) -> Self {
+        Self::from_arc(Arc::new(file), offset, shared)
+    }
+
+    /// Same as `new`, for a file that also backs other regions.
+    pub fn from_arc(file: Arc<File>, offset: u64, shared: bool) -> Self {
+        FileBacking {
+            file,
+            offset,
+            shared,
+        }
+    }
+
+    /// Returns the file backing the region.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the offset of the region in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns whether the writes to the region reach the file.
+    pub fn shared(&self) -> bool {
+        self.shared
+    }
+}
+
+/// Tracks a mapping of anonymous or file backed memory in the current process and the
+/// corresponding base address in the guest's memory space.
 pub struct MemoryRegion {
     mapping: MemoryMapping,
     guest_base: GuestAddress,
+    file_backing: Option<FileBacking>,
+    // One bit for each page of the region, set when the VMM writes to the page.
+    dirty_bitmap: Vec<AtomicU64>,
 }
 
 impl MemoryRegion {
@@ -47,6 +98,23 @@ impl MemoryRegion {
     pub fn size(&self) -> usize {
         self.mapping.size()
     }
+
+    /// Returns the file backing the memory region, if any.
+    pub fn file_backing(&self) -> Option<&FileBacking> {
+        self.file_backing.as_ref()
+    }
+
+    // Marks the pages overlapping `[offset, offset + len)` as dirty.
+    fn mark_dirty(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first_page = offset / DIRTY_PAGE_SIZE;
+        let last_page = (offset + len - 1) / DIRTY_PAGE_SIZE;
+        for page in first_page..=last_page {
+            self.dirty_bitmap[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        }
+    }
 }
 
 fn region_end(region: &MemoryRegion) -> GuestAddress {
@@ -64,6 +132,29 @@ impl GuestMemory {
     /// Creates a container for guest memory regions.
     /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
     pub fn new(ranges: &[(GuestAddress, usize)]) -> Result<GuestMemory> {
+        let ranges: Vec<(GuestAddress, usize, Option<FileBacking>)> = ranges
+            .iter()
+            .map(|&(guest_base, size)| (guest_base, size, None))
+            .collect();
+        Self::new_with_files(&ranges)
+    }
+
+    /// Creates a container for guest memory regions, each one optionally backed by a file.
+    /// Valid memory regions are specified as a Vec of (Address, Size, FileBacking) tuples sorted
+    /// by Address. The regions without a file are backed by anonymous memory.
+    pub fn new_with_files(
+        ranges: &[(GuestAddress, usize, Option<FileBacking>)],
+    ) -> Result<GuestMemory> {
+        Self::new_with_huge_pages(ranges, HugePageConfig::None)
+    }
+
+    /// Same as `new_with_files`, with every region backed by the pages described by
+    /// `huge_pages`. With hugetlbfs pages, the sizes and file offsets of the regions must be
+    /// multiples of 2 MiB and the files must be on a hugetlbfs mount.
+    pub fn new_with_huge_pages(
+        ranges: &[(GuestAddress, usize, Option<FileBacking>)],
+        huge_pages: HugePageConfig,
+    ) -> Result<GuestMemory> {
         if ranges.is_empty() {
             return Err(Error::NoMemoryRegions);
         }
@@ -80,10 +171,25 @@ impl GuestMemory {
                 }
             }
 
-            let mapping = MemoryMapping::new(range.1).map_err(Error::MemoryMappingFailed)?;
+            let mapping = match range.2 {
+                Some(ref backing) => MemoryMapping::from_file(
+                    backing.file(),
+                    backing.offset(),
+                    range.1,
+                    backing.shared(),
+                    huge_pages,
+                ),
+                None => MemoryMapping::new_with_huge_pages(range.1, huge_pages),
+            }
+            .map_err(Error::MemoryMappingFailed)?;
+            let num_pages = (range.1 + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
             regions.push(MemoryRegion {
                 mapping,
                 guest_base: range.0,
+                file_backing: range.2.clone(),
+                dirty_bitmap: (0..(num_pages + 63) / 64)
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
             });
         }
 
@@ -190,10 +296,13 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_slice_at_addr(&self, buf: &[u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region_partial(guest_addr, move |mapping, offset| {
-            mapping
+        self.do_in_region_partial(guest_addr, move |region, offset| {
+            let count = region
+                .mapping
                 .write_slice(buf, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+            region.mark_dirty(offset, count);
+            Ok(count)
         })
     }
 
@@ -217,8 +326,9 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn read_slice_at_addr(&self, buf: &mut [u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region_partial(guest_addr, move |mapping, offset| {
-            mapping
+        self.do_in_region_partial(guest_addr, move |region, offset| {
+            region
+                .mapping
                 .read_slice(buf, offset)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
         })
@@ -248,8 +358,9 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn read_obj_from_addr<T: DataInit>(&self, guest_addr: GuestAddress) -> Result<T> {
-        self.do_in_region(guest_addr, mem::size_of::<T>(), |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, mem::size_of::<T>(), |region, offset| {
+            region
+                .mapping
                 .read_obj(offset)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
         })
@@ -274,10 +385,13 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_obj_at_addr<T: DataInit>(&self, val: T, guest_addr: GuestAddress) -> Result<()> {
-        self.do_in_region(guest_addr, mem::size_of::<T>(), move |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, mem::size_of::<T>(), move |region, offset| {
+            region
+                .mapping
                 .write_obj(val, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+            region.mark_dirty(offset, mem::size_of::<T>());
+            Ok(())
         })
     }
 
@@ -316,10 +430,13 @@ impl GuestMemory {
     where
         F: Read,
     {
-        self.do_in_region(guest_addr, count, move |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, count, move |region, offset| {
+            region
+                .mapping
                 .read_to_memory(offset, src, count)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+            region.mark_dirty(offset, count);
+            Ok(())
         })
     }
 
@@ -356,8 +473,9 @@ impl GuestMemory {
     where
         F: Write,
     {
-        self.do_in_region(guest_addr, count, move |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, count, move |region, offset| {
+            region
+                .mapping
                 .write_from_memory(offset, dst, count)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
         })
@@ -384,13 +502,85 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn get_host_address(&self, guest_addr: GuestAddress) -> Result<*const u8> {
-        self.do_in_region(guest_addr, 1, |mapping, offset| {
+        self.do_in_region(guest_addr, 1, |region, offset| {
             // This is safe; `do_in_region` already checks that offset is in
             // bounds.
-            Ok(unsafe { mapping.as_ptr().add(offset) } as *const u8)
+            Ok(unsafe { region.mapping.as_ptr().add(offset) } as *const u8)
+        })
+    }
+
+    /// Returns the host address of the guest memory range `[guest_addr, guest_addr + len)`,
+    /// such as a buffer handed over to the kernel for asynchronous I/O. The range must be
+    /// contained in a single region. Writes through the returned pointer are not tracked, see
+    /// `mark_dirty`.
+    pub fn get_host_address_range(&self, guest_addr: GuestAddress, len: usize) -> Result<*mut u8> {
+        self.do_in_region(guest_addr, len, |region, offset| {
+            // This is safe; `do_in_region` already checks that the range is in bounds.
+            Ok(unsafe { region.mapping.as_ptr().add(offset) })
         })
     }
 
+    /// Releases the guest memory range `[guest_addr, guest_addr + len)` to the host, such as the
+    /// pages handed over by a balloon device. Both `guest_addr` and `len` must be page aligned.
+    /// The range reads as zeros afterwards, unless it is backed by a private file mapping, in
+    /// which case it reads as the contents of the file. The range is marked as dirty.
+    pub fn discard_range(&self, guest_addr: GuestAddress, len: usize) -> Result<()> {
+        self.do_in_region(guest_addr, len, |region, offset| {
+            // Anonymous and shared file mappings only release their pages when the backing range
+            // is removed too.
+            let private = region
+                .file_backing
+                .as_ref()
+                .map_or(false, |backing| !backing.shared());
+            if private {
+                region.mapping.discard_range(offset, len)
+            } else {
+                region.mapping.remove_range(offset, len)
+            }
+            .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+            // The contents of the range changed without the guest writing to it.
+            region.mark_dirty(offset, len);
+            Ok(())
+        })
+    }
+
+    /// Marks the guest memory range `[guest_addr, guest_addr + len)` as dirty. This must be
+    /// called after writing to the guest memory through a pointer returned by
+    /// `get_host_address`, since such writes cannot be tracked otherwise.
+    pub fn mark_dirty(&self, guest_addr: GuestAddress, len: usize) -> Result<()> {
+        self.do_in_region(guest_addr, len, |region, offset| {
+            region.mark_dirty(offset, len);
+            Ok(())
+        })
+    }
+
+    /// Marks as dirty the pages of the region at `index` whose bits are set in `bitmap`, such as
+    /// a dirty page log retrieved from KVM.
+    pub fn mark_dirty_pages(&self, index: usize, bitmap: &[u64]) -> Result<()> {
+        let region = self
+            .regions
+            .get(index)
+            .ok_or(Error::InvalidRegionIndex(index))?;
+        for (dirty, pages) in region.dirty_bitmap.iter().zip(bitmap.iter()) {
+            dirty.fetch_or(*pages, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Returns the bitmap of the pages of the region at `index` written since the last call,
+    /// one bit per `DIRTY_PAGE_SIZE` bytes, and clears it.
+    pub fn take_dirty_pages(&self, index: usize) -> Result<Vec<u64>> {
+        let region = self
+            .regions
+            .get(index)
+            .ok_or(Error::InvalidRegionIndex(index))?;
+        Ok(region
+            .dirty_bitmap
+            .iter()
+            .map(|pages| pages.swap(0, Ordering::Relaxed))
+            .collect())
+    }
+
     /// Applies two functions, specified as callbacks, on the inner memory regions.
     ///
     /// # Arguments
@@ -433,13 +623,13 @@ impl GuestMemory {
     /// Read the whole object from a single MemoryRegion
     fn do_in_region<F, T>(&self, guest_addr: GuestAddress, size: usize, cb: F) -> Result<T>
     where
-        F: FnOnce(&MemoryMapping, usize) -> Result<T>,
+        F: FnOnce(&MemoryRegion, usize) -> Result<T>,
     {
         for region in self.regions.iter() {
             if guest_addr >= region.guest_base && guest_addr < region_end(region) {
                 let offset = guest_addr.offset_from(region.guest_base);
                 if size <= region.mapping.size() - offset {
-                    return cb(&region.mapping, offset);
+                    return cb(region, offset);
                 }
                 break;
             }
@@ -450,11 +640,11 @@ impl GuestMemory {
     /// Read the whole or partial content from a single MemoryRegion
     fn do_in_region_partial<F>(&self, guest_addr: GuestAddress, cb: F) -> Result<usize>
     where
-        F: FnOnce(&MemoryMapping, usize) -> Result<usize>,
+        F: FnOnce(&MemoryRegion, usize) -> Result<usize>,
     {
         for region in self.regions.iter() {
             if guest_addr >= region.guest_base && guest_addr < region_end(region) {
-                return cb(&region.mapping, guest_addr.offset_from(region.guest_base));
+                return cb(region, guest_addr.offset_from(region.guest_base));
             }
         }
         Err(Error::InvalidGuestAddress(guest_addr))
@@ -463,8 +653,9 @@ impl GuestMemory {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
     use super::*;
-    use std::fs::File;
     use std::mem;
     use std::path::Path;
 
@@ -605,7 +796,11 @@ mod tests {
 
     // Get the base address of the mapping for a GuestAddress.
     fn get_mapping(mem: &GuestMemory, addr: GuestAddress) -> Result<*const u8> {
-        mem.do_in_region(addr, 1, |mapping, _| Ok(mapping.as_ptr() as *const u8))
+        mem.do_in_region(
+            addr,
+            1,
+            |region, _| Ok(region.mapping.as_ptr() as *const u8),
+        )
     }
 
     #[test]
@@ -642,4 +837,126 @@ mod tests {
             3
         );
     }
+
+    #[test]
+    fn test_file_backed_regions() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(0x3000).unwrap();
+        let mem = GuestMemory::new_with_files(&[
+            (GuestAddress(0), 0x1000, None),
+            (
+                GuestAddress(0x1000),
+                0x1000,
+                Some(FileBacking::new(file.try_clone().unwrap(), 0x1000, true)),
+            ),
+            (
+                GuestAddress(0x2000),
+                0x1000,
+                Some(FileBacking::new(file.try_clone().unwrap(), 0x2000, false)),
+            ),
+        ])
+        .unwrap();
+        mem.with_regions_mut(|index, _, _, _| -> result::Result<(), ()> {
+            assert_eq!(mem.regions[index].file_backing().is_some(), index > 0);
+            Ok(())
+        })
+        .unwrap();
+
+        // Only the writes to the shared region reach the file.
+        mem.write_obj_at_addr(0x11u8, GuestAddress(0x1010)).unwrap();
+        mem.write_obj_at_addr(0x22u8, GuestAddress(0x2010)).unwrap();
+        let mut contents = Vec::new();
+        (&file).read_to_end(&mut contents).unwrap();
+        assert_eq!(contents[0x1010], 0x11);
+        assert_eq!(contents[0x2010], 0);
+
+        // The file must be large enough to back the region.
+        match GuestMemory::new_with_files(&[(
+            GuestAddress(0),
+            0x1000,
+            Some(FileBacking::new(file, 0x3000, true)),
+        )]) {
+            Err(Error::MemoryMappingFailed(mmap::Error::InvalidRange(0x3000, 0x1000))) => (),
+            _ => panic!("Unexpected guest memory result."),
+        }
+    }
+
+    #[test]
+    fn test_get_host_address_range() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let mem = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let addr = mem
+            .get_host_address_range(GuestAddress(0x1100), 0x100)
+            .unwrap();
+        assert_eq!(
+            addr as *const u8,
+            mem.get_host_address(GuestAddress(0x1100)).unwrap()
+        );
+        // The range cannot span several regions.
+        assert!(mem
+            .get_host_address_range(GuestAddress(0xf00), 0x200)
+            .is_err());
+        assert!(mem
+            .get_host_address_range(GuestAddress(0x1f00), 0x200)
+            .is_err());
+    }
+
+    #[test]
+    fn test_discard_range() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x10_0000);
+        let mem = GuestMemory::new(&[(start_addr1, 0x10_0000), (start_addr2, 0x4_0000)]).unwrap();
+        mem.write_obj_at_addr(1u64, GuestAddress(0x1000)).unwrap();
+        mem.write_obj_at_addr(1u64, GuestAddress(0x10_1000))
+            .unwrap();
+        mem.take_dirty_pages(1).unwrap();
+        mem.discard_range(GuestAddress(0x10_0000), 0x2000).unwrap();
+        // The discarded pages are dirty.
+        assert_eq!(mem.take_dirty_pages(1).unwrap(), vec![0b11]);
+        assert_eq!(
+            mem.read_obj_from_addr::<u64>(GuestAddress(0x1000)).unwrap(),
+            1
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u64>(GuestAddress(0x10_1000))
+                .unwrap(),
+            0
+        );
+        // The range cannot span several regions.
+        assert!(mem.discard_range(GuestAddress(0xf_f000), 0x2000).is_err());
+        assert!(mem.discard_range(GuestAddress(0x20_0000), 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x10_0000);
+        let mem = GuestMemory::new(&[(start_addr1, 0x10_0000), (start_addr2, 0x4_0000)]).unwrap();
+        assert_eq!(mem.take_dirty_pages(0).unwrap(), vec![0u64; 4]);
+        assert_eq!(mem.take_dirty_pages(1).unwrap(), vec![0u64; 1]);
+        assert!(mem.take_dirty_pages(2).is_err());
+
+        // Writes spanning a page boundary dirty both pages.
+        mem.write_slice_at_addr(&[1, 2, 3, 4], GuestAddress(0xffe))
+            .unwrap();
+        mem.write_obj_at_addr(1u64, GuestAddress(0x2000)).unwrap();
+        mem.write_slice_at_addr(&[1, 2, 3], GuestAddress(0x10_3000))
+            .unwrap();
+        mem.read_to_memory(GuestAddress(0x4_1000), &mut &[0u8; 0x2000][..], 0x2000)
+            .unwrap();
+        // Reads do not dirty any page.
+        mem.read_obj_from_addr::<u64>(GuestAddress(0x8000)).unwrap();
+        mem.mark_dirty(GuestAddress(0x10_5000), 1).unwrap();
+        assert!(mem.mark_dirty(GuestAddress(0x20_0000), 1).is_err());
+        mem.mark_dirty_pages(0, &[0, 1 << 63]).unwrap();
+
+        assert_eq!(
+            mem.take_dirty_pages(0).unwrap(),
+            vec![0b111, 1 << 63 | 0b110, 0, 0]
+        );
+        assert_eq!(mem.take_dirty_pages(1).unwrap(), vec![0b10_1000]);
+        // Taking the dirty pages clears them.
+        assert_eq!(mem.take_dirty_pages(0).unwrap(), vec![0u64; 4]);
+    }
 }
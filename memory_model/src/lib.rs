@@ -57,6 +57,8 @@ mod mmap;
 
 pub use guest_address::GuestAddress;
 pub use guest_memory::Error as GuestMemoryError;
+pub use guest_memory::FileBacking;
 pub use guest_memory::GuestMemory;
 pub use guest_memory::MemoryRegion;
-pub use mmap::{Error as MemoryMappingError, MemoryMapping};
+pub use guest_memory::DIRTY_PAGE_SIZE;
+pub use mmap::{Error as MemoryMappingError, HugePageConfig, MemoryMapping};
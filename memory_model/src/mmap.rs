@@ -9,7 +9,9 @@
 //! mmap object leaves scope.
 
 use std;
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
 
 use libc;
@@ -31,10 +33,45 @@ pub enum Error {
     WriteToMemory(io::Error),
     /// Reading from memory failed
     ReadFromMemory(io::Error),
+    /// The file mapped with hugetlbfs pages is not on a hugetlbfs mount.
+    NotOnHugetlbfs,
 }
 type Result<T> = std::result::Result<T, Error>;
 
-/// Wraps an anonymous shared memory mapping in the current process.
+// See include/uapi/linux/magic.h and include/uapi/asm-generic/hugetlb_encode.h in the kernel code.
+const HUGETLBFS_MAGIC: u64 = 0x9584_58f6;
+const MAP_HUGE_2MB: libc::c_int = 21 << 26;
+
+/// The kind of pages backing a memory mapping.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HugePageConfig {
+    /// Regular pages.
+    None,
+    /// 2 MiB pages allocated from the pool of huge pages reserved by the host. The pages are
+    /// reserved when the memory is mapped, so that the mapping fails if the pool is too small.
+    Hugetlbfs2M,
+    /// Regular pages, which the kernel may merge into transparent huge pages.
+    Transparent,
+}
+
+impl Default for HugePageConfig {
+    fn default() -> Self {
+        HugePageConfig::None
+    }
+}
+
+impl HugePageConfig {
+    // Returns the flags to add to the `mmap` flags. Huge pages are reserved upfront, otherwise
+    // running out of them would raise SIGBUS when the guest first accesses its memory.
+    fn mmap_flags(self) -> libc::c_int {
+        match self {
+            HugePageConfig::Hugetlbfs2M => 0,
+            HugePageConfig::None | HugePageConfig::Transparent => libc::MAP_NORESERVE,
+        }
+    }
+}
+
+/// Wraps an anonymous or file backed memory mapping in the current process.
 pub struct MemoryMapping {
     addr: *mut u8,
     size: usize,
@@ -53,6 +90,20 @@ impl MemoryMapping {
     /// # Arguments
     /// * `size` - Size of memory region in bytes.
     pub fn new(size: usize) -> Result<MemoryMapping> {
+        Self::new_with_huge_pages(size, HugePageConfig::None)
+    }
+
+    /// Creates an anonymous shared mapping of `size` bytes, backed by the pages described by
+    /// `huge_pages`.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes, a multiple of 2 MiB for hugetlbfs pages.
+    /// * `huge_pages` - The kind of pages backing the mapping.
+    pub fn new_with_huge_pages(size: usize, huge_pages: HugePageConfig) -> Result<MemoryMapping> {
+        let flags = match huge_pages {
+            HugePageConfig::Hugetlbfs2M => libc::MAP_HUGETLB | MAP_HUGE_2MB,
+            HugePageConfig::None | HugePageConfig::Transparent => 0,
+        };
         // This is safe because we are creating an anonymous mapping in a place not already used by
         // any other area in this process.
         let addr = unsafe {
@@ -60,7 +111,7 @@ impl MemoryMapping {
                 null_mut(),
                 size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | flags | huge_pages.mmap_flags(),
                 -1,
                 0,
             )
@@ -68,10 +119,123 @@ impl MemoryMapping {
         if addr == libc::MAP_FAILED {
             return Err(Error::SystemCallFailed(io::Error::last_os_error()));
         }
-        Ok(MemoryMapping {
+        let mapping = MemoryMapping {
             addr: addr as *mut u8,
             size,
-        })
+        };
+        if huge_pages == HugePageConfig::Transparent {
+            mapping.advise_huge_pages()?;
+        }
+        Ok(mapping)
+    }
+
+    /// Maps `size` bytes of `file`, starting at `offset`. With a shared mapping, the writes to
+    /// the memory reach the file and are visible to the other processes mapping it. With a
+    /// private mapping, the pages are read from the file on first access and the writes are only
+    /// visible to this mapping.
+    ///
+    /// # Arguments
+    /// * `file` - The file to map, which must hold at least `offset + size` bytes.
+    /// * `offset` - Offset of the mapping in the file, which must be page aligned.
+    /// * `size` - Size of memory region in bytes.
+    /// * `shared` - Whether to create a shared or a private mapping.
+    /// * `huge_pages` - The kind of pages backing the mapping. Files mapped with hugetlbfs pages
+    ///                  must be on a hugetlbfs mount.
+    pub fn from_file(
+        file: &File,
+        offset: u64,
+        size: usize,
+        shared: bool,
+        huge_pages: HugePageConfig,
+    ) -> Result<MemoryMapping> {
+        // Accessing the mapping past the end of the file would raise SIGBUS.
+        let file_size = file.metadata().map_err(Error::SystemCallFailed)?.len();
+        if offset
+            .checked_add(size as u64)
+            .map_or(true, |end| end > file_size)
+        {
+            return Err(Error::InvalidRange(offset as usize, size));
+        }
+        if huge_pages == HugePageConfig::Hugetlbfs2M && !is_on_hugetlbfs(file)? {
+            return Err(Error::NotOnHugetlbfs);
+        }
+
+        let flags = if shared {
+            libc::MAP_SHARED
+        } else {
+            libc::MAP_PRIVATE
+        };
+        // This is safe because we are creating a new mapping in a place not already used by any
+        // other area in this process.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags | huge_pages.mmap_flags(),
+                file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        let mapping = MemoryMapping {
+            addr: addr as *mut u8,
+            size,
+        };
+        if huge_pages == HugePageConfig::Transparent {
+            mapping.advise_huge_pages()?;
+        }
+        Ok(mapping)
+    }
+
+    // Lets the kernel back the mapping with transparent huge pages.
+    fn advise_huge_pages(&self) -> Result<()> {
+        // This is safe because the advice does not change the contents of the mapping.
+        unsafe { self.advise_range(0, self.size, libc::MADV_HUGEPAGE) }
+    }
+
+    /// Releases the `count` bytes of memory starting at `offset` of a private file mapping to
+    /// the host. The range reads as the contents of the file afterwards. Anonymous and shared file
+    /// mappings keep their contents; see `remove_range`.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset of the range in the mapping, which must be page aligned.
+    /// * `count` - Size of the range in bytes.
+    pub fn discard_range(&self, offset: usize, count: usize) -> Result<()> {
+        // This is safe because the range is checked to be part of the mapping, which we own,
+        // and is only accessed through the bounds checked interface.
+        unsafe { self.advise_range(offset, count, libc::MADV_DONTNEED) }
+    }
+
+    /// Releases the `count` bytes of memory starting at `offset` of an anonymous or shared file
+    /// mapping, along with the matching range of the backing file. The range reads as zeros
+    /// afterwards.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset of the range in the mapping, which must be page aligned.
+    /// * `count` - Size of the range in bytes.
+    pub fn remove_range(&self, offset: usize, count: usize) -> Result<()> {
+        // This is safe because the range is checked to be part of the mapping, which we own,
+        // and is only accessed through the bounds checked interface.
+        unsafe { self.advise_range(offset, count, libc::MADV_REMOVE) }
+    }
+
+    // Calls `madvise` on `count` bytes of the mapping starting at `offset`. Unsafe because some
+    // advices change the contents of the mapping.
+    unsafe fn advise_range(&self, offset: usize, count: usize, advice: libc::c_int) -> Result<()> {
+        if offset
+            .checked_add(count)
+            .map_or(true, |end| end > self.size)
+        {
+            return Err(Error::InvalidRange(offset, count));
+        }
+        let ret = libc::madvise(self.addr.add(offset) as *mut libc::c_void, count, advice);
+        if ret != 0 {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        Ok(())
     }
 
     /// Returns a pointer to the beginning of the memory region.  Should only be
@@ -293,6 +457,17 @@ impl MemoryMapping {
     }
 }
 
+// Returns whether `file` is on a hugetlbfs mount.
+fn is_on_hugetlbfs(file: &File) -> Result<bool> {
+    // This is safe because `statfs` is plain old data, filled by the kernel.
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    // This is safe because we pass a valid file descriptor and a valid `statfs` structure.
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut statfs) } != 0 {
+        return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+    }
+    Ok(statfs.f_type as u64 == HUGETLBFS_MAGIC)
+}
+
 impl Drop for MemoryMapping {
     fn drop(&mut self) {
         // This is safe because we mmap the area at addr ourselves, and nobody
@@ -309,7 +484,7 @@ mod tests {
 
     use self::tempfile::tempfile;
     use super::*;
-    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
     use std::mem;
     use std::path::Path;
 
@@ -334,6 +509,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn map_file() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0u8; 0x2000]).unwrap();
+
+        // The writes to a shared mapping reach the file.
+        let m =
+            MemoryMapping::from_file(&file, 0x1000, 0x1000, true, HugePageConfig::None).unwrap();
+        assert_eq!(m.size(), 0x1000);
+        m.write_obj(0x55u8, 0x10).unwrap();
+        let mut buf = [0u8; 0x11];
+        file.seek(SeekFrom::Start(0x1000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0x10], 0x55);
+
+        // The writes to a private mapping do not.
+        let m =
+            MemoryMapping::from_file(&file, 0x1000, 0x1000, false, HugePageConfig::None).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x10).unwrap(), 0x55);
+        m.write_obj(0xaau8, 0x10).unwrap();
+        file.seek(SeekFrom::Start(0x1000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0x10], 0x55);
+
+        // The mapping cannot span past the end of the file.
+        match MemoryMapping::from_file(&file, 0x1000, 0x2000, true, HugePageConfig::None) {
+            Err(Error::InvalidRange(0x1000, 0x2000)) => (),
+            _ => panic!("Unexpected mapping result."),
+        }
+        // The offset must be page aligned.
+        match MemoryMapping::from_file(&file, 0x10, 0x1000, true, HugePageConfig::None) {
+            Err(Error::SystemCallFailed(e)) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            _ => panic!("Unexpected mapping result."),
+        }
+    }
+
+    #[test]
+    fn test_huge_pages() {
+        // Transparent huge pages are only a hint to the kernel.
+        let m = MemoryMapping::new_with_huge_pages(0x40_0000, HugePageConfig::Transparent).unwrap();
+        m.write_obj(0x55u8, 0x20_0000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x20_0000).unwrap(), 0x55);
+
+        // A regular file cannot be mapped with hugetlbfs pages.
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0u8; 0x2000]).unwrap();
+        match MemoryMapping::from_file(&file, 0, 0x2000, true, HugePageConfig::Hugetlbfs2M) {
+            Err(Error::NotOnHugetlbfs) => (),
+            _ => panic!("Unexpected mapping result."),
+        }
+        let m =
+            MemoryMapping::from_file(&file, 0, 0x2000, true, HugePageConfig::Transparent).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x1000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_discard_range() {
+        let m = MemoryMapping::new(0x3000).unwrap();
+        m.write_obj(0x55u8, 0x10).unwrap();
+        m.write_obj(0x55u8, 0x1010).unwrap();
+        m.remove_range(0x1000, 0x2000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x10).unwrap(), 0x55);
+        assert_eq!(m.read_obj::<u8>(0x1010).unwrap(), 0);
+        match m.remove_range(0x1000, 0x3000) {
+            Err(Error::InvalidRange(0x1000, 0x3000)) => (),
+            _ => panic!("Unexpected remove result."),
+        }
+        // The range must be page aligned.
+        assert!(m.remove_range(0x10, 0x1000).is_err());
+
+        // A private file mapping reads the file again, while a shared one removes its contents.
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xaau8; 0x2000]).unwrap();
+        let m = MemoryMapping::from_file(&file, 0, 0x2000, false, HugePageConfig::None).unwrap();
+        m.write_obj(0x55u8, 0x10).unwrap();
+        m.discard_range(0, 0x1000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x10).unwrap(), 0xaa);
+        let m = MemoryMapping::from_file(&file, 0, 0x2000, true, HugePageConfig::None).unwrap();
+        m.remove_range(0x1000, 0x1000).unwrap();
+        assert_eq!(m.read_obj::<u8>(0x10).unwrap(), 0xaa);
+        assert_eq!(m.read_obj::<u8>(0x1010).unwrap(), 0);
+    }
+
     #[test]
     fn test_write_past_end() {
         let m = MemoryMapping::new(5).unwrap();
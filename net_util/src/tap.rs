@@ -41,6 +41,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -101,6 +102,25 @@ impl Tap {
     /// Tap::open_named("doc-test-tap").unwrap();
     /// ```
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Tap::open_queue(if_name, 0)
+    }
+
+    /// Open `num_queues` queues of a multi-queue TUN/TAP device given the interface name.
+    /// Each queue has its own file descriptor. An interface created without `IFF_MULTI_QUEUE`
+    /// cannot be opened this way.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_multi_queue(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        (0..num_queues)
+            .map(|_| Tap::open_queue(if_name, net_gen::IFF_MULTI_QUEUE))
+            .collect()
+    }
+
+    // Opens a queue of the interface, passing `flags` to TUNSETIFF along with the flags used
+    // for every queue.
+    fn open_queue(if_name: &str, flags: c_uint) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -127,7 +147,7 @@ impl Tap {
             ifrn_name.copy_from_slice(terminated_if_name.as_ref());
             let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
             *ifru_flags =
-                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as c_short;
+                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | flags) as c_short;
         }
 
         // ioctl is safe since we call it with a valid tap fd and check the return
@@ -190,6 +210,30 @@ impl Tap {
         Ok(())
     }
 
+    /// Attach the queue to its multi-queue interface, or detach it so that the kernel stops
+    /// placing frames on it. Queues are attached when opened.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let mut ifreq: net_gen::ifreq = Default::default();
+
+        // We only access one field of the ifru union, hence this is safe.
+        unsafe {
+            let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
+            *ifru_flags = if enabled {
+                net_gen::IFF_ATTACH_QUEUE
+            } else {
+                net_gen::IFF_DETACH_QUEUE
+            } as c_short;
+        }
+
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.tap_file, TUNSETQUEUE(), &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     fn get_ifreq(&self) -> net_gen::ifreq {
         let mut ifreq: net_gen::ifreq = Default::default();
 
@@ -403,6 +447,30 @@ mod tests {
         assert!(faulty_tap.set_offload(0).is_err());
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let next_ip = NEXT_IP.fetch_add(1, Ordering::SeqCst);
+        let name = format!("mqtap{}", next_ip);
+        let taps = Tap::open_multi_queue(&name, 4).unwrap();
+        assert_eq!(taps.len(), 4);
+        for tap in &taps[1..] {
+            assert_eq!(*tap, taps[0]);
+            assert_ne!(tap.as_raw_fd(), taps[0].as_raw_fd());
+        }
+
+        // Queues can be detached and attached again.
+        taps[3].set_queue_enabled(false).unwrap();
+        taps[3].set_queue_enabled(true).unwrap();
+        // Attaching a queue twice fails.
+        assert!(taps[3].set_queue_enabled(true).is_err());
+
+        // The interface exists as long as a queue is open. It cannot be opened as a
+        // single-queue interface.
+        assert!(Tap::open_named(&name).is_err());
+        drop(taps);
+        assert!(Tap::open_named(&name).is_ok());
+    }
+
     #[test]
     fn test_tap_enable() {
         let tap = Tap::new().unwrap();
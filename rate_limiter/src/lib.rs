@@ -27,6 +27,12 @@
 //! configuration parameter provided by the user. The token buckets will never
 //! replenish above their respective `size`.
 //!
+//! Reads and writes can additionally be given their own bandwidth and ops token
+//! buckets, with `update_read_buckets()` and `update_write_buckets()`. Tokens of the
+//! `TokenType::Read*` and `TokenType::Write*` types are then charged both to the
+//! bucket of their direction and to the aggregate bucket of their kind, so the two
+//! directions share the aggregate budget while each stays under its own one.
+//!
 //! Each token bucket can start off with a `one_time_burst` initial extra capacity
 //! on top of their `size`. This initial extra credit does not replenish and
 //! can be used for an initial burst of data.
@@ -233,11 +239,24 @@ impl TokenBucket {
 }
 
 /// Enum that describes the type of token used.
+#[derive(Clone, Copy)]
 pub enum TokenType {
     /// Token type used for bandwidth limiting.
     Bytes,
     /// Token type used for operations/second limiting.
     Ops,
+    /// Token type used for bandwidth limiting of reads. These tokens are charged to the read
+    /// bandwidth bucket and to the `Bytes` one.
+    ReadBytes,
+    /// Token type used for operations/second limiting of reads. These tokens are charged to the
+    /// read ops bucket and to the `Ops` one.
+    ReadOps,
+    /// Token type used for bandwidth limiting of writes. These tokens are charged to the write
+    /// bandwidth bucket and to the `Bytes` one.
+    WriteBytes,
+    /// Token type used for operations/second limiting of writes. These tokens are charged to the
+    /// write ops bucket and to the `Ops` one.
+    WriteOps,
 }
 
 /// Rate Limiter that works on both bandwidth and ops/s limiting.
@@ -258,6 +277,11 @@ pub enum TokenType {
 pub struct RateLimiter {
     bandwidth: Option<TokenBucket>,
     ops: Option<TokenBucket>,
+    // Buckets only charged by the reads or by the writes, on top of the above ones.
+    read_bandwidth: Option<TokenBucket>,
+    read_ops: Option<TokenBucket>,
+    write_bandwidth: Option<TokenBucket>,
+    write_ops: Option<TokenBucket>,
 
     timer_fd: TimerFd,
     // Internal flag that quickly determines timer state.
@@ -266,7 +290,12 @@ pub struct RateLimiter {
 
 impl PartialEq for RateLimiter {
     fn eq(&self, other: &RateLimiter) -> bool {
-        self.bandwidth == other.bandwidth && self.ops == other.ops
+        self.bandwidth == other.bandwidth
+            && self.ops == other.ops
+            && self.read_bandwidth == other.read_bandwidth
+            && self.read_ops == other.read_ops
+            && self.write_bandwidth == other.write_bandwidth
+            && self.write_ops == other.write_ops
     }
 }
 
@@ -274,8 +303,14 @@ impl fmt::Debug for RateLimiter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "RateLimiter {{ bandwidth: {:?}, ops: {:?} }}",
-            self.bandwidth, self.ops
+            "RateLimiter {{ bandwidth: {:?}, ops: {:?}, read_bandwidth: {:?}, read_ops: {:?}, \
+             write_bandwidth: {:?}, write_ops: {:?} }}",
+            self.bandwidth,
+            self.ops,
+            self.read_bandwidth,
+            self.read_ops,
+            self.write_bandwidth,
+            self.write_ops
         )
     }
 }
@@ -301,6 +336,29 @@ impl RateLimiter {
         }
     }
 
+    // Replaces `bucket` with a bucket built from the parameters of `new_bucket`, if any.
+    fn update_bucket(bucket: &mut Option<TokenBucket>, new_bucket: Option<TokenBucket>) {
+        if let Some(b) = new_bucket {
+            *bucket = Self::make_bucket(b.size, b.one_time_burst, b.refill_time);
+        }
+    }
+
+    // Returns the bucket dedicated to the direction of `token_type`, if any, and the aggregate
+    // bucket of its kind.
+    fn buckets(
+        &mut self,
+        token_type: TokenType,
+    ) -> (Option<&mut TokenBucket>, Option<&mut TokenBucket>) {
+        match token_type {
+            TokenType::Bytes => (None, self.bandwidth.as_mut()),
+            TokenType::Ops => (None, self.ops.as_mut()),
+            TokenType::ReadBytes => (self.read_bandwidth.as_mut(), self.bandwidth.as_mut()),
+            TokenType::ReadOps => (self.read_ops.as_mut(), self.ops.as_mut()),
+            TokenType::WriteBytes => (self.write_bandwidth.as_mut(), self.bandwidth.as_mut()),
+            TokenType::WriteOps => (self.write_ops.as_mut(), self.ops.as_mut()),
+        }
+    }
+
     /// Creates a new Rate Limiter that can limit on both bytes/s and ops/s.
     ///
     /// # Arguments
@@ -350,6 +408,10 @@ impl RateLimiter {
         Ok(RateLimiter {
             bandwidth: bytes_token_bucket,
             ops: ops_token_bucket,
+            read_bandwidth: None,
+            read_ops: None,
+            write_bandwidth: None,
+            write_ops: None,
             timer_fd,
             timer_active: false,
         })
@@ -358,18 +420,26 @@ impl RateLimiter {
     /// Attempts to consume tokens and returns whether that is possible.
     ///
     /// If rate limiting is disabled on provided `token_type`, this function will always succeed.
+    /// Read and write tokens are only consumed if both their directional and their aggregate
+    /// buckets have enough budget.
     pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
-        // Identify the required token bucket.
-        let token_bucket = match token_type {
-            TokenType::Bytes => self.bandwidth.as_mut(),
-            TokenType::Ops => self.ops.as_mut(),
-        };
-        // Try to consume from the token bucket.
-        let success = match token_bucket {
-            Some(bucket) => bucket.reduce(tokens),
-            // If bucket is not present rate limiting is disabled on token type,
+        // Identify the required token buckets and try to consume from them.
+        let success = match self.buckets(token_type) {
+            (Some(directional), Some(aggregate)) => {
+                if !directional.reduce(tokens) {
+                    false
+                } else if !aggregate.reduce(tokens) {
+                    // Revert the consume() on the directional bucket.
+                    directional.replenish(tokens);
+                    false
+                } else {
+                    true
+                }
+            }
+            (Some(bucket), None) | (None, Some(bucket)) => bucket.reduce(tokens),
+            // If no bucket is present rate limiting is disabled on token type,
             // consume() will always succeed.
-            None => true,
+            (None, None) => true,
         };
         // When we report budget is over, there will be no further calls here,
         // register a timer to replenish the bucket and resume processing;
@@ -389,13 +459,10 @@ impl RateLimiter {
     /// Can be used to *manually* add tokens to a bucket. Useful for reverting a
     /// `consume()` if needed.
     pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
-        // Identify the required token bucket.
-        let token_bucket = match token_type {
-            TokenType::Bytes => self.bandwidth.as_mut(),
-            TokenType::Ops => self.ops.as_mut(),
-        };
-        // Add tokens to the token bucket.
-        if let Some(bucket) = token_bucket {
+        // Identify the required token buckets.
+        let (directional, aggregate) = self.buckets(token_type);
+        // Add tokens to the token buckets.
+        for bucket in directional.into_iter().chain(aggregate) {
             bucket.replenish(tokens);
         }
     }
@@ -439,13 +506,22 @@ impl RateLimiter {
         // the only method the user has to disable rate limiting. I.e. if the user passes `null`
         // as the token bucket config, the old config is left unchanged.
 
-        if let Some(b) = bytes {
-            self.bandwidth = Self::make_bucket(b.size, b.one_time_burst, b.refill_time);
-        }
+        Self::update_bucket(&mut self.bandwidth, bytes);
+        Self::update_bucket(&mut self.ops, ops);
+    }
 
-        if let Some(b) = ops {
-            self.ops = Self::make_bucket(b.size, b.one_time_burst, b.refill_time);
-        }
+    /// Updates the parameters of the token buckets only charged by the reads. Just like with
+    /// `update_buckets()`, a `None` bucket is left unchanged.
+    pub fn update_read_buckets(&mut self, bytes: Option<TokenBucket>, ops: Option<TokenBucket>) {
+        Self::update_bucket(&mut self.read_bandwidth, bytes);
+        Self::update_bucket(&mut self.read_ops, ops);
+    }
+
+    /// Updates the parameters of the token buckets only charged by the writes. Just like with
+    /// `update_buckets()`, a `None` bucket is left unchanged.
+    pub fn update_write_buckets(&mut self, bytes: Option<TokenBucket>, ops: Option<TokenBucket>) {
+        Self::update_bucket(&mut self.write_bandwidth, bytes);
+        Self::update_bucket(&mut self.write_ops, ops);
     }
 
     /// Returns an immutable view of the inner bandwidth token bucket.
@@ -457,6 +533,26 @@ impl RateLimiter {
     pub fn ops(&self) -> Option<&TokenBucket> {
         self.ops.as_ref()
     }
+
+    /// Returns an immutable view of the inner read bandwidth token bucket.
+    pub fn read_bandwidth(&self) -> Option<&TokenBucket> {
+        self.read_bandwidth.as_ref()
+    }
+
+    /// Returns an immutable view of the inner read ops token bucket.
+    pub fn read_ops(&self) -> Option<&TokenBucket> {
+        self.read_ops.as_ref()
+    }
+
+    /// Returns an immutable view of the inner write bandwidth token bucket.
+    pub fn write_bandwidth(&self) -> Option<&TokenBucket> {
+        self.write_bandwidth.as_ref()
+    }
+
+    /// Returns an immutable view of the inner write ops token bucket.
+    pub fn write_ops(&self) -> Option<&TokenBucket> {
+        self.write_ops.as_ref()
+    }
 }
 
 impl AsRawFd for RateLimiter {
@@ -510,6 +606,10 @@ mod tests {
             match token_type {
                 TokenType::Bytes => self.bandwidth.as_ref(),
                 TokenType::Ops => self.ops.as_ref(),
+                TokenType::ReadBytes => self.read_bandwidth.as_ref(),
+                TokenType::ReadOps => self.read_ops.as_ref(),
+                TokenType::WriteBytes => self.write_bandwidth.as_ref(),
+                TokenType::WriteOps => self.write_ops.as_ref(),
             }
         }
     }
@@ -742,6 +842,57 @@ mod tests {
         //assert!(!l.consume(u64::max_value(), TokenType::Bytes));
     }
 
+    #[test]
+    fn test_rate_limiter_directional() {
+        // rate limiter with an aggregate limit of 1000 bytes/s and 1000 ops/s
+        let mut l = RateLimiter::new(1000, None, 1000, 1000, None, 1000).unwrap();
+        // reads are limited to 600 bytes/s, and writes to 10 ops/s
+        l.update_read_buckets(Some(TokenBucket::new(600, None, 1000)), None);
+        l.update_write_buckets(None, Some(TokenBucket::new(10, None, 1000)));
+        assert_eq!(l.read_bandwidth().unwrap().capacity(), 600);
+        assert!(l.read_ops().is_none());
+        assert!(l.write_bandwidth().is_none());
+        assert_eq!(l.write_ops().unwrap().capacity(), 10);
+
+        // reads are charged to both their own bucket and to the aggregate one
+        assert!(l.consume(500, TokenType::ReadBytes));
+        assert_eq!(
+            l.get_token_bucket(TokenType::ReadBytes).unwrap().budget(),
+            100
+        );
+        assert_eq!(l.get_token_bucket(TokenType::Bytes).unwrap().budget(), 500);
+        // a failed consume() leaves both buckets unchanged
+        assert!(!l.consume(200, TokenType::ReadBytes));
+        assert!(l.is_blocked());
+        assert_eq!(
+            l.get_token_bucket(TokenType::ReadBytes).unwrap().budget(),
+            100
+        );
+        assert_eq!(l.get_token_bucket(TokenType::Bytes).unwrap().budget(), 500);
+        // writes only have the aggregate bandwidth limit
+        assert!(!l.consume(600, TokenType::WriteBytes));
+        assert_eq!(
+            l.get_token_bucket(TokenType::ReadBytes).unwrap().budget(),
+            100
+        );
+        assert!(l.consume(400, TokenType::WriteBytes));
+        assert_eq!(l.get_token_bucket(TokenType::Bytes).unwrap().budget(), 100);
+        // replenishing gives the tokens back to both buckets
+        l.manual_replenish(50, TokenType::ReadBytes);
+        assert_eq!(
+            l.get_token_bucket(TokenType::ReadBytes).unwrap().budget(),
+            150
+        );
+        assert_eq!(l.get_token_bucket(TokenType::Bytes).unwrap().budget(), 150);
+
+        // the write ops bucket runs out before the aggregate one
+        assert!(l.consume(10, TokenType::WriteOps));
+        assert!(!l.consume(1, TokenType::WriteOps));
+        assert!(l.consume(1, TokenType::ReadOps));
+        assert!(l.consume(1, TokenType::Ops));
+        assert_eq!(l.get_token_bucket(TokenType::Ops).unwrap().budget(), 988);
+    }
+
     #[test]
     fn test_update_buckets() {
         let mut x = RateLimiter::new(1000, Some(2000), 1000, 10, Some(20), 1000).unwrap();
@@ -765,6 +916,19 @@ mod tests {
 
         assert_eq!(x.bandwidth, Some(new_bw));
         assert_eq!(x.ops, Some(new_ops));
+
+        // The directional buckets are updated separately.
+        let new_read_bw = TokenBucket::new(456, None, 78);
+        x.update_read_buckets(Some(new_read_bw.clone()), None);
+        x.update_write_buckets(None, None);
+        x.read_bandwidth.as_mut().unwrap().last_update = new_read_bw.last_update;
+        assert_eq!(x.read_bandwidth, Some(new_read_bw));
+        assert_eq!(x.read_ops, None);
+        assert_eq!(x.write_bandwidth, None);
+        assert_eq!(x.write_ops, None);
+        // Zero-sized buckets disable rate limiting.
+        x.update_read_buckets(Some(TokenBucket::new(0, None, 100)), None);
+        assert_eq!(x.read_bandwidth, None);
     }
 
     #[test]
@@ -773,7 +937,8 @@ mod tests {
         assert_eq!(
             format!("{:?}", l),
             format!(
-                "RateLimiter {{ bandwidth: {:?}, ops: {:?} }}",
+                "RateLimiter {{ bandwidth: {:?}, ops: {:?}, read_bandwidth: None, read_ops: None, \
+                 write_bandwidth: None, write_ops: None }}",
                 l.bandwidth(),
                 l.ops()
             ),
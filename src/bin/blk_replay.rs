@@ -0,0 +1,86 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+extern crate clap;
+
+extern crate devices;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::process;
+
+use clap::{App, Arg};
+
+use devices::virtio::{replay, DiskImage, ImageFormat, TraceReader};
+
+// Replays the block trace at `trace_path` against the disk image at `image_path`, printing the
+// requests whose status differs from the traced one. Returns the number of such requests.
+fn run(trace_path: &Path, image_path: &Path, format: ImageFormat) -> io::Result<usize> {
+    let trace = TraceReader::new(BufReader::new(File::open(trace_path)?))?;
+    let file = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let mut disk = DiskImage::new(file, format, image_path)?;
+
+    let replayed = replay(trace, &mut disk)?;
+    let mut mismatches = 0;
+    for outcome in &replayed {
+        if outcome.status != outcome.record.status {
+            mismatches += 1;
+            println!(
+                "Status {} instead of {}: {:?}",
+                outcome.status, outcome.record.status, outcome.record
+            );
+        }
+    }
+    println!(
+        "Replayed {} requests, {} with a different status.",
+        replayed.len(),
+        mismatches
+    );
+    Ok(mismatches)
+}
+
+fn main() {
+    let matches = App::new("blk_replay")
+        .about("Replays the requests of a block device trace against a disk image.")
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Path of the trace recorded by the block device.")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .help("Path of the disk image the requests are replayed against. It is modified.")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("Format of the disk image.")
+                .takes_value(true)
+                .possible_values(&["raw", "qcow2"])
+                .default_value("raw"),
+        )
+        .get_matches();
+
+    let format = match matches.value_of("format") {
+        Some("qcow2") => ImageFormat::Qcow2,
+        _ => ImageFormat::Raw,
+    };
+    // The arguments are required, so the unwraps are safe.
+    match run(
+        Path::new(matches.value_of("trace").unwrap()),
+        Path::new(matches.value_of("image").unwrap()),
+        format,
+    ) {
+        Ok(0) => (),
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("Cannot replay the trace: {}", e);
+            process::exit(2);
+        }
+    }
+}
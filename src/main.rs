@@ -34,6 +34,8 @@ use mmds::MMDS;
 use sys_util::{EventFd, Terminal};
 use vmm::signal_handler::register_signal_handlers;
 use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::migration::MigrationReceiveConfig;
 use vmm::{EventLoopExitReason, Vmm};
 
 const DEFAULT_API_SOCK_PATH: &str = "/tmp/firecracker.socket";
@@ -139,6 +141,14 @@ fn main() {
                 .required(false)
                 .requires("config-file")
         )
+        .arg(
+            Arg::with_name("incoming")
+                .long("incoming")
+                .help("Path to a unix domain socket on which to wait for a microVM migrated from another Firecracker process.")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("config-file")
+        )
         .get_matches();
 
     let bind_path = cmd_arguments
@@ -180,6 +190,8 @@ fn main() {
 
     let no_api = cmd_arguments.is_present("no-api");
 
+    let incoming_socket_path = cmd_arguments.value_of("incoming").map(PathBuf::from);
+
     let api_shared_info = Arc::new(RwLock::new(InstanceInfo {
         state: InstanceState::Uninitialized,
         id: instance_id,
@@ -241,6 +253,7 @@ fn main() {
         to_api,
         seccomp_level,
         vmm_config_json,
+        incoming_socket_path,
     );
 }
 
@@ -256,6 +269,8 @@ fn main() {
 ///                     number) or 2 (filter by syscall number and argument values).
 /// * `config_json` - Optional parameter that can be used to configure the guest machine without
 ///                   using the API socket.
+/// * `incoming_socket_path` - Optional path of the socket on which to receive a migrated microVM
+///                            before handling the API requests.
 fn start_vmm(
     api_shared_info: Arc<RwLock<InstanceInfo>>,
     api_event_fd: EventFd,
@@ -263,6 +278,7 @@ fn start_vmm(
     to_api: Sender<VmmResponse>,
     seccomp_level: u32,
     config_json: Option<String>,
+    incoming_socket_path: Option<PathBuf>,
 ) {
     // If this fails, consider it fatal. Use expect().
     let mut vmm =
@@ -286,6 +302,28 @@ fn start_vmm(
         info!("Successfully started microvm that was configured from one single json");
     }
 
+    // Set while the migrated microVM is being received.
+    #[cfg(target_arch = "x86_64")]
+    let mut receiving_migration = false;
+    #[cfg(target_arch = "x86_64")]
+    {
+        if let Some(socket_path) = incoming_socket_path {
+            vmm.receive_migration(MigrationReceiveConfig { socket_path })
+                .unwrap_or_else(|err| {
+                    error!("Receiving the migrated microvm failed: {}", err);
+                    process::exit(i32::from(vmm::FC_EXIT_CODE_UNEXPECTED_ERROR));
+                });
+            receiving_migration = true;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if incoming_socket_path.is_some() {
+            error!("Migrating microvms is not supported on this architecture");
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        }
+    }
+
     let exit_code = loop {
         match vmm.run_event_loop() {
             Err(e) => {
@@ -293,10 +331,6 @@ fn start_vmm(
                 break vmm::FC_EXIT_CODE_GENERIC_ERROR;
             }
             Ok(exit_reason) => match exit_reason {
-                EventLoopExitReason::Break => {
-                    info!("Gracefully terminated VMM control loop");
-                    break vmm::FC_EXIT_CODE_OK;
-                }
                 EventLoopExitReason::ControlAction => {
                     if let Err(exit_code) =
                         vmm_control_event(&mut vmm, &api_event_fd, &from_api, &to_api)
@@ -304,6 +338,31 @@ fn start_vmm(
                         break exit_code;
                     }
                 }
+                #[cfg(target_arch = "x86_64")]
+                EventLoopExitReason::MigrationDone => {
+                    let result = vmm.migration_result();
+                    if receiving_migration {
+                        receiving_migration = false;
+                        if let Err(err) = result {
+                            error!("Receiving the migrated microvm failed: {}", err);
+                            process::exit(i32::from(vmm::FC_EXIT_CODE_UNEXPECTED_ERROR));
+                        }
+                        info!("Successfully resumed the migrated microvm");
+                    } else {
+                        // The API client waits for the outcome of the migration it requested.
+                        to_api
+                            .send(Box::new(result.map(|_| api_server::VmmData::Empty)))
+                            .map_err(|_| ())
+                            .expect("one-shot channel closed");
+                    }
+                }
+                exit_reason => {
+                    // Keep serving the API requests until the exit status is no longer needed.
+                    if let Some(exit_code) = vmm.handle_exit(exit_reason) {
+                        info!("Gracefully terminated VMM control loop");
+                        break exit_code;
+                    }
+                }
             },
         };
     };
@@ -349,21 +408,64 @@ fn vmm_control_event(
                 SetVsockDevice(vsock_cfg) => vmm
                     .set_vsock_device(vsock_cfg)
                     .map(|_| api_server::VmmData::Empty),
+                SetBalloonDevice(balloon_cfg) => vmm
+                    .set_balloon_device(balloon_cfg)
+                    .map(|_| api_server::VmmData::Empty),
+                UpdateBalloon(balloon_update) => vmm
+                    .update_balloon(balloon_update)
+                    .map(|_| api_server::VmmData::Empty),
+                GetBalloonConfig => vmm.balloon_config().map(api_server::VmmData::BalloonConfig),
+                GetBalloonStats => vmm
+                    .balloon_statistics()
+                    .map(api_server::VmmData::BalloonStatistics),
                 RescanBlockDevice(drive_id) => vmm
                     .rescan_block_device(&drive_id)
                     .map(|_| api_server::VmmData::Empty),
                 StartMicroVm => vmm.start_microvm().map(|_| api_server::VmmData::Empty),
                 #[cfg(target_arch = "x86_64")]
                 SendCtrlAltDel => vmm.send_ctrl_alt_del().map(|_| api_server::VmmData::Empty),
+                Shutdown(shutdown_cfg) => vmm
+                    .shutdown(shutdown_cfg)
+                    .map(|_| api_server::VmmData::Empty),
+                GetExitStatus => vmm.exit_status().map(api_server::VmmData::ExitStatus),
+                MergeOverlay(overlay_merge_cfg) => vmm
+                    .merge_overlay(overlay_merge_cfg)
+                    .map(|_| api_server::VmmData::Empty),
                 SetVmConfiguration(machine_config_body) => vmm
                     .set_vm_configuration(machine_config_body)
                     .map(|_| api_server::VmmData::Empty),
                 UpdateBlockDevicePath(drive_id, path_on_host) => vmm
                     .set_block_device_path(drive_id, path_on_host)
                     .map(|_| api_server::VmmData::Empty),
+                UpdateBlockDevice(drive_update) => vmm
+                    .update_block_device(drive_update)
+                    .map(|_| api_server::VmmData::Empty),
                 UpdateNetworkInterface(netif_update) => vmm
                     .update_net_device(netif_update)
                     .map(|_| api_server::VmmData::Empty),
+                CaptureNetworkInterface(netif_capture) => vmm
+                    .capture_net_device(netif_capture)
+                    .map(|_| api_server::VmmData::Empty),
+                PauseVm => vmm.pause_vm().map(|_| api_server::VmmData::Empty),
+                ResumeVm => vmm.resume_vm().map(|_| api_server::VmmData::Empty),
+                #[cfg(target_arch = "x86_64")]
+                CreateSnapshot(snapshot_create_cfg) => vmm
+                    .create_snapshot(snapshot_create_cfg)
+                    .map(|_| api_server::VmmData::Empty),
+                #[cfg(target_arch = "x86_64")]
+                LoadSnapshot(snapshot_load_cfg) => vmm
+                    .load_snapshot(snapshot_load_cfg)
+                    .map(|_| api_server::VmmData::Empty),
+                #[cfg(target_arch = "x86_64")]
+                MergeSnapshot(snapshot_merge_cfg) => vmm
+                    .merge_snapshot(snapshot_merge_cfg)
+                    .map(|_| api_server::VmmData::Empty),
+                #[cfg(target_arch = "x86_64")]
+                SendMigration(migration_send_cfg) => match vmm.send_migration(migration_send_cfg) {
+                    // The response is sent once the migration completed.
+                    Ok(()) => return Ok(()),
+                    Err(e) => Err(e),
+                },
             };
             // Run the requested action and send back the result.
             to_api
@@ -0,0 +1,553 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{io, mem, ptr};
+
+use libc::{c_long, c_void, MAP_FAILED, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+
+use super::SyscallReturnCode;
+
+// The io_uring system calls have the same numbers on every architecture.
+const SYS_IO_URING_SETUP: c_long = 425;
+/// The number of the `io_uring_enter` system call.
+pub const SYS_IO_URING_ENTER: c_long = 426;
+const SYS_IO_URING_REGISTER: c_long = 427;
+
+// See include/uapi/linux/io_uring.h in the kernel code.
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_EVENTFD: u32 = 4;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_uring_sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_uring_cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// A memory area shared with the kernel.
+struct Mapping {
+    addr: *mut u8,
+    size: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, size: usize, offset: i64) -> io::Result<Mapping> {
+        // This is safe because we map a new memory area and check the result.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+
+    // Returns a pointer to the object at `offset`. The caller must check that the object fits in
+    // the mapping before dereferencing the pointer.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + mem::size_of::<T>() <= self.size);
+        // This is safe because the offset was provided by the kernel for this mapping.
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // This is safe because we mapped the area ourselves and nothing points to it anymore.
+        unsafe {
+            libc::munmap(self.addr as *mut c_void, self.size);
+        }
+    }
+}
+
+/// The result of an operation completed by an `IoUring`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Completion {
+    /// The value passed along with the operation when it was pushed.
+    pub user_data: u64,
+    /// The number of bytes transferred, or the negated errno value if the operation failed.
+    pub result: i32,
+}
+
+/// A safe wrapper around a Linux io_uring (man 7 io_uring), for running file operations
+/// asynchronously.
+///
+/// Operations are pushed to the submission queue, then handed over to the kernel with `submit`.
+/// Their results are collected with `pop_completion`, once the registered eventfd is signaled.
+pub struct IoUring {
+    ring_fd: File,
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+    sq_entries: u32,
+    // The operations pushed since the last submission.
+    unsubmitted: u32,
+    // The operations submitted but not completed yet.
+    in_flight: u32,
+}
+
+// The raw pointers only refer to the mappings owned by this object.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Creates a new io_uring whose submission queue holds at least `entries` operations.
+    pub fn new(entries: u32) -> io::Result<IoUring> {
+        let mut params = io_uring_params::default();
+        // This is safe because the syscall only creates a new file descriptor, `params` is a
+        // valid `struct io_uring_params` and we check the result.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_SETUP,
+                entries,
+                &mut params as *mut io_uring_params,
+            )
+        } as RawFd;
+        let fd = SyscallReturnCode(ret).into_result()?;
+        // This is safe because we checked ret for success and know the kernel gave us an fd that
+        // we own.
+        let ring_fd = unsafe { File::from_raw_fd(fd) };
+
+        let sq_ring = Mapping::new(
+            fd,
+            params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>(),
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq_ring = Mapping::new(
+            fd,
+            params.cq_off.cqes as usize
+                + params.cq_entries as usize * mem::size_of::<io_uring_cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = Mapping::new(
+            fd,
+            params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        Ok(IoUring {
+            ring_fd,
+            sq_ring,
+            cq_ring,
+            sqes,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            sq_entries: params.sq_entries,
+            unsubmitted: 0,
+            in_flight: 0,
+        })
+    }
+
+    /// Signals `eventfd` whenever an operation completes.
+    pub fn register_eventfd(&self, eventfd: &dyn AsRawFd) -> io::Result<()> {
+        let fd = eventfd.as_raw_fd();
+        // This is safe because the kernel only reads one file descriptor from the pointer.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_REGISTER,
+                self.ring_fd.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &fd as *const RawFd,
+                1,
+            )
+        } as i32;
+        SyscallReturnCode(ret).into_empty_result()
+    }
+
+    /// Returns the number of operations that can still be pushed. The number of operations that
+    /// did not complete yet is bounded by the size of the submission queue, so that completions
+    /// are never dropped.
+    pub fn free_slots(&self) -> u32 {
+        self.sq_entries - self.unsubmitted - self.in_flight
+    }
+
+    /// Returns the number of operations pushed that did not complete yet.
+    pub fn pending(&self) -> u32 {
+        self.unsubmitted + self.in_flight
+    }
+
+    /// Pushes the reading of `len` bytes at `offset` in `fd` to `buf`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must point to at least `len` writable bytes until the operation completes.
+    pub unsafe fn push_read(
+        &mut self,
+        fd: RawFd,
+        buf: *mut u8,
+        len: u32,
+        offset: u64,
+        user_data: u64,
+    ) -> io::Result<()> {
+        self.push(io_uring_sqe {
+            opcode: IORING_OP_READ,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Pushes the writing of `len` bytes from `buf` at `offset` in `fd`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must point to at least `len` readable bytes until the operation completes.
+    pub unsafe fn push_write(
+        &mut self,
+        fd: RawFd,
+        buf: *const u8,
+        len: u32,
+        offset: u64,
+        user_data: u64,
+    ) -> io::Result<()> {
+        self.push(io_uring_sqe {
+            opcode: IORING_OP_WRITE,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Pushes the flushing of the data and metadata of `fd` to the storage device.
+    pub fn push_fsync(&mut self, fd: RawFd, user_data: u64) -> io::Result<()> {
+        self.push(io_uring_sqe {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Pushes the flushing of the data of `fd` to the storage device, along with the metadata
+    /// needed for reading it back, like fdatasync(2).
+    pub fn push_fdatasync(&mut self, fd: RawFd, user_data: u64) -> io::Result<()> {
+        self.push(io_uring_sqe {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            rw_flags: IORING_FSYNC_DATASYNC,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    fn push(&mut self, sqe: io_uring_sqe) -> io::Result<()> {
+        if self.free_slots() == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        let mask = self.sq_mask();
+        let tail = self.sq_tail().load(Ordering::Relaxed);
+        let index = tail & mask;
+        // These are safe because the index is masked to the number of entries of the queue, and
+        // the kernel does not read these entries until the tail moves past them.
+        unsafe {
+            ptr::write_volatile(self.sqes.at::<io_uring_sqe>(0).add(index as usize), sqe);
+            ptr::write_volatile(
+                self.sq_ring
+                    .at::<u32>(self.sq_off.array)
+                    .add(index as usize),
+                index,
+            );
+        }
+        self.sq_tail()
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        Ok(())
+    }
+
+    /// Hands the pushed operations over to the kernel and returns their number.
+    pub fn submit(&mut self) -> io::Result<u32> {
+        let mut submitted = 0;
+        while self.unsubmitted > 0 {
+            // This is safe because the kernel only reads the submission queue we own.
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.ring_fd.as_raw_fd(),
+                    self.unsubmitted,
+                    0,
+                    0,
+                    ptr::null::<c_void>(),
+                    0,
+                )
+            } as i32;
+            let count = match SyscallReturnCode(ret).into_result() {
+                Ok(count) => count as u32,
+                Err(ref e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) => return Err(e),
+            };
+            if count == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+            self.unsubmitted -= count;
+            self.in_flight += count;
+            submitted += count;
+        }
+        Ok(submitted)
+    }
+
+    /// Waits for at least `count` operations to complete.
+    pub fn wait(&mut self, count: u32) -> io::Result<()> {
+        // This is safe because no operation is submitted.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_ENTER,
+                self.ring_fd.as_raw_fd(),
+                0,
+                count,
+                IORING_ENTER_GETEVENTS,
+                ptr::null::<c_void>(),
+                0,
+            )
+        } as i32;
+        SyscallReturnCode(ret).into_empty_result()
+    }
+
+    /// Returns the result of the next completed operation, if any.
+    pub fn pop_completion(&mut self) -> Option<Completion> {
+        let head = self.cq_head().load(Ordering::Relaxed);
+        if head == self.cq_tail().load(Ordering::Acquire) {
+            return None;
+        }
+        let mask = self.cq_mask();
+        // This is safe because the index is masked to the number of entries of the queue, and
+        // the kernel does not write this entry again until the head moves past it.
+        let cqe = unsafe {
+            ptr::read_volatile(
+                self.cq_ring
+                    .at::<io_uring_cqe>(self.cq_off.cqes)
+                    .add((head & mask) as usize),
+            )
+        };
+        self.cq_head()
+            .store(head.wrapping_add(1), Ordering::Release);
+        self.in_flight = self.in_flight.saturating_sub(1);
+        Some(Completion {
+            user_data: cqe.user_data,
+            result: cqe.res,
+        })
+    }
+
+    // The accessors below are safe because the offsets were provided by the kernel for the
+    // mappings and the kernel updates the values atomically.
+    fn sq_tail(&self) -> &AtomicU32 {
+        unsafe { &*self.sq_ring.at::<AtomicU32>(self.sq_off.tail) }
+    }
+
+    fn sq_mask(&self) -> u32 {
+        unsafe { *self.sq_ring.at::<u32>(self.sq_off.ring_mask) }
+    }
+
+    fn cq_head(&self) -> &AtomicU32 {
+        unsafe { &*self.cq_ring.at::<AtomicU32>(self.cq_off.head) }
+    }
+
+    fn cq_tail(&self) -> &AtomicU32 {
+        unsafe { &*self.cq_ring.at::<AtomicU32>(self.cq_off.tail) }
+    }
+
+    fn cq_mask(&self) -> u32 {
+        unsafe { *self.cq_ring.at::<u32>(self.cq_off.ring_mask) }
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use EventFd;
+
+    #[test]
+    fn test_struct_sizes() {
+        assert_eq!(mem::size_of::<io_uring_params>(), 120);
+        assert_eq!(mem::size_of::<io_uring_sqe>(), 64);
+        assert_eq!(mem::size_of::<io_uring_cqe>(), 16);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut ring = IoUring::new(4).unwrap();
+        let evt = EventFd::new().unwrap();
+        ring.register_eventfd(&evt).unwrap();
+        assert_eq!(ring.free_slots(), 4);
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xaa; 0x200]).unwrap();
+
+        let src = [0x55u8; 0x100];
+        let mut dst = [0u8; 0x100];
+        unsafe {
+            ring.push_write(file.as_raw_fd(), src.as_ptr(), 0x100, 0x100, 1)
+                .unwrap();
+        }
+        ring.push_fdatasync(file.as_raw_fd(), 2).unwrap();
+        assert_eq!(ring.free_slots(), 2);
+        assert_eq!(ring.submit().unwrap(), 2);
+        assert_eq!(ring.pending(), 2);
+        ring.wait(2).unwrap();
+        assert!(evt.read().unwrap() >= 1);
+
+        let mut completions = vec![
+            ring.pop_completion().unwrap(),
+            ring.pop_completion().unwrap(),
+        ];
+        completions.sort_by_key(|c| c.user_data);
+        assert_eq!(
+            completions,
+            vec![
+                Completion {
+                    user_data: 1,
+                    result: 0x100
+                },
+                Completion {
+                    user_data: 2,
+                    result: 0
+                }
+            ]
+        );
+        assert!(ring.pop_completion().is_none());
+        assert_eq!(ring.pending(), 0);
+
+        unsafe {
+            ring.push_read(file.as_raw_fd(), dst.as_mut_ptr(), 0x100, 0x80, 3)
+                .unwrap();
+        }
+        ring.submit().unwrap();
+        ring.wait(1).unwrap();
+        assert_eq!(
+            ring.pop_completion(),
+            Some(Completion {
+                user_data: 3,
+                result: 0x100
+            })
+        );
+        assert_eq!(&dst[..0x80], &[0xaa; 0x80][..]);
+        assert_eq!(&dst[0x80..], &[0x55; 0x80][..]);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(&contents[0x100..], &[0x55; 0x100][..]);
+
+        // A failed operation reports the error.
+        unsafe {
+            ring.push_read(-1, dst.as_mut_ptr(), 0x100, 0, 4).unwrap();
+        }
+        ring.submit().unwrap();
+        ring.wait(1).unwrap();
+        assert_eq!(ring.pop_completion().unwrap().result, -libc::EBADF);
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let mut ring = IoUring::new(2).unwrap();
+        let file = tempfile().unwrap();
+        ring.push_fsync(file.as_raw_fd(), 0).unwrap();
+        ring.push_fsync(file.as_raw_fd(), 1).unwrap();
+        assert!(ring.push_fsync(file.as_raw_fd(), 2).is_err());
+        ring.submit().unwrap();
+        // The slots are only freed once the operations complete.
+        assert_eq!(ring.free_slots(), 0);
+        ring.wait(2).unwrap();
+        while ring.pop_completion().is_some() {}
+        assert_eq!(ring.free_slots(), 2);
+    }
+}
@@ -14,15 +14,21 @@ use libc::c_int;
 pub mod ioctl;
 
 mod eventfd;
+mod io_uring;
 mod signal;
+mod sock_ctrl_msg;
 mod struct_util;
 mod terminal;
+mod userfaultfd;
 
 pub use eventfd::*;
+pub use io_uring::*;
 pub use ioctl::*;
 pub use signal::*;
+pub use sock_ctrl_msg::*;
 pub use struct_util::{read_struct, read_struct_slice};
 pub use terminal::*;
+pub use userfaultfd::*;
 
 /// Wrapper to interpret syscall exit codes and provide a rustacean `io::Result`
 pub struct SyscallReturnCode(pub c_int);
@@ -0,0 +1,130 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr::copy_nonoverlapping;
+
+use libc::{
+    c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN,
+    CMSG_SPACE, MSG_CMSG_CLOEXEC, SCM_RIGHTS, SOL_SOCKET,
+};
+
+// Large enough to hold the control message carrying a single file descriptor.
+const CMSG_BUFFER_LEN: usize = 64;
+
+/// Sends `buf` over `socket`, along with a copy of the file descriptor `fd`.
+/// Returns the number of bytes of `buf` that were sent.
+pub fn send_with_fd(socket: &UnixStream, buf: &[u8], fd: RawFd) -> io::Result<usize> {
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN / 8];
+    let mut iov = iovec {
+        iov_base: buf.as_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // This is safe because an all zero `msghdr` is valid.
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+    // This is safe because `CMSG_SPACE` only computes a length.
+    msg.msg_controllen = unsafe { CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+
+    // This is safe because the control buffer is large enough for a control message holding one
+    // file descriptor.
+    unsafe {
+        let cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = CMSG_LEN(mem::size_of::<RawFd>() as u32) as usize;
+        copy_nonoverlapping(
+            &fd as *const RawFd as *const u8,
+            CMSG_DATA(cmsg),
+            mem::size_of::<RawFd>(),
+        );
+    }
+
+    // This is safe because every pointer held by `msg` is valid for the duration of the call.
+    let ret = unsafe { sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Receives data sent with `send_with_fd` in `buf`. Returns the number of bytes received and the
+/// received file descriptor, if any. The caller owns the received file descriptor.
+pub fn recv_with_fd(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<RawFd>)> {
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN / 8];
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // This is safe because an all zero `msghdr` is valid.
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = CMSG_BUFFER_LEN;
+
+    // This is safe because every pointer held by `msg` is valid for the duration of the call.
+    let ret = unsafe { recvmsg(socket.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fd = None;
+    // This is safe because the kernel filled the control buffer, whose length is checked by
+    // `CMSG_FIRSTHDR`.
+    unsafe {
+        let cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null() && (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_RIGHTS {
+            let mut received: RawFd = -1;
+            copy_nonoverlapping(
+                CMSG_DATA(cmsg),
+                &mut received as *mut RawFd as *mut u8,
+                mem::size_of::<RawFd>(),
+            );
+            fd = Some(received);
+        }
+    }
+    Ok((ret as usize, fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::FromRawFd;
+
+    extern crate tempfile;
+
+    #[test]
+    fn test_send_recv_fd() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"fd").unwrap();
+
+        assert_eq!(
+            send_with_fd(&sender, b"hello", file.as_raw_fd()).unwrap(),
+            5
+        );
+        let mut buf = [0u8; 16];
+        let (len, fd) = recv_with_fd(&receiver, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        // This is safe because we own the received file descriptor.
+        let mut received = unsafe { File::from_raw_fd(fd.unwrap()) };
+        received.seek(SeekFrom::Start(0)).unwrap();
+        let mut contents = String::new();
+        received.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "fd");
+
+        // Plain data carries no file descriptor.
+        (&sender).write_all(b"data").unwrap();
+        let (len, fd) = recv_with_fd(&receiver, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"data");
+        assert!(fd.is_none());
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::{io, mem};
+
+use libc::{c_void, read, EAGAIN, EEXIST, O_CLOEXEC, O_NONBLOCK};
+
+use super::SyscallReturnCode;
+use ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u32 = 0xaa;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct uffdio_api {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct uffdio_range {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct uffdio_register {
+    range: uffdio_range,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct uffdio_copy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+// The `arg` union of `struct uffd_msg`, as filled for page fault events.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct uffd_msg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    padding: u32,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, uffdio_api);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, uffdio_register);
+ioctl_ior_nr!(UFFDIO_WAKE, UFFDIO, 0x02, uffdio_range);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, uffdio_copy);
+
+/// A safe wrapper around a Linux userfaultfd (man 2 userfaultfd).
+///
+/// Once a memory range is registered, the threads accessing a missing page of the range are put
+/// to sleep and a page fault event is queued on the userfaultfd. The page fault is resolved by
+/// copying the contents of the page in the range with `copy`.
+pub struct UserfaultFd {
+    uffd: File,
+}
+
+impl UserfaultFd {
+    /// Creates a new non blocking userfaultfd and negotiates the API with the kernel.
+    pub fn new() -> io::Result<UserfaultFd> {
+        // This is safe because the syscall only creates a new file descriptor and we check the
+        // result.
+        let ret = unsafe { libc::syscall(libc::SYS_userfaultfd, O_CLOEXEC | O_NONBLOCK) } as RawFd;
+        let fd = SyscallReturnCode(ret).into_result()?;
+        // This is safe because we checked ret for success and know the kernel gave us an fd that
+        // we own.
+        let uffd = UserfaultFd {
+            uffd: unsafe { File::from_raw_fd(fd) },
+        };
+
+        let mut api = uffdio_api {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // This is safe because we made this fd and `api` is a valid `struct uffdio_api`.
+        SyscallReturnCode(unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) })
+            .into_empty_result()?;
+        Ok(uffd)
+    }
+
+    /// Registers the `len` bytes of memory starting at `addr` to be tracked for missing pages.
+    /// Both `addr` and `len` must be page aligned.
+    pub fn register(&self, addr: u64, len: u64) -> io::Result<()> {
+        let mut register = uffdio_register {
+            range: uffdio_range { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // This is safe because we made this fd and `register` is a valid
+        // `struct uffdio_register`.
+        SyscallReturnCode(unsafe {
+            ioctl_with_mut_ref(&self.uffd, UFFDIO_REGISTER(), &mut register)
+        })
+        .into_empty_result()
+    }
+
+    /// Reads the next page fault event and returns the faulting address, or `None` if no event
+    /// is queued.
+    pub fn read_page_fault(&self) -> io::Result<Option<u64>> {
+        loop {
+            let mut msg = uffd_msg::default();
+            // This is safe because we made this fd and the size of the buffer matches the size
+            // passed to the syscall.
+            let ret = unsafe {
+                read(
+                    self.uffd.as_raw_fd(),
+                    &mut msg as *mut uffd_msg as *mut c_void,
+                    mem::size_of::<uffd_msg>(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(EAGAIN) => Ok(None),
+                    _ => Err(err),
+                };
+            }
+            if ret as usize != mem::size_of::<uffd_msg>() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            // Only page fault events are enabled, skip anything else.
+            if msg.event == UFFD_EVENT_PAGEFAULT {
+                return Ok(Some(msg.address));
+            }
+        }
+    }
+
+    /// Atomically copies `len` bytes from `src` to the registered memory at `dst` and wakes up
+    /// the threads waiting for these pages. Both `dst` and `len` must be page aligned.
+    ///
+    /// # Safety
+    ///
+    /// `src` must point to at least `len` readable bytes.
+    pub unsafe fn copy(&self, src: *const u8, dst: u64, len: u64) -> io::Result<()> {
+        let mut copy = uffdio_copy {
+            dst,
+            src: src as u64,
+            len,
+            mode: 0,
+            copy: 0,
+        };
+        if ioctl_with_mut_ref(&self.uffd, UFFDIO_COPY(), &mut copy) == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // Another thread already resolved the page fault, but the kernel does not wake up the
+            // waiting threads in this case.
+            Some(EEXIST) => self.wake(dst, len),
+            _ => Err(err),
+        }
+    }
+
+    /// Wakes up the threads waiting for the `len` bytes of memory starting at `addr`.
+    pub fn wake(&self, addr: u64, len: u64) -> io::Result<()> {
+        let range = uffdio_range { start: addr, len };
+        // This is safe because we made this fd and `range` is a valid `struct uffdio_range`.
+        SyscallReturnCode(unsafe { ioctl_with_ref(&self.uffd, UFFDIO_WAKE(), &range) })
+            .into_empty_result()
+    }
+}
+
+impl AsRawFd for UserfaultFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.uffd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UserfaultFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.uffd.into_raw_fd()
+    }
+}
+
+impl FromRawFd for UserfaultFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        UserfaultFd {
+            uffd: File::from_raw_fd(fd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn test_struct_sizes() {
+        assert_eq!(mem::size_of::<uffdio_api>(), 24);
+        assert_eq!(mem::size_of::<uffdio_register>(), 32);
+        assert_eq!(mem::size_of::<uffdio_copy>(), 40);
+        assert_eq!(mem::size_of::<uffd_msg>(), 32);
+        assert_eq!(UFFDIO_COPY(), 0xc028_aa03);
+        assert_eq!(UFFDIO_WAKE(), 0x8010_aa02);
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let uffd = UserfaultFd::new().unwrap();
+        assert!(uffd.read_page_fault().unwrap().is_none());
+
+        // This is safe because we check the result and unmap the memory at the end of the test.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                2 * PAGE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let addr = addr as u64;
+        uffd.register(addr, 2 * PAGE_SIZE as u64).unwrap();
+        // Unaligned ranges are rejected.
+        assert!(uffd.register(addr + 1, PAGE_SIZE as u64).is_err());
+
+        // The reader sleeps until the page fault is resolved.
+        let reader = thread::spawn(move || {
+            // This is safe because the memory is mapped until the thread is joined.
+            unsafe { *((addr + PAGE_SIZE as u64 + 8) as *const u8) }
+        });
+        let fault_addr = loop {
+            if let Some(fault_addr) = uffd.read_page_fault().unwrap() {
+                break fault_addr;
+            }
+            thread::yield_now();
+        };
+        assert_eq!(
+            fault_addr & !(PAGE_SIZE as u64 - 1),
+            addr + PAGE_SIZE as u64
+        );
+
+        let page = vec![0xaau8; PAGE_SIZE];
+        // This is safe because `page` holds `PAGE_SIZE` bytes.
+        unsafe {
+            uffd.copy(page.as_ptr(), addr + PAGE_SIZE as u64, PAGE_SIZE as u64)
+                .unwrap();
+            // Copying a page that is already present only wakes up the waiting threads.
+            uffd.copy(page.as_ptr(), addr + PAGE_SIZE as u64, PAGE_SIZE as u64)
+                .unwrap();
+        }
+        assert_eq!(reader.join().unwrap(), 0xaa);
+
+        // This is safe because nothing uses the memory anymore.
+        unsafe { libc::munmap(addr as *mut c_void, 2 * PAGE_SIZE) };
+    }
+}
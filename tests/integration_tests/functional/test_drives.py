@@ -233,6 +233,49 @@ def test_patch_drive(test_microvm_with_ssh, network_config):
     assert stdout.readline().decode('utf-8').strip() == size_bytes_str
 
 
+@pytest.mark.parametrize(
+    "cache_type",
+    ["Writeback", "Direct"]
+)
+def test_flush(test_microvm_with_ssh, network_config, cache_type):
+    """Test that the guest can flush a drive with seccomp enabled."""
+    test_microvm = test_microvm_with_ssh
+    test_microvm.spawn()
+
+    # Set up the microVM with 1 vCPUs, 256 MiB of RAM, 1 network iface, a root
+    # file system with the rw permission, and a scratch drive.
+    test_microvm.basic_config()
+
+    _tap, _, _ = test_microvm.ssh_network_config(network_config, '1')
+
+    fs = drive_tools.FilesystemFile(
+        os.path.join(test_microvm.fsfiles, 'scratch')
+    )
+    response = test_microvm.drive.put(
+        drive_id='scratch',
+        path_on_host=test_microvm.create_jailed_resource(fs.path),
+        is_root_device=False,
+        is_read_only=False,
+        cache_type=cache_type
+    )
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    test_microvm.start()
+
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+
+    # `conv=fsync` makes the guest send a flush request once the data is
+    # written.
+    exit_code, _, stderr = ssh_connection.execute_command(
+        'dd if=/dev/urandom of=/dev/vdb bs=4096 count=16 conv=fsync'
+    )
+    assert exit_code == 0, stderr.read().decode('utf-8')
+
+    # Firecracker is still alive after serving the flush.
+    response = test_microvm.machine_cfg.get()
+    assert test_microvm.api_session.is_status_ok(response.status_code)
+
+
 def _check_scratch_size(ssh_connection, size):
     # The scratch block device is /dev/vdb in the guest.
     _, stdout, stderr = ssh_connection.execute_command(
@@ -24,6 +24,24 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_epoll_wait),
             allow_syscall(libc::SYS_exit),
             allow_syscall(libc::SYS_exit_group),
+            // Block devices discard and zero ranges of their backing file.
+            allow_syscall_if(
+                libc::SYS_fallocate,
+                or![
+                    and![Cond::new(
+                        1,
+                        ArgLen::DWORD,
+                        Eq,
+                        super::FALLOC_FL_PUNCH_HOLE | super::FALLOC_FL_KEEP_SIZE
+                    )?],
+                    and![Cond::new(
+                        1,
+                        ArgLen::DWORD,
+                        Eq,
+                        super::FALLOC_FL_ZERO_RANGE | super::FALLOC_FL_KEEP_SIZE
+                    )?],
+                ],
+            ),
             allow_syscall_if(
                 libc::SYS_fcntl,
                 or![and![
@@ -32,6 +50,13 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                 ]],
             ),
             allow_syscall(libc::SYS_fstat),
+            // Snapshot files are flushed to disk before the microVM is resumed.
+            allow_syscall(libc::SYS_fsync),
+            // Guest flush requests sync the data of the drive images and overlays.
+            allow_syscall(libc::SYS_fdatasync),
+            // Snapshot memory files are truncated to the guest memory size, which leaves holes
+            // for the pages missing from diff snapshots.
+            allow_syscall(libc::SYS_ftruncate),
             #[cfg(target_arch = "aarch64")]
             allow_syscall(libc::SYS_newfstatat),
             allow_syscall_if(
@@ -55,17 +80,18 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                 ],
             ),
             allow_syscall(libc::SYS_getrandom),
+            // Block devices using the asynchronous I/O engine submit their requests to an io_uring.
+            allow_syscall(sys_util::SYS_IO_URING_ENTER),
             allow_syscall_if(libc::SYS_ioctl, super::create_ioctl_seccomp_rule()?),
             allow_syscall(libc::SYS_lseek),
-            #[cfg(target_env = "musl")]
+            // Used by the musl allocator, and for releasing the guest memory reclaimed by the
+            // balloon device. MADV_REMOVE releases the guest memory backed by a shared file.
             allow_syscall_if(
                 libc::SYS_madvise,
-                or![and![Cond::new(
-                    2,
-                    ArgLen::DWORD,
-                    Eq,
-                    libc::MADV_DONTNEED as u64
-                )?],],
+                or![
+                    and![Cond::new(2, ArgLen::DWORD, Eq, libc::MADV_DONTNEED as u64)?],
+                    and![Cond::new(2, ArgLen::DWORD, Eq, libc::MADV_REMOVE as u64)?],
+                ],
             ),
             allow_syscall(libc::SYS_mmap),
             allow_syscall(libc::SYS_munmap),
@@ -74,19 +100,45 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_openat),
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_pipe),
+            // The page fault handler reads the missing pages from the snapshot memory file.
+            allow_syscall(libc::SYS_pread64),
+            // The metadata of qcow2 images is read and written in place.
+            allow_syscall(libc::SYS_pwrite64),
             allow_syscall(libc::SYS_read),
             allow_syscall(libc::SYS_readv),
             allow_syscall(libc::SYS_recvfrom),
             // SYS_rt_sigreturn is needed in case a fault does occur, so that the signal handler
             // can return. Otherwise we get stuck in a fault loop.
             allow_syscall(libc::SYS_rt_sigreturn),
+            // The sockets of the migration and of the NBD servers reached over Unix sockets have
+            // timeouts.
+            allow_syscall_if(
+                libc::SYS_setsockopt,
+                or![
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_RCVTIMEO as u64)?,
+                    ],
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_SNDTIMEO as u64)?,
+                    ],
+                ],
+            ),
             allow_syscall(libc::SYS_sigaltstack),
+            // The VMM thread signals the vCPU threads to kick them out of `KVM_RUN`.
+            #[cfg(target_env = "gnu")]
+            allow_syscall(libc::SYS_tgkill),
+            #[cfg(target_env = "musl")]
+            allow_syscall(libc::SYS_tkill),
             allow_syscall_if(
                 libc::SYS_socket,
-                or![and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],],
+                or![and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?]],
             ),
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_stat),
+            // Direct I/O drives query the alignment of their disk image.
+            allow_syscall(super::SYS_STATX),
             allow_syscall(libc::SYS_timerfd_create),
             allow_syscall(libc::SYS_timerfd_settime),
             allow_syscall(libc::SYS_write),
@@ -97,3 +149,34 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
         SeccompAction::Trap,
     )?)
 }
+
+/// The default filter, extended with the syscalls needed by the workers of the block devices for
+/// reaching their NBD servers over TCP and reconnecting to them.
+pub fn nbd_filter() -> Result<SeccompFilter, Error> {
+    let mut filter = default_filter()?;
+    for (syscall_number, rules) in vec![
+        allow_syscall_if(
+            libc::SYS_socket,
+            or![
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
+            ],
+        ),
+        allow_syscall_if(
+            libc::SYS_setsockopt,
+            or![and![
+                Cond::new(1, ArgLen::DWORD, Eq, libc::IPPROTO_TCP as u64)?,
+                Cond::new(2, ArgLen::DWORD, Eq, libc::TCP_NODELAY as u64)?,
+            ]],
+        ),
+        allow_syscall(libc::SYS_sendto),
+        // The client waits before reconnecting to the server.
+        allow_syscall(libc::SYS_nanosleep),
+        allow_syscall(libc::SYS_clock_nanosleep),
+    ]
+    .into_iter()
+    {
+        filter.add_rules(syscall_number, rules)?;
+    }
+    Ok(filter)
+}
@@ -10,7 +10,7 @@ use seccomp::{
 mod macros;
 mod filters;
 
-pub use self::filters::default_filter;
+pub use self::filters::{default_filter, nbd_filter};
 
 /// Applies the configured level of seccomp filtering to the current thread.
 ///
@@ -26,10 +26,34 @@ pub fn set_seccomp_level(seccomp_level: u32) -> Result<(), Error> {
     }
 }
 
+/// Applies the configured level of seccomp filtering to the current thread, which serves the
+/// requests of a block device to its NBD server.
+///
+pub fn set_nbd_seccomp_level(seccomp_level: u32) -> Result<(), Error> {
+    match seccomp_level {
+        SECCOMP_LEVEL_ADVANCED => nbd_filter()?.apply(),
+        SECCOMP_LEVEL_BASIC => nbd_filter()?.allow_all().apply(),
+        SECCOMP_LEVEL_NONE => Ok(()),
+        _ => Err(Error::InvalidLevel),
+    }
+}
+
+// See include/uapi/linux/falloc.h in the kernel code.
+const FALLOC_FL_KEEP_SIZE: u64 = 0x01;
+const FALLOC_FL_PUNCH_HOLE: u64 = 0x02;
+const FALLOC_FL_ZERO_RANGE: u64 = 0x10;
+
 // See include/uapi/asm-generic/fcntl.h in the kernel code.
 const FCNTL_FD_CLOEXEC: u64 = 1;
 const FCNTL_F_SETFD: u64 = 2;
 
+// The number of statx, which the libc crate only defines for some targets. See
+// arch/x86/entry/syscalls/syscall_64.tbl and include/uapi/asm-generic/unistd.h in the kernel code.
+#[cfg(target_arch = "x86_64")]
+const SYS_STATX: libc::c_long = 332;
+#[cfg(target_arch = "aarch64")]
+const SYS_STATX: libc::c_long = 291;
+
 // See include/uapi/linux/futex.h in the kernel code.
 const FUTEX_WAIT: u64 = 0;
 const FUTEX_WAKE: u64 = 1;
@@ -73,11 +97,48 @@ const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
 const KVM_GET_SREGS: u64 = 0x8138_ae83;
 const KVM_GET_LAPIC: u64 = 0x8400_ae8e;
 const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008_ae05;
+const KVM_GET_REGS: u64 = 0x8090_ae81;
+const KVM_GET_MSRS: u64 = 0xc008_ae88;
+const KVM_GET_CPUID2: u64 = 0xc008_ae91;
+const KVM_GET_XSAVE: u64 = 0x9000_aea4;
+const KVM_SET_XSAVE: u64 = 0x5000_aea5;
+const KVM_GET_XCRS: u64 = 0x8188_aea6;
+const KVM_SET_XCRS: u64 = 0x4188_aea7;
+const KVM_GET_MP_STATE: u64 = 0x8004_ae98;
+const KVM_SET_MP_STATE: u64 = 0x4004_ae99;
+const KVM_GET_VCPU_EVENTS: u64 = 0x8040_ae9f;
+const KVM_SET_VCPU_EVENTS: u64 = 0x4040_aea0;
+const KVM_GET_DEBUGREGS: u64 = 0x8080_aea1;
+const KVM_SET_DEBUGREGS: u64 = 0x4080_aea2;
+const KVM_GET_IRQCHIP: u64 = 0xc208_ae62;
+const KVM_SET_IRQCHIP: u64 = 0x8208_ae63;
+const KVM_GET_PIT2: u64 = 0x8070_ae9f;
+const KVM_SET_PIT2: u64 = 0x4070_aea0;
+const KVM_GET_CLOCK: u64 = 0x8030_ae7c;
+const KVM_SET_CLOCK: u64 = 0x4030_ae7b;
+
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKSSZGET: u64 = 0x1268;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
+const TUNSETQUEUE: u64 = 0x4004_54d9;
+
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_SET_FEATURES: u64 = 0x4008_af00;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008_af03;
+const VHOST_SET_VRING_NUM: u64 = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028_af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008_af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008_af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008_af21;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008_af30;
+
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFDIO_WAKE: u64 = 0x8010_aa02;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
 
 fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     Ok(or![
@@ -99,9 +160,19 @@ fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIOCLEX)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKSSZGET)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETQUEUE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_MEM_TABLE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_ADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_KICK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_CALL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_NET_SET_BACKEND)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_LAPIC)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_SREGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_RUN)?],
@@ -111,6 +182,27 @@ fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MSRS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_REGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_SREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_REGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MSRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_CPUID2)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XSAVE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XSAVE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XCRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XCRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MP_STATE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MP_STATE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_VCPU_EVENTS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_VCPU_EVENTS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_DEBUGREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_DEBUGREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_IRQCHIP)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_IRQCHIP)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_PIT2)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_PIT2)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_CLOCK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CLOCK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_WAKE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_COPY)?],
     ])
 }
 
@@ -14,11 +14,15 @@ use std::{fmt, io};
 use arch::aarch64::DeviceInfoForFDT;
 use arch::DeviceType;
 use devices;
-use devices::virtio::TYPE_BLOCK;
+use devices::virtio::vsock::{Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioDevice, Net, RestoreError, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK,
+};
 use devices::{BusDevice, RawIOHandler};
 use kernel_cmdline;
 use kvm_ioctls::{IoEventAddress, VmFd};
 use memory_model::GuestMemory;
+use snapshot::{VirtioBackendState, VirtioDeviceState};
 
 /// Errors for MMIO device manager.
 #[derive(Debug)]
@@ -41,6 +45,10 @@ pub enum Error {
     DeviceNotFound,
     /// Failed to update the mmio device.
     UpdateFailed,
+    /// The saved devices do not match the registered devices.
+    DeviceStateMismatch,
+    /// Failed to restore the state of a device.
+    RestoreDevice(RestoreError),
 }
 
 impl fmt::Display for Error {
@@ -57,6 +65,10 @@ impl fmt::Display for Error {
             Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {}", e),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
+            Error::DeviceStateMismatch => {
+                write!(f, "the saved devices do not match the registered devices")
+            }
+            Error::RestoreDevice(ref e) => write!(f, "failed to restore device state: {:?}", e),
         }
     }
 }
@@ -82,6 +94,7 @@ pub struct MMIODeviceManager {
     last_irq: u32,
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
     raw_io_handlers: HashMap<(DeviceType, String), Arc<Mutex<dyn RawIOHandler>>>,
+    virtio_devices: HashMap<(u32, String), Arc<Mutex<MmioDevice>>>,
 }
 
 impl MMIODeviceManager {
@@ -102,6 +115,7 @@ impl MMIODeviceManager {
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
             raw_io_handlers: HashMap::new(),
+            virtio_devices: HashMap::new(),
         }
     }
 
@@ -117,8 +131,8 @@ impl MMIODeviceManager {
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
-        let mmio_device = devices::virtio::MmioDevice::new(self.guest_mem.clone(), device)
-            .map_err(Error::CreateMmioDevice)?;
+        let mmio_device =
+            MmioDevice::new(self.guest_mem.clone(), device).map_err(Error::CreateMmioDevice)?;
         for (i, queue_evt) in mmio_device.queue_evts().iter().enumerate() {
             let io_addr = IoEventAddress::Mmio(
                 self.mmio_base + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
@@ -133,9 +147,12 @@ impl MMIODeviceManager {
                 .map_err(Error::RegisterIrqFd)?;
         }
 
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
         self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
+            .insert(mmio_device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        self.virtio_devices
+            .insert((type_id, device_id.to_string()), mmio_device);
 
         // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
         // to kernel commandline for virtio mmio devices to get recognized
@@ -286,11 +303,13 @@ impl MMIODeviceManager {
             .get(&(device_type, device_type.to_string()))
     }
 
-    /// Update a drive by rebuilding its config space and rewriting it on the bus.
+    /// Update a drive by rewriting the capacity in its config space on the bus.
     pub fn update_drive(&self, device_id: &str, new_size: u64) -> Result<()> {
         match self.get_device(DeviceType::Virtio(TYPE_BLOCK), device_id) {
             Some(device) => {
-                let data = devices::virtio::build_config_space(new_size);
+                // The capacity, in sectors, is the first field of the config space. The other
+                // fields do not depend on the size of the disk.
+                let data = (new_size / devices::virtio::SECTOR_SIZE).to_le_bytes();
                 let mut busdev = device.lock().map_err(|_| Error::UpdateFailed)?;
 
                 busdev.write(MMIO_CFG_SPACE_OFF, &data[..]);
@@ -301,6 +320,135 @@ impl MMIODeviceManager {
             None => Err(Error::DeviceNotFound),
         }
     }
+
+    /// Asks the balloon device to hold `num_pages` pages by rewriting the target size in its
+    /// config space. The config space can only be written once the guest driver is loaded.
+    pub fn update_balloon(&self, device_id: &str, num_pages: u32) -> Result<()> {
+        match self.get_device(DeviceType::Virtio(TYPE_BALLOON), device_id) {
+            Some(device) => {
+                let mut busdev = device.lock().map_err(|_| Error::UpdateFailed)?;
+
+                busdev.write(MMIO_CFG_SPACE_OFF, &num_pages.to_le_bytes());
+                let mut data = [0u8; 4];
+                busdev.read(MMIO_CFG_SPACE_OFF, &mut data);
+                if u32::from_le_bytes(data) != num_pages {
+                    return Err(Error::UpdateFailed);
+                }
+                busdev.interrupt(devices::virtio::VIRTIO_MMIO_INT_CONFIG);
+
+                Ok(())
+            }
+            None => Err(Error::DeviceNotFound),
+        }
+    }
+
+    /// Returns the number of pages held by the balloon device, as reported by the guest.
+    pub fn balloon_actual_pages(&self, device_id: &str) -> Result<u32> {
+        match self.get_device(DeviceType::Virtio(TYPE_BALLOON), device_id) {
+            Some(device) => {
+                let mut data = [0u8; 4];
+                device
+                    .lock()
+                    .map_err(|_| Error::UpdateFailed)?
+                    .read(MMIO_CFG_SPACE_OFF + 4, &mut data);
+                Ok(u32::from_le_bytes(data))
+            }
+            None => Err(Error::DeviceNotFound),
+        }
+    }
+
+    /// Saves the state of the MMIO transport of every registered virtio device.
+    pub fn save_virtio_devices(&self) -> Result<Vec<VirtioDeviceState>> {
+        let mut states = Vec::with_capacity(self.virtio_devices.len());
+        for ((type_id, device_id), device) in self.virtio_devices.iter() {
+            let mmio_addr = self
+                .id_to_dev_info
+                .get(&(DeviceType::Virtio(*type_id), device_id.clone()))
+                .ok_or(Error::DeviceNotFound)?
+                .addr;
+            let device = device.lock().map_err(|_| Error::UpdateFailed)?;
+            states.push(VirtioDeviceState {
+                type_id: *type_id,
+                device_id: device_id.clone(),
+                mmio_addr,
+                transport: device.save_state(&self.guest_mem),
+                backend: save_backend_state(device.device()),
+            });
+        }
+        states.sort_by_key(|state| state.mmio_addr);
+        Ok(states)
+    }
+
+    /// Restores the state of the registered virtio devices and of their MMIO transports.
+    ///
+    /// The devices must have been registered in the same order, and thus at the same addresses,
+    /// as when their state was saved.
+    pub fn restore_virtio_devices(&self, states: &[VirtioDeviceState]) -> Result<()> {
+        if states.len() != self.virtio_devices.len() {
+            return Err(Error::DeviceStateMismatch);
+        }
+        for state in states {
+            let key = (state.type_id, state.device_id.clone());
+            let dev_info = self
+                .id_to_dev_info
+                .get(&(DeviceType::Virtio(state.type_id), state.device_id.clone()))
+                .ok_or(Error::DeviceNotFound)?;
+            if dev_info.addr != state.mmio_addr {
+                return Err(Error::DeviceStateMismatch);
+            }
+            let mut device = self
+                .virtio_devices
+                .get(&key)
+                .ok_or(Error::DeviceNotFound)?
+                .lock()
+                .map_err(|_| Error::UpdateFailed)?;
+            // The backend is restored first, since restoring the transport may activate it.
+            if let Some(ref backend) = state.backend {
+                restore_backend_state(device.device_mut(), backend)?;
+            }
+            device
+                .restore_state(&state.transport)
+                .map_err(Error::RestoreDevice)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the state of `device`, for the device types that have one.
+fn save_backend_state(device: &dyn VirtioDevice) -> Option<VirtioBackendState> {
+    let device = device.as_any();
+    if let Some(block) = device.downcast_ref::<Block>() {
+        return Some(VirtioBackendState::Block(block.save_state()));
+    }
+    if let Some(net) = device.downcast_ref::<Net>() {
+        return Some(VirtioBackendState::Net(net.save_state()));
+    }
+    if let Some(vsock) = device.downcast_ref::<Vsock<VsockUnixBackend>>() {
+        return Some(VirtioBackendState::Vsock(vsock.save_state()));
+    }
+    device
+        .downcast_ref::<Balloon>()
+        .map(|balloon| VirtioBackendState::Balloon(balloon.save_state()))
+}
+
+// Restores the state of `device`, which must be of the type the state was saved from.
+fn restore_backend_state(device: &mut dyn VirtioDevice, state: &VirtioBackendState) -> Result<()> {
+    let device = device.as_mut_any();
+    match *state {
+        VirtioBackendState::Block(ref state) => device
+            .downcast_mut::<Block>()
+            .map(|block| block.restore_state(state)),
+        VirtioBackendState::Net(ref state) => device
+            .downcast_mut::<Net>()
+            .map(|net| net.restore_state(state)),
+        VirtioBackendState::Vsock(ref state) => device
+            .downcast_mut::<Vsock<VsockUnixBackend>>()
+            .map(|vsock| vsock.restore_state(state)),
+        VirtioBackendState::Balloon(ref state) => device
+            .downcast_mut::<Balloon>()
+            .map(|balloon| balloon.restore_state(state)),
+    }
+    .ok_or(Error::DeviceStateMismatch)
 }
 
 /// Private structure for storing information about the MMIO device registered at some address on the bus.
@@ -620,6 +768,79 @@ mod tests {
             .is_none());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_save_restore_virtio_devices() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let vmm = create_vmm_object();
+        vmm.vm.setup_irqchip().unwrap();
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+
+        let mut device_manager = MMIODeviceManager::new(
+            guest_mem.clone(),
+            &mut 0xd000_0000,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        );
+        device_manager
+            .register_virtio_device(
+                vmm.vm.fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                TYPE_BLOCK,
+                "foo",
+            )
+            .unwrap();
+        let states = device_manager.save_virtio_devices().unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].device_id, "foo");
+        assert_eq!(states[0].type_id, TYPE_BLOCK);
+        assert_eq!(states[0].mmio_addr, 0xd000_0000);
+
+        // Devices registered at other addresses cannot be restored.
+        let mut other_manager = MMIODeviceManager::new(
+            guest_mem.clone(),
+            &mut 0xe000_0000,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        );
+        assert!(other_manager.restore_virtio_devices(&states).is_err());
+        other_manager
+            .register_virtio_device(
+                vmm.vm.fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                TYPE_BLOCK,
+                "foo",
+            )
+            .unwrap();
+        match other_manager.restore_virtio_devices(&states) {
+            Err(Error::DeviceStateMismatch) => (),
+            _ => panic!("Unexpected restore result."),
+        }
+
+        // The ioevents of the first device are still registered with the first VM.
+        let new_vmm = create_vmm_object();
+        new_vmm.vm.setup_irqchip().unwrap();
+        let mut new_manager =
+            MMIODeviceManager::new(guest_mem, &mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        new_manager
+            .register_virtio_device(
+                new_vmm.vm.fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                TYPE_BLOCK,
+                "foo",
+            )
+            .unwrap();
+        // The saved backend state must match the type of the device.
+        let mut block_states = states.clone();
+        block_states[0].backend = Some(VirtioBackendState::Block(Default::default()));
+        match new_manager.restore_virtio_devices(&block_states) {
+            Err(Error::DeviceStateMismatch) => (),
+            _ => panic!("Unexpected restore result."),
+        }
+        assert!(new_manager.restore_virtio_devices(&states).is_ok());
+    }
+
     #[test]
     fn test_raw_io_device() {
         let start_addr1 = GuestAddress(0x0);
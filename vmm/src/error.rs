@@ -7,9 +7,12 @@ use std::fmt::{Display, Formatter};
 use std::io;
 
 use super::{
-    device_manager, vmm_config::boot_source::BootSourceConfigError, vmm_config::drive::DriveError,
-    vmm_config::logger::LoggerConfigError, vmm_config::machine_config::VmConfigError,
-    vmm_config::net::NetworkInterfaceError, vmm_config::vsock::VsockError, vstate,
+    device_manager, vmm_config::balloon::BalloonError,
+    vmm_config::boot_source::BootSourceConfigError, vmm_config::drive::DriveError,
+    vmm_config::instance_info::VmRunStateError, vmm_config::logger::LoggerConfigError,
+    vmm_config::machine_config::VmConfigError, vmm_config::migration::MigrationError,
+    vmm_config::net::NetworkInterfaceError, vmm_config::shutdown::ShutdownError,
+    vmm_config::snapshot::SnapshotError, vmm_config::vsock::VsockError, vstate,
 };
 use devices::legacy::I8042DeviceError;
 use kernel::loader as kernel_loader;
@@ -83,10 +86,14 @@ pub enum StartMicrovmError {
     /// Unable to seek the block device backing file due to invalid permissions or
     /// the file was deleted/corrupted.
     CreateBlockDevice(std::io::Error),
+    /// Cannot create the file recording the requests handled by a block device.
+    CreateBlockTrace(std::io::Error),
     /// Split this at some point.
     /// Internal errors are due to resource exhaustion.
     /// Users errors are due to invalid permissions.
     CreateNetDevice(devices::virtio::Error),
+    /// Failed to create the balloon device.
+    CreateBalloonDevice(std::io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(std::io::Error),
     /// Failed to create the backend for the vsock device.
@@ -99,6 +106,8 @@ pub enum StartMicrovmError {
     EventFd,
     /// Memory regions are overlapping or mmap fails.
     GuestMemory(GuestMemoryError),
+    /// Cannot create or open the file backing the guest memory.
+    GuestMemoryBackend(std::io::Error),
     /// The kernel command line is invalid.
     KernelCmdline(String),
     /// Cannot load kernel due to invalid memory configuration or invalid kernel image.
@@ -108,14 +117,23 @@ pub enum StartMicrovmError {
     LegacyIOBus(device_manager::legacy::Error),
     /// Cannot load command line string.
     LoadCommandline(kernel::cmdline::Error),
+    /// The file backing the guest memory is not on a hugetlbfs mount.
+    MemoryFileNotOnHugetlbfs,
     /// The start command was issued more than once.
     MicroVMAlreadyRunning,
+    /// Cannot start the thread running the transfers of the migrations.
+    MigrationWorker(std::io::Error),
     /// Cannot start the VM because the kernel was not configured.
     MissingKernelConfig,
     /// The net device configuration is missing the tap device.
     NetDeviceNotConfigured,
+    /// The host did not reserve enough huge pages to back the guest memory, holds the number of
+    /// huge pages needed.
+    NotEnoughHugePages(usize),
     /// Cannot open the block device backing file.
     OpenBlockDevice(std::io::Error),
+    /// Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus.
+    RegisterBalloonDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
     RegisterBlockDevice(device_manager::mmio::Error),
     /// Cannot add event to Epoll.
@@ -170,6 +188,8 @@ impl Display for StartMicrovmError {
                  the file was deleted/corrupted. Error number: {}",
                 err
             ),
+            CreateBlockTrace(ref err) => write!(f, "Cannot create the block device trace: {}", err),
+            CreateBalloonDevice(ref err) => write!(f, "Cannot create balloon device: {}", err),
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
             CreateVsockBackend(ref err) => {
                 write!(f, "Cannot create backend for vsock device: {:?}", err)
@@ -189,6 +209,11 @@ impl Display for StartMicrovmError {
                 err_msg = err_msg.replace("\"", "");
                 write!(f, "Invalid Memory Configuration: {}", err_msg)
             }
+            GuestMemoryBackend(ref err) => write!(
+                f,
+                "Cannot create or open the file backing the guest memory. {}",
+                err
+            ),
             KernelCmdline(ref err) => write!(f, "Invalid kernel command line: {}", err),
             KernelLoader(ref err) => {
                 let mut err_msg = format!("{}", err);
@@ -212,17 +237,38 @@ impl Display for StartMicrovmError {
                 err_msg = err_msg.replace("\"", "");
                 write!(f, "Cannot load command line string. {}", err_msg)
             }
+            MemoryFileNotOnHugetlbfs => write!(
+                f,
+                "The file backing the guest memory must be on a hugetlbfs mount when the memory \
+                 is backed by 2M pages."
+            ),
             MicroVMAlreadyRunning => write!(f, "Microvm already running."),
+            MigrationWorker(ref err) => write!(f, "Cannot start the migration worker. {}", err),
             MissingKernelConfig => write!(f, "Cannot start microvm without kernel configuration."),
             NetDeviceNotConfigured => {
                 write!(f, "The net device configuration is missing the tap device.")
             }
+            NotEnoughHugePages(required) => write!(
+                f,
+                "The host did not reserve enough 2M huge pages to back the guest memory ({} \
+                 needed). Huge pages are reserved through /proc/sys/vm/nr_hugepages.",
+                required
+            ),
             OpenBlockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
 
                 write!(f, "Cannot open the block device backing file. {}", err_msg)
             }
+            RegisterBalloonDevice(ref err) => {
+                let mut err_msg = format!("{}", err);
+                err_msg = err_msg.replace("\"", "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus. {}",
+                    err_msg
+                )
+            }
             RegisterBlockDevice(ref err) => {
                 let mut err_msg = format!("{}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -302,6 +348,9 @@ pub enum ErrorKind {
 /// Wrapper for all errors associated with VMM actions.
 #[derive(Debug)]
 pub enum VmmActionError {
+    /// One of the actions `SetBalloonDevice`, `UpdateBalloon`, `GetBalloonConfig` or
+    /// `GetBalloonStats` failed because of bad user input (`ErrorKind::User`).
+    Balloon(ErrorKind, BalloonError),
     /// The action `ConfigureBootSource` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     BootSource(ErrorKind, BootSourceConfigError),
@@ -315,6 +364,9 @@ pub enum VmmActionError {
     /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed either because of bad
     /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     MachineConfig(ErrorKind, VmConfigError),
+    /// Sending or receiving a migration failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Migration(ErrorKind, MigrationError),
     /// The action `InsertNetworkDevice` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     NetworkConfig(ErrorKind, NetworkInterfaceError),
@@ -324,11 +376,43 @@ pub enum VmmActionError {
     /// The action `SendCtrlAltDel` failed. Details are provided by the device-specific error
     /// `I8042DeviceError`.
     SendCtrlAltDel(ErrorKind, I8042DeviceError),
+    /// One of the actions `Shutdown` or `GetExitStatus` failed because of bad user input
+    /// (`ErrorKind::User`).
+    Shutdown(ErrorKind, ShutdownError),
+    /// One of the actions `CreateSnapshot` or `LoadSnapshot` failed either because of bad user
+    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Snapshot(ErrorKind, SnapshotError),
+    /// One of the actions `PauseVm` or `ResumeVm` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    VmRunState(ErrorKind, VmRunStateError),
     /// The action `set_vsock_device` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     VsockConfig(ErrorKind, VsockError),
 }
 
+// It's convenient to turn BalloonErrors into VmmActionErrors directly.
+impl std::convert::From<BalloonError> for VmmActionError {
+    fn from(e: BalloonError) -> Self {
+        use BalloonError::*;
+
+        // This match is used to force developers who add new types of
+        // `BalloonError`s to explicitly consider what kind they should
+        // have. Remove this comment when a match arm that yields
+        // something other than `ErrorKind::User` is added.
+        let kind = match e {
+            // User errors.
+            UpdateNotAllowedPostBoot
+            | DeviceNotFound
+            | StatisticsDisabled
+            | TooManyPagesRequested
+            | UpdateFailed
+            | HugePagesBacking => ErrorKind::User,
+        };
+
+        VmmActionError::Balloon(kind, e)
+    }
+}
+
 // It's convenient to turn DriveErrors into VmmActionErrors directly.
 impl std::convert::From<DriveError> for VmmActionError {
     fn from(e: DriveError) -> Self {
@@ -348,7 +432,16 @@ impl std::convert::From<DriveError> for VmmActionError {
             | BlockDeviceUpdateFailed
             | OperationNotAllowedPreBoot
             | UpdateNotAllowedPostBoot
-            | RootBlockDeviceAlreadyAdded => ErrorKind::User,
+            | RootBlockDeviceAlreadyAdded
+            | InvalidOverlayPath
+            | NoOverlay
+            | CannotMergeOverlay
+            | MergeNotAllowedWhileRunning
+            | InvalidQueueCount
+            | DirectIoNotSupported
+            | NbdFormatNotSupported
+            | NbdUpdateNotAllowedPostBoot
+            | InvalidTracePath => ErrorKind::User,
         };
 
         VmmActionError::DriveConfig(kind, e)
@@ -366,13 +459,45 @@ impl std::convert::From<VmConfigError> for VmmActionError {
         // something other than `ErrorKind::User` is added.
         let kind = match e {
             // User errors.
-            InvalidVcpuCount | InvalidMemorySize | UpdateNotAllowedPostBoot => ErrorKind::User,
+            InvalidVcpuCount
+            | InvalidMemorySize
+            | UpdateNotAllowedPostBoot
+            | MemorySizeNotHugePageAligned
+            | HugePagesWithBalloon
+            | DirtyPageTrackingWithVhost => ErrorKind::User,
         };
 
         VmmActionError::MachineConfig(kind, e)
     }
 }
 
+// It's convenient to turn MigrationErrors into VmmActionErrors directly.
+impl std::convert::From<MigrationError> for VmmActionError {
+    fn from(e: MigrationError) -> Self {
+        use MigrationError::*;
+
+        let kind = match e {
+            // User errors.
+            MicroVMNotRunning
+            | MicroVMAlreadyRunning
+            | DirtyPageTrackingDisabled
+            | VhostNet
+            | Connect(_)
+            | Bind(_)
+            | InvalidMagic
+            | UnsupportedVersion(_) => ErrorKind::User,
+            // Internal errors.
+            Accept(_) | Send(_) | Receive(_) | UnexpectedMessage(_) | MessageTooLong(_)
+            | InvalidMemoryLayout | Serialize(_) | Deserialize(_) | GuestMemory(_) | Vcpu(_)
+            | DeviceEvents(_) | State(_) | Destination(_) | StartWorker(_) | WorkerStopped => {
+                ErrorKind::Internal
+            }
+        };
+
+        VmmActionError::Migration(kind, e)
+    }
+}
+
 // It's convenient to turn NetworkInterfaceErrors into VmmActionErrors directly.
 impl std::convert::From<NetworkInterfaceError> for VmmActionError {
     fn from(e: NetworkInterfaceError) -> Self {
@@ -384,7 +509,18 @@ impl std::convert::From<NetworkInterfaceError> for VmmActionError {
             GuestMacAddressInUse(_)
             | HostDeviceNameInUse(_)
             | DeviceIdNotFound
-            | UpdateNotAllowedPostBoot => ErrorKind::User,
+            | UpdateNotAllowedPostBoot
+            | InvalidQueueCount
+            | VhostWithMmds
+            | VhostWithRateLimiter
+            | VhostWithEgressFilter
+            | EgressFilterWithoutGuestMac
+            | OperationNotAllowedPreBoot
+            | VhostWithCapture
+            | InvalidCaptureLimits
+            | CreateCapture(_)
+            | CaptureFileExists
+            | VhostWithDirtyPageTracking => ErrorKind::User,
             // Internal errors.
             EpollHandlerNotFound(_) | RateLimiterUpdateFailed(_) => ErrorKind::Internal,
             OpenTap(ref te) => match te {
@@ -408,22 +544,29 @@ impl std::convert::From<StartMicrovmError> for VmmActionError {
             // User errors.
             CreateVsockBackend(_)
             | CreateBlockDevice(_)
+            | CreateBlockTrace(_)
             | CreateNetDevice(_)
+            | GuestMemoryBackend(_)
             | KernelCmdline(_)
             | KernelLoader(_)
+            | MemoryFileNotOnHugetlbfs
             | MicroVMAlreadyRunning
             | MissingKernelConfig
             | NetDeviceNotConfigured
+            | NotEnoughHugePages(_)
             | OpenBlockDevice(_)
             | VcpusNotConfigured => ErrorKind::User,
             // Internal errors.
             ConfigureSystem(_)
             | ConfigureVm(_)
+            | CreateBalloonDevice(_)
             | CreateRateLimiter(_)
             | CreateVsockDevice(_)
             | DeviceManager
             | EventFd
             | GuestMemory(_)
+            | MigrationWorker(_)
+            | RegisterBalloonDevice(_)
             | RegisterBlockDevice(_)
             | RegisterEvent
             | RegisterMMIODevice(_)
@@ -446,19 +589,90 @@ impl std::convert::From<StartMicrovmError> for VmmActionError {
     }
 }
 
+// It's convenient to turn SnapshotErrors into VmmActionErrors directly.
+impl std::convert::From<SnapshotError> for VmmActionError {
+    fn from(e: SnapshotError) -> Self {
+        use SnapshotError::*;
+
+        let kind = match e {
+            // User errors.
+            MicroVMNotRunning
+            | MicroVMAlreadyRunning
+            | CreateFile(_)
+            | OpenFile(_)
+            | Deserialize(_)
+            | InvalidMagic
+            | UnsupportedVersion(_)
+            | InvalidMemoryFile
+            | VcpuCountMismatch
+            | DirtyPageTrackingDisabled
+            | NotDiffSnapshot
+            | MemoryFileInUse
+            | VhostNet
+            | PageFaultHandlerSocket(_) => ErrorKind::User,
+            // Internal errors.
+            WriteFile(_) | ReadFile(_) | Serialize(_) | Userfaultfd(_) | GuestMemory(_)
+            | Vcpu(_) | Vm(_) | DeviceManager(_) | BlockRequests(_) | Restore(_) => {
+                ErrorKind::Internal
+            }
+        };
+
+        VmmActionError::Snapshot(kind, e)
+    }
+}
+
+// It's convenient to turn ShutdownErrors into VmmActionErrors directly.
+impl std::convert::From<ShutdownError> for VmmActionError {
+    fn from(e: ShutdownError) -> Self {
+        use ShutdownError::*;
+
+        // This match is used to force developers who add new types of
+        // `ShutdownError`s to explicitly consider what kind they should
+        // have. Remove this comment when a match arm that yields
+        // something other than `ErrorKind::User` is added.
+        let kind = match e {
+            // User errors.
+            InProgress | NotExited | NotSupported => ErrorKind::User,
+        };
+
+        VmmActionError::Shutdown(kind, e)
+    }
+}
+
+// It's convenient to turn VmRunStateErrors into VmmActionErrors directly.
+impl std::convert::From<VmRunStateError> for VmmActionError {
+    fn from(e: VmRunStateError) -> Self {
+        use VmRunStateError::*;
+
+        let kind = match e {
+            // User errors.
+            MicroVMNotRunning | MicroVMNotPaused => ErrorKind::User,
+            // Internal errors.
+            Vcpu(_) | DeviceEvents(_) => ErrorKind::Internal,
+        };
+
+        VmmActionError::VmRunState(kind, e)
+    }
+}
+
 impl VmmActionError {
     /// Returns the error type.
     pub fn kind(&self) -> &ErrorKind {
         use self::VmmActionError::*;
 
         match *self {
+            Balloon(ref kind, _) => kind,
             BootSource(ref kind, _) => kind,
             DriveConfig(ref kind, _) => kind,
             Logger(ref kind, _) => kind,
             MachineConfig(ref kind, _) => kind,
+            Migration(ref kind, _) => kind,
             NetworkConfig(ref kind, _) => kind,
             StartMicrovm(ref kind, _) => kind,
             SendCtrlAltDel(ref kind, _) => kind,
+            Shutdown(ref kind, _) => kind,
+            Snapshot(ref kind, _) => kind,
+            VmRunState(ref kind, _) => kind,
             VsockConfig(ref kind, _) => kind,
         }
     }
@@ -469,13 +683,18 @@ impl Display for VmmActionError {
         use self::VmmActionError::*;
 
         let error = match *self {
-            BootSource(_, ref err) => err as &dyn ToString,
+            Balloon(_, ref err) => err as &dyn ToString,
+            BootSource(_, ref err) => err,
             DriveConfig(_, ref err) => err,
             Logger(_, ref err) => err,
             MachineConfig(_, ref err) => err,
+            Migration(_, ref err) => err,
             NetworkConfig(_, ref err) => err,
             StartMicrovm(_, ref err) => err,
             SendCtrlAltDel(_, ref err) => err,
+            Shutdown(_, ref err) => err,
+            Snapshot(_, ref err) => err,
+            VmRunState(_, ref err) => err,
             VsockConfig(_, ref err) => err,
         };
 
@@ -533,6 +752,27 @@ mod tests {
             error_kind(DriveError::RootBlockDeviceAlreadyAdded),
             ErrorKind::User
         );
+        assert_eq!(error_kind(DriveError::InvalidOverlayPath), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::NoOverlay), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::CannotMergeOverlay), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::MergeNotAllowedWhileRunning),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::InvalidQueueCount), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::DirectIoNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::NbdFormatNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::NbdUpdateNotAllowedPostBoot),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(DriveError::InvalidTracePath), ErrorKind::User);
     }
 
     #[test]
@@ -547,6 +787,15 @@ mod tests {
             error_kind(VmConfigError::UpdateNotAllowedPostBoot),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(VmConfigError::HugePagesWithBalloon),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmConfigError::DirtyPageTrackingWithVhost),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(BalloonError::HugePagesBacking), ErrorKind::User);
     }
 
     #[test]
@@ -570,6 +819,52 @@ mod tests {
             error_kind(NetworkInterfaceError::DeviceIdNotFound),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidQueueCount),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::VhostWithMmds),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::VhostWithRateLimiter),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::VhostWithEgressFilter),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::EgressFilterWithoutGuestMac),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::OperationNotAllowedPreBoot),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::VhostWithCapture),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidCaptureLimits),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::VhostWithDirtyPageTracking),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::CaptureFileExists),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::CreateCapture(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
         // NetworkInterfaceError::OpenTap can be of multiple kinds.
         {
             assert_eq!(
@@ -615,6 +910,111 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_migration_error_conversion() {
+        // Test `MigrationError` conversion.
+        assert_eq!(
+            error_kind(MigrationError::MicroVMNotRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(MigrationError::Connect(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(MigrationError::InvalidMagic), ErrorKind::User);
+        assert_eq!(error_kind(MigrationError::VhostNet), ErrorKind::User);
+        assert_eq!(
+            error_kind(MigrationError::Send(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::Destination(String::new())),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(MigrationError::WorkerStopped),
+            ErrorKind::Internal
+        );
+    }
+
+    #[test]
+    fn test_snapshot_error_conversion() {
+        // Test `SnapshotError` conversion.
+        assert_eq!(
+            error_kind(SnapshotError::MicroVMNotRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::OpenFile(io::Error::from_raw_os_error(0))),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(SnapshotError::InvalidMagic), ErrorKind::User);
+        assert_eq!(error_kind(SnapshotError::VhostNet), ErrorKind::User);
+        assert_eq!(
+            error_kind(SnapshotError::DirtyPageTrackingDisabled),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::UnsupportedVersion(2)),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::WriteFile(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::Vcpu(vstate::Error::VcpuResponseTimeout)),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::PageFaultHandlerSocket(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(SnapshotError::Userfaultfd(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(SnapshotError::BlockRequests(devices::Error::IoError(
+                io::Error::from_raw_os_error(0)
+            ))),
+            ErrorKind::Internal
+        );
+    }
+
+    #[test]
+    fn test_shutdown_error_conversion() {
+        // Test `ShutdownError` conversion.
+        assert_eq!(error_kind(ShutdownError::InProgress), ErrorKind::User);
+        assert_eq!(error_kind(ShutdownError::NotExited), ErrorKind::User);
+        assert_eq!(error_kind(ShutdownError::NotSupported), ErrorKind::User);
+    }
+
+    #[test]
+    fn test_vm_run_state_error_conversion() {
+        // Test `VmRunStateError` conversion.
+        assert_eq!(
+            error_kind(VmRunStateError::MicroVMNotRunning),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmRunStateError::MicroVMNotPaused),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmRunStateError::Vcpu(vstate::Error::VcpuResponseTimeout)),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(VmRunStateError::DeviceEvents(io::Error::from_raw_os_error(
+                0
+            ))),
+            ErrorKind::Internal
+        );
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_start_microvm_error_conversion_cl() {
@@ -638,6 +1038,12 @@ mod tests {
             )),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateBlockTrace(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(StartMicrovmError::CreateNetDevice(
                 devices::virtio::Error::TapOpen(net_util::TapError::CreateTap(
@@ -646,6 +1052,12 @@ mod tests {
             )),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateBalloonDevice(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::CreateRateLimiter(
                 io::Error::from_raw_os_error(0)
@@ -726,6 +1138,26 @@ mod tests {
             )),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::GuestMemoryBackend(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::MemoryFileNotOnHugetlbfs),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::NotEnoughHugePages(64)),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterBalloonDevice(
+                device_manager::mmio::Error::IrqsExhausted
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::RegisterBlockDevice(
                 device_manager::mmio::Error::IrqsExhausted
@@ -786,6 +1218,12 @@ mod tests {
             )),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::MigrationWorker(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::Internal
+        );
     }
 
     #[test]
@@ -38,22 +38,31 @@ extern crate sys_util;
 pub mod default_syscalls;
 mod device_manager;
 pub mod error;
+#[cfg(target_arch = "x86_64")]
+mod migration;
+#[cfg(target_arch = "x86_64")]
+mod page_fault_handler;
 /// Signal handling utilities.
 pub mod signal_handler;
+mod snapshot;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 mod vstate;
 
 use std::collections::HashMap;
-use std::fs::{metadata, File, OpenOptions};
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::PathBuf;
+#[cfg(target_arch = "x86_64")]
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::result;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Barrier, Mutex, RwLock};
-use std::thread;
 use std::time::Duration;
 
 use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
@@ -68,8 +77,12 @@ use device_manager::mmio::MMIODeviceManager;
 use devices::virtio;
 use devices::virtio::vsock::{TYPE_VSOCK, VSOCK_EVENTS_COUNT};
 use devices::virtio::EpollConfigConstructor;
-use devices::virtio::{BLOCK_EVENTS_COUNT, TYPE_BLOCK};
-use devices::virtio::{NET_EVENTS_COUNT, TYPE_NET};
+use devices::virtio::{
+    block_events_count, disk_image_size, is_nbd_uri, BlockTrace, DirectFile, DiskImage, NbdAddress,
+    NbdDisk, OverlayFile, PacketCapture, TYPE_BLOCK,
+};
+use devices::virtio::{net_events_count, TYPE_NET};
+use devices::virtio::{BalloonEpollHandler, BALLOON_EVENTS_COUNT, BALLOON_PAGE_SIZE, TYPE_BALLOON};
 use devices::RawIOHandler;
 use devices::{DeviceEventT, EpollHandler};
 use error::{Error, Result, UserResult};
@@ -80,29 +93,63 @@ use logger::error::LoggerError;
 #[cfg(target_arch = "x86_64")]
 use logger::LogOption;
 use logger::{AppInfo, Level, Metric, LOGGER, METRICS};
-use memory_model::{GuestAddress, GuestMemory};
+use memory_model::{FileBacking, GuestAddress, GuestMemory, HugePageConfig, MemoryMappingError};
+#[cfg(target_arch = "x86_64")]
+use migration::{
+    count_dirty_pages, merge_dirty_pages, Job, JobOutput, MigrationWorker, MAX_PRECOPY_ROUNDS,
+    MAX_STOP_COPY_PAGES,
+};
 use net_util::TapError;
+#[cfg(target_arch = "x86_64")]
+use page_fault_handler::{register_guest_memory, PageFaultHandler};
 #[cfg(target_arch = "aarch64")]
 use serde_json::Value;
+#[cfg(target_arch = "x86_64")]
+use snapshot::{
+    dirty_page_ranges, MemoryRegionState, MicrovmState, SNAPSHOT_MAGIC, SNAPSHOT_VERSION,
+};
 use sys_util::{EventFd, Terminal};
+use vmm_config::balloon::{
+    BalloonDeviceConfig, BalloonError, BalloonStatistics, BalloonUpdateConfig,
+};
 use vmm_config::boot_source::{
     BootSourceConfig, BootSourceConfigError, KernelConfig, DEFAULT_KERNEL_CMDLINE,
 };
 use vmm_config::device_config::DeviceConfigs;
-use vmm_config::drive::{BlockDeviceConfig, BlockDeviceConfigs, DriveError};
-use vmm_config::instance_info::{InstanceInfo, InstanceState};
+use vmm_config::drive::{
+    BlockDeviceConfig, BlockDeviceConfigs, BlockDeviceUpdateConfig, CacheType, DriveError,
+    ImageFormat, OverlayMergeConfig,
+};
+use vmm_config::instance_info::{InstanceInfo, InstanceState, VmRunStateError};
 use vmm_config::logger::{LoggerConfig, LoggerConfigError, LoggerLevel, LoggerWriter};
-use vmm_config::machine_config::{VmConfig, VmConfigError};
+use vmm_config::machine_config::{BackingPageSize, MemoryBackend, VmConfig, VmConfigError};
+#[cfg(target_arch = "x86_64")]
+use vmm_config::migration::{MigrationError, MigrationReceiveConfig, MigrationSendConfig};
 use vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceConfigs, NetworkInterfaceError,
-    NetworkInterfaceUpdateConfig,
+    NetworkInterfaceCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceConfigs,
+    NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+};
+#[cfg(target_arch = "x86_64")]
+use vmm_config::shutdown::{ExitStatus, ShutdownConfig, ShutdownError};
+use vmm_config::snapshot::{
+    SnapshotCreateConfig, SnapshotError, SnapshotLoadConfig, SnapshotMemoryBackend,
+    SnapshotMergeConfig, SnapshotType,
 };
 use vmm_config::vsock::{VsockDeviceConfig, VsockError};
-use vstate::{KvmContext, Vcpu, Vm};
+#[cfg(target_arch = "x86_64")]
+use vstate::VcpuState;
+use vstate::{KvmContext, Vcpu, VcpuEvent, VcpuHandle, VcpuResponse, Vm};
 
 pub use error::{ErrorKind, StartMicrovmError, VmmActionError};
 
 const WRITE_METRICS_PERIOD_SECONDS: u64 = 60;
+// How long to wait for a vCPU to answer an event.
+const VCPU_RESPONSE_TIMEOUT_MS: u64 = 1000;
+// The time, in milliseconds, the exit status of a microVM that was shut down through the API
+// remains available before the Firecracker process exits.
+const EXIT_STATUS_LINGER_MS: u64 = 1000;
+// The balloon device is unique, so it always uses the same id.
+const BALLOON_DEV_ID: &str = "balloon";
 
 /// Success exit code.
 pub const FC_EXIT_CODE_OK: u8 = 0;
@@ -120,16 +167,27 @@ pub const FC_EXIT_CODE_SIGSEGV: u8 = 150;
 pub const FC_EXIT_CODE_INVALID_JSON: u8 = 151;
 /// Bad configuration for microvm's resources, when using a single json.
 pub const FC_EXIT_CODE_BAD_CONFIGURATION: u8 = 152;
+/// Firecracker stopped a guest that did not shut down within the grace period of a `Shutdown`
+/// action.
+pub const FC_EXIT_CODE_SHUTDOWN_TIMEOUT: u8 = 153;
+/// Firecracker was shut down because the page faults of a restored guest memory could not be
+/// served.
+pub const FC_EXIT_CODE_PAGE_FAULT_HANDLER: u8 = 154;
 
 /// Describes all possible reasons which may cause the event loop to return to the caller in
 /// the absence of errors.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum EventLoopExitReason {
     /// A break statement interrupted the event loop during normal execution. This is the
     /// default exit reason.
     Break,
     /// The control action file descriptor has data available for reading.
     ControlAction,
+    /// The guest was still running at the end of the grace period of a `Shutdown` action.
+    ShutdownTimeout,
+    /// The migration being sent or received completed. Its outcome is returned by
+    /// `Vmm::migration_result`.
+    MigrationDone,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -137,8 +195,12 @@ enum EpollDispatch {
     Exit,
     Stdin,
     DeviceHandler(usize, DeviceEventT),
+    DeviceEvents,
     VmmActionRequest,
     WriteMetrics,
+    ShutdownTimer,
+    #[cfg(target_arch = "x86_64")]
+    Migration,
 }
 
 struct MaybeHandler {
@@ -161,6 +223,10 @@ impl MaybeHandler {
 struct EpollContext {
     epoll_raw_fd: RawFd,
     stdin_index: u64,
+    // The events of the devices are registered on a separate epoll fd, nested in `epoll_raw_fd`,
+    // so that they can all be stopped at once while the microVM is paused.
+    device_epoll_raw_fd: RawFd,
+    device_events_index: u64,
     // FIXME: find a different design as this does not scale. This Vec can only grow.
     dispatch_table: Vec<Option<EpollDispatch>>,
     device_handlers: Vec<MaybeHandler>,
@@ -178,26 +244,36 @@ impl EpollContext {
         const EPOLL_EVENTS_LEN: usize = 100;
 
         let epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
+        let device_epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
 
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
         // * 1 stdin event
+        // * 1 event for the nested device epoll fd
         // * 2 queue events for virtio block
         // * 4 for virtio net
-        // The total is 8 elements; allowing spare capacity to avoid reallocations.
+        // The total is 9 elements; allowing spare capacity to avoid reallocations.
         let mut dispatch_table = Vec::with_capacity(20);
         let stdin_index = dispatch_table.len() as u64;
         dispatch_table.push(None);
-        Ok(EpollContext {
+        let device_events_index = dispatch_table.len() as u64;
+        dispatch_table.push(None);
+        let mut epoll_context = EpollContext {
             epoll_raw_fd,
             stdin_index,
+            device_epoll_raw_fd,
+            device_events_index,
             dispatch_table,
             device_handlers: Vec::with_capacity(6),
             device_id_to_handler_id: HashMap::new(),
             events: vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN],
             num_events: 0,
             event_index: 0,
-        })
+        };
+        epoll_context
+            .enable_device_events()
+            .map_err(Error::EpollFd)?;
+        Ok(epoll_context)
     }
 
     fn enable_stdin_event(&mut self) {
@@ -232,6 +308,34 @@ impl EpollContext {
         self.dispatch_table[self.stdin_index as usize] = None;
     }
 
+    /// Starts dispatching the events of the devices.
+    fn enable_device_events(&mut self) -> io::Result<()> {
+        epoll::ctl(
+            self.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.device_epoll_raw_fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, self.device_events_index),
+        )?;
+        self.dispatch_table[self.device_events_index as usize] = Some(EpollDispatch::DeviceEvents);
+        Ok(())
+    }
+
+    /// Stops dispatching the events of the devices. The events are not lost: the devices
+    /// will be notified of them once `enable_device_events` is called.
+    fn disable_device_events(&mut self) -> io::Result<()> {
+        epoll::ctl(
+            self.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            self.device_epoll_raw_fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, self.device_events_index),
+        )?;
+        self.dispatch_table[self.device_events_index as usize] = None;
+        // Drop the events that were already read, as they may belong to devices. Every fd is
+        // level triggered, so the events will be reported again by the next `epoll::wait`.
+        self.event_index = self.num_events;
+        Ok(())
+    }
+
     /// Given a file descriptor `fd`, and an EpollDispatch token `token`,
     /// associate `token` with an `EPOLLIN` event for `fd`, through the
     /// `dispatch_table`.
@@ -297,7 +401,7 @@ impl EpollContext {
             self.device_handlers.len() - 1,
         );
 
-        T::new(dispatch_base, self.epoll_raw_fd, sender)
+        T::new(dispatch_base, self.device_epoll_raw_fd, sender)
     }
 
     fn get_device_handler_by_handler_id(&mut self, id: usize) -> Result<&mut dyn EpollHandler> {
@@ -335,28 +439,44 @@ impl EpollContext {
             .ok_or(Error::DeviceEventHandlerInvalidDowncast)
     }
 
-    /// Gets the next event from `epoll_raw_fd`.
+    /// Gets the next event from `epoll_raw_fd`, or from `device_epoll_raw_fd` when the
+    /// devices have pending events.
     fn get_event(&mut self) -> Result<epoll::Event> {
-        // Check if no events are left in `events`:
-        while self.num_events == self.event_index {
-            // If so, get more events.
-            // Note that if there is an error, we propagate it.
-            self.num_events =
-                epoll::wait(self.epoll_raw_fd, -1, &mut self.events[..]).map_err(Error::Poll)?;
-            // And reset the event_index.
-            self.event_index = 0;
-        }
+        loop {
+            // Check if no events are left in `events`:
+            while self.num_events == self.event_index {
+                // If so, get more events.
+                // Note that if there is an error, we propagate it.
+                self.num_events = epoll::wait(self.epoll_raw_fd, -1, &mut self.events[..])
+                    .map_err(Error::Poll)?;
+                // And reset the event_index.
+                self.event_index = 0;
+            }
+
+            // Now, move our position in the stream.
+            self.event_index += 1;
+            let event = self.events[self.event_index - 1];
 
-        // Now, move our position in the stream.
-        self.event_index += 1;
+            if self.dispatch_table[event.data as usize] != Some(EpollDispatch::DeviceEvents) {
+                // And return the appropriate event.
+                return Ok(event);
+            }
 
-        // And return the appropriate event.
-        Ok(self.events[self.event_index - 1])
+            // The devices have pending events, read them instead. The events left in `events`
+            // are dropped, but every fd is level triggered so they will be reported again.
+            self.num_events = epoll::wait(self.device_epoll_raw_fd, 0, &mut self.events[..])
+                .map_err(Error::Poll)?;
+            self.event_index = 0;
+        }
     }
 }
 
 impl Drop for EpollContext {
     fn drop(&mut self) {
+        let rc = unsafe { libc::close(self.device_epoll_raw_fd) };
+        if rc != 0 {
+            warn!("Cannot close device epoll.");
+        }
         let rc = unsafe { libc::close(self.epoll_raw_fd) };
         if rc != 0 {
             warn!("Cannot close epoll.");
@@ -364,6 +484,158 @@ impl Drop for EpollContext {
     }
 }
 
+// See include/uapi/asm-generic/hugetlb_encode.h in the kernel code.
+const MFD_HUGE_2MB: libc::c_uint = 21 << 26;
+// The size of the hugetlbfs pages backing the guest memory.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+// Creates an anonymous file with `memfd_create`, on hugetlbfs if `huge_pages` asks for it.
+fn create_memfd(huge_pages: HugePageConfig) -> io::Result<File> {
+    let flags = match huge_pages {
+        HugePageConfig::Hugetlbfs2M => libc::MFD_CLOEXEC | libc::MFD_HUGETLB | MFD_HUGE_2MB,
+        HugePageConfig::None | HugePageConfig::Transparent => libc::MFD_CLOEXEC,
+    };
+    // This is safe because the name is a valid NUL terminated string and we check the result.
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, b"guest_mem\0".as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we own the new file descriptor.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+// Opens the file backing the guest memory, which must hold `mem_size` bytes. Returns the file and
+// whether the guest memory is a shared mapping of it, or `None` for anonymous memory.
+fn open_mem_backend(
+    mem_backend: &MemoryBackend,
+    mem_size: usize,
+    huge_pages: HugePageConfig,
+) -> io::Result<Option<(File, bool)>> {
+    match *mem_backend {
+        MemoryBackend::Anonymous => Ok(None),
+        MemoryBackend::Memfd => {
+            let file = create_memfd(huge_pages)?;
+            file.set_len(mem_size as u64)?;
+            Ok(Some((file, true)))
+        }
+        MemoryBackend::File {
+            ref path,
+            shared: true,
+        } => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() < mem_size as u64 {
+                file.set_len(mem_size as u64)?;
+            }
+            Ok(Some((file, true)))
+        }
+        // A private mapping never writes to the file, so the file must already be large enough.
+        MemoryBackend::File {
+            ref path,
+            shared: false,
+        } => Ok(Some((File::open(path)?, false))),
+    }
+}
+
+// Returns the number of 2M huge pages the host can still hand out, if the kernel exposes it.
+fn available_huge_pages() -> Option<usize> {
+    let read_count = |name: &str| -> Option<usize> {
+        std::fs::read_to_string(format!(
+            "/sys/kernel/mm/hugepages/hugepages-2048kB/{}",
+            name
+        ))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+    };
+    Some(read_count("free_hugepages")?.saturating_sub(read_count("resv_hugepages")?))
+}
+
+// Returns whether `path` and `other` point to the same file.
+#[cfg(target_arch = "x86_64")]
+fn mib_to_balloon_pages(amount_mib: u32) -> u32 {
+    amount_mib.saturating_mul(((1 << 20) / BALLOON_PAGE_SIZE) as u32)
+}
+
+fn balloon_pages_to_mib(num_pages: u32) -> u32 {
+    num_pages / ((1 << 20) / BALLOON_PAGE_SIZE) as u32
+}
+
+// Returns whether `path` leads to the open `file`. Only the device and inode numbers are compared,
+// so that this also runs under the seccomp filters, which do not allow resolving the path.
+fn is_file_at(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(file), Ok(other)) => file.dev() == other.dev() && file.ino() == other.ino(),
+        _ => false,
+    }
+}
+
+// Opens the disk image of the drive described by `drive_config`, found at `path_on_host`. The
+// image is only read when the drive has an overlay, which is created if missing.
+fn open_disk_image(drive_config: &BlockDeviceConfig, path_on_host: &Path) -> io::Result<DiskImage> {
+    let writable = !drive_config.is_read_only();
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(writable && drive_config.overlay_path.is_none());
+    if drive_config.cache_type == CacheType::Direct {
+        // Only raw images without overlays are accessed with direct I/O.
+        options.custom_flags(libc::O_DIRECT);
+        return Ok(DiskImage::Direct(DirectFile::new(
+            options.open(path_on_host)?,
+        )?));
+    }
+    let disk_image = if is_nbd_uri(path_on_host) {
+        // Only raw images are served over NBD.
+        if drive_config.format != ImageFormat::Raw {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let address = NbdAddress::parse(path_on_host)?;
+        DiskImage::Nbd(NbdDisk::connect(
+            &address,
+            writable && drive_config.overlay_path.is_none(),
+        )?)
+    } else {
+        let file = options.open(path_on_host)?;
+        DiskImage::new(file, drive_config.format, path_on_host)?
+    };
+    match drive_config.overlay_path {
+        Some(ref overlay_path) => {
+            let overlay = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .create(writable)
+                .open(overlay_path)?;
+            DiskImage::with_overlay(disk_image, overlay)
+        }
+        None => Ok(disk_image),
+    }
+}
+
+// Lays the guest memory `regions` out one after the other in `file`, the same way they are laid
+// out in the memory file of a snapshot.
+fn file_backed_regions(
+    regions: &[(GuestAddress, usize)],
+    file: File,
+    shared: bool,
+) -> Vec<(GuestAddress, usize, Option<FileBacking>)> {
+    let file = Arc::new(file);
+    let mut offset = 0;
+    regions
+        .iter()
+        .map(|&(guest_base, size)| {
+            let file_backing = FileBacking::from_arc(file.clone(), offset, shared);
+            offset += size as u64;
+            (guest_base, size, Some(file_backing))
+        })
+        .collect()
+}
+
 /// Used for configuring a vmm from one single json passed to the Firecracker process.
 #[derive(Deserialize)]
 pub struct VmmConfig {
@@ -379,6 +651,35 @@ pub struct VmmConfig {
     machine_config: Option<VmConfig>,
     #[serde(rename = "vsock")]
     vsock_device: Option<VsockDeviceConfig>,
+    #[serde(rename = "balloon")]
+    balloon_device: Option<BalloonDeviceConfig>,
+}
+
+// A migration being sent, which waits for the worker to complete a pre-copy round or, once
+// `stop_copy` is set, for the destination to resume the microVM.
+#[cfg(target_arch = "x86_64")]
+struct OutgoingMigration {
+    // The pages taken from the dirty page tracking, which are given back once the migration
+    // ended.
+    taken_pages: Vec<Vec<u64>>,
+    round: usize,
+    stop_copy: bool,
+    // Whether the migration paused the vCPUs and the devices.
+    paused: bool,
+}
+
+// A migration being received, which waits for the worker to receive the guest memory and the
+// state of the microVM or, once `result` is set, to report it to the source.
+#[cfg(target_arch = "x86_64")]
+struct IncomingMigration {
+    socket_path: PathBuf,
+    result: Option<UserResult>,
+}
+
+#[cfg(target_arch = "x86_64")]
+enum Migration {
+    Outgoing(OutgoingMigration),
+    Incoming(IncomingMigration),
 }
 
 /// Contains the state and associated methods required for the Firecracker VMM.
@@ -393,7 +694,7 @@ pub struct Vmm {
     // Guest VM core resources.
     guest_memory: Option<GuestMemory>,
     kernel_config: Option<KernelConfig>,
-    vcpus_handles: Vec<thread::JoinHandle<()>>,
+    vcpus_handles: Vec<VcpuHandle>,
     exit_evt: Option<EventFd>,
     vm: Vm,
 
@@ -409,6 +710,25 @@ pub struct Vmm {
 
     write_metrics_event_fd: TimerFd,
 
+    // Bounds the grace period of a shutdown, then the time the exit status remains available.
+    shutdown_timer: TimerFd,
+    shutdown_requested: bool,
+    exit_status: Option<ExitStatus>,
+
+    // Serves the page faults of a guest memory restored from a snapshot with userfaultfd.
+    #[cfg(target_arch = "x86_64")]
+    page_fault_handler: Option<PageFaultHandler>,
+
+    // Runs the transfers of the migrations, and writes to `migration_evt` once a job completed.
+    #[cfg(target_arch = "x86_64")]
+    migration_worker: Option<MigrationWorker>,
+    #[cfg(target_arch = "x86_64")]
+    migration_evt: EventFd,
+    #[cfg(target_arch = "x86_64")]
+    migration: Option<Migration>,
+    #[cfg(target_arch = "x86_64")]
+    migration_result: Option<UserResult>,
+
     // The level of seccomp filtering used. Seccomp filters are loaded before executing guest code.
     seccomp_level: u32,
 }
@@ -437,10 +757,25 @@ impl Vmm {
             )
             .expect("Cannot add write metrics TimerFd to epoll.");
 
+        let shutdown_timer =
+            TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::TimerFd)?;
+
+        epoll_context
+            .add_epollin_event(&shutdown_timer, EpollDispatch::ShutdownTimer)
+            .expect("Cannot add shutdown TimerFd to epoll.");
+
+        #[cfg(target_arch = "x86_64")]
+        let migration_evt = EventFd::new().map_err(Error::EventFd)?;
+        #[cfg(target_arch = "x86_64")]
+        epoll_context
+            .add_epollin_event(&migration_evt, EpollDispatch::Migration)
+            .expect("Cannot add migration EventFd to epoll.");
+
         let device_configs = DeviceConfigs::new(
             BlockDeviceConfigs::new(),
             NetworkInterfaceConfigs::new(),
             None,
+            None,
         );
 
         let kvm = KvmContext::new().map_err(Error::KvmContext)?;
@@ -462,6 +797,19 @@ impl Vmm {
             device_configs,
             epoll_context,
             write_metrics_event_fd,
+            shutdown_timer,
+            shutdown_requested: false,
+            exit_status: None,
+            #[cfg(target_arch = "x86_64")]
+            page_fault_handler: None,
+            #[cfg(target_arch = "x86_64")]
+            migration_worker: None,
+            #[cfg(target_arch = "x86_64")]
+            migration_evt,
+            #[cfg(target_arch = "x86_64")]
+            migration: None,
+            #[cfg(target_arch = "x86_64")]
+            migration_result: None,
             seccomp_level,
         })
     }
@@ -474,7 +822,7 @@ impl Vmm {
     fn update_drive_handler(
         &mut self,
         drive_id: &str,
-        disk_image: File,
+        disk_image: DiskImage,
     ) -> result::Result<(), DriveError> {
         let handler = self
             .epoll_context
@@ -512,13 +860,11 @@ impl Vmm {
         // `unwrap` is suitable for this context since this should be called only after the
         // device manager has been initialized.
         let device_manager = self.mmio_device_manager.as_mut().unwrap();
+        let seccomp_level = self.seccomp_level;
 
         for drive_config in self.device_configs.block.config_list.iter_mut() {
             // Add the block device from file.
-            let block_file = OpenOptions::new()
-                .read(true)
-                .write(!drive_config.is_read_only)
-                .open(&drive_config.path_on_host)
+            let disk_image = open_disk_image(drive_config, &drive_config.path_on_host)
                 .map_err(OpenBlockDevice)?;
 
             if drive_config.is_root_device && drive_config.get_partuuid().is_some() {
@@ -540,7 +886,7 @@ impl Vmm {
             let epoll_config = epoll_context.allocate_tokens_for_virtio_device(
                 TYPE_BLOCK,
                 &drive_config.drive_id,
-                BLOCK_EVENTS_COUNT,
+                block_events_count(drive_config.num_queues),
             );
             let rate_limiter = drive_config
                 .rate_limiter
@@ -548,15 +894,45 @@ impl Vmm {
                 .transpose()
                 .map_err(CreateRateLimiter)?;
 
-            let block_box = Box::new(
-                devices::virtio::Block::new(
-                    block_file,
-                    drive_config.is_read_only,
-                    epoll_config,
-                    rate_limiter,
-                )
-                .map_err(CreateBlockDevice)?,
-            );
+            let mut block = devices::virtio::Block::new(
+                disk_image,
+                drive_config.is_read_only,
+                epoll_config,
+                rate_limiter,
+                drive_config.io_engine,
+                drive_config.num_queues,
+                drive_config.cache_type,
+                METRICS.block_devices.register(&drive_config.drive_id),
+            )
+            .map_err(CreateBlockDevice)?;
+            // The seccomp filters of the VMM thread do not allow starting threads, nor reaching
+            // NBD servers over TCP, so the worker serving the drive is started beforehand.
+            if is_nbd_uri(&drive_config.path_on_host) {
+                block
+                    .start_nbd_worker(move || {
+                        // Load seccomp filters for this thread.
+                        // Execution panics if filters cannot be loaded, use --seccomp-level=0 if
+                        // skipping filters altogether is the desired behaviour.
+                        if let Err(e) = default_syscalls::set_nbd_seccomp_level(seccomp_level) {
+                            panic!(
+                                "Failed to set the requested seccomp filters on the NBD worker: \
+                                 Error: {}",
+                                e
+                            );
+                        }
+                    })
+                    .map_err(CreateBlockDevice)?;
+            }
+            if let Some(ref trace_path) = drive_config.trace_path {
+                let trace_file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(trace_path)
+                    .map_err(CreateBlockTrace)?;
+                block.set_trace(BlockTrace::new(trace_file).map_err(CreateBlockTrace)?);
+            }
+            let block_box = Box::new(block);
             device_manager
                 .register_virtio_device(
                     self.vm.fd(),
@@ -585,10 +961,11 @@ impl Vmm {
             let epoll_config = self.epoll_context.allocate_tokens_for_virtio_device(
                 TYPE_NET,
                 &cfg.iface_id,
-                NET_EVENTS_COUNT,
+                net_events_count(cfg.num_queues),
             );
 
             let allow_mmds_requests = cfg.allow_mmds_requests();
+            let vhost = cfg.vhost;
 
             let rx_rate_limiter = cfg
                 .rx_rate_limiter
@@ -603,20 +980,26 @@ impl Vmm {
                 .map_err(CreateRateLimiter)?;
 
             let vm_fd = self.vm.fd();
-            cfg.open_tap()
+            cfg.open_taps()
                 .map_err(|_| NetDeviceNotConfigured)
-                .and_then(|tap| {
-                    let net_box = Box::new(
-                        devices::virtio::Net::new_with_tap(
-                            tap,
-                            cfg.guest_mac(),
-                            epoll_config,
-                            rx_rate_limiter,
-                            tx_rate_limiter,
-                            allow_mmds_requests,
-                        )
-                        .map_err(CreateNetDevice)?,
-                    );
+                .and_then(|taps| {
+                    let mut net = devices::virtio::Net::new_with_taps(
+                        taps,
+                        cfg.guest_mac(),
+                        epoll_config,
+                        rx_rate_limiter,
+                        tx_rate_limiter,
+                        allow_mmds_requests,
+                        METRICS.net_devices.register(&cfg.iface_id),
+                    )
+                    .map_err(CreateNetDevice)?;
+                    if vhost {
+                        net.enable_vhost().map_err(CreateNetDevice)?;
+                    }
+                    if let Some(egress_filter) = cfg.egress_filter() {
+                        net.set_egress_filter(egress_filter);
+                    }
+                    let net_box = Box::new(net);
 
                     device_manager
                         .register_virtio_device(
@@ -672,6 +1055,46 @@ impl Vmm {
         Ok(())
     }
 
+    fn attach_balloon_device(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        let kernel_config = self
+            .kernel_config
+            .as_mut()
+            .ok_or(StartMicrovmError::MissingKernelConfig)?;
+
+        // `unwrap` is suitable for this context since this should be called only after the
+        // device manager has been initialized.
+        let device_manager = self.mmio_device_manager.as_mut().unwrap();
+
+        if let Some(cfg) = &self.device_configs.balloon {
+            let epoll_config = self.epoll_context.allocate_tokens_for_virtio_device(
+                TYPE_BALLOON,
+                BALLOON_DEV_ID,
+                BALLOON_EVENTS_COUNT,
+            );
+            let balloon_box = Box::new(
+                devices::virtio::Balloon::new(
+                    mib_to_balloon_pages(cfg.amount_mib),
+                    cfg.deflate_on_oom,
+                    cfg.free_page_reporting,
+                    cfg.stats_polling_interval_s,
+                    epoll_config,
+                )
+                .map_err(StartMicrovmError::CreateBalloonDevice)?,
+            );
+            device_manager
+                .register_virtio_device(
+                    self.vm.fd(),
+                    balloon_box,
+                    &mut kernel_config.cmdline,
+                    TYPE_BALLOON,
+                    BALLOON_DEV_ID,
+                )
+                .map_err(StartMicrovmError::RegisterBalloonDevice)?;
+        }
+
+        Ok(())
+    }
+
     fn set_kernel_config(&mut self, kernel_config: KernelConfig) {
         self.kernel_config = Some(kernel_config);
     }
@@ -712,9 +1135,52 @@ impl Vmm {
                 ))?
                 << 20;
             let arch_mem_regions = arch::arch_memory_regions(mem_size);
-            self.set_guest_memory(
-                GuestMemory::new(&arch_mem_regions).map_err(StartMicrovmError::GuestMemory)?,
-            );
+            let huge_pages = self
+                .vm_config
+                .backing_page_size
+                .unwrap_or(BackingPageSize::Size4K)
+                .huge_pages();
+            let required_huge_pages = mem_size / HUGE_PAGE_SIZE;
+            // The pages of an existing hugetlbfs file may already be allocated, so only check
+            // the memory allocated on the spot.
+            if huge_pages == HugePageConfig::Hugetlbfs2M
+                && match self.vm_config.mem_backend {
+                    Some(MemoryBackend::File { .. }) => false,
+                    _ => true,
+                }
+                && available_huge_pages().map_or(false, |count| count < required_huge_pages)
+            {
+                return Err(StartMicrovmError::NotEnoughHugePages(required_huge_pages));
+            }
+
+            let mem_file = match self.vm_config.mem_backend {
+                Some(ref mem_backend) => open_mem_backend(mem_backend, mem_size, huge_pages)
+                    .map_err(StartMicrovmError::GuestMemoryBackend)?,
+                None => None,
+            };
+            let regions: Vec<(GuestAddress, usize, Option<FileBacking>)> = match mem_file {
+                Some((file, shared)) => file_backed_regions(&arch_mem_regions, file, shared),
+                None => arch_mem_regions
+                    .iter()
+                    .map(|&(guest_base, size)| (guest_base, size, None))
+                    .collect(),
+            };
+            let guest_memory =
+                GuestMemory::new_with_huge_pages(&regions, huge_pages).map_err(|e| match e {
+                    memory_model::GuestMemoryError::MemoryMappingFailed(
+                        MemoryMappingError::NotOnHugetlbfs,
+                    ) => StartMicrovmError::MemoryFileNotOnHugetlbfs,
+                    // The huge pages are reserved when the memory is mapped.
+                    memory_model::GuestMemoryError::MemoryMappingFailed(
+                        MemoryMappingError::SystemCallFailed(ref err),
+                    ) if huge_pages == HugePageConfig::Hugetlbfs2M
+                        && err.raw_os_error() == Some(libc::ENOMEM) =>
+                    {
+                        StartMicrovmError::NotEnoughHugePages(required_huge_pages)
+                    }
+                    e => StartMicrovmError::GuestMemory(e),
+                })?;
+            self.set_guest_memory(guest_memory);
         }
 
         self.vm
@@ -725,6 +1191,7 @@ impl Vmm {
                         memory_model::GuestMemoryError::MemoryNotInitialized,
                     ))?,
                 &self.kvm,
+                self.vm_config.track_dirty_pages.unwrap_or(false),
             )
             .map_err(StartMicrovmError::ConfigureVm)?;
         Ok(())
@@ -766,6 +1233,7 @@ impl Vmm {
         self.attach_block_devices()?;
         self.attach_net_devices()?;
         self.attach_vsock_devices()?;
+        self.attach_balloon_device()?;
 
         Ok(())
     }
@@ -887,6 +1355,7 @@ impl Vmm {
                     cpu_index,
                     self.vm.fd(),
                     self.vm.supported_cpuid().clone(),
+                    self.vm.msr_list().to_vec(),
                     self.pio_device_manager.io_bus.clone(),
                     request_ts.clone(),
                 )
@@ -922,12 +1391,14 @@ impl Vmm {
             "The number of vCPU fds is corrupted!"
         );
 
+        Vcpu::register_kick_signal_handler().map_err(StartMicrovmError::Vcpu)?;
+
         self.vcpus_handles.reserve(vcpu_count as usize);
 
         let vcpus_thread_barrier = Arc::new(Barrier::new((vcpu_count + 1) as usize));
 
         // We're going in reverse so we can `.pop()` on the vec and still maintain order.
-        for cpu_id in (0..vcpu_count).rev() {
+        for _ in 0..vcpu_count {
             let vcpu_thread_barrier = vcpus_thread_barrier.clone();
 
             // On x86_64 we support i8042. Get a clone of its reset event.
@@ -941,10 +1412,15 @@ impl Vmm {
                 .get_reset_evt_clone()
                 .map_err(|_| StartMicrovmError::EventFd)?;
 
-            // On aarch64 we don't support i8042. Use a dummy event nobody touches until
-            // we get i8042 support.
+            // On aarch64 we don't support i8042. Use the exit event registered with
+            // `register_events`.
             #[cfg(target_arch = "aarch64")]
-            let vcpu_exit_evt = EventFd::new().map_err(|_| StartMicrovmError::EventFd)?;
+            let vcpu_exit_evt = self
+                .exit_evt
+                .as_ref()
+                .ok_or(StartMicrovmError::EventFd)?
+                .try_clone()
+                .map_err(|_| StartMicrovmError::EventFd)?;
 
             // `unwrap` is safe since we are asserting that the `vcpu_count` is equal to the number
             // of items of `vcpus` vector.
@@ -954,17 +1430,22 @@ impl Vmm {
                 vcpu.set_mmio_bus(mmio_device_manager.bus.clone());
             }
 
-            let seccomp_level = self.seccomp_level;
             self.vcpus_handles.push(
-                thread::Builder::new()
-                    .name(format!("fc_vcpu{}", cpu_id))
-                    .spawn(move || {
-                        vcpu.run(vcpu_thread_barrier, seccomp_level, vcpu_exit_evt);
-                    })
-                    .map_err(StartMicrovmError::VcpuSpawn)?,
+                vcpu.start_threaded(vcpu_thread_barrier, self.seccomp_level, vcpu_exit_evt)
+                    .map_err(StartMicrovmError::Vcpu)?,
             );
         }
 
+        // The seccomp filters of the VMM thread do not allow starting threads, so the migration
+        // worker must be started beforehand.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.vm_config.track_dirty_pages.unwrap_or(false) {
+                self.start_migration_worker()
+                    .map_err(StartMicrovmError::MigrationWorker)?;
+            }
+        }
+
         // Load seccomp filters for the VMM thread.
         // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
         // altogether is the desired behaviour.
@@ -987,12 +1468,13 @@ impl Vmm {
             memory_model::GuestMemoryError::MemoryNotInitialized,
         ))?;
 
-        let entry_addr = kernel_loader::load_kernel(
-            vm_memory,
-            &mut kernel_config.kernel_file,
-            arch::get_kernel_start(),
-        )
-        .map_err(KernelLoader)?;
+        let kernel_file = kernel_config
+            .kernel_file
+            .as_mut()
+            .ok_or(MissingKernelConfig)?;
+        let entry_addr =
+            kernel_loader::load_kernel(vm_memory, kernel_file, arch::get_kernel_start())
+                .map_err(KernelLoader)?;
 
         // This is x86_64 specific since on aarch64 the commandline will be specified through the FDT.
         #[cfg(target_arch = "x86_64")]
@@ -1071,6 +1553,18 @@ impl Vmm {
 
             self.exit_evt = Some(exit_poll_evt_fd);
         }
+        #[cfg(target_arch = "aarch64")]
+        {
+            // The vCPUs write to this event when they stop running, e.g. after the guest
+            // powered off through PSCI.
+            let exit_evt = EventFd::new().map_err(|_| StartMicrovmError::EventFd)?;
+
+            self.epoll_context
+                .add_epollin_event(&exit_evt, EpollDispatch::Exit)
+                .map_err(|_| StartMicrovmError::RegisterEvent)?;
+
+            self.exit_evt = Some(exit_evt);
+        }
 
         self.epoll_context.enable_stdin_event();
 
@@ -1151,6 +1645,12 @@ impl Vmm {
             .expect("Failed to start microVM because shared info couldn't be written due to poisoned lock")
             .state = InstanceState::Running;
 
+        self.arm_write_metrics_timer();
+
+        Ok(())
+    }
+
+    fn arm_write_metrics_timer(&mut self) {
         // Arm the log write timer.
         // TODO: the timer does not stop on InstanceStop.
         let timer_state = TimerState::Periodic {
@@ -1164,8 +1664,6 @@ impl Vmm {
         if LOGGER.log_metrics().is_err() {
             METRICS.logger.missed_metrics_count.inc();
         }
-
-        Ok(())
     }
 
     /// Injects CTRL+ALT+DEL keystroke combo in the i8042 device.
@@ -1179,76 +1677,1093 @@ impl Vmm {
             .map_err(|e| VmmActionError::SendCtrlAltDel(ErrorKind::Internal, e))
     }
 
-    /// Waits for all vCPUs to exit and terminates the Firecracker process.
-    pub fn stop(&mut self, exit_code: i32) {
-        info!("Vmm is stopping.");
-
-        if let Err(e) = self.stdin_handle.lock().set_canon_mode() {
-            warn!("Cannot set canonical mode for the terminal. {:?}", e);
+    /// Asks the guest to shut down and stops the microVM if the guest is still running at the
+    /// end of the grace period. The exit status remains available for a while once the microVM
+    /// stopped, before the Firecracker process exits.
+    pub fn shutdown(&mut self, config: ShutdownConfig) -> UserResult {
+        info!("VMM received shutdown command");
+        if self.shutdown_requested {
+            return Err(ShutdownError::InProgress.into());
+        }
+        // There is no power button device to ask the guest to shut down with yet.
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.is_instance_initialized() {
+                return Err(ShutdownError::NotSupported.into());
+            }
         }
 
-        // Log the metrics before exiting.
-        if let Err(e) = LOGGER.log_metrics() {
-            error!("Failed to log metrics while stopping: {}", e);
+        match self.instance_state() {
+            InstanceState::Uninitialized | InstanceState::Starting => {
+                // There is no guest to wait for.
+                self.shutdown_requested = true;
+                self.handle_exit(EventLoopExitReason::Break);
+                return Ok(());
+            }
+            // The guest can only act on the request while it runs.
+            InstanceState::Paused => self.resume_vm()?,
+            InstanceState::Running => (),
         }
 
-        // Exit from Firecracker using the provided exit code. Safe because we're terminating
-        // the process anyway.
-        unsafe {
-            libc::_exit(exit_code);
+        #[cfg(target_arch = "x86_64")]
+        self.request_guest_shutdown();
+        self.shutdown_requested = true;
+        self.arm_shutdown_timer(config.grace_period_ms);
+
+        Ok(())
+    }
+
+    // The microVM is stopped at the end of the grace period anyway, so failing to reach the
+    // guest is not an error.
+    #[cfg(target_arch = "x86_64")]
+    fn request_guest_shutdown(&mut self) {
+        if let Err(e) = self
+            .pio_device_manager
+            .i8042
+            .lock()
+            .expect("i8042 lock was poisoned")
+            .trigger_ctrl_alt_del()
+        {
+            warn!("Cannot ask the guest to shut down. {}", e);
         }
     }
 
-    fn is_instance_initialized(&self) -> bool {
-        let error_string = "Cannot check instance initialization as shared info lock is poisoned";
-        self.shared_info.read().expect(error_string).state != InstanceState::Uninitialized
+    fn arm_shutdown_timer(&mut self, timeout_ms: u64) {
+        // A zero timeout would disarm the timer.
+        let timer_state = TimerState::Oneshot(Duration::from_millis(std::cmp::max(timeout_ms, 1)));
+        self.shutdown_timer
+            .set_state(timer_state, SetTimeFlags::Default);
     }
 
-    fn handle_stdin_event(&self, buffer: &[u8]) -> Result<()> {
-        match self.get_serial_device() {
-            Some(serial) => {
-                // Use expect() to panic if another thread panicked
-                // while holding the lock.
-                serial
-                    .lock()
-                    .expect("Failed to process stdin event due to poisoned lock")
-                    .raw_input(buffer)
-                    .map_err(Error::Serial)?;
+    /// Records the exit status of the microVM once the event loop returned `exit_reason`.
+    /// Returns the exit code of the Firecracker process, or `None` if the exit status must
+    /// first remain available to the API client that requested the shutdown.
+    pub fn handle_exit(&mut self, exit_reason: EventLoopExitReason) -> Option<u8> {
+        if let Some(ref exit_status) = self.exit_status {
+            return Some(exit_status.exit_code);
+        }
+
+        let exit_code = match exit_reason {
+            EventLoopExitReason::ShutdownTimeout => {
+                warn!("The guest did not shut down within the grace period. Stopping the microVM.");
+                FC_EXIT_CODE_SHUTDOWN_TIMEOUT
             }
-            None => warn!("Unable to handle stdin event: no serial device available"),
+            _ => FC_EXIT_CODE_OK,
+        };
+        self.exit_status = Some(ExitStatus {
+            exit_reason,
+            exit_code,
+        });
+        if !self.shutdown_requested {
+            return Some(exit_code);
         }
 
-        Ok(())
+        if exit_reason == EventLoopExitReason::ShutdownTimeout {
+            // Keep the guest from running while the exit status is available.
+            if let Err(e) = self.pause_vm() {
+                warn!("Cannot pause the microVM. {}", e);
+            }
+        }
+        self.arm_shutdown_timer(EXIT_STATUS_LINGER_MS);
+
+        None
     }
 
-    /// Wait on VMM events and dispatch them to the appropriate handler. Returns to the caller
-    /// when a control action occurs.
-    pub fn run_event_loop(&mut self) -> Result<EventLoopExitReason> {
-        // TODO: try handling of errors/failures without breaking this main loop.
-        loop {
-            let event = self.epoll_context.get_event()?;
-            let evset = match epoll::Events::from_bits(event.events) {
-                Some(evset) => evset,
-                None => {
-                    let evbits = event.events;
-                    warn!("epoll: ignoring unknown event set: 0x{:x}", evbits);
-                    continue;
-                }
-            };
+    /// Returns how the microVM exited.
+    pub fn exit_status(&self) -> std::result::Result<ExitStatus, VmmActionError> {
+        self.exit_status
+            .clone()
+            .ok_or_else(|| ShutdownError::NotExited.into())
+    }
 
-            match self.epoll_context.dispatch_table[event.data as usize] {
-                Some(EpollDispatch::Exit) => {
-                    match self.exit_evt {
-                        Some(ref ev) => {
-                            ev.read().map_err(Error::EventFd)?;
-                        }
-                        None => warn!("leftover exit-evt in epollcontext!"),
-                    }
-                    self.stop(i32::from(FC_EXIT_CODE_OK));
+    // Sends `event` to every vCPU and collects their responses, in vCPU order.
+    fn exchange_vcpu_events(
+        &self,
+        event: VcpuEvent,
+    ) -> std::result::Result<Vec<VcpuResponse>, vstate::Error> {
+        for handle in self.vcpus_handles.iter() {
+            // Drop the late responses to a previous exchange that timed out or failed, so that
+            // they are not mistaken for responses to `event`.
+            while handle.response_receiver().try_recv().is_ok() {}
+            handle.send_event(event)?;
+        }
+        self.vcpus_handles
+            .iter()
+            .map(|handle| {
+                match handle
+                    .response_receiver()
+                    .recv_timeout(Duration::from_millis(VCPU_RESPONSE_TIMEOUT_MS))
+                {
+                    Ok(VcpuResponse::Error(e)) => Err(e),
+                    Ok(response) => Ok(response),
+                    Err(_) => Err(vstate::Error::VcpuResponseTimeout),
                 }
-                Some(EpollDispatch::Stdin) => {
-                    let mut out = [0u8; 64];
-                    let stdin_lock = self.stdin_handle.lock();
+            })
+            .collect()
+    }
+
+    // Pauses every vCPU. When some vCPUs cannot be paused, the ones that were are resumed, so
+    // that the vCPUs are either all paused or all running.
+    fn pause_vcpus(&self) -> std::result::Result<(), vstate::Error> {
+        self.exchange_vcpu_events(VcpuEvent::Pause)
+            .map(|_| ())
+            .map_err(|e| {
+                self.resume_vcpus_after_error();
+                e
+            })
+    }
+
+    fn resume_vcpus_after_error(&self) {
+        if let Err(e) = self.exchange_vcpu_events(VcpuEvent::Resume) {
+            error!("Failed to resume the vCPUs: {:?}", e);
+        }
+    }
+
+    fn instance_state(&self) -> InstanceState {
+        self.shared_info
+            .read()
+            .expect("Failed to read shared info due to poisoned lock")
+            .state
+            .clone()
+    }
+
+    fn set_instance_state(&mut self, instance_state: InstanceState) {
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to set instance state because shared info couldn't be written due to poisoned lock")
+            .state = instance_state;
+    }
+
+    /// Parks the vCPUs outside of `KVM_RUN` and stops handling the device events.
+    /// This action can only be called while the microVM is running.
+    pub fn pause_vm(&mut self) -> UserResult {
+        info!("VMM received pause command");
+        if self.instance_state() != InstanceState::Running {
+            return Err(VmRunStateError::MicroVMNotRunning.into());
+        }
+
+        self.pause_vcpus().map_err(VmRunStateError::Vcpu)?;
+        if let Err(e) = self.epoll_context.disable_device_events() {
+            // The microVM keeps running, as its state says.
+            self.resume_vcpus_after_error();
+            return Err(VmRunStateError::DeviceEvents(e).into());
+        }
+        self.set_instance_state(InstanceState::Paused);
+
+        Ok(())
+    }
+
+    /// Restarts the device event handlers and the vCPUs of a paused microVM.
+    pub fn resume_vm(&mut self) -> UserResult {
+        info!("VMM received resume command");
+        if self.instance_state() != InstanceState::Paused {
+            return Err(VmRunStateError::MicroVMNotPaused.into());
+        }
+
+        self.epoll_context
+            .enable_device_events()
+            .map_err(VmRunStateError::DeviceEvents)?;
+        if let Err(e) = self.exchange_vcpu_events(VcpuEvent::Resume) {
+            // Park the vCPUs that resumed again, so that the microVM stays paused as its state
+            // says.
+            if let Err(e) = self.exchange_vcpu_events(VcpuEvent::Pause) {
+                error!("Failed to pause the vCPUs: {:?}", e);
+            }
+            if let Err(e) = self.epoll_context.disable_device_events() {
+                error!("Failed to disable the device events: {}", e);
+            }
+            return Err(VmRunStateError::Vcpu(e).into());
+        }
+        self.set_instance_state(InstanceState::Running);
+
+        Ok(())
+    }
+
+    // Returns the layout of the guest memory.
+    #[cfg(target_arch = "x86_64")]
+    fn memory_layout(&self) -> std::result::Result<Vec<MemoryRegionState>, SnapshotError> {
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(SnapshotError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        let mut memory_regions = Vec::with_capacity(guest_memory.num_regions());
+        guest_memory
+            .with_regions_mut(|_, guest_base, size, _| {
+                memory_regions.push(MemoryRegionState {
+                    base_address: guest_base.offset() as u64,
+                    size,
+                    dirty_pages: None,
+                });
+                Ok(())
+            })
+            .map_err(SnapshotError::GuestMemory)?;
+        Ok(memory_regions)
+    }
+
+    // Collects the state of the microVM, whose vCPUs must be paused.
+    #[cfg(target_arch = "x86_64")]
+    fn microvm_state(&self) -> std::result::Result<MicrovmState, SnapshotError> {
+        let vcpu_states = self
+            .exchange_vcpu_events(VcpuEvent::SaveState)
+            .map_err(SnapshotError::Vcpu)?
+            .into_iter()
+            .map(|response| match response {
+                VcpuResponse::SavedState(state) => Ok(*state),
+                _ => Err(SnapshotError::Vcpu(vstate::Error::VcpuNotPaused)),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let vm_state = self.vm.save_state().map_err(SnapshotError::Vm)?;
+        let virtio_devices = self
+            .mmio_device_manager
+            .as_ref()
+            .map_or(Ok(vec![]), |manager| manager.save_virtio_devices())
+            .map_err(SnapshotError::DeviceManager)?;
+        // If the lock is poisoned, it's OK to panic.
+        let serial_state = self
+            .pio_device_manager
+            .stdio_serial
+            .lock()
+            .expect("Failed to save the serial state due to poisoned lock")
+            .save_state();
+
+        Ok(MicrovmState {
+            magic: SNAPSHOT_MAGIC,
+            version: SNAPSHOT_VERSION,
+            vm_config: self.vm_config.clone(),
+            memory_regions: self.memory_layout()?,
+            block_devices: self
+                .device_configs
+                .block
+                .config_list
+                .iter()
+                .cloned()
+                .collect(),
+            net_devices: self
+                .device_configs
+                .network_interface
+                .iter()
+                .cloned()
+                .collect(),
+            vsock_device: self.device_configs.vsock.clone(),
+            balloon_device: self.device_configs.balloon.clone(),
+            vm_state,
+            vcpu_states,
+            virtio_devices,
+            serial_state,
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn save_microvm_state(
+        &self,
+        config: &SnapshotCreateConfig,
+        dirty_pages: &[Vec<u64>],
+    ) -> std::result::Result<(), SnapshotError> {
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(SnapshotError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        let mut microvm_state = self.microvm_state()?;
+        if config.snapshot_type == SnapshotType::Diff {
+            for (index, region) in microvm_state.memory_regions.iter_mut().enumerate() {
+                region.dirty_pages = Some(dirty_pages[index].clone());
+            }
+        }
+
+        let snapshot_file =
+            File::create(&config.snapshot_path).map_err(SnapshotError::CreateFile)?;
+        serde_json::to_writer(&snapshot_file, &microvm_state).map_err(SnapshotError::Serialize)?;
+        snapshot_file.sync_all().map_err(SnapshotError::WriteFile)?;
+
+        // The memory file holds the contents of the guest memory regions, back to back.
+        // Diff snapshots only hold the dirty pages and leave holes for the others.
+        let mut mem_file =
+            File::create(&config.mem_file_path).map_err(SnapshotError::CreateFile)?;
+        let mut file_offset = 0;
+        for region in microvm_state.memory_regions.iter() {
+            let ranges = match region.dirty_pages {
+                Some(ref bitmap) => dirty_page_ranges(bitmap, region.size),
+                None => vec![(0, region.size)],
+            };
+            for (offset, len) in ranges {
+                mem_file
+                    .seek(SeekFrom::Start((file_offset + offset) as u64))
+                    .map_err(SnapshotError::WriteFile)?;
+                guest_memory
+                    .write_from_memory(
+                        GuestAddress(region.base_address as usize + offset),
+                        &mut mem_file,
+                        len,
+                    )
+                    .map_err(SnapshotError::GuestMemory)?;
+            }
+            file_offset += region.size;
+        }
+        mem_file
+            .set_len(file_offset as u64)
+            .map_err(SnapshotError::WriteFile)?;
+        mem_file.sync_all().map_err(SnapshotError::WriteFile)
+    }
+
+    // Collects the pages written since the last snapshot and resets the tracking, so that the
+    // next diff snapshot only holds the pages written from now on.
+    #[cfg(target_arch = "x86_64")]
+    // Completes the block requests in flight on the asynchronous I/O engine, whose reads would
+    // otherwise reach the guest memory after it was saved. Must be called once the vCPUs are
+    // paused, as the devices do not submit new requests until the VMM thread serves them again.
+    #[cfg(target_arch = "x86_64")]
+    fn drain_block_requests(&mut self) -> std::result::Result<(), SnapshotError> {
+        let drive_ids: Vec<String> = self
+            .device_configs
+            .block
+            .config_list
+            .iter()
+            .filter(|config| {
+                config.io_engine == vmm_config::drive::IoEngine::Async
+                    || is_nbd_uri(&config.path_on_host)
+            })
+            .map(|config| config.drive_id.clone())
+            .collect();
+        for drive_id in drive_ids {
+            // The handler only exists once the guest activated the device.
+            if let Ok(handler) = self
+                .epoll_context
+                .get_device_handler_by_device_id::<virtio::BlockEpollHandler>(TYPE_BLOCK, &drive_id)
+            {
+                handler
+                    .drain_in_flight()
+                    .map_err(SnapshotError::BlockRequests)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_dirty_pages(&mut self) -> std::result::Result<Vec<Vec<u64>>, SnapshotError> {
+        // Move the pages logged by KVM to the dirty page tracking of the guest memory.
+        self.get_dirty_page_count();
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(SnapshotError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        (0..guest_memory.num_regions())
+            .map(|index| {
+                guest_memory
+                    .take_dirty_pages(index)
+                    .map_err(SnapshotError::GuestMemory)
+            })
+            .collect()
+    }
+
+    // Saves the microVM state along with the pages written since the last snapshot. If that
+    // fails, the pages are kept for the next snapshot.
+    #[cfg(target_arch = "x86_64")]
+    fn save_microvm_state_and_dirty_pages(
+        &mut self,
+        config: &SnapshotCreateConfig,
+    ) -> std::result::Result<(), SnapshotError> {
+        self.drain_block_requests()?;
+        let dirty_pages = self.take_dirty_pages()?;
+        let result = self.save_microvm_state(config, &dirty_pages);
+        if result.is_err() {
+            if let Some(guest_memory) = self.guest_memory.as_ref() {
+                for (index, bitmap) in dirty_pages.iter().enumerate() {
+                    // `index` is a valid region index, so this cannot fail.
+                    let _ = guest_memory.mark_dirty_pages(index, bitmap);
+                }
+            }
+        }
+        result
+    }
+
+    /// Saves the state of the microVM and the contents of its memory in the files described by
+    /// `config`. A running microVM is paused while its state is saved, then resumed.
+    #[cfg(target_arch = "x86_64")]
+    pub fn create_snapshot(&mut self, config: SnapshotCreateConfig) -> UserResult {
+        let state = self.instance_state();
+        if state != InstanceState::Running && state != InstanceState::Paused {
+            return Err(SnapshotError::MicroVMNotRunning.into());
+        }
+        if config.snapshot_type == SnapshotType::Diff
+            && !self.vm_config.track_dirty_pages.unwrap_or(false)
+        {
+            return Err(SnapshotError::DirtyPageTrackingDisabled.into());
+        }
+        // The vhost-net drivers keep moving frames, and their ring indexes are not saved.
+        if self.has_vhost_net_device() {
+            return Err(SnapshotError::VhostNet.into());
+        }
+        // Truncating the file backing the guest memory would pull the memory from under the guest.
+        let backs_guest_memory = self.guest_memory.as_ref().map_or(false, |guest_memory| {
+            guest_memory.map_and_fold(
+                false,
+                |(_, region)| {
+                    region.file_backing().map_or(false, |backing| {
+                        is_file_at(backing.file(), &config.mem_file_path)
+                    })
+                },
+                |in_use, region_in_use| in_use || region_in_use,
+            )
+        });
+        if backs_guest_memory {
+            return Err(SnapshotError::MemoryFileInUse.into());
+        }
+        // The same goes for the file the pages not accessed yet are loaded from.
+        if let Some(ref handler) = self.page_fault_handler {
+            if is_file_at(handler.mem_file(), &config.mem_file_path) {
+                return Err(SnapshotError::MemoryFileInUse.into());
+            }
+        }
+        if state == InstanceState::Paused {
+            return self
+                .save_microvm_state_and_dirty_pages(&config)
+                .map_err(VmmActionError::from);
+        }
+
+        let result = self
+            .exchange_vcpu_events(VcpuEvent::Pause)
+            .map_err(SnapshotError::Vcpu)
+            .and_then(|_| self.save_microvm_state_and_dirty_pages(&config));
+        // Resume the vCPUs even if saving the state failed.
+        self.exchange_vcpu_events(VcpuEvent::Resume)
+            .map_err(SnapshotError::Vcpu)?;
+
+        result.map_err(VmmActionError::from)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn restore_vcpus(
+        &mut self,
+        vcpu_states: &[VcpuState],
+    ) -> std::result::Result<Vec<Vcpu>, SnapshotError> {
+        let mut vcpus = Vec::with_capacity(vcpu_states.len());
+        for (cpu_index, state) in vcpu_states.iter().enumerate() {
+            let mut vcpu = Vcpu::new_x86_64(
+                cpu_index as u8,
+                self.vm.fd(),
+                self.vm.supported_cpuid().clone(),
+                self.vm.msr_list().to_vec(),
+                self.pio_device_manager.io_bus.clone(),
+                TimestampUs::default(),
+            )
+            .map_err(SnapshotError::Vcpu)?;
+            vcpu.restore_state(state).map_err(SnapshotError::Vcpu)?;
+            vcpus.push(vcpu);
+        }
+        Ok(vcpus)
+    }
+
+    // Checks that `microvm_state` was saved by a compatible build.
+    #[cfg(target_arch = "x86_64")]
+    fn check_microvm_state(microvm_state: &MicrovmState) -> std::result::Result<(), SnapshotError> {
+        if microvm_state.magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        if !snapshot::is_supported_version(microvm_state.version) {
+            return Err(SnapshotError::UnsupportedVersion(microvm_state.version));
+        }
+        if microvm_state.vcpu_states.len()
+            != microvm_state.vm_config.vcpu_count.unwrap_or(0) as usize
+        {
+            return Err(SnapshotError::VcpuCountMismatch);
+        }
+        if microvm_state.net_devices.iter().any(|config| config.vhost) {
+            return Err(SnapshotError::VhostNet);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn restore_microvm_state(
+        &mut self,
+        config: &SnapshotLoadConfig,
+    ) -> std::result::Result<(), VmmActionError> {
+        let snapshot_file = File::open(&config.snapshot_path).map_err(SnapshotError::OpenFile)?;
+        let microvm_state: MicrovmState =
+            serde_json::from_reader(snapshot_file).map_err(SnapshotError::Deserialize)?;
+        Self::check_microvm_state(&microvm_state)?;
+
+        // Rebuild the guest memory with the saved layout as a copy on write mapping of the
+        // memory file, so that the pages are only read once the guest accesses them.
+        let mem_file = File::open(&config.mem_file_path).map_err(SnapshotError::OpenFile)?;
+        let mem_file_size = mem_file.metadata().map_err(SnapshotError::ReadFile)?.len();
+        let mem_size: usize = microvm_state.memory_regions.iter().map(|r| r.size).sum();
+        if mem_file_size != mem_size as u64 {
+            return Err(SnapshotError::InvalidMemoryFile.into());
+        }
+        let regions: Vec<(GuestAddress, usize)> = microvm_state
+            .memory_regions
+            .iter()
+            .map(|r| (GuestAddress(r.base_address as usize), r.size))
+            .collect();
+        let (guest_memory, mem_backend) = match config.mem_backend {
+            SnapshotMemoryBackend::File => {
+                let guest_memory =
+                    GuestMemory::new_with_files(&file_backed_regions(&regions, mem_file, false))
+                        .map_err(SnapshotError::GuestMemory)?;
+                let mem_backend = MemoryBackend::File {
+                    path: config.mem_file_path.clone(),
+                    shared: false,
+                };
+                (guest_memory, mem_backend)
+            }
+            // The pages are copied to anonymous memory on first access, so the guest memory no
+            // longer depends on the memory file once every page was loaded.
+            SnapshotMemoryBackend::Uffd => {
+                let guest_memory =
+                    GuestMemory::new(&regions).map_err(SnapshotError::GuestMemory)?;
+                let (uffd, uffd_regions) =
+                    register_guest_memory(&guest_memory).map_err(SnapshotError::Userfaultfd)?;
+                self.page_fault_handler = Some(
+                    PageFaultHandler::start(uffd, uffd_regions, mem_file, self.seccomp_level)
+                        .map_err(SnapshotError::Userfaultfd)?,
+                );
+                (guest_memory, MemoryBackend::Anonymous)
+            }
+            SnapshotMemoryBackend::UffdSocket { ref socket_path } => {
+                let guest_memory =
+                    GuestMemory::new(&regions).map_err(SnapshotError::GuestMemory)?;
+                let (uffd, uffd_regions) =
+                    register_guest_memory(&guest_memory).map_err(SnapshotError::Userfaultfd)?;
+                page_fault_handler::send_to_handler(socket_path, &uffd, &uffd_regions)
+                    .map_err(SnapshotError::PageFaultHandlerSocket)?;
+                (guest_memory, MemoryBackend::Anonymous)
+            }
+        };
+
+        self.restore_microvm(&microvm_state, guest_memory, mem_backend)
+    }
+
+    // Rebuilds the microVM described by `microvm_state` around the already filled `guest_memory`,
+    // backed by `mem_backend`, then starts its vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_microvm(
+        &mut self,
+        microvm_state: &MicrovmState,
+        guest_memory: GuestMemory,
+        mem_backend: MemoryBackend,
+    ) -> std::result::Result<(), VmmActionError> {
+        // The restored memory is the base of the next diff snapshot.
+        for index in 0..guest_memory.num_regions() {
+            guest_memory
+                .take_dirty_pages(index)
+                .map_err(SnapshotError::GuestMemory)?;
+        }
+
+        self.vm_config = microvm_state.vm_config.clone();
+        self.vm_config.mem_backend = Some(mem_backend);
+        // The restored guest memory is always backed by regular pages.
+        self.vm_config.backing_page_size = Some(BackingPageSize::Size4K);
+        for block_device in microvm_state.block_devices.iter().cloned() {
+            self.device_configs.block.insert(block_device)?;
+        }
+        for net_device in microvm_state.net_devices.iter().cloned() {
+            self.device_configs.network_interface.insert(net_device)?;
+        }
+        self.device_configs.vsock = microvm_state.vsock_device.clone();
+        self.device_configs.balloon = microvm_state.balloon_device.clone();
+
+        self.set_guest_memory(guest_memory);
+        // The guest is not booted again, so the command line is only used to describe the
+        // devices while attaching them.
+        self.set_kernel_config(KernelConfig {
+            cmdline: kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE),
+            kernel_file: None,
+        });
+
+        self.init_guest_memory()?;
+        self.setup_interrupt_controller()?;
+        self.attach_virtio_devices()?;
+        self.attach_legacy_devices()?;
+
+        // `unwrap` is safe because the device manager was created while attaching the devices.
+        self.mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .restore_virtio_devices(&microvm_state.virtio_devices)
+            .map_err(SnapshotError::DeviceManager)?;
+        // If the lock is poisoned, it's OK to panic.
+        self.pio_device_manager
+            .stdio_serial
+            .lock()
+            .expect("Failed to restore the serial state due to poisoned lock")
+            .restore_state(&microvm_state.serial_state);
+        self.vm
+            .restore_state(&microvm_state.vm_state)
+            .map_err(SnapshotError::Vm)?;
+        let vcpus = self.restore_vcpus(&microvm_state.vcpu_states)?;
+
+        self.configure_stdin()?;
+        self.register_events()?;
+        self.start_vcpus(vcpus)?;
+
+        Ok(())
+    }
+
+    /// Rebuilds a microVM from the state and memory files described by `config`, then resumes
+    /// it. This action can only be called before the microVM is configured and started.
+    #[cfg(target_arch = "x86_64")]
+    pub fn load_snapshot(&mut self, config: SnapshotLoadConfig) -> UserResult {
+        info!("VMM received load snapshot command");
+        if self.is_instance_initialized() {
+            return Err(SnapshotError::MicroVMAlreadyRunning.into());
+        }
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to load snapshot because shared info couldn't be written due to poisoned lock")
+            .state = InstanceState::Starting;
+
+        self.restore_microvm_state(&config)?;
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to load snapshot because shared info couldn't be written due to poisoned lock")
+            .state = InstanceState::Running;
+
+        self.arm_write_metrics_timer();
+
+        Ok(())
+    }
+
+    /// Copies the pages held by the memory file of the diff snapshot described by `config` to
+    /// the base memory file, which can then be loaded along with the state of the diff snapshot.
+    #[cfg(target_arch = "x86_64")]
+    pub fn merge_snapshot(&mut self, config: SnapshotMergeConfig) -> UserResult {
+        let snapshot_file = File::open(&config.snapshot_path).map_err(SnapshotError::OpenFile)?;
+        let microvm_state: MicrovmState =
+            serde_json::from_reader(snapshot_file).map_err(SnapshotError::Deserialize)?;
+        if microvm_state.magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidMagic.into());
+        }
+        if !snapshot::is_supported_version(microvm_state.version) {
+            return Err(SnapshotError::UnsupportedVersion(microvm_state.version).into());
+        }
+        if microvm_state
+            .memory_regions
+            .iter()
+            .any(|region| region.dirty_pages.is_none())
+        {
+            return Err(SnapshotError::NotDiffSnapshot.into());
+        }
+
+        let mem_size: usize = microvm_state.memory_regions.iter().map(|r| r.size).sum();
+        let mut diff_file = File::open(&config.mem_file_path).map_err(SnapshotError::OpenFile)?;
+        let mut base_file = OpenOptions::new()
+            .write(true)
+            .open(&config.base_mem_file_path)
+            .map_err(SnapshotError::OpenFile)?;
+        for file in [&diff_file, &base_file].iter() {
+            if file.metadata().map_err(SnapshotError::ReadFile)?.len() != mem_size as u64 {
+                return Err(SnapshotError::InvalidMemoryFile.into());
+            }
+        }
+
+        let mut file_offset = 0;
+        let mut buf = Vec::new();
+        for region in microvm_state.memory_regions.iter() {
+            // `unwrap` is safe because we checked that every region has a dirty page bitmap.
+            let bitmap = region.dirty_pages.as_ref().unwrap();
+            for (offset, len) in dirty_page_ranges(bitmap, region.size) {
+                let position = SeekFrom::Start((file_offset + offset) as u64);
+                buf.resize(len, 0);
+                diff_file
+                    .seek(position)
+                    .and_then(|_| diff_file.read_exact(&mut buf))
+                    .map_err(SnapshotError::ReadFile)?;
+                base_file
+                    .seek(position)
+                    .and_then(|_| base_file.write_all(&buf))
+                    .map_err(SnapshotError::WriteFile)?;
+            }
+            file_offset += region.size;
+        }
+        base_file
+            .sync_all()
+            .map_err(|e| SnapshotError::WriteFile(e).into())
+    }
+
+    // Starts the migration worker, unless it already runs. The seccomp filters of the VMM thread
+    // do not allow starting threads, so this is done before they are loaded.
+    #[cfg(target_arch = "x86_64")]
+    fn start_migration_worker(&mut self) -> io::Result<&MigrationWorker> {
+        if self.migration_worker.is_none() {
+            let done_evt = self.migration_evt.try_clone()?;
+            self.migration_worker = Some(MigrationWorker::start(done_evt, self.seccomp_level)?);
+        }
+        // Safe to unwrap because the worker was started above.
+        Ok(self.migration_worker.as_ref().unwrap())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn migration_worker(&self) -> std::result::Result<&MigrationWorker, MigrationError> {
+        self.migration_worker
+            .as_ref()
+            .ok_or(MigrationError::WorkerStopped)
+    }
+
+    // Gives back to the dirty page tracking the pages taken from it, so that they are not hidden
+    // from the next diff snapshot.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_dirty_pages(&self, dirty_pages: &[Vec<u64>]) {
+        if let Some(guest_memory) = self.guest_memory.as_ref() {
+            for (index, bitmap) in dirty_pages.iter().enumerate() {
+                // `index` is a valid region index, so this cannot fail.
+                let _ = guest_memory.mark_dirty_pages(index, bitmap);
+            }
+        }
+    }
+
+    /// Migrates the microVM to the Firecracker process listening on the socket described by
+    /// `config`. The guest memory is copied while the vCPUs run, then the vCPUs are paused and
+    /// the remaining dirty pages are sent along with the state of the microVM. Once the
+    /// destination resumed the microVM, this microVM stays paused.
+    ///
+    /// The transfers run on the migration worker. The event loop returns `MigrationDone` once
+    /// the migration completed, and its outcome is then returned by `migration_result`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn send_migration(&mut self, config: MigrationSendConfig) -> UserResult {
+        info!("VMM received send migration command");
+        let state = self.instance_state();
+        if state != InstanceState::Running && state != InstanceState::Paused {
+            return Err(MigrationError::MicroVMNotRunning.into());
+        }
+        if !self.vm_config.track_dirty_pages.unwrap_or(false) {
+            return Err(MigrationError::DirtyPageTrackingDisabled.into());
+        }
+        if self.has_vhost_net_device() {
+            return Err(MigrationError::VhostNet.into());
+        }
+        let memory_regions = self.memory_layout().map_err(MigrationError::State)?;
+        // `memory_layout` fails without a guest memory.
+        let guest_memory = self.guest_memory.clone().unwrap();
+
+        let taken_pages = self.take_dirty_pages().map_err(MigrationError::State)?;
+        let started = self
+            .start_migration_worker()
+            .map_err(MigrationError::StartWorker)
+            .and_then(|worker| {
+                worker.submit(Job::Start {
+                    socket_path: config.socket_path,
+                    guest_memory,
+                    memory_regions,
+                })
+            });
+        if let Err(e) = started {
+            self.restore_dirty_pages(&taken_pages);
+            return Err(e.into());
+        }
+        self.migration = Some(Migration::Outgoing(OutgoingMigration {
+            taken_pages,
+            round: 1,
+            stop_copy: false,
+            paused: false,
+        }));
+        Ok(())
+    }
+
+    // Hands the pages written during the last pre-copy round over to the worker. Once the guest
+    // writes few enough pages, or after the last round, the vCPUs are paused and the remaining
+    // pages are sent along with the state of the microVM.
+    #[cfg(target_arch = "x86_64")]
+    fn send_next_round(
+        &mut self,
+        migration: &mut OutgoingMigration,
+    ) -> std::result::Result<(), MigrationError> {
+        let mut dirty_pages = self.take_dirty_pages().map_err(MigrationError::State)?;
+        merge_dirty_pages(&mut migration.taken_pages, &dirty_pages);
+        if migration.round < MAX_PRECOPY_ROUNDS
+            && count_dirty_pages(&dirty_pages) > MAX_STOP_COPY_PAGES
+        {
+            migration.round += 1;
+            return self.migration_worker()?.submit(Job::SendPages(dirty_pages));
+        }
+
+        if self.instance_state() == InstanceState::Running {
+            self.pause_vcpus().map_err(MigrationError::Vcpu)?;
+            migration.paused = true;
+            self.epoll_context
+                .disable_device_events()
+                .map_err(MigrationError::DeviceEvents)?;
+        }
+        self.drain_block_requests().map_err(MigrationError::State)?;
+        let last_pages = self.take_dirty_pages().map_err(MigrationError::State)?;
+        merge_dirty_pages(&mut migration.taken_pages, &last_pages);
+        merge_dirty_pages(&mut dirty_pages, &last_pages);
+
+        let microvm_state = self.microvm_state().map_err(MigrationError::State)?;
+        let state = serde_json::to_vec(&microvm_state).map_err(MigrationError::Serialize)?;
+        migration.stop_copy = true;
+        self.migration_worker()?
+            .submit(Job::SendState(dirty_pages, state))
+    }
+
+    // Moves the migration being sent to its next step once the worker completed the previous
+    // one. Returns the outcome of the migration once it ended.
+    #[cfg(target_arch = "x86_64")]
+    fn continue_outgoing_migration(
+        &mut self,
+        mut migration: OutgoingMigration,
+        output: std::result::Result<JobOutput, MigrationError>,
+    ) -> Option<UserResult> {
+        let result = match output {
+            Ok(_) if !migration.stop_copy => match self.send_next_round(&mut migration) {
+                Ok(()) => {
+                    self.migration = Some(Migration::Outgoing(migration));
+                    return None;
+                }
+                Err(e) => Err(e),
+            },
+            output => output.map(|_| ()),
+        };
+
+        self.restore_dirty_pages(&migration.taken_pages);
+        if result.is_ok() {
+            self.set_instance_state(InstanceState::Paused);
+        } else if migration.paused {
+            // Resume the microVM, since the destination did not.
+            if let Err(e) = self.epoll_context.enable_device_events() {
+                error!("Failed to resume the device events after migration: {}", e);
+            }
+            if let Err(e) = self.exchange_vcpu_events(VcpuEvent::Resume) {
+                error!("Failed to resume the vCPUs after migration: {:?}", e);
+            }
+        }
+        Some(result.map_err(VmmActionError::from))
+    }
+
+    // Rebuilds the microVM received from the source.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_received_microvm(
+        &mut self,
+        output: JobOutput,
+    ) -> std::result::Result<(), VmmActionError> {
+        let (guest_memory, memory_regions, state) = match output {
+            JobOutput::Received(guest_memory, memory_regions, state) => {
+                (guest_memory, memory_regions, state)
+            }
+            JobOutput::Done => unreachable!("The worker answers Receive with Received."),
+        };
+        let microvm_state: MicrovmState =
+            serde_json::from_slice(&state).map_err(MigrationError::Deserialize)?;
+        if microvm_state.memory_regions != memory_regions {
+            return Err(MigrationError::InvalidMemoryLayout.into());
+        }
+        Self::check_microvm_state(&microvm_state)?;
+
+        self.restore_microvm(&microvm_state, guest_memory, MemoryBackend::Anonymous)
+    }
+
+    // Moves the migration being received to its next step once the worker completed the
+    // previous one. Returns the outcome of the migration once it ended.
+    #[cfg(target_arch = "x86_64")]
+    fn continue_incoming_migration(
+        &mut self,
+        migration: IncomingMigration,
+        output: std::result::Result<JobOutput, MigrationError>,
+    ) -> Option<UserResult> {
+        let result = match migration.result {
+            // The source was told about the outcome of the migration.
+            Some(result) => {
+                return Some(match output {
+                    Ok(_) => result,
+                    Err(e) => {
+                        if result.is_ok() {
+                            return Some(Err(e.into()));
+                        }
+                        warn!("Cannot report the migration failure to the source. {}", e);
+                        result
+                    }
+                });
+            }
+            None => {
+                // A single migration is received on the socket.
+                if let Err(e) = std::fs::remove_file(&migration.socket_path) {
+                    warn!("Cannot remove the migration socket. {}", e);
+                }
+                match output {
+                    // Nobody connected, so there is nobody to report the failure to.
+                    Err(e @ MigrationError::Accept(_)) => return Some(Err(e.into())),
+                    output => output
+                        .map_err(VmmActionError::from)
+                        .and_then(|output| self.restore_received_microvm(output)),
+                }
+            }
+        };
+
+        if result.is_ok() {
+            self.set_instance_state(InstanceState::Running);
+            self.arm_write_metrics_timer();
+        }
+        let reply = result.as_ref().err().map(ToString::to_string);
+        match self
+            .migration_worker()
+            .and_then(|worker| worker.submit(Job::Reply(reply)))
+        {
+            Ok(()) => {
+                self.migration = Some(Migration::Incoming(IncomingMigration {
+                    socket_path: migration.socket_path,
+                    result: Some(result),
+                }));
+                None
+            }
+            Err(e) => Some(result.and(Err(e.into()))),
+        }
+    }
+
+    // Handles the completion of a migration job. Returns true once the migration ended.
+    #[cfg(target_arch = "x86_64")]
+    fn handle_migration_event(&mut self) -> bool {
+        let output = match self
+            .migration_worker
+            .as_ref()
+            .and_then(MigrationWorker::try_result)
+        {
+            Some(output) => output,
+            None => return false,
+        };
+        let result = match self.migration.take() {
+            Some(Migration::Outgoing(migration)) => {
+                self.continue_outgoing_migration(migration, output)
+            }
+            Some(Migration::Incoming(migration)) => {
+                self.continue_incoming_migration(migration, output)
+            }
+            None => {
+                warn!("Got a migration event without a migration in progress");
+                None
+            }
+        };
+        match result {
+            Some(result) => {
+                self.migration_result = Some(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the outcome of the last migration, once the event loop returned `MigrationDone`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn migration_result(&mut self) -> UserResult {
+        self.migration_result.take().unwrap_or(Ok(()))
+    }
+
+    /// Waits for a migration on the socket described by `config`, then rebuilds and resumes the
+    /// migrated microVM. This action can only be called before the microVM is configured and
+    /// started.
+    ///
+    /// The transfers run on the migration worker. The event loop returns `MigrationDone` once
+    /// the migration completed, and its outcome is then returned by `migration_result`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn receive_migration(&mut self, config: MigrationReceiveConfig) -> UserResult {
+        info!("VMM waiting for an incoming migration");
+        if self.is_instance_initialized() {
+            return Err(MigrationError::MicroVMAlreadyRunning.into());
+        }
+        self.start_migration_worker()
+            .map_err(MigrationError::StartWorker)?;
+        self.set_instance_state(InstanceState::Starting);
+
+        let listener = UnixListener::bind(&config.socket_path).map_err(MigrationError::Bind)?;
+        self.migration_worker()?.submit(Job::Receive(listener))?;
+        self.migration = Some(Migration::Incoming(IncomingMigration {
+            socket_path: config.socket_path,
+            result: None,
+        }));
+        Ok(())
+    }
+
+    /// Waits for all vCPUs to exit and terminates the Firecracker process.
+    pub fn stop(&mut self, exit_code: i32) {
+        info!("Vmm is stopping.");
+
+        if let Err(e) = self.stdin_handle.lock().set_canon_mode() {
+            warn!("Cannot set canonical mode for the terminal. {:?}", e);
+        }
+
+        // Log the metrics before exiting.
+        if let Err(e) = LOGGER.log_metrics() {
+            error!("Failed to log metrics while stopping: {}", e);
+        }
+
+        // Exit from Firecracker using the provided exit code. Safe because we're terminating
+        // the process anyway.
+        unsafe {
+            libc::_exit(exit_code);
+        }
+    }
+
+    fn is_instance_initialized(&self) -> bool {
+        let error_string = "Cannot check instance initialization as shared info lock is poisoned";
+        self.shared_info.read().expect(error_string).state != InstanceState::Uninitialized
+    }
+
+    // Whether an interface hands its data path over to vhost-net, whose writes to the guest
+    // memory are not tracked and whose rings are not saved.
+    fn has_vhost_net_device(&self) -> bool {
+        self.device_configs
+            .network_interface
+            .iter()
+            .any(|config| config.vhost)
+    }
+
+    fn handle_stdin_event(&self, buffer: &[u8]) -> Result<()> {
+        match self.get_serial_device() {
+            Some(serial) => {
+                // Use expect() to panic if another thread panicked
+                // while holding the lock.
+                serial
+                    .lock()
+                    .expect("Failed to process stdin event due to poisoned lock")
+                    .raw_input(buffer)
+                    .map_err(Error::Serial)?;
+            }
+            None => warn!("Unable to handle stdin event: no serial device available"),
+        }
+
+        Ok(())
+    }
+
+    /// Wait on VMM events and dispatch them to the appropriate handler. Returns to the caller
+    /// when a control action occurs or when the microVM stops running.
+    pub fn run_event_loop(&mut self) -> Result<EventLoopExitReason> {
+        // TODO: try handling of errors/failures without breaking this main loop.
+        loop {
+            let event = self.epoll_context.get_event()?;
+            let evset = match epoll::Events::from_bits(event.events) {
+                Some(evset) => evset,
+                None => {
+                    let evbits = event.events;
+                    warn!("epoll: ignoring unknown event set: 0x{:x}", evbits);
+                    continue;
+                }
+            };
+
+            match self.epoll_context.dispatch_table[event.data as usize] {
+                Some(EpollDispatch::Exit) => {
+                    match self.exit_evt {
+                        Some(ref ev) => {
+                            ev.read().map_err(Error::EventFd)?;
+                        }
+                        None => warn!("leftover exit-evt in epollcontext!"),
+                    }
+                    // The guest already stopped if the exit status is known.
+                    if self.exit_status.is_none() {
+                        return Ok(EventLoopExitReason::Break);
+                    }
+                }
+                Some(EpollDispatch::Stdin) => {
+                    let mut out = [0u8; 64];
+                    let stdin_lock = self.stdin_handle.lock();
                     match stdin_lock.read_raw(&mut out[..]) {
                         Ok(0) => {
                             // Zero-length read indicates EOF. Remove from pollables.
@@ -1284,6 +2799,9 @@ impl Vmm {
                 Some(EpollDispatch::VmmActionRequest) => {
                     return Ok(EventLoopExitReason::ControlAction);
                 }
+                Some(EpollDispatch::DeviceEvents) => {
+                    // Never returned by `get_event`.
+                }
                 Some(EpollDispatch::WriteMetrics) => {
                     self.write_metrics_event_fd.read();
                     // Please note that, since LOGGER has no output file configured yet, it will write to
@@ -1292,32 +2810,53 @@ impl Vmm {
                         error!("Failed to log metrics: {}", e);
                     }
                 }
+                Some(EpollDispatch::ShutdownTimer) => {
+                    self.shutdown_timer.read();
+                    return Ok(match self.exit_status {
+                        // The time the exit status remains available is over.
+                        Some(ref exit_status) => exit_status.exit_reason,
+                        None => EventLoopExitReason::ShutdownTimeout,
+                    });
+                }
+                #[cfg(target_arch = "x86_64")]
+                Some(EpollDispatch::Migration) => {
+                    self.migration_evt.read().map_err(Error::EventFd)?;
+                    if self.handle_migration_event() {
+                        return Ok(EventLoopExitReason::MigrationDone);
+                    }
+                }
                 None => {
                     // Do nothing.
                 }
             }
         }
-        // Currently, we never get to return with Ok(EventLoopExitReason::Break) because
-        // we just invoke stop() whenever that would happen.
     }
 
     // Count the number of pages dirtied since the last call to this function.
+    // The pages are also recorded in the dirty page tracking of the guest memory, so that they
+    // are not lost for diff snapshots.
     // Because this is used for metrics, it swallows most errors and simply doesn't count dirty
     // pages if the KVM operation fails.
     #[cfg(target_arch = "x86_64")]
     fn get_dirty_page_count(&mut self) -> usize {
+        let guest_memory = match self.guest_memory() {
+            Some(mem) => mem,
+            None => return 0,
+        };
         let dirty_pages_in_region =
             |(slot, memory_region): (usize, &memory_model::MemoryRegion)| {
                 self.vm
                     .fd()
                     .get_dirty_log(slot as u32, memory_region.size())
-                    .map(|v| v.iter().map(|page| page.count_ones() as usize).sum())
+                    .map(|v| {
+                        // `slot` is a valid region index, so this cannot fail.
+                        let _ = guest_memory.mark_dirty_pages(slot, &v);
+                        v.iter().map(|page| page.count_ones() as usize).sum()
+                    })
                     .unwrap_or(0 as usize)
             };
 
-        self.guest_memory()
-            .map(|ref mem| mem.map_and_fold(0, dirty_pages_in_region, std::ops::Add::add))
-            .unwrap_or(0)
+        guest_memory.map_and_fold(0, dirty_pages_in_region, std::ops::Add::add)
     }
 
     /// Set the guest boot source configuration.
@@ -1345,7 +2884,7 @@ impl Vmm {
             .map_err(|e| BootSource(User, InvalidKernelCommandLine(e.to_string())))?;
 
         let kernel_config = KernelConfig {
-            kernel_file,
+            kernel_file: Some(kernel_file),
             cmdline,
         };
         self.set_kernel_config(kernel_config);
@@ -1367,6 +2906,29 @@ impl Vmm {
             return Err(VmConfigError::InvalidMemorySize.into());
         }
 
+        let mem_size_mib = machine_config
+            .mem_size_mib
+            .or(self.vm_config.mem_size_mib)
+            .unwrap_or(0);
+        let backing_page_size = machine_config
+            .backing_page_size
+            .or(self.vm_config.backing_page_size);
+        if backing_page_size == Some(BackingPageSize::Size2M)
+            && mem_size_mib % (HUGE_PAGE_SIZE >> 20) != 0
+        {
+            return Err(VmConfigError::MemorySizeNotHugePageAligned.into());
+        }
+        // The balloon releases 4K pages, which cannot be punched out of 2M pages.
+        if backing_page_size == Some(BackingPageSize::Size2M)
+            && self.device_configs.balloon.is_some()
+        {
+            return Err(VmConfigError::HugePagesWithBalloon.into());
+        }
+        // The pages written by vhost-net do not show up in the dirty page bitmaps.
+        if machine_config.track_dirty_pages == Some(true) && self.has_vhost_net_device() {
+            return Err(VmConfigError::DirtyPageTrackingWithVhost.into());
+        }
+
         let ht_enabled = machine_config
             .ht_enabled
             .unwrap_or_else(|| self.vm_config.ht_enabled.unwrap());
@@ -1393,6 +2955,18 @@ impl Vmm {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
+        if machine_config.track_dirty_pages.is_some() {
+            self.vm_config.track_dirty_pages = machine_config.track_dirty_pages;
+        }
+
+        if machine_config.mem_backend.is_some() {
+            self.vm_config.mem_backend = machine_config.mem_backend;
+        }
+
+        if machine_config.backing_page_size.is_some() {
+            self.vm_config.backing_page_size = machine_config.backing_page_size;
+        }
+
         Ok(())
     }
 
@@ -1401,6 +2975,9 @@ impl Vmm {
         if self.is_instance_initialized() {
             return Err(NetworkInterfaceError::UpdateNotAllowedPostBoot.into());
         }
+        if body.vhost && self.vm_config.track_dirty_pages.unwrap_or(false) {
+            return Err(NetworkInterfaceError::VhostWithDirtyPageTracking.into());
+        }
         self.device_configs
             .network_interface
             .insert(body)
@@ -1409,6 +2986,16 @@ impl Vmm {
 
     /// Updates configuration for an emulated net device as described in `new_cfg`.
     pub fn update_net_device(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> UserResult {
+        // The frames of interfaces using vhost-net do not go through the rate limiters.
+        let vhost = self
+            .device_configs
+            .network_interface
+            .iter()
+            .any(|c| c.iface_id == new_cfg.iface_id && c.vhost);
+        if vhost && (new_cfg.rx_rate_limiter.is_some() || new_cfg.tx_rate_limiter.is_some()) {
+            return Err(NetworkInterfaceError::VhostWithRateLimiter.into());
+        }
+
         if !self.is_instance_initialized() {
             // VM not started yet, so we only need to update the device configs, not the actual
             // live device.
@@ -1469,6 +3056,59 @@ impl Vmm {
         Ok(())
     }
 
+    /// Starts or stops capturing the frames received and sent by a network interface, as
+    /// described in `capture_cfg`.
+    pub fn capture_net_device(&mut self, capture_cfg: NetworkInterfaceCaptureConfig) -> UserResult {
+        // Only the handlers of running devices see the frames.
+        if !self.is_instance_initialized() {
+            return Err(NetworkInterfaceError::OperationNotAllowedPreBoot.into());
+        }
+        let iface_cfg = self
+            .device_configs
+            .network_interface
+            .iter()
+            .find(|c| c.iface_id == capture_cfg.iface_id)
+            .ok_or(NetworkInterfaceError::DeviceIdNotFound)?;
+        if iface_cfg.vhost {
+            return Err(NetworkInterfaceError::VhostWithCapture.into());
+        }
+
+        let capture = match capture_cfg.capture_path {
+            Some(ref capture_path) => {
+                capture_cfg.validate()?;
+                // Existing files, such as the disk images of the drives, and symbolic links are
+                // never written to.
+                let capture_file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(capture_path)
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::AlreadyExists => NetworkInterfaceError::CaptureFileExists,
+                        _ => NetworkInterfaceError::CreateCapture(e),
+                    })?;
+                Some(
+                    PacketCapture::new(
+                        capture_file,
+                        capture_cfg.snap_len,
+                        capture_cfg.max_size_bytes,
+                    )
+                    .map_err(NetworkInterfaceError::CreateCapture)?,
+                )
+            }
+            None => None,
+        };
+
+        self.epoll_context
+            .get_device_handler_by_device_id::<virtio::NetEpollHandler>(
+                TYPE_NET,
+                &capture_cfg.iface_id,
+            )
+            .map_err(NetworkInterfaceError::EpollHandlerNotFound)?
+            .set_capture(capture);
+        Ok(())
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> UserResult {
         if self.is_instance_initialized() {
@@ -1482,6 +3122,86 @@ impl Vmm {
         }
     }
 
+    /// Sets the balloon device to be attached when the VM starts.
+    pub fn set_balloon_device(&mut self, config: BalloonDeviceConfig) -> UserResult {
+        if self.is_instance_initialized() {
+            return Err(BalloonError::UpdateNotAllowedPostBoot.into());
+        }
+        if self.vm_config.backing_page_size == Some(BackingPageSize::Size2M) {
+            return Err(BalloonError::HugePagesBacking.into());
+        }
+        self.check_balloon_size(config.amount_mib)?;
+        self.device_configs.balloon = Some(config);
+        Ok(())
+    }
+
+    /// Updates the target size of the balloon. After boot, the guest is notified of the new
+    /// size right away.
+    pub fn update_balloon(&mut self, update: BalloonUpdateConfig) -> UserResult {
+        if self.device_configs.balloon.is_none() {
+            return Err(BalloonError::DeviceNotFound.into());
+        }
+        self.check_balloon_size(update.amount_mib)?;
+
+        if self.is_instance_initialized() {
+            // Safe to unwrap() because mmio_device_manager is initialized before the guest boots.
+            self.mmio_device_manager
+                .as_ref()
+                .unwrap()
+                .update_balloon(BALLOON_DEV_ID, mib_to_balloon_pages(update.amount_mib))
+                .map_err(|_| BalloonError::UpdateFailed)?;
+        }
+        // Checked above.
+        self.device_configs.balloon.as_mut().unwrap().amount_mib = update.amount_mib;
+        Ok(())
+    }
+
+    /// Returns the configuration of the balloon device.
+    pub fn balloon_config(&self) -> std::result::Result<BalloonDeviceConfig, VmmActionError> {
+        self.device_configs
+            .balloon
+            .clone()
+            .ok_or_else(|| BalloonError::DeviceNotFound.into())
+    }
+
+    /// Returns the size of the balloon and the latest memory statistics reported by the guest.
+    pub fn balloon_statistics(&mut self) -> std::result::Result<BalloonStatistics, VmmActionError> {
+        let config = self.balloon_config()?;
+        if config.stats_polling_interval_s == 0 {
+            return Err(BalloonError::StatisticsDisabled.into());
+        }
+
+        let actual_pages = match self.mmio_device_manager {
+            Some(ref device_manager) if self.is_instance_initialized() => device_manager
+                .balloon_actual_pages(BALLOON_DEV_ID)
+                .map_err(|_| BalloonError::DeviceNotFound)?,
+            _ => 0,
+        };
+        // The handler only exists once the guest driver activated the device.
+        let memory_stats = self
+            .epoll_context
+            .get_device_handler_by_device_id::<BalloonEpollHandler>(TYPE_BALLOON, BALLOON_DEV_ID)
+            .map(|handler| handler.latest_stats().clone())
+            .unwrap_or_default();
+
+        Ok(BalloonStatistics {
+            target_pages: mib_to_balloon_pages(config.amount_mib),
+            actual_pages,
+            target_mib: config.amount_mib,
+            actual_mib: balloon_pages_to_mib(actual_pages),
+            memory_stats,
+        })
+    }
+
+    fn check_balloon_size(&self, amount_mib: u32) -> std::result::Result<(), BalloonError> {
+        match self.vm_config.mem_size_mib {
+            Some(mem_size_mib) if amount_mib as usize > mem_size_mib => {
+                Err(BalloonError::TooManyPagesRequested)
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Updates the path of the host file backing the emulated block device with id `drive_id`.
     pub fn set_block_device_path(&mut self, drive_id: String, path_on_host: String) -> UserResult {
         // Get the block device configuration specified by drive_id.
@@ -1492,12 +3212,22 @@ impl Vmm {
             .ok_or(DriveError::InvalidBlockDeviceID)?;
 
         let file_path = PathBuf::from(path_on_host);
-        // Try to open the file specified by path_on_host using the permissions of the block_device.
-        let disk_file = OpenOptions::new()
-            .read(true)
-            .write(!self.device_configs.block.config_list[block_device_index].is_read_only())
-            .open(&file_path)
-            .map_err(|_| DriveError::CannotOpenBlockDevice)?;
+        // The NBD workers are only started before boot.
+        if self.is_instance_initialized()
+            && (is_nbd_uri(&file_path)
+                || is_nbd_uri(
+                    &self.device_configs.block.config_list[block_device_index].path_on_host,
+                ))
+        {
+            return Err(DriveError::NbdUpdateNotAllowedPostBoot.into());
+        }
+        // Try to open the file specified by path_on_host using the permissions, the format and the
+        // overlay of the block_device.
+        let disk_image = open_disk_image(
+            &self.device_configs.block.config_list[block_device_index],
+            &file_path,
+        )
+        .map_err(|_| DriveError::CannotOpenBlockDevice)?;
 
         // Update the path of the block device with the specified path_on_host.
         self.device_configs.block.config_list[block_device_index].path_on_host = file_path;
@@ -1505,12 +3235,59 @@ impl Vmm {
         // When the microvm is running, we also need to update the drive handler and send a
         // rescan command to the drive.
         if self.is_instance_initialized() {
-            self.update_drive_handler(&drive_id, disk_file)?;
+            self.update_drive_handler(&drive_id, disk_image)?;
             self.rescan_block_device(&drive_id)?;
         }
         Ok(())
     }
 
+    /// Updates the path and the rate limiter of a block device as described in `new_cfg`.
+    pub fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> UserResult {
+        let block_device_index = self
+            .device_configs
+            .block
+            .get_index_of_drive_id(&new_cfg.drive_id)
+            .ok_or(DriveError::InvalidBlockDeviceID)?;
+
+        if let Some(path_on_host) = new_cfg.path_on_host {
+            self.set_block_device_path(new_cfg.drive_id.clone(), path_on_host)?;
+        }
+
+        if let Some(new_rlim_cfg) = new_cfg.rate_limiter {
+            // When the microvm is running, the live device is updated as well.
+            if self.is_instance_initialized() {
+                let handler = self
+                    .epoll_context
+                    .get_device_handler_by_device_id::<virtio::BlockEpollHandler>(
+                        TYPE_BLOCK,
+                        &new_cfg.drive_id,
+                    )
+                    .map_err(|_| DriveError::EpollHandlerNotFound)?;
+                let into_token_bucket = |bucket: Option<vmm_config::TokenBucketConfig>| {
+                    bucket.map(vmm_config::TokenBucketConfig::into_token_bucket)
+                };
+                handler.patch_rate_limiter(
+                    into_token_bucket(new_rlim_cfg.bandwidth),
+                    into_token_bucket(new_rlim_cfg.ops),
+                    into_token_bucket(new_rlim_cfg.read_bandwidth),
+                    into_token_bucket(new_rlim_cfg.read_ops),
+                    into_token_bucket(new_rlim_cfg.write_bandwidth),
+                    into_token_bucket(new_rlim_cfg.write_ops),
+                );
+            }
+
+            let old_cfg = &mut self.device_configs.block.config_list[block_device_index];
+            if let Some(ref mut old_rlim_cfg) = old_cfg.rate_limiter {
+                // We already have a rate limiter set, so we'll update it.
+                old_rlim_cfg.update(&new_rlim_cfg);
+            } else {
+                // No old rate limiter; create one now.
+                old_cfg.rate_limiter = Some(new_rlim_cfg);
+            }
+        }
+        Ok(())
+    }
+
     /// Triggers a rescan of the host file backing the emulated block device with id `drive_id`.
     pub fn rescan_block_device(&mut self, drive_id: &str) -> UserResult {
         // Rescan can only happen after the guest is booted.
@@ -1523,9 +3300,13 @@ impl Vmm {
         let device_manager = self.mmio_device_manager.as_ref().unwrap();
         for drive_config in self.device_configs.block.config_list.iter() {
             if drive_config.drive_id == *drive_id {
-                let metadata = metadata(&drive_config.path_on_host)
+                // The size of an NBD export does not change while the client is connected.
+                if is_nbd_uri(&drive_config.path_on_host) {
+                    return Err(DriveError::NbdUpdateNotAllowedPostBoot.into());
+                }
+                // The guest sees the virtual size of the disk image, not the size of its file.
+                let new_size = disk_image_size(&drive_config.path_on_host, drive_config.format)
                     .map_err(|_| DriveError::BlockDeviceUpdateFailed)?;
-                let new_size = metadata.len();
                 if new_size % virtio::block::SECTOR_SIZE != 0 {
                     warn!(
                         "Disk size {} is not a multiple of sector size {}; \
@@ -1542,6 +3323,71 @@ impl Vmm {
         Err(VmmActionError::from(DriveError::InvalidBlockDeviceID))
     }
 
+    /// Writes the contents of the drive with id `drive_id`, made of its base image and of its
+    /// overlay, to a new standalone raw image. The microVM must be paused or not started, so
+    /// that the guest does not write to the drive meanwhile.
+    pub fn merge_overlay(&mut self, config: OverlayMergeConfig) -> UserResult {
+        match self.instance_state() {
+            InstanceState::Uninitialized | InstanceState::Paused => (),
+            InstanceState::Starting | InstanceState::Running => {
+                return Err(DriveError::MergeNotAllowedWhileRunning.into());
+            }
+        }
+        let drive_config = self
+            .device_configs
+            .block
+            .config_list
+            .iter()
+            .find(|drive_config| drive_config.drive_id == config.drive_id)
+            .ok_or(DriveError::InvalidBlockDeviceID)?;
+        let overlay_path = drive_config
+            .overlay_path
+            .as_ref()
+            .ok_or(DriveError::NoOverlay)?;
+        // The VMM thread cannot reach NBD servers over TCP once it loaded its seccomp filters.
+        if is_nbd_uri(&drive_config.path_on_host) && self.is_instance_initialized() {
+            return Err(DriveError::NbdUpdateNotAllowedPostBoot.into());
+        }
+
+        // The overlay is written through by the block device, so a separate handle sees all the
+        // sectors written so far.
+        let merge = || -> io::Result<()> {
+            let base = if is_nbd_uri(&drive_config.path_on_host) {
+                let address = NbdAddress::parse(&drive_config.path_on_host)?;
+                DiskImage::Nbd(NbdDisk::connect(&address, false)?)
+            } else {
+                let base = OpenOptions::new()
+                    .read(true)
+                    .open(&drive_config.path_on_host)?;
+                DiskImage::new(base, drive_config.format, &drive_config.path_on_host)?
+            };
+            let overlay = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(overlay_path)?;
+            let mut overlay = OverlayFile::open(base, overlay)?;
+            let mut dest = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&config.destination_path)?;
+            let result = overlay.merge_into(&mut dest);
+            // The partial image was created above, so it is not someone else's file.
+            if result.is_err() {
+                if let Err(e) = std::fs::remove_file(&config.destination_path) {
+                    warn!("Cannot remove the partially merged image: {}", e);
+                }
+            }
+            result
+        };
+        merge().map_err(|e| {
+            error!(
+                "Cannot merge the overlay of drive {}: {}",
+                config.drive_id, e
+            );
+            DriveError::CannotMergeOverlay.into()
+        })
+    }
+
     /// Inserts a block to be attached when the VM starts.
     // Only call this function as part of user configuration.
     // If the drive_id does not exist, a new Block Device Config is added to the list.
@@ -1661,6 +3507,9 @@ impl Vmm {
         if let Some(vsock_config) = vmm_config.vsock_device {
             self.set_vsock_device(vsock_config)?;
         }
+        if let Some(balloon_config) = vmm_config.balloon_device {
+            self.set_balloon_device(balloon_config)?;
+        }
         Ok(())
     }
 
@@ -1691,13 +3540,17 @@ mod tests {
     use std::io::BufRead;
     use std::io::BufReader;
     use std::sync::atomic::AtomicUsize;
+    use std::thread;
 
-    use self::tempfile::NamedTempFile;
+    use self::tempfile::{NamedTempFile, TempDir};
     use arch::DeviceType;
     use devices::virtio::{ActivateResult, MmioDevice, Queue};
     use dumbo::MacAddr;
-    use vmm_config::drive::DriveError;
+    #[cfg(target_arch = "x86_64")]
+    use migration::MessageKind;
+    use vmm_config::drive::{BlockDeviceUpdateConfig, DriveError, IoEngine, OverlayMergeConfig};
     use vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm_config::net::EgressFilterConfig;
     use vmm_config::{RateLimiterConfig, TokenBucketConfig};
 
     fn good_kernel_file() -> PathBuf {
@@ -1742,15 +3595,11 @@ mod tests {
             assert!(cmdline.insert_str(DEFAULT_KERNEL_CMDLINE).is_ok());
             let kernel_cfg = KernelConfig {
                 cmdline,
-                kernel_file,
+                kernel_file: Some(kernel_file),
             };
             self.set_kernel_config(kernel_cfg);
         }
 
-        fn set_instance_state(&mut self, instance_state: InstanceState) {
-            self.shared_info.write().unwrap().state = instance_state;
-        }
-
         fn update_block_device_path(&mut self, block_device_id: &str, new_path: PathBuf) {
             for config in self.device_configs.block.config_list.iter_mut() {
                 if config.drive_id == block_device_id {
@@ -1856,7 +3705,7 @@ mod tests {
         let mut ep = EpollContext::new().unwrap();
         let (base, sender) = ep.allocate_tokens_for_device(1);
         assert_eq!(ep.device_handlers.len(), 1);
-        assert_eq!(base, 1);
+        assert_eq!(base, 2);
 
         let handler = DummyEpollHandler { evt: None };
         assert!(sender.send(Box::new(handler)).is_ok());
@@ -1875,6 +3724,12 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -1891,6 +3746,12 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -1907,6 +3768,12 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_err());
 
@@ -1920,6 +3787,12 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(non_root).is_ok());
 
@@ -1932,6 +3805,12 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(non_root).is_err());
 
@@ -1944,6 +3823,12 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         assert!(vmm.insert_block_device(root_block_device).is_err())
     }
@@ -1960,6 +3845,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
@@ -1972,6 +3860,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
@@ -1983,6 +3874,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
 
@@ -1995,6 +3889,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
     }
@@ -2021,9 +3918,13 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig {
                 bandwidth: Some(tbc_1mtps),
                 ops: None,
+                ..Default::default()
             }),
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         })
         .unwrap();
 
@@ -2032,10 +3933,12 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig {
                 bandwidth: None,
                 ops: Some(tbc_2mtps),
+                ..Default::default()
             }),
             tx_rate_limiter: Some(RateLimiterConfig {
                 bandwidth: None,
                 ops: Some(tbc_2mtps),
+                ..Default::default()
             }),
         })
         .unwrap();
@@ -2105,15 +4008,250 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig {
                 bandwidth: Some(tbc_2mtps),
                 ops: None,
+                ..Default::default()
             }),
             tx_rate_limiter: Some(RateLimiterConfig {
                 bandwidth: Some(tbc_1mtps),
                 ops: None,
+                ..Default::default()
             }),
         })
         .unwrap();
     }
 
+    #[test]
+    fn test_update_vhost_net_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        vmm.insert_net_device(NetworkInterfaceConfig {
+            iface_id: String::from("1"),
+            host_dev_name: String::from("vhosthostname"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: true,
+            egress_filter: None,
+        })
+        .unwrap();
+
+        // Interfaces using vhost-net cannot be rate limited.
+        match vmm.update_net_device(NetworkInterfaceUpdateConfig {
+            iface_id: "1".to_string(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: Some(RateLimiterConfig::default()),
+        }) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::VhostWithRateLimiter,
+            )) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithRateLimiter"),
+        }
+        assert!(vmm
+            .device_configs
+            .network_interface
+            .iter()
+            .next()
+            .unwrap()
+            .tx_rate_limiter
+            .is_none());
+
+        // The pages written by vhost-net are not tracked.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            track_dirty_pages: Some(true),
+            mem_backend: None,
+            backing_page_size: None,
+        };
+        assert_match!(
+            vmm.set_vm_configuration(machine_config.clone()),
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::DirtyPageTrackingWithVhost
+            ))
+        );
+        let mut tracking_vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(tracking_vmm.set_vm_configuration(machine_config).is_ok());
+        assert_match!(
+            tracking_vmm.insert_net_device(
+                vmm.device_configs
+                    .network_interface
+                    .iter()
+                    .next()
+                    .unwrap()
+                    .clone()
+            ),
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::VhostWithDirtyPageTracking
+            ))
+        );
+
+        // The frames of interfaces using vhost-net cannot be captured.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.capture_net_device(NetworkInterfaceCaptureConfig {
+            iface_id: "1".to_string(),
+            capture_path: Some(PathBuf::from("/tmp/vhost.pcap")),
+            snap_len: 65535,
+            max_size_bytes: 1 << 20,
+        }) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::VhostWithCapture,
+            )) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithCapture"),
+        }
+
+        // The rings of interfaces using vhost-net are neither stopped nor saved.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let dir = TempDir::new().unwrap();
+            assert_match!(
+                vmm.create_snapshot(SnapshotCreateConfig {
+                    snapshot_path: dir.path().join("snapshot"),
+                    mem_file_path: dir.path().join("mem"),
+                    snapshot_type: SnapshotType::Full,
+                }),
+                Err(VmmActionError::Snapshot(
+                    ErrorKind::User,
+                    SnapshotError::VhostNet
+                ))
+            );
+            vmm.vm_config.track_dirty_pages = Some(true);
+            assert_match!(
+                vmm.send_migration(MigrationSendConfig {
+                    socket_path: dir.path().join("migration.sock"),
+                }),
+                Err(VmmActionError::Migration(
+                    ErrorKind::User,
+                    MigrationError::VhostNet
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_capture_net_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.insert_net_device(NetworkInterfaceConfig {
+            iface_id: String::from("1"),
+            host_dev_name: String::from("capturehost"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            allow_mmds_requests: true,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
+        })
+        .unwrap();
+
+        let capture_dir = TempDir::new().unwrap();
+        let capture_path = capture_dir.path().join("capture.pcap");
+        let capture_cfg = |iface_id: &str, capture_path: Option<&Path>, snap_len: u32| {
+            NetworkInterfaceCaptureConfig {
+                iface_id: iface_id.to_string(),
+                capture_path: capture_path.map(Path::to_path_buf),
+                snap_len,
+                max_size_bytes: 1 << 20,
+            }
+        };
+        macro_rules! assert_capture_error {
+            ($config: expr, $error: pat) => {
+                match vmm.capture_net_device($config) {
+                    Err(VmmActionError::NetworkConfig(ErrorKind::User, $error)) => (),
+                    _ => panic!("Unexpected capture outcome"),
+                }
+            };
+        }
+
+        // Frames can only be captured after boot.
+        assert_capture_error!(
+            capture_cfg("1", Some(&capture_path), 65535),
+            NetworkInterfaceError::OperationNotAllowedPreBoot
+        );
+
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.setup_interrupt_controller().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+        vmm.attach_net_devices().unwrap();
+        vmm.set_instance_state(InstanceState::Running);
+
+        assert_capture_error!(
+            capture_cfg("2", Some(&capture_path), 65535),
+            NetworkInterfaceError::DeviceIdNotFound
+        );
+        assert_capture_error!(
+            capture_cfg("1", Some(&capture_path), 0),
+            NetworkInterfaceError::InvalidCaptureLimits
+        );
+        assert_capture_error!(
+            capture_cfg(
+                "1",
+                Some(&capture_dir.path().join("missing/capture.pcap")),
+                65535
+            ),
+            NetworkInterfaceError::CreateCapture(_)
+        );
+        assert!(!capture_path.exists());
+
+        // Existing files are never overwritten, nor are the targets of symbolic links.
+        let existing_path = capture_dir.path().join("existing");
+        std::fs::write(&existing_path, b"data").unwrap();
+        assert_capture_error!(
+            capture_cfg("1", Some(&existing_path), 65535),
+            NetworkInterfaceError::CaptureFileExists
+        );
+        let link_path = capture_dir.path().join("link");
+        std::os::unix::fs::symlink(capture_dir.path().join("target"), &link_path).unwrap();
+        assert_capture_error!(
+            capture_cfg("1", Some(&link_path), 65535),
+            NetworkInterfaceError::CaptureFileExists
+        );
+        assert_eq!(std::fs::read(&existing_path).unwrap(), b"data");
+        assert!(!capture_dir.path().join("target").exists());
+
+        // Activate the device.
+        {
+            let device_manager = vmm.mmio_device_manager.as_ref().unwrap();
+            let bus_device_mutex = device_manager
+                .get_device(DeviceType::Virtio(TYPE_NET), "1")
+                .unwrap();
+            let bus_device = &mut *bus_device_mutex.lock().unwrap();
+            let mmio_device: &mut MmioDevice = bus_device
+                .as_mut_any()
+                .downcast_mut::<MmioDevice>()
+                .unwrap();
+
+            assert!(mmio_device
+                .device_mut()
+                .activate(
+                    vmm.guest_memory().unwrap().clone(),
+                    EventFd::new().unwrap(),
+                    Arc::new(AtomicUsize::new(0)),
+                    vec![Queue::new(0), Queue::new(0)],
+                    vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+                )
+                .is_ok());
+        }
+
+        // The capture starts with the pcap header.
+        vmm.capture_net_device(capture_cfg("1", Some(&capture_path), 65535))
+            .unwrap();
+        assert_eq!(
+            std::fs::metadata(&capture_path).unwrap().len(),
+            virtio::PCAP_HEADER_SIZE
+        );
+        vmm.capture_net_device(capture_cfg("1", None, 65535))
+            .unwrap();
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_machine_configuration() {
@@ -2136,6 +4274,9 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2148,6 +4289,9 @@ mod tests {
             mem_size_mib: Some(256),
             ht_enabled: None,
             cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2161,6 +4305,9 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2172,6 +4319,9 @@ mod tests {
             mem_size_mib: Some(0),
             ht_enabled: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2187,6 +4337,9 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.ht_enabled, Some(false));
@@ -2197,19 +4350,69 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(2));
         assert_eq!(vmm.vm_config.ht_enabled, Some(true));
         assert_eq!(vmm.vm_config.cpu_template, Some(CpuFeaturesTemplate::T2));
 
-        // 3. Test update vm configuration after boot.
+        // 3. Test update vm configuration after boot.
+        // The memory size must be a multiple of the size of the huge pages.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: Some(129),
+            ht_enabled: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: Some(BackingPageSize::Size2M),
+        };
+        match vmm.set_vm_configuration(machine_config.clone()) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::MemorySizeNotHugePageAligned,
+            )) => (),
+            _ => panic!("Unexpected machine configuration result."),
+        }
+        assert!(vmm
+            .set_vm_configuration(VmConfig {
+                mem_size_mib: Some(256),
+                ..machine_config
+            })
+            .is_ok());
+        assert_eq!(
+            vmm.vm_config().backing_page_size,
+            Some(BackingPageSize::Size2M)
+        );
+        // The memory size cannot be updated to an odd size either.
+        match vmm.set_vm_configuration(VmConfig {
+            vcpu_count: None,
+            mem_size_mib: Some(255),
+            ht_enabled: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
+        }) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::MemorySizeNotHugePageAligned,
+            )) => (),
+            _ => panic!("Unexpected machine configuration result."),
+        }
+
         vmm.set_instance_state(InstanceState::Running);
         let machine_config = VmConfig {
             vcpu_count: Some(2),
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
     }
@@ -2261,6 +4464,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_device_events() {
+        let mut ep = EpollContext::new().unwrap();
+        let evfd = EventFd::new().unwrap();
+        let (dispatch_base, _) = ep.allocate_tokens_for_device(1);
+        epoll::ctl(
+            ep.device_epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            evfd.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_base),
+        )
+        .unwrap();
+        assert!(evfd.write(1).is_ok());
+
+        // The device event is reported through the main epoll fd.
+        let event = ep.get_event().unwrap();
+        assert_eq!({ event.data }, dispatch_base);
+        assert_eq!(
+            ep.dispatch_table[event.data as usize],
+            Some(EpollDispatch::DeviceHandler(0, 0))
+        );
+
+        // While the device events are disabled, nothing is reported.
+        assert!(ep.disable_device_events().is_ok());
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 10];
+        assert_eq!(epoll::wait(ep.epoll_raw_fd, 0, &mut events[..]).unwrap(), 0);
+
+        // The pending event is reported again once the device events are enabled.
+        assert!(ep.enable_device_events().is_ok());
+        let event = ep.get_event().unwrap();
+        assert_eq!({ event.data }, dispatch_base);
+    }
+
     #[test]
     fn test_check_health() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2268,7 +4504,7 @@ mod tests {
 
         vmm.set_kernel_config(KernelConfig {
             cmdline: kernel_cmdline::Cmdline::new(10),
-            kernel_file: tempfile::tempfile().unwrap(),
+            kernel_file: Some(tempfile::tempfile().unwrap()),
         });
         assert!(vmm.check_health().is_ok());
     }
@@ -2314,6 +4550,12 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         // Test that creating a new block device returns the correct output.
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2337,6 +4579,12 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2363,6 +4611,12 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2393,6 +4647,30 @@ mod tests {
                 .is_some());
         }
 
+        // Use Case 4: The requests handled by the drive are traced.
+        let trace_dir = TempDir::new().unwrap();
+        for &(ref trace_path, is_ok) in &[
+            (trace_dir.path().join("trace"), true),
+            (trace_dir.path().join("missing").join("trace"), false),
+        ] {
+            let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+            let traced_block_device = BlockDeviceConfig {
+                trace_path: Some(trace_path.clone()),
+                ..non_root_block_device.clone()
+            };
+            assert!(vmm.insert_block_device(traced_block_device).is_ok());
+            assert!(vmm.init_guest_memory().is_ok());
+            assert!(vmm.setup_interrupt_controller().is_ok());
+            vmm.default_kernel_config(None);
+            vmm.init_mmio_device_manager()
+                .expect("Cannot initialize mmio device manager");
+            match vmm.attach_block_devices() {
+                Ok(()) => assert!(is_ok && trace_path.exists()),
+                Err(StartMicrovmError::CreateBlockTrace(_)) => assert!(!is_ok),
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+
         // Test partial update of block devices.
         let new_block = NamedTempFile::new().unwrap();
         let path = String::from(new_block.path().to_path_buf().to_str().unwrap());
@@ -2407,6 +4685,18 @@ mod tests {
 
         vmm.set_instance_state(InstanceState::Running);
 
+        // Test switching a drive to an NBD export, after instance start.
+        assert_match!(
+            vmm.set_block_device_path(
+                "not_root".to_string(),
+                String::from("nbd://127.0.0.1:10809")
+            ),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::NbdUpdateNotAllowedPostBoot
+            ))
+        );
+
         // Test updating the block device path, after instance start.
         let path = String::from(new_block.path().to_path_buf().to_str().unwrap());
         match vmm.set_block_device_path("not_root".to_string(), path) {
@@ -2439,8 +4729,41 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
+        };
+
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+
+        // Network interfaces can have several queue pairs.
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("mqnetif"),
+            host_dev_name: String::from("mqhostname5"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            allow_mmds_requests: false,
+            num_queues: 4,
+            vhost: false,
+            egress_filter: None,
         };
+        assert!(vmm.insert_net_device(network_interface).is_ok());
 
+        // The frames sent by the guest can be filtered.
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("filterednetif"),
+            host_dev_name: String::from("filterhost6"),
+            guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0c").unwrap()),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: Some(EgressFilterConfig {
+                allowed_ipv4_addresses: Some(vec!["192.168.0.2".parse().unwrap()]),
+            }),
+        };
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
         assert!(vmm.attach_net_devices().is_ok());
@@ -2449,6 +4772,108 @@ mod tests {
         assert!(vmm.attach_net_devices().is_err());
     }
 
+    #[test]
+    fn test_balloon() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert_match!(
+            vmm.update_balloon(BalloonUpdateConfig { amount_mib: 16 }),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::DeviceNotFound
+            ))
+        );
+        assert!(vmm.balloon_config().is_err());
+
+        let mut config = BalloonDeviceConfig {
+            amount_mib: 1024,
+            deflate_on_oom: true,
+            free_page_reporting: false,
+            stats_polling_interval_s: 0,
+        };
+        // The balloon cannot be larger than the guest memory.
+        assert_match!(
+            vmm.set_balloon_device(config.clone()),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::TooManyPagesRequested
+            ))
+        );
+        config.amount_mib = 16;
+        assert!(vmm.set_balloon_device(config.clone()).is_ok());
+        assert!(vmm
+            .update_balloon(BalloonUpdateConfig { amount_mib: 32 })
+            .is_ok());
+        config.amount_mib = 32;
+        assert_eq!(vmm.balloon_config().unwrap(), config);
+        assert_match!(
+            vmm.balloon_statistics(),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::StatisticsDisabled
+            ))
+        );
+        config.stats_polling_interval_s = 1;
+        assert!(vmm.set_balloon_device(config.clone()).is_ok());
+
+        // The balloon cannot release the pages of a memory backed by 2M pages.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            mem_backend: None,
+            backing_page_size: Some(BackingPageSize::Size2M),
+        };
+        assert_match!(
+            vmm.set_vm_configuration(machine_config.clone()),
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::HugePagesWithBalloon
+            ))
+        );
+        let mut huge_pages_vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(huge_pages_vmm.set_vm_configuration(machine_config).is_ok());
+        assert_match!(
+            huge_pages_vmm.set_balloon_device(config),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::HugePagesBacking
+            ))
+        );
+
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.setup_interrupt_controller()
+            .expect("Failed to setup interrupt controller");
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+        assert!(vmm.attach_balloon_device().is_ok());
+        vmm.set_instance_state(InstanceState::Running);
+
+        // The device can only be added before boot.
+        assert_match!(
+            vmm.set_balloon_device(vmm.balloon_config().unwrap()),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::UpdateNotAllowedPostBoot
+            ))
+        );
+        // The guest driver did not load.
+        assert_match!(
+            vmm.update_balloon(BalloonUpdateConfig { amount_mib: 64 }),
+            Err(VmmActionError::Balloon(
+                ErrorKind::User,
+                BalloonError::UpdateFailed
+            ))
+        );
+        let stats = vmm.balloon_statistics().unwrap();
+        assert_eq!(stats.target_mib, 32);
+        assert_eq!(stats.target_pages, 32 * 256);
+        assert_eq!(stats.actual_pages, 0);
+        assert_eq!(stats.memory_stats, devices::virtio::BalloonStats::default());
+    }
+
     #[test]
     fn test_init_devices() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2509,6 +4934,135 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_update_block_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.default_kernel_config(None);
+
+        let tbc_1mtps = TokenBucketConfig {
+            size: 1024 * 1024,
+            one_time_burst: None,
+            refill_time: 1000,
+        };
+        let tbc_2mtps = TokenBucketConfig {
+            size: 2 * 1024 * 1024,
+            one_time_burst: None,
+            refill_time: 1000,
+        };
+
+        let scratch_file = NamedTempFile::new().unwrap();
+        let scratch_id = "scratch".to_string();
+        vmm.insert_block_device(BlockDeviceConfig {
+            drive_id: scratch_id.clone(),
+            path_on_host: scratch_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: Some(RateLimiterConfig {
+                bandwidth: Some(tbc_1mtps),
+                ..Default::default()
+            }),
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
+        })
+        .unwrap();
+
+        // Test update_block_device with invalid ID.
+        assert_match!(
+            vmm.update_block_device(BlockDeviceUpdateConfig {
+                drive_id: "foo".to_string(),
+                path_on_host: None,
+                rate_limiter: None,
+            }),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDeviceID
+            ))
+        );
+
+        // Before boot, the new rate limiter config is merged into the existing one.
+        vmm.update_block_device(BlockDeviceUpdateConfig {
+            drive_id: scratch_id.clone(),
+            path_on_host: None,
+            rate_limiter: Some(RateLimiterConfig {
+                write_ops: Some(tbc_2mtps),
+                ..Default::default()
+            }),
+        })
+        .unwrap();
+        {
+            let rate_limiter = vmm.device_configs.block.config_list[0]
+                .rate_limiter
+                .unwrap();
+            assert_eq!(rate_limiter.bandwidth, Some(tbc_1mtps));
+            assert_eq!(rate_limiter.write_ops, Some(tbc_2mtps));
+        }
+
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.setup_interrupt_controller().is_ok());
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+
+        vmm.attach_block_devices().unwrap();
+        vmm.set_instance_state(InstanceState::Running);
+
+        let update = || BlockDeviceUpdateConfig {
+            drive_id: scratch_id.clone(),
+            path_on_host: None,
+            rate_limiter: Some(RateLimiterConfig {
+                bandwidth: Some(tbc_2mtps),
+                read_ops: Some(tbc_1mtps),
+                ..Default::default()
+            }),
+        };
+
+        // The update should fail before device activation.
+        assert_match!(
+            vmm.update_block_device(update()),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::EpollHandlerNotFound
+            ))
+        );
+
+        // Activate the device
+        {
+            let device_manager = vmm.mmio_device_manager.as_ref().unwrap();
+            let bus_device_mutex = device_manager
+                .get_device(DeviceType::Virtio(TYPE_BLOCK), &scratch_id)
+                .unwrap();
+            let bus_device = &mut *bus_device_mutex.lock().unwrap();
+            let mmio_device: &mut MmioDevice = bus_device
+                .as_mut_any()
+                .downcast_mut::<MmioDevice>()
+                .unwrap();
+
+            assert!(mmio_device
+                .device_mut()
+                .activate(
+                    vmm.guest_memory().unwrap().clone(),
+                    EventFd::new().unwrap(),
+                    Arc::new(AtomicUsize::new(0)),
+                    vec![Queue::new(0)],
+                    vec![EventFd::new().unwrap()],
+                )
+                .is_ok());
+        }
+
+        // The update should succeed after the device activation.
+        vmm.update_block_device(update()).unwrap();
+        let rate_limiter = vmm.device_configs.block.config_list[0]
+            .rate_limiter
+            .unwrap();
+        assert_eq!(rate_limiter.bandwidth, Some(tbc_2mtps));
+        assert_eq!(rate_limiter.read_ops, Some(tbc_1mtps));
+        assert_eq!(rate_limiter.write_ops, Some(tbc_2mtps));
+    }
+
     #[test]
     fn test_block_device_rescan() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2525,6 +5079,12 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         let non_root_block_device = BlockDeviceConfig {
             drive_id: scratch_id.clone(),
@@ -2533,6 +5093,12 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2565,66 +5131,197 @@ mod tests {
 
         vmm.set_instance_state(InstanceState::Running);
 
-        // Test valid rescan_block_device.
-        assert!(vmm.rescan_block_device(&scratch_id).is_ok());
+        // Test valid rescan_block_device.
+        assert!(vmm.rescan_block_device(&scratch_id).is_ok());
+
+        // Test rescan block device with size not a multiple of sector size.
+        let new_size = 10 * virtio::block::SECTOR_SIZE + 1;
+        scratch_file.as_file().set_len(new_size).unwrap();
+        assert!(vmm.rescan_block_device(&scratch_id).is_ok());
+
+        // Test rescan block device with invalid path.
+        let prev_path = non_root_block_device.path_on_host().clone();
+        vmm.update_block_device_path(&scratch_id, PathBuf::from("foo"));
+        assert_match!(
+            vmm.rescan_block_device(&scratch_id),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::BlockDeviceUpdateFailed,
+            ))
+        );
+        vmm.update_block_device_path(&scratch_id, prev_path);
+
+        // Test rescan_block_device with invalid ID.
+        assert_match!(
+            vmm.rescan_block_device(&"foo".to_string()),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDeviceID
+            ))
+        );
+
+        vmm.change_id(&scratch_id, "scratch");
+        assert_match!(
+            vmm.rescan_block_device(&scratch_id),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDeviceID
+            ))
+        );
+
+        // Test rescan_block_device with invalid device address.
+        vmm.remove_device_info(TYPE_BLOCK, &scratch_id);
+
+        assert_match!(
+            vmm.rescan_block_device(&scratch_id),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDeviceID
+            ))
+        );
+
+        // Test rescan not allowed.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm
+            .insert_block_device(non_root_block_device.clone())
+            .is_ok());
+
+        assert_match!(
+            vmm.rescan_block_device(&scratch_id),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::OperationNotAllowedPreBoot,
+            ))
+        );
+    }
+
+    #[test]
+    fn test_merge_overlay() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base");
+        let overlay_path = dir.path().join("overlay");
+        let dest_path = dir.path().join("merged");
+        std::fs::write(&base_path, vec![1u8; 0x4000]).unwrap();
+
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: base_path.clone(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: Some(overlay_path.clone()),
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
+        };
+        assert!(vmm.insert_block_device(block_device.clone()).is_ok());
+        let merge_config = OverlayMergeConfig {
+            drive_id: String::from("root"),
+            destination_path: dest_path.clone(),
+        };
+        // The overlay is only created along with the block device.
+        assert_match!(
+            vmm.merge_overlay(merge_config.clone()),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::CannotMergeOverlay
+            ))
+        );
+
+        // Write to the drive, as the guest would.
+        {
+            let mut disk_image = open_disk_image(&block_device, &base_path).unwrap();
+            disk_image.seek(SeekFrom::Start(0x200)).unwrap();
+            disk_image.write_all(&[2u8; 0x200]).unwrap();
+        }
+        assert_eq!(std::fs::read(&base_path).unwrap(), vec![1u8; 0x4000]);
 
-        // Test rescan block device with size not a multiple of sector size.
-        let new_size = 10 * virtio::block::SECTOR_SIZE + 1;
-        scratch_file.as_file().set_len(new_size).unwrap();
-        assert!(vmm.rescan_block_device(&scratch_id).is_ok());
+        assert!(vmm.merge_overlay(merge_config.clone()).is_ok());
+        let merged = std::fs::read(&dest_path).unwrap();
+        assert_eq!(merged.len(), 0x4000);
+        assert!(merged[..0x200].iter().all(|&b| b == 1));
+        assert!(merged[0x200..0x400].iter().all(|&b| b == 2));
+        assert!(merged[0x400..].iter().all(|&b| b == 1));
 
-        // Test rescan block device with invalid path.
-        let prev_path = non_root_block_device.path_on_host().clone();
-        vmm.update_block_device_path(&scratch_id, PathBuf::from("foo"));
+        // The destination must not exist.
         assert_match!(
-            vmm.rescan_block_device(&scratch_id),
+            vmm.merge_overlay(merge_config.clone()),
             Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
-                DriveError::BlockDeviceUpdateFailed,
+                DriveError::CannotMergeOverlay
             ))
         );
-        vmm.update_block_device_path(&scratch_id, prev_path);
 
-        // Test rescan_block_device with invalid ID.
+        // A failed merge leaves no partial image behind.
+        let overlay = OpenOptions::new().write(true).open(&overlay_path).unwrap();
+        let overlay_len = overlay.metadata().unwrap().len();
+        overlay.set_len(overlay_len - 0x4000).unwrap();
+        let failed_path = dir.path().join("failed");
         assert_match!(
-            vmm.rescan_block_device(&"foo".to_string()),
+            vmm.merge_overlay(OverlayMergeConfig {
+                drive_id: String::from("root"),
+                destination_path: failed_path.clone(),
+            }),
             Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
-                DriveError::InvalidBlockDeviceID
+                DriveError::CannotMergeOverlay
             ))
         );
+        assert!(!failed_path.exists());
+        overlay.set_len(overlay_len).unwrap();
 
-        vmm.change_id(&scratch_id, "scratch");
+        // The guest must not write to the drive while it is merged.
+        vmm.set_instance_state(InstanceState::Running);
         assert_match!(
-            vmm.rescan_block_device(&scratch_id),
+            vmm.merge_overlay(merge_config.clone()),
             Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
-                DriveError::InvalidBlockDeviceID
+                DriveError::MergeNotAllowedWhileRunning
             ))
         );
-
-        // Test rescan_block_device with invalid device address.
-        vmm.remove_device_info(TYPE_BLOCK, &scratch_id);
+        vmm.set_instance_state(InstanceState::Uninitialized);
 
         assert_match!(
-            vmm.rescan_block_device(&scratch_id),
+            vmm.merge_overlay(OverlayMergeConfig {
+                drive_id: String::from("foo"),
+                destination_path: dest_path.clone(),
+            }),
             Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
                 DriveError::InvalidBlockDeviceID
             ))
         );
 
-        // Test rescan not allowed.
-        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
-        assert!(vmm
-            .insert_block_device(non_root_block_device.clone())
-            .is_ok());
+        let block_device = BlockDeviceConfig {
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
+            ..block_device
+        };
+        assert!(vmm.insert_block_device(block_device.clone()).is_ok());
+        assert_match!(
+            vmm.merge_overlay(merge_config),
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::NoOverlay
+            ))
+        );
 
+        // The overlay cannot replace the base image.
+        let block_device = BlockDeviceConfig {
+            overlay_path: Some(base_path),
+            ..block_device
+        };
         assert_match!(
-            vmm.rescan_block_device(&scratch_id),
+            vmm.insert_block_device(block_device),
             Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
-                DriveError::OperationNotAllowedPreBoot,
+                DriveError::InvalidOverlayPath
             ))
         );
     }
@@ -2855,6 +5552,9 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         };
 
         assert!(vmm.insert_net_device(network_interface).is_ok());
@@ -3208,4 +5908,668 @@ mod tests {
 
         assert_eq!(vmm.kvm_vm().fd().as_raw_fd(), vmm.vm.fd().as_raw_fd());
     }
+
+    #[test]
+    fn test_pause_resume_vm() {
+        // Only a running microVM can be paused.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.pause_vm() {
+            Err(VmmActionError::VmRunState(
+                ErrorKind::User,
+                VmRunStateError::MicroVMNotRunning,
+            )) => (),
+            _ => panic!("Unexpected pause result."),
+        }
+        match vmm.resume_vm() {
+            Err(VmmActionError::VmRunState(ErrorKind::User, VmRunStateError::MicroVMNotPaused)) => {
+            }
+            _ => panic!("Unexpected resume result."),
+        }
+
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        assert!(vmm.pause_vm().is_ok());
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+        assert!(
+            vmm.epoll_context.dispatch_table[vmm.epoll_context.device_events_index as usize]
+                .is_none()
+        );
+        match vmm.pause_vm() {
+            Err(VmmActionError::VmRunState(
+                ErrorKind::User,
+                VmRunStateError::MicroVMNotRunning,
+            )) => (),
+            _ => panic!("Unexpected pause result."),
+        }
+
+        assert!(vmm.resume_vm().is_ok());
+        assert_eq!(vmm.instance_state(), InstanceState::Running);
+        assert_eq!(
+            vmm.epoll_context.dispatch_table[vmm.epoll_context.device_events_index as usize],
+            Some(EpollDispatch::DeviceEvents)
+        );
+        match vmm.resume_vm() {
+            Err(VmmActionError::VmRunState(ErrorKind::User, VmRunStateError::MicroVMNotPaused)) => {
+            }
+            _ => panic!("Unexpected resume result."),
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // Without a shutdown request, the process exits as soon as the guest stops.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        assert_match!(
+            vmm.exit_status(),
+            Err(VmmActionError::Shutdown(
+                ErrorKind::User,
+                ShutdownError::NotExited
+            ))
+        );
+        assert_eq!(
+            vmm.handle_exit(EventLoopExitReason::Break),
+            Some(FC_EXIT_CODE_OK)
+        );
+
+        // A microVM that did not start stops right away.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.shutdown(ShutdownConfig::default()).is_ok());
+        assert_match!(
+            vmm.shutdown(ShutdownConfig::default()),
+            Err(VmmActionError::Shutdown(
+                ErrorKind::User,
+                ShutdownError::InProgress
+            ))
+        );
+        assert_eq!(
+            vmm.exit_status().unwrap(),
+            ExitStatus {
+                exit_reason: EventLoopExitReason::Break,
+                exit_code: FC_EXIT_CODE_OK,
+            }
+        );
+        // The event loop returns once the exit status is no longer available.
+        assert_eq!(vmm.run_event_loop().unwrap(), EventLoopExitReason::Break);
+        assert_eq!(
+            vmm.handle_exit(EventLoopExitReason::Break),
+            Some(FC_EXIT_CODE_OK)
+        );
+
+        // The guest cannot be asked to shut down on aarch64.
+        #[cfg(target_arch = "aarch64")]
+        assert_match!(
+            create_vmm_object(InstanceState::Running).shutdown(ShutdownConfig::default()),
+            Err(VmmActionError::Shutdown(
+                ErrorKind::User,
+                ShutdownError::NotSupported
+            ))
+        );
+        #[cfg(target_arch = "x86_64")]
+        {
+            // A guest that is still running at the end of the grace period is stopped.
+            let mut vmm = create_vmm_object(InstanceState::Running);
+            assert!(vmm.pause_vm().is_ok());
+            assert!(vmm
+                .shutdown(ShutdownConfig {
+                    grace_period_ms: 10
+                })
+                .is_ok());
+            assert_eq!(vmm.instance_state(), InstanceState::Running);
+            assert_eq!(
+                vmm.run_event_loop().unwrap(),
+                EventLoopExitReason::ShutdownTimeout
+            );
+            assert_eq!(vmm.handle_exit(EventLoopExitReason::ShutdownTimeout), None);
+            assert_eq!(vmm.instance_state(), InstanceState::Paused);
+            assert_eq!(
+                vmm.exit_status().unwrap(),
+                ExitStatus {
+                    exit_reason: EventLoopExitReason::ShutdownTimeout,
+                    exit_code: FC_EXIT_CODE_SHUTDOWN_TIMEOUT,
+                }
+            );
+            assert_eq!(
+                vmm.run_event_loop().unwrap(),
+                EventLoopExitReason::ShutdownTimeout
+            );
+            assert_eq!(
+                vmm.handle_exit(EventLoopExitReason::ShutdownTimeout),
+                Some(FC_EXIT_CODE_SHUTDOWN_TIMEOUT)
+            );
+        }
+    }
+
+    #[test]
+    fn test_mem_backends() {
+        let mem_file = NamedTempFile::new().unwrap();
+
+        // The writes to a shared file reach the file, which is grown to the memory size.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: mem_file.path().to_path_buf(),
+            shared: true,
+        });
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.guest_memory()
+            .unwrap()
+            .write_obj_at_addr(0x1111u64, GuestAddress(0x1000))
+            .unwrap();
+        let mut mem = Vec::new();
+        mem_file.reopen().unwrap().read_to_end(&mut mem).unwrap();
+        assert_eq!(mem.len(), 1 << 20);
+        assert_eq!(&mem[0x1000..0x1002], &[0x11, 0x11]);
+
+        // A private mapping reads the file, but does not write to it.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: mem_file.path().to_path_buf(),
+            shared: false,
+        });
+        assert!(vmm.init_guest_memory().is_ok());
+        let guest_memory = vmm.guest_memory().unwrap().clone();
+        assert_eq!(
+            guest_memory
+                .read_obj_from_addr::<u64>(GuestAddress(0x1000))
+                .unwrap(),
+            0x1111
+        );
+        guest_memory
+            .write_obj_at_addr(0x2222u64, GuestAddress(0x1000))
+            .unwrap();
+        let mut mem = Vec::new();
+        mem_file.reopen().unwrap().read_to_end(&mut mem).unwrap();
+        assert_eq!(&mem[0x1000..0x1002], &[0x11, 0x11]);
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::Memfd);
+        assert!(vmm.init_guest_memory().is_ok());
+
+        // A private file must exist and hold the whole guest memory.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(2);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: mem_file.path().to_path_buf(),
+            shared: false,
+        });
+        match vmm.init_guest_memory() {
+            Err(StartMicrovmError::GuestMemory(_)) => (),
+            _ => panic!("Unexpected init guest memory result."),
+        }
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: PathBuf::from("/invalid/mem/path"),
+            shared: false,
+        });
+        match vmm.init_guest_memory() {
+            Err(StartMicrovmError::GuestMemoryBackend(_)) => (),
+            _ => panic!("Unexpected init guest memory result."),
+        }
+    }
+
+    #[test]
+    fn test_backing_page_sizes() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(4);
+        vmm.vm_config.backing_page_size = Some(BackingPageSize::Transparent);
+        assert!(vmm.init_guest_memory().is_ok());
+
+        // A file backing the memory with hugetlbfs pages must be on a hugetlbfs mount.
+        let mem_file = NamedTempFile::new().unwrap();
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(4);
+        vmm.vm_config.backing_page_size = Some(BackingPageSize::Size2M);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: mem_file.path().to_path_buf(),
+            shared: true,
+        });
+        match vmm.init_guest_memory() {
+            Err(StartMicrovmError::MemoryFileNotOnHugetlbfs) => (),
+            _ => panic!("Unexpected init guest memory result."),
+        }
+
+        // The result of allocating huge pages depends on how many of them the host reserved.
+        vmm.vm_config.mem_backend = Some(MemoryBackend::Anonymous);
+        if available_huge_pages() == Some(0) {
+            match vmm.init_guest_memory() {
+                Err(StartMicrovmError::NotEnoughHugePages(2)) => (),
+                _ => panic!("Unexpected init guest memory result."),
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_snapshot_preconditions() {
+        let snapshot_file = NamedTempFile::new().unwrap();
+        let mem_file = NamedTempFile::new().unwrap();
+
+        // Snapshots can only be created while the microVM is running.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.create_snapshot(SnapshotCreateConfig {
+            snapshot_path: snapshot_file.path().to_path_buf(),
+            mem_file_path: mem_file.path().to_path_buf(),
+            snapshot_type: SnapshotType::Full,
+        }) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MicroVMNotRunning)) => (),
+            _ => panic!("Unexpected create snapshot result."),
+        }
+
+        // Diff snapshots require dirty page tracking.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        match vmm.create_snapshot(SnapshotCreateConfig {
+            snapshot_path: snapshot_file.path().to_path_buf(),
+            mem_file_path: mem_file.path().to_path_buf(),
+            snapshot_type: SnapshotType::Diff,
+        }) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::DirtyPageTrackingDisabled,
+            )) => (),
+            _ => panic!("Unexpected create snapshot result."),
+        }
+
+        // The memory file cannot be the file backing the guest memory, whatever its path.
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.mem_backend = Some(MemoryBackend::File {
+            path: mem_file.path().to_path_buf(),
+            shared: true,
+        });
+        assert!(vmm.init_guest_memory().is_ok());
+        let link_dir = TempDir::new().unwrap();
+        let link_path = link_dir.path().join("mem");
+        std::fs::hard_link(mem_file.path(), &link_path).unwrap();
+        for mem_file_path in &[mem_file.path().to_path_buf(), link_path] {
+            match vmm.create_snapshot(SnapshotCreateConfig {
+                snapshot_path: snapshot_file.path().to_path_buf(),
+                mem_file_path: mem_file_path.clone(),
+                snapshot_type: SnapshotType::Full,
+            }) {
+                Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MemoryFileInUse)) => {}
+                _ => panic!("Unexpected create snapshot result."),
+            }
+        }
+        vmm.guest_memory = None;
+
+        // Nor the file the page fault handler loads the guest memory from.
+        vmm.vm_config.mem_backend = Some(MemoryBackend::Anonymous);
+        let guest_memory = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let (uffd, regions) = register_guest_memory(&guest_memory).unwrap();
+        vmm.page_fault_handler = Some(
+            PageFaultHandler::start(
+                uffd,
+                regions,
+                File::open(mem_file.path()).unwrap(),
+                seccomp::SECCOMP_LEVEL_NONE,
+            )
+            .unwrap(),
+        );
+        for mem_file_path in &[mem_file.path().to_path_buf(), link_dir.path().join("mem")] {
+            match vmm.create_snapshot(SnapshotCreateConfig {
+                snapshot_path: snapshot_file.path().to_path_buf(),
+                mem_file_path: mem_file_path.clone(),
+                snapshot_type: SnapshotType::Full,
+            }) {
+                Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MemoryFileInUse)) => {}
+                _ => panic!("Unexpected create snapshot result."),
+            }
+        }
+
+        // Snapshots can only be loaded before the microVM is started.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        let load_config = SnapshotLoadConfig {
+            snapshot_path: snapshot_file.path().to_path_buf(),
+            mem_file_path: mem_file.path().to_path_buf(),
+            mem_backend: SnapshotMemoryBackend::Uffd,
+        };
+        match vmm.load_snapshot(load_config.clone()) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::MicroVMAlreadyRunning,
+            )) => {}
+            _ => panic!("Unexpected load snapshot result."),
+        }
+
+        // The snapshot file must hold a valid microVM state.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.load_snapshot(load_config) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::Deserialize(_))) => (),
+            _ => panic!("Unexpected load snapshot result."),
+        }
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.load_snapshot(SnapshotLoadConfig {
+            snapshot_path: PathBuf::from("/invalid/snapshot/path"),
+            mem_file_path: mem_file.path().to_path_buf(),
+            mem_backend: SnapshotMemoryBackend::File,
+        }) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::OpenFile(_))) => (),
+            _ => panic!("Unexpected load snapshot result."),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_diff_snapshot() {
+        let snapshot_file = NamedTempFile::new().unwrap();
+        let base_mem_file = NamedTempFile::new().unwrap();
+        let diff_snapshot_file = NamedTempFile::new().unwrap();
+        let diff_mem_file = NamedTempFile::new().unwrap();
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.track_dirty_pages = Some(true);
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.setup_interrupt_controller().is_ok());
+        // Without vCPUs, the microVM can be snapshotted while paused.
+        vmm.set_instance_state(InstanceState::Paused);
+
+        let guest_memory = vmm.guest_memory().unwrap().clone();
+        guest_memory
+            .write_obj_at_addr(0x1111u64, GuestAddress(0x1000))
+            .unwrap();
+        assert!(vmm
+            .create_snapshot(SnapshotCreateConfig {
+                snapshot_path: snapshot_file.path().to_path_buf(),
+                mem_file_path: base_mem_file.path().to_path_buf(),
+                snapshot_type: SnapshotType::Full,
+            })
+            .is_ok());
+
+        // Only the pages written after the full snapshot end up in the diff snapshot.
+        guest_memory
+            .write_obj_at_addr(0x2222u64, GuestAddress(0x3008))
+            .unwrap();
+        let diff_config = SnapshotCreateConfig {
+            snapshot_path: diff_snapshot_file.path().to_path_buf(),
+            mem_file_path: diff_mem_file.path().to_path_buf(),
+            snapshot_type: SnapshotType::Diff,
+        };
+        assert!(vmm.create_snapshot(diff_config).is_ok());
+        let microvm_state: MicrovmState =
+            serde_json::from_reader(diff_snapshot_file.reopen().unwrap()).unwrap();
+        assert_eq!(microvm_state.memory_regions.len(), 1);
+        let dirty_pages = microvm_state.memory_regions[0]
+            .dirty_pages
+            .as_ref()
+            .unwrap();
+        assert_eq!(dirty_pages[0], 0b1000);
+        assert!(dirty_pages[1..].iter().all(|pages| *pages == 0));
+
+        let mut diff_mem = Vec::new();
+        diff_mem_file
+            .reopen()
+            .unwrap()
+            .read_to_end(&mut diff_mem)
+            .unwrap();
+        assert_eq!(diff_mem.len(), 1 << 20);
+        assert_eq!(&diff_mem[0x1000..0x1002], &[0, 0]);
+        assert_eq!(&diff_mem[0x3008..0x300a], &[0x22, 0x22]);
+
+        // A full snapshot cannot be merged.
+        match vmm.merge_snapshot(SnapshotMergeConfig {
+            snapshot_path: snapshot_file.path().to_path_buf(),
+            mem_file_path: base_mem_file.path().to_path_buf(),
+            base_mem_file_path: base_mem_file.path().to_path_buf(),
+        }) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::NotDiffSnapshot)) => (),
+            _ => panic!("Unexpected merge snapshot result."),
+        }
+
+        // Merging the diff onto the base memory file yields the current guest memory.
+        assert!(vmm
+            .merge_snapshot(SnapshotMergeConfig {
+                snapshot_path: diff_snapshot_file.path().to_path_buf(),
+                mem_file_path: diff_mem_file.path().to_path_buf(),
+                base_mem_file_path: base_mem_file.path().to_path_buf(),
+            })
+            .is_ok());
+        let mut base_mem = Vec::new();
+        base_mem_file
+            .reopen()
+            .unwrap()
+            .read_to_end(&mut base_mem)
+            .unwrap();
+        let mut expected_mem = vec![0u8; 1 << 20];
+        guest_memory
+            .read_slice_at_addr(&mut expected_mem, GuestAddress(0))
+            .unwrap();
+        assert!(base_mem == expected_mem);
+
+        // Snapshots saved by older versions can still be merged and loaded, and the ones that
+        // predate the versioned states are rejected.
+        let mut microvm_state: serde_json::Value =
+            serde_json::from_reader(snapshot_file.reopen().unwrap()).unwrap();
+        microvm_state["version"] = serde_json::Value::from(2);
+        // The test microVM has no vCPUs to restore.
+        microvm_state["vm_config"]["state"]["vcpu_count"] = serde_json::Value::from(0);
+        let old_snapshot_file = NamedTempFile::new().unwrap();
+        serde_json::to_writer(old_snapshot_file.as_file(), &microvm_state).unwrap();
+        let mut new_vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(new_vmm
+            .load_snapshot(SnapshotLoadConfig {
+                snapshot_path: old_snapshot_file.path().to_path_buf(),
+                mem_file_path: base_mem_file.path().to_path_buf(),
+                mem_backend: SnapshotMemoryBackend::File,
+            })
+            .is_ok());
+        microvm_state["version"] = serde_json::Value::from(1);
+        let old_snapshot_file = NamedTempFile::new().unwrap();
+        serde_json::to_writer(old_snapshot_file.as_file(), &microvm_state).unwrap();
+        let mut new_vmm = create_vmm_object(InstanceState::Uninitialized);
+        match new_vmm.load_snapshot(SnapshotLoadConfig {
+            snapshot_path: old_snapshot_file.path().to_path_buf(),
+            mem_file_path: base_mem_file.path().to_path_buf(),
+            mem_backend: SnapshotMemoryBackend::File,
+        }) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::UnsupportedVersion(1),
+            )) => {}
+            _ => panic!("Unexpected load snapshot result."),
+        }
+
+        // States saved with a version unknown to this build are rejected.
+        let mut microvm_state: serde_json::Value =
+            serde_json::from_reader(diff_snapshot_file.reopen().unwrap()).unwrap();
+        microvm_state["vm_config"]["version"] = serde_json::Value::from(99);
+        let future_snapshot_file = NamedTempFile::new().unwrap();
+        serde_json::to_writer(future_snapshot_file.as_file(), &microvm_state).unwrap();
+        match vmm.merge_snapshot(SnapshotMergeConfig {
+            snapshot_path: future_snapshot_file.path().to_path_buf(),
+            mem_file_path: diff_mem_file.path().to_path_buf(),
+            base_mem_file_path: base_mem_file.path().to_path_buf(),
+        }) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::Deserialize(e))) => {
+                assert!(e
+                    .to_string()
+                    .starts_with("Unsupported version 99 of the VmConfig state."));
+            }
+            _ => panic!("Unexpected merge snapshot result."),
+        }
+    }
+
+    // Runs the event loop until the migration in progress ended, and returns its outcome.
+    #[cfg(target_arch = "x86_64")]
+    fn wait_for_migration(vmm: &mut Vmm, started: UserResult) -> UserResult {
+        started?;
+        match vmm.run_event_loop().unwrap() {
+            EventLoopExitReason::MigrationDone => vmm.migration_result(),
+            exit_reason => panic!("Unexpected event loop exit reason {:?}.", exit_reason),
+        }
+    }
+
+    // Plays the destination of a migration: receives the guest memory of a 1 MiB microVM, then
+    // answers with `reply`.
+    #[cfg(target_arch = "x86_64")]
+    fn fake_migration_destination(listener: UnixListener, reply: &'static [u8]) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().unwrap();
+        migration::read_hello(&mut stream).unwrap();
+        let layout = migration::read_message(&mut stream, MessageKind::MemoryLayout).unwrap();
+        let memory_regions: Vec<MemoryRegionState> = serde_json::from_slice(&layout).unwrap();
+        assert_eq!(memory_regions.len(), 1);
+        assert_eq!(memory_regions[0].size, 1 << 20);
+
+        let mut memory = vec![0u8; 1 << 20];
+        loop {
+            match migration::read_header(&mut stream).unwrap() {
+                (MessageKind::Pages, len) => {
+                    let (address, len) = migration::read_pages_header(&mut stream, len).unwrap();
+                    let address = address as usize;
+                    stream
+                        .read_exact(&mut memory[address..address + len])
+                        .unwrap();
+                }
+                (MessageKind::State, len) => {
+                    let state = migration::read_payload(&mut stream, len).unwrap();
+                    let microvm_state: MicrovmState = serde_json::from_slice(&state).unwrap();
+                    assert_eq!(microvm_state.memory_regions, memory_regions);
+                    break;
+                }
+                (kind, _) => panic!("Unexpected migration message {:?}.", kind),
+            }
+        }
+
+        let kind = if reply.is_empty() {
+            MessageKind::Done
+        } else {
+            MessageKind::Error
+        };
+        migration::write_message(&mut stream, kind, reply).unwrap();
+        memory
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_migration_preconditions() {
+        let socket_dir = TempDir::new().unwrap();
+        let socket_path = socket_dir.path().join("migration.sock");
+
+        // Only running or paused microVMs can be migrated.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.send_migration(MigrationSendConfig {
+            socket_path: socket_path.clone(),
+        }) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::MicroVMNotRunning)) => {}
+            _ => panic!("Unexpected send migration result."),
+        }
+
+        // Migrations require dirty page tracking.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        match vmm.send_migration(MigrationSendConfig {
+            socket_path: socket_path.clone(),
+        }) {
+            Err(VmmActionError::Migration(
+                ErrorKind::User,
+                MigrationError::DirtyPageTrackingDisabled,
+            )) => (),
+            _ => panic!("Unexpected send migration result."),
+        }
+
+        // Nobody listens on the socket.
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.track_dirty_pages = Some(true);
+        assert!(vmm.init_guest_memory().is_ok());
+        let started = vmm.send_migration(MigrationSendConfig {
+            socket_path: socket_path.clone(),
+        });
+        match wait_for_migration(&mut vmm, started) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::Connect(_))) => (),
+            _ => panic!("Unexpected send migration result."),
+        }
+
+        // Migrations can only be received before the microVM is started.
+        match vmm.receive_migration(MigrationReceiveConfig {
+            socket_path: socket_path.clone(),
+        }) {
+            Err(VmmActionError::Migration(
+                ErrorKind::User,
+                MigrationError::MicroVMAlreadyRunning,
+            )) => (),
+            _ => panic!("Unexpected receive migration result."),
+        }
+
+        // The source must speak the migration protocol. The failure is reported to the source.
+        let source_path = socket_path.clone();
+        let source = thread::spawn(move || {
+            let mut stream = loop {
+                match UnixStream::connect(&source_path) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            migration::write_message(&mut stream, MessageKind::Hello, &[0u8; 10]).unwrap();
+            migration::read_message(&mut stream, MessageKind::Error).unwrap()
+        });
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let started = vmm.receive_migration(MigrationReceiveConfig {
+            socket_path: socket_path.clone(),
+        });
+        match wait_for_migration(&mut vmm, started) {
+            Err(VmmActionError::Migration(ErrorKind::User, MigrationError::InvalidMagic)) => (),
+            _ => panic!("Unexpected receive migration result."),
+        }
+        assert_eq!(
+            source.join().unwrap(),
+            MigrationError::InvalidMagic.to_string().as_bytes()
+        );
+        // The socket is removed once the source connected.
+        assert!(!socket_path.exists());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_send_migration() {
+        let socket_dir = TempDir::new().unwrap();
+        let socket_path = socket_dir.path().join("migration.sock");
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.mem_size_mib = Some(1);
+        vmm.vm_config.track_dirty_pages = Some(true);
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.setup_interrupt_controller().is_ok());
+        // Without vCPUs, the microVM can be migrated while paused.
+        vmm.set_instance_state(InstanceState::Paused);
+
+        let guest_memory = vmm.guest_memory().unwrap().clone();
+        guest_memory
+            .write_obj_at_addr(0x1111u64, GuestAddress(0x1000))
+            .unwrap();
+        guest_memory
+            .write_obj_at_addr(0x2222u64, GuestAddress(0xf_fff8))
+            .unwrap();
+
+        // The destination receives the whole guest memory.
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let destination = thread::spawn(move || fake_migration_destination(listener, b""));
+        let started = vmm.send_migration(MigrationSendConfig {
+            socket_path: socket_path.clone(),
+        });
+        assert!(wait_for_migration(&mut vmm, started).is_ok());
+        let mut expected_mem = vec![0u8; 1 << 20];
+        guest_memory
+            .read_slice_at_addr(&mut expected_mem, GuestAddress(0))
+            .unwrap();
+        assert!(destination.join().unwrap() == expected_mem);
+        assert_eq!(vmm.instance_state(), InstanceState::Paused);
+
+        // The pages written before the migration still end up in the next diff snapshot.
+        let dirty_pages = vmm.take_dirty_pages().unwrap();
+        assert_eq!(dirty_pages[0][0], 0b10);
+        assert_eq!(dirty_pages[0][3], 1 << 63);
+
+        // The failure of the destination is reported.
+        std::fs::remove_file(&socket_path).unwrap();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let destination = thread::spawn(move || fake_migration_destination(listener, b"failed"));
+        let started = vmm.send_migration(MigrationSendConfig { socket_path });
+        match wait_for_migration(&mut vmm, started) {
+            Err(VmmActionError::Migration(
+                ErrorKind::Internal,
+                MigrationError::Destination(ref msg),
+            )) if msg == "failed" => (),
+            _ => panic!("Unexpected send migration result."),
+        }
+        destination.join().unwrap();
+    }
 }
@@ -0,0 +1,664 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the protocol used to migrate a running microVM between two Firecracker processes over
+//! a Unix stream socket.
+//!
+//! Every message is made of a header, holding the kind of the message and the length of its
+//! payload, followed by the payload. The source sends:
+//! 1. `Hello`, holding the magic value and the version of the protocol;
+//! 2. `MemoryLayout`, holding the JSON serialized layout of the guest memory;
+//! 3. `Pages` messages, each holding a guest physical address followed by the contents of the
+//!    guest memory at that address. The first pre-copy round sends the whole guest memory, the
+//!    next ones only send the pages written by the guest during the previous round. Once the
+//!    vCPUs are paused, the pages written during the last round are sent as well;
+//! 4. `State`, holding the JSON serialized `MicrovmState`.
+//!
+//! The destination then answers with `Done` once the microVM runs, or with `Error`, holding a
+//! description of the failure.
+//!
+//! The transfers run on a worker thread, so that the event loop keeps serving the devices of the
+//! microVM meanwhile. Every socket operation is bounded by a timeout.
+
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use std::{mem, thread};
+
+use default_syscalls;
+use memory_model::{GuestAddress, GuestMemory, DIRTY_PAGE_SIZE};
+use snapshot::{dirty_page_ranges, MemoryRegionState};
+use sys_util::EventFd;
+use vmm_config::migration::MigrationError;
+
+/// Magic value sent at the start of every migration.
+pub const MIGRATION_MAGIC: u64 = 0x0710_1984_f1c1_a5e1;
+/// The version of the migration protocol spoken by this build.
+pub const MIGRATION_VERSION: u16 = 1;
+/// The maximum number of pre-copy rounds sent while the vCPUs are running.
+pub const MAX_PRECOPY_ROUNDS: usize = 10;
+/// The pre-copy rounds stop as soon as the guest dirtied fewer pages than this during a round.
+/// The remaining pages are sent while the vCPUs are paused.
+pub const MAX_STOP_COPY_PAGES: usize = 256;
+/// The maximum length of the messages that are read in memory before being processed (all but
+/// `Pages`).
+pub const MAX_MESSAGE_LEN: u64 = 64 << 20;
+/// The time the source waits for the destination to accept its connection.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The time the destination waits for the source to connect.
+pub const ACCEPT_TIMEOUT: Duration = Duration::from_secs(300);
+/// The time a read or a write on the migration socket may wait for the peer. It also bounds
+/// the time the source waits for the destination to resume the microVM.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+const HELLO_LEN: usize = 10;
+const HEADER_LEN: usize = 12;
+const PAGES_ADDRESS_LEN: u64 = 8;
+
+/// The kinds of migration messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    /// Starts the migration, holds the protocol magic value and version.
+    Hello,
+    /// Holds the layout of the guest memory.
+    MemoryLayout,
+    /// Holds a guest physical address and the contents of the guest memory at that address.
+    Pages,
+    /// Holds the microVM state.
+    State,
+    /// The destination resumed the microVM.
+    Done,
+    /// The destination failed to resume the microVM, holds the description of the failure.
+    Error,
+}
+
+impl MessageKind {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MessageKind::Hello),
+            2 => Some(MessageKind::MemoryLayout),
+            3 => Some(MessageKind::Pages),
+            4 => Some(MessageKind::State),
+            5 => Some(MessageKind::Done),
+            6 => Some(MessageKind::Error),
+            _ => None,
+        }
+    }
+
+    /// Returns the value identifying the kind on the wire.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            MessageKind::Hello => 1,
+            MessageKind::MemoryLayout => 2,
+            MessageKind::Pages => 3,
+            MessageKind::State => 4,
+            MessageKind::Done => 5,
+            MessageKind::Error => 6,
+        }
+    }
+}
+
+/// Writes the header of a message of `kind` with a payload of `len` bytes.
+pub fn write_header<W: Write>(
+    writer: &mut W,
+    kind: MessageKind,
+    len: u64,
+) -> Result<(), MigrationError> {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&kind.to_u32().to_le_bytes());
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    writer.write_all(&header).map_err(MigrationError::Send)
+}
+
+/// Reads the header of the next message and returns its kind and the length of its payload.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<(MessageKind, u64), MigrationError> {
+    let mut header = [0u8; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .map_err(MigrationError::Receive)?;
+    let mut kind = [0u8; 4];
+    kind.copy_from_slice(&header[..4]);
+    let kind = u32::from_le_bytes(kind);
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&header[4..]);
+    let len = u64::from_le_bytes(len);
+    MessageKind::from_u32(kind)
+        .map(|kind| (kind, len))
+        .ok_or(MigrationError::UnexpectedMessage(kind))
+}
+
+/// Writes a whole message.
+pub fn write_message<W: Write>(
+    writer: &mut W,
+    kind: MessageKind,
+    payload: &[u8],
+) -> Result<(), MigrationError> {
+    write_header(writer, kind, payload.len() as u64)?;
+    writer.write_all(payload).map_err(MigrationError::Send)
+}
+
+/// Reads the payload of a message of `len` bytes.
+pub fn read_payload<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, MigrationError> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(MigrationError::MessageTooLong(len));
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .map_err(MigrationError::Receive)?;
+    Ok(payload)
+}
+
+/// Reads the next message, which must be of `kind`, and returns its payload.
+pub fn read_message<R: Read>(reader: &mut R, kind: MessageKind) -> Result<Vec<u8>, MigrationError> {
+    let (next_kind, len) = read_header(reader)?;
+    if next_kind != kind {
+        return Err(MigrationError::UnexpectedMessage(next_kind.to_u32()));
+    }
+    read_payload(reader, len)
+}
+
+/// Writes the `Hello` message.
+pub fn write_hello<W: Write>(writer: &mut W) -> Result<(), MigrationError> {
+    let mut hello = [0u8; HELLO_LEN];
+    hello[..8].copy_from_slice(&MIGRATION_MAGIC.to_le_bytes());
+    hello[8..].copy_from_slice(&MIGRATION_VERSION.to_le_bytes());
+    write_message(writer, MessageKind::Hello, &hello)
+}
+
+/// Reads the `Hello` message and checks that the peer speaks the same protocol version.
+pub fn read_hello<R: Read>(reader: &mut R) -> Result<(), MigrationError> {
+    let hello = read_message(reader, MessageKind::Hello)?;
+    if hello.len() != HELLO_LEN {
+        return Err(MigrationError::InvalidMagic);
+    }
+    let mut magic = [0u8; 8];
+    magic.copy_from_slice(&hello[..8]);
+    if u64::from_le_bytes(magic) != MIGRATION_MAGIC {
+        return Err(MigrationError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes([hello[8], hello[9]]);
+    if version != MIGRATION_VERSION {
+        return Err(MigrationError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+/// Writes the header of a `Pages` message holding `len` bytes of guest memory found at
+/// `guest_address`. The caller then writes the contents of the guest memory.
+pub fn write_pages_header<W: Write>(
+    writer: &mut W,
+    guest_address: u64,
+    len: usize,
+) -> Result<(), MigrationError> {
+    write_header(writer, MessageKind::Pages, PAGES_ADDRESS_LEN + len as u64)?;
+    writer
+        .write_all(&guest_address.to_le_bytes())
+        .map_err(MigrationError::Send)
+}
+
+/// Reads the beginning of the payload of a `Pages` message of `len` bytes and returns the guest
+/// address and the length of the guest memory contents that follow.
+pub fn read_pages_header<R: Read>(
+    reader: &mut R,
+    len: u64,
+) -> Result<(u64, usize), MigrationError> {
+    if len < PAGES_ADDRESS_LEN {
+        return Err(MigrationError::UnexpectedMessage(
+            MessageKind::Pages.to_u32(),
+        ));
+    }
+    let mut guest_address = [0u8; 8];
+    reader
+        .read_exact(&mut guest_address)
+        .map_err(MigrationError::Receive)?;
+    Ok((
+        u64::from_le_bytes(guest_address),
+        (len - PAGES_ADDRESS_LEN) as usize,
+    ))
+}
+
+/// Returns the number of pages set in the dirty page bitmaps of the guest memory regions.
+pub fn count_dirty_pages(dirty_pages: &[Vec<u64>]) -> usize {
+    dirty_pages
+        .iter()
+        .flat_map(|bitmap| bitmap.iter())
+        .map(|word| word.count_ones() as usize)
+        .sum()
+}
+
+/// Sets in `dirty_pages` the pages set in `other`. Both hold one bitmap per guest memory region.
+pub fn merge_dirty_pages(dirty_pages: &mut [Vec<u64>], other: &[Vec<u64>]) {
+    for (bitmap, other_bitmap) in dirty_pages.iter_mut().zip(other.iter()) {
+        for (word, other_word) in bitmap.iter_mut().zip(other_bitmap.iter()) {
+            *word |= *other_word;
+        }
+    }
+}
+
+// Sets the `SO_RCVTIMEO` or `SO_SNDTIMEO` socket `option` of `fd` to `timeout`.
+fn set_socket_timeout(fd: RawFd, option: libc::c_int, timeout: Duration) -> io::Result<()> {
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: libc::suseconds_t::from(timeout.subsec_micros()),
+    };
+    // Safe because `timeval` is a valid value for both options and we check the result.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &timeval as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The socket calls report an expired timeout as `EAGAIN`.
+fn timed_out(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        io::Error::from(io::ErrorKind::TimedOut)
+    } else {
+        e
+    }
+}
+
+fn set_io_timeouts(stream: &UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))
+}
+
+/// Connects to the destination listening on `socket_path`. The connection waits for room in the
+/// backlog of the destination for at most `timeout`.
+pub fn connect(socket_path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+    let path = socket_path.as_os_str().as_bytes();
+    // Safe because `sockaddr_un` is plain old data.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    // The path must be followed by a null byte.
+    if path.len() >= addr.sun_path.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+
+    // Safe because we check the result.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the new file descriptor.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    // A Unix socket waits for the listener for as long as its send timeout.
+    set_socket_timeout(fd, libc::SO_SNDTIMEO, timeout)?;
+    let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+    // Safe because `addr` is a valid socket address of at least `len` bytes.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(timed_out(io::Error::last_os_error()));
+    }
+    set_io_timeouts(&stream)?;
+    Ok(stream)
+}
+
+/// Accepts the connection of the source on `listener`, waiting for it for at most `timeout`.
+pub fn accept(listener: &UnixListener, timeout: Duration) -> io::Result<UnixStream> {
+    // A Unix listener waits for a connection for as long as its receive timeout.
+    set_socket_timeout(listener.as_raw_fd(), libc::SO_RCVTIMEO, timeout)?;
+    let (stream, _) = listener.accept().map_err(timed_out)?;
+    set_io_timeouts(&stream)?;
+    Ok(stream)
+}
+
+/// The transfers run by the migration worker.
+pub enum Job {
+    /// Connects to the destination listening on `socket_path`, then sends the memory layout and
+    /// the whole contents of `guest_memory`.
+    Start {
+        /// The path of the socket of the destination.
+        socket_path: PathBuf,
+        /// The guest memory to migrate.
+        guest_memory: GuestMemory,
+        /// The layout of `guest_memory`.
+        memory_regions: Vec<MemoryRegionState>,
+    },
+    /// Sends the pages set in the dirty page bitmaps, one per guest memory region.
+    SendPages(Vec<Vec<u64>>),
+    /// Sends the pages set in the dirty page bitmaps and the serialized microVM state, then waits
+    /// for the destination to resume the microVM. This ends the migration.
+    SendState(Vec<Vec<u64>>, Vec<u8>),
+    /// Accepts the connection of the source on the listener, then receives the guest memory and
+    /// the serialized microVM state.
+    Receive(UnixListener),
+    /// Tells the source that the microVM runs, or why it could not be resumed. This ends the
+    /// migration.
+    Reply(Option<String>),
+}
+
+/// The outcome of a job.
+pub enum JobOutput {
+    /// The job completed.
+    Done,
+    /// The guest memory, its layout and the serialized microVM state received from the source.
+    Received(GuestMemory, Vec<MemoryRegionState>, Vec<u8>),
+}
+
+// The state of the migration run by the worker.
+#[derive(Default)]
+struct Transfer {
+    stream: Option<UnixStream>,
+    guest_memory: Option<GuestMemory>,
+    memory_regions: Vec<MemoryRegionState>,
+}
+
+impl Transfer {
+    fn run(&mut self, job: Job) -> Result<JobOutput, MigrationError> {
+        match job {
+            Job::Start {
+                socket_path,
+                guest_memory,
+                memory_regions,
+            } => {
+                let mut stream =
+                    connect(&socket_path, CONNECT_TIMEOUT).map_err(MigrationError::Connect)?;
+                write_hello(&mut stream)?;
+                let layout =
+                    serde_json::to_vec(&memory_regions).map_err(MigrationError::Serialize)?;
+                write_message(&mut stream, MessageKind::MemoryLayout, &layout)?;
+                self.stream = Some(stream);
+                self.guest_memory = Some(guest_memory);
+                // The first pre-copy round sends the whole guest memory. The bits past the end of
+                // a region are ignored.
+                let all_pages: Vec<Vec<u64>> = memory_regions
+                    .iter()
+                    .map(|region| vec![!0; region.size / DIRTY_PAGE_SIZE / 64 + 1])
+                    .collect();
+                self.memory_regions = memory_regions;
+                self.send_pages(&all_pages)?;
+            }
+            Job::SendPages(dirty_pages) => self.send_pages(&dirty_pages)?,
+            Job::SendState(dirty_pages, state) => {
+                self.send_pages(&dirty_pages)?;
+                let stream = self.stream()?;
+                write_message(stream, MessageKind::State, &state)?;
+                match read_header(stream)? {
+                    (MessageKind::Done, 0) => (),
+                    (MessageKind::Error, len) => {
+                        let description = read_payload(stream, len)?;
+                        return Err(MigrationError::Destination(
+                            String::from_utf8_lossy(&description).into_owned(),
+                        ));
+                    }
+                    (kind, _) => return Err(MigrationError::UnexpectedMessage(kind.to_u32())),
+                }
+                *self = Transfer::default();
+            }
+            Job::Receive(listener) => {
+                self.stream =
+                    Some(accept(&listener, ACCEPT_TIMEOUT).map_err(MigrationError::Accept)?);
+                return self.receive();
+            }
+            Job::Reply(error) => {
+                let stream = self.stream()?;
+                match error {
+                    None => write_message(stream, MessageKind::Done, &[])?,
+                    Some(description) => {
+                        write_message(stream, MessageKind::Error, description.as_bytes())?
+                    }
+                }
+                *self = Transfer::default();
+            }
+        }
+        Ok(JobOutput::Done)
+    }
+
+    fn stream(&mut self) -> Result<&mut UnixStream, MigrationError> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| MigrationError::Send(io::Error::from(io::ErrorKind::NotConnected)))
+    }
+
+    fn send_pages(&mut self, dirty_pages: &[Vec<u64>]) -> Result<(), MigrationError> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| MigrationError::Send(io::Error::from(io::ErrorKind::NotConnected)))?;
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(MigrationError::GuestMemory(
+                memory_model::GuestMemoryError::MemoryNotInitialized,
+            ))?;
+        for (region, bitmap) in self.memory_regions.iter().zip(dirty_pages.iter()) {
+            for (offset, len) in dirty_page_ranges(bitmap, region.size) {
+                let guest_address = region.base_address + offset as u64;
+                write_pages_header(stream, guest_address, len)?;
+                guest_memory
+                    .write_from_memory(GuestAddress(guest_address as usize), stream, len)
+                    .map_err(MigrationError::GuestMemory)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<JobOutput, MigrationError> {
+        let stream = self.stream()?;
+        read_hello(stream)?;
+        let layout = read_message(stream, MessageKind::MemoryLayout)?;
+        let memory_regions: Vec<MemoryRegionState> =
+            serde_json::from_slice(&layout).map_err(MigrationError::Deserialize)?;
+        let regions: Vec<(GuestAddress, usize)> = memory_regions
+            .iter()
+            .map(|r| (GuestAddress(r.base_address as usize), r.size))
+            .collect();
+        let guest_memory = GuestMemory::new(&regions).map_err(MigrationError::GuestMemory)?;
+
+        loop {
+            match read_header(stream)? {
+                (MessageKind::Pages, len) => {
+                    let (guest_address, len) = read_pages_header(stream, len)?;
+                    guest_memory
+                        .read_to_memory(GuestAddress(guest_address as usize), stream, len)
+                        .map_err(MigrationError::GuestMemory)?;
+                }
+                (MessageKind::State, len) => {
+                    let state = read_payload(stream, len)?;
+                    return Ok(JobOutput::Received(guest_memory, memory_regions, state));
+                }
+                (kind, _) => return Err(MigrationError::UnexpectedMessage(kind.to_u32())),
+            }
+        }
+    }
+}
+
+/// Runs the jobs of the migrations on a dedicated thread. The worker writes to the event given
+/// at start every time a job completes, and the outcome of the job is then available from
+/// `try_result`.
+///
+/// Failing a job ends the migration, except for `Receive`, whose failure can still be reported
+/// to the source.
+pub struct MigrationWorker {
+    jobs: Sender<Job>,
+    results: Receiver<Result<JobOutput, MigrationError>>,
+}
+
+impl MigrationWorker {
+    /// Starts the worker thread, which loads the seccomp filters of `seccomp_level` and writes to
+    /// `done_evt` every time a job completes.
+    pub fn start(done_evt: EventFd, seccomp_level: u32) -> io::Result<MigrationWorker> {
+        let (jobs, job_receiver) = channel();
+        let (result_sender, results) = channel();
+        thread::Builder::new()
+            .name("fc_migration".to_string())
+            .spawn(move || {
+                // Load seccomp filters for this thread.
+                // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping
+                // filters altogether is the desired behaviour.
+                if let Err(e) = default_syscalls::set_seccomp_level(seccomp_level) {
+                    panic!(
+                        "Failed to set the requested seccomp filters on the migration worker: \
+                         Error: {}",
+                        e
+                    );
+                }
+
+                let mut transfer = Transfer::default();
+                // The worker stops once the VMM drops its end of the channel.
+                while let Ok(job) = job_receiver.recv() {
+                    let reply_on_error = match job {
+                        Job::Receive(_) => true,
+                        _ => false,
+                    };
+                    let result = transfer.run(job);
+                    if result.is_err() && !reply_on_error {
+                        transfer = Transfer::default();
+                    }
+                    if result_sender.send(result).is_err() || done_evt.write(1).is_err() {
+                        return;
+                    }
+                }
+            })?;
+        Ok(MigrationWorker { jobs, results })
+    }
+
+    /// Hands `job` over to the worker.
+    pub fn submit(&self, job: Job) -> Result<(), MigrationError> {
+        self.jobs
+            .send(job)
+            .map_err(|_| MigrationError::WorkerStopped)
+    }
+
+    /// Returns the outcome of the next completed job, if any.
+    pub fn try_result(&self) -> Option<Result<JobOutput, MigrationError>> {
+        self.results.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let mut buf = Vec::new();
+        write_hello(&mut buf).unwrap();
+        write_message(&mut buf, MessageKind::State, b"{}").unwrap();
+        write_pages_header(&mut buf, 0x1000, 2).unwrap();
+        buf.extend_from_slice(&[0xaa, 0xbb]);
+        write_message(&mut buf, MessageKind::Done, &[]).unwrap();
+
+        let mut reader = &buf[..];
+        read_hello(&mut reader).unwrap();
+        assert_eq!(
+            read_message(&mut reader, MessageKind::State).unwrap(),
+            b"{}"
+        );
+        let (kind, len) = read_header(&mut reader).unwrap();
+        assert_eq!(kind, MessageKind::Pages);
+        assert_eq!(read_pages_header(&mut reader, len).unwrap(), (0x1000, 2));
+        assert_eq!(read_payload(&mut reader, 2).unwrap(), vec![0xaa, 0xbb]);
+        match read_message(&mut reader, MessageKind::State) {
+            Err(MigrationError::UnexpectedMessage(5)) => (),
+            _ => panic!("Unexpected read result."),
+        }
+        match read_header(&mut reader) {
+            Err(MigrationError::Receive(_)) => (),
+            _ => panic!("Unexpected read result."),
+        }
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let mut dirty_pages = vec![vec![0b1010, 0], vec![1 << 63]];
+        assert_eq!(count_dirty_pages(&dirty_pages), 3);
+        merge_dirty_pages(&mut dirty_pages, &[vec![0b0110, 1], vec![1]]);
+        assert_eq!(dirty_pages, vec![vec![0b1110, 1], vec![1 << 63 | 1]]);
+        assert_eq!(count_dirty_pages(&dirty_pages), 6);
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        // Unknown message kind.
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&7u32.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        match read_header(&mut &buf[..]) {
+            Err(MigrationError::UnexpectedMessage(7)) => (),
+            _ => panic!("Unexpected read result."),
+        }
+
+        // Invalid hello messages.
+        let mut buf = Vec::new();
+        write_message(&mut buf, MessageKind::Hello, &[0u8; HELLO_LEN]).unwrap();
+        match read_hello(&mut &buf[..]) {
+            Err(MigrationError::InvalidMagic) => (),
+            _ => panic!("Unexpected read result."),
+        }
+        let mut hello = MIGRATION_MAGIC.to_le_bytes().to_vec();
+        hello.extend_from_slice(&(MIGRATION_VERSION + 1).to_le_bytes());
+        let mut buf = Vec::new();
+        write_message(&mut buf, MessageKind::Hello, &hello).unwrap();
+        match read_hello(&mut &buf[..]) {
+            Err(MigrationError::UnsupportedVersion(v)) => assert_eq!(v, MIGRATION_VERSION + 1),
+            _ => panic!("Unexpected read result."),
+        }
+
+        // Messages that are too long or too short.
+        match read_payload(&mut &buf[..], MAX_MESSAGE_LEN + 1) {
+            Err(MigrationError::MessageTooLong(_)) => (),
+            _ => panic!("Unexpected read result."),
+        }
+        match read_pages_header(&mut &buf[..], PAGES_ADDRESS_LEN - 1) {
+            Err(MigrationError::UnexpectedMessage(_)) => (),
+            _ => panic!("Unexpected read result."),
+        }
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let socket_dir = TempDir::new().unwrap();
+        let socket_path = socket_dir.path().join("migration.sock");
+
+        // Nobody listens on the socket.
+        assert!(connect(&socket_path, CONNECT_TIMEOUT).is_err());
+        let long_path = socket_dir.path().join("a".repeat(128));
+        assert_eq!(
+            connect(&long_path, CONNECT_TIMEOUT).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // Nobody connects to the socket.
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        assert_eq!(
+            accept(&listener, Duration::from_millis(10))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        let mut source = connect(&socket_path, CONNECT_TIMEOUT).unwrap();
+        let mut destination = accept(&listener, ACCEPT_TIMEOUT).unwrap();
+        assert_eq!(destination.read_timeout().unwrap(), Some(IO_TIMEOUT));
+        assert_eq!(source.write_timeout().unwrap(), Some(IO_TIMEOUT));
+        write_hello(&mut source).unwrap();
+        read_hello(&mut destination).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loads the guest memory of a restored microVM on demand, using userfaultfd.
+//!
+//! The guest memory is registered with a userfaultfd, so that the first access to each page is
+//! reported as a page fault event instead of being served by the kernel. The page faults are
+//! served either by a handler thread of this process, which copies the missing pages from the
+//! memory file of the snapshot, or by an external process. In the latter case, the userfaultfd
+//! is sent over a Unix socket, along with the JSON serialized list of `UffdRegion`s describing
+//! where each guest memory region is mapped and where it is found in the memory file.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use default_syscalls;
+use logger::{Metric, METRICS};
+use memory_model::GuestMemory;
+use sys_util::{send_with_fd, EventFd, UserfaultFd};
+use FC_EXIT_CODE_PAGE_FAULT_HANDLER;
+
+/// The size of the pages copied in the guest memory to serve a page fault.
+pub const PAGE_SIZE: usize = 4096;
+
+const UFFD_TOKEN: u64 = 0;
+const KILL_TOKEN: u64 = 1;
+
+/// Describes where a guest memory region is mapped in the address space of Firecracker and where
+/// its contents are found in the memory file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UffdRegion {
+    /// The host virtual address of the start of the region.
+    pub base_host_virt_addr: u64,
+    /// The size of the region in bytes.
+    pub size: usize,
+    /// The offset of the region in the memory file.
+    pub offset: u64,
+}
+
+/// Registers every region of `guest_memory` with a new userfaultfd. The regions are laid out one
+/// after the other in the memory file.
+pub fn register_guest_memory(
+    guest_memory: &GuestMemory,
+) -> io::Result<(UserfaultFd, Vec<UffdRegion>)> {
+    let uffd = UserfaultFd::new()?;
+    let mut regions = Vec::with_capacity(guest_memory.num_regions());
+    let mut offset = 0;
+    guest_memory.with_regions_mut(|_, _, size, host_addr| {
+        uffd.register(host_addr as u64, size as u64)?;
+        regions.push(UffdRegion {
+            base_host_virt_addr: host_addr as u64,
+            size,
+            offset,
+        });
+        offset += size as u64;
+        Ok::<(), io::Error>(())
+    })?;
+    Ok((uffd, regions))
+}
+
+/// Copies the page holding the faulting `address` from `mem_file` to the guest memory. `page` is
+/// a `PAGE_SIZE` bytes scratch buffer.
+pub fn serve_page_fault(
+    uffd: &UserfaultFd,
+    regions: &[UffdRegion],
+    mem_file: &File,
+    address: u64,
+    page: &mut [u8],
+) -> io::Result<()> {
+    let page_address = address & !(PAGE_SIZE as u64 - 1);
+    let region = regions
+        .iter()
+        .find(|r| {
+            page_address >= r.base_host_virt_addr
+                && page_address < r.base_host_virt_addr + r.size as u64
+        })
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let file_offset = region.offset + page_address - region.base_host_virt_addr;
+    mem_file.read_exact_at(page, file_offset)?;
+    // This is safe because `page` holds `PAGE_SIZE` bytes.
+    unsafe { uffd.copy(page.as_ptr(), page_address, PAGE_SIZE as u64) }
+}
+
+// Serves every queued page fault. Returns an error if the userfaultfd cannot be read or if a
+// page fault cannot be served.
+fn serve_page_faults(
+    uffd: &UserfaultFd,
+    regions: &[UffdRegion],
+    mem_file: &File,
+    page: &mut [u8],
+) -> io::Result<()> {
+    while let Some(address) = uffd.read_page_fault()? {
+        if let Err(e) = serve_page_fault(uffd, regions, mem_file, address, page) {
+            METRICS.memory.page_fault_fails.inc();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("Failed to serve the page fault at {:#x}: {}", address, e),
+            ));
+        }
+        METRICS.memory.page_faults.inc();
+    }
+    Ok(())
+}
+
+// The faulting threads would wait for their pages forever, so the process exits when the page
+// faults cannot be served anymore.
+fn exit_on_error(e: io::Error) -> ! {
+    error!("The page fault handler failed: {}", e);
+    process::exit(i32::from(FC_EXIT_CODE_PAGE_FAULT_HANDLER));
+}
+
+/// A thread serving the page faults of the guest memory from the memory file of a snapshot.
+/// The thread is stopped when the handler is dropped. Firecracker exits with
+/// `FC_EXIT_CODE_PAGE_FAULT_HANDLER` if a page fault cannot be served.
+pub struct PageFaultHandler {
+    mem_file: Arc<File>,
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PageFaultHandler {
+    /// Starts serving the page faults reported by `uffd` from `mem_file`. The thread loads the
+    /// seccomp filters of `seccomp_level`.
+    pub fn start(
+        uffd: UserfaultFd,
+        regions: Vec<UffdRegion>,
+        mem_file: File,
+        seccomp_level: u32,
+    ) -> io::Result<PageFaultHandler> {
+        let mem_file = Arc::new(mem_file);
+        let thread_mem_file = mem_file.clone();
+        let kill_evt = EventFd::new()?;
+        let epoll_raw_fd = epoll::create(true)?;
+        // This is safe because we own the new file descriptor.
+        let epoll_file = unsafe { File::from_raw_fd(epoll_raw_fd) };
+        epoll::ctl(
+            epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            uffd.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, UFFD_TOKEN),
+        )?;
+        epoll::ctl(
+            epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, KILL_TOKEN),
+        )?;
+
+        let thread = thread::Builder::new()
+            .name("fc_uffd".to_string())
+            .spawn(move || {
+                // Load seccomp filters for this thread.
+                // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping
+                // filters altogether is the desired behaviour.
+                if let Err(e) = default_syscalls::set_seccomp_level(seccomp_level) {
+                    panic!(
+                        "Failed to set the requested seccomp filters on the page fault handler: \
+                         Error: {}",
+                        e
+                    );
+                }
+
+                let mut page = vec![0u8; PAGE_SIZE];
+                let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 2];
+                loop {
+                    let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..])
+                    {
+                        Ok(num_events) => num_events,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => exit_on_error(e),
+                    };
+                    for event in events.iter().take(num_events) {
+                        if event.data == KILL_TOKEN {
+                            return;
+                        }
+                    }
+                    if let Err(e) = serve_page_faults(&uffd, &regions, &thread_mem_file, &mut page)
+                    {
+                        exit_on_error(e);
+                    }
+                }
+            })?;
+
+        Ok(PageFaultHandler {
+            mem_file,
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the memory file the pages are copied from.
+    pub fn mem_file(&self) -> &File {
+        &self.mem_file
+    }
+}
+
+impl Drop for PageFaultHandler {
+    fn drop(&mut self) {
+        if let Err(e) = self.kill_evt.write(1) {
+            warn!("Cannot stop the page fault handler: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("The page fault handler panicked.");
+            }
+        }
+    }
+}
+
+/// Hands `uffd` over to the external page fault handler listening on `socket_path`, along with
+/// the layout of the guest memory.
+pub fn send_to_handler(
+    socket_path: &Path,
+    uffd: &UserfaultFd,
+    regions: &[UffdRegion],
+) -> io::Result<()> {
+    let layout = serde_json::to_vec(regions)?;
+    let stream = UnixStream::connect(socket_path)?;
+    let sent = send_with_fd(&stream, &layout, uffd.as_raw_fd())?;
+    if sent != layout.len() {
+        return Err(io::Error::from(io::ErrorKind::WriteZero));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+
+    use memory_model::GuestAddress;
+    use seccomp::SECCOMP_LEVEL_NONE;
+    use sys_util::recv_with_fd;
+
+    fn mem_file(size: usize) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        let contents: Vec<u8> = (0..size).map(|i| (i / PAGE_SIZE) as u8).collect();
+        file.write_all(&contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_page_fault_handler() {
+        let guest_memory = GuestMemory::new(&[
+            (GuestAddress(0), 4 * PAGE_SIZE),
+            (GuestAddress(0x10_0000), 2 * PAGE_SIZE),
+        ])
+        .unwrap();
+        let (uffd, regions) = register_guest_memory(&guest_memory).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].offset, 0);
+        assert_eq!(regions[1].offset, 4 * PAGE_SIZE as u64);
+        assert_eq!(regions[1].size, 2 * PAGE_SIZE);
+
+        let page_faults = METRICS.memory.page_faults.count();
+        let handler =
+            PageFaultHandler::start(uffd, regions, mem_file(6 * PAGE_SIZE), SECCOMP_LEVEL_NONE)
+                .unwrap();
+        assert_eq!(
+            handler.mem_file().metadata().unwrap().len(),
+            6 * PAGE_SIZE as u64
+        );
+
+        // Every page is loaded from the matching offset of the memory file.
+        for page in 0..4 {
+            let value: u8 = guest_memory
+                .read_obj_from_addr(GuestAddress(page * PAGE_SIZE + 8))
+                .unwrap();
+            assert_eq!(value, page as u8);
+        }
+        let value: u8 = guest_memory
+            .read_obj_from_addr(GuestAddress(0x10_0000 + PAGE_SIZE))
+            .unwrap();
+        assert_eq!(value, 5);
+        // Pages are only loaded once.
+        guest_memory
+            .write_obj_at_addr(0xffu8, GuestAddress(0x10_0000 + PAGE_SIZE))
+            .unwrap();
+        let value: u8 = guest_memory
+            .read_obj_from_addr(GuestAddress(0x10_0000 + PAGE_SIZE))
+            .unwrap();
+        assert_eq!(value, 0xff);
+
+        // Dropping the handler stops the thread.
+        drop(handler);
+        // Other tests may run in parallel.
+        assert!(METRICS.memory.page_faults.count() >= page_faults + 5);
+    }
+
+    #[test]
+    fn test_serve_page_fault_errors() {
+        let guest_memory = GuestMemory::new(&[(GuestAddress(0), PAGE_SIZE)]).unwrap();
+        let (uffd, regions) = register_guest_memory(&guest_memory).unwrap();
+        let mut page = vec![0u8; PAGE_SIZE];
+
+        // The address is not part of the guest memory.
+        let address = regions[0].base_host_virt_addr + PAGE_SIZE as u64;
+        let err = serve_page_fault(&uffd, &regions, &mem_file(PAGE_SIZE), address, &mut page)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The memory file is too short.
+        let address = regions[0].base_host_virt_addr;
+        let err = serve_page_fault(&uffd, &regions, &mem_file(0), address, &mut page).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_send_to_handler() {
+        let guest_memory = GuestMemory::new(&[(GuestAddress(0), 2 * PAGE_SIZE)]).unwrap();
+        let (uffd, regions) = register_guest_memory(&guest_memory).unwrap();
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let socket_path = tmp_dir.path().join("uffd.sock");
+        assert!(send_to_handler(&socket_path, &uffd, &regions).is_err());
+
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        send_to_handler(&socket_path, &uffd, &regions).unwrap();
+
+        // Serve the page faults the way an external handler would.
+        let (stream, _) = listener.accept().unwrap();
+        let mut buf = vec![0u8; 1024];
+        let (len, fd) = recv_with_fd(&stream, &mut buf).unwrap();
+        let received_regions: Vec<UffdRegion> = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(received_regions, regions);
+        // This is safe because we own the received file descriptor.
+        let received_uffd = unsafe { UserfaultFd::from_raw_fd(fd.unwrap()) };
+        let handler = PageFaultHandler::start(
+            received_uffd,
+            received_regions,
+            mem_file(2 * PAGE_SIZE),
+            SECCOMP_LEVEL_NONE,
+        )
+        .unwrap();
+
+        let value: u8 = guest_memory
+            .read_obj_from_addr(GuestAddress(PAGE_SIZE))
+            .unwrap();
+        assert_eq!(value, 1);
+        drop(handler);
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the state of a microVM saved in a snapshot file.
+
+#[cfg(target_arch = "x86_64")]
+use devices::legacy::SerialState;
+use devices::virtio::vsock::VsockState;
+use devices::virtio::{BalloonState, BlockState, MmioDeviceState, NetState};
+use memory_model::DIRTY_PAGE_SIZE;
+use vmm_config::balloon::BalloonDeviceConfig;
+use vmm_config::drive::BlockDeviceConfig;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::net::NetworkInterfaceConfig;
+use vmm_config::vsock::VsockDeviceConfig;
+#[cfg(target_arch = "x86_64")]
+use vstate::{VcpuState, VmState};
+
+/// Magic value found at the start of every snapshot file.
+pub const SNAPSHOT_MAGIC: u64 = 0x0710_1984_f1c1_a5e0;
+/// The version of the layout of the snapshot file written by this build. The state of each
+/// component saved in the file carries its own version (see `fc_util::versioned`).
+///
+/// Version 3 saves the state of the virtio device backends along with their transports.
+/// Version 4 saves the configuration of the balloon device.
+pub const SNAPSHOT_VERSION: u16 = 4;
+/// The oldest version of the layout of the snapshot file that this build can load. The
+/// components saved by version 1 did not carry their own version.
+pub const MIN_SNAPSHOT_VERSION: u16 = 2;
+
+/// Returns whether this build can load a snapshot file saved with the layout `version`.
+pub fn is_supported_version(version: u16) -> bool {
+    (MIN_SNAPSHOT_VERSION..=SNAPSHOT_VERSION).contains(&version)
+}
+
+/// A guest memory region, as laid out in the memory file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MemoryRegionState {
+    /// Guest physical address of the region.
+    pub base_address: u64,
+    /// Size of the region in bytes.
+    pub size: usize,
+    /// For diff snapshots, the bitmap of the pages present in the memory file, one bit per
+    /// `DIRTY_PAGE_SIZE` bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty_pages: Option<Vec<u64>>,
+}
+
+/// Returns the `(offset, length)` byte ranges of the runs of consecutive pages set in
+/// `bitmap`, for a memory region of `region_size` bytes.
+pub fn dirty_page_ranges(bitmap: &[u64], region_size: usize) -> Vec<(usize, usize)> {
+    let num_pages = (region_size + DIRTY_PAGE_SIZE - 1) / DIRTY_PAGE_SIZE;
+    let is_dirty = |page: usize| {
+        bitmap
+            .get(page / 64)
+            .map_or(false, |w| w & (1 << (page % 64)) != 0)
+    };
+
+    let mut ranges = Vec::new();
+    let mut page = 0;
+    while page < num_pages {
+        if !is_dirty(page) {
+            page += 1;
+            continue;
+        }
+        let first_page = page;
+        while page < num_pages && is_dirty(page) {
+            page += 1;
+        }
+        let offset = first_page * DIRTY_PAGE_SIZE;
+        let end = std::cmp::min(page * DIRTY_PAGE_SIZE, region_size);
+        ranges.push((offset, end - offset));
+    }
+    ranges
+}
+
+/// The state of a virtio device attached to the MMIO bus.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VirtioDeviceState {
+    /// The device type, as defined by the virtio specification.
+    pub type_id: u32,
+    /// The identifier of the device (drive id, interface id or vsock id).
+    pub device_id: String,
+    /// The base address of the device on the MMIO bus.
+    pub mmio_addr: u64,
+    /// The state of the MMIO transport of the device.
+    #[serde(with = "fc_util::versioned")]
+    pub transport: MmioDeviceState,
+    /// The state of the device backend. Snapshots older than version 3 do not hold it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<VirtioBackendState>,
+}
+
+/// The state of the backend of a virtio device.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum VirtioBackendState {
+    /// The state of a block device.
+    Block(#[serde(with = "fc_util::versioned")] BlockState),
+    /// The state of a network device.
+    Net(#[serde(with = "fc_util::versioned")] NetState),
+    /// The state of a vsock device.
+    Vsock(#[serde(with = "fc_util::versioned")] VsockState),
+    /// The state of a balloon device.
+    Balloon(#[serde(with = "fc_util::versioned")] BalloonState),
+}
+
+/// Everything that is needed to rebuild a microVM, except for the contents of its memory.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MicrovmState {
+    /// Identifies the file as a Firecracker snapshot.
+    pub magic: u64,
+    /// The version of the snapshot format.
+    pub version: u16,
+    /// The machine configuration.
+    #[serde(with = "fc_util::versioned")]
+    pub vm_config: VmConfig,
+    /// The layout of the guest memory.
+    pub memory_regions: Vec<MemoryRegionState>,
+    /// The configurations of the block devices.
+    pub block_devices: Vec<BlockDeviceConfig>,
+    /// The configurations of the network interfaces.
+    pub net_devices: Vec<NetworkInterfaceConfig>,
+    /// The configuration of the vsock device.
+    pub vsock_device: Option<VsockDeviceConfig>,
+    /// The configuration of the balloon device.
+    #[serde(default)]
+    pub balloon_device: Option<BalloonDeviceConfig>,
+    /// The state of the in-kernel irqchip, PIT and clock.
+    #[serde(with = "fc_util::versioned")]
+    pub vm_state: VmState,
+    /// The state of each vCPU, ordered by vCPU index.
+    #[serde(with = "fc_util::versioned::vec")]
+    pub vcpu_states: Vec<VcpuState>,
+    /// The state of each virtio device.
+    pub virtio_devices: Vec<VirtioDeviceState>,
+    /// The state of the serial console.
+    #[serde(with = "fc_util::versioned")]
+    pub serial_state: SerialState,
+}
+
+/// Serializes and deserializes plain old data structures (such as the KVM structures) as raw
+/// byte arrays.
+///
+/// Must only be used with `Copy` types for which every bit pattern is a valid value.
+pub mod pod {
+    use std::{mem, ptr, slice};
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Serializes `value` as an array of bytes.
+    pub fn serialize<T: Copy, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        // Safe because `value` is a valid reference to `mem::size_of::<T>()` bytes.
+        let bytes =
+            unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+        serializer.serialize_bytes(bytes)
+    }
+
+    /// Deserializes a value from an array of bytes.
+    pub fn deserialize<'de, T: Copy, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        if bytes.len() != mem::size_of::<T>() {
+            return Err(D::Error::invalid_length(
+                bytes.len(),
+                &"the size of the serialized structure",
+            ));
+        }
+        // Safe because we checked the length of the buffer and `T` is plain old data.
+        Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+}
+
+/// Serializes and deserializes vectors of plain old data structures as raw byte arrays.
+///
+/// Must only be used with `Copy` types for which every bit pattern is a valid value.
+pub mod pod_vec {
+    use std::{mem, ptr, slice};
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Serializes `values` as an array of bytes.
+    pub fn serialize<T: Copy, S: Serializer>(
+        values: &[T],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        // Safe because `values` is a valid slice of `values.len()` elements.
+        let bytes = unsafe {
+            slice::from_raw_parts(values.as_ptr() as *const u8, mem::size_of_val(values))
+        };
+        serializer.serialize_bytes(bytes)
+    }
+
+    /// Deserializes a vector of values from an array of bytes.
+    pub fn deserialize<'de, T: Copy, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<T>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let size = mem::size_of::<T>();
+        if bytes.len() % size != 0 {
+            return Err(D::Error::invalid_length(
+                bytes.len(),
+                &"a multiple of the size of the serialized structure",
+            ));
+        }
+        Ok(bytes
+            .chunks(size)
+            // Safe because every chunk is `size` bytes long and `T` is plain old data.
+            .map(|chunk| unsafe { ptr::read_unaligned(chunk.as_ptr() as *const T) })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Pod {
+        a: u64,
+        b: u64,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct Wrapper {
+        #[serde(with = "pod")]
+        single: Pod,
+        #[serde(with = "pod_vec")]
+        many: Vec<Pod>,
+    }
+
+    #[test]
+    fn test_is_supported_version() {
+        assert!(!is_supported_version(0));
+        assert!(!is_supported_version(1));
+        assert!(is_supported_version(MIN_SNAPSHOT_VERSION));
+        assert!(is_supported_version(SNAPSHOT_VERSION));
+        assert!(!is_supported_version(SNAPSHOT_VERSION + 1));
+    }
+
+    #[test]
+    fn test_dirty_page_ranges() {
+        assert!(dirty_page_ranges(&[], 0x10_0000).is_empty());
+        assert!(dirty_page_ranges(&[0, 0], 0x10_0000).is_empty());
+        assert_eq!(
+            dirty_page_ranges(&[0b1101 | 1 << 63, 1], 0x10_0000),
+            vec![(0, 0x1000), (0x2000, 0x2000), (0x3f000, 0x2000)]
+        );
+        // The last page of a region can be partial.
+        assert_eq!(dirty_page_ranges(&[0b10], 0x1800), vec![(0x1000, 0x800)]);
+        // Bits past the end of the region are ignored.
+        assert_eq!(dirty_page_ranges(&[0b110], 0x2000), vec![(0x1000, 0x1000)]);
+    }
+
+    #[test]
+    fn test_pod_serialization() {
+        let wrapper = Wrapper {
+            single: Pod { a: 1, b: 2 },
+            many: vec![Pod { a: 3, b: 4 }, Pod { a: 5, b: 6 }],
+        };
+        let json = serde_json::to_string(&wrapper).unwrap();
+        let restored: Wrapper = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.single, wrapper.single);
+        assert_eq!(restored.many, wrapper.many);
+
+        // Buffers with the wrong length are rejected.
+        assert!(serde_json::from_str::<Wrapper>(r#"{"single":[1,2,3],"many":[]}"#).is_err());
+        let json = format!(
+            r#"{{"single":{},"many":[1,2,3]}}"#,
+            serde_json::to_string(&vec![0u8; std::mem::size_of::<Pod>()]).unwrap()
+        );
+        assert!(serde_json::from_str::<Wrapper>(&json).is_err());
+    }
+}
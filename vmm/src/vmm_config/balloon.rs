@@ -0,0 +1,135 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+
+use devices::virtio::BalloonStats;
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from balloon related requests.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonDeviceConfig {
+    /// The amount of memory, in MiB, the balloon takes from the guest.
+    pub amount_mib: u32,
+    /// Whether the guest may take memory back from the balloon when it runs out of memory.
+    pub deflate_on_oom: bool,
+    /// Whether the guest hands its free pages over to the host.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    /// The interval, in seconds, at which the guest refreshes its memory statistics.
+    /// The statistics are disabled when set to 0.
+    #[serde(default)]
+    pub stats_polling_interval_s: u16,
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from the request updating the target size of the balloon.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonUpdateConfig {
+    /// The amount of memory, in MiB, the balloon takes from the guest.
+    pub amount_mib: u32,
+}
+
+/// The size of the balloon and the latest memory statistics reported by the guest.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BalloonStatistics {
+    /// The number of pages the balloon should hold.
+    pub target_pages: u32,
+    /// The number of pages the balloon holds.
+    pub actual_pages: u32,
+    /// The amount of memory, in MiB, the balloon should hold.
+    pub target_mib: u32,
+    /// The amount of memory, in MiB, the balloon holds.
+    pub actual_mib: u32,
+    /// The memory statistics reported by the guest.
+    #[serde(flatten)]
+    pub memory_stats: BalloonStats,
+}
+
+/// Errors associated with the balloon device.
+#[derive(Debug)]
+pub enum BalloonError {
+    /// The balloon device can only be added before booting the microVM.
+    UpdateNotAllowedPostBoot,
+    /// No balloon device was configured.
+    DeviceNotFound,
+    /// The statistics are only available when a polling interval is configured.
+    StatisticsDisabled,
+    /// The balloon cannot be larger than the guest memory.
+    TooManyPagesRequested,
+    /// The target size could not be sent to the guest.
+    UpdateFailed,
+    /// The balloon cannot release the 4K pages of a guest memory backed by 2M pages.
+    HugePagesBacking,
+}
+
+impl Display for BalloonError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::BalloonError::*;
+        match *self {
+            UpdateNotAllowedPostBoot => {
+                write!(f, "The update operation is not allowed after boot.")
+            }
+            DeviceNotFound => write!(f, "No balloon device is configured."),
+            StatisticsDisabled => write!(
+                f,
+                "The balloon statistics are disabled. Set a non zero stats_polling_interval_s \
+                 to enable them."
+            ),
+            TooManyPagesRequested => write!(
+                f,
+                "The balloon cannot be larger than the memory of the microVM."
+            ),
+            UpdateFailed => write!(
+                f,
+                "Cannot update the balloon size. The guest balloon driver is not ready."
+            ),
+            HugePagesBacking => write!(
+                f,
+                "The balloon device cannot be used when the memory is backed by 2M pages."
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balloon_config() {
+        let config: BalloonDeviceConfig =
+            serde_json::from_str(r#"{"amount_mib": 64, "deflate_on_oom": true}"#).unwrap();
+        assert_eq!(
+            config,
+            BalloonDeviceConfig {
+                amount_mib: 64,
+                deflate_on_oom: true,
+                free_page_reporting: false,
+                stats_polling_interval_s: 0,
+            }
+        );
+        assert!(serde_json::from_str::<BalloonDeviceConfig>(r#"{"amount_mib": 64}"#).is_err());
+        assert!(serde_json::from_str::<BalloonUpdateConfig>(
+            r#"{"amount_mib": 64, "deflate_on_oom": true}"#
+        )
+        .is_err());
+
+        let stats = BalloonStatistics {
+            target_pages: 0x4000,
+            actual_pages: 0x100,
+            target_mib: 64,
+            actual_mib: 1,
+            memory_stats: BalloonStats {
+                free_memory: Some(0x1000),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&stats).unwrap(),
+            r#"{"target_pages":16384,"actual_pages":256,"target_mib":64,"actual_mib":1,"free_memory":4096}"#
+        );
+    }
+}
@@ -22,8 +22,9 @@ pub const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off nomodules 825
 pub struct KernelConfig {
     /// The commandline validated against correctness.
     pub cmdline: kernel::cmdline::Cmdline,
-    /// The descriptor to the kernel file.
-    pub kernel_file: File,
+    /// The descriptor to the kernel file. There is no kernel file when the microVM is restored
+    /// from a snapshot.
+    pub kernel_file: Option<File>,
 }
 
 /// Strongly typed data structure used to configure the boot source of the
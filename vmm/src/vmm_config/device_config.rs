@@ -3,6 +3,7 @@
 
 #![deny(warnings)]
 
+use vmm_config::balloon::*;
 use vmm_config::drive::*;
 use vmm_config::net::*;
 use vmm_config::vsock::*;
@@ -16,6 +17,8 @@ pub struct DeviceConfigs {
     pub network_interface: NetworkInterfaceConfigs,
     /// The configurations for vsock devices.
     pub vsock: Option<VsockDeviceConfig>,
+    /// The configuration for the balloon device.
+    pub balloon: Option<BalloonDeviceConfig>,
 }
 
 impl DeviceConfigs {
@@ -24,11 +27,13 @@ impl DeviceConfigs {
         block: BlockDeviceConfigs,
         network_interface: NetworkInterfaceConfigs,
         vsock: Option<VsockDeviceConfig>,
+        balloon: Option<BalloonDeviceConfig>,
     ) -> DeviceConfigs {
         DeviceConfigs {
             block,
             network_interface,
             vsock,
+            balloon,
         }
     }
 }
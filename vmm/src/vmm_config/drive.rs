@@ -3,10 +3,12 @@
 
 use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 
 use super::RateLimiterConfig;
+use devices::virtio::{is_nbd_uri, NbdAddress};
+pub use devices::virtio::{CacheType, ImageFormat, IoEngine, MAX_NUM_QUEUES};
 
 type Result<T> = result::Result<T, DriveError>;
 
@@ -31,6 +33,24 @@ pub enum DriveError {
     UpdateNotAllowedPostBoot,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The overlay path is the path of the base image.
+    InvalidOverlayPath,
+    /// The drive has no overlay.
+    NoOverlay,
+    /// Cannot merge the overlay of the drive into a new image.
+    CannotMergeOverlay,
+    /// The overlay can only be merged while the microVM is paused or before it starts.
+    MergeNotAllowedWhileRunning,
+    /// The number of request queues is out of range.
+    InvalidQueueCount,
+    /// Direct I/O is not supported for the image format of the drive or its overlay.
+    DirectIoNotSupported,
+    /// NBD exports can only hold raw images.
+    NbdFormatNotSupported,
+    /// The drives served over NBD can only be updated, rescanned or merged before boot.
+    NbdUpdateNotAllowedPostBoot,
+    /// The trace path is the path of the disk image or of its overlay.
+    InvalidTracePath,
 }
 
 impl Display for DriveError {
@@ -51,13 +71,30 @@ impl Display for DriveError {
                 OperationNotAllowedPreBoot => "Operation not allowed pre-boot!",
                 RootBlockDeviceAlreadyAdded => "A root block device already exists!",
                 UpdateNotAllowedPostBoot => "The update operation is not allowed after boot.",
+                InvalidOverlayPath => "The overlay path must differ from the block device path!",
+                NoOverlay => "The block device has no overlay!",
+                CannotMergeOverlay => "Cannot merge the overlay into a new image!",
+                MergeNotAllowedWhileRunning => {
+                    "The overlay can only be merged while the microVM is paused or not started!"
+                }
+                InvalidQueueCount => "The number of request queues must be between 1 and 16!",
+                DirectIoNotSupported => {
+                    "Direct I/O is only supported for raw image files without overlays!"
+                }
+                NbdFormatNotSupported => "Only raw images can be served over NBD!",
+                NbdUpdateNotAllowedPostBoot => {
+                    "Drives served over NBD cannot be updated, rescanned or merged after boot!"
+                }
+                InvalidTracePath => {
+                    "The trace path must differ from the paths of the disk image and its overlay!"
+                }
             }
         )
     }
 }
 
 /// Use this structure to set up the Block Device before booting the kernel.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
@@ -76,6 +113,41 @@ pub struct BlockDeviceConfig {
     pub is_read_only: bool,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// The engine used for accessing the drive. The synchronous engine is used when the
+    /// asynchronous one is not supported by the host.
+    #[serde(default)]
+    pub io_engine: IoEngine,
+    /// The format of the disk image.
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// Path of a copy on write overlay, created if missing. When set, the disk image is only
+    /// read, and the sectors written by the guest are stored in the overlay.
+    #[serde(default)]
+    pub overlay_path: Option<PathBuf>,
+    /// The number of request queues exposed to the guest.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+    /// How the drive uses the host page cache.
+    #[serde(default)]
+    pub cache_type: CacheType,
+    /// Path of a file, created or truncated, recording the requests handled by the drive.
+    #[serde(default)]
+    pub trace_path: Option<PathBuf>,
+}
+
+fn default_num_queues() -> u16 {
+    1
+}
+
+/// This struct represents the strongly typed equivalent of the payload of the `MergeOverlay`
+/// action.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayMergeConfig {
+    /// Unique identifier of the drive.
+    pub drive_id: String,
+    /// Path of the standalone raw image to create.
+    pub destination_path: PathBuf,
 }
 
 impl BlockDeviceConfig {
@@ -95,6 +167,54 @@ impl BlockDeviceConfig {
     }
 }
 
+/// The data fed into a drive update request. Currently, only the path of the backing file and
+/// the rate limiter can be updated.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDeviceUpdateConfig {
+    /// The drive ID, as provided by the user at drive creation time.
+    pub drive_id: String,
+    /// New path of the host file backing the drive.
+    pub path_on_host: Option<String>,
+    /// New rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+fn is_valid_queue_count(num_queues: u16) -> bool {
+    (1..=MAX_NUM_QUEUES).contains(&num_queues)
+}
+
+// NBD exports are only reached once the drive is attached.
+fn is_valid_path(path_on_host: &Path) -> bool {
+    if is_nbd_uri(path_on_host) {
+        NbdAddress::parse(path_on_host).is_ok()
+    } else {
+        path_on_host.exists()
+    }
+}
+
+// The trace file is truncated, so it must not hold the disk image or its overlay.
+fn is_valid_trace_path(config: &BlockDeviceConfig) -> bool {
+    match config.trace_path {
+        Some(ref trace_path) => {
+            *trace_path != config.path_on_host && config.overlay_path.as_ref() != Some(trace_path)
+        }
+        None => true,
+    }
+}
+
+fn supports_format(config: &BlockDeviceConfig) -> bool {
+    config.format == ImageFormat::Raw || !is_nbd_uri(&config.path_on_host)
+}
+
+fn supports_cache_type(config: &BlockDeviceConfig) -> bool {
+    config.cache_type != CacheType::Direct
+        || (config.format == ImageFormat::Raw
+            && config.overlay_path.is_none()
+            && !is_nbd_uri(&config.path_on_host))
+}
+
 /// Wrapper for the collection that holds all the Block Devices Configs
 #[derive(Default)]
 pub struct BlockDeviceConfigs {
@@ -158,10 +278,30 @@ impl BlockDeviceConfigs {
 
     fn create(&mut self, block_device_config: BlockDeviceConfig) -> Result<()> {
         // check if the path exists
-        if !block_device_config.path_on_host.exists() {
+        if !is_valid_path(&block_device_config.path_on_host) {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if !supports_format(&block_device_config) {
+            return Err(DriveError::NbdFormatNotSupported);
+        }
+
+        if block_device_config.overlay_path.as_ref() == Some(&block_device_config.path_on_host) {
+            return Err(DriveError::InvalidOverlayPath);
+        }
+
+        if !is_valid_queue_count(block_device_config.num_queues) {
+            return Err(DriveError::InvalidQueueCount);
+        }
+
+        if !supports_cache_type(&block_device_config) {
+            return Err(DriveError::DirectIoNotSupported);
+        }
+
+        if !is_valid_trace_path(&block_device_config) {
+            return Err(DriveError::InvalidTracePath);
+        }
+
         if self
             .get_index_of_drive_path(&block_device_config.path_on_host)
             .is_some()
@@ -194,10 +334,30 @@ impl BlockDeviceConfigs {
     /// root block devices.
     fn update(&mut self, mut index: usize, new_config: BlockDeviceConfig) -> Result<()> {
         // Check if the path exists
-        if !new_config.path_on_host.exists() {
+        if !is_valid_path(&new_config.path_on_host) {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if !supports_format(&new_config) {
+            return Err(DriveError::NbdFormatNotSupported);
+        }
+
+        if new_config.overlay_path.as_ref() == Some(&new_config.path_on_host) {
+            return Err(DriveError::InvalidOverlayPath);
+        }
+
+        if !is_valid_queue_count(new_config.num_queues) {
+            return Err(DriveError::InvalidQueueCount);
+        }
+
+        if !supports_cache_type(&new_config) {
+            return Err(DriveError::DirectIoNotSupported);
+        }
+
+        if !is_valid_trace_path(&new_config) {
+            return Err(DriveError::InvalidTracePath);
+        }
+
         // Check if the root block device is being updated.
         if self.config_list[index].is_root_device {
             self.has_root_block = new_config.is_root_device;
@@ -233,21 +393,6 @@ mod tests {
     use self::tempfile::NamedTempFile;
     use super::*;
 
-    // This implementation is used only in tests.
-    // We cannot directly derive clone because RateLimiter does not implement clone.
-    impl Clone for BlockDeviceConfig {
-        fn clone(&self) -> Self {
-            BlockDeviceConfig {
-                path_on_host: self.path_on_host.clone(),
-                is_root_device: self.is_root_device,
-                partuuid: self.partuuid.clone(),
-                is_read_only: self.is_read_only,
-                drive_id: self.drive_id.clone(),
-                rate_limiter: None,
-            }
-        }
-    }
-
     #[test]
     fn test_create_block_devices_configs() {
         let block_devices_configs = BlockDeviceConfigs::new();
@@ -267,6 +412,12 @@ mod tests {
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -299,6 +450,12 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -324,6 +481,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -335,6 +498,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -359,6 +528,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -370,6 +545,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -381,6 +562,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -416,6 +603,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -427,6 +620,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -438,6 +637,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -474,6 +679,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -485,6 +696,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -552,6 +769,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         let root_block_device_new = BlockDeviceConfig {
             path_on_host: dummy_path_2,
@@ -560,6 +783,12 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: None,
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: None,
         };
         let index1 = block_devices_configs
             .get_index_of_drive_id(&root_block_device_old.drive_id)
@@ -575,4 +804,243 @@ mod tests {
             .is_ok());
         assert!(block_devices_configs.has_partuuid_root);
     }
+
+    #[test]
+    fn test_io_engine() {
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.io_engine, IoEngine::Sync);
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Async"
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.io_engine, IoEngine::Async);
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Libaio"
+              }"#;
+        assert!(serde_json::from_str::<BlockDeviceConfig>(json).is_err());
+    }
+
+    #[test]
+    fn test_image_format() {
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.format, ImageFormat::Raw);
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "format": "Qcow2"
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.format, ImageFormat::Qcow2);
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "format": "Vmdk"
+              }"#;
+        assert!(serde_json::from_str::<BlockDeviceConfig>(json).is_err());
+    }
+
+    #[test]
+    fn test_num_queues() {
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.num_queues, 1);
+
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            num_queues: 0,
+            ..config
+        };
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueCount)
+        );
+        block_device.num_queues = MAX_NUM_QUEUES + 1;
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueCount)
+        );
+
+        block_device.num_queues = 4;
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        block_device.num_queues = 0;
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidQueueCount)
+        );
+        assert_eq!(block_devices_configs.config_list[0].num_queues, 4);
+    }
+
+    #[test]
+    fn test_cache_type() {
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.cache_type, CacheType::Writeback);
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "cache_type": "Direct"
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.cache_type, CacheType::Direct);
+
+        // Only raw images without overlays support direct I/O.
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            format: ImageFormat::Qcow2,
+            ..config
+        };
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::DirectIoNotSupported)
+        );
+        block_device.format = ImageFormat::Raw;
+        block_device.overlay_path = Some(PathBuf::from("/foo/overlay"));
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::DirectIoNotSupported)
+        );
+        block_device.overlay_path = None;
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        block_device.cache_type = CacheType::Unsafe;
+        block_device.format = ImageFormat::Qcow2;
+        assert!(block_devices_configs.insert(block_device).is_ok());
+    }
+
+    #[test]
+    fn test_trace_path() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            drive_id: String::from("1"),
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            rate_limiter: None,
+            io_engine: IoEngine::Sync,
+            format: ImageFormat::Raw,
+            overlay_path: Some(PathBuf::from("/foo/overlay")),
+            num_queues: 1,
+            cache_type: CacheType::Writeback,
+            trace_path: Some(dummy_file.path().to_path_buf()),
+        };
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+
+        // Traces must not overwrite the disk image or its overlay.
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidTracePath)
+        );
+        block_device.trace_path = Some(PathBuf::from("/foo/overlay"));
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidTracePath)
+        );
+
+        block_device.trace_path = Some(PathBuf::from("/foo/trace"));
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+        // The same checks apply to updates.
+        block_device.trace_path = Some(dummy_file.path().to_path_buf());
+        assert_eq!(
+            block_devices_configs.insert(block_device),
+            Err(DriveError::InvalidTracePath)
+        );
+
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "/foo/bar",
+                "is_root_device": false,
+                "is_read_only": false,
+                "trace_path": "/foo/trace"
+              }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.trace_path, Some(PathBuf::from("/foo/trace")));
+    }
+
+    #[test]
+    fn test_nbd_path() {
+        let json = r#"{
+                "drive_id": "1",
+                "path_on_host": "nbd+unix:///foo/nbd.sock?export=disk",
+                "is_root_device": false,
+                "is_read_only": false
+              }"#;
+        let mut block_device: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+
+        // The export is not connected to yet, so the socket may not exist.
+        assert!(block_devices_configs.insert(block_device.clone()).is_ok());
+
+        block_device.path_on_host = PathBuf::from("nbd://localhost:10809");
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::InvalidBlockDevicePath)
+        );
+
+        block_device.path_on_host = PathBuf::from("nbd://127.0.0.1:10809/disk");
+        block_device.format = ImageFormat::Qcow2;
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::NbdFormatNotSupported)
+        );
+
+        block_device.format = ImageFormat::Raw;
+        block_device.cache_type = CacheType::Direct;
+        assert_eq!(
+            block_devices_configs.insert(block_device.clone()),
+            Err(DriveError::DirectIoNotSupported)
+        );
+
+        block_device.cache_type = CacheType::Writeback;
+        assert!(block_devices_configs.insert(block_device).is_ok());
+        assert_eq!(block_devices_configs.config_list.len(), 1);
+        assert_eq!(
+            block_devices_configs.config_list[0].path_on_host,
+            PathBuf::from("nbd://127.0.0.1:10809/disk")
+        );
+    }
 }
@@ -1,9 +1,15 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+
+use vstate;
+
 /// The microvm state. When Firecracker starts, the instance state is Uninitialized.
 /// Once start_microvm method is called, the state goes from Uninitialized to Starting.
 /// The state is changed to Running before ending the start_microvm method.
+/// A running microvm can be moved to Paused and back to Running.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum InstanceState {
     /// Microvm is not initialized.
@@ -12,6 +18,8 @@ pub enum InstanceState {
     Starting,
     /// Microvm is running.
     Running,
+    /// Microvm is paused.
+    Paused,
 }
 
 /// The strongly typed that contains general information about the microVM.
@@ -24,3 +32,48 @@ pub struct InstanceInfo {
     /// The version of the VMM that runs the microVM.
     pub vmm_version: String,
 }
+
+/// The state a running microVM can be moved to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum VmRunState {
+    /// Stop running the vCPUs and the devices.
+    Paused,
+    /// Go back to running the vCPUs and the devices.
+    Resumed,
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// of the request for changing the state of the microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VmRunStateConfig {
+    /// The requested state.
+    pub state: VmRunState,
+}
+
+/// Errors associated with pausing and resuming the microVM.
+#[derive(Debug)]
+pub enum VmRunStateError {
+    /// Only a running microVM can be paused.
+    MicroVMNotRunning,
+    /// Only a paused microVM can be resumed.
+    MicroVMNotPaused,
+    /// Cannot pause or resume the vCPUs.
+    Vcpu(vstate::Error),
+    /// Cannot stop or restart the device event handlers.
+    DeviceEvents(io::Error),
+}
+
+impl Display for VmRunStateError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VmRunStateError::*;
+        match *self {
+            MicroVMNotRunning => write!(f, "Cannot pause a microVM that is not running."),
+            MicroVMNotPaused => write!(f, "Cannot resume a microVM that is not paused."),
+            Vcpu(ref e) => write!(f, "Cannot pause or resume the vCPUs. {:?}", e),
+            DeviceEvents(ref e) => {
+                write!(f, "Cannot stop or restart the device event handlers. {}", e)
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use fc_util::versioned::{Error as VersionError, Result as VersionResult, Versioned};
+use memory_model::HugePageConfig;
 use serde::{de, Deserialize};
+use serde_json::Value;
 use std::fmt;
+use std::path::PathBuf;
 
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
@@ -18,6 +22,12 @@ pub enum VmConfigError {
     InvalidMemorySize,
     /// Cannot update the configuration of the microvm post boot.
     UpdateNotAllowedPostBoot,
+    /// The memory size is not a multiple of the size of the huge pages backing it.
+    MemorySizeNotHugePageAligned,
+    /// The memory cannot be backed by 2M pages when a balloon device is configured.
+    HugePagesWithBalloon,
+    /// Dirty pages cannot be tracked when an interface uses vhost-net.
+    DirtyPageTrackingWithVhost,
 }
 
 impl fmt::Display for VmConfigError {
@@ -33,6 +43,18 @@ impl fmt::Display for VmConfigError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
+            MemorySizeNotHugePageAligned => write!(
+                f,
+                "The memory size (MiB) must be a multiple of 2 when backed by 2M pages."
+            ),
+            HugePagesWithBalloon => write!(
+                f,
+                "The memory cannot be backed by 2M pages when a balloon device is configured."
+            ),
+            DirtyPageTrackingWithVhost => write!(
+                f,
+                "Dirty page tracking cannot be enabled when an interface uses vhost-net."
+            ),
         }
     }
 }
@@ -58,6 +80,16 @@ pub struct VmConfig {
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Enables or disables the tracking of the guest memory pages written by the guest, which
+    /// is needed for creating diff snapshots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_dirty_pages: Option<bool>,
+    /// The memory backing the guest RAM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_backend: Option<MemoryBackend>,
+    /// The size of the pages backing the guest RAM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backing_page_size: Option<BackingPageSize>,
 }
 
 impl Default for VmConfig {
@@ -67,6 +99,47 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            track_dirty_pages: Some(false),
+            mem_backend: Some(MemoryBackend::Anonymous),
+            backing_page_size: Some(BackingPageSize::Size4K),
+        }
+    }
+}
+
+// Version 1 did not have the `mem_backend` field, version 2 did not have the `backing_page_size`
+// field.
+impl Versioned for VmConfig {
+    const NAME: &'static str = "VmConfig";
+    const VERSION: u16 = 3;
+
+    fn upgrade(version: u16, state: Value) -> VersionResult<Value> {
+        match version {
+            1 | 2 => Ok(state),
+            _ => Err(VersionError::UnsupportedVersion {
+                name: Self::NAME,
+                version,
+            }),
+        }
+    }
+
+    fn downgrade(version: u16, mut state: Value) -> VersionResult<Value> {
+        match version {
+            2 => {
+                state
+                    .as_object_mut()
+                    .map(|fields| fields.remove("mem_backend"));
+                Ok(state)
+            }
+            3 => {
+                state
+                    .as_object_mut()
+                    .map(|fields| fields.remove("backing_page_size"));
+                Ok(state)
+            }
+            _ => Err(VersionError::UnsupportedVersion {
+                name: Self::NAME,
+                version: version - 1,
+            }),
         }
     }
 }
@@ -79,9 +152,76 @@ impl fmt::Display for VmConfig {
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
+        let track_dirty_pages = self.track_dirty_pages.unwrap_or(false);
+        let mem_backend = serde_json::to_string(
+            self.mem_backend
+                .as_ref()
+                .unwrap_or(&MemoryBackend::Anonymous),
+        )
+        .map_err(|_| fmt::Error)?;
+        let backing_page_size = self
+            .backing_page_size
+            .unwrap_or(BackingPageSize::Size4K)
+            .to_string();
 
-        write!(f, "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?} }}",
-               vcpu_count, mem_size, ht_enabled, cpu_template)
+        write!(f, "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?},  \"track_dirty_pages\": {:?},  \"mem_backend\": {},  \"backing_page_size\": {:?} }}",
+               vcpu_count, mem_size, ht_enabled, cpu_template, track_dirty_pages, mem_backend, backing_page_size)
+    }
+}
+
+/// The memory backing the guest RAM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "backend_type", deny_unknown_fields)]
+pub enum MemoryBackend {
+    /// Anonymous memory, only accessible to this process.
+    Anonymous,
+    /// An anonymous file created with `memfd_create`, which can be shared with other processes
+    /// through `/proc/<pid>/fd`.
+    Memfd,
+    /// A file on the host.
+    File {
+        /// Path to the file. A shared file is created if it does not exist and grown to the
+        /// memory size, while a private one must already hold the whole guest memory.
+        path: PathBuf,
+        /// Whether the writes to the guest memory reach the file. Otherwise, the file is mapped
+        /// copy on write.
+        shared: bool,
+    },
+}
+
+/// The size of the pages backing the guest RAM.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum BackingPageSize {
+    /// Regular 4 KiB pages.
+    #[serde(rename = "4K")]
+    Size4K,
+    /// 2 MiB pages from the pool of huge pages reserved by the host (hugetlbfs). A file backing
+    /// the guest memory must then be on a hugetlbfs mount.
+    #[serde(rename = "2M")]
+    Size2M,
+    /// Regular pages, which the kernel may merge into transparent huge pages.
+    #[serde(rename = "THP")]
+    Transparent,
+}
+
+impl BackingPageSize {
+    /// Returns the matching configuration of the guest memory mappings.
+    pub fn huge_pages(self) -> HugePageConfig {
+        match self {
+            BackingPageSize::Size4K => HugePageConfig::None,
+            BackingPageSize::Size2M => HugePageConfig::Hugetlbfs2M,
+            BackingPageSize::Transparent => HugePageConfig::Transparent,
+        }
+    }
+}
+
+impl fmt::Display for BackingPageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackingPageSize::Size4K => write!(f, "4K"),
+            BackingPageSize::Size2M => write!(f, "2M"),
+            BackingPageSize::Transparent => write!(f, "THP"),
+        }
     }
 }
 
@@ -130,6 +270,75 @@ mod tests {
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
     }
 
+    #[test]
+    fn test_mem_backend() {
+        let config: VmConfig = serde_json::from_str(
+            r#"{"mem_backend": {"backend_type": "File", "path": "/mem", "shared": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mem_backend,
+            Some(MemoryBackend::File {
+                path: PathBuf::from("/mem"),
+                shared: true,
+            })
+        );
+        let config: VmConfig =
+            serde_json::from_str(r#"{"mem_backend": {"backend_type": "Memfd"}}"#).unwrap();
+        assert_eq!(config.mem_backend, Some(MemoryBackend::Memfd));
+        assert!(
+            serde_json::from_str::<VmConfig>(r#"{"mem_backend": {"backend_type": "File"}}"#)
+                .is_err()
+        );
+        assert!(serde_json::from_str::<VmConfig>(
+            r#"{"mem_backend": {"backend_type": "Hugetlbfs"}}"#
+        )
+        .is_err());
+
+        // Version 1 of the state did not have a memory backend.
+        let config = VmConfig {
+            mem_backend: Some(MemoryBackend::Memfd),
+            ..Default::default()
+        };
+        let value = fc_util::versioned::to_value(&config, 1).unwrap();
+        assert!(value["state"].get("mem_backend").is_none());
+        let config: VmConfig = fc_util::versioned::from_value(value).unwrap();
+        assert_eq!(config.mem_backend, None);
+    }
+
+    #[test]
+    fn test_backing_page_size() {
+        for (name, page_size, huge_pages) in [
+            ("4K", BackingPageSize::Size4K, HugePageConfig::None),
+            ("2M", BackingPageSize::Size2M, HugePageConfig::Hugetlbfs2M),
+            (
+                "THP",
+                BackingPageSize::Transparent,
+                HugePageConfig::Transparent,
+            ),
+        ]
+        .iter()
+        {
+            let config: VmConfig =
+                serde_json::from_str(&format!(r#"{{"backing_page_size": "{}"}}"#, name)).unwrap();
+            assert_eq!(config.backing_page_size, Some(*page_size));
+            assert_eq!(page_size.to_string(), *name);
+            assert_eq!(page_size.huge_pages(), *huge_pages);
+        }
+        assert!(serde_json::from_str::<VmConfig>(r#"{"backing_page_size": "1G"}"#).is_err());
+
+        // Version 2 of the state did not have a backing page size.
+        let config = VmConfig {
+            backing_page_size: Some(BackingPageSize::Size2M),
+            ..Default::default()
+        };
+        let value = fc_util::versioned::to_value(&config, 2).unwrap();
+        assert!(value["state"].get("backing_page_size").is_none());
+        assert!(value["state"].get("mem_backend").is_some());
+        let config: VmConfig = fc_util::versioned::from_value(value).unwrap();
+        assert_eq!(config.backing_page_size, None);
+    }
+
     #[test]
     fn test_display_vm_config_error() {
         let expected_str = "The vCPU number is invalid! The vCPU number can only \
@@ -144,5 +353,11 @@ mod tests {
             VmConfigError::UpdateNotAllowedPostBoot.to_string(),
             expected_str
         );
+
+        let expected_str = "The memory size (MiB) must be a multiple of 2 when backed by 2M pages.";
+        assert_eq!(
+            VmConfigError::MemorySizeNotHugePageAligned.to_string(),
+            expected_str
+        );
     }
 }
@@ -0,0 +1,125 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::path::PathBuf;
+
+use memory_model::GuestMemoryError;
+use vmm_config::snapshot::SnapshotError;
+use vstate;
+
+/// This struct represents the strongly typed equivalent of the json body
+/// of the request for migrating the microVM to another Firecracker process.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationSendConfig {
+    /// Path to the Unix socket the destination Firecracker process listens on.
+    pub socket_path: PathBuf,
+}
+
+/// Describes where a Firecracker process started in incoming mode waits for a migration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationReceiveConfig {
+    /// Path to the Unix socket to listen on.
+    pub socket_path: PathBuf,
+}
+
+/// Errors associated with migrating a microVM.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// A microVM can only be migrated while it is running or paused.
+    MicroVMNotRunning,
+    /// A migration can only be received before the microVM is configured and started.
+    MicroVMAlreadyRunning,
+    /// Migrations rely on dirty page tracking, which must be enabled in the machine configuration.
+    DirtyPageTrackingDisabled,
+    /// The pages written by vhost-net are not tracked, nor the state of its rings saved.
+    VhostNet,
+    /// Cannot connect to the socket of the destination.
+    Connect(io::Error),
+    /// Cannot bind or listen on the migration socket.
+    Bind(io::Error),
+    /// Cannot accept the connection of the source.
+    Accept(io::Error),
+    /// Cannot send data over the migration socket.
+    Send(io::Error),
+    /// Cannot receive data from the migration socket.
+    Receive(io::Error),
+    /// The peer does not speak the migration protocol.
+    InvalidMagic,
+    /// The peer uses an unsupported version of the migration protocol.
+    UnsupportedVersion(u16),
+    /// The peer sent a message that was not expected at this point of the migration.
+    UnexpectedMessage(u32),
+    /// The peer sent a message longer than the protocol allows.
+    MessageTooLong(u64),
+    /// The microVM state does not match the memory layout sent at the start of the migration.
+    InvalidMemoryLayout,
+    /// Cannot serialize the memory layout or the microVM state.
+    Serialize(serde_json::Error),
+    /// Cannot deserialize the memory layout or the microVM state.
+    Deserialize(serde_json::Error),
+    /// Cannot access the guest memory.
+    GuestMemory(GuestMemoryError),
+    /// Cannot pause or resume the vCPUs.
+    Vcpu(vstate::Error),
+    /// Cannot stop or restart handling the device events.
+    DeviceEvents(io::Error),
+    /// Cannot save the state of the microVM.
+    State(SnapshotError),
+    /// The destination failed to resume the microVM.
+    Destination(String),
+    /// Cannot start the thread running the transfers.
+    StartWorker(io::Error),
+    /// The thread running the transfers stopped.
+    WorkerStopped,
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::MigrationError::*;
+        match *self {
+            MicroVMNotRunning => write!(
+                f,
+                "Cannot migrate a microVM that is not running or paused."
+            ),
+            MicroVMAlreadyRunning => write!(
+                f,
+                "Receiving a migration is only allowed before the microVM is configured and started."
+            ),
+            DirtyPageTrackingDisabled => write!(
+                f,
+                "Migrations require dirty page tracking to be enabled in the machine configuration."
+            ),
+            VhostNet => write!(
+                f,
+                "Migrations are not supported for microVMs with interfaces using vhost-net."
+            ),
+            Connect(ref e) => write!(f, "Cannot connect to the migration socket. {}", e),
+            Bind(ref e) => write!(f, "Cannot listen on the migration socket. {}", e),
+            Accept(ref e) => write!(f, "Cannot accept the migration connection. {}", e),
+            Send(ref e) => write!(f, "Cannot send migration data. {}", e),
+            Receive(ref e) => write!(f, "Cannot receive migration data. {}", e),
+            InvalidMagic => write!(f, "The peer is not a Firecracker migration endpoint."),
+            UnsupportedVersion(version) => {
+                write!(f, "Unsupported migration protocol version: {}.", version)
+            }
+            UnexpectedMessage(kind) => write!(f, "Unexpected migration message: {}.", kind),
+            MessageTooLong(len) => write!(f, "Migration message too long: {} bytes.", len),
+            InvalidMemoryLayout => write!(
+                f,
+                "The microVM state does not match the migrated memory layout."
+            ),
+            Serialize(ref e) => write!(f, "Cannot serialize the microVM state. {}", e),
+            Deserialize(ref e) => write!(f, "Cannot deserialize the microVM state. {}", e),
+            GuestMemory(ref e) => write!(f, "Cannot access the guest memory. {:?}", e),
+            Vcpu(ref e) => write!(f, "Cannot pause or resume the vCPUs. {:?}", e),
+            DeviceEvents(ref e) => write!(f, "Cannot pause or resume the devices. {}", e),
+            State(ref e) => write!(f, "Cannot save the microVM state. {}", e),
+            Destination(ref msg) => write!(f, "The destination failed to resume the microVM: {}", msg),
+            StartWorker(ref e) => write!(f, "Cannot start the migration worker. {}", e),
+            WorkerStopped => write!(f, "The migration worker stopped."),
+        }
+    }
+}
@@ -4,6 +4,8 @@
 use rate_limiter::{RateLimiter, TokenBucket};
 use std::io;
 
+/// Wrapper for configuring the balloon device attached to the microVM.
+pub mod balloon;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
 /// Wrapper for device configurations.
@@ -16,8 +18,14 @@ pub mod instance_info;
 pub mod logger;
 /// Wrapper for configuring the memory and CPU of the microVM.
 pub mod machine_config;
+/// Wrapper for migrating microVMs between Firecracker processes.
+pub mod migration;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for shutting down the microVM and reporting how it exited.
+pub mod shutdown;
+/// Wrapper for creating and loading microVM snapshots.
+pub mod snapshot;
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
 
@@ -32,7 +40,7 @@ pub mod vsock;
 
 /// A public-facing, stateless structure, holding all the data we need to create a TokenBucket
 /// (live) object.
-#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct TokenBucketConfig {
     /// See TokenBucket::size.
     pub size: u64,
@@ -55,12 +63,21 @@ impl TokenBucketConfig {
 
 /// A public-facing, stateless structure, holding all the data we need to create a RateLimiter
 /// (live) object.
-#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct RateLimiterConfig {
     /// Data used to initialize the RateLimiter::bandwidth bucket.
     pub bandwidth: Option<TokenBucketConfig>,
     /// Data used to initialize the RateLimiter::ops bucket.
     pub ops: Option<TokenBucketConfig>,
+    /// Data used to initialize the RateLimiter::read_bandwidth bucket. Only block devices charge
+    /// the reads and the writes to separate buckets.
+    pub read_bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the RateLimiter::read_ops bucket.
+    pub read_ops: Option<TokenBucketConfig>,
+    /// Data used to initialize the RateLimiter::write_bandwidth bucket.
+    pub write_bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the RateLimiter::write_ops bucket.
+    pub write_ops: Option<TokenBucketConfig>,
 }
 
 impl RateLimiterConfig {
@@ -68,14 +85,25 @@ impl RateLimiterConfig {
     pub fn into_rate_limiter(self) -> Result<RateLimiter, io::Error> {
         let bw = self.bandwidth.unwrap_or_default();
         let ops = self.ops.unwrap_or_default();
-        RateLimiter::new(
+        let mut rate_limiter = RateLimiter::new(
             bw.size,
             bw.one_time_burst,
             bw.refill_time,
             ops.size,
             ops.one_time_burst,
             ops.refill_time,
-        )
+        )?;
+        rate_limiter.update_read_buckets(
+            self.read_bandwidth
+                .map(TokenBucketConfig::into_token_bucket),
+            self.read_ops.map(TokenBucketConfig::into_token_bucket),
+        );
+        rate_limiter.update_write_buckets(
+            self.write_bandwidth
+                .map(TokenBucketConfig::into_token_bucket),
+            self.write_ops.map(TokenBucketConfig::into_token_bucket),
+        );
+        Ok(rate_limiter)
     }
     /// Updates the configuration, merging in new options from `new_config`.
     pub fn update(&mut self, new_config: &RateLimiterConfig) {
@@ -85,6 +113,18 @@ impl RateLimiterConfig {
         if new_config.ops.is_some() {
             self.ops = new_config.ops;
         }
+        if new_config.read_bandwidth.is_some() {
+            self.read_bandwidth = new_config.read_bandwidth;
+        }
+        if new_config.read_ops.is_some() {
+            self.read_ops = new_config.read_ops;
+        }
+        if new_config.write_bandwidth.is_some() {
+            self.write_bandwidth = new_config.write_bandwidth;
+        }
+        if new_config.write_ops.is_some() {
+            self.write_ops = new_config.write_ops;
+        }
     }
 }
 
@@ -119,6 +159,7 @@ mod tests {
                 one_time_burst: None,
                 refill_time: REFILL_TIME * 2,
             }),
+            ..Default::default()
         };
         let rl = rlconf.into_rate_limiter().unwrap();
         assert_eq!(rl.bandwidth().unwrap().capacity(), SIZE);
@@ -135,6 +176,7 @@ mod tests {
                 refill_time: REFILL_TIME * 2,
             }),
             ops: None,
+            ..Default::default()
         });
         assert_eq!(rlconf.bandwidth.unwrap().size, SIZE * 2);
         assert_eq!(
@@ -146,4 +188,44 @@ mod tests {
         assert_eq!(rlconf.ops.unwrap().one_time_burst, None);
         assert_eq!(rlconf.ops.unwrap().refill_time, REFILL_TIME * 2);
     }
+
+    #[test]
+    fn test_directional_rate_limiter_configs() {
+        // The configurations predating the directional buckets are still valid.
+        let rlconf: RateLimiterConfig =
+            serde_json::from_str(r#"{"bandwidth": {"size": 1000, "refill_time": 100}}"#).unwrap();
+        assert!(rlconf.read_bandwidth.is_none());
+        let rl = rlconf.into_rate_limiter().unwrap();
+        assert_eq!(rl.bandwidth().unwrap().capacity(), 1000);
+        assert!(rl.read_bandwidth().is_none());
+        assert!(rl.write_ops().is_none());
+
+        let mut rlconf: RateLimiterConfig = serde_json::from_str(
+            r#"{
+                "ops": {"size": 1000, "refill_time": 100},
+                "read_bandwidth": {"size": 2000, "refill_time": 100},
+                "write_ops": {"size": 10, "refill_time": 100}
+            }"#,
+        )
+        .unwrap();
+        let rl = rlconf.into_rate_limiter().unwrap();
+        assert!(rl.bandwidth().is_none());
+        assert_eq!(rl.ops().unwrap().capacity(), 1000);
+        assert_eq!(rl.read_bandwidth().unwrap().capacity(), 2000);
+        assert!(rl.read_ops().is_none());
+        assert!(rl.write_bandwidth().is_none());
+        assert_eq!(rl.write_ops().unwrap().capacity(), 10);
+
+        rlconf.update(&RateLimiterConfig {
+            write_bandwidth: Some(TokenBucketConfig {
+                size: 3000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(rlconf.read_bandwidth.unwrap().size, 2000);
+        assert_eq!(rlconf.write_bandwidth.unwrap().size, 3000);
+        assert_eq!(rlconf.write_ops.unwrap().size, 10);
+    }
 }
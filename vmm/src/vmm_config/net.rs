@@ -2,17 +2,31 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::result;
 
 use super::super::error::Error as VmmInternalError;
 use super::RateLimiterConfig;
 use devices;
+use devices::virtio::{EgressFilter, MAX_QUEUE_PAIRS, PCAP_HEADER_SIZE};
 use dumbo::MacAddr;
 use net_util::{Tap, TapError};
 
+/// Configuration of the filter applied to the frames sent by the guest. Frames whose source MAC
+/// address is not the guest MAC address are dropped.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressFilterConfig {
+    /// The addresses the guest may use as the source of its IPv4 packets and as the sender of its
+    /// ARP frames. Any address is allowed when missing.
+    pub allowed_ipv4_addresses: Option<Vec<Ipv4Addr>>,
+}
+
 /// This struct represents the strongly typed equivalent of the json body from net iface
 /// related requests.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
@@ -32,6 +46,19 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
+    /// The number of RX/TX queue pairs exposed to the guest. Interfaces with more than one
+    /// queue pair require a multi-queue TAP device, and use one of its queues per queue pair.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+    /// If this field is set, the frames are moved between the guest and the TAP device by the
+    /// in-kernel vhost-net driver instead of the device model. Such interfaces can neither reply
+    /// to MMDS requests nor be rate limited.
+    #[serde(default)]
+    pub vhost: bool,
+    /// If this field is set, the frames sent by the guest are filtered before reaching the TAP
+    /// device, so that the guest cannot spoof its MAC or IPv4 address. Requires a guest MAC
+    /// address.
+    pub egress_filter: Option<EgressFilterConfig>,
 }
 
 // Serde does not allow specifying a default value for a field
@@ -41,10 +68,19 @@ fn default_allow_mmds_requests() -> bool {
     false
 }
 
+fn default_num_queues() -> u16 {
+    1
+}
+
 impl NetworkInterfaceConfig {
-    /// Returns the tap device that `host_dev_name` refers to.
-    pub fn open_tap(&self) -> result::Result<Tap, NetworkInterfaceError> {
-        Tap::open_named(self.host_dev_name.as_str()).map_err(NetworkInterfaceError::OpenTap)
+    /// Returns one queue of the tap device that `host_dev_name` refers to per queue pair.
+    pub fn open_taps(&self) -> result::Result<Vec<Tap>, NetworkInterfaceError> {
+        if self.num_queues > 1 {
+            Tap::open_multi_queue(&self.host_dev_name, usize::from(self.num_queues))
+        } else {
+            Tap::open_named(&self.host_dev_name).map(|tap| vec![tap])
+        }
+        .map_err(NetworkInterfaceError::OpenTap)
     }
 
     /// Returns a reference to the mac address. It the mac address is not configured, it
@@ -57,6 +93,17 @@ impl NetworkInterfaceConfig {
     pub fn allow_mmds_requests(&self) -> bool {
         self.allow_mmds_requests
     }
+
+    /// Returns the filter to apply to the frames sent by the guest, if any.
+    pub fn egress_filter(&self) -> Option<EgressFilter> {
+        match (&self.egress_filter, self.guest_mac) {
+            (Some(filter), Some(guest_mac)) => Some(EgressFilter::new(
+                guest_mac,
+                filter.allowed_ipv4_addresses.clone(),
+            )),
+            _ => None,
+        }
+    }
 }
 
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
@@ -74,6 +121,44 @@ pub struct NetworkInterfaceUpdateConfig {
     pub tx_rate_limiter: Option<RateLimiterConfig>,
 }
 
+/// The data fed into a request starting or stopping the capture of the frames received and sent
+/// by a network interface, MMDS frames included.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceCaptureConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// Path of the pcap file receiving the frames, which must not exist yet. The current capture
+    /// of the interface, if any, stops when missing.
+    pub capture_path: Option<PathBuf>,
+    /// The maximum number of bytes of each frame written to the capture.
+    #[serde(default = "default_snap_len")]
+    pub snap_len: u32,
+    /// The maximum size of the capture file. The capture stops once it is reached.
+    #[serde(default = "default_max_capture_size")]
+    pub max_size_bytes: u64,
+}
+
+// Frames are at most 65535 bytes long, so they are captured whole by default.
+fn default_snap_len() -> u32 {
+    65535
+}
+
+fn default_max_capture_size() -> u64 {
+    64 << 20
+}
+
+impl NetworkInterfaceCaptureConfig {
+    /// Checks that the capture can hold at least one byte of a frame.
+    pub fn validate(&self) -> result::Result<(), NetworkInterfaceError> {
+        if self.snap_len == 0 || self.max_size_bytes <= PCAP_HEADER_SIZE {
+            Err(NetworkInterfaceError::InvalidCaptureLimits)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum NetworkInterfaceError {
@@ -91,6 +176,29 @@ pub enum NetworkInterfaceError {
     RateLimiterUpdateFailed(devices::Error),
     /// The update is not allowed after booting the microvm.
     UpdateNotAllowedPostBoot,
+    /// The number of queue pairs is out of range.
+    InvalidQueueCount,
+    /// MMDS requests cannot be handled by interfaces using vhost-net.
+    VhostWithMmds,
+    /// Interfaces using vhost-net cannot be rate limited.
+    VhostWithRateLimiter,
+    /// The frames of interfaces using vhost-net cannot be filtered.
+    VhostWithEgressFilter,
+    /// The egress filter requires a guest MAC address.
+    EgressFilterWithoutGuestMac,
+    /// The operation is not allowed before booting the microvm.
+    OperationNotAllowedPreBoot,
+    /// The frames of interfaces using vhost-net cannot be captured.
+    VhostWithCapture,
+    /// The snap length or the size cap of a capture is too small.
+    InvalidCaptureLimits,
+    /// Cannot create the file capturing the frames of an interface.
+    CreateCapture(io::Error),
+    /// The capture file already exists.
+    CaptureFileExists,
+    /// The pages written by vhost-net are not tracked, so it cannot be used along with dirty page
+    /// tracking.
+    VhostWithDirtyPageTracking,
 }
 
 impl Display for NetworkInterfaceError {
@@ -128,10 +236,78 @@ impl Display for NetworkInterfaceError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.",)
             }
+            InvalidQueueCount => write!(
+                f,
+                "The number of queue pairs must be between 1 and {}.",
+                MAX_QUEUE_PAIRS
+            ),
+            VhostWithMmds => write!(
+                f,
+                "MMDS requests cannot be allowed on an interface using vhost-net."
+            ),
+            VhostWithRateLimiter => write!(
+                f,
+                "Rate limiters cannot be set on an interface using vhost-net."
+            ),
+            VhostWithEgressFilter => write!(
+                f,
+                "An egress filter cannot be set on an interface using vhost-net."
+            ),
+            EgressFilterWithoutGuestMac => write!(
+                f,
+                "An egress filter requires the guest MAC address to be set."
+            ),
+            OperationNotAllowedPreBoot => write!(f, "Operation not allowed pre-boot!"),
+            VhostWithCapture => write!(
+                f,
+                "The frames of an interface using vhost-net cannot be captured."
+            ),
+            InvalidCaptureLimits => write!(
+                f,
+                "The snap length must be positive and the size cap must exceed {} bytes.",
+                PCAP_HEADER_SIZE
+            ),
+            CreateCapture(ref e) => write!(f, "Cannot create the packet capture: {}", e),
+            CaptureFileExists => write!(f, "The packet capture file already exists."),
+            VhostWithDirtyPageTracking => write!(
+                f,
+                "An interface using vhost-net cannot be added when dirty page tracking is enabled."
+            ),
         }
     }
 }
 
+fn is_valid_queue_count(num_queues: u16) -> bool {
+    (1..=MAX_QUEUE_PAIRS).contains(&num_queues)
+}
+
+// The frames of interfaces using vhost-net bypass the device model, so they can neither reach
+// the MMDS nor go through the rate limiters and the egress filter.
+fn validate_vhost(config: &NetworkInterfaceConfig) -> result::Result<(), NetworkInterfaceError> {
+    if !config.vhost {
+        Ok(())
+    } else if config.allow_mmds_requests {
+        Err(NetworkInterfaceError::VhostWithMmds)
+    } else if config.rx_rate_limiter.is_some() || config.tx_rate_limiter.is_some() {
+        Err(NetworkInterfaceError::VhostWithRateLimiter)
+    } else if config.egress_filter.is_some() {
+        Err(NetworkInterfaceError::VhostWithEgressFilter)
+    } else {
+        Ok(())
+    }
+}
+
+// The egress filter drops the frames whose source is not the guest MAC address.
+fn validate_egress_filter(
+    config: &NetworkInterfaceConfig,
+) -> result::Result<(), NetworkInterfaceError> {
+    if config.egress_filter.is_some() && config.guest_mac.is_none() {
+        Err(NetworkInterfaceError::EgressFilterWithoutGuestMac)
+    } else {
+        Ok(())
+    }
+}
+
 /// A wrapper over the list of the `NetworkInterfaceConfig` that the microvm has configured.
 #[derive(Default)]
 pub struct NetworkInterfaceConfigs {
@@ -146,6 +322,11 @@ impl NetworkInterfaceConfigs {
         }
     }
 
+    /// Returns an iterator over the network interfaces.
+    pub fn iter(&self) -> ::std::slice::Iter<NetworkInterfaceConfig> {
+        self.if_list.iter()
+    }
+
     /// Returns a mutable iterator over the network interfaces.
     pub fn iter_mut(&mut self) -> ::std::slice::IterMut<NetworkInterfaceConfig> {
         self.if_list.iter_mut()
@@ -185,6 +366,12 @@ impl NetworkInterfaceConfigs {
         index: usize,
         new_config: &NetworkInterfaceConfig,
     ) -> result::Result<(), NetworkInterfaceError> {
+        if !is_valid_queue_count(new_config.num_queues) {
+            return Err(NetworkInterfaceError::InvalidQueueCount);
+        }
+        validate_vhost(new_config)?;
+        validate_egress_filter(new_config)?;
+
         // Check that the mac address is unique. In order to do so, we search for the
         // network interface that has the same mac address as the one specified in new_config.
         // If the same mac is used in another network interface config, return error.
@@ -216,13 +403,19 @@ impl NetworkInterfaceConfigs {
         self.if_list[index] = updated_netif_config;
 
         // Check that the tap can be opened.
-        self.if_list[index].open_tap().map(|_| ())
+        self.if_list[index].open_taps().map(|_| ())
     }
 
     fn validate_create(
         &self,
         new_config: &NetworkInterfaceConfig,
     ) -> result::Result<(), NetworkInterfaceError> {
+        if !is_valid_queue_count(new_config.num_queues) {
+            return Err(NetworkInterfaceError::InvalidQueueCount);
+        }
+        validate_vhost(new_config)?;
+        validate_egress_filter(new_config)?;
+
         // Check that there is no other interface in the list that has the same mac.
         if new_config.guest_mac.is_some()
             && self
@@ -245,7 +438,7 @@ impl NetworkInterfaceConfigs {
         }
 
         // Check that the tap refered to in `new_config` can be opened.
-        new_config.open_tap().map(|_| ())
+        new_config.open_taps().map(|_| ())
     }
 
     fn create(
@@ -274,19 +467,9 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: false,
-        }
-    }
-
-    impl Clone for NetworkInterfaceConfig {
-        fn clone(&self) -> Self {
-            NetworkInterfaceConfig {
-                iface_id: self.iface_id.clone(),
-                host_dev_name: self.host_dev_name.clone(),
-                guest_mac: self.guest_mac,
-                rx_rate_limiter: None,
-                tx_rate_limiter: None,
-                allow_mmds_requests: self.allow_mmds_requests,
-            }
+            num_queues: 1,
+            vhost: false,
+            egress_filter: None,
         }
     }
 
@@ -398,6 +581,163 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_num_queues() {
+        let json = r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "mqdev1"
+              }"#;
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(netif.num_queues, 1);
+
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        netif.num_queues = 0;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::InvalidQueueCount) => (),
+            _ => panic!("Expected NetworkInterfaceError::InvalidQueueCount"),
+        }
+        netif.num_queues = MAX_QUEUE_PAIRS + 1;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::InvalidQueueCount) => (),
+            _ => panic!("Expected NetworkInterfaceError::InvalidQueueCount"),
+        }
+
+        netif.num_queues = 4;
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+        assert_eq!(netif_configs.if_list[0].open_taps().unwrap().len(), 4);
+
+        netif.num_queues = 0;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::InvalidQueueCount) => (),
+            _ => panic!("Expected NetworkInterfaceError::InvalidQueueCount"),
+        }
+        assert_eq!(netif_configs.if_list[0].num_queues, 4);
+    }
+
+    #[test]
+    fn test_vhost() {
+        let json = r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "vhostdev1",
+                "allow_mmds_requests": true,
+                "rx_rate_limiter": {}
+              }"#;
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(json).unwrap();
+        assert!(!netif.vhost);
+
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        netif.vhost = true;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::VhostWithMmds) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithMmds"),
+        }
+        netif.allow_mmds_requests = false;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::VhostWithRateLimiter) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithRateLimiter"),
+        }
+        netif.rx_rate_limiter = None;
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+
+        // The same checks apply to updates.
+        netif.allow_mmds_requests = true;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::VhostWithMmds) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithMmds"),
+        }
+        netif.vhost = false;
+        assert!(netif_configs.insert(netif).is_ok());
+        assert!(!netif_configs.if_list[0].vhost);
+    }
+
+    #[test]
+    fn test_egress_filter() {
+        let json = r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "filterdev1",
+                "egress_filter": {
+                    "allowed_ipv4_addresses": ["192.168.0.2", "0.0.0.0"]
+                }
+              }"#;
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(json).unwrap();
+        let allowed_ipv4_addresses = vec![Ipv4Addr::new(192, 168, 0, 2), Ipv4Addr::new(0, 0, 0, 0)];
+        assert_eq!(
+            netif.egress_filter,
+            Some(EgressFilterConfig {
+                allowed_ipv4_addresses: Some(allowed_ipv4_addresses.clone()),
+            })
+        );
+        assert!(serde_json::from_str::<EgressFilterConfig>(r#"{"foo": []}"#).is_err());
+        assert!(serde_json::from_str::<EgressFilterConfig>(
+            r#"{"allowed_ipv4_addresses": ["foo"]}"#
+        )
+        .is_err());
+
+        // The filter needs the guest MAC address.
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::EgressFilterWithoutGuestMac) => (),
+            _ => panic!("Expected NetworkInterfaceError::EgressFilterWithoutGuestMac"),
+        }
+        assert_eq!(netif.egress_filter(), None);
+
+        let guest_mac = MacAddr::parse_str("01:23:45:67:89:0a").unwrap();
+        netif.guest_mac = Some(guest_mac);
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+        assert_eq!(
+            netif_configs.if_list[0].egress_filter(),
+            Some(EgressFilter::new(guest_mac, Some(allowed_ipv4_addresses)))
+        );
+
+        // The frames of interfaces using vhost-net cannot be filtered.
+        netif.vhost = true;
+        match netif_configs.insert(netif.clone()) {
+            Err(NetworkInterfaceError::VhostWithEgressFilter) => (),
+            _ => panic!("Expected NetworkInterfaceError::VhostWithEgressFilter"),
+        }
+
+        netif.vhost = false;
+        netif.egress_filter = None;
+        assert!(netif_configs.insert(netif).is_ok());
+        assert_eq!(netif_configs.if_list[0].egress_filter(), None);
+    }
+
+    #[test]
+    fn test_capture_config() {
+        let config: NetworkInterfaceCaptureConfig =
+            serde_json::from_str(r#"{"iface_id": "foo", "capture_path": "/tmp/foo.pcap"}"#)
+                .unwrap();
+        assert_eq!(
+            config,
+            NetworkInterfaceCaptureConfig {
+                iface_id: "foo".to_string(),
+                capture_path: Some(PathBuf::from("/tmp/foo.pcap")),
+                snap_len: 65535,
+                max_size_bytes: 64 << 20,
+            }
+        );
+        assert!(config.validate().is_ok());
+
+        let invalid_configs = [
+            NetworkInterfaceCaptureConfig {
+                snap_len: 0,
+                ..config
+            },
+            NetworkInterfaceCaptureConfig {
+                iface_id: "foo".to_string(),
+                capture_path: None,
+                snap_len: 1,
+                max_size_bytes: PCAP_HEADER_SIZE,
+            },
+        ];
+        for config in invalid_configs.iter() {
+            match config.validate() {
+                Err(NetworkInterfaceError::InvalidCaptureLimits) => (),
+                _ => panic!("Expected NetworkInterfaceError::InvalidCaptureLimits"),
+            }
+        }
+    }
+
     #[test]
     fn test_error_display() {
         let _ = format!(
@@ -443,5 +783,50 @@ mod tests {
             NetworkInterfaceError::UpdateNotAllowedPostBoot,
             NetworkInterfaceError::UpdateNotAllowedPostBoot
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidQueueCount,
+            NetworkInterfaceError::InvalidQueueCount
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostWithMmds,
+            NetworkInterfaceError::VhostWithMmds
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostWithRateLimiter,
+            NetworkInterfaceError::VhostWithRateLimiter
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostWithEgressFilter,
+            NetworkInterfaceError::VhostWithEgressFilter
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::EgressFilterWithoutGuestMac,
+            NetworkInterfaceError::EgressFilterWithoutGuestMac
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::OperationNotAllowedPreBoot,
+            NetworkInterfaceError::OperationNotAllowedPreBoot
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostWithCapture,
+            NetworkInterfaceError::VhostWithCapture
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidCaptureLimits,
+            NetworkInterfaceError::InvalidCaptureLimits
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::CreateCapture(io::Error::from_raw_os_error(0)),
+            NetworkInterfaceError::CreateCapture(io::Error::from_raw_os_error(0))
+        );
     }
 }
@@ -0,0 +1,87 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+
+use EventLoopExitReason;
+
+/// The time, in milliseconds, the guest is given to shut down when no grace period is provided.
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD_MS: u64 = 5000;
+
+fn default_grace_period_ms() -> u64 {
+    DEFAULT_SHUTDOWN_GRACE_PERIOD_MS
+}
+
+/// This struct represents the strongly typed equivalent of the payload of the `Shutdown` action.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// The time, in milliseconds, the guest is given to shut down before the microVM is stopped.
+    #[serde(default = "default_grace_period_ms")]
+    pub grace_period_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period_ms: DEFAULT_SHUTDOWN_GRACE_PERIOD_MS,
+        }
+    }
+}
+
+/// The outcome of a microVM that stopped running.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExitStatus {
+    /// The reason the event loop of the VMM returned for the last time.
+    pub exit_reason: EventLoopExitReason,
+    /// The exit code of the Firecracker process.
+    pub exit_code: u8,
+}
+
+/// Errors associated with shutting down the microVM.
+#[derive(Debug)]
+pub enum ShutdownError {
+    /// A shutdown was already requested.
+    InProgress,
+    /// The microVM is still running, so there is no exit status yet.
+    NotExited,
+    /// The guest cannot be asked to shut down on this architecture.
+    NotSupported,
+}
+
+impl Display for ShutdownError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::ShutdownError::*;
+        match *self {
+            InProgress => write!(f, "The microVM is already shutting down."),
+            NotExited => write!(f, "The microVM has not exited yet."),
+            NotSupported => write!(
+                f,
+                "Asking the guest to shut down is not supported on this architecture."
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_config() {
+        let config: ShutdownConfig = serde_json::from_str(r#"{"grace_period_ms": 100}"#).unwrap();
+        assert_eq!(config.grace_period_ms, 100);
+        let config: ShutdownConfig = serde_json::from_str(r#"{}"#).unwrap();
+        assert_eq!(config, ShutdownConfig::default());
+        assert!(serde_json::from_str::<ShutdownConfig>(r#"{"grace_period": 100}"#).is_err());
+
+        let status = ExitStatus {
+            exit_reason: EventLoopExitReason::ShutdownTimeout,
+            exit_code: ::FC_EXIT_CODE_SHUTDOWN_TIMEOUT,
+        };
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"exit_reason":"ShutdownTimeout","exit_code":153}"#
+        );
+    }
+}
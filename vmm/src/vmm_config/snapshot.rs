@@ -0,0 +1,211 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::path::PathBuf;
+
+use device_manager;
+use devices;
+use error::StartMicrovmError;
+use memory_model::GuestMemoryError;
+use vstate;
+
+/// The kind of memory file written when creating a snapshot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SnapshotType {
+    /// The memory file holds the whole guest memory.
+    Full,
+    /// The memory file is a sparse file holding only the pages written since the last snapshot.
+    Diff,
+}
+
+impl Default for SnapshotType {
+    fn default() -> Self {
+        SnapshotType::Full
+    }
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// of the request for creating a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotCreateConfig {
+    /// Path to the file that will hold the microVM state.
+    pub snapshot_path: PathBuf,
+    /// Path to the file that will hold the guest memory.
+    pub mem_file_path: PathBuf,
+    /// Whether to write the whole guest memory or only the pages written since the last snapshot.
+    #[serde(default)]
+    pub snapshot_type: SnapshotType,
+}
+
+/// Describes how the guest memory of a loaded snapshot is populated from the memory file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "backend_type", deny_unknown_fields)]
+pub enum SnapshotMemoryBackend {
+    /// The guest memory is a copy on write mapping of the memory file.
+    File,
+    /// The guest memory is registered with userfaultfd and each page is copied from the memory
+    /// file by a handler thread the first time it is accessed.
+    Uffd,
+    /// The guest memory is registered with userfaultfd, which is sent to the external page
+    /// fault handler listening on `socket_path`.
+    UffdSocket {
+        /// Path to the Unix socket of the page fault handler.
+        socket_path: PathBuf,
+    },
+}
+
+impl Default for SnapshotMemoryBackend {
+    fn default() -> Self {
+        SnapshotMemoryBackend::File
+    }
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// of the request for loading a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotLoadConfig {
+    /// Path to the file holding the microVM state.
+    pub snapshot_path: PathBuf,
+    /// Path to the file holding the guest memory.
+    pub mem_file_path: PathBuf,
+    /// How the guest memory is populated from the memory file.
+    #[serde(default)]
+    pub mem_backend: SnapshotMemoryBackend,
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// of the request for merging a diff snapshot onto the memory file of a previous snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotMergeConfig {
+    /// Path to the file holding the microVM state of the diff snapshot.
+    pub snapshot_path: PathBuf,
+    /// Path to the sparse memory file of the diff snapshot.
+    pub mem_file_path: PathBuf,
+    /// Path to the memory file the dirty pages are copied to.
+    pub base_mem_file_path: PathBuf,
+}
+
+/// Errors associated with creating and loading snapshots.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Snapshots can only be created while the microVM is running or paused.
+    MicroVMNotRunning,
+    /// Snapshots can only be loaded before the microVM is started.
+    MicroVMAlreadyRunning,
+    /// Cannot create the snapshot or memory file.
+    CreateFile(io::Error),
+    /// Cannot open the snapshot or memory file.
+    OpenFile(io::Error),
+    /// Cannot write to or flush the snapshot or memory file.
+    WriteFile(io::Error),
+    /// Cannot read from the snapshot or memory file.
+    ReadFile(io::Error),
+    /// Cannot serialize the microVM state.
+    Serialize(serde_json::Error),
+    /// Cannot deserialize the microVM state.
+    Deserialize(serde_json::Error),
+    /// The file is not a snapshot file.
+    InvalidMagic,
+    /// The snapshot was created with an unsupported version of the snapshot format.
+    UnsupportedVersion(u16),
+    /// The memory file does not match the memory layout described by the snapshot.
+    InvalidMemoryFile,
+    /// The snapshot does not hold the state of every vCPU.
+    VcpuCountMismatch,
+    /// Diff snapshots can only be created when dirty page tracking is enabled.
+    DirtyPageTrackingDisabled,
+    /// Only diff snapshots can be merged onto a memory file.
+    NotDiffSnapshot,
+    /// The memory file of the snapshot is the file backing the guest memory.
+    MemoryFileInUse,
+    /// The rings of the interfaces using vhost-net are not stopped, nor their state saved.
+    VhostNet,
+    /// Cannot register the guest memory with userfaultfd or start the page fault handler.
+    Userfaultfd(io::Error),
+    /// Cannot hand the userfaultfd over to the external page fault handler.
+    PageFaultHandlerSocket(io::Error),
+    /// Cannot access the guest memory.
+    GuestMemory(GuestMemoryError),
+    /// Cannot pause, resume or query the vCPUs.
+    Vcpu(vstate::Error),
+    /// Cannot save or restore the state of the VM.
+    Vm(vstate::Error),
+    /// Cannot save or restore the state of the devices.
+    DeviceManager(device_manager::mmio::Error),
+    /// Cannot complete the block requests in flight on the asynchronous I/O engine.
+    BlockRequests(devices::Error),
+    /// Cannot rebuild the microVM.
+    Restore(StartMicrovmError),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::SnapshotError::*;
+        match *self {
+            MicroVMNotRunning => write!(
+                f,
+                "Cannot create a snapshot of a microVM that is not running or paused."
+            ),
+            MicroVMAlreadyRunning => write!(
+                f,
+                "Loading a snapshot is only allowed before the microVM is configured and started."
+            ),
+            CreateFile(ref e) => write!(f, "Cannot create the snapshot file. {}", e),
+            OpenFile(ref e) => write!(f, "Cannot open the snapshot file. {}", e),
+            WriteFile(ref e) => write!(f, "Cannot write the snapshot file. {}", e),
+            ReadFile(ref e) => write!(f, "Cannot read the snapshot file. {}", e),
+            Serialize(ref e) => write!(f, "Cannot serialize the microVM state. {}", e),
+            Deserialize(ref e) => write!(f, "Cannot deserialize the microVM state. {}", e),
+            InvalidMagic => write!(f, "The file is not a Firecracker snapshot."),
+            UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot format version: {}.", version)
+            }
+            InvalidMemoryFile => write!(
+                f,
+                "The memory file does not match the memory layout of the snapshot."
+            ),
+            VcpuCountMismatch => write!(
+                f,
+                "The number of vCPU states does not match the vCPU count of the snapshot."
+            ),
+            DirtyPageTrackingDisabled => write!(
+                f,
+                "Diff snapshots require dirty page tracking to be enabled in the machine configuration."
+            ),
+            NotDiffSnapshot => write!(f, "The snapshot is not a diff snapshot."),
+            MemoryFileInUse => write!(
+                f,
+                "The memory file cannot be the file backing the guest memory."
+            ),
+            VhostNet => write!(
+                f,
+                "Snapshots are not supported for microVMs with interfaces using vhost-net."
+            ),
+            Userfaultfd(ref e) => write!(
+                f,
+                "Cannot load the guest memory on demand with userfaultfd. {}",
+                e
+            ),
+            PageFaultHandlerSocket(ref e) => write!(
+                f,
+                "Cannot send the userfaultfd to the page fault handler. {}",
+                e
+            ),
+            GuestMemory(ref e) => write!(f, "Cannot access the guest memory. {:?}", e),
+            Vcpu(ref e) => write!(f, "Cannot save or restore the vCPU state. {:?}", e),
+            Vm(ref e) => write!(f, "Cannot save or restore the VM state. {:?}", e),
+            DeviceManager(ref e) => write!(f, "Cannot save or restore the device state. {}", e),
+            BlockRequests(ref e) => write!(
+                f,
+                "Cannot complete the block requests in flight. {:?}",
+                e
+            ),
+            Restore(ref e) => write!(f, "Cannot restore the microVM. {}", e),
+        }
+    }
+}
@@ -5,9 +5,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::cell::Cell;
 use std::io;
+#[cfg(target_arch = "x86_64")]
+use std::mem;
 use std::result;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Barrier};
+use std::thread;
 
 use super::TimestampUs;
 use arch;
@@ -17,12 +22,22 @@ use arch::aarch64::gic::GICDevice;
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
 use default_syscalls;
 #[cfg(target_arch = "x86_64")]
-use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
+use fc_util::versioned::Versioned;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_msrs, kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events,
+    kvm_xcrs, kvm_xsave, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+    KVM_PIT_SPEAKER_DUMMY,
+};
 use kvm_bindings::{kvm_userspace_memory_region, KVM_API_VERSION};
 use kvm_ioctls::*;
+use libc::{c_int, c_void, siginfo_t};
 use logger::{LogOption, Metric, LOGGER, METRICS};
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
-use sys_util::EventFd;
+#[cfg(target_arch = "x86_64")]
+use snapshot::{pod, pod_vec};
+use sys_util::{register_vcpu_signal_handler, EventFd, Killable};
 #[cfg(target_arch = "x86_64")]
 use vmm_config::machine_config::CpuFeaturesTemplate;
 use vmm_config::machine_config::VmConfig;
@@ -35,6 +50,18 @@ const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u64 = 0x03f0;
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u64 = 0x40000000;
 const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
 
+/// Offset from `SIGRTMIN` of the signal used to kick vCPU threads out of `KVM_RUN`.
+pub const VCPU_RTSIG_OFFSET: i32 = 0;
+
+// MSRs which are reported by `KVM_GET_MSR_INDEX_LIST` but which cannot be read back, or which
+// must not be restored on a running vCPU.
+#[cfg(target_arch = "x86_64")]
+const MSR_IA32_FEATURE_CONTROL: u32 = 0x0000_003a;
+#[cfg(target_arch = "x86_64")]
+const MSR_IA32_MCG_CTL: u32 = 0x0000_017b;
+
+thread_local!(static TLS_VCPU_PTR: Cell<Option<*const Vcpu>> = Cell::new(None));
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
@@ -89,6 +116,26 @@ pub enum Error {
     VcpuSpawn(io::Error),
     /// Unexpected KVM_RUN exit reason
     VcpuUnhandledKvmExit,
+    /// Cannot register the signal handler used to kick the vCPUs.
+    RegisterSignalHandler(io::Error),
+    /// Cannot send an event to a vCPU thread.
+    VcpuEventSend,
+    /// Cannot signal a vCPU thread.
+    VcpuKick(io::Error),
+    /// The vCPU thread did not answer an event in time.
+    VcpuResponseTimeout,
+    /// The vCPU must be paused for this operation.
+    VcpuNotPaused,
+    /// Cannot retrieve the state of the vCPU.
+    VcpuGetState(io::Error),
+    /// Cannot restore the state of the vCPU.
+    VcpuSetState(io::Error),
+    /// Only part of the MSRs could be saved or restored.
+    VcpuMsrsIncomplete,
+    /// Cannot retrieve the state of the VM.
+    VmGetState(io::Error),
+    /// Cannot restore the state of the VM.
+    VmSetState(io::Error),
     #[cfg(target_arch = "aarch64")]
     /// Error setting up the global interrupt controller.
     SetupGIC(arch::aarch64::gic::Error),
@@ -155,6 +202,8 @@ pub struct Vm {
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     supported_cpuid: CpuId,
+    #[cfg(target_arch = "x86_64")]
+    msr_list: Vec<u32>,
 
     // Arm specific fields.
     // On aarch64 we need to keep around the fd obtained by creating the VGIC device.
@@ -172,10 +221,21 @@ impl Vm {
         let supported_cpuid = kvm
             .get_supported_cpuid(MAX_KVM_CPUID_ENTRIES)
             .map_err(Error::VmFd)?;
+        // The list of MSRs saved along with the vCPU state. It is retrieved here, as the
+        // system ioctl it relies on is not allowed once the seccomp filters are in place.
+        #[cfg(target_arch = "x86_64")]
+        let msr_list = kvm
+            .get_msr_index_list()
+            .map_err(Error::VmFd)?
+            .into_iter()
+            .filter(|msr| *msr != MSR_IA32_FEATURE_CONTROL && *msr != MSR_IA32_MCG_CTL)
+            .collect();
         Ok(Vm {
             fd: vm_fd,
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid,
+            #[cfg(target_arch = "x86_64")]
+            msr_list,
             guest_mem: None,
             #[cfg(target_arch = "aarch64")]
             irqchip_handle: None,
@@ -188,8 +248,20 @@ impl Vm {
         &self.supported_cpuid
     }
 
-    /// Initializes the guest memory.
-    pub fn memory_init(&mut self, guest_mem: GuestMemory, kvm_context: &KvmContext) -> Result<()> {
+    /// Returns the list of MSRs that make up the state of a vCPU.
+    #[cfg(target_arch = "x86_64")]
+    pub fn msr_list(&self) -> &[u32] {
+        &self.msr_list
+    }
+
+    /// Initializes the guest memory. When `track_dirty_pages` is set, KVM logs the pages
+    /// written by the guest.
+    pub fn memory_init(
+        &mut self,
+        guest_mem: GuestMemory,
+        kvm_context: &KvmContext,
+        track_dirty_pages: bool,
+    ) -> Result<()> {
         if guest_mem.num_regions() > kvm_context.max_memslots() {
             return Err(Error::NotEnoughMemorySlots);
         }
@@ -197,7 +269,9 @@ impl Vm {
             .with_regions(|index, guest_addr, size, host_addr| {
                 info!("Guest memory starts at {:x?}", host_addr);
 
-                let flags = if LOGGER.flags() & LogOption::LogDirtyPages as usize > 0 {
+                let flags = if track_dirty_pages
+                    || LOGGER.flags() & LogOption::LogDirtyPages as usize > 0
+                {
                     KVM_MEM_LOG_DIRTY_PAGES
                 } else {
                     0
@@ -236,6 +310,63 @@ impl Vm {
         self.fd.create_pit2(pit_config).map_err(Error::VmSetup)
     }
 
+    /// Saves the state of the in-kernel interrupt controllers, PIT and clock.
+    #[cfg(target_arch = "x86_64")]
+    pub fn save_state(&self) -> Result<VmState> {
+        let mut pic_master = Self::irqchip(KVM_IRQCHIP_PIC_MASTER);
+        self.fd
+            .get_irqchip(&mut pic_master)
+            .map_err(Error::VmGetState)?;
+        let mut pic_slave = Self::irqchip(KVM_IRQCHIP_PIC_SLAVE);
+        self.fd
+            .get_irqchip(&mut pic_slave)
+            .map_err(Error::VmGetState)?;
+        let mut ioapic = Self::irqchip(KVM_IRQCHIP_IOAPIC);
+        self.fd
+            .get_irqchip(&mut ioapic)
+            .map_err(Error::VmGetState)?;
+        let pitstate = self.fd.get_pit2().map_err(Error::VmGetState)?;
+        let mut clock = self.fd.get_clock().map_err(Error::VmGetState)?;
+        // KVM_SET_CLOCK only accepts a zeroed flags field.
+        clock.flags = 0;
+
+        Ok(VmState {
+            pic_master,
+            pic_slave,
+            ioapic,
+            pitstate,
+            clock,
+        })
+    }
+
+    /// Restores the state of the in-kernel interrupt controllers, PIT and clock.
+    ///
+    /// The interrupt controllers must have been created with `setup_irqchip` beforehand.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore_state(&self, state: &VmState) -> Result<()> {
+        self.fd
+            .set_pit2(&state.pitstate)
+            .map_err(Error::VmSetState)?;
+        self.fd.set_clock(&state.clock).map_err(Error::VmSetState)?;
+        self.fd
+            .set_irqchip(&state.pic_master)
+            .map_err(Error::VmSetState)?;
+        self.fd
+            .set_irqchip(&state.pic_slave)
+            .map_err(Error::VmSetState)?;
+        self.fd
+            .set_irqchip(&state.ioapic)
+            .map_err(Error::VmSetState)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn irqchip(chip_id: u32) -> kvm_irqchip {
+        // Safe because `kvm_irqchip` is a POD struct.
+        let mut irqchip: kvm_irqchip = unsafe { mem::zeroed() };
+        irqchip.chip_id = chip_id;
+        irqchip
+    }
+
     /// Creates the GIC (Global Interrupt Controller).
     #[cfg(target_arch = "aarch64")]
     pub fn setup_irqchip(&mut self, vcpu_count: u8) -> Result<()> {
@@ -266,10 +397,166 @@ impl Vm {
     }
 }
 
+/// The state of the in-kernel devices of a VM.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct VmState {
+    #[serde(with = "pod")]
+    pic_master: kvm_irqchip,
+    #[serde(with = "pod")]
+    pic_slave: kvm_irqchip,
+    #[serde(with = "pod")]
+    ioapic: kvm_irqchip,
+    #[serde(with = "pod")]
+    pitstate: kvm_pit_state2,
+    #[serde(with = "pod")]
+    clock: kvm_clock_data,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Versioned for VmState {
+    const NAME: &'static str = "Vm";
+    const VERSION: u16 = 1;
+}
+
+/// The architectural state of a vCPU.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VcpuState {
+    #[serde(with = "pod_vec")]
+    cpuid: Vec<kvm_cpuid_entry2>,
+    #[serde(with = "pod_vec")]
+    msrs: Vec<kvm_msr_entry>,
+    #[serde(with = "pod")]
+    regs: kvm_regs,
+    #[serde(with = "pod")]
+    sregs: kvm_sregs,
+    #[serde(with = "pod")]
+    xsave: kvm_xsave,
+    #[serde(with = "pod")]
+    xcrs: kvm_xcrs,
+    #[serde(with = "pod")]
+    debug_regs: kvm_debugregs,
+    #[serde(with = "pod")]
+    lapic: kvm_lapic_state,
+    #[serde(with = "pod")]
+    mp_state: kvm_mp_state,
+    #[serde(with = "pod")]
+    vcpu_events: kvm_vcpu_events,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Versioned for VcpuState {
+    const NAME: &'static str = "Vcpu";
+    const VERSION: u16 = 1;
+}
+
+/// Events sent to a running vCPU thread.
+#[derive(Clone, Copy)]
+pub enum VcpuEvent {
+    /// Stop running guest code until a `Resume` event arrives.
+    Pause,
+    /// Go back to running guest code.
+    Resume,
+    /// Save the state of the (paused) vCPU.
+    #[cfg(target_arch = "x86_64")]
+    SaveState,
+}
+
+/// Responses sent back by a vCPU thread after handling a `VcpuEvent`.
+pub enum VcpuResponse {
+    /// The vCPU is paused.
+    Paused,
+    /// The vCPU is running guest code again.
+    Resumed,
+    /// The state of the vCPU.
+    #[cfg(target_arch = "x86_64")]
+    SavedState(Box<VcpuState>),
+    /// The event could not be handled.
+    Error(Error),
+}
+
+/// Handle used by the VMM thread to control a running vCPU thread.
+pub struct VcpuHandle {
+    event_sender: Sender<VcpuEvent>,
+    response_receiver: Receiver<VcpuResponse>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl VcpuHandle {
+    /// Sends `event` to the vCPU and kicks it out of `KVM_RUN` so that the event gets handled.
+    pub fn send_event(&self, event: VcpuEvent) -> Result<()> {
+        self.event_sender
+            .send(event)
+            .map_err(|_| Error::VcpuEventSend)?;
+        self.thread.kill(VCPU_RTSIG_OFFSET).map_err(Error::VcpuKick)
+    }
+
+    /// Returns the channel on which the vCPU answers to the events it receives.
+    pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
+        &self.response_receiver
+    }
+}
+
+// Owns a `kvm_msrs` structure along with the entries of its flexible array member.
+#[cfg(target_arch = "x86_64")]
+struct MsrBuffer {
+    // Backed by `u64`s so that the buffer is suitably aligned for `kvm_msrs`.
+    buf: Vec<u64>,
+    nmsrs: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MsrBuffer {
+    fn new(entries: &[kvm_msr_entry]) -> Self {
+        let size = mem::size_of::<kvm_msrs>() + mem::size_of_val(entries);
+        let mut buffer = MsrBuffer {
+            buf: vec![0; (size + mem::size_of::<u64>() - 1) / mem::size_of::<u64>()],
+            nmsrs: entries.len(),
+        };
+        let msrs = buffer.msrs_mut();
+        msrs.nmsrs = entries.len() as u32;
+        // Safe because the buffer was sized to hold `entries.len()` entries.
+        unsafe { msrs.entries.as_mut_slice(entries.len()) }.copy_from_slice(entries);
+        buffer
+    }
+
+    fn msrs(&self) -> &kvm_msrs {
+        // Safe because the buffer is large enough and properly aligned for a `kvm_msrs`.
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            &*(self.buf.as_ptr() as *const kvm_msrs)
+        }
+    }
+
+    fn msrs_mut(&mut self) -> &mut kvm_msrs {
+        // Safe because the buffer is large enough and properly aligned for a `kvm_msrs`.
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            &mut *(self.buf.as_mut_ptr() as *mut kvm_msrs)
+        }
+    }
+
+    fn entries(&self) -> &[kvm_msr_entry] {
+        // Safe because the buffer holds `nmsrs` entries.
+        unsafe { self.msrs().entries.as_slice(self.nmsrs) }
+    }
+}
+
+// Describes the outcome of a `KVM_RUN`.
+enum VcpuEmulation {
+    // The exit was handled and the vCPU can go back to running guest code.
+    Handled,
+    // `KVM_RUN` was interrupted by a signal, which means there may be events to handle.
+    Interrupted,
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     #[cfg(target_arch = "x86_64")]
     cpuid: CpuId,
+    #[cfg(target_arch = "x86_64")]
+    msr_list: Vec<u32>,
     fd: VcpuFd,
     id: u8,
     #[cfg(target_arch = "x86_64")]
@@ -278,6 +565,12 @@ pub struct Vcpu {
     create_ts: TimestampUs,
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
+
+    event_receiver: Receiver<VcpuEvent>,
+    response_sender: Sender<VcpuResponse>,
+    // The ends of the channels handed over to the `VcpuHandle` when the vCPU thread is started.
+    event_sender: Option<Sender<VcpuEvent>>,
+    response_receiver: Option<Receiver<VcpuResponse>>,
 }
 
 impl Vcpu {
@@ -288,6 +581,7 @@ impl Vcpu {
     /// * `id` - Represents the CPU number between [0, max vcpus).
     /// * `vm_fd` - The kvm `VmFd` for the virtual machine this vcpu will get attached to.
     /// * `cpuid` - The `CpuId` listing the supported capabilities of this vcpu.
+    /// * `msr_list` - The MSRs saved and restored along with the state of this vcpu.
     /// * `io_bus` - The io-bus used to access port-io devices.
     /// * `create_ts` - A timestamp used by the vcpu to calculate its lifetime.
     #[cfg(target_arch = "x86_64")]
@@ -295,19 +589,27 @@ impl Vcpu {
         id: u8,
         vm_fd: &VmFd,
         cpuid: CpuId,
+        msr_list: Vec<u32>,
         io_bus: devices::Bus,
         create_ts: TimestampUs,
     ) -> Result<Self> {
         let kvm_vcpu = vm_fd.create_vcpu(id).map_err(Error::VcpuFd)?;
+        let (event_sender, event_receiver) = channel();
+        let (response_sender, response_receiver) = channel();
 
         // Initially the cpuid per vCPU is the one supported by this VM.
         Ok(Vcpu {
             cpuid,
+            msr_list,
             fd: kvm_vcpu,
             id,
             io_bus,
             mmio_bus: None,
             create_ts,
+            event_receiver,
+            response_sender,
+            event_sender: Some(event_sender),
+            response_receiver: Some(response_receiver),
         })
     }
 
@@ -321,12 +623,18 @@ impl Vcpu {
     #[cfg(target_arch = "aarch64")]
     pub fn new_aarch64(id: u8, vm_fd: &VmFd, create_ts: TimestampUs) -> Result<Self> {
         let kvm_vcpu = vm_fd.create_vcpu(id).map_err(Error::VcpuFd)?;
+        let (event_sender, event_receiver) = channel();
+        let (response_sender, response_receiver) = channel();
         Ok(Vcpu {
             fd: kvm_vcpu,
             id,
             mmio_bus: None,
             create_ts,
             mpidr: 0,
+            event_receiver,
+            response_sender,
+            event_sender: Some(event_sender),
+            response_receiver: Some(response_receiver),
         })
     }
 
@@ -395,6 +703,102 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Saves the architectural state of the vcpu.
+    ///
+    /// Must be called from the vcpu thread, while the vcpu is not running guest code.
+    #[cfg(target_arch = "x86_64")]
+    pub fn save_state(&self) -> Result<VcpuState> {
+        // The order of the calls mirrors the restore order, which is the one KVM expects.
+        let mp_state = self.fd.get_mp_state().map_err(Error::VcpuGetState)?;
+        let regs = self.fd.get_regs().map_err(Error::VcpuGetState)?;
+        let sregs = self.fd.get_sregs().map_err(Error::VcpuGetState)?;
+        let xsave = self.fd.get_xsave().map_err(Error::VcpuGetState)?;
+        let xcrs = self.fd.get_xcrs().map_err(Error::VcpuGetState)?;
+        let debug_regs = self.fd.get_debug_regs().map_err(Error::VcpuGetState)?;
+        let lapic = self.fd.get_lapic().map_err(Error::VcpuGetState)?;
+
+        let msrs = {
+            let entries: Vec<kvm_msr_entry> = self
+                .msr_list
+                .iter()
+                .map(|index| kvm_msr_entry {
+                    index: *index,
+                    ..Default::default()
+                })
+                .collect();
+            let mut buffer = MsrBuffer::new(&entries);
+            let nmsrs = self
+                .fd
+                .get_msrs(buffer.msrs_mut())
+                .map_err(Error::VcpuGetState)?;
+            if nmsrs as usize != entries.len() {
+                return Err(Error::VcpuMsrsIncomplete);
+            }
+            buffer.entries().to_vec()
+        };
+
+        let vcpu_events = self.fd.get_vcpu_events().map_err(Error::VcpuGetState)?;
+        let cpuid = self
+            .fd
+            .get_cpuid2(MAX_KVM_CPUID_ENTRIES)
+            .map_err(Error::VcpuGetState)?
+            .as_slice()
+            .to_vec();
+
+        Ok(VcpuState {
+            cpuid,
+            msrs,
+            regs,
+            sregs,
+            xsave,
+            xcrs,
+            debug_regs,
+            lapic,
+            mp_state,
+            vcpu_events,
+        })
+    }
+
+    /// Restores the architectural state of the vcpu. Replaces `configure_x86_64` for vcpus
+    /// resumed from a snapshot.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore_state(&mut self, state: &VcpuState) -> Result<()> {
+        self.cpuid = CpuId::from_entries(&state.cpuid);
+        self.fd
+            .set_cpuid2(&self.cpuid)
+            .map_err(Error::SetSupportedCpusFailed)?;
+        self.fd
+            .set_mp_state(state.mp_state)
+            .map_err(Error::VcpuSetState)?;
+        self.fd.set_regs(&state.regs).map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_sregs(&state.sregs)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xsave(&state.xsave)
+            .map_err(Error::VcpuSetState)?;
+        self.fd.set_xcrs(&state.xcrs).map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_debug_regs(&state.debug_regs)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_lapic(&state.lapic)
+            .map_err(Error::VcpuSetState)?;
+
+        let buffer = MsrBuffer::new(&state.msrs);
+        let nmsrs = self
+            .fd
+            .set_msrs(buffer.msrs())
+            .map_err(Error::VcpuSetState)?;
+        if nmsrs as usize != state.msrs.len() {
+            return Err(Error::VcpuMsrsIncomplete);
+        }
+
+        self.fd
+            .set_vcpu_events(&state.vcpu_events)
+            .map_err(Error::VcpuSetState)
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Configures an aarch64 specific vcpu.
     ///
@@ -439,14 +843,14 @@ impl Vcpu {
         }
     }
 
-    fn run_emulation(&mut self) -> Result<()> {
+    fn run_emulation(&mut self) -> Result<VcpuEmulation> {
         match self.fd.run() {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
                     self.io_bus.read(u64::from(addr), data);
                     METRICS.vcpu.exit_io_in.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoOut(addr, data) => {
@@ -454,14 +858,14 @@ impl Vcpu {
 
                     self.io_bus.write(u64::from(addr), data);
                     METRICS.vcpu.exit_io_out.inc();
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.read(addr, data);
                         METRICS.vcpu.exit_mmio_read.inc();
                     }
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
                     if let Some(ref mmio_bus) = self.mmio_bus {
@@ -471,7 +875,7 @@ impl Vcpu {
                         mmio_bus.write(addr, data);
                         METRICS.vcpu.exit_mmio_write.inc();
                     }
-                    Ok(())
+                    Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Hlt => {
                     info!("Received KVM_EXIT_HLT signal");
@@ -481,6 +885,10 @@ impl Vcpu {
                     info!("Received KVM_EXIT_SHUTDOWN signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
+                VcpuExit::SystemEvent => {
+                    info!("Received KVM_EXIT_SYSTEM_EVENT signal");
+                    Err(Error::VcpuUnhandledKvmExit)
+                }
                 // Documentation specifies that below kvm exits are considered
                 // errors.
                 VcpuExit::FailEntry => {
@@ -505,8 +913,12 @@ impl Vcpu {
             // error in our code in which case it is better to panic.
             Err(ref e) => {
                 match e.raw_os_error().unwrap() {
-                    // Why do we check for these if we only return EINVAL?
-                    libc::EAGAIN | libc::EINTR => Ok(()),
+                    libc::EAGAIN => Ok(VcpuEmulation::Handled),
+                    // The vcpu was kicked out of KVM_RUN by the VMM thread.
+                    libc::EINTR => {
+                        self.fd.set_kvm_immediate_exit(0);
+                        Ok(VcpuEmulation::Interrupted)
+                    }
                     _ => {
                         METRICS.vcpu.failures.inc();
                         error!("Failure during vcpu run: {}", e);
@@ -517,6 +929,100 @@ impl Vcpu {
         }
     }
 
+    /// Registers the signal handler used by the VMM thread to kick vcpus out of `KVM_RUN`.
+    pub fn register_kick_signal_handler() -> Result<()> {
+        extern "C" fn handle_signal(_: c_int, _: *mut siginfo_t, _: *mut c_void) {
+            TLS_VCPU_PTR.with(|v| {
+                if let Some(vcpu_ptr) = v.get() {
+                    // Safe because the pointer is only set by `init_thread_local_data` while the
+                    // vcpu is running on this thread.
+                    let vcpu = unsafe { &*vcpu_ptr };
+                    vcpu.fd.set_kvm_immediate_exit(1);
+                }
+            });
+        }
+
+        // Safe because the handler only touches the `kvm_run` structure of the vcpu running on
+        // the signaled thread.
+        unsafe { register_vcpu_signal_handler(VCPU_RTSIG_OFFSET, handle_signal) }
+            .map_err(Error::RegisterSignalHandler)
+    }
+
+    /// Moves the vcpu to a new thread and starts running guest code.
+    ///
+    /// Returns the handle through which the vcpu thread can be controlled.
+    pub fn start_threaded(
+        mut self,
+        thread_barrier: Arc<Barrier>,
+        seccomp_level: u32,
+        vcpu_exit_evt: EventFd,
+    ) -> Result<VcpuHandle> {
+        let event_sender = self.event_sender.take().ok_or(Error::VcpuEventSend)?;
+        let response_receiver = self.response_receiver.take().ok_or(Error::VcpuEventSend)?;
+        let thread = thread::Builder::new()
+            .name(format!("fc_vcpu{}", self.id))
+            .spawn(move || self.run(thread_barrier, seccomp_level, vcpu_exit_evt))
+            .map_err(Error::VcpuSpawn)?;
+
+        Ok(VcpuHandle {
+            event_sender,
+            response_receiver,
+            thread,
+        })
+    }
+
+    // Makes the vcpu reachable from the kick signal handler.
+    fn init_thread_local_data(&mut self) {
+        let vcpu_ptr = self as *const Vcpu;
+        TLS_VCPU_PTR.with(|v| v.set(Some(vcpu_ptr)));
+    }
+
+    // Handles the events sent by the VMM thread. While paused, the vcpu blocks here waiting for
+    // events. Returns `false` if the vcpu thread should exit.
+    fn handle_events(&mut self) -> bool {
+        let mut paused = false;
+        loop {
+            let event = if paused {
+                match self.event_receiver.recv() {
+                    Ok(event) => event,
+                    // Nobody can resume the vcpu anymore.
+                    Err(_) => return false,
+                }
+            } else {
+                match self.event_receiver.try_recv() {
+                    Ok(event) => event,
+                    Err(TryRecvError::Empty) => return true,
+                    Err(TryRecvError::Disconnected) => return true,
+                }
+            };
+
+            let response = match event {
+                VcpuEvent::Pause => {
+                    paused = true;
+                    VcpuResponse::Paused
+                }
+                VcpuEvent::Resume => {
+                    paused = false;
+                    VcpuResponse::Resumed
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuEvent::SaveState => {
+                    if paused {
+                        match self.save_state() {
+                            Ok(state) => VcpuResponse::SavedState(Box::new(state)),
+                            Err(e) => VcpuResponse::Error(e),
+                        }
+                    } else {
+                        VcpuResponse::Error(Error::VcpuNotPaused)
+                    }
+                }
+            };
+            if self.response_sender.send(response).is_err() && paused {
+                return false;
+            }
+        }
+    }
+
     /// Main loop of the vCPU thread.
     ///
     /// Runs the vCPU in KVM context in a loop. Handles KVM_EXITs then goes back in.
@@ -538,9 +1044,20 @@ impl Vcpu {
             );
         }
 
+        self.init_thread_local_data();
         thread_barrier.wait();
 
-        while self.run_emulation().is_ok() {}
+        loop {
+            match self.run_emulation() {
+                Ok(VcpuEmulation::Handled) => (),
+                Ok(VcpuEmulation::Interrupted) => {
+                    if !self.handle_events() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
 
         // Nothing we need do for the success case.
         if let Err(e) = vcpu_exit_evt.write(1) {
@@ -562,7 +1079,7 @@ mod tests {
         let kvm = KvmContext::new().unwrap();
         let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("Cannot create new vm");
-        assert!(vm.memory_init(gm, &kvm).is_ok());
+        assert!(vm.memory_init(gm, &kvm, false).is_ok());
 
         let vcpu;
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -572,6 +1089,7 @@ mod tests {
                 1,
                 vm.fd(),
                 vm.supported_cpuid().clone(),
+                vm.msr_list().to_vec(),
                 devices::Bus::new(),
                 super::super::TimestampUs::default(),
             )
@@ -613,14 +1131,14 @@ mod tests {
 
         // Create valid memory region and test that the initialization is successful.
         let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
-        assert!(vm.memory_init(gm, &kvm_context).is_ok());
+        assert!(vm.memory_init(gm, &kvm_context, false).is_ok());
 
         // Set the maximum number of memory slots to 1 in KvmContext to check the error
         // path of memory_init. Create 2 non-overlapping memory slots.
         kvm_context.max_memslots = 1;
         let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x1001), 0x2000)])
             .unwrap();
-        assert!(vm.memory_init(gm, &kvm_context).is_err());
+        assert!(vm.memory_init(gm, &kvm_context, false).is_err());
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -639,6 +1157,7 @@ mod tests {
             1,
             vm.fd(),
             vm.supported_cpuid().clone(),
+            vm.msr_list().to_vec(),
             devices::Bus::new(),
             super::super::TimestampUs::default(),
         )
@@ -693,7 +1212,7 @@ mod tests {
         let kvm = KvmContext::new().unwrap();
         let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let mut vm = Vm::new(kvm.fd()).expect("new vm failed");
-        assert!(vm.memory_init(gm, &kvm).is_ok());
+        assert!(vm.memory_init(gm, &kvm, false).is_ok());
         let vm_mem = vm.get_memory().unwrap();
 
         // Try it for when vcpu id is 0.
@@ -712,6 +1231,70 @@ mod tests {
             .is_ok());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vm_save_restore_state() {
+        let (vm, _) = setup_vcpu();
+
+        let state = vm.save_state().unwrap();
+        assert!(vm.restore_state(&state).is_ok());
+
+        // The state cannot be restored on a VM without an irqchip.
+        let kvm = KvmContext::new().unwrap();
+        let other_vm = Vm::new(kvm.fd()).unwrap();
+        assert!(other_vm.restore_state(&state).is_err());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_save_restore_state() {
+        let (vm, mut vcpu) = setup_vcpu();
+        let vm_mem = vm.get_memory().unwrap();
+        vcpu.configure_x86_64(&VmConfig::default(), vm_mem, GuestAddress(0))
+            .unwrap();
+
+        let state = vcpu.save_state().unwrap();
+        assert!(!state.msrs.is_empty());
+        assert!(vcpu.restore_state(&state).is_ok());
+
+        let restored_state = vcpu.save_state().unwrap();
+        assert_eq!(restored_state.regs.rip, state.regs.rip);
+        assert_eq!(restored_state.sregs.cr0, state.sregs.cr0);
+        assert_eq!(restored_state.msrs.len(), state.msrs.len());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_pause_resume() {
+        let (vm, mut vcpu) = setup_vcpu();
+        let vm_mem = vm.get_memory().unwrap();
+        vcpu.configure_x86_64(&VmConfig::default(), vm_mem, GuestAddress(0))
+            .unwrap();
+        // Keep the guest spinning on a `jmp $` instruction.
+        vm_mem
+            .write_slice_at_addr(&[0xeb, 0xfe], GuestAddress(0))
+            .unwrap();
+        Vcpu::register_kick_signal_handler().unwrap();
+
+        let barrier = Arc::new(Barrier::new(2));
+        let exit_evt = EventFd::new().unwrap();
+        let handle = vcpu
+            .start_threaded(barrier.clone(), seccomp::SECCOMP_LEVEL_NONE, exit_evt)
+            .unwrap();
+        barrier.wait();
+
+        handle.send_event(VcpuEvent::Pause).unwrap();
+        match handle.response_receiver().recv().unwrap() {
+            VcpuResponse::Paused => (),
+            _ => panic!("Unexpected vcpu response."),
+        }
+        handle.send_event(VcpuEvent::Resume).unwrap();
+        match handle.response_receiver().recv().unwrap() {
+            VcpuResponse::Resumed => (),
+            _ => panic!("Unexpected vcpu response."),
+        }
+    }
+
     #[test]
     #[should_panic]
     fn test_vcpu_run_failed() {